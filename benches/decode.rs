@@ -0,0 +1,61 @@
+//! Benchmarks of `PacketCodec::decode` for the small packets that dominate the receive path of a busy client.
+//!
+//! Run with `cargo bench --bench decode`.
+
+use tokio_codec::{ Decoder, Encoder };
+
+fn encode(packets: &[mqtt::proto::Packet]) -> bytes::BytesMut {
+	let mut codec: mqtt::proto::PacketCodec = Default::default();
+	let mut bytes = bytes::BytesMut::new();
+	for packet in packets {
+		codec.encode(packet.clone(), &mut bytes).unwrap();
+	}
+	bytes
+}
+
+fn publish(packet_identifier_dup_qos: mqtt::proto::PacketIdentifierDupQoS, payload_len: usize) -> mqtt::proto::Packet {
+	mqtt::proto::Packet::Publish(mqtt::proto::Publish {
+		packet_identifier_dup_qos,
+		retain: false,
+		topic_name: "devices/device-1/telemetry".to_owned(),
+		payload: vec![0x01; payload_len].into(),
+	})
+}
+
+fn decode_all(bytes: &mut bytes::BytesMut) -> usize {
+	let mut codec: mqtt::proto::PacketCodec = Default::default();
+	let mut decoded = 0;
+	while let Some(packet) = codec.decode(bytes).unwrap() {
+		criterion::black_box(packet);
+		decoded += 1;
+	}
+	decoded
+}
+
+fn bench_decode(c: &mut criterion::Criterion) {
+	let packet_identifier = mqtt::proto::PacketIdentifier::new(1).unwrap();
+
+	let cases = vec![
+		("puback", vec![mqtt::proto::Packet::PubAck(mqtt::proto::PubAck { packet_identifier })]),
+		("publish_qos0_16b", vec![publish(mqtt::proto::PacketIdentifierDupQoS::AtMostOnce, 16)]),
+		("publish_qos1_16b", vec![publish(mqtt::proto::PacketIdentifierDupQoS::AtLeastOnce(packet_identifier, false), 16)]),
+		("publish_qos1_1kib", vec![publish(mqtt::proto::PacketIdentifierDupQoS::AtLeastOnce(packet_identifier, false), 1024)]),
+		// Many packets that were read from the connection together
+		("publish_qos0_16b_x100", vec![publish(mqtt::proto::PacketIdentifierDupQoS::AtMostOnce, 16); 100]),
+	];
+
+	let mut group = c.benchmark_group("decode");
+	for (name, packets) in cases {
+		let bytes = encode(&packets);
+		group.throughput(criterion::Throughput::Elements(packets.len() as u64));
+		group.bench_function(name, |b| b.iter_batched_ref(
+			|| bytes.clone(),
+			|bytes| assert_eq!(decode_all(bytes), packets.len()),
+			criterion::BatchSize::SmallInput,
+		));
+	}
+	group.finish();
+}
+
+criterion::criterion_group!(benches, bench_decode);
+criterion::criterion_main!(benches);
@@ -0,0 +1,135 @@
+//! Benchmark of the throughput of at-least-once publications, from `PublishHandle::publish` to the server's PUBACK, over a loopback TCP connection.
+//!
+//! The server is a minimal one on a separate thread that acks every PUBLISH packet as soon as it has read it, so the client is the bottleneck.
+//!
+//! Run with `cargo bench --bench publish`.
+
+use futures::{ Future, Stream };
+
+/// The number of publications in each iteration
+const PUBLICATIONS: usize = 10_000;
+
+/// Accepts one connection, and acks its CONNECT and every PUBLISH and PINGREQ packet
+fn run_server(listener: &std::net::TcpListener) {
+	let (mut stream, _) = listener.accept().unwrap();
+	stream.set_nodelay(true).unwrap();
+
+	let mut read_buf = vec![0_u8; 64 * 1024];
+	let mut received: Vec<u8> = vec![];
+	let mut responses = vec![];
+
+	loop {
+		let read = match std::io::Read::read(&mut stream, &mut read_buf) {
+			Ok(0) | Err(_) => return,
+			Ok(read) => read,
+		};
+		received.extend_from_slice(&read_buf[..read]);
+
+		let mut consumed = 0;
+		while let Some((packet, len)) = mqtt::proto::decode(&received[consumed..]).unwrap() {
+			consumed += len;
+
+			let response = match packet {
+				mqtt::proto::Packet::Connect(_) => mqtt::proto::Packet::ConnAck(mqtt::proto::ConnAck {
+					session_present: false,
+					return_code: mqtt::proto::ConnectReturnCode::Accepted,
+				}),
+				mqtt::proto::Packet::Publish(mqtt::proto::Publish {
+					packet_identifier_dup_qos: mqtt::proto::PacketIdentifierDupQoS::AtLeastOnce(packet_identifier, _),
+					..
+				}) => mqtt::proto::Packet::PubAck(mqtt::proto::PubAck { packet_identifier }),
+				mqtt::proto::Packet::PingReq(_) => mqtt::proto::Packet::PingResp(mqtt::proto::PingResp),
+				_ => continue,
+			};
+			mqtt::proto::encode(&response, &mut responses).unwrap();
+		}
+		let _ = received.drain(..consumed);
+
+		if !responses.is_empty() {
+			if std::io::Write::write_all(&mut stream, &responses).is_err() {
+				return;
+			}
+			responses.clear();
+		}
+	}
+}
+
+fn bench_publish(c: &mut criterion::Criterion) {
+	let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+	let addr = listener.local_addr().unwrap();
+	let _server = std::thread::spawn(move || run_server(&listener));
+
+	let mut runtime = tokio::runtime::current_thread::Runtime::new().unwrap();
+
+	let client =
+		mqtt::Client::new(
+			None,
+			None,
+			None,
+			move || tokio::net::TcpStream::connect(&addr).map(|io| { io.set_nodelay(true).unwrap(); (io, None) }),
+			std::time::Duration::from_secs(0),
+			std::time::Duration::from_secs(60),
+		);
+	let mut publish_handle = client.publish_handle().unwrap();
+	runtime.spawn(client.for_each(|_| Ok(())).map_err(|err| panic!("client failed: {}", err)));
+
+	let topic_name: mqtt::topic::TopicName = "devices/device-1/telemetry".parse().unwrap();
+	let payload: bytes::Bytes = vec![0x01; 64].into();
+
+	let mut group = c.benchmark_group("publish");
+	group.sample_size(10);
+	group.throughput(criterion::Throughput::Elements(PUBLICATIONS as u64));
+	group.bench_function("qos1_64b", |b| b.iter_custom(|iters| {
+		let start = std::time::Instant::now();
+
+		for _ in 0..iters {
+			let publishes: futures::stream::FuturesUnordered<_> =
+				(0..PUBLICATIONS)
+				.map(|_| publish_handle.publish(mqtt::proto::Publication {
+					topic_name: topic_name.clone(),
+					qos: mqtt::proto::QoS::AtLeastOnce,
+					retain: false,
+					payload: payload.clone(),
+				}))
+				.collect();
+			runtime.block_on(publishes.for_each(|()| Ok(()))).unwrap();
+		}
+
+		start.elapsed()
+	}));
+
+	// A producer that waits for the client to accept each publication before making the next one, instead of queuing up futures
+	group.bench_function("qos1_64b_poll_ready", |b| b.iter_custom(|iters| {
+		let start = std::time::Instant::now();
+
+		for _ in 0..iters {
+			let mut remaining = PUBLICATIONS;
+			let mut publishes = futures::stream::FuturesUnordered::new();
+			runtime.block_on(futures::future::poll_fn(|| -> futures::Poll<(), mqtt::PublishError> {
+				while remaining > 0 {
+					futures::try_ready!(publish_handle.poll_ready());
+					publishes.push(publish_handle.publish(mqtt::proto::Publication {
+						topic_name: topic_name.clone(),
+						qos: mqtt::proto::QoS::AtLeastOnce,
+						retain: false,
+						payload: payload.clone(),
+					}));
+					remaining -= 1;
+
+					while let futures::Async::Ready(Some(())) = publishes.poll()? {
+					}
+				}
+
+				while futures::try_ready!(publishes.poll()).is_some() {
+				}
+				Ok(futures::Async::Ready(()))
+			})).unwrap();
+		}
+
+		start.elapsed()
+	}));
+	group.finish();
+}
+
+criterion::criterion_group!(benches, bench_publish);
+criterion::criterion_main!(benches);
@@ -0,0 +1,427 @@
+// This example runs a battery of protocol-conformance scenarios against a server, and reports whether each of them passed or failed.
+//
+// It is meant to validate a server deployment for use with this client. Every scenario uses its own topics under "mqtt-conformance/",
+// so it's safe to run against a server that is in use. The example exits with a non-zero status if any scenario failed.
+//
+// The scenarios are:
+//
+// - connect: The client connects and receives a CONNACK.
+// - qos0, qos1, qos2: The client subscribes to a topic and receives its own publication with the same QoS.
+// - retained: A retained publication is delivered to a client that subscribes after it was published.
+// - will: The will of a client whose connection is dropped without a DISCONNECT is delivered to a subscriber.
+// - keep-alive: The server responds to the client's pings while it's idle, without the connection being dropped.
+// - session-resume: A subscription made with a persistent session survives a reconnect, and a publication sent while the client
+//   was disconnected is delivered when it reconnects with the same session. The client always uses a clean session,
+//   so this scenario drives the protocol with the packet codec directly.
+//
+// Example:
+//
+//     cargo run --example conformance -- --server 127.0.0.1:1883
+
+use futures::{ Future, Sink, Stream };
+
+#[allow(dead_code)] // This example doesn't use every helper
+mod common;
+
+#[derive(Debug, structopt_derive::StructOpt)]
+struct Options {
+	#[structopt(help = "Address of the MQTT server.", long = "server")]
+	server: std::net::SocketAddr,
+
+	#[structopt(help = "Username used to authenticate with the server, if any.", long = "username")]
+	username: Option<String>,
+
+	#[structopt(help = "Password used to authenticate with the server, if any.", long = "password")]
+	password: Option<String>,
+
+	#[structopt(
+		help = "How long each scenario may take before it fails, in seconds.",
+		long = "timeout",
+		default_value = "10",
+		parse(try_from_str = "common::duration_from_secs_str"),
+	)]
+	timeout: std::time::Duration,
+}
+
+/// The keep-alive used by the clients of every scenario except keep-alive
+const KEEP_ALIVE: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// The keep-alive used by the client of the keep-alive scenario, and how long it stays idle
+const SHORT_KEEP_ALIVE: std::time::Duration = std::time::Duration::from_secs(2);
+const IDLE: std::time::Duration = std::time::Duration::from_secs(5);
+
+type ScenarioFuture = Box<dyn Future<Item = (), Error = String> + Send>;
+
+const SCENARIOS: &[(&str, fn(&Context) -> ScenarioFuture)] = &[
+	("connect", connect),
+	("qos0", |context| roundtrip(context, mqtt::proto::QoS::AtMostOnce)),
+	("qos1", |context| roundtrip(context, mqtt::proto::QoS::AtLeastOnce)),
+	("qos2", |context| roundtrip(context, mqtt::proto::QoS::ExactlyOnce)),
+	("retained", retained),
+	("will", will),
+	("keep-alive", keep_alive),
+	("session-resume", session_resume),
+];
+
+fn main() {
+	env_logger::Builder::from_env(env_logger::Env::new().filter_or("MQTT_LOG", "mqtt=warn,conformance=info")).init();
+
+	let Options {
+		server,
+		username,
+		password,
+		timeout,
+	} = structopt::StructOpt::from_args();
+
+	let mut runtime = tokio::runtime::Runtime::new().expect("couldn't initialize tokio runtime");
+
+	let unique = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|since_epoch| since_epoch.as_nanos()).unwrap_or_default();
+	let context = Context {
+		server,
+		username,
+		password,
+		prefix: format!("mqtt-conformance/{}-{}", std::process::id(), unique),
+	};
+
+	let mut failed = 0;
+
+	for (name, scenario) in SCENARIOS {
+		let scenario = tokio::timer::Timeout::new(scenario(&context), timeout).map_err(move |err| {
+			if err.is_elapsed() {
+				format!("timed out after {:?}", timeout)
+			}
+			else if let Some(err) = err.into_inner() {
+				err
+			}
+			else {
+				"timer failed".to_owned()
+			}
+		});
+
+		match runtime.block_on(scenario) {
+			Ok(()) => println!("PASS {}", name),
+			Err(err) => {
+				println!("FAIL {}: {}", name, err);
+				failed += 1;
+			},
+		}
+	}
+
+	println!();
+	println!("{} passed, {} failed", SCENARIOS.len() - failed, failed);
+
+	if failed > 0 {
+		std::process::exit(1);
+	}
+}
+
+#[derive(Debug)]
+struct Context {
+	server: std::net::SocketAddr,
+	username: Option<String>,
+	password: Option<String>,
+
+	/// Prefix of the topics and client IDs used by the scenarios, so that they don't interfere with other users of the server or other runs
+	prefix: String,
+}
+
+impl Context {
+	fn client(&self, will: Option<mqtt::proto::Publication>, keep_alive: std::time::Duration) -> mqtt::Client<TcpIoSource> {
+		let io_source = TcpIoSource {
+			server: self.server,
+			password: self.password.clone(),
+		};
+
+		mqtt::Client::new(None, self.username.clone(), will, io_source, std::time::Duration::from_secs(1), keep_alive)
+	}
+
+	fn topic(&self, name: &str) -> mqtt::topic::TopicName {
+		format!("{}/{}", self.prefix, name).parse().expect("scenario topic is not a valid topic name")
+	}
+
+	fn publication(&self, name: &str, qos: mqtt::proto::QoS, retain: bool, payload: &'static str) -> mqtt::proto::Publication {
+		mqtt::proto::Publication {
+			topic_name: self.topic(name),
+			qos,
+			retain,
+			payload: payload.into(),
+		}
+	}
+}
+
+/// Connects to the server over TCP.
+///
+/// This is a named type rather than a closure so that the scenarios' futures can be boxed as `Send`.
+#[derive(Debug)]
+struct TcpIoSource {
+	server: std::net::SocketAddr,
+	password: Option<String>,
+}
+
+impl mqtt::IoSource for TcpIoSource {
+	type Io = tokio::net::TcpStream;
+	type Future = Box<dyn Future<Item = (Self::Io, Option<String>), Error = std::io::Error> + Send>;
+
+	fn connect(&mut self) -> Self::Future {
+		let password = self.password.clone();
+		Box::new(tokio::net::TcpStream::connect(&self.server).map(|io| (io, password)))
+	}
+}
+
+fn connect(context: &Context) -> ScenarioFuture {
+	let client = context.client(None, KEEP_ALIVE);
+	Box::new(wait_for(client, "CONNACK", is_new_connection).map(drop))
+}
+
+fn roundtrip(context: &Context, qos: mqtt::proto::QoS) -> ScenarioFuture {
+	let publication = context.publication(&format!("qos{}", u8::from(qos)), qos, false, "roundtrip");
+
+	let mut client = context.client(None, KEEP_ALIVE);
+	let mut publish_handle = match client.publish_handle() {
+		Ok(publish_handle) => publish_handle,
+		Err(err) => return Box::new(futures::future::err(format!("couldn't get publish handle: {}", err))),
+	};
+	if let Err(err) = client.subscribe(subscribe_to(&publication)) {
+		return Box::new(futures::future::err(format!("couldn't subscribe: {}", err)));
+	}
+
+	Box::new(
+		wait_for(client, "SUBACK", is_subscription_update)
+		.and_then(move |client| {
+			let publish = publish_handle.publish(publication.clone()).map_err(|err| format!("couldn't publish: {}", err));
+			let receive = wait_for(client, "own publication", move |event| is_publication_of(event, &publication, false));
+			publish.join(receive).map(drop)
+		}))
+}
+
+fn retained(context: &Context) -> ScenarioFuture {
+	let publication = context.publication("retained", mqtt::proto::QoS::AtLeastOnce, true, "retained");
+	let clear = mqtt::proto::Publication { payload: Default::default(), ..publication.clone() };
+
+	let mut subscriber = context.client(None, KEEP_ALIVE);
+	if let Err(err) = subscriber.subscribe(subscribe_to(&publication)) {
+		return Box::new(futures::future::err(format!("couldn't subscribe: {}", err)));
+	}
+
+	let publish_clear = publish_once(context, clear);
+
+	Box::new(
+		publish_once(context, publication.clone())
+		.and_then(move |()| wait_for(subscriber, "retained publication", move |event| is_publication_of(event, &publication, true)))
+		// Clear the retained publication so that it doesn't linger on the server
+		.and_then(move |_| publish_clear))
+}
+
+fn will(context: &Context) -> ScenarioFuture {
+	let will = context.publication("will", mqtt::proto::QoS::AtLeastOnce, false, "will");
+
+	let mut subscriber = context.client(None, KEEP_ALIVE);
+	if let Err(err) = subscriber.subscribe(subscribe_to(&will)) {
+		return Box::new(futures::future::err(format!("couldn't subscribe: {}", err)));
+	}
+
+	let testator = context.client(Some(will.clone()), KEEP_ALIVE);
+
+	Box::new(
+		wait_for(subscriber, "SUBACK", is_subscription_update)
+		.join(wait_for(testator, "CONNACK of the client with the will", is_new_connection))
+		.and_then(move |(subscriber, testator)| {
+			// Dropping the client closes its connection without sending a DISCONNECT, so the server must publish its will
+			drop(testator);
+			wait_for(subscriber, "will", move |event| is_publication_of(event, &will, false))
+		})
+		.map(drop))
+}
+
+fn keep_alive(context: &Context) -> ScenarioFuture {
+	let client = context.client(None, SHORT_KEEP_ALIVE);
+	let stats = client.stats_handle();
+
+	Box::new(
+		wait_for(client, "CONNACK", is_new_connection)
+		.and_then(|client| {
+			// The client is idle, so any event, like a ping timeout or a reconnection, means the server didn't keep the connection alive
+			let events = client.into_future().then(|result| match result {
+				Ok((Some(event), _)) => Err(format!("unexpected event while idle: {:?}", event)),
+				Ok((None, _)) => Err("client stopped while idle".to_owned()),
+				Err((err, _)) => Err(format!("client failed while idle: {}", err)),
+			});
+
+			let idle = tokio::timer::Delay::new(std::time::Instant::now() + IDLE).map_err(|err| format!("timer failed: {}", err));
+
+			idle.select(events).map(drop).map_err(|(err, _)| err)
+		})
+		.and_then(move |()| match stats.stats().last_ping_round_trip_time {
+			Some(_) => Ok(()),
+			None => Err(format!("server did not respond to any ping within {:?}", IDLE)),
+		}))
+}
+
+fn session_resume(context: &Context) -> ScenarioFuture {
+	let client_id = format!("{}-session", context.prefix).replace('/', "-");
+	let publication = context.publication("session", mqtt::proto::QoS::AtLeastOnce, false, "queued");
+	let server = context.server;
+	let (username, password) = (context.username.clone(), context.password.clone());
+	let connect = move |client_id| raw_connect(server, username.clone(), password.clone(), client_id);
+
+	let publish = publish_once(context, publication.clone());
+
+	Box::new(
+		// Subscribe with a persistent session, then disconnect
+		connect(mqtt::proto::ClientId::IdWithExistingSession(client_id.clone()))
+		.and_then({
+			let subscribe_to = subscribe_to(&publication);
+			move |(framed, _)| framed.send(mqtt::proto::Packet::Subscribe(mqtt::proto::Subscribe {
+				packet_identifier: mqtt::proto::PacketIdentifier::new(1).expect("1 is a valid packet identifier"),
+				subscribe_to: vec![subscribe_to],
+			})).map_err(|err| format!("couldn't send SUBSCRIBE: {}", err))
+		})
+		.and_then(|framed| raw_next(framed, "SUBACK"))
+		.and_then(|(packet, framed)| match packet {
+			mqtt::proto::Packet::SubAck(mqtt::proto::SubAck { qos, .. }) if qos == [mqtt::proto::SubAckQos::Success(mqtt::proto::QoS::AtLeastOnce)] => Ok(framed),
+			packet => Err(format!("expected SUBACK granting AtLeastOnce but received {:?}", packet)),
+		})
+		.and_then(|framed| framed.send(mqtt::proto::Packet::Disconnect(mqtt::proto::Disconnect)).map_err(|err| format!("couldn't send DISCONNECT: {}", err)))
+
+		// Publish while the session's client is disconnected
+		.and_then(move |_| publish)
+
+		// Resume the session, and expect the queued publication
+		.and_then({
+			let connect = connect.clone();
+			let client_id = client_id.clone();
+			move |()| connect(mqtt::proto::ClientId::IdWithExistingSession(client_id))
+		})
+		.and_then(|(framed, session_present)|
+			if session_present {
+				Ok(framed)
+			}
+			else {
+				Err("server did not resume the session".to_owned())
+			})
+		.and_then(|framed| raw_next(framed, "queued publication"))
+		.and_then(move |(packet, framed)| match packet {
+			mqtt::proto::Packet::Publish(mqtt::proto::Publish {
+				packet_identifier_dup_qos: mqtt::proto::PacketIdentifierDupQoS::AtLeastOnce(packet_identifier, _),
+				topic_name,
+				payload,
+				..
+			}) if topic_name == publication.topic_name.as_str() && payload == publication.payload => Ok((packet_identifier, framed)),
+			packet => Err(format!("expected the queued publication but received {:?}", packet)),
+		})
+		.and_then(|(packet_identifier, framed)|
+			framed.send(mqtt::proto::Packet::PubAck(mqtt::proto::PubAck { packet_identifier }))
+			.and_then(|framed| framed.send(mqtt::proto::Packet::Disconnect(mqtt::proto::Disconnect)))
+			.map_err(|err| format!("couldn't ack the queued publication: {}", err)))
+
+		// Connect with a clean session so that the server discards the persistent one
+		.and_then(move |_| connect(mqtt::proto::ClientId::IdWithCleanSession(client_id)))
+		.and_then(|(framed, _)| framed.send(mqtt::proto::Packet::Disconnect(mqtt::proto::Disconnect)).map_err(|err| format!("couldn't send DISCONNECT: {}", err)))
+		.map(drop))
+}
+
+type RawConnection = tokio::codec::Framed<tokio::net::TcpStream, mqtt::proto::PacketCodec>;
+
+/// Connects to the server without a client, and returns the connection and whether the server resumed an existing session
+fn raw_connect(
+	server: std::net::SocketAddr,
+	username: Option<String>,
+	password: Option<String>,
+	client_id: mqtt::proto::ClientId,
+) -> impl Future<Item = (RawConnection, bool), Error = String> {
+	tokio::net::TcpStream::connect(&server)
+		.map_err(|err| format!("couldn't connect: {}", err))
+		.and_then(move |io| {
+			let framed = tokio::codec::Framed::new(io, mqtt::proto::PacketCodec::default());
+			framed.send(mqtt::proto::Packet::Connect(mqtt::proto::Connect {
+				username,
+				password,
+				will: None,
+				client_id,
+				keep_alive: KEEP_ALIVE,
+			})).map_err(|err| format!("couldn't send CONNECT: {}", err))
+		})
+		.and_then(|framed| raw_next(framed, "CONNACK"))
+		.and_then(|(packet, framed)| match packet {
+			mqtt::proto::Packet::ConnAck(mqtt::proto::ConnAck { session_present, return_code: mqtt::proto::ConnectReturnCode::Accepted }) =>
+				Ok((framed, session_present)),
+			packet => Err(format!("expected CONNACK accepting the connection but received {:?}", packet)),
+		})
+}
+
+fn raw_next(framed: RawConnection, description: &'static str) -> impl Future<Item = (mqtt::proto::Packet, RawConnection), Error = String> {
+	framed.into_future().then(move |result| match result {
+		Ok((Some(packet), framed)) => Ok((packet, framed)),
+		Ok((None, _)) => Err(format!("server closed the connection while waiting for {}", description)),
+		Err((err, _)) => Err(format!("connection failed while waiting for {}: {}", description, err)),
+	})
+}
+
+/// Publishes the given publication with a new client, and resolves once the server has acked it
+fn publish_once(context: &Context, publication: mqtt::proto::Publication) -> ScenarioFuture {
+	let client = context.client(None, KEEP_ALIVE);
+	let mut publish_handle = match client.publish_handle() {
+		Ok(publish_handle) => publish_handle,
+		Err(err) => return Box::new(futures::future::err(format!("couldn't get publish handle: {}", err))),
+	};
+
+	let publish = publish_handle.publish(publication).map_err(|err| format!("couldn't publish: {}", err));
+	let client = client.for_each(|_| Ok(())).then(|result| match result {
+		Ok(()) => Err("publishing client stopped".to_owned()),
+		Err(err) => Err(format!("publishing client failed: {}", err)),
+	});
+
+	// Poll the client until the publication is acked, and then drop it
+	Box::new(publish.select(client).map(drop).map_err(|(err, _)| err))
+}
+
+/// Polls the client until it emits an event that matches the predicate, and then returns the client
+fn wait_for<S, P>(client: S, description: &'static str, predicate: P) -> impl Future<Item = S, Error = String>
+where
+	S: Stream<Item = mqtt::Event, Error = mqtt::Error>,
+	P: Fn(&mqtt::Event) -> bool,
+{
+	let predicate = std::sync::Arc::new(predicate);
+
+	futures::future::loop_fn(client, move |client| {
+		let predicate = predicate.clone();
+		client.into_future().then(move |result| match result {
+			Ok((Some(event), client)) =>
+				if predicate(&event) {
+					Ok(futures::future::Loop::Break(client))
+				}
+				else {
+					log::debug!("ignoring {:?} while waiting for {}", event, description);
+					Ok(futures::future::Loop::Continue(client))
+				},
+			Ok((None, _)) => Err(format!("client stopped while waiting for {}", description)),
+			Err((err, _)) => Err(format!("client failed while waiting for {}: {}", description, err)),
+		})
+	})
+}
+
+fn subscribe_to(publication: &mqtt::proto::Publication) -> mqtt::proto::SubscribeTo {
+	mqtt::proto::SubscribeTo {
+		topic_filter: publication.topic_name.as_str().parse().expect("topic name is not a valid topic filter"),
+		qos: publication.qos,
+	}
+}
+
+fn is_new_connection(event: &mqtt::Event) -> bool {
+	matches!(event, mqtt::Event::NewConnection { .. })
+}
+
+fn is_subscription_update(event: &mqtt::Event) -> bool {
+	matches!(event, mqtt::Event::SubscriptionUpdates(_))
+}
+
+fn is_publication_of(event: &mqtt::Event, publication: &mqtt::proto::Publication, retain: bool) -> bool {
+	match event {
+		mqtt::Event::Publication(received) =>
+			received.topic_name == publication.topic_name.as_str() &&
+			received.qos == publication.qos &&
+			received.retain == retain &&
+			received.payload == publication.payload,
+		_ => false,
+	}
+}
@@ -45,7 +45,7 @@ struct Options {
 	publish_frequency: std::time::Duration,
 
 	#[structopt(help = "The topic of the publications.", long = "topic")]
-	topic: String,
+	topic: mqtt::topic::TopicName,
 
 	#[structopt(help = "The QoS of the publications.", long = "qos", parse(try_from_str = "common::qos_from_str"))]
 	qos: mqtt::proto::QoS,
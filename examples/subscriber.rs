@@ -37,7 +37,7 @@ struct Options {
 	keep_alive: std::time::Duration,
 
 	#[structopt(help = "The topic filter to subscribe to.", long = "topic-filter")]
-	topic_filter: String,
+	topic_filter: mqtt::topic::TopicFilter,
 
 	#[structopt(help = "The QoS with which to subscribe to the topic.", long = "qos", parse(try_from_str = "common::qos_from_str"))]
 	qos: mqtt::proto::QoS,
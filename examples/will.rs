@@ -47,7 +47,7 @@ struct Options {
 	keep_alive: std::time::Duration,
 
 	#[structopt(help = "The topic of the will.", long = "topic")]
-	topic: String,
+	topic: mqtt::topic::TopicName,
 
 	#[structopt(help = "The QoS of the will.", long = "qos", parse(try_from_str = "common::qos_from_str"))]
 	qos: mqtt::proto::QoS,
@@ -97,7 +97,7 @@ fn main() {
 	runtime.spawn(
 		update_subscription_handle
 		.subscribe(mqtt::proto::SubscribeTo {
-			topic_filter: topic,
+			topic_filter: topic.parse().expect("topic of the will is not a valid topic filter"),
 			qos,
 		})
 		.map_err(|err| panic!("couldn't update subscription: {}", err)));
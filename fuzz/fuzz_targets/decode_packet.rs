@@ -0,0 +1,19 @@
+//! Fuzzes `mqtt::proto::decode_packet` with arbitrary input, as if a broker sent it.
+//!
+//!     cargo +nightly fuzz run decode_packet
+
+#![no_main]
+
+libfuzzer_sys::fuzz_target!(|data: &[u8]| {
+	if let Ok(packet) = mqtt::proto::decode_packet(data) {
+		// A decoded packet must re-encode, and the re-encoding must decode to the same packet.
+		//
+		// The re-encoding is not compared to the input since there are multiple ways to encode some packets,
+		// like a remaining length of `0x81 0x00` which is re-encoded as `0x01`.
+		let mut bytes = vec![];
+		mqtt::proto::encode(&packet, &mut bytes).unwrap();
+
+		let packet2 = mqtt::proto::decode_packet(&bytes).unwrap();
+		assert_eq!(packet, packet2);
+	}
+});
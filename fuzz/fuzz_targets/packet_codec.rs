@@ -0,0 +1,32 @@
+//! Fuzzes `mqtt::proto::PacketCodec` with arbitrary input that arrives in arbitrary chunks, like reads from a connection to a broker.
+//!
+//!     cargo +nightly fuzz run packet_codec
+
+#![no_main]
+
+use tokio_codec::Decoder;
+
+libfuzzer_sys::fuzz_target!(|data: &[u8]| {
+	// The first byte is the size of the chunks that the rest of the input is fed to the codec in
+	let (chunk_size, data) = match data.split_first() {
+		Some((chunk_size, data)) => (std::cmp::max(usize::from(*chunk_size), 1), data),
+		None => return,
+	};
+
+	let mut codec: mqtt::proto::PacketCodec = Default::default();
+	let mut bytes = bytes::BytesMut::new();
+
+	for chunk in data.chunks(chunk_size) {
+		bytes.extend_from_slice(chunk);
+
+		loop {
+			match codec.decode(&mut bytes) {
+				Ok(Some(_)) => (),
+				Ok(None) => break,
+
+				// The client drops the connection after a decode error, so there is nothing more to decode
+				Err(_) => return,
+			}
+		}
+	}
+});
@@ -21,7 +21,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 			username: Some("username".to_string()),
 			password: Some("password".to_string()),
 			will: Some(mqtt::proto::Publication {
-				topic_name: "will-topic".to_string(),
+				topic_name: "will-topic".parse().unwrap(),
 				qos: mqtt::proto::QoS::ExactlyOnce,
 				retain: true,
 				payload: b"\x00\x01\x02\xFF\xFE\xFD"[..].into(),
@@ -71,7 +71,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 			packet_identifier: mqtt::proto::PacketIdentifier::new(5).unwrap(),
 			subscribe_to: vec![
 				mqtt::proto::SubscribeTo {
-					topic_filter: "subscribe-topic".to_string(),
+					topic_filter: "subscribe-topic".parse().unwrap(),
 					qos: mqtt::proto::QoS::ExactlyOnce,
 				},
 			],
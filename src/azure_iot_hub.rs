@@ -0,0 +1,284 @@
+/*!
+ * Credentials for connecting to Azure IoT Hub as a device or module, using shared access signature (SAS) tokens.
+ *
+ * [`SasTokenProvider`] is a [`CredentialsProvider`](crate::CredentialsProvider) that generates a fresh SAS token for every connection,
+ * with the username that IoT Hub expects. Since IoT Hub disconnects the client when the token of its connection expires, use
+ * [`SasTokenProvider::io_source`] to also close every connection a little before its token expires, so that the client reconnects
+ * with a new token on its own schedule.
+ *
+ * ```no_run
+ * # use futures::Future;
+ * let provider =
+ *     mqtt::azure_iot_hub::SasTokenProvider::from_connection_string("HostName=myhub.azure-devices.net;DeviceId=my-device;SharedAccessKey=...")
+ *     .unwrap();
+ *
+ * let addr = "127.0.0.1:8883".parse().unwrap();
+ * let io_source = provider.io_source(move || tokio::net::TcpStream::connect(&addr).map(|io| (io, None))); // IoT Hub requires TLS
+ *
+ * let mut client = mqtt::Client::new(
+ *     Some(provider.identity().client_id()),
+ *     None,
+ *     None,
+ *     io_source,
+ *     std::time::Duration::from_secs(30),
+ *     std::time::Duration::from_secs(240),
+ * );
+ * client.set_credentials_provider(provider);
+ * ```
+ *
+ * This module is only available with the `azure-iot-hub` feature.
+ */
+
+/// The API version that the username advertises to IoT Hub
+const API_VERSION: &str = "2021-04-12";
+
+/// The default lifetime of the generated SAS tokens
+const DEFAULT_TOKEN_LIFETIME: std::time::Duration = std::time::Duration::from_hours(1);
+
+/// The identity of a device, or of a module of a device, in an IoT hub
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Identity {
+	/// The hostname of the IoT hub, like `myhub.azure-devices.net`
+	pub hub_hostname: String,
+
+	pub device_id: String,
+
+	pub module_id: Option<String>,
+}
+
+impl Identity {
+	/// The client ID that the device or module must connect with, ie `{device_id}` or `{device_id}/{module_id}`
+	pub fn client_id(&self) -> String {
+		match &self.module_id {
+			Some(module_id) => format!("{}/{}", self.device_id, module_id),
+			None => self.device_id.clone(),
+		}
+	}
+
+	/// The username that the device or module must connect with, ie `{hub_hostname}/{client_id}/?api-version=...`
+	pub fn username(&self) -> String {
+		format!("{}/{}/?api-version={}", self.hub_hostname, self.client_id(), API_VERSION)
+	}
+
+	/// The resource URI that SAS tokens for the device or module are scoped to,
+	/// ie `{hub_hostname}/devices/{device_id}` or `{hub_hostname}/devices/{device_id}/modules/{module_id}`
+	pub fn resource_uri(&self) -> String {
+		match &self.module_id {
+			Some(module_id) => format!("{}/devices/{}/modules/{}", self.hub_hostname, self.device_id, module_id),
+			None => format!("{}/devices/{}", self.hub_hostname, self.device_id),
+		}
+	}
+}
+
+/// A [`CredentialsProvider`](crate::CredentialsProvider) that generates a SAS token for every connection to IoT Hub.
+///
+/// Every token is valid for the token lifetime from the time that it is generated. The username is the [`Identity::username`].
+#[derive(Clone)]
+pub struct SasTokenProvider {
+	identity: Identity,
+	key: Vec<u8>,
+	policy_name: Option<String>,
+	token_lifetime: std::time::Duration,
+}
+
+impl SasTokenProvider {
+	/// Creates a provider for the given identity, that signs tokens with the given base64-encoded shared access key.
+	pub fn new(identity: Identity, key: &str) -> Result<Self, SasTokenProviderError> {
+		let key = base64::Engine::decode(&base64::engine::general_purpose::STANDARD, key).map_err(SasTokenProviderError::InvalidKey)?;
+
+		Ok(SasTokenProvider {
+			identity,
+			key,
+			policy_name: None,
+			token_lifetime: DEFAULT_TOKEN_LIFETIME,
+		})
+	}
+
+	/// Creates a provider from a device or module connection string, like
+	/// `HostName=myhub.azure-devices.net;DeviceId=my-device;SharedAccessKey=...` as shown by the Azure portal.
+	///
+	/// The optional `ModuleId` field makes the identity a module's, and the optional `SharedAccessKeyName` field sets the policy name.
+	/// Other fields, like `GatewayHostName`, are ignored.
+	pub fn from_connection_string(connection_string: &str) -> Result<Self, SasTokenProviderError> {
+		let mut hub_hostname = None;
+		let mut device_id = None;
+		let mut module_id = None;
+		let mut key = None;
+		let mut policy_name = None;
+
+		for field in connection_string.split(';').filter(|field| !field.is_empty()) {
+			let (name, value) = match field.find('=') {
+				Some(index) => (&field[..index], field[index + 1..].to_owned()),
+				None => return Err(SasTokenProviderError::MalformedConnectionStringField(field.to_owned())),
+			};
+			match name {
+				"HostName" => hub_hostname = Some(value),
+				"DeviceId" => device_id = Some(value),
+				"ModuleId" => module_id = Some(value),
+				"SharedAccessKey" => key = Some(value),
+				"SharedAccessKeyName" => policy_name = Some(value),
+				_ => (),
+			}
+		}
+
+		let identity = Identity {
+			hub_hostname: hub_hostname.ok_or(SasTokenProviderError::MissingConnectionStringField("HostName"))?,
+			device_id: device_id.ok_or(SasTokenProviderError::MissingConnectionStringField("DeviceId"))?,
+			module_id,
+		};
+		let key = key.ok_or(SasTokenProviderError::MissingConnectionStringField("SharedAccessKey"))?;
+
+		let mut provider = SasTokenProvider::new(identity, &key)?;
+		provider.set_policy_name(policy_name);
+		Ok(provider)
+	}
+
+	pub fn identity(&self) -> &Identity {
+		&self.identity
+	}
+
+	/// Sets the name of the shared access policy that the key belongs to, for keys of the hub rather than of the device or module.
+	///
+	/// Defaults to `None`, ie the key is the device's or module's own key.
+	pub fn set_policy_name(&mut self, policy_name: Option<String>) {
+		self.policy_name = policy_name;
+	}
+
+	/// Sets how long every generated token is valid for.
+	///
+	/// Defaults to one hour.
+	pub fn set_token_lifetime(&mut self, token_lifetime: std::time::Duration) {
+		self.token_lifetime = token_lifetime;
+	}
+
+	/// Wraps the given `io_source` so that every connection is closed once nine tenths of the token lifetime have elapsed,
+	/// and the client reconnects with a new token before IoT Hub disconnects it for using an expired one.
+	pub fn io_source<S>(&self, io_source: S) -> crate::expiring::ExpiringIoSource<S> {
+		crate::expiring::ExpiringIoSource::new(io_source, self.token_lifetime * 9 / 10)
+	}
+
+	/// Generates a token that expires at the given time.
+	pub fn token(&self, expiry: std::time::SystemTime) -> String {
+		let expiry = expiry.duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_secs();
+		sas_token(&self.identity.resource_uri(), &self.key, self.policy_name.as_ref().map(AsRef::as_ref), expiry)
+	}
+}
+
+impl crate::CredentialsProvider for SasTokenProvider {
+	type Future = futures::future::FutureResult<crate::Credentials, std::convert::Infallible>;
+
+	fn get(&mut self) -> Self::Future {
+		let token = self.token(std::time::SystemTime::now() + self.token_lifetime);
+		futures::future::ok(crate::Credentials {
+			username: Some(self.identity.username()),
+			password: Some(token),
+		})
+	}
+}
+
+impl std::fmt::Debug for SasTokenProvider {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("SasTokenProvider")
+			.field("identity", &self.identity)
+			.field("policy_name", &self.policy_name)
+			.field("token_lifetime", &self.token_lifetime)
+			.finish_non_exhaustive()
+	}
+}
+
+/// Generates a SAS token for the given resource URI, signed with the given key, that expires at the given number of seconds since the UNIX epoch.
+fn sas_token(resource_uri: &str, key: &[u8], policy_name: Option<&str>, expiry: u64) -> String {
+	let resource_uri = crate::percent_encoding::encode(resource_uri);
+
+	let mut mac: hmac::Hmac<sha2::Sha256> = hmac::Mac::new_from_slice(key).expect("HMAC accepts keys of any length");
+	hmac::Mac::update(&mut mac, format!("{}\n{}", resource_uri, expiry).as_bytes());
+	let signature = base64::Engine::encode(&base64::engine::general_purpose::STANDARD, hmac::Mac::finalize(mac).into_bytes());
+
+	let mut token = format!("SharedAccessSignature sr={}&sig={}&se={}", resource_uri, crate::percent_encoding::encode(&signature), expiry);
+	if let Some(policy_name) = policy_name {
+		token.push_str("&skn=");
+		token.push_str(&crate::percent_encoding::encode(policy_name));
+	}
+	token
+}
+
+/// An error from creating a [`SasTokenProvider`]
+#[derive(Debug)]
+pub enum SasTokenProviderError {
+	InvalidKey(base64::DecodeError),
+	MalformedConnectionStringField(String),
+	MissingConnectionStringField(&'static str),
+}
+
+impl std::fmt::Display for SasTokenProviderError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			SasTokenProviderError::InvalidKey(err) => write!(f, "shared access key is not valid base64: {}", err),
+			SasTokenProviderError::MalformedConnectionStringField(name) => write!(f, "connection string field {:?} has no value", name),
+			SasTokenProviderError::MissingConnectionStringField(name) => write!(f, "connection string does not have a {:?} field", name),
+		}
+	}
+}
+
+impl std::error::Error for SasTokenProviderError {
+	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+		match self {
+			SasTokenProviderError::InvalidKey(err) => Some(err),
+			SasTokenProviderError::MalformedConnectionStringField(_) |
+			SasTokenProviderError::MissingConnectionStringField(_) => None,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	#[test]
+	fn identity() {
+		let mut identity = super::Identity {
+			hub_hostname: "myhub.azure-devices.net".to_owned(),
+			device_id: "my-device".to_owned(),
+			module_id: None,
+		};
+		assert_eq!(identity.client_id(), "my-device");
+		assert_eq!(identity.username(), "myhub.azure-devices.net/my-device/?api-version=2021-04-12");
+		assert_eq!(identity.resource_uri(), "myhub.azure-devices.net/devices/my-device");
+
+		identity.module_id = Some("my-module".to_owned());
+		assert_eq!(identity.client_id(), "my-device/my-module");
+		assert_eq!(identity.username(), "myhub.azure-devices.net/my-device/my-module/?api-version=2021-04-12");
+		assert_eq!(identity.resource_uri(), "myhub.azure-devices.net/devices/my-device/modules/my-module");
+	}
+
+	#[test]
+	fn token() {
+		let provider =
+			super::SasTokenProvider::from_connection_string(
+				"HostName=myhub.azure-devices.net;DeviceId=my-device;SharedAccessKey=MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY=",
+			).unwrap();
+
+		assert_eq!(
+			provider.token(std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_600_000_000)),
+			"SharedAccessSignature sr=myhub.azure-devices.net%2Fdevices%2Fmy-device&sig=%2BT7fSeYRgwPN72Tu%2F6T2HEIEFyq3CVTUxPa%2FdXyuhtU%3D&se=1600000000",
+		);
+	}
+
+	#[test]
+	fn from_connection_string() {
+		match super::SasTokenProvider::from_connection_string("HostName=myhub.azure-devices.net;DeviceId=my-device") {
+			Err(super::SasTokenProviderError::MissingConnectionStringField("SharedAccessKey")) => (),
+			result => panic!("{:?}", result),
+		}
+
+		match super::SasTokenProvider::from_connection_string("HostName=myhub.azure-devices.net;DeviceId;SharedAccessKey=AAAA") {
+			Err(super::SasTokenProviderError::MalformedConnectionStringField(ref name)) if name == "DeviceId" => (),
+			result => panic!("{:?}", result),
+		}
+
+		let provider =
+			super::SasTokenProvider::from_connection_string(
+				"HostName=myhub.azure-devices.net;DeviceId=my-device;ModuleId=my-module;SharedAccessKeyName=my-policy;SharedAccessKey=AAAA;GatewayHostName=edge",
+			).unwrap();
+		assert_eq!(provider.identity().client_id(), "my-device/my-module");
+		assert!(provider.token(std::time::UNIX_EPOCH).ends_with("&se=0&skn=my-policy"));
+	}
+}
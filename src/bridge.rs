@@ -0,0 +1,450 @@
+/*!
+ * A [`Bridge`] that forwards publications between two servers, like an edge broker and a cloud broker.
+ *
+ * The bridge owns a client for each server, called the local and the remote client. Publications are forwarded according to [`BridgeRule`]s,
+ * which are modeled after the bridge configuration of common brokers: a rule has a topic pattern, the [`Direction`] to forward publications in,
+ * a maximum quality of service, and a prefix for each side. The bridge subscribes to `{local_prefix}{pattern}` with the local client
+ * and to `{remote_prefix}{pattern}` with the remote client, depending on the direction, and replaces one prefix with the other
+ * in the topic names of the publications it forwards.
+ *
+ * ```no_run
+ * # use futures::{ Future, Stream };
+ * # let local_addr = "127.0.0.1:1883".parse().unwrap();
+ * # let remote_addr = "192.0.2.1:1883".parse().unwrap();
+ * # let local_io_source = move || tokio::net::TcpStream::connect(&local_addr).map(|io| (io, None));
+ * # let remote_io_source = move || tokio::net::TcpStream::connect(&remote_addr).map(|io| (io, None));
+ * let local = mqtt::Client::new(Some("bridge".to_owned()), None, None, local_io_source, std::time::Duration::from_secs(30), std::time::Duration::from_secs(240));
+ * let remote = mqtt::Client::new(Some("edge-1".to_owned()), None, None, remote_io_source, std::time::Duration::from_secs(30), std::time::Duration::from_secs(240));
+ * let mut bridge = mqtt::bridge::Bridge::new(local, remote);
+ *
+ * // Telemetry goes from `sensors/...` on the edge broker to `edge-1/sensors/...` in the cloud, with at most QoS 0
+ * let mut telemetry = mqtt::bridge::BridgeRule::new("sensors/#", mqtt::bridge::Direction::Out);
+ * telemetry.set_remote_prefix("edge-1/");
+ * telemetry.set_max_qos(mqtt::proto::QoS::AtMostOnce);
+ * bridge.add_rule(telemetry).unwrap();
+ *
+ * // Commands go from `edge-1/commands/...` in the cloud to `commands/...` on the edge broker
+ * let mut commands = mqtt::bridge::BridgeRule::new("commands/#", mqtt::bridge::Direction::In);
+ * commands.set_remote_prefix("edge-1/");
+ * bridge.add_rule(commands).unwrap();
+ *
+ * tokio::run(bridge.for_each(|_event| Ok(())).map_err(|err| eprintln!("bridge failed: {}", err)));
+ * ```
+ */
+
+use futures::{ Future, Stream };
+
+/// The maximum number of forwarded publications that the bridge remembers to recognize them when the server sends them back.
+const MAX_ECHOES: usize = 1024;
+
+/// Forwards publications between a local and a remote [`Client`](crate::Client).
+///
+/// The bridge is a [`Stream`] of the events of both clients, and must be polled for it to forward anything.
+/// Publications that are forwarded are not yielded. Publications that don't match any rule, like those of subscriptions that were made
+/// with the clients before they were passed to the bridge, as well as all other events, are yielded as is. Errors of either client
+/// are yielded too, and the bridge can continue to be polled after them. The stream ends when the events of either client end.
+pub struct Bridge<L, R> where L: crate::IoSource, R: crate::IoSource {
+	local: crate::Client<L>,
+	remote: crate::Client<R>,
+	rules: Vec<Rule>,
+
+	/// Publications that were forwarded to the local server, and that it will send back since they match a rule that forwards them to the remote server
+	local_echoes: std::collections::VecDeque<(String, bytes::Bytes)>,
+
+	/// Publications that were forwarded to the remote server, and that it will send back since they match a rule that forwards them to the local server
+	remote_echoes: std::collections::VecDeque<(String, bytes::Bytes)>,
+
+	publishes: futures::stream::FuturesUnordered<crate::PublishFuture>,
+
+	topic_interner: Option<crate::topic::TopicInterner>,
+
+	/// Reused to build the topic names of forwarded publications, when they are interned
+	topic_name_buffer: String,
+}
+
+impl<L, R> Bridge<L, R> where L: crate::IoSource, R: crate::IoSource {
+	/// Creates a bridge between the given clients.
+	///
+	/// Rules are added with [`Bridge::add_rule`].
+	pub fn new(local: crate::Client<L>, remote: crate::Client<R>) -> Self {
+		Bridge {
+			local,
+			remote,
+			rules: vec![],
+			local_echoes: Default::default(),
+			remote_echoes: Default::default(),
+			publishes: futures::stream::FuturesUnordered::new(),
+			topic_interner: None,
+			topic_name_buffer: String::new(),
+		}
+	}
+
+	/// Adds the given rule, and subscribes to its topic filters.
+	///
+	/// A publication is forwarded according to the first rule that matches it, in the order the rules were added.
+	pub fn add_rule(&mut self, rule: BridgeRule) -> Result<(), RuleError> {
+		let local_topic_filter = crate::topic::TopicFilter::new(format!("{}{}", rule.local_prefix, rule.pattern)).map_err(RuleError::InvalidTopicFilter)?;
+		let remote_topic_filter = crate::topic::TopicFilter::new(format!("{}{}", rule.remote_prefix, rule.pattern)).map_err(RuleError::InvalidTopicFilter)?;
+
+		if rule.direction.forwards_out() {
+			self.local.subscribe(crate::proto::SubscribeTo { topic_filter: local_topic_filter.clone(), qos: rule.max_qos }).map_err(RuleError::Subscribe)?;
+		}
+
+		if rule.direction.forwards_in() {
+			self.remote.subscribe(crate::proto::SubscribeTo { topic_filter: remote_topic_filter.clone(), qos: rule.max_qos }).map_err(RuleError::Subscribe)?;
+		}
+
+		self.rules.push(Rule {
+			inner: rule,
+			local_topic_filter,
+			remote_topic_filter,
+		});
+		Ok(())
+	}
+
+	/// Sets the interner for the topic names of forwarded publications.
+	///
+	/// Without one, every forwarded publication gets a newly allocated topic name. With one, the publications that are forwarded to the same topic
+	/// share its topic name. This is worthwhile when the rules match a fixed set of topics.
+	///
+	/// Defaults to `None`.
+	pub fn set_topic_interner(&mut self, topic_interner: Option<crate::topic::TopicInterner>) {
+		self.topic_interner = topic_interner;
+	}
+
+	/// The local client, for example to get a handle to it
+	pub fn local(&self) -> &crate::Client<L> {
+		&self.local
+	}
+
+	/// The remote client, for example to get a handle to it
+	pub fn remote(&self) -> &crate::Client<R> {
+		&self.remote
+	}
+}
+
+impl<L, R> Stream for Bridge<L, R>
+where
+	L: crate::IoSource,
+	<<L as crate::IoSource>::Future as Future>::Error: std::fmt::Display,
+	R: crate::IoSource,
+	<<R as crate::IoSource>::Future as Future>::Error: std::fmt::Display,
+{
+	type Item = BridgeEvent;
+	type Error = BridgeError;
+
+	fn poll(&mut self) -> futures::Poll<Option<Self::Item>, Self::Error> {
+		loop {
+			let mut idle = true;
+
+			match self.local.poll().map_err(BridgeError::Local)? {
+				futures::Async::Ready(Some(crate::Event::Publication(publication))) => {
+					idle = false;
+
+					if let Some(publication) = forward(
+						&self.rules,
+						Side::Local,
+						publication,
+						&mut self.local_echoes,
+						&mut self.remote,
+						&mut self.remote_echoes,
+						&mut self.publishes,
+						self.topic_interner.as_ref(),
+						&mut self.topic_name_buffer,
+					) {
+						return Ok(futures::Async::Ready(Some(BridgeEvent::Local(crate::Event::Publication(publication)))));
+					}
+				},
+
+				futures::Async::Ready(Some(event)) => return Ok(futures::Async::Ready(Some(BridgeEvent::Local(event)))),
+
+				futures::Async::Ready(None) => return Ok(futures::Async::Ready(None)),
+
+				futures::Async::NotReady => (),
+			}
+
+			match self.remote.poll().map_err(BridgeError::Remote)? {
+				futures::Async::Ready(Some(crate::Event::Publication(publication))) => {
+					idle = false;
+
+					if let Some(publication) = forward(
+						&self.rules,
+						Side::Remote,
+						publication,
+						&mut self.remote_echoes,
+						&mut self.local,
+						&mut self.local_echoes,
+						&mut self.publishes,
+						self.topic_interner.as_ref(),
+						&mut self.topic_name_buffer,
+					) {
+						return Ok(futures::Async::Ready(Some(BridgeEvent::Remote(crate::Event::Publication(publication)))));
+					}
+				},
+
+				futures::Async::Ready(Some(event)) => return Ok(futures::Async::Ready(Some(BridgeEvent::Remote(event)))),
+
+				futures::Async::Ready(None) => return Ok(futures::Async::Ready(None)),
+
+				futures::Async::NotReady => (),
+			}
+
+			loop {
+				match self.publishes.poll() {
+					Ok(futures::Async::Ready(Some(()))) => (),
+					Ok(futures::Async::Ready(None) | futures::Async::NotReady) => break,
+					Err(err) => log::warn!("could not forward publication: {}", err),
+				}
+			}
+
+			if idle {
+				return Ok(futures::Async::NotReady);
+			}
+		}
+	}
+}
+
+impl<L, R> std::fmt::Debug for Bridge<L, R> where L: crate::IoSource, R: crate::IoSource {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("Bridge")
+			.field("rules", &self.rules.iter().map(|rule| &rule.inner).collect::<Vec<_>>())
+			.finish_non_exhaustive()
+	}
+}
+
+/// Forwards the given publication that was received from one side of the bridge to the other side, if it matches a rule that forwards from that side.
+///
+/// Returns the publication if it does not match any rule.
+fn forward<IoS>(
+	rules: &[Rule],
+	from: Side,
+	publication: crate::ReceivedPublication,
+	from_echoes: &mut std::collections::VecDeque<(String, bytes::Bytes)>,
+	to: &mut crate::Client<IoS>,
+	to_echoes: &mut std::collections::VecDeque<(String, bytes::Bytes)>,
+	publishes: &mut futures::stream::FuturesUnordered<crate::PublishFuture>,
+	topic_interner: Option<&crate::topic::TopicInterner>,
+	topic_name_buffer: &mut String,
+) -> Option<crate::ReceivedPublication> where IoS: crate::IoSource {
+	if let Some(position) = from_echoes.iter().position(|(topic_name, payload)| *topic_name == publication.topic_name && *payload == publication.payload) {
+		// The bridge forwarded this publication to this side itself
+		let _ = from_echoes.remove(position);
+		return None;
+	}
+
+	let rule = match rules.iter().find(|rule| rule.forwards_from(from) && rule.topic_filter(from).matches(&publication.topic_name)) {
+		Some(rule) => rule,
+		None => return Some(publication),
+	};
+
+	let to_side = from.other();
+	let topic_name_suffix = &publication.topic_name[rule.prefix(from).len()..];
+	let topic_name = match topic_interner {
+		Some(topic_interner) => {
+			topic_name_buffer.clear();
+			topic_name_buffer.push_str(rule.prefix(to_side));
+			topic_name_buffer.push_str(topic_name_suffix);
+			topic_interner.intern(topic_name_buffer)
+		},
+
+		None => crate::topic::TopicName::new(format!("{}{}", rule.prefix(to_side), topic_name_suffix)),
+	};
+	let topic_name = match topic_name {
+		Ok(topic_name) => topic_name,
+		Err(err) => {
+			log::warn!("could not forward publication of {:?}: {}", publication.topic_name, err);
+			return None;
+		},
+	};
+
+	// The other side's server sends the publication back to the bridge if it matches a rule that forwards from that side
+	if rules.iter().any(|rule| rule.forwards_from(to_side) && rule.topic_filter(to_side).matches(topic_name.as_str())) {
+		if to_echoes.len() == MAX_ECHOES {
+			let _ = to_echoes.pop_front();
+		}
+		to_echoes.push_back((topic_name.as_str().to_owned(), publication.payload.clone()));
+	}
+
+	publishes.push(to.publish(crate::proto::Publication {
+		topic_name,
+		qos: std::cmp::min(publication.qos, rule.inner.max_qos),
+		retain: publication.retain,
+		payload: publication.payload,
+	}));
+	None
+}
+
+/// A rule for forwarding publications with a [`Bridge`]
+#[derive(Clone, Debug)]
+pub struct BridgeRule {
+	pattern: String,
+	direction: Direction,
+	max_qos: crate::proto::QoS,
+	local_prefix: String,
+	remote_prefix: String,
+}
+
+impl BridgeRule {
+	/// Creates a rule that forwards the publications whose topic names match the given pattern in the given direction.
+	///
+	/// The pattern is a topic filter, and can contain wildcards.
+	pub fn new(pattern: impl Into<String>, direction: Direction) -> Self {
+		BridgeRule {
+			pattern: pattern.into(),
+			direction,
+			max_qos: crate::proto::QoS::ExactlyOnce,
+			local_prefix: String::new(),
+			remote_prefix: String::new(),
+		}
+	}
+
+	/// Sets the maximum quality of service of the forwarded publications. Publications with a higher quality of service
+	/// are forwarded with this one instead. This is also the quality of service of the rule's subscriptions.
+	///
+	/// Defaults to [`QoS::ExactlyOnce`](crate::proto::QoS::ExactlyOnce), ie publications are forwarded with their own quality of service.
+	pub fn set_max_qos(&mut self, max_qos: crate::proto::QoS) {
+		self.max_qos = max_qos;
+	}
+
+	/// Sets the prefix of the rule's topic names on the local server. If the prefix is meant to be a separate topic level,
+	/// it must end with `/`.
+	///
+	/// Defaults to an empty prefix.
+	pub fn set_local_prefix(&mut self, local_prefix: impl Into<String>) {
+		self.local_prefix = local_prefix.into();
+	}
+
+	/// Sets the prefix of the rule's topic names on the remote server. If the prefix is meant to be a separate topic level,
+	/// it must end with `/`.
+	///
+	/// Defaults to an empty prefix.
+	pub fn set_remote_prefix(&mut self, remote_prefix: impl Into<String>) {
+		self.remote_prefix = remote_prefix.into();
+	}
+}
+
+/// The direction that a [`BridgeRule`] forwards publications in
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Direction {
+	/// From the local server to the remote server
+	Out,
+
+	/// From the remote server to the local server
+	In,
+
+	/// Both ways. The bridge recognizes the publications that it forwarded when the server sends them back, and does not forward them again.
+	Both,
+}
+
+impl Direction {
+	fn forwards_out(self) -> bool {
+		matches!(self, Direction::Out | Direction::Both)
+	}
+
+	fn forwards_in(self) -> bool {
+		matches!(self, Direction::In | Direction::Both)
+	}
+}
+
+/// A rule that has been added to a bridge, with its validated topic filters
+#[derive(Debug)]
+struct Rule {
+	inner: BridgeRule,
+	local_topic_filter: crate::topic::TopicFilter,
+	remote_topic_filter: crate::topic::TopicFilter,
+}
+
+impl Rule {
+	fn forwards_from(&self, side: Side) -> bool {
+		match side {
+			Side::Local => self.inner.direction.forwards_out(),
+			Side::Remote => self.inner.direction.forwards_in(),
+		}
+	}
+
+	fn topic_filter(&self, side: Side) -> &crate::topic::TopicFilter {
+		match side {
+			Side::Local => &self.local_topic_filter,
+			Side::Remote => &self.remote_topic_filter,
+		}
+	}
+
+	fn prefix(&self, side: Side) -> &str {
+		match side {
+			Side::Local => &self.inner.local_prefix,
+			Side::Remote => &self.inner.remote_prefix,
+		}
+	}
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Side {
+	Local,
+	Remote,
+}
+
+impl Side {
+	fn other(self) -> Self {
+		match self {
+			Side::Local => Side::Remote,
+			Side::Remote => Side::Local,
+		}
+	}
+}
+
+/// An event of one of the clients of a [`Bridge`]
+#[derive(Debug, PartialEq, Eq)]
+pub enum BridgeEvent {
+	Local(crate::Event),
+	Remote(crate::Event),
+}
+
+/// An error of one of the clients of a [`Bridge`]
+#[derive(Debug)]
+pub enum BridgeError {
+	Local(crate::Error),
+	Remote(crate::Error),
+}
+
+impl std::fmt::Display for BridgeError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			BridgeError::Local(err) => write!(f, "local client failed: {}", err),
+			BridgeError::Remote(err) => write!(f, "remote client failed: {}", err),
+		}
+	}
+}
+
+impl std::error::Error for BridgeError {
+	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+		match self {
+			BridgeError::Local(err) |
+			BridgeError::Remote(err) => Some(err),
+		}
+	}
+}
+
+/// An error from [`Bridge::add_rule`]
+#[derive(Debug)]
+pub enum RuleError {
+	InvalidTopicFilter(crate::topic::TopicError),
+	Subscribe(crate::UpdateSubscriptionError),
+}
+
+impl std::fmt::Display for RuleError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			RuleError::InvalidTopicFilter(err) => write!(f, "rule has an invalid topic filter: {}", err),
+			RuleError::Subscribe(err) => write!(f, "could not subscribe to the rule's topic filter: {}", err),
+		}
+	}
+}
+
+impl std::error::Error for RuleError {
+	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+		match self {
+			RuleError::InvalidTopicFilter(err) => Some(err),
+			RuleError::Subscribe(err) => Some(err),
+		}
+	}
+}
@@ -0,0 +1,340 @@
+/*!
+ * Records every packet that a [`Client`](crate::Client) sends and receives, with timestamps, for post-mortem analysis and replay.
+ *
+ * Set a capture with [`Client::set_packet_capture`](crate::Client::set_packet_capture). Captures in the [`CaptureFormat::Binary`] format
+ * can be read back with a [`CaptureReader`], for example to feed the packets that a server sent into a replay harness.
+ */
+
+/// The format that a [`PacketCapture`] writes its records in
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CaptureFormat {
+	/// Each record is:
+	///
+	/// - one byte for the direction of the packet, `0x00` for [`Direction::Sent`] and `0x01` for [`Direction::Received`],
+	/// - eight bytes for the time that the packet was sent or received, as the number of microseconds since the UNIX epoch, big-endian,
+	/// - the packet, encoded as it was on the wire.
+	///
+	/// Captures in this format can be read with a [`CaptureReader`].
+	Binary,
+
+	/// Each record is a JSON object on its own line, like
+	///
+	/// ```json
+	/// {"timestamp_us":1571070000000000,"direction":"sent","type":"PINGREQ","bytes":"c000"}
+	/// ```
+	///
+	/// `bytes` is the packet, encoded as it was on the wire, in hex.
+	JsonLines,
+}
+
+/// Whether a packet was sent to the server or received from it
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Direction {
+	Sent,
+	Received,
+}
+
+/// Records the packets of a client to a writer, like a file.
+///
+/// Each record is flushed as soon as it is written, so that the capture is complete even if the process crashes.
+/// Errors from writing records are logged and otherwise ignored, so that they do not affect the client's connection.
+pub struct PacketCapture {
+	format: CaptureFormat,
+	writer: std::sync::Mutex<Box<dyn std::io::Write + Send>>,
+}
+
+impl PacketCapture {
+	/// Creates a capture that writes records to the given writer in the given format.
+	pub fn new<W>(writer: W, format: CaptureFormat) -> Self where W: std::io::Write + Send + 'static {
+		PacketCapture {
+			format,
+			writer: std::sync::Mutex::new(Box::new(writer)),
+		}
+	}
+
+	/// Creates a capture that writes records to the file at the given path in the given format. The file is truncated if it already exists.
+	pub fn create<P>(path: P, format: CaptureFormat) -> std::io::Result<Self> where P: AsRef<std::path::Path> {
+		let file = std::fs::File::create(path)?;
+		Ok(PacketCapture::new(std::io::BufWriter::new(file), format))
+	}
+
+	pub(crate) fn record(&self, direction: Direction, packet: &crate::proto::Packet) {
+		if let Err(err) = self.try_record(direction, packet) {
+			log::warn!("could not record {} packet in capture: {}", packet.type_name(), err);
+		}
+	}
+
+	fn try_record(&self, direction: Direction, packet: &crate::proto::Packet) -> Result<(), CaptureWriteError> {
+		let timestamp = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default();
+		let timestamp_us = std::convert::TryInto::try_into(timestamp.as_micros()).unwrap_or(u64::max_value());
+
+		let mut packet_bytes = vec![];
+		crate::proto::encode(packet, &mut packet_bytes).map_err(CaptureWriteError::Encode)?;
+
+		let record = match self.format {
+			CaptureFormat::Binary => {
+				let mut record = vec![];
+				record.push(match direction {
+					Direction::Sent => 0x00,
+					Direction::Received => 0x01,
+				});
+				record.extend_from_slice(&timestamp_us.to_be_bytes());
+				record.extend_from_slice(&packet_bytes);
+				record
+			},
+
+			CaptureFormat::JsonLines => {
+				use std::fmt::Write;
+
+				let mut line = String::new();
+				let direction = match direction {
+					Direction::Sent => "sent",
+					Direction::Received => "received",
+				};
+				write!(line, r#"{{"timestamp_us":{},"direction":"{}","type":"{}","bytes":""#, timestamp_us, direction, packet.type_name())
+					.expect("writing to a String cannot fail");
+				for b in packet_bytes {
+					write!(line, "{:02x}", b).expect("writing to a String cannot fail");
+				}
+				line.push_str("\"}\n");
+				line.into_bytes()
+			},
+		};
+
+		// A poisoned writer is still usable. At worst the record that was being written when the lock was poisoned is incomplete.
+		let mut writer = self.writer.lock().unwrap_or_else(std::sync::PoisonError::into_inner);
+		writer.write_all(&record).map_err(CaptureWriteError::Io)?;
+		writer.flush().map_err(CaptureWriteError::Io)?;
+		Ok(())
+	}
+}
+
+impl std::fmt::Debug for PacketCapture {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("PacketCapture")
+			.field("format", &self.format)
+			.finish_non_exhaustive()
+	}
+}
+
+#[derive(Debug)]
+enum CaptureWriteError {
+	Encode(crate::proto::EncodeError),
+	Io(std::io::Error),
+}
+
+impl std::fmt::Display for CaptureWriteError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			CaptureWriteError::Encode(err) => write!(f, "could not encode packet: {}", err),
+			CaptureWriteError::Io(err) => write!(f, "I/O error: {}", err),
+		}
+	}
+}
+
+/// A packet read from a capture by a [`CaptureReader`]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CapturedPacket {
+	/// The time that the packet was sent or received
+	pub timestamp: std::time::SystemTime,
+
+	pub direction: Direction,
+
+	pub packet: crate::proto::Packet,
+}
+
+/// Reads the packets of a capture that was written in the [`CaptureFormat::Binary`] format.
+///
+/// The reader is an iterator of the captured packets. It stops after the first error.
+#[derive(Debug)]
+pub struct CaptureReader<R> {
+	reader: R,
+	failed: bool,
+}
+
+impl<R> CaptureReader<R> where R: std::io::Read {
+	pub fn new(reader: R) -> Self {
+		CaptureReader {
+			reader,
+			failed: false,
+		}
+	}
+
+	fn read_record(&mut self) -> Result<Option<CapturedPacket>, ReadError> {
+		let mut direction = [0_u8; 1];
+		loop {
+			match self.reader.read(&mut direction) {
+				Ok(0) => return Ok(None),
+				Ok(_) => break,
+				Err(ref err) if err.kind() == std::io::ErrorKind::Interrupted => (),
+				Err(err) => return Err(ReadError::Io(err)),
+			}
+		}
+		let direction = match direction[0] {
+			0x00 => Direction::Sent,
+			0x01 => Direction::Received,
+			direction => return Err(ReadError::UnrecognizedDirection(direction)),
+		};
+
+		let mut timestamp_us = [0_u8; 8];
+		self.reader.read_exact(&mut timestamp_us).map_err(ReadError::Io)?;
+		let timestamp = std::time::UNIX_EPOCH + std::time::Duration::from_micros(u64::from_be_bytes(timestamp_us));
+
+		// The packet's fixed header is its type byte followed by its remaining length, encoded in up to four bytes
+		let mut packet_bytes = vec![0_u8; 2];
+		self.reader.read_exact(&mut packet_bytes).map_err(ReadError::Io)?;
+		let mut remaining_length = 0_usize;
+		let mut multiplier = 1_usize;
+		loop {
+			let encoded_byte = packet_bytes[packet_bytes.len() - 1];
+			remaining_length += usize::from(encoded_byte & 0x7F) * multiplier;
+			if encoded_byte & 0x80 == 0 {
+				break;
+			}
+
+			if packet_bytes.len() == 5 {
+				return Err(ReadError::Decode(crate::proto::DecodeError::RemainingLengthTooHigh));
+			}
+
+			multiplier *= 0x80;
+			let mut encoded_byte = [0_u8; 1];
+			self.reader.read_exact(&mut encoded_byte).map_err(ReadError::Io)?;
+			packet_bytes.push(encoded_byte[0]);
+		}
+
+		let header_len = packet_bytes.len();
+		packet_bytes.resize(header_len + remaining_length, 0);
+		self.reader.read_exact(&mut packet_bytes[header_len..]).map_err(ReadError::Io)?;
+
+		match crate::proto::decode(&packet_bytes).map_err(ReadError::Decode)? {
+			Some((packet, _)) => Ok(Some(CapturedPacket { timestamp, direction, packet })),
+			None => Err(ReadError::Decode(crate::proto::DecodeError::IncompletePacket)),
+		}
+	}
+}
+
+impl<R> Iterator for CaptureReader<R> where R: std::io::Read {
+	type Item = Result<CapturedPacket, ReadError>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		if self.failed {
+			return None;
+		}
+
+		match self.read_record() {
+			Ok(Some(captured_packet)) => Some(Ok(captured_packet)),
+			Ok(None) => None,
+			Err(err) => {
+				self.failed = true;
+				Some(Err(err))
+			},
+		}
+	}
+}
+
+/// An error from reading a capture with a [`CaptureReader`]
+#[derive(Debug)]
+pub enum ReadError {
+	Decode(crate::proto::DecodeError),
+	Io(std::io::Error),
+	UnrecognizedDirection(u8),
+}
+
+impl std::fmt::Display for ReadError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			ReadError::Decode(err) => write!(f, "could not decode captured packet: {}", err),
+			ReadError::Io(err) => write!(f, "I/O error: {}", err),
+			ReadError::UnrecognizedDirection(direction) => write!(f, "could not parse direction 0x{:02X}", direction),
+		}
+	}
+}
+
+impl std::error::Error for ReadError {
+	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+		match self {
+			ReadError::Decode(err) => Some(err),
+			ReadError::Io(err) => Some(err),
+			ReadError::UnrecognizedDirection(_) => None,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	/// A writer whose contents can be inspected after it has been given to a `PacketCapture`
+	#[derive(Clone, Default)]
+	struct SharedBuffer(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+	impl std::io::Write for SharedBuffer {
+		fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+			self.0.lock().unwrap().extend_from_slice(buf);
+			Ok(buf.len())
+		}
+
+		fn flush(&mut self) -> std::io::Result<()> {
+			Ok(())
+		}
+	}
+
+	fn packets() -> Vec<(super::Direction, crate::proto::Packet)> {
+		vec![
+			(super::Direction::Sent, crate::proto::Packet::Publish(crate::proto::Publish {
+				packet_identifier_dup_qos: crate::proto::PacketIdentifierDupQoS::AtLeastOnce(crate::proto::PacketIdentifier::new(1).unwrap(), false),
+				retain: false,
+				topic_name: "topic1".to_owned(),
+				payload: vec![0x01; 200].into(),
+			})),
+			(super::Direction::Received, crate::proto::Packet::PubAck(crate::proto::PubAck {
+				packet_identifier: crate::proto::PacketIdentifier::new(1).unwrap(),
+			})),
+			(super::Direction::Sent, crate::proto::Packet::PingReq(crate::proto::PingReq)),
+		]
+	}
+
+	#[test]
+	fn binary_round_trip() {
+		let buffer: SharedBuffer = Default::default();
+		let capture = super::PacketCapture::new(buffer.clone(), super::CaptureFormat::Binary);
+
+		let start = std::time::SystemTime::now();
+		for (direction, packet) in packets() {
+			capture.record(direction, &packet);
+		}
+		let end = std::time::SystemTime::now();
+
+		let buffer = buffer.0.lock().unwrap().clone();
+		let captured_packets: Vec<_> = super::CaptureReader::new(&buffer[..]).collect::<Result<_, _>>().unwrap();
+		assert_eq!(captured_packets.len(), 3);
+		for (captured_packet, (direction, packet)) in captured_packets.into_iter().zip(packets()) {
+			assert_eq!(captured_packet.direction, direction);
+			assert_eq!(captured_packet.packet, packet);
+			assert!(captured_packet.timestamp >= start - std::time::Duration::from_millis(1));
+			assert!(captured_packet.timestamp <= end);
+		}
+
+		// A truncated capture yields the complete records and then an error
+		let mut reader = super::CaptureReader::new(&buffer[..(buffer.len() - 1)]);
+		assert!(reader.next().unwrap().is_ok());
+		assert!(reader.next().unwrap().is_ok());
+		assert!(reader.next().unwrap().is_err());
+		assert!(reader.next().is_none());
+	}
+
+	#[test]
+	fn json_lines() {
+		let buffer: SharedBuffer = Default::default();
+		let capture = super::PacketCapture::new(buffer.clone(), super::CaptureFormat::JsonLines);
+
+		for (direction, packet) in packets() {
+			capture.record(direction, &packet);
+		}
+
+		let buffer = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+		let lines: Vec<_> = buffer.lines().collect();
+		assert_eq!(lines.len(), 3);
+		assert!(lines[1].starts_with(r#"{"timestamp_us":"#), "{}", lines[1]);
+		assert!(lines[1].ends_with(r#","direction":"received","type":"PUBACK","bytes":"40020001"}"#), "{}", lines[1]);
+		assert!(lines[2].ends_with(r#","direction":"sent","type":"PINGREQ","bytes":"c000"}"#), "{}", lines[2]);
+	}
+}
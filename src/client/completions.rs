@@ -0,0 +1,207 @@
+/// A pool of slots that report the outcome of publish requests to their [`PublishFuture`](super::PublishFuture)s.
+///
+/// This takes the place of a oneshot channel per publish request, which would allocate for every publication. A slot is recycled
+/// once both the request and its future are done with it, so the pool only grows to the largest number of publications that were
+/// waiting for their acks at the same time.
+///
+/// Every `PublishHandle` has its own pool, so that handles that are used from different threads don't contend for it.
+#[derive(Clone, Default)]
+pub(super) struct Completions(std::sync::Arc<std::sync::Mutex<Slots>>);
+
+#[derive(Default)]
+struct Slots {
+	slots: Vec<Slot>,
+	free: Vec<usize>,
+}
+
+enum Slot {
+	Free,
+
+	/// Both the sender and the receiver exist. Holds the task of the receiver, if it has been polled.
+	Pending(Option<futures::task::Task>),
+
+	/// The sender completed the request. The receiver still exists.
+	Completed,
+
+	/// The sender was dropped without completing the request. The receiver still exists.
+	Abandoned,
+
+	/// The receiver was dropped. The sender still exists.
+	Canceled,
+}
+
+impl Completions {
+	pub(super) fn pair(&self) -> (CompletionSender, CompletionReceiver) {
+		let mut slots = self.lock();
+
+		let index = match slots.free.pop() {
+			Some(index) => {
+				slots.slots[index] = Slot::Pending(None);
+				index
+			},
+			None => {
+				slots.slots.push(Slot::Pending(None));
+				slots.slots.len() - 1
+			},
+		};
+
+		(
+			CompletionSender { completions: self.clone(), index, completed: false },
+			CompletionReceiver { completions: self.clone(), index },
+		)
+	}
+
+	fn lock(&self) -> std::sync::MutexGuard<'_, Slots> {
+		// Every change to the slots is a single assignment, so a poisoned lock still has consistent slots.
+		self.0.lock().unwrap_or_else(std::sync::PoisonError::into_inner)
+	}
+}
+
+impl std::fmt::Debug for Completions {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		let slots = self.lock();
+		f.debug_struct("Completions")
+			.field("slots", &slots.slots.len())
+			.field("free", &slots.free.len())
+			.finish()
+	}
+}
+
+impl Slots {
+	fn release(&mut self, index: usize) {
+		self.slots[index] = Slot::Free;
+		self.free.push(index);
+	}
+}
+
+/// Completes a publish request. Dropping it without calling [`CompletionSender::complete`] fails the request's future.
+pub(super) struct CompletionSender {
+	completions: Completions,
+	index: usize,
+	completed: bool,
+}
+
+impl CompletionSender {
+	pub(super) fn complete(mut self) {
+		self.completed = true;
+	}
+
+	/// Whether the receiver has been dropped, ie nothing is waiting for the request to complete
+	pub(super) fn is_canceled(&self) -> bool {
+		matches!(self.completions.lock().slots[self.index], Slot::Canceled)
+	}
+}
+
+impl Drop for CompletionSender {
+	fn drop(&mut self) {
+		let mut slots = self.completions.lock();
+
+		match &mut slots.slots[self.index] {
+			slot @ Slot::Pending(_) => {
+				let task = match std::mem::replace(slot, if self.completed { Slot::Completed } else { Slot::Abandoned }) {
+					Slot::Pending(task) => task,
+					_ => unreachable!(),
+				};
+				if let Some(task) = task {
+					task.notify();
+				}
+			},
+
+			Slot::Canceled => slots.release(self.index),
+
+			Slot::Free |
+			Slot::Completed |
+			Slot::Abandoned => unreachable!("slot of a live sender is not pending or canceled"),
+		}
+	}
+}
+
+impl std::fmt::Debug for CompletionSender {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("CompletionSender").field("index", &self.index).finish_non_exhaustive()
+	}
+}
+
+/// Resolves when the publish request has been completed, or fails if it was dropped without being completed
+pub(super) struct CompletionReceiver {
+	completions: Completions,
+	index: usize,
+}
+
+impl futures::Future for CompletionReceiver {
+	type Item = ();
+	type Error = ();
+
+	fn poll(&mut self) -> futures::Poll<Self::Item, Self::Error> {
+		let mut slots = self.completions.lock();
+
+		match &mut slots.slots[self.index] {
+			Slot::Pending(task) => {
+				if !task.as_ref().is_some_and(futures::task::Task::will_notify_current) {
+					*task = Some(futures::task::current());
+				}
+				Ok(futures::Async::NotReady)
+			},
+
+			Slot::Completed => Ok(futures::Async::Ready(())),
+
+			Slot::Abandoned => Err(()),
+
+			Slot::Free |
+			Slot::Canceled => unreachable!("slot of a live receiver is free or canceled"),
+		}
+	}
+}
+
+impl Drop for CompletionReceiver {
+	fn drop(&mut self) {
+		let mut slots = self.completions.lock();
+
+		match slots.slots[self.index] {
+			Slot::Pending(_) => slots.slots[self.index] = Slot::Canceled,
+
+			Slot::Completed |
+			Slot::Abandoned => slots.release(self.index),
+
+			Slot::Free |
+			Slot::Canceled => unreachable!("slot of a live receiver is free or canceled"),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use futures::Future;
+
+	use super::*;
+
+	#[test]
+	fn slots_are_recycled() {
+		let completions: Completions = Default::default();
+
+		let (sender1, mut receiver1) = completions.pair();
+		let (sender2, mut receiver2) = completions.pair();
+		let (sender3, receiver3) = completions.pair();
+		assert_eq!(completions.lock().slots.len(), 3);
+
+		sender1.complete();
+		assert_eq!(receiver1.poll(), Ok(futures::Async::Ready(())));
+
+		drop(sender2);
+		assert_eq!(receiver2.poll(), Err(()));
+
+		assert!(!sender3.is_canceled());
+		drop(receiver3);
+		assert!(sender3.is_canceled());
+
+		// Each slot is only recycled once both of its sides are gone
+		assert!(completions.lock().free.is_empty());
+		drop(receiver1);
+		drop(receiver2);
+		drop(sender3);
+		assert_eq!(completions.lock().free.len(), 3);
+
+		let (_sender, _receiver) = completions.pair();
+		assert_eq!(completions.lock().slots.len(), 3);
+	}
+}
@@ -1,10 +1,24 @@
-use futures::{ Future, Sink, Stream };
+use futures::{ Future, Sink };
 
 #[derive(Debug)]
 pub(super) struct Connect<IoS> where IoS: super::IoSource {
 	io_source: IoS,
+	credentials_provider: Option<super::BoxedCredentialsProvider>,
 	max_back_off: std::time::Duration,
 	current_back_off: std::time::Duration,
+	timeout: Option<std::time::Duration>,
+	timeout_timer: Option<tokio_timer::Delay>,
+	max_incoming_packet_size: Option<usize>,
+	string_validation: crate::proto::StringValidation,
+	metrics: crate::metrics::Metrics,
+	packet_log_format: crate::PacketLogFormat,
+	payload_logging: crate::PayloadLogging,
+	capture: Option<std::sync::Arc<crate::capture::PacketCapture>>,
+	tap: Option<crate::tap::PacketTap>,
+	topic_prefix: Option<crate::topic::TopicName>,
+	flush_policy: crate::FlushPolicy,
+	payload_streaming_threshold: Option<usize>,
+	span: crate::trace::Span,
 	state: State<IoS>,
 }
 
@@ -12,11 +26,17 @@ enum State<IoS> where IoS: super::IoSource {
 	BeginBackOff,
 	EndBackOff(tokio_timer::Delay),
 	BeginConnecting,
-	WaitingForIoToConnect(<IoS as super::IoSource>::Future),
+	WaitingForCredentials(super::BoxedCredentialsFuture),
+	BeginConnectingIo(Option<super::Credentials>),
+	WaitingForIoToConnect {
+		io: <IoS as super::IoSource>::Future,
+		credentials: Option<super::Credentials>,
+	},
 	Framed {
 		framed: crate::logging_framed::LoggingFramed<<IoS as super::IoSource>::Io>,
 		framed_state: FramedState,
 		password: Option<String>,
+		credentials: Option<super::Credentials>,
 	},
 }
 
@@ -38,7 +58,9 @@ impl<IoS> std::fmt::Debug for State<IoS> where IoS: super::IoSource {
 			State::BeginBackOff => f.write_str("BeginBackOff"),
 			State::EndBackOff(_) => f.write_str("EndBackOff"),
 			State::BeginConnecting => f.write_str("BeginConnecting"),
-			State::WaitingForIoToConnect(_) => f.write_str("WaitingForIoToConnect"),
+			State::WaitingForCredentials(_) => f.write_str("WaitingForCredentials"),
+			State::BeginConnectingIo(_) => f.write_str("BeginConnectingIo"),
+			State::WaitingForIoToConnect { .. } => f.write_str("WaitingForIoToConnect"),
 			State::Framed { framed_state, .. } => f.debug_struct("Framed").field("framed_state", framed_state).finish(),
 		}
 	}
@@ -48,15 +70,83 @@ impl<IoS> Connect<IoS> where IoS: super::IoSource {
 	pub(super) fn new(io_source: IoS, max_back_off: std::time::Duration) -> Self {
 		Connect {
 			io_source,
+			credentials_provider: None,
 			max_back_off,
 			current_back_off: std::time::Duration::from_secs(0),
+			timeout: None,
+			timeout_timer: None,
+			max_incoming_packet_size: None,
+			string_validation: Default::default(),
+			metrics: Default::default(),
+			packet_log_format: Default::default(),
+			payload_logging: Default::default(),
+			capture: None,
+			tap: None,
+			topic_prefix: None,
+			flush_policy: Default::default(),
+			payload_streaming_threshold: None,
+			span: crate::trace::Span::connection(),
 			state: State::BeginConnecting,
 		}
 	}
 
 	pub(super) fn reconnect(&mut self) {
+		self.span = crate::trace::Span::connection();
 		self.state = State::BeginBackOff;
 	}
+
+	/// The span of the current connection, including the attempts to establish it
+	pub(super) fn span(&self) -> &crate::trace::Span {
+		&self.span
+	}
+
+	pub(super) fn set_timeout(&mut self, timeout: Option<std::time::Duration>) {
+		self.timeout = timeout;
+	}
+
+	pub(super) fn set_max_incoming_packet_size(&mut self, max_incoming_packet_size: Option<usize>) {
+		self.max_incoming_packet_size = max_incoming_packet_size;
+	}
+
+	pub(super) fn set_string_validation(&mut self, string_validation: crate::proto::StringValidation) {
+		self.string_validation = string_validation;
+	}
+
+	pub(super) fn set_metrics(&mut self, metrics: crate::metrics::Metrics) {
+		self.metrics = metrics;
+	}
+
+	pub(super) fn set_packet_log_format(&mut self, packet_log_format: crate::PacketLogFormat) {
+		self.packet_log_format = packet_log_format;
+	}
+
+	pub(super) fn set_payload_logging(&mut self, payload_logging: crate::PayloadLogging) {
+		self.payload_logging = payload_logging;
+	}
+
+	pub(super) fn set_capture(&mut self, capture: Option<std::sync::Arc<crate::capture::PacketCapture>>) {
+		self.capture = capture;
+	}
+
+	pub(super) fn set_tap(&mut self, tap: Option<crate::tap::PacketTap>) {
+		self.tap = tap;
+	}
+
+	pub(super) fn set_topic_prefix(&mut self, topic_prefix: Option<crate::topic::TopicName>) {
+		self.topic_prefix = topic_prefix;
+	}
+
+	pub(super) fn set_flush_policy(&mut self, flush_policy: crate::FlushPolicy) {
+		self.flush_policy = flush_policy;
+	}
+
+	pub(super) fn set_payload_streaming_threshold(&mut self, payload_streaming_threshold: Option<usize>) {
+		self.payload_streaming_threshold = payload_streaming_threshold;
+	}
+
+	pub(super) fn set_credentials_provider(&mut self, credentials_provider: super::BoxedCredentialsProvider) {
+		self.credentials_provider = Some(credentials_provider);
+	}
 }
 
 impl<IoS> Connect<IoS> where IoS: super::IoSource, <<IoS as super::IoSource>::Future as Future>::Error: std::fmt::Display {
@@ -65,13 +155,41 @@ impl<IoS> Connect<IoS> where IoS: super::IoSource, <<IoS as super::IoSource>::Fu
 		username: Option<&str>,
 		will: Option<&crate::proto::Publication>,
 		client_id: &mut crate::proto::ClientId,
-		keep_alive: std::time::Duration,
+		keep_alive: &mut std::time::Duration,
+		next_keep_alive: &mut Option<std::time::Duration>,
 	) -> futures::Poll<Connected<'a, IoS>, ()> {
 		let state = &mut self.state;
 
 		loop {
 			log::trace!("    {:?}", state);
 
+			// The connect timeout covers the I/O source connecting and the CONNECT / CONNACK exchange.
+			match state {
+				State::WaitingForIoToConnect { .. } |
+				State::Framed { framed_state: FramedState::BeginSendingConnect, .. } |
+				State::Framed { framed_state: FramedState::EndSendingConnect, .. } |
+				State::Framed { framed_state: FramedState::WaitingForConnAck, .. } =>
+					if let Some(timeout_timer) = &mut self.timeout_timer {
+						match timeout_timer.poll().expect("could not poll connect timeout timer") {
+							futures::Async::Ready(()) => {
+								log::warn!("could not connect to server: timed out");
+								self.timeout_timer = None;
+								*state = State::BeginBackOff;
+								continue;
+							},
+
+							futures::Async::NotReady => (),
+						}
+					},
+
+				State::BeginBackOff |
+				State::EndBackOff(_) |
+				State::BeginConnecting |
+				State::WaitingForCredentials(_) |
+				State::BeginConnectingIo(_) |
+				State::Framed { framed_state: FramedState::Connected { .. }, .. } => (),
+			}
+
 			match state {
 				State::BeginBackOff => match self.current_back_off {
 					back_off if back_off.as_secs() == 0 => {
@@ -81,7 +199,7 @@ impl<IoS> Connect<IoS> where IoS: super::IoSource, <<IoS as super::IoSource>::Fu
 
 					back_off => {
 						log::debug!("Backing off for {:?}", back_off);
-						let back_off_deadline = std::time::Instant::now() + back_off;
+						let back_off_deadline = tokio_timer::clock::now() + back_off;
 						self.current_back_off = std::cmp::min(self.max_back_off, self.current_back_off * 2);
 						*state = State::EndBackOff(tokio_timer::Delay::new(back_off_deadline));
 					},
@@ -92,19 +210,51 @@ impl<IoS> Connect<IoS> where IoS: super::IoSource, <<IoS as super::IoSource>::Fu
 					futures::Async::NotReady => return Ok(futures::Async::NotReady),
 				},
 
-				State::BeginConnecting => {
+				State::BeginConnecting => match &mut self.credentials_provider {
+					Some(credentials_provider) => {
+						let credentials = credentials_provider.get();
+						*state = State::WaitingForCredentials(credentials);
+					},
+
+					None => *state = State::BeginConnectingIo(None),
+				},
+
+				State::WaitingForCredentials(credentials) => match credentials.poll() {
+					Ok(futures::Async::Ready(credentials)) => *state = State::BeginConnectingIo(Some(credentials)),
+
+					Ok(futures::Async::NotReady) => return Ok(futures::Async::NotReady),
+
+					Err(err) => {
+						log::warn!("could not get credentials: {}", err);
+						*state = State::BeginBackOff;
+					},
+				},
+
+				State::BeginConnectingIo(credentials) => {
+					let credentials = credentials.take();
+					self.timeout_timer = self.timeout.map(|timeout| tokio_timer::Delay::new(tokio_timer::clock::now() + timeout));
 					let io = self.io_source.connect();
-					*state = State::WaitingForIoToConnect(io);
+					*state = State::WaitingForIoToConnect { io, credentials };
 				},
 
-				State::WaitingForIoToConnect(io) => match io.poll() {
+				State::WaitingForIoToConnect { io, credentials } => match io.poll() {
 					Ok(futures::Async::Ready((io, password))) => {
-						let framed = crate::logging_framed::LoggingFramed::new(io);
+						let mut codec = crate::proto::PacketCodec::new(self.max_incoming_packet_size);
+						codec.set_string_validation(self.string_validation);
+						let mut framed = crate::logging_framed::LoggingFramed::new(io, codec, self.metrics.clone());
+						framed.set_packet_log_format(self.packet_log_format);
+						framed.set_payload_logging(self.payload_logging);
+						framed.set_capture(self.capture.clone());
+						framed.set_tap(self.tap.clone());
+						framed.set_topic_prefix(self.topic_prefix.clone());
+						framed.set_flush_policy(self.flush_policy);
+						framed.set_payload_streaming_threshold(self.payload_streaming_threshold);
 						*state =
 							State::Framed {
 								framed,
 								framed_state: FramedState::BeginSendingConnect,
 								password,
+								credentials: credentials.take(),
 							};
 					},
 
@@ -116,16 +266,29 @@ impl<IoS> Connect<IoS> where IoS: super::IoSource, <<IoS as super::IoSource>::Fu
 					},
 				},
 
-				State::Framed { framed, framed_state: framed_state @ FramedState::BeginSendingConnect, password } => {
+				State::Framed { framed, framed_state: framed_state @ FramedState::BeginSendingConnect, password, credentials } => {
+					// Credentials from the credentials provider, if any, take precedence over the configured username
+					// and the password from the I/O source.
+					let (username, password) = match credentials {
+						Some(super::Credentials { username, password }) => (username.clone(), password.clone()),
+						None => (username.map(ToOwned::to_owned), password.clone()),
+					};
+
+					// A changed keep-alive applies from the next CONNECT on, which the connection then also pings with
+					if let Some(next_keep_alive) = next_keep_alive.take() {
+						log::debug!("keep-alive changed from {:?} to {:?}", keep_alive, next_keep_alive);
+						*keep_alive = next_keep_alive;
+					}
+
 					let packet = crate::proto::Packet::Connect(crate::proto::Connect {
-						username: username.map(ToOwned::to_owned),
-						password: password.clone(),
+						username,
+						password,
 						will: will.cloned(),
 						client_id: client_id.clone(),
-						keep_alive,
+						keep_alive: *keep_alive,
 					});
 
-					match framed.start_send(packet) {
+					match framed.start_send(packet.into()) {
 						Ok(futures::AsyncSink::Ready) => *framed_state = FramedState::EndSendingConnect,
 						Ok(futures::AsyncSink::NotReady(_)) => return Ok(futures::Async::NotReady),
 						Err(err) => {
@@ -144,10 +307,11 @@ impl<IoS> Connect<IoS> where IoS: super::IoSource, <<IoS as super::IoSource>::Fu
 					},
 				},
 
-				State::Framed { framed, framed_state: framed_state @ FramedState::WaitingForConnAck, .. } => match framed.poll() {
-					Ok(futures::Async::Ready(Some(packet))) => match packet {
+				State::Framed { framed, framed_state: framed_state @ FramedState::WaitingForConnAck, .. } => match framed.poll_received() {
+					Ok(futures::Async::Ready(Some(crate::logging_framed::Received::Packet(packet)))) => match packet {
 						crate::proto::Packet::ConnAck(crate::proto::ConnAck { session_present, return_code: crate::proto::ConnectReturnCode::Accepted }) => {
 							self.current_back_off = std::time::Duration::from_secs(0);
+							self.timeout_timer = None;
 
 							let reset_session = match client_id {
 								crate::proto::ClientId::ServerGenerated => true,
@@ -175,6 +339,11 @@ impl<IoS> Connect<IoS> where IoS: super::IoSource, <<IoS as super::IoSource>::Fu
 						},
 					},
 
+					Ok(futures::Async::Ready(Some(received))) => {
+						log::warn!("could not connect to server: expected to receive ConnAck but received {:?}", received);
+						*state = State::BeginBackOff;
+					},
+
 					Ok(futures::Async::Ready(None)) => {
 						log::warn!("could not connect to server: connection closed by server");
 						*state = State::BeginBackOff;
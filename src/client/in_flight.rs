@@ -0,0 +1,152 @@
+/// A map from packet identifiers to the publications that are in flight with them.
+///
+/// [`PacketIdentifiers`](super::PacketIdentifiers) reserves identifiers in increasing order, wrapping around after the largest one,
+/// so the identifiers that are in use at any time fall in a window that starts at the oldest one. This keeps the entries in a ring buffer
+/// that covers that window, with a slot for every identifier in it. Looking up an entry is an index computation instead of a tree search,
+/// and inserting and removing entries doesn't allocate once the buffer has grown to the size of the window.
+///
+/// Identifiers in the window that are used for something else, like SUBSCRIBE packets, leave their slots empty.
+pub(super) struct InFlight<T> {
+	/// The packet identifier of the first slot
+	first: crate::proto::PacketIdentifier,
+
+	/// Neither the first nor the last slot is ever empty
+	slots: std::collections::VecDeque<Option<T>>,
+
+	len: usize,
+}
+
+impl<T> InFlight<T> {
+	pub(super) fn len(&self) -> usize {
+		self.len
+	}
+
+	pub(super) fn get_mut(&mut self, packet_identifier: crate::proto::PacketIdentifier) -> Option<&mut T> {
+		let offset = self.offset(packet_identifier);
+		self.slots.get_mut(offset)?.as_mut()
+	}
+
+	/// Inserts the entry for the given packet identifier, and returns the previous one
+	pub(super) fn insert(&mut self, packet_identifier: crate::proto::PacketIdentifier, value: T) -> Option<T> {
+		if self.slots.is_empty() {
+			self.first = packet_identifier;
+		}
+
+		let offset = self.offset(packet_identifier);
+		if offset >= self.slots.len() {
+			self.slots.resize_with(offset + 1, || None);
+		}
+
+		let previous = self.slots[offset].replace(value);
+		if previous.is_none() {
+			self.len += 1;
+		}
+		previous
+	}
+
+	pub(super) fn remove(&mut self, packet_identifier: crate::proto::PacketIdentifier) -> Option<T> {
+		let offset = self.offset(packet_identifier);
+		let value = self.slots.get_mut(offset)?.take()?;
+		self.len -= 1;
+
+		while let Some(None) = self.slots.back() {
+			self.slots.pop_back();
+		}
+
+		while let Some(None) = self.slots.front() {
+			self.slots.pop_front();
+			self.first += 1;
+		}
+
+		Some(value)
+	}
+
+	/// The entries in the order their packet identifiers were reserved
+	pub(super) fn iter(&self) -> impl Iterator<Item = (crate::proto::PacketIdentifier, &T)> {
+		let first = self.first;
+		self.slots.iter().enumerate().filter_map(move |(offset, value)| Some((packet_identifier_at(first, offset), value.as_ref()?)))
+	}
+
+	/// The entries in the order their packet identifiers were reserved
+	pub(super) fn iter_mut(&mut self) -> impl Iterator<Item = (crate::proto::PacketIdentifier, &mut T)> {
+		let first = self.first;
+		self.slots.iter_mut().enumerate().filter_map(move |(offset, value)| Some((packet_identifier_at(first, offset), value.as_mut()?)))
+	}
+
+	/// The number of slots between the first slot and the slot of the given packet identifier, wrapping around like `PacketIdentifiers` does
+	fn offset(&self, packet_identifier: crate::proto::PacketIdentifier) -> usize {
+		(usize::from(packet_identifier.get()) + MODULUS - usize::from(self.first.get())) % MODULUS
+	}
+}
+
+/// The number of valid packet identifiers
+const MODULUS: usize = u16::MAX as usize;
+
+/// The packet identifier of the slot at the given offset from the first slot. The inverse of `InFlight::offset`.
+fn packet_identifier_at(first: crate::proto::PacketIdentifier, offset: usize) -> crate::proto::PacketIdentifier {
+	let raw = (usize::from(first.get()) - 1 + offset) % MODULUS + 1;
+	#[allow(clippy::cast_possible_truncation)] // raw is at most MODULUS
+	let raw = raw as u16;
+	crate::proto::PacketIdentifier::new(raw).expect("raw is at least 1")
+}
+
+impl<T> Default for InFlight<T> {
+	fn default() -> Self {
+		InFlight {
+			first: crate::proto::PacketIdentifier::max_value(),
+			slots: Default::default(),
+			len: 0,
+		}
+	}
+}
+
+impl<T> std::fmt::Debug for InFlight<T> where T: std::fmt::Debug {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_map().entries(self.iter()).finish()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn in_flight_wraps_around() {
+		let packet_identifier = |raw| crate::proto::PacketIdentifier::new(raw).unwrap();
+
+		let mut in_flight: InFlight<&str> = Default::default();
+		assert_eq!(in_flight.insert(packet_identifier(65533), "z"), None);
+		assert_eq!(in_flight.remove(packet_identifier(65533)), Some("z"));
+		assert_eq!(in_flight.insert(packet_identifier(65534), "a"), None);
+		assert_eq!(in_flight.insert(packet_identifier(1), "c"), None);
+		assert_eq!(in_flight.insert(packet_identifier(65535), "b"), None);
+		assert_eq!(in_flight.len(), 3);
+		assert_eq!(
+			in_flight.iter().collect::<Vec<_>>(),
+			vec![(packet_identifier(65534), &"a"), (packet_identifier(65535), &"b"), (packet_identifier(1), &"c")],
+		);
+
+		assert_eq!(in_flight.remove(packet_identifier(65535)), Some("b"));
+		assert_eq!(in_flight.remove(packet_identifier(65535)), None);
+		assert_eq!(in_flight.slots.len(), 3);
+
+		assert_eq!(in_flight.remove(packet_identifier(65534)), Some("a"));
+		assert_eq!(in_flight.slots.len(), 1);
+		assert_eq!(in_flight.first, packet_identifier(1));
+
+		assert_eq!(in_flight.insert(packet_identifier(3), "e"), None);
+		assert_eq!(in_flight.insert(packet_identifier(4), "g"), None);
+		assert_eq!(in_flight.remove(packet_identifier(4)), Some("g"));
+		*in_flight.get_mut(packet_identifier(3)).unwrap() = "f";
+		assert_eq!(in_flight.get_mut(packet_identifier(2)), None);
+		assert_eq!(
+			in_flight.iter().collect::<Vec<_>>(),
+			vec![(packet_identifier(1), &"c"), (packet_identifier(3), &"f")],
+		);
+
+		assert_eq!(in_flight.remove(packet_identifier(1)), Some("c"));
+		assert_eq!(in_flight.remove(packet_identifier(3)), Some("f"));
+		assert_eq!(in_flight.len(), 0);
+		assert!(in_flight.slots.is_empty());
+	}
+}
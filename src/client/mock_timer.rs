@@ -0,0 +1,40 @@
+/// A clock and timer for the unit tests of the client's timers. Its time only moves when the test advances it,
+/// so the tests are deterministic and don't actually wait for the timers.
+pub(super) struct MockTimer {
+	now: MockNow,
+	timer: tokio_timer::Timer<tokio_executor::park::ParkThread, tokio_timer::clock::Clock>,
+}
+
+impl MockTimer {
+	pub(super) fn new() -> Self {
+		let now = MockNow(std::sync::Arc::new(std::sync::Mutex::new(std::time::Instant::now())));
+		let timer = tokio_timer::Timer::new_with_now(tokio_executor::park::ParkThread::new(), tokio_timer::clock::Clock::new_with_now(now.clone()));
+		MockTimer { now, timer }
+	}
+
+	/// Calls the given function from within a task, with this clock and timer as the defaults,
+	/// so that the timers it creates and polls run on this clock.
+	pub(super) fn run<R>(&mut self, f: impl FnOnce() -> R) -> R {
+		let clock = tokio_timer::clock::Clock::new_with_now(self.now.clone());
+		let timer_handle = self.timer.handle();
+		let mut enter = tokio_executor::enter().expect("MockTimer::run cannot be called from within an executor");
+
+		tokio_timer::clock::with_default(&clock, &mut enter, |enter| tokio_timer::with_default(&timer_handle, enter, |_|
+			futures::executor::spawn(futures::future::lazy(|| Ok::<_, ()>(f()))).wait_future().expect("lazy future cannot fail")))
+	}
+
+	/// Advances the clock by the given duration, and fires the timers that have expired.
+	pub(super) fn advance(&mut self, duration: std::time::Duration) {
+		*self.now.0.lock().unwrap_or_else(std::sync::PoisonError::into_inner) += duration;
+		let _ = self.timer.turn(Some(std::time::Duration::from_secs(0))).expect("couldn't turn timer");
+	}
+}
+
+#[derive(Clone)]
+struct MockNow(std::sync::Arc<std::sync::Mutex<std::time::Instant>>);
+
+impl tokio_timer::clock::Now for MockNow {
+	fn now(&self) -> std::time::Instant {
+		*self.0.lock().unwrap_or_else(std::sync::PoisonError::into_inner)
+	}
+}
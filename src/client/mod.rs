@@ -1,12 +1,29 @@
 use futures::{ Future, Sink, Stream };
 
+mod completions;
 mod connect;
+mod in_flight;
+#[cfg(test)]
+mod mock_timer;
 mod ping;
 mod publish;
+mod publish_queue;
+mod slow_consumer;
 mod subscriptions;
-
-pub use self::publish::{ PublishError, PublishHandle };
-pub use self::subscriptions::{ UpdateSubscriptionError, UpdateSubscriptionHandle };
+mod watchdog;
+
+pub use self::publish::{
+	AckError,
+	AckHandle,
+	PublishError,
+	PublishFuture,
+	PublishHandle,
+	PublishStreamError,
+	PublishStreamFuture,
+	PublishToManyFuture,
+	PublishWithTokenFuture,
+};
+pub use self::subscriptions::{ UpdateSubscriptionError, UpdateSubscriptionFuture, UpdateSubscriptionHandle };
 
 /// An MQTT v3.1.1 client.
 ///
@@ -20,8 +37,18 @@ pub use self::subscriptions::{ UpdateSubscriptionError, UpdateSubscriptionHandle
 /// The [`Stream`] only ends (returns `Ready(None)`) when the client is told to shut down gracefully using the handle
 /// returned by [`Client::shutdown_handle`]. The `Client` becomes unusable after it has returned `None`
 /// and should be dropped.
+///
+/// Statistics about the client can be queried using the handle returned by [`Client::stats_handle`],
+/// and metrics can be reported to a [`MetricsSink`](crate::metrics::MetricsSink) with [`Client::set_metrics_sink`].
+///
+/// With the `tracing` feature, the client is instrumented with `tracing` spans for each connection, for each publication
+/// from when it is queued to when the server acks it, and for each SUBSCRIBE and UNSUBSCRIBE packet until the server acks it.
+///
+/// The keep-alive, connect timeout and reconnect back-off timers are created on the default `tokio_timer` timer,
+/// and all their deadlines are computed with `tokio_timer::clock::now()`. So a test can drive the client with simulated time
+/// by running it on an executor whose timer and default clock use a custom [`tokio_timer::clock::Now`] implementation.
 #[derive(Debug)]
-pub struct Client<IoS>(ClientState<IoS>) where IoS: IoSource;
+pub struct Client<IoS>(ClientState<IoS>, StatsHandle) where IoS: IoSource;
 
 impl<IoS> Client<IoS> where IoS: IoSource {
 	/// Create a new client with the given parameters
@@ -34,6 +61,7 @@ impl<IoS> Client<IoS> where IoS: IoSource {
 	/// * `username`
 	///
 	///     Optional username credential for the server. Note that password is provided via `io_source`.
+	///     Both can instead be provided for each new connection by a credentials provider. See [`Client::set_credentials_provider`].
 	///
 	/// * `io_source`
 	///
@@ -45,7 +73,8 @@ impl<IoS> Client<IoS> where IoS: IoSource {
 	///
 	/// * `keep_alive`
 	///
-	///     The keep-alive time advertised to the server. The client will ping the server at half this interval.
+	///     The keep-alive time advertised to the server. The client will ping the server at half this interval if it has not sent any other packet in that time.
+	///     A keep-alive of zero (or anything less than one second) disables keep-alive, and the client will not ping the server at all.
 	pub fn new(
 		client_id: Option<String>,
 		username: Option<String>,
@@ -60,6 +89,8 @@ impl<IoS> Client<IoS> where IoS: IoSource {
 		};
 
 		let (shutdown_send, shutdown_recv) = futures::sync::mpsc::channel(0);
+		let (keep_alive_send, keep_alive_recv) = futures::sync::mpsc::channel(0);
+		let (pause_send, pause_recv) = futures::sync::mpsc::channel(0);
 
 		// TODO: username / password / will can be too large and prevent a CONNECT packet from being encoded.
 		//       `Client::new()` should detect that and retrurn an error.
@@ -70,27 +101,402 @@ impl<IoS> Client<IoS> where IoS: IoSource {
 			username,
 			will,
 			keep_alive,
+			ping_response_timeout: None,
+			activity_watchdog: None,
 
 			shutdown_send,
 			shutdown_recv,
 
+			keep_alive_send,
+			keep_alive_recv,
+			next_keep_alive: None,
+
+			pause_send,
+			pause_recv,
+			paused: false,
+			paused_publications: Default::default(),
+			paused_publications_limit: None,
+			slow_consumer_warning: None,
+			slow_consumer: self::slow_consumer::State::BelowThreshold,
+
 			packet_identifiers: Default::default(),
 
 			connect: self::connect::Connect::new(io_source, max_reconnect_back_off),
 			ping: self::ping::State::BeginWaitingForNextPing,
+			watchdog: self::watchdog::State::BeginWaiting,
 			publish: Default::default(),
 			subscriptions: Default::default(),
 
+			publication_handlers: vec![],
+
 			packets_waiting_to_be_sent: Default::default(),
-		})
+			pending_events: Default::default(),
+
+			metrics: Default::default(),
+		}, Default::default())
+	}
+
+	/// Sets how long the client waits for the server to respond to a ping before it considers the connection dead and reconnects.
+	///
+	/// Defaults to the keep-alive interval. Increase it for high-latency links where PINGRESP might legitimately take longer to arrive.
+	pub fn set_ping_response_timeout(&mut self, ping_response_timeout: std::time::Duration) {
+		match &mut self.0 {
+			ClientState::Up { ping_response_timeout: ping_response_timeout_, .. } => *ping_response_timeout_ = Some(ping_response_timeout),
+			ClientState::ShuttingDown { .. } |
+			ClientState::ShutDown { .. } => (),
+		}
+	}
+
+	/// Enables a watchdog that reconnects to the server if no packet has been received from it for the given multiple of the keep-alive.
+	///
+	/// This catches half-open connections, such as those silently dropped by a NAT, where writes keep succeeding
+	/// even though the server will never receive them. For this to not trigger on a healthy but idle connection,
+	/// the multiple should be larger than the ping interval, ie at least 1.
+	///
+	/// Defaults to `None`, ie the watchdog is disabled. It also has no effect if keep-alive is disabled.
+	pub fn set_activity_watchdog(&mut self, keep_alive_multiplier: Option<u32>) {
+		match &mut self.0 {
+			ClientState::Up { activity_watchdog, .. } => *activity_watchdog = keep_alive_multiplier,
+			ClientState::ShuttingDown { .. } |
+			ClientState::ShutDown { .. } => (),
+		}
+	}
+
+	/// Sets how many publish requests can be buffered between [`PublishHandle`]s and the client, in addition to one request
+	/// for each handle.
+	///
+	/// Defaults to 0, ie the client only buffers as many publish requests as there are handles until it picks them up. A larger capacity
+	/// lets bursty publishers queue up publications without waiting for the client to be polled.
+	///
+	/// This also applies to handles that were obtained before this is called.
+	///
+	/// [`PublishHandle::publish`] queues its publication even if the buffer is full, since its future holds the publication until it's acked anyway.
+	/// Use [`PublishHandle::poll_ready`] to wait for room in the buffer first.
+	pub fn set_publish_channel_capacity(&mut self, capacity: usize) {
+		match &mut self.0 {
+			ClientState::Up { publish, .. } => publish.set_publish_queue_capacity(capacity),
+			ClientState::ShuttingDown { .. } |
+			ClientState::ShutDown { .. } => (),
+		}
+	}
+
+	/// Sets the timeout for establishing a new connection to the server.
+	///
+	/// The timeout covers both the future returned by the `io_source` and the exchange of the CONNECT and CONNACK packets.
+	/// If the connection is not established within this time, it is abandoned and the client backs off and tries again.
+	/// This is independent of the keep-alive.
+	///
+	/// Defaults to `None`, ie the client waits indefinitely for the connection to be established.
+	pub fn set_connect_timeout(&mut self, connect_timeout: Option<std::time::Duration>) {
+		match &mut self.0 {
+			ClientState::Up { connect, .. } => connect.set_timeout(connect_timeout),
+			ClientState::ShuttingDown { .. } |
+			ClientState::ShutDown { .. } => (),
+		}
+	}
+
+	/// Sets the maximum remaining length of packets that the client accepts from the server.
+	///
+	/// If the server sends a larger packet, the client fails the connection with [`Error::DecodePacket`] containing
+	/// [`DecodeError::PacketTooLarge`](crate::proto::DecodeError::PacketTooLarge) as soon as it has read the packet's fixed header,
+	/// instead of buffering the whole packet. The client then reconnects as usual.
+	///
+	/// The new value is used for connections that are established after this call.
+	///
+	/// Defaults to `None`, ie packets of any size are accepted.
+	pub fn set_max_incoming_packet_size(&mut self, max_incoming_packet_size: Option<usize>) {
+		match &mut self.0 {
+			ClientState::Up { connect, .. } => connect.set_max_incoming_packet_size(max_incoming_packet_size),
+			ClientState::ShuttingDown { .. } |
+			ClientState::ShutDown { .. } => (),
+		}
+	}
+
+	/// Sets how strictly the client validates the UTF-8 strings in the packets that it receives from the server.
+	///
+	/// With [`StringValidation::Strict`](crate::proto::StringValidation::Strict), a packet with a string that is not valid UTF-8 or that contains U+0000
+	/// fails the connection with [`Error::DecodePacket`]. [`StringValidation::Lenient`](crate::proto::StringValidation::Lenient) accepts such strings instead,
+	/// which is useful with servers that forward topic names without validating them. Strings that the client sends are always validated strictly.
+	///
+	/// The new value is used for connections that are established after this call.
+	///
+	/// Defaults to [`StringValidation::Strict`](crate::proto::StringValidation::Strict).
+	pub fn set_string_validation(&mut self, string_validation: crate::proto::StringValidation) {
+		match &mut self.0 {
+			ClientState::Up { connect, .. } => connect.set_string_validation(string_validation),
+			ClientState::ShuttingDown { .. } |
+			ClientState::ShutDown { .. } => (),
+		}
+	}
+
+	/// Sets the maximum remaining length of PUBLISH packets that the client will send to the server.
+	///
+	/// Publications that would be encoded into a larger packet are rejected by [`Client::publish`] and [`PublishHandle`]
+	/// with [`PublishError::PacketTooLarge`] before they are queued, instead of being sent to a server that would close the connection because of them.
+	/// The limit also applies to publications from [`PublishHandle`]s that were created before this call.
+	///
+	/// Defaults to `None`, ie publications are only limited by what can be encoded in a PUBLISH packet.
+	pub fn set_max_outgoing_packet_size(&mut self, max_outgoing_packet_size: Option<usize>) {
+		match &mut self.0 {
+			ClientState::Up { publish, .. } => publish.set_max_outgoing_packet_size(max_outgoing_packet_size),
+			ClientState::ShuttingDown { .. } |
+			ClientState::ShutDown { .. } => (),
+		}
+	}
+
+	/// Sets the format that the client writes the packets that it sends and receives in when it logs them at the trace level.
+	///
+	/// Use [`PacketLogFormat::Json`](crate::PacketLogFormat::Json) to log packets as JSON objects that log pipelines can index.
+	/// The new value is used for connections that are established after this call.
+	///
+	/// Defaults to [`PacketLogFormat::Debug`](crate::PacketLogFormat::Debug).
+	pub fn set_packet_log_format(&mut self, packet_log_format: crate::PacketLogFormat) {
+		match &mut self.0 {
+			ClientState::Up { connect, .. } => connect.set_packet_log_format(packet_log_format),
+			ClientState::ShuttingDown { .. } |
+			ClientState::ShutDown { .. } => (),
+		}
+	}
+
+	/// Sets when the client writes the packets that it sends to the connection, to trade the latency of packets for fewer writes.
+	///
+	/// The new value is used for connections that are established after this call. Use [`MetricsSink::flushed`](crate::metrics::MetricsSink::flushed)
+	/// to observe how many packets share each flush.
+	///
+	/// Defaults to [`FlushPolicy::Immediate`](crate::FlushPolicy::Immediate).
+	pub fn set_flush_policy(&mut self, flush_policy: crate::FlushPolicy) {
+		match &mut self.0 {
+			ClientState::Up { connect, .. } => connect.set_flush_policy(flush_policy),
+			ClientState::ShuttingDown { .. } |
+			ClientState::ShutDown { .. } => (),
+		}
+	}
+
+	/// Sets the payload length above which at-most-once and at-least-once publications are received in chunks, instead of the client
+	/// buffering the whole payload. Such a publication is returned as an [`Event::StreamedPublication`] as soon as its header has been read,
+	/// followed by an [`Event::StreamedPayloadChunk`] for every chunk of its payload as it is read from the connection.
+	///
+	/// Streamed publications are returned even while the client is paused, and are not passed to interceptors or to the handlers registered
+	/// with [`Client::on`], since those need the whole payload. The PUBACK of an at-least-once publication is sent once its whole payload
+	/// has been received. If the connection is lost before that, the chunks stop and the next event is [`Event::NewConnection`].
+	///
+	/// The new value is used for connections that are established after this call.
+	///
+	/// Defaults to `None`, ie every publication is received whole.
+	pub fn set_payload_streaming_threshold(&mut self, payload_streaming_threshold: Option<usize>) {
+		match &mut self.0 {
+			ClientState::Up { connect, .. } => connect.set_payload_streaming_threshold(payload_streaming_threshold),
+			ClientState::ShuttingDown { .. } |
+			ClientState::ShutDown { .. } => (),
+		}
+	}
+
+	/// Sets how the payloads of publications are written when the client logs the packets that it sends and receives at the trace level.
+	///
+	/// Use this to truncate, hex-encode or redact payloads that contain credentials or personal data.
+	/// The new value is used for connections that are established after this call.
+	///
+	/// Defaults to [`PayloadLogging::Debug`](crate::PayloadLogging::Debug) without a `max_len`, ie payloads are written in full.
+	pub fn set_payload_logging(&mut self, payload_logging: crate::PayloadLogging) {
+		match &mut self.0 {
+			ClientState::Up { connect, .. } => connect.set_payload_logging(payload_logging),
+			ClientState::ShuttingDown { .. } |
+			ClientState::ShutDown { .. } => (),
+		}
+	}
+
+	/// Sets a capture that records every packet that the client sends and receives, with timestamps.
+	/// See [`PacketCapture`](crate::capture::PacketCapture) for the formats that it can write.
+	///
+	/// Packets are recorded for connections that are established after this call. Pass `None` to stop recording them.
+	///
+	/// Defaults to `None`.
+	pub fn set_packet_capture(&mut self, capture: Option<crate::capture::PacketCapture>) {
+		match &mut self.0 {
+			ClientState::Up { connect, .. } => connect.set_capture(capture.map(std::sync::Arc::new)),
+			ClientState::ShuttingDown { .. } |
+			ClientState::ShutDown { .. } => (),
+		}
+	}
+
+	/// Sets a tap that can inject packets into the client's receive path and intercept the packets that it sends.
+	/// See [`PacketTap`](crate::tap::PacketTap) for details.
+	///
+	/// The tap is used for connections that are established after this call. Pass `None` to remove it.
+	///
+	/// Defaults to `None`.
+	pub fn set_packet_tap(&mut self, tap: Option<crate::tap::PacketTap>) {
+		match &mut self.0 {
+			ClientState::Up { connect, .. } => connect.set_tap(tap),
+			ClientState::ShuttingDown { .. } |
+			ClientState::ShutDown { .. } => (),
+		}
+	}
+
+	/// Sets a prefix that is added to the topics of everything the client sends, and stripped from the topics of the publications it receives.
+	///
+	/// This puts the client in a namespace, like `tenant-a/`, without changing the topics that it is used with. The prefix is added to
+	/// the topic names of publications, including the will, and to the topic filters of subscriptions and unsubscriptions. Events and errors
+	/// still have the topics without the prefix. A prefix that is meant to be a separate topic level must end with `/`.
+	///
+	/// Topics that start with `$`, like `$SYS/#`, are reserved by the server, so they do not get the prefix.
+	/// The prefix also does not count towards the maximum outgoing packet size.
+	///
+	/// The prefix is used for connections that are established after this call, so it should be set before the client first connects.
+	///
+	/// Defaults to `None`.
+	pub fn set_topic_prefix(&mut self, topic_prefix: Option<crate::topic::TopicName>) {
+		match &mut self.0 {
+			ClientState::Up { connect, .. } => connect.set_topic_prefix(topic_prefix),
+			ClientState::ShuttingDown { .. } |
+			ClientState::ShutDown { .. } => (),
+		}
+	}
+
+	/// Sets a sink that the client reports metrics to, like the packets and bytes that it sends and receives, its reconnects,
+	/// and how many publications are queued and in flight. See [`MetricsSink`](crate::metrics::MetricsSink) for all the metrics.
+	///
+	/// The packet and byte metrics are reported for connections that are established after this call.
+	///
+	/// Defaults to no sink.
+	pub fn set_metrics_sink<M>(&mut self, metrics_sink: M) where M: crate::metrics::MetricsSink + Send + Sync + 'static {
+		match &mut self.0 {
+			ClientState::Up { connect, metrics, .. } => {
+				*metrics = crate::metrics::Metrics::new(std::sync::Arc::new(metrics_sink));
+				connect.set_metrics(metrics.clone());
+			},
+			ClientState::ShuttingDown { .. } |
+			ClientState::ShutDown { .. } => (),
+		}
+	}
+
+	/// Sets a provider of credentials that will be invoked before each new connection to the server.
+	///
+	/// This is useful for credentials that expire, like SAS tokens or JWTs, since the provider can return fresh credentials
+	/// for every connection without needing to recreate the client.
+	///
+	/// The username and password returned by the provider are used instead of the `username` given to [`Client::new`]
+	/// and the password returned by the `io_source`. If the provider fails, the client backs off and tries again like it does
+	/// for any other connection failure.
+	pub fn set_credentials_provider<CP>(&mut self, credentials_provider: CP)
+	where
+		CP: CredentialsProvider + Send + 'static,
+		<CP as CredentialsProvider>::Future: Send + 'static,
+		<<CP as CredentialsProvider>::Future as Future>::Error: std::fmt::Display,
+	{
+		let mut credentials_provider = credentials_provider;
+		let credentials_provider = BoxedCredentialsProvider(Box::new(move || -> BoxedCredentialsFuture {
+			Box::new(credentials_provider.get().map_err(|err| err.to_string()))
+		}));
+
+		match &mut self.0 {
+			ClientState::Up { connect, .. } => connect.set_credentials_provider(credentials_provider),
+			ClientState::ShuttingDown { .. } |
+			ClientState::ShutDown { .. } => (),
+		}
+	}
+
+	/// Sets whether the client resubscribes to its subscriptions when it connects to the server and the server did not have a session for it.
+	///
+	/// If this is disabled, the client forgets the subscriptions that the server had acked when the session is reset,
+	/// and the user is expected to subscribe again after receiving an [`Event::NewConnection`] with `reset_session: true`.
+	/// Subscription updates that were not acked by the server yet are still sent on the new connection.
+	///
+	/// Defaults to `true`.
+	pub fn set_resubscribe_on_session_reset(&mut self, resubscribe_on_session_reset: bool) {
+		match &mut self.0 {
+			ClientState::Up { subscriptions, .. } => subscriptions.set_resubscribe_on_session_reset(resubscribe_on_session_reset),
+			ClientState::ShuttingDown { .. } |
+			ClientState::ShutDown { .. } => (),
+		}
+	}
+
+	/// Sets whether the client waits for the application to ack the at-least-once publications it receives, before it acks them to the server.
+	///
+	/// This lets the application ack a publication only once it has processed it, so that the server delivers it again
+	/// if the application fails before then. Every at-least-once publication that the client receives must then be acked
+	/// with an [`AckHandle`], including those that are passed to the handlers registered with [`Client::on`]. MQTT requires publications
+	/// to be acked in the order they were received, so [`AckHandle::ack`] always acks the oldest publication that has not been acked yet.
+	///
+	/// If the connection is lost, the server sends the publications that were not acked again on the next connection if it resumes the session,
+	/// and they must be acked again. Exactly-once publications are acked by the client as usual, since the server does not send them again.
+	///
+	/// Defaults to `false`.
+	pub fn set_manual_acks(&mut self, manual_acks: bool) {
+		match &mut self.0 {
+			ClientState::Up { publish, .. } => publish.set_manual_acks(manual_acks),
+			ClientState::ShuttingDown { .. } |
+			ClientState::ShutDown { .. } => (),
+		}
+	}
+
+	/// Limits the number of publications that are buffered while the client is paused via a [`PauseHandle`].
+	///
+	/// `overflow_policy` determines what happens when the limit is reached. `None` removes the limit.
+	///
+	/// Defaults to `None`, ie the buffer is unbounded.
+	pub fn set_paused_publications_limit(&mut self, limit: Option<(usize, OverflowPolicy)>) {
+		match &mut self.0 {
+			ClientState::Up { paused_publications_limit, .. } => *paused_publications_limit = limit,
+			ClientState::ShuttingDown { .. } |
+			ClientState::ShutDown { .. } => (),
+		}
+	}
+
+	/// Emits an [`Event::SlowConsumer`] when the application is not polling the client fast enough to keep up with the events it receives,
+	/// ie when the client's receive backlog stays at or above `threshold` for longer than `duration` without the application taking an event from it.
+	///
+	/// The receive backlog is the events that the client has read from the connection but not returned yet, and the publications that it has
+	/// buffered while paused via a [`PauseHandle`]. This makes backpressure problems visible before the backlog uses up too much memory.
+	/// Packets that the client has not read from the connection yet are not counted.
+	/// The event is emitted again only after the backlog has dropped below `threshold`.
+	///
+	/// Defaults to `None`, ie the event is never emitted.
+	pub fn set_slow_consumer_warning(&mut self, warning: Option<(usize, std::time::Duration)>) {
+		match &mut self.0 {
+			ClientState::Up { slow_consumer_warning, slow_consumer, .. } => {
+				*slow_consumer_warning = warning;
+				*slow_consumer = self::slow_consumer::State::BelowThreshold;
+			},
+			ClientState::ShuttingDown { .. } |
+			ClientState::ShutDown { .. } => (),
+		}
+	}
+
+	/// Registers a handler for publications whose topic name matches the given topic filter.
+	///
+	/// While the client is polled, each received publication is passed to every handler whose topic filter matches it,
+	/// instead of being returned as an [`Event::Publication`]. Publications that don't match any handler are still returned as events.
+	///
+	/// This does not subscribe to the topic filter. Use [`Client::subscribe`] for that.
+	pub fn on<F>(&mut self, topic_filter: crate::topic::TopicFilter, handler: F) where F: FnMut(&ReceivedPublication) + Send + 'static {
+		match &mut self.0 {
+			ClientState::Up { publication_handlers, .. } =>
+				publication_handlers.push(PublicationHandler { topic_filter, handler: Box::new(handler) }),
+			ClientState::ShuttingDown { .. } |
+			ClientState::ShutDown { .. } => (),
+		}
 	}
 
 	/// Queues a message to be published to the server
-	pub fn publish(&mut self, publication: crate::proto::Publication) -> impl Future<Item = (), Error = PublishError> {
+	pub fn publish(&mut self, publication: crate::proto::Publication) -> PublishFuture {
+		match &mut self.0 {
+			ClientState::Up { publish, .. } => publish.publish(publication),
+			ClientState::ShuttingDown { .. } |
+			ClientState::ShutDown { .. } => PublishFuture::err(PublishError::ClientDoesNotExist),
+		}
+	}
+
+	/// Adds an interceptor that the publications this client sends and receives go through, like to encrypt their payloads.
+	/// See [`Interceptor`](crate::interceptor::Interceptor) for details.
+	///
+	/// The interceptor is also used by the publish handles of the client, including those that were created before this call.
+	/// Interceptors run in the order they were added for outgoing publications, and in the reverse order for incoming ones.
+	pub fn add_interceptor<I>(&mut self, interceptor: I) where I: crate::interceptor::Interceptor + 'static {
 		match &mut self.0 {
-			ClientState::Up { publish, .. } => futures::future::Either::A(publish.publish(publication)),
+			ClientState::Up { publish, .. } => publish.add_interceptor(Box::new(interceptor)),
 			ClientState::ShuttingDown { .. } |
-			ClientState::ShutDown { .. } => futures::future::Either::B(futures::future::err(PublishError::ClientDoesNotExist)),
+			ClientState::ShutDown { .. } => (),
 		}
 	}
 
@@ -113,7 +519,7 @@ impl<IoS> Client<IoS> where IoS: IoSource {
 	}
 
 	/// Unsubscribes from the given topic
-	pub fn unsubscribe(&mut self, unsubscribe_from: String) -> Result<(), UpdateSubscriptionError> {
+	pub fn unsubscribe(&mut self, unsubscribe_from: crate::topic::TopicFilter) -> Result<(), UpdateSubscriptionError> {
 		match &mut self.0 {
 			ClientState::Up { subscriptions, .. } => subscriptions.unsubscribe(unsubscribe_from),
 			ClientState::ShuttingDown { .. } |
@@ -121,6 +527,28 @@ impl<IoS> Client<IoS> where IoS: IoSource {
 		}
 	}
 
+	/// Replaces the current subscriptions with the given ones
+	///
+	/// Only the difference between the current and the new subscriptions is sent to the server.
+	pub fn set_subscriptions(&mut self, subscribe_to: Vec<crate::proto::SubscribeTo>) -> Result<(), UpdateSubscriptionError> {
+		match &mut self.0 {
+			ClientState::Up { subscriptions, .. } => subscriptions.set_subscriptions(subscribe_to),
+			ClientState::ShuttingDown { .. } |
+			ClientState::ShutDown { .. } => Err(UpdateSubscriptionError::ClientDoesNotExist),
+		}
+	}
+
+	/// Returns the current subscriptions, including changes that have not been acked by the server yet
+	///
+	/// Changes requested through an [`UpdateSubscriptionHandle`] are only included once the client has received them.
+	pub fn subscriptions(&self) -> Result<Subscriptions, UpdateSubscriptionError> {
+		match &self.0 {
+			ClientState::Up { subscriptions, .. } => Ok(subscriptions.subscriptions()),
+			ClientState::ShuttingDown { .. } |
+			ClientState::ShutDown { .. } => Err(UpdateSubscriptionError::ClientDoesNotExist),
+		}
+	}
+
 	/// Returns a handle that can be used to update subscriptions
 	pub fn update_subscription_handle(&self) -> Result<UpdateSubscriptionHandle, UpdateSubscriptionError> {
 		match &self.0 {
@@ -130,6 +558,46 @@ impl<IoS> Client<IoS> where IoS: IoSource {
 		}
 	}
 
+	/// Returns a snapshot of the current statistics about the client
+	///
+	/// Use [`Client::stats_handle`] to query the statistics from a different task than the one polling the client.
+	pub fn stats(&self) -> Stats {
+		self.1.stats()
+	}
+
+	/// Returns a handle that can be used to query statistics about the client
+	pub fn stats_handle(&self) -> StatsHandle {
+		self.1.clone()
+	}
+
+	/// Returns a handle that can be used to change the keep-alive for subsequent connections
+	pub fn keep_alive_handle(&self) -> Result<KeepAliveHandle, KeepAliveError> {
+		match &self.0 {
+			ClientState::Up { keep_alive_send, .. } => Ok(KeepAliveHandle(keep_alive_send.clone())),
+			ClientState::ShuttingDown { .. } |
+			ClientState::ShutDown { .. } => Err(KeepAliveError::ClientDoesNotExist),
+		}
+	}
+
+	/// Returns a handle that can be used to ack the publications received from the server, if manual acks are enabled
+	/// with [`Client::set_manual_acks`]
+	pub fn ack_handle(&self) -> Result<AckHandle, AckError> {
+		match &self.0 {
+			ClientState::Up { publish, .. } => Ok(publish.ack_handle()),
+			ClientState::ShuttingDown { .. } |
+			ClientState::ShutDown { .. } => Err(AckError::ClientDoesNotExist),
+		}
+	}
+
+	/// Returns a handle that can be used to pause and resume the delivery of publications received from the server
+	pub fn pause_handle(&self) -> Result<PauseHandle, PauseError> {
+		match &self.0 {
+			ClientState::Up { pause_send, .. } => Ok(PauseHandle(pause_send.clone())),
+			ClientState::ShuttingDown { .. } |
+			ClientState::ShutDown { .. } => Err(PauseError::ClientDoesNotExist),
+		}
+	}
+
 	/// Returns a handle that can be used to signal the client to shut down
 	pub fn shutdown_handle(&self) -> Result<ShutdownHandle, ShutdownError> {
 		match &self.0 {
@@ -145,6 +613,20 @@ impl<IoS> Stream for Client<IoS> where IoS: IoSource, <<IoS as IoSource>::Future
 	type Error = Error;
 
 	fn poll(&mut self) -> futures::Poll<Option<Self::Item>, Self::Error> {
+		let result = self.poll_event();
+
+		if let Ok(futures::Async::Ready(Some(_))) = &result {
+			if let ClientState::Up { slow_consumer_warning: Some((threshold, duration)), slow_consumer, paused_publications, pending_events, .. } = &mut self.0 {
+				slow_consumer.event_returned(paused_publications.len() + pending_events.len(), *threshold, *duration);
+			}
+		}
+
+		result
+	}
+}
+
+impl<IoS> Client<IoS> where IoS: IoSource, <<IoS as IoSource>::Future as Future>::Error: std::fmt::Display {
+	fn poll_event(&mut self) -> futures::Poll<Option<Event>, Error> {
 		let reason = loop {
 			match &mut self.0 {
 				ClientState::Up {
@@ -152,20 +634,41 @@ impl<IoS> Stream for Client<IoS> where IoS: IoSource, <<IoS as IoSource>::Future
 					username,
 					will,
 					keep_alive,
+					ping_response_timeout,
+					activity_watchdog,
 
 					shutdown_recv,
 
+					keep_alive_recv,
+					next_keep_alive,
+
+					pause_recv,
+					paused,
+					paused_publications,
+					paused_publications_limit,
+					slow_consumer_warning,
+					slow_consumer,
+
 					packet_identifiers,
 
 					connect,
 					ping,
+					watchdog,
 					publish,
 					subscriptions,
 
+					publication_handlers,
+
 					packets_waiting_to_be_sent,
+					pending_events,
+
+					metrics,
 
 					..
 				} => {
+					let connection_span = connect.span().clone();
+					let _connection_span = connection_span.enter();
+
 					match shutdown_recv.poll().expect("Receiver::poll cannot fail") {
 						futures::Async::Ready(Some(())) => break None,
 
@@ -173,11 +676,36 @@ impl<IoS> Stream for Client<IoS> where IoS: IoSource, <<IoS as IoSource>::Future
 						futures::Async::NotReady => (),
 					}
 
+					while let futures::Async::Ready(Some(keep_alive)) = keep_alive_recv.poll().expect("Receiver::poll cannot fail") {
+						*next_keep_alive = Some(keep_alive);
+					}
+
+					while let futures::Async::Ready(Some(pause)) = pause_recv.poll().expect("Receiver::poll cannot fail") {
+						*paused = pause;
+					}
+
+					if !*paused {
+						while let Some(publication) = paused_publications.pop_front() {
+							if let Some(publication) = dispatch_publication(publication_handlers, publication) {
+								return Ok(futures::Async::Ready(Some(Event::Publication(publication))));
+							}
+						}
+					}
+
+					if let Some((threshold, duration)) = slow_consumer_warning {
+						let backlog = paused_publications.len() + pending_events.len();
+						if slow_consumer.poll(backlog, *threshold, *duration) {
+							log::warn!("{} events have been waiting for the application to poll the client for longer than {:?}", backlog, duration);
+							return Ok(futures::Async::Ready(Some(Event::SlowConsumer { queued_events: backlog })));
+						}
+					}
+
 					let self::connect::Connected { framed, new_connection, reset_session } = match connect.poll(
 						username.as_ref().map(AsRef::as_ref),
 						will.as_ref(),
 						client_id,
-						*keep_alive,
+						keep_alive,
+						next_keep_alive,
 					) {
 						Ok(futures::Async::Ready(framed)) => framed,
 						Ok(futures::Async::NotReady) => return Ok(futures::Async::NotReady),
@@ -187,26 +715,67 @@ impl<IoS> Stream for Client<IoS> where IoS: IoSource, <<IoS as IoSource>::Future
 					if new_connection {
 						log::debug!("New connection established");
 
+						metrics.connected(reset_session);
+						connection_span.connected(reset_session);
+
 						*packets_waiting_to_be_sent = Default::default();
 
 						ping.new_connection();
 
-						packets_waiting_to_be_sent.extend(publish.new_connection(reset_session, packet_identifiers));
+						watchdog.new_connection();
 
-						packets_waiting_to_be_sent.extend(subscriptions.new_connection(reset_session, packet_identifiers));
+						packets_waiting_to_be_sent.extend(publish.new_connection(reset_session, packet_identifiers, &self.1));
+
+						packets_waiting_to_be_sent.extend(subscriptions.new_connection(reset_session, packet_identifiers).map(Into::into));
 
 						return Ok(futures::Async::Ready(Some(Event::NewConnection { reset_session })));
 					}
 
-					match client_poll(
+					if *paused {
+						if let Some((limit, OverflowPolicy::BlockReads)) = paused_publications_limit {
+							if paused_publications.len() >= *limit {
+								// Stop reading from the connection until the client is resumed
+								return Ok(futures::Async::NotReady);
+							}
+						}
+					}
+
+					let result = client_poll(
 						framed,
+						&self.1,
+						metrics,
 						*keep_alive,
+						ping_response_timeout.unwrap_or(*keep_alive),
+						match activity_watchdog {
+							Some(keep_alive_multiplier) if keep_alive.as_secs() > 0 => Some(*keep_alive * *keep_alive_multiplier),
+							_ => None,
+						},
 						packets_waiting_to_be_sent,
+						pending_events,
 						packet_identifiers,
 						ping,
+						watchdog,
 						publish,
 						subscriptions,
-					) {
+					);
+
+					metrics.publications_in_flight(publish.publications_in_flight());
+					metrics.publications_queued(publish.publications_queued());
+					self.1.update(|stats| {
+						stats.publications_in_flight = publish.publications_in_flight();
+						stats.publications_queued = publish.publications_queued();
+					});
+
+					match result {
+						Ok(futures::Async::Ready(Event::Publication(publication))) => match publish.intercept_incoming(publication) {
+							Some(publication) if *paused =>
+								buffer_paused_publication(paused_publications, *paused_publications_limit, publication, metrics),
+							Some(publication) =>
+								if let Some(publication) = dispatch_publication(publication_handlers, publication) {
+									return Ok(futures::Async::Ready(Some(Event::Publication(publication))));
+								},
+							None => (),
+						},
 						Ok(futures::Async::Ready(event)) => return Ok(futures::Async::Ready(Some(event))),
 						Ok(futures::Async::NotReady) => return Ok(futures::Async::NotReady),
 						Err(err) =>
@@ -216,6 +785,10 @@ impl<IoS> Stream for Client<IoS> where IoS: IoSource, <<IoS as IoSource>::Future
 							else {
 								log::warn!("client will reconnect because of error: {}", err);
 
+								metrics.connection_lost(&err);
+								connection_span.connection_lost(&err);
+								self.1.update(|stats| stats.reconnects += 1);
+
 								if !err.session_is_resumable() {
 									// Ensure clean session if the error is such that the session is not resumable.
 									//
@@ -229,6 +802,10 @@ impl<IoS> Stream for Client<IoS> where IoS: IoSource, <<IoS as IoSource>::Future
 								}
 
 								connect.reconnect();
+
+								if let Error::PingTimeout = err {
+									return Ok(futures::Async::Ready(Some(Event::PingTimeout)));
+								}
 							},
 					}
 				},
@@ -249,7 +826,8 @@ impl<IoS> Stream for Client<IoS> where IoS: IoSource, <<IoS as IoSource>::Future
 						username.as_ref().map(AsRef::as_ref),
 						will.as_ref(),
 						client_id,
-						*keep_alive,
+						keep_alive,
+						&mut None,
 					) {
 						Ok(futures::Async::Ready(framed)) => framed,
 						Ok(futures::Async::NotReady) => {
@@ -278,7 +856,7 @@ impl<IoS> Stream for Client<IoS> where IoS: IoSource, <<IoS as IoSource>::Future
 							}
 						}
 						else {
-							match framed.start_send(crate::proto::Packet::Disconnect(crate::proto::Disconnect)) {
+							match framed.start_send(crate::proto::Packet::Disconnect(crate::proto::Disconnect).into()) {
 								Ok(futures::AsyncSink::Ready) => *sent_disconnect = true,
 
 								Ok(futures::AsyncSink::NotReady(_)) => return Ok(futures::Async::NotReady),
@@ -362,6 +940,148 @@ where
 	}
 }
 
+/// This trait provides the credentials that a [`Client`] uses for a new connection.
+///
+/// The trait is automatically implemented for all [`FnMut`] that return a credentials future.
+pub trait CredentialsProvider {
+	/// The credentials future.
+	type Future: Future<Item = Credentials>;
+
+	/// Returns a [`Future`] that resolves to the credentials to use for the next connection
+	fn get(&mut self) -> Self::Future;
+}
+
+impl<F, A> CredentialsProvider for F
+where
+	F: FnMut() -> A,
+	A: Future<Item = Credentials>,
+{
+	type Future = A;
+
+	fn get(&mut self) -> Self::Future {
+		(self)()
+	}
+}
+
+/// The credentials returned by a [`CredentialsProvider`]
+#[derive(Clone, Default, Eq, PartialEq)]
+pub struct Credentials {
+	pub username: Option<String>,
+	pub password: Option<String>,
+}
+
+impl std::fmt::Debug for Credentials {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("Credentials")
+			.field("username", &self.username)
+			.finish()
+	}
+}
+
+type BoxedCredentialsFuture = Box<dyn Future<Item = Credentials, Error = String> + Send>;
+
+struct BoxedCredentialsProvider(Box<dyn FnMut() -> BoxedCredentialsFuture + Send>);
+
+impl BoxedCredentialsProvider {
+	fn get(&mut self) -> BoxedCredentialsFuture {
+		(self.0)()
+	}
+}
+
+impl std::fmt::Debug for BoxedCredentialsProvider {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("BoxedCredentialsProvider").finish()
+	}
+}
+
+fn buffer_paused_publication(
+	paused_publications: &mut std::collections::VecDeque<ReceivedPublication>,
+	paused_publications_limit: Option<(usize, OverflowPolicy)>,
+	publication: ReceivedPublication,
+	metrics: &crate::metrics::Metrics,
+) {
+	match paused_publications_limit {
+		Some((limit, OverflowPolicy::DropOldest)) if paused_publications.len() >= limit => {
+			if limit == 0 {
+				log::debug!("dropping publication to {} received while paused", publication.topic_name);
+				metrics.publication_dropped(&publication);
+				return;
+			}
+
+			if let Some(dropped) = paused_publications.pop_front() {
+				log::debug!("dropping publication to {} received while paused", dropped.topic_name);
+				metrics.publication_dropped(&dropped);
+			}
+		},
+
+		Some((limit, OverflowPolicy::DropAtMostOnce)) if paused_publications.len() >= limit => {
+			if publication.qos == crate::proto::QoS::AtMostOnce {
+				log::debug!("dropping publication to {} received while paused", publication.topic_name);
+				metrics.publication_dropped(&publication);
+				return;
+			}
+
+			let oldest_at_most_once = paused_publications.iter().position(|publication| publication.qos == crate::proto::QoS::AtMostOnce);
+			if let Some(dropped) = oldest_at_most_once.and_then(|index| paused_publications.remove(index)) {
+				log::debug!("dropping publication to {} received while paused", dropped.topic_name);
+				metrics.publication_dropped(&dropped);
+			}
+		},
+
+		_ => (),
+	}
+
+	paused_publications.push_back(publication);
+}
+
+/// Passes the publication to every handler with a matching topic filter. Returns the publication back if there are no such handlers.
+fn dispatch_publication(publication_handlers: &mut [PublicationHandler], publication: ReceivedPublication) -> Option<ReceivedPublication> {
+	let mut handled = false;
+
+	for PublicationHandler { topic_filter, handler } in publication_handlers {
+		if topic_filter.matches(&publication.topic_name) {
+			handler(&publication);
+			handled = true;
+		}
+	}
+
+	if handled {
+		None
+	}
+	else {
+		Some(publication)
+	}
+}
+
+struct PublicationHandler {
+	topic_filter: crate::topic::TopicFilter,
+	handler: Box<dyn FnMut(&ReceivedPublication) + Send>,
+}
+
+impl std::fmt::Debug for PublicationHandler {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("PublicationHandler")
+			.field("topic_filter", &self.topic_filter)
+			.finish()
+	}
+}
+
+/// What the [`Client`] does when the limit set with [`Client::set_paused_publications_limit`] is reached
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum OverflowPolicy {
+	/// Stop reading from the connection until the client is resumed.
+	///
+	/// No packets are sent or received in the meantime, including pings, so the server may close the connection if the client stays paused for longer than the keep-alive.
+	BlockReads,
+
+	/// Drop the oldest buffered publication to make room for the new one.
+	DropOldest,
+
+	/// Drop the new publication if it is [`AtMostOnce`](crate::proto::QoS::AtMostOnce), otherwise the oldest buffered `AtMostOnce` publication.
+	/// Publications with a higher quality of service are never dropped, so if none of the buffered publications can be dropped, the buffer grows beyond the limit.
+	DropAtMostOnce,
+}
+
 /// An event generated by the [`Client`]
 #[derive(Debug, PartialEq, Eq)]
 pub enum Event {
@@ -374,19 +1094,57 @@ pub enum Event {
 	/// A publication received from the server
 	Publication(ReceivedPublication),
 
+	/// The header of a publication received from the server whose payload is longer than the threshold set with
+	/// [`Client::set_payload_streaming_threshold`]. Its payload follows as [`Event::StreamedPayloadChunk`]s.
+	StreamedPublication(StreamedPublication),
+
+	/// The next chunk of the payload of the most recent [`Event::StreamedPublication`]
+	StreamedPayloadChunk {
+		chunk: bytes::Bytes,
+
+		/// The length of the rest of the payload. The payload is complete when this is zero.
+		remaining: usize,
+	},
+
 	/// Subscription updates acked by the server
 	SubscriptionUpdates(Vec<SubscriptionUpdateEvent>),
+
+	/// The server did not respond to a ping in time, so the [`Client`] gave up on the connection and will reconnect.
+	///
+	/// This distinguishes keep-alive failures from the connection being broken by an I/O error, which the `Client`
+	/// also recovers from by reconnecting but does not emit an event for.
+	PingTimeout,
+
+	/// The receive backlog of the [`Client`] has stayed at or above the threshold set with [`Client::set_slow_consumer_warning`]
+	/// for longer than its duration without the application taking an event from it.
+	SlowConsumer {
+		/// The number of events in the receive backlog
+		queued_events: usize,
+	},
 }
 
 /// A subscription update event
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum SubscriptionUpdateEvent {
 	Subscribe(crate::proto::SubscribeTo),
-	Unsubscribe(String),
+	Unsubscribe(crate::topic::TopicFilter),
+}
+
+/// The subscriptions of a [`Client`], returned by [`Client::subscriptions`]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Subscriptions {
+	/// The subscriptions that have been acked by the server, along with the [`QoS`](crate::proto::QoS) that the server granted for each of them
+	pub acked: std::collections::BTreeMap<crate::topic::TopicFilter, crate::proto::QoS>,
+
+	/// The changes that have not been acked by the server yet, in the order that they will be applied.
+	///
+	/// This includes changes that have been sent to the server as well as ones that are still waiting to be sent.
+	/// The [`QoS`](crate::proto::QoS) of a pending subscription is the one that was requested; the server may grant a higher one.
+	pub pending: Vec<SubscriptionUpdateEvent>,
 }
 
 /// A message that was received from the server
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct ReceivedPublication {
 	pub topic_name: String,
 	pub dup: bool,
@@ -395,6 +1153,184 @@ pub struct ReceivedPublication {
 	pub payload: bytes::Bytes,
 }
 
+/// The header of a message that was received from the server, whose payload is received in chunks.
+/// See [`Client::set_payload_streaming_threshold`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StreamedPublication {
+	pub topic_name: String,
+	pub dup: bool,
+	pub qos: crate::proto::QoS,
+	pub retain: bool,
+
+	/// The length of the whole payload
+	pub payload_len: usize,
+}
+
+/// Statistics about a [`Client`]
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Stats {
+	/// The time between the most recent PINGREQ sent to the server and the corresponding PINGRESP, if any
+	pub last_ping_round_trip_time: Option<std::time::Duration>,
+
+	/// The number of publications that have been sent to the server, not counting retransmissions
+	pub publications_sent: u64,
+
+	/// The number of `AtLeastOnce` and `ExactlyOnce` publications that the server has acked with a PUBACK or PUBCOMP
+	pub publications_acked: u64,
+
+	/// The number of `AtMostOnce` publications that have been received from the server
+	pub publications_received_at_most_once: u64,
+
+	/// The number of `AtLeastOnce` publications that have been received from the server, including ones that the server sent again
+	pub publications_received_at_least_once: u64,
+
+	/// The number of `ExactlyOnce` publications that have been received from the server
+	pub publications_received_exactly_once: u64,
+
+	/// The number of publications that have been sent to the server again after a reconnect, because the server had not acked them
+	pub retransmissions: u64,
+
+	/// The number of times the client lost its connection to the server and reconnected
+	pub reconnects: u64,
+
+	/// The number of publications that have been sent to the server and are waiting for it to ack them,
+	/// as of the last time the client was polled while it was connected
+	pub publications_in_flight: usize,
+
+	/// The number of publications that are waiting to be sent to the server,
+	/// as of the last time the client was polled while it was connected
+	pub publications_queued: usize,
+}
+
+/// Used to query statistics about the [`Client`]
+///
+/// The handle can be used from a different task than the one polling the `Client`.
+#[derive(Clone, Debug, Default)]
+pub struct StatsHandle(std::sync::Arc<std::sync::Mutex<Stats>>);
+
+impl StatsHandle {
+	/// Returns a snapshot of the current statistics
+	pub fn stats(&self) -> Stats {
+		self.lock().clone()
+	}
+
+	fn update(&self, f: impl FnOnce(&mut Stats)) {
+		f(&mut self.lock());
+	}
+
+	fn lock(&self) -> std::sync::MutexGuard<'_, Stats> {
+		// The stats are plain values that can't be left in an inconsistent state, so a poisoned lock is still usable.
+		self.0.lock().unwrap_or_else(std::sync::PoisonError::into_inner)
+	}
+}
+
+/// Used to change the keep-alive of the [`Client`]
+#[derive(Clone)]
+pub struct KeepAliveHandle(futures::sync::mpsc::Sender<std::time::Duration>);
+
+impl KeepAliveHandle {
+	/// Changes the keep-alive that the [`Client`] advertises to the server.
+	///
+	/// The current connection keeps using the keep-alive it was established with. The new value takes effect
+	/// with the next CONNECT packet that the `Client` sends, including one for a connection attempt that is already in progress
+	/// or waiting to be retried.
+	///
+	/// The returned `Future` resolves when the `Client` is guaranteed the notification. It fails with [`KeepAliveError::KeepAliveTooHigh`]
+	/// if the keep-alive has more seconds than fit in a CONNECT packet.
+	pub fn set_keep_alive(&self, keep_alive: std::time::Duration) -> KeepAliveFuture {
+		if keep_alive.as_secs() > u64::from(u16::MAX) {
+			return KeepAliveFuture(KeepAliveFutureState::Failed(Some(KeepAliveError::KeepAliveTooHigh(keep_alive))));
+		}
+
+		KeepAliveFuture(KeepAliveFutureState::Sending(self.0.clone().send(keep_alive)))
+	}
+}
+
+/// The [`Future`] returned by [`KeepAliveHandle::set_keep_alive`].
+///
+/// It resolves when the new keep-alive has been received by the client.
+#[must_use = "futures do nothing unless polled"]
+pub struct KeepAliveFuture(KeepAliveFutureState);
+
+enum KeepAliveFutureState {
+	Failed(Option<KeepAliveError>),
+	Sending(futures::sink::Send<futures::sync::mpsc::Sender<std::time::Duration>>),
+}
+
+impl Future for KeepAliveFuture {
+	type Item = ();
+	type Error = KeepAliveError;
+
+	fn poll(&mut self) -> futures::Poll<Self::Item, Self::Error> {
+		match &mut self.0 {
+			KeepAliveFutureState::Failed(err) => Err(err.take().expect("KeepAliveFuture polled after completion")),
+
+			KeepAliveFutureState::Sending(send) => {
+				let _ = futures::try_ready!(send.poll().map_err(|_| KeepAliveError::ClientDoesNotExist));
+				Ok(futures::Async::Ready(()))
+			},
+		}
+	}
+}
+
+impl std::fmt::Debug for KeepAliveFuture {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match &self.0 {
+			KeepAliveFutureState::Failed(_) => f.write_str("Failed"),
+			KeepAliveFutureState::Sending(_) => f.write_str("Sending"),
+		}
+	}
+}
+
+/// Used to pause and resume the delivery of publications by the [`Client`]
+#[derive(Clone)]
+pub struct PauseHandle(futures::sync::mpsc::Sender<bool>);
+
+impl PauseHandle {
+	/// Stops the [`Client`] from delivering the publications it receives, either as [`Event::Publication`] or to the handlers registered with [`Client::on`].
+	///
+	/// The connection is kept alive, and publications that are received while the client is paused are acked and buffered
+	/// until [`PauseHandle::resume`] is called. So this should only be used for short periods, or for subscriptions with a low rate of publications.
+	///
+	/// The returned `Future` resolves when the `Client` is guaranteed the notification.
+	pub fn pause(&self) -> PauseFuture {
+		self.send(true)
+	}
+
+	/// Resumes the delivery of publications after [`PauseHandle::pause`], starting with the ones that were buffered while the client was paused.
+	///
+	/// The returned `Future` resolves when the `Client` is guaranteed the notification.
+	pub fn resume(&self) -> PauseFuture {
+		self.send(false)
+	}
+
+	fn send(&self, pause: bool) -> PauseFuture {
+		PauseFuture(self.0.clone().send(pause))
+	}
+}
+
+/// The [`Future`] returned by [`PauseHandle::pause`] and [`PauseHandle::resume`].
+///
+/// It resolves when the notification has been received by the client.
+#[must_use = "futures do nothing unless polled"]
+pub struct PauseFuture(futures::sink::Send<futures::sync::mpsc::Sender<bool>>);
+
+impl Future for PauseFuture {
+	type Item = ();
+	type Error = PauseError;
+
+	fn poll(&mut self) -> futures::Poll<Self::Item, Self::Error> {
+		let _ = futures::try_ready!(self.0.poll().map_err(|_| PauseError::ClientDoesNotExist));
+		Ok(futures::Async::Ready(()))
+	}
+}
+
+impl std::fmt::Debug for PauseFuture {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("PauseFuture").finish_non_exhaustive()
+	}
+}
+
 pub struct ShutdownHandle(futures::sync::mpsc::Sender<()>);
 
 impl ShutdownHandle {
@@ -418,18 +1354,59 @@ enum ClientState<IoS> where IoS: IoSource {
 		will: Option<crate::proto::Publication>,
 		keep_alive: std::time::Duration,
 
+		/// How long to wait for a PINGRESP. `None` means the keep-alive is used.
+		ping_response_timeout: Option<std::time::Duration>,
+
+		/// The multiple of the keep-alive after which the connection is considered dead if nothing was received. `None` means the watchdog is disabled.
+		activity_watchdog: Option<u32>,
+
 		shutdown_send: futures::sync::mpsc::Sender<()>,
 		shutdown_recv: futures::sync::mpsc::Receiver<()>,
 
+		keep_alive_send: futures::sync::mpsc::Sender<std::time::Duration>,
+		keep_alive_recv: futures::sync::mpsc::Receiver<std::time::Duration>,
+
+		/// The keep-alive to use from the next CONNECT packet onwards, if it was changed via a `KeepAliveHandle`
+		next_keep_alive: Option<std::time::Duration>,
+
+		pause_send: futures::sync::mpsc::Sender<bool>,
+		pause_recv: futures::sync::mpsc::Receiver<bool>,
+
+		/// Set when the delivery of publications has been paused via a `PauseHandle`
+		paused: bool,
+
+		/// Publications received while `paused` was set, in the order they were received
+		paused_publications: std::collections::VecDeque<ReceivedPublication>,
+
+		/// The maximum length of `paused_publications`, and what to do when it's reached. `None` means it's unbounded.
+		paused_publications_limit: Option<(usize, OverflowPolicy)>,
+
+		/// The length of the receive backlog, ie `paused_publications` and `pending_events`, and how long it has to stay at or above it
+		/// without an event being returned before `Event::SlowConsumer` is emitted.
+		/// `None` means the warning is disabled.
+		slow_consumer_warning: Option<(usize, std::time::Duration)>,
+		slow_consumer: self::slow_consumer::State,
+
 		packet_identifiers: PacketIdentifiers,
 
 		connect: self::connect::Connect<IoS>,
 		ping: self::ping::State,
+		watchdog: self::watchdog::State,
 		publish: self::publish::State,
 		subscriptions: self::subscriptions::State,
 
+		/// Handlers registered with `Client::on`
+		publication_handlers: Vec<PublicationHandler>,
+
 		/// Packets waiting to be written to the underlying `Framed`
-		packets_waiting_to_be_sent: std::collections::VecDeque<crate::proto::Packet>,
+		packets_waiting_to_be_sent: std::collections::VecDeque<crate::logging_framed::OutgoingPacket>,
+
+		/// Events of packets that were read together and that haven't been returned yet, in the order the packets were read.
+		/// An error, if any, is always the last one.
+		pending_events: std::collections::VecDeque<Result<Event, Error>>,
+
+		/// Set with `Client::set_metrics_sink`. The `Connect` holds a clone for the connections that it establishes.
+		metrics: crate::metrics::Metrics,
 	},
 
 	ShuttingDown {
@@ -453,12 +1430,25 @@ enum ClientState<IoS> where IoS: IoSource {
 	},
 }
 
+/// The maximum number of packets that `client_poll` reads before it sends the packets that they caused, like their acks
+const MAX_PACKETS_READ_TOGETHER: usize = 64;
+
+/// Reads and handles all the packets that are ready, up to `MAX_PACKETS_READ_TOGETHER` at a time, then sends all the resulting packets
+/// and flushes the connection once, if its flush policy allows. Doing this per packet instead would cost a write and a wakeup for every ack under load.
+///
+/// The events of the packets that were read together are queued in `pending_events` and returned one at a time.
 fn client_poll<S>(
 	framed: &mut crate::logging_framed::LoggingFramed<S>,
+	stats: &StatsHandle,
+	metrics: &crate::metrics::Metrics,
 	keep_alive: std::time::Duration,
-	packets_waiting_to_be_sent: &mut std::collections::VecDeque<crate::proto::Packet>,
+	ping_response_timeout: std::time::Duration,
+	activity_timeout: Option<std::time::Duration>,
+	packets_waiting_to_be_sent: &mut std::collections::VecDeque<crate::logging_framed::OutgoingPacket>,
+	pending_events: &mut std::collections::VecDeque<Result<Event, Error>>,
 	packet_identifiers: &mut PacketIdentifiers,
 	ping: &mut self::ping::State,
+	watchdog: &mut self::watchdog::State,
 	publish: &mut self::publish::State,
 	subscriptions: &mut self::subscriptions::State,
 ) -> futures::Poll<Event, Error>
@@ -466,86 +1456,180 @@ where
 	S: tokio_io::AsyncRead + tokio_io::AsyncWrite,
 {
 	loop {
-		// Begin sending any packets waiting to be sent
-		while let Some(packet) = packets_waiting_to_be_sent.pop_front() {
-			match framed.start_send(packet).map_err(Error::EncodePacket)? {
-				futures::AsyncSink::Ready => (),
-
-				futures::AsyncSink::NotReady(packet) => {
-					packets_waiting_to_be_sent.push_front(packet);
-					break;
-				},
-			}
+		if let Some(event) = pending_events.pop_front() {
+			return event.map(futures::Async::Ready);
 		}
 
-		// Finish sending any packets waiting to be sent.
-		//
-		// We don't care whether this returns Async::NotReady or Ready.
-		let _ = framed.poll_complete().map_err(Error::EncodePacket)?;
+		let mut packets_read = 0;
+		let result = loop {
+			let received = match framed.poll_received().map_err(Error::DecodePacket) {
+				Ok(futures::Async::Ready(Some(received))) => Some(received),
+				Ok(futures::Async::Ready(None)) => break Err(Error::ServerClosedConnection),
+				Ok(futures::Async::NotReady) => None,
+				Err(err) => break Err(err),
+			};
+			let read_packet = received.is_some();
 
-		let mut continue_loop = false;
+			// Each chunk of a streamed payload is returned before the next one is read, so that the chunks don't pile up in `pending_events`
+			let read_payload_chunk = matches!(received, Some(crate::logging_framed::Received::PayloadChunk(_)));
 
-		let mut packet = match framed.poll().map_err(Error::DecodePacket)? {
-			futures::Async::Ready(Some(packet)) => {
-				// May have more packets after this one, so keep looping
-				continue_loop = true;
-				Some(packet)
-			},
-			futures::Async::Ready(None) => return Err(Error::ServerClosedConnection),
-			futures::Async::NotReady => None,
+			if let Err(err) = handle_packet(
+				received,
+				stats,
+				metrics,
+				keep_alive,
+				ping_response_timeout,
+				activity_timeout,
+				packets_waiting_to_be_sent,
+				pending_events,
+				packet_identifiers,
+				ping,
+				watchdog,
+				publish,
+				subscriptions,
+			) {
+				break Err(err);
+			}
+
+			if !read_packet {
+				break Ok(());
+			}
+
+			packets_read += 1;
+			if packets_read == MAX_PACKETS_READ_TOGETHER || read_payload_chunk {
+				break Ok(());
+			}
 		};
 
-		let mut new_packets_to_be_sent = vec![];
+		// Send the packets even if there was an error, since the events before it are still returned. If the connection is broken,
+		// this fails too and the packets are sent again on the next connection.
+		let packets_sent = match send_packets(framed, keep_alive, packets_waiting_to_be_sent, ping) {
+			Ok(packets_sent) => packets_sent,
+			Err(err) => {
+				if result.is_ok() {
+					pending_events.push_back(Err(err));
+				}
+				0
+			},
+		};
 
+		if let Err(err) = result {
+			pending_events.push_back(Err(err));
+		}
 
-		// Ping
-		match ping.poll(&mut packet, keep_alive)? {
-			futures::Async::Ready(packet) => new_packets_to_be_sent.push(packet),
-			futures::Async::NotReady => (),
+		// Reading more packets may have become possible if any were read or sent
+		if pending_events.is_empty() && packets_read == 0 && packets_sent == 0 {
+			return Ok(futures::Async::NotReady);
 		}
+	}
+}
 
-		// Publish
-		let (new_publish_packets, publication_received) = publish.poll(
-			&mut packet,
-			packet_identifiers,
-		)?;
-		new_packets_to_be_sent.extend(new_publish_packets);
+/// Handles the given packet or part of a streamed publication that was read from the connection, or does any work that doesn't depend on
+/// a packet, like timers, if it's `None`.
+fn handle_packet(
+	received: Option<crate::logging_framed::Received>,
+	stats: &StatsHandle,
+	metrics: &crate::metrics::Metrics,
+	keep_alive: std::time::Duration,
+	ping_response_timeout: std::time::Duration,
+	activity_timeout: Option<std::time::Duration>,
+	packets_waiting_to_be_sent: &mut std::collections::VecDeque<crate::logging_framed::OutgoingPacket>,
+	pending_events: &mut std::collections::VecDeque<Result<Event, Error>>,
+	packet_identifiers: &mut PacketIdentifiers,
+	ping: &mut self::ping::State,
+	watchdog: &mut self::watchdog::State,
+	publish: &mut self::publish::State,
+	subscriptions: &mut self::subscriptions::State,
+) -> Result<(), Error> {
+	// Watchdog
+	if let Some(activity_timeout) = activity_timeout {
+		watchdog.poll(received.is_some(), activity_timeout)?;
+	}
 
-		// Subscriptions
-		let subscription_updates =
-			if publication_received.is_some() {
-				vec![]
-			}
-			else {
-				let (new_subscription_packets, subscription_updates) = subscriptions.poll(
-					&mut packet,
-					packet_identifiers,
-				)?;
-				new_packets_to_be_sent.extend(new_subscription_packets);
-				subscription_updates
-			};
+	// Streamed publications
+	let mut packet = match received {
+		Some(crate::logging_framed::Received::Packet(packet)) => Some(packet),
+
+		Some(crate::logging_framed::Received::StreamedPublish { publish: publish_packet, payload_len }) => {
+			let streamed_publication = publish.streamed_publish_received(publish_packet, payload_len, stats);
+			pending_events.push_back(Ok(Event::StreamedPublication(streamed_publication)));
+			None
+		},
+
+		Some(crate::logging_framed::Received::PayloadChunk(chunk)) => {
+			let remaining = publish.payload_chunk_received(chunk.len(), packets_waiting_to_be_sent);
+			pending_events.push_back(Ok(Event::StreamedPayloadChunk { chunk, remaining }));
+			None
+		},
+
+		None => None,
+	};
+
+	// Ping
+	match ping.poll(&mut packet, keep_alive, ping_response_timeout, stats)? {
+		futures::Async::Ready(packet) => packets_waiting_to_be_sent.push_back(packet.into()),
+		futures::Async::NotReady => (),
+	}
 
+	// Publish
+	let publication_received = publish.poll(
+		&mut packet,
+		packets_waiting_to_be_sent,
+		packet_identifiers,
+		stats,
+		metrics,
+	)?;
+
+	// Subscriptions
+	let (new_subscription_packets, subscription_updates) = subscriptions.poll(
+		&mut packet,
+		packet_identifiers,
+	)?;
+	packets_waiting_to_be_sent.extend(new_subscription_packets.into_iter().map(Into::into));
+
+	assert!(packet.is_none(), "unconsumed packet");
+
+	if let Some(publication_received) = publication_received {
+		pending_events.push_back(Ok(Event::Publication(publication_received)));
+	}
 
-		assert!(packet.is_none(), "unconsumed packet");
+	if !subscription_updates.is_empty() {
+		pending_events.push_back(Ok(Event::SubscriptionUpdates(subscription_updates)));
+	}
 
-		if !new_packets_to_be_sent.is_empty() {
-			// Have new packets to send, so keep looping
-			continue_loop = true;
-			packets_waiting_to_be_sent.extend(new_packets_to_be_sent);
-		}
+	Ok(())
+}
 
-		if let Some(publication_received) = publication_received {
-			return Ok(futures::Async::Ready(Event::Publication(publication_received)));
-		}
+/// Writes as many of the packets waiting to be sent as the connection accepts, then flushes it if its flush policy says so. Returns the number of packets that were written.
+fn send_packets<S>(
+	framed: &mut crate::logging_framed::LoggingFramed<S>,
+	keep_alive: std::time::Duration,
+	packets_waiting_to_be_sent: &mut std::collections::VecDeque<crate::logging_framed::OutgoingPacket>,
+	ping: &mut self::ping::State,
+) -> Result<usize, Error>
+where
+	S: tokio_io::AsyncRead + tokio_io::AsyncWrite,
+{
+	let mut packets_sent = 0;
 
-		if !subscription_updates.is_empty() {
-			return Ok(futures::Async::Ready(Event::SubscriptionUpdates(subscription_updates)));
-		}
+	while let Some(packet) = packets_waiting_to_be_sent.pop_front() {
+		match framed.start_send(packet).map_err(Error::EncodePacket)? {
+			futures::AsyncSink::Ready => {
+				ping.packet_sent(keep_alive);
+				packets_sent += 1;
+			},
 
-		if !continue_loop {
-			return Ok(futures::Async::NotReady);
+			futures::AsyncSink::NotReady(packet) => {
+				packets_waiting_to_be_sent.push_front(packet);
+				break;
+			},
 		}
 	}
+
+	// We don't care whether this returns Async::NotReady or Ready.
+	let _ = framed.poll_flush_policy().map_err(Error::EncodePacket)?;
+
+	Ok(packets_sent)
 }
 
 struct PacketIdentifiers {
@@ -612,7 +1696,10 @@ pub enum Error {
 	DecodePacket(crate::proto::DecodeError),
 	DuplicateExactlyOncePublishPacketNotMarkedDuplicate(crate::proto::PacketIdentifier),
 	EncodePacket(crate::proto::EncodeError),
+	InactivityTimeout,
+	InactivityTimer(tokio_timer::Error),
 	PacketIdentifiersExhausted,
+	PingTimeout,
 	PingTimer(tokio_timer::Error),
 	ServerClosedConnection,
 	SubAckDoesNotContainEnoughQoS(crate::proto::PacketIdentifier, usize, usize),
@@ -649,6 +1736,8 @@ impl Error {
 				std::io::ErrorKind::WriteZero => true,
 				_ => false,
 			},
+			Error::InactivityTimeout |
+			Error::PingTimeout |
 			Error::ServerClosedConnection => true,
 			_ => false,
 		}
@@ -671,9 +1760,18 @@ impl std::fmt::Display for Error {
 			Error::EncodePacket(err) =>
 				write!(f, "could not encode packet: {}", err),
 
+			Error::InactivityTimeout =>
+				write!(f, "did not receive anything from server in time"),
+
+			Error::InactivityTimer(err) =>
+				write!(f, "activity watchdog timer failed: {}", err),
+
 			Error::PacketIdentifiersExhausted =>
 				write!(f, "all packet identifiers exhausted"),
 
+			Error::PingTimeout =>
+				write!(f, "did not receive PINGRESP from server in time"),
+
 			Error::PingTimer(err) =>
 				write!(f, "ping timer failed: {}", err),
 
@@ -705,7 +1803,10 @@ impl std::error::Error for Error {
 			Error::DecodePacket(err) => Some(err),
 			Error::DuplicateExactlyOncePublishPacketNotMarkedDuplicate(_) => None,
 			Error::EncodePacket(err) => Some(err),
+			Error::InactivityTimeout => None,
+			Error::InactivityTimer(err) => Some(err),
 			Error::PacketIdentifiersExhausted => None,
+			Error::PingTimeout => None,
 			Error::PingTimer(err) => Some(err),
 			Error::ServerClosedConnection => None,
 			Error::SubAckDoesNotContainEnoughQoS(_, _, _) => None,
@@ -728,6 +1829,43 @@ impl std::fmt::Display for UnexpectedSubUnsubAckReason {
 	}
 }
 
+#[derive(Debug)]
+pub enum KeepAliveError {
+	ClientDoesNotExist,
+	KeepAliveTooHigh(std::time::Duration),
+}
+
+impl std::fmt::Display for KeepAliveError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			KeepAliveError::ClientDoesNotExist =>
+				write!(f, "client does not exist"),
+			KeepAliveError::KeepAliveTooHigh(keep_alive) =>
+				write!(f, "keep-alive {:?} is too high", keep_alive),
+		}
+	}
+}
+
+impl std::error::Error for KeepAliveError {
+}
+
+#[derive(Debug)]
+pub enum PauseError {
+	ClientDoesNotExist,
+}
+
+impl std::fmt::Display for PauseError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			PauseError::ClientDoesNotExist =>
+				write!(f, "client does not exist"),
+		}
+	}
+}
+
+impl std::error::Error for PauseError {
+}
+
 #[derive(Debug)]
 pub enum ShutdownError {
 	ClientDoesNotExist,
@@ -757,7 +1895,7 @@ mod tests {
 		assert_eq!(PacketIdentifiers::SIZE, 1024);
 
 		let mut packet_identifiers: PacketIdentifiers = Default::default();
-		assert_eq!(packet_identifiers.in_use[..], Box::new([0; PacketIdentifiers::SIZE])[..]);
+		assert_eq!(packet_identifiers.in_use[..], [0; PacketIdentifiers::SIZE][..]);
 
 		assert_eq!(packet_identifiers.reserve().unwrap().get(), 1);
 		let mut expected = Box::new([0; PacketIdentifiers::SIZE]);
@@ -795,7 +1933,7 @@ mod tests {
 		assert_eq!(packet_identifiers.in_use[..], expected[..]);
 
 		packet_identifiers.discard(crate::proto::PacketIdentifier::new(4).unwrap());
-		assert_eq!(packet_identifiers.in_use[..], Box::new([0; PacketIdentifiers::SIZE])[..]);
+		assert_eq!(packet_identifiers.in_use[..], [0; PacketIdentifiers::SIZE][..]);
 
 		assert_eq!(packet_identifiers.reserve().unwrap().get(), 5);
 		let mut expected = Box::new([0; PacketIdentifiers::SIZE]);
@@ -2,7 +2,13 @@ use futures::Future;
 
 pub(super) enum State {
 	BeginWaitingForNextPing,
-	WaitingForNextPing(tokio_timer::Delay),
+	WaitingForNextPing {
+		ping_timer: tokio_timer::Delay,
+
+		/// Set when a PINGREQ has been sent and the corresponding PINGRESP has not been received yet.
+		/// Contains the time the PINGREQ was sent.
+		ping_response_timer: Option<(std::time::Instant, tokio_timer::Delay)>,
+	},
 }
 
 impl State {
@@ -10,33 +16,67 @@ impl State {
 		&mut self,
 		packet: &mut Option<crate::proto::Packet>,
 		keep_alive: std::time::Duration,
+		ping_response_timeout: std::time::Duration,
+		stats: &super::StatsHandle,
 	) -> futures::Poll<crate::proto::Packet, super::Error> {
 		if let Some(crate::proto::Packet::PingResp(crate::proto::PingResp)) = packet {
 			let _ = packet.take();
 
 			match self {
 				State::BeginWaitingForNextPing => (),
-				State::WaitingForNextPing(ping_timer) => ping_timer.reset(deadline(std::time::Instant::now(), keep_alive)),
+				State::WaitingForNextPing { ping_timer, ping_response_timer } => {
+					let now = tokio_timer::clock::now();
+
+					ping_timer.reset(deadline(now, keep_alive));
+
+					if let Some((ping_sent, _)) = ping_response_timer.take() {
+						let round_trip_time = now - ping_sent;
+						log::trace!("ping round-trip time: {:?}", round_trip_time);
+						stats.update(|stats| stats.last_ping_round_trip_time = Some(round_trip_time));
+					}
+				},
 			}
 		}
 
+		if keep_alive.as_secs() == 0 {
+			// A keep-alive of zero is advertised to the server as keep-alive being disabled,
+			// so there's no need to ping.
+			return Ok(futures::Async::NotReady);
+		}
+
 		loop {
 			log::trace!("    {:?}", self);
 
 			match self {
 				State::BeginWaitingForNextPing => {
-					let ping_timer = tokio_timer::Delay::new(deadline(std::time::Instant::now(), keep_alive));
-					*self = State::WaitingForNextPing(ping_timer);
+					let ping_timer = tokio_timer::Delay::new(deadline(tokio_timer::clock::now(), keep_alive));
+					*self = State::WaitingForNextPing { ping_timer, ping_response_timer: None };
 				},
 
-				State::WaitingForNextPing(ping_timer) => match ping_timer.poll().map_err(super::Error::PingTimer)? {
-					futures::Async::Ready(()) => {
-						ping_timer.reset(deadline(ping_timer.deadline(), keep_alive));
-						return Ok(futures::Async::Ready(crate::proto::Packet::PingReq(crate::proto::PingReq)));
-					},
+				State::WaitingForNextPing { ping_timer, ping_response_timer } => {
+					if let Some((_, ping_response_timer)) = ping_response_timer {
+						match ping_response_timer.poll().map_err(super::Error::PingTimer)? {
+							futures::Async::Ready(()) => return Err(super::Error::PingTimeout),
+							futures::Async::NotReady => (),
+						}
+					}
+
+					match ping_timer.poll().map_err(super::Error::PingTimer)? {
+						futures::Async::Ready(()) => {
+							ping_timer.reset(deadline(ping_timer.deadline(), keep_alive));
+
+							// If a previous PINGREQ is still unanswered, keep waiting on its timer rather than restarting it
+							if ping_response_timer.is_none() {
+								let now = tokio_timer::clock::now();
+								*ping_response_timer = Some((now, tokio_timer::Delay::new(now + ping_response_timeout)));
+							}
 
-					futures::Async::NotReady =>
-						return Ok(futures::Async::NotReady),
+							return Ok(futures::Async::Ready(crate::proto::Packet::PingReq(crate::proto::PingReq)));
+						},
+
+						futures::Async::NotReady =>
+							return Ok(futures::Async::NotReady),
+					}
 				},
 			}
 		}
@@ -45,6 +85,15 @@ impl State {
 	pub(super) fn new_connection(&mut self) {
 		*self = State::BeginWaitingForNextPing;
 	}
+
+	/// The keep-alive is satisfied by the client sending any packet, not just PINGREQ,
+	/// so this is used to push back the next ping when a packet is sent to the server.
+	pub(super) fn packet_sent(&mut self, keep_alive: std::time::Duration) {
+		match self {
+			State::BeginWaitingForNextPing => (),
+			State::WaitingForNextPing { ping_timer, .. } => ping_timer.reset(deadline(tokio_timer::clock::now(), keep_alive)),
+		}
+	}
 }
 
 impl std::fmt::Debug for State {
@@ -59,3 +108,81 @@ impl std::fmt::Debug for State {
 fn deadline(now: std::time::Instant, keep_alive: std::time::Duration) -> std::time::Instant {
 	now + keep_alive / 2
 }
+
+#[cfg(test)]
+mod tests {
+	#[test]
+	fn zero_keep_alive_does_not_ping() {
+		let mut state = super::State::BeginWaitingForNextPing;
+
+		// No timer is created for a zero keep-alive, so this doesn't need to run inside a tokio runtime.
+		for _ in 0..3 {
+			match state.poll(&mut None, std::time::Duration::from_secs(0), std::time::Duration::from_secs(0), &Default::default()).unwrap() {
+				futures::Async::Ready(packet) => panic!("unexpected packet {:?}", packet),
+				futures::Async::NotReady => (),
+			}
+
+			match state {
+				super::State::BeginWaitingForNextPing => (),
+				super::State::WaitingForNextPing { .. } => panic!("ping timer should not have been created"),
+			}
+		}
+	}
+
+	#[test]
+	fn unanswered_ping_times_out() {
+		let mut timer = crate::client::mock_timer::MockTimer::new();
+		let mut state = super::State::BeginWaitingForNextPing;
+		let mut poll = |timer: &mut crate::client::mock_timer::MockTimer|
+			timer.run(|| state.poll(&mut None, std::time::Duration::from_secs(2), std::time::Duration::from_millis(100), &Default::default()));
+
+		assert!(poll(&mut timer).unwrap().is_not_ready());
+
+		// The first ping is sent after half the keep-alive
+		timer.advance(std::time::Duration::from_millis(999));
+		assert!(poll(&mut timer).unwrap().is_not_ready());
+
+		timer.advance(std::time::Duration::from_millis(1));
+		match poll(&mut timer).unwrap() {
+			futures::Async::Ready(crate::proto::Packet::PingReq(crate::proto::PingReq)) => (),
+			result => panic!("expected PingReq but got {:?}", result),
+		}
+		assert!(poll(&mut timer).unwrap().is_not_ready());
+
+		// The ping times out after the ping response timeout
+		timer.advance(std::time::Duration::from_millis(99));
+		assert!(poll(&mut timer).unwrap().is_not_ready());
+
+		timer.advance(std::time::Duration::from_millis(1));
+		match poll(&mut timer) {
+			Err(crate::Error::PingTimeout) => (),
+			result => panic!("expected PingTimeout but got {:?}", result),
+		}
+	}
+
+	#[test]
+	fn ping_round_trip_time_is_recorded() {
+		let mut timer = crate::client::mock_timer::MockTimer::new();
+		let mut state = super::State::BeginWaitingForNextPing;
+		let stats: super::super::StatsHandle = Default::default();
+		let keep_alive = std::time::Duration::from_secs(2);
+
+		assert!(timer.run(|| state.poll(&mut None, keep_alive, keep_alive, &stats)).unwrap().is_not_ready());
+
+		timer.advance(std::time::Duration::from_secs(1));
+		match timer.run(|| state.poll(&mut None, keep_alive, keep_alive, &stats)).unwrap() {
+			futures::Async::Ready(crate::proto::Packet::PingReq(crate::proto::PingReq)) => (),
+			result => panic!("expected PingReq but got {:?}", result),
+		}
+		assert_eq!(stats.stats().last_ping_round_trip_time, None);
+
+		timer.advance(std::time::Duration::from_millis(100));
+		let mut packet = Some(crate::proto::Packet::PingResp(crate::proto::PingResp));
+		match timer.run(|| state.poll(&mut packet, keep_alive, keep_alive, &stats)).unwrap() {
+			futures::Async::Ready(packet) => panic!("unexpected packet {:?}", packet),
+			futures::Async::NotReady => (),
+		}
+
+		assert_eq!(stats.stats().last_ping_round_trip_time, Some(std::time::Duration::from_millis(100)));
+	}
+}
@@ -1,62 +1,93 @@
-use futures::{ Future, IntoFuture, Sink, Stream };
+use futures::{ Future, Stream };
 
 #[derive(Debug)]
 pub(super) struct State {
-	publish_request_send: futures::sync::mpsc::Sender<PublishRequest>,
-	publish_request_recv: futures::sync::mpsc::Receiver<PublishRequest>,
+	/// The publish requests of all `PublishHandle`s
+	publish_queue: super::publish_queue::PublishQueue,
 
 	publish_requests_waiting_to_be_sent: std::collections::VecDeque<PublishRequest>,
 
-	/// Holds PUBLISH packets sent by us, waiting for a corresponding PUBACK or PUBREC
-	waiting_to_be_acked:
-		std::collections::BTreeMap<crate::proto::PacketIdentifier, (futures::sync::oneshot::Sender<()>, crate::proto::Publish)>,
+	/// The largest remaining length of PUBLISH packets that may be sent, shared with all `PublishHandle`s.
+	/// `usize::max_value()` if there is no limit.
+	max_outgoing_packet_size: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+
+	/// The interceptors that outgoing and incoming publications go through, shared with all `PublishHandle`s
+	interceptors: crate::interceptor::Interceptors,
+
+	/// The completions of the publish requests made with `Client::publish`
+	completions: super::completions::Completions,
+
+	/// Holds the publications of PUBLISH packets sent by us, waiting for a corresponding PUBACK or PUBREC, or for a PUBCOMP after the PUBREC
+	in_flight: super::in_flight::InFlight<InFlightPublication>,
 
 	/// Holds the identifiers of PUBREC packets sent by us, waiting for a corresponding PUBREL,
 	/// and the contents of the original PUBLISH packet for which we sent the PUBREC
 	waiting_to_be_released:
 		std::collections::BTreeMap<crate::proto::PacketIdentifier, crate::ReceivedPublication>,
 
-	/// Holds PUBLISH packets sent by us, waiting for a corresponding PUBCOMP
-	waiting_to_be_completed:
-		std::collections::BTreeMap<crate::proto::PacketIdentifier, (futures::sync::oneshot::Sender<()>, crate::proto::Publish)>,
+	/// Whether the PUBACKs of received at-least-once publications are only sent once they have been acked with an `AckHandle`
+	manual_acks: bool,
+
+	/// The identifiers of the at-least-once publications that were received while `manual_acks` is set and have not been acked yet,
+	/// in the order they were received. The identifiers of publications that were received on a previous connection are `None`,
+	/// since the server sends those publications again instead of expecting a PUBACK for them.
+	waiting_for_manual_ack: std::collections::VecDeque<Option<crate::proto::PacketIdentifier>>,
+
+	manual_ack_send: futures::sync::mpsc::UnboundedSender<()>,
+	manual_ack_recv: futures::sync::mpsc::UnboundedReceiver<()>,
+
+	/// The streamed publication whose payload is being received, if any
+	streamed_payload: Option<StreamedPayload>,
 }
 
 impl State {
 	pub(super) fn poll(
 		&mut self,
 		packet: &mut Option<crate::proto::Packet>,
+		packets_waiting_to_be_sent: &mut std::collections::VecDeque<crate::logging_framed::OutgoingPacket>,
 		packet_identifiers: &mut super::PacketIdentifiers,
-	) -> Result<(Vec<crate::proto::Packet>, Option<crate::ReceivedPublication>), super::Error> {
-		let mut packets_waiting_to_be_sent = vec![];
+		stats: &super::StatsHandle,
+		metrics: &crate::metrics::Metrics,
+	) -> Result<Option<crate::ReceivedPublication>, super::Error> {
 		let mut publication_received = None;
 
 		match packet.take() {
-			Some(crate::proto::Packet::PubAck(crate::proto::PubAck { packet_identifier })) => match self.waiting_to_be_acked.remove(&packet_identifier) {
-				Some((ack_sender, _)) => {
+			Some(crate::proto::Packet::PubAck(crate::proto::PubAck { packet_identifier })) => match self.remove_in_flight(packet_identifier, InFlightState::WaitingToBeAcked) {
+				Some(InFlightPublication { ack_sender, span, .. }) => {
 					packet_identifiers.discard(packet_identifier);
 
-					match ack_sender.send(()) {
-						Ok(()) => (),
-						Err(()) => log::debug!("could not send ack for publish request because ack receiver has been dropped"),
-					}
+					send_ack(ack_sender);
+
+					stats.update(|stats| stats.publications_acked += 1);
+
+					span.event("received PUBACK");
+				},
+				None => {
+					log::warn!("ignoring PUBACK for a PUBLISH we never sent");
+					metrics.packet_ignored(&crate::proto::Packet::PubAck(crate::proto::PubAck { packet_identifier }));
 				},
-				None => log::warn!("ignoring PUBACK for a PUBLISH we never sent"),
 			},
 
-			Some(crate::proto::Packet::PubComp(crate::proto::PubComp { packet_identifier })) => match self.waiting_to_be_completed.remove(&packet_identifier) {
-				Some((ack_sender, _)) => {
+			Some(crate::proto::Packet::PubComp(crate::proto::PubComp { packet_identifier })) => match self.remove_in_flight(packet_identifier, InFlightState::WaitingToBeCompleted) {
+				Some(InFlightPublication { ack_sender, span, .. }) => {
 					packet_identifiers.discard(packet_identifier);
 
-					match ack_sender.send(()) {
-						Ok(()) => (),
-						Err(()) => log::debug!("could not send ack for publish request because ack receiver has been dropped"),
-					}
+					send_ack(ack_sender);
+
+					stats.update(|stats| stats.publications_acked += 1);
+
+					span.event("received PUBCOMP");
+				},
+				None => {
+					log::warn!("ignoring PUBCOMP for a PUBREL we never sent");
+					metrics.packet_ignored(&crate::proto::Packet::PubComp(crate::proto::PubComp { packet_identifier }));
 				},
-				None => log::warn!("ignoring PUBCOMP for a PUBREL we never sent"),
 			},
 
 			Some(crate::proto::Packet::Publish(crate::proto::Publish { packet_identifier_dup_qos, retain, topic_name, payload })) => match packet_identifier_dup_qos {
 				crate::proto::PacketIdentifierDupQoS::AtMostOnce => {
+					stats.update(|stats| stats.publications_received_at_most_once += 1);
+
 					publication_received = Some(crate::ReceivedPublication {
 						topic_name,
 						dup: false,
@@ -67,6 +98,8 @@ impl State {
 				},
 
 				crate::proto::PacketIdentifierDupQoS::AtLeastOnce(packet_identifier, dup) => {
+					stats.update(|stats| stats.publications_received_at_least_once += 1);
+
 					publication_received = Some(crate::ReceivedPublication {
 						topic_name,
 						dup,
@@ -75,9 +108,14 @@ impl State {
 						payload,
 					});
 
-					packets_waiting_to_be_sent.push(crate::proto::Packet::PubAck(crate::proto::PubAck {
-						packet_identifier,
-					}));
+					if self.manual_acks {
+						self.waiting_for_manual_ack.push_back(Some(packet_identifier));
+					}
+					else {
+						packets_waiting_to_be_sent.push_back(crate::proto::Packet::PubAck(crate::proto::PubAck {
+							packet_identifier,
+						}).into());
+					}
 				},
 
 				crate::proto::PacketIdentifierDupQoS::ExactlyOnce(packet_identifier, dup) => {
@@ -102,129 +140,220 @@ impl State {
 						},
 					}
 
-					packets_waiting_to_be_sent.push(crate::proto::Packet::PubRec(crate::proto::PubRec {
+					packets_waiting_to_be_sent.push_back(crate::proto::Packet::PubRec(crate::proto::PubRec {
 						packet_identifier,
-					}));
+					}).into());
 				},
 			},
 
 			Some(crate::proto::Packet::PubRec(crate::proto::PubRec { packet_identifier })) => {
-				match self.waiting_to_be_acked.remove(&packet_identifier) {
-					Some((ack_sender, packet)) => {
-						self.waiting_to_be_completed.insert(packet_identifier, (ack_sender, packet));
+				match self.in_flight.get_mut(packet_identifier) {
+					Some(in_flight) if in_flight.state == InFlightState::WaitingToBeAcked => {
+						in_flight.state = InFlightState::WaitingToBeCompleted;
+
+						in_flight.span.event("received PUBREC");
+					},
+					_ => {
+						log::warn!("ignoring PUBREC for a PUBLISH we never sent");
+						metrics.packet_ignored(&crate::proto::Packet::PubRec(crate::proto::PubRec { packet_identifier }));
 					},
-					None => log::warn!("ignoring PUBREC for a PUBLISH we never sent"),
 				}
 
-				packets_waiting_to_be_sent.push(crate::proto::Packet::PubRel(crate::proto::PubRel {
+				packets_waiting_to_be_sent.push_back(crate::proto::Packet::PubRel(crate::proto::PubRel {
 					packet_identifier,
-				}));
+				}).into());
 			},
 
 			Some(crate::proto::Packet::PubRel(crate::proto::PubRel { packet_identifier })) => {
 				if let Some(publication) = self.waiting_to_be_released.remove(&packet_identifier) {
 					packet_identifiers.discard(packet_identifier);
+					stats.update(|stats| stats.publications_received_exactly_once += 1);
 					publication_received = Some(publication);
 				}
 				else {
 					log::warn!("ignoring PUBREL for a PUBREC we never sent");
+					metrics.packet_ignored(&crate::proto::Packet::PubRel(crate::proto::PubRel { packet_identifier }));
 				}
 
-				packets_waiting_to_be_sent.push(crate::proto::Packet::PubComp(crate::proto::PubComp {
+				packets_waiting_to_be_sent.push_back(crate::proto::Packet::PubComp(crate::proto::PubComp {
 					packet_identifier,
-				}));
+				}).into());
 			},
 
 			other => *packet = other,
 		}
 
+		while let futures::Async::Ready(Some(())) = self.manual_ack_recv.poll().expect("UnboundedReceiver::poll cannot fail") {
+			match self.waiting_for_manual_ack.pop_front() {
+				Some(Some(packet_identifier)) =>
+					packets_waiting_to_be_sent.push_back(crate::proto::Packet::PubAck(crate::proto::PubAck {
+						packet_identifier,
+					}).into()),
+				Some(None) => (),
+				None => log::warn!("ignoring manual ack because there is no received publication waiting for one"),
+			}
+		}
+
+		self.publish_queue.poll(&mut self.publish_requests_waiting_to_be_sent);
 
-		while let futures::Async::Ready(Some(publish_request)) = self.publish_request_recv.poll().expect("Receiver::poll cannot fail") {
-			self.publish_requests_waiting_to_be_sent.push_back(publish_request);
+		let mut publications_sent = 0;
+		let result = self.send_publish_requests(packets_waiting_to_be_sent, packet_identifiers, &mut publications_sent);
+
+		// Updated once for all the publications that were sent, since the stats are behind a lock
+		if publications_sent > 0 {
+			stats.update(|stats| stats.publications_sent += publications_sent);
 		}
 
+		result?;
 
-		while let Some(PublishRequest { publication, ack_sender }) = self.publish_requests_waiting_to_be_sent.pop_front() {
-			match publication.qos {
-				crate::proto::QoS::AtMostOnce => {
-					packets_waiting_to_be_sent.push(crate::proto::Packet::Publish(crate::proto::Publish {
-						packet_identifier_dup_qos: crate::proto::PacketIdentifierDupQoS::AtMostOnce,
-						retain: publication.retain,
-						topic_name: publication.topic_name,
-						payload: publication.payload,
-					}));
-
-					match ack_sender.send(()) {
-						Ok(()) => (),
-						Err(()) => log::debug!("could not send ack for publish request because ack receiver has been dropped"),
-					}
-				},
+		Ok(publication_received)
+	}
 
-				crate::proto::QoS::AtLeastOnce => {
-					let packet_identifier = match packet_identifiers.reserve() {
-						Ok(packet_identifier) => packet_identifier,
-						Err(err) => {
-							self.publish_requests_waiting_to_be_sent.push_front(PublishRequest { publication, ack_sender });
-							return Err(err);
-						},
-					};
+	/// Handles the header of a PUBLISH packet whose payload is streamed, and returns the event for it.
+	/// Its PUBACK, if any, is sent once the whole payload has been received.
+	pub(super) fn streamed_publish_received(
+		&mut self,
+		publish: crate::proto::Publish,
+		payload_len: usize,
+		stats: &super::StatsHandle,
+	) -> super::StreamedPublication {
+		let crate::proto::Publish { packet_identifier_dup_qos, retain, topic_name, payload: _ } = publish;
 
-					let packet = crate::proto::Packet::Publish(crate::proto::Publish {
-						packet_identifier_dup_qos: crate::proto::PacketIdentifierDupQoS::AtLeastOnce(packet_identifier, false),
-						retain: publication.retain,
-						topic_name: publication.topic_name.clone(),
-						payload: publication.payload.clone(),
+		let (qos, dup, puback) = match packet_identifier_dup_qos {
+			crate::proto::PacketIdentifierDupQoS::AtMostOnce => {
+				stats.update(|stats| stats.publications_received_at_most_once += 1);
+				(crate::proto::QoS::AtMostOnce, false, None)
+			},
+
+			crate::proto::PacketIdentifierDupQoS::AtLeastOnce(packet_identifier, dup) => {
+				stats.update(|stats| stats.publications_received_at_least_once += 1);
+
+				if self.manual_acks {
+					self.waiting_for_manual_ack.push_back(Some(packet_identifier));
+					(crate::proto::QoS::AtLeastOnce, dup, None)
+				}
+				else {
+					(crate::proto::QoS::AtLeastOnce, dup, Some(packet_identifier))
+				}
+			},
+
+			crate::proto::PacketIdentifierDupQoS::ExactlyOnce(..) => unreachable!("the payloads of exactly-once publications are not streamed"),
+		};
+
+		self.streamed_payload = Some(StreamedPayload { remaining: payload_len, puback });
+
+		super::StreamedPublication {
+			topic_name,
+			dup,
+			qos,
+			retain,
+			payload_len,
+		}
+	}
+
+	/// Handles a chunk of the payload of the streamed publication, and returns the length of the rest of the payload.
+	pub(super) fn payload_chunk_received(
+		&mut self,
+		chunk_len: usize,
+		packets_waiting_to_be_sent: &mut std::collections::VecDeque<crate::logging_framed::OutgoingPacket>,
+	) -> usize {
+		let streamed_payload = self.streamed_payload.as_mut().expect("payload chunk received without a streamed publication");
+		streamed_payload.remaining -= chunk_len;
+
+		let remaining = streamed_payload.remaining;
+		if remaining == 0 {
+			if let Some(StreamedPayload { puback: Some(packet_identifier), .. }) = self.streamed_payload.take() {
+				packets_waiting_to_be_sent.push_back(crate::proto::Packet::PubAck(crate::proto::PubAck {
+					packet_identifier,
+				}).into());
+			}
+		}
+
+		remaining
+	}
+
+	fn send_publish_requests(
+		&mut self,
+		packets_waiting_to_be_sent: &mut std::collections::VecDeque<crate::logging_framed::OutgoingPacket>,
+		packet_identifiers: &mut super::PacketIdentifiers,
+		publications_sent: &mut u64,
+	) -> Result<(), super::Error> {
+		while let Some(PublishRequest { publication, payload_chunks, ack_sender, cancelable, span }) = self.publish_requests_waiting_to_be_sent.pop_front() {
+			if cancelable && ack_sender.as_ref().is_some_and(super::completions::CompletionSender::is_canceled) {
+				log::debug!("dropping publish request for topic {:?} because it timed out before it could be sent", publication.topic_name);
+				span.event("dropped because it timed out before it could be sent");
+				continue;
+			}
+
+			match publication.qos {
+				crate::proto::QoS::AtMostOnce => {
+					packets_waiting_to_be_sent.push_back(crate::logging_framed::OutgoingPacket {
+						packet: crate::proto::Packet::Publish(crate::proto::Publish {
+							packet_identifier_dup_qos: crate::proto::PacketIdentifierDupQoS::AtMostOnce,
+							retain: publication.retain,
+							topic_name: String::new(),
+							payload: publication.payload,
+						}),
+						topic_name: Some(publication.topic_name),
+						payload_chunks,
 					});
 
-					self.waiting_to_be_acked.insert(packet_identifier, (ack_sender, crate::proto::Publish {
-						packet_identifier_dup_qos: crate::proto::PacketIdentifierDupQoS::AtLeastOnce(packet_identifier, true),
-						retain: publication.retain,
-						topic_name: publication.topic_name,
-						payload: publication.payload,
-					}));
+					send_ack(ack_sender);
 
-					packets_waiting_to_be_sent.push(packet);
+					*publications_sent += 1;
+					span.event("sent PUBLISH");
 				},
 
+				crate::proto::QoS::AtLeastOnce |
 				crate::proto::QoS::ExactlyOnce => {
 					let packet_identifier = match packet_identifiers.reserve() {
 						Ok(packet_identifier) => packet_identifier,
 						Err(err) => {
-							self.publish_requests_waiting_to_be_sent.push_front(PublishRequest { publication, ack_sender });
+							self.publish_requests_waiting_to_be_sent.push_front(PublishRequest { publication, payload_chunks, ack_sender, cancelable, span });
 							return Err(err);
 						},
 					};
 
-					let packet = crate::proto::Packet::Publish(crate::proto::Publish {
-						packet_identifier_dup_qos: crate::proto::PacketIdentifierDupQoS::ExactlyOnce(packet_identifier, false),
-						retain: publication.retain,
-						topic_name: publication.topic_name.clone(),
-						payload: publication.payload.clone(),
-					});
+					packets_waiting_to_be_sent.push_back(publish_packet(packet_identifier, false, &publication, payload_chunks.as_ref()));
 
-					self.waiting_to_be_acked.insert(packet_identifier, (ack_sender, crate::proto::Publish {
-						packet_identifier_dup_qos: crate::proto::PacketIdentifierDupQoS::ExactlyOnce(packet_identifier, true),
-						retain: publication.retain,
-						topic_name: publication.topic_name,
-						payload: publication.payload,
-					}));
+					span.record_packet_identifier(packet_identifier);
+					*publications_sent += 1;
+					span.event("sent PUBLISH");
 
-					packets_waiting_to_be_sent.push(packet);
+					self.in_flight.insert(packet_identifier, InFlightPublication {
+						ack_sender,
+						publication,
+						payload_chunks,
+						state: InFlightState::WaitingToBeAcked,
+						span,
+					});
 				},
 			}
 		}
 
-		Ok((packets_waiting_to_be_sent, publication_received))
+		Ok(())
 	}
 
 	pub (super) fn new_connection<'a>(
 		&'a mut self,
 		reset_session: bool,
 		packet_identifiers: &mut super::PacketIdentifiers,
-	) -> impl Iterator<Item = crate::proto::Packet> + 'a {
+		stats: &super::StatsHandle,
+	) -> impl Iterator<Item = crate::logging_framed::OutgoingPacket> + 'a {
+		// The packet identifiers of publications received on the previous connection are not valid on this one.
+		// Their manual acks are still counted, so that the acks of the publications received after them stay in order.
+		for packet_identifier in &mut self.waiting_for_manual_ack {
+			*packet_identifier = None;
+		}
+
+		// The rest of the payload that was being received is lost with the previous connection
+		self.streamed_payload = None;
+
 		if reset_session {
-			// Move all waiting_to_be_completed back to waiting_to_be_acked since we must restart the ExactlyOnce protocol flow
-			self.waiting_to_be_acked.append(&mut self.waiting_to_be_completed);
+			// Move all publications waiting to be completed back to waiting to be acked since we must restart the ExactlyOnce protocol flow
+			for (_, in_flight) in self.in_flight.iter_mut() {
+				in_flight.state = InFlightState::WaitingToBeAcked;
+			}
 
 			// Clear waiting_to_be_released
 			for (packet_identifier, _) in std::mem::replace(&mut self.waiting_to_be_released, Default::default()) {
@@ -232,66 +361,577 @@ impl State {
 			}
 		}
 
-		self.waiting_to_be_acked.values().map(|(_, packet)| crate::proto::Packet::Publish(packet.clone()))
+		for (_, in_flight) in self.in_flight.iter() {
+			in_flight.span.event("resending on new connection");
+		}
+
+		let publications_in_flight = self.publications_in_flight() as u64;
+		stats.update(|stats| stats.retransmissions += publications_in_flight);
+
+		let in_flight = &self.in_flight;
+		let resend = move |state| in_flight.iter()
+			.filter(move |(_, in_flight)| in_flight.state == state)
+			.map(|(packet_identifier, in_flight)| publish_packet(packet_identifier, true, &in_flight.publication, in_flight.payload_chunks.as_ref()));
+
+		resend(InFlightState::WaitingToBeAcked)
 		.chain(self.waiting_to_be_released.keys().map(|&packet_identifier| crate::proto::Packet::PubRec(crate::proto::PubRec {
 			packet_identifier,
-		})))
-		.chain(self.waiting_to_be_completed.values().map(|(_, packet)| crate::proto::Packet::Publish(packet.clone())))
+		}).into()))
+		.chain(resend(InFlightState::WaitingToBeCompleted))
 	}
 
-	pub(super) fn publish(&mut self, publication: crate::proto::Publication) -> impl Future<Item = (), Error = PublishError> {
-		let (ack_sender, ack_receiver) = futures::sync::oneshot::channel();
-		match PublishRequest::new(publication, ack_sender) {
+	pub(super) fn publish(&mut self, publication: crate::proto::Publication) -> PublishFuture {
+		let (ack_sender, ack_receiver) = self.completions.pair();
+		match PublishRequest::new(publication, None, Some(ack_sender), &self.max_outgoing_packet_size, &self.interceptors) {
 			Ok(publish_request) => {
 				self.publish_requests_waiting_to_be_sent.push_back(publish_request);
-				futures::future::Either::A(ack_receiver.map_err(|_| PublishError::ClientDoesNotExist))
+				PublishFuture(PublishFutureState::WaitingForAck(ack_receiver), None)
 			},
 
-			Err(err) => futures::future::Either::B(futures::future::err(err)),
+			Err(err) => PublishFuture::err(err),
+		}
+	}
+
+	/// The number of publications that have been sent to the server and have not been acked by it yet
+	pub(super) fn publications_in_flight(&self) -> usize {
+		self.in_flight.len()
+	}
+
+	fn remove_in_flight(&mut self, packet_identifier: crate::proto::PacketIdentifier, state: InFlightState) -> Option<InFlightPublication> {
+		match self.in_flight.get_mut(packet_identifier) {
+			Some(in_flight) if in_flight.state == state => self.in_flight.remove(packet_identifier),
+			_ => None,
 		}
 	}
 
+	/// The number of publications that are waiting to be sent to the server
+	pub(super) fn publications_queued(&self) -> usize {
+		self.publish_requests_waiting_to_be_sent.len()
+	}
+
 	pub(super) fn publish_handle(&self) -> PublishHandle {
-		PublishHandle(self.publish_request_send.clone())
+		PublishHandle {
+			publish_request_send: self.publish_queue.sender(),
+			max_outgoing_packet_size: self.max_outgoing_packet_size.clone(),
+			interceptors: self.interceptors.clone(),
+			completions: Default::default(),
+		}
+	}
+
+	pub(super) fn set_manual_acks(&mut self, manual_acks: bool) {
+		self.manual_acks = manual_acks;
+	}
+
+	pub(super) fn ack_handle(&self) -> AckHandle {
+		AckHandle(self.manual_ack_send.clone())
+	}
+
+	pub(super) fn add_interceptor(&mut self, interceptor: Box<dyn crate::interceptor::Interceptor>) {
+		self.interceptors.add(interceptor);
+	}
+
+	/// Runs a received publication through the interceptors. Returns `None` if one of them rejected it.
+	pub(super) fn intercept_incoming(&self, mut publication: crate::ReceivedPublication) -> Option<crate::ReceivedPublication> {
+		match self.interceptors.incoming(&mut publication) {
+			Ok(()) => Some(publication),
+			Err(err) => {
+				log::warn!("Dropping publication with topic {:?} that was rejected by an interceptor: {}", publication.topic_name, err);
+
+				if self.manual_acks && publication.qos == crate::proto::QoS::AtLeastOnce {
+					// The application never sees this publication, so it can't ack it
+					let _ = self.manual_ack_send.unbounded_send(());
+				}
+
+				None
+			},
+		}
+	}
+
+	pub(super) fn set_max_outgoing_packet_size(&mut self, max_outgoing_packet_size: Option<usize>) {
+		self.max_outgoing_packet_size.store(max_outgoing_packet_size.unwrap_or_else(usize::max_value), std::sync::atomic::Ordering::Relaxed);
+	}
+
+	pub(super) fn set_publish_queue_capacity(&mut self, capacity: usize) {
+		self.publish_queue.set_capacity(capacity);
 	}
 }
 
 impl Default for State {
 	fn default() -> Self {
-		let (publish_request_send, publish_request_recv) = futures::sync::mpsc::channel(0);
+		let (manual_ack_send, manual_ack_recv) = futures::sync::mpsc::unbounded();
 
 		State {
-			publish_request_send,
-			publish_request_recv,
-
+			publish_queue: super::publish_queue::PublishQueue::new(0),
 			publish_requests_waiting_to_be_sent: Default::default(),
-			waiting_to_be_acked: Default::default(),
+			max_outgoing_packet_size: std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(usize::max_value())),
+			interceptors: Default::default(),
+			completions: Default::default(),
+			in_flight: Default::default(),
 			waiting_to_be_released: Default::default(),
-			waiting_to_be_completed: Default::default(),
+			manual_acks: false,
+			waiting_for_manual_ack: Default::default(),
+			manual_ack_send,
+			manual_ack_recv,
+			streamed_payload: None,
 		}
 	}
 }
 
+/// A publication that has been sent to the server and has not been acked by it yet
+#[derive(Debug)]
+struct InFlightPublication {
+	ack_sender: Option<super::completions::CompletionSender>,
+	publication: crate::proto::Publication,
+	payload_chunks: Option<std::sync::Arc<[bytes::Bytes]>>,
+	state: InFlightState,
+	span: crate::trace::Span,
+}
+
+/// The part of a streamed publication that is still being received
+#[derive(Debug)]
+struct StreamedPayload {
+	remaining: usize,
+
+	/// The packet identifier to send a PUBACK for once the whole payload has been received
+	puback: Option<crate::proto::PacketIdentifier>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum InFlightState {
+	/// Waiting for a PUBACK, or a PUBREC for an exactly-once publication
+	WaitingToBeAcked,
+
+	/// Waiting for a PUBCOMP after the PUBREC of an exactly-once publication
+	WaitingToBeCompleted,
+}
+
+fn send_ack(ack_sender: Option<super::completions::CompletionSender>) {
+	if let Some(ack_sender) = ack_sender {
+		ack_sender.complete();
+	}
+}
+
+/// Builds the PUBLISH packet for a publication that the server must acknowledge.
+///
+/// The in-flight maps only hold the publication, so the DUP flag is set here when the packet is retransmitted.
+/// The packet shares the publication's topic name and payload instead of copying them.
+fn publish_packet(
+	packet_identifier: crate::proto::PacketIdentifier,
+	dup: bool,
+	publication: &crate::proto::Publication,
+	payload_chunks: Option<&std::sync::Arc<[bytes::Bytes]>>,
+) -> crate::logging_framed::OutgoingPacket {
+	let packet_identifier_dup_qos = match publication.qos {
+		crate::proto::QoS::AtMostOnce => unreachable!("AtMostOnce publications are not held in the in-flight maps"),
+		crate::proto::QoS::AtLeastOnce => crate::proto::PacketIdentifierDupQoS::AtLeastOnce(packet_identifier, dup),
+		crate::proto::QoS::ExactlyOnce => crate::proto::PacketIdentifierDupQoS::ExactlyOnce(packet_identifier, dup),
+	};
+
+	crate::logging_framed::OutgoingPacket {
+		packet: crate::proto::Packet::Publish(crate::proto::Publish {
+			packet_identifier_dup_qos,
+			retain: publication.retain,
+			topic_name: String::new(),
+			payload: publication.payload.clone(),
+		}),
+		topic_name: Some(publication.topic_name.clone()),
+		payload_chunks: payload_chunks.cloned(),
+	}
+}
+
 /// Used to publish messages to the server
-pub struct PublishHandle(futures::sync::mpsc::Sender<PublishRequest>);
+pub struct PublishHandle {
+	publish_request_send: super::publish_queue::PublishQueueSender,
+	max_outgoing_packet_size: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+	interceptors: crate::interceptor::Interceptors,
+	completions: super::completions::Completions,
+}
 
 impl PublishHandle {
+	/// Checks whether the client can accept a new publish request from this handle.
+	///
+	/// If this returns `Ready`, the next call to [`PublishHandle::publish`] hands the publication to the client immediately.
+	/// Otherwise the current task is notified when the handle becomes ready. This lets producers wait for capacity
+	/// before constructing the publication, instead of queuing up futures.
+	pub fn poll_ready(&mut self) -> futures::Poll<(), PublishError> {
+		self.publish_request_send.poll_ready().map_err(|super::publish_queue::QueueClosed| PublishError::ClientDoesNotExist)
+	}
+
 	/// Publish the given message to the server
-	pub fn publish(&mut self, publication: crate::proto::Publication) -> impl Future<Item = (), Error = PublishError> {
-		let (ack_sender, ack_receiver) = futures::sync::oneshot::channel();
+	pub fn publish(&mut self, publication: crate::proto::Publication) -> PublishFuture {
+		self.publish_inner(publication, None, None)
+	}
+
+	/// Queues the given message to be published to the server without waiting for it to be acknowledged.
+	///
+	/// Unlike [`PublishHandle::publish`], this does not allocate a future and a channel to report the ack, which makes it suitable
+	/// for publishing many at-most-once messages at a high rate. The publication is still delivered according to its `qos`,
+	/// but the caller is not told when that happens.
+	///
+	/// If the client cannot accept the publication right now, it is returned in [`PublishError::NotReady`]. Use [`PublishHandle::poll_ready`]
+	/// to wait until it can.
+	pub fn publish_without_ack(&mut self, publication: crate::proto::Publication) -> Result<(), PublishError> {
+		// The interceptors may have changed the publication, so keep the original one to return if the client is not ready,
+		// otherwise publishing it again would run it through them twice.
+		let original = if self.interceptors.is_empty() { None } else { Some(publication.clone()) };
+
+		let publish_request = PublishRequest::new(publication, None, None, &self.max_outgoing_packet_size, &self.interceptors)?;
+
+		match self.publish_request_send.try_send(publish_request) {
+			Ok(()) => Ok(()),
+			Err(super::publish_queue::TrySendError::Closed) => Err(PublishError::ClientDoesNotExist),
+			Err(super::publish_queue::TrySendError::Full(publication)) => Err(PublishError::NotReady(original.unwrap_or(publication))),
+		}
+	}
+
+	/// Publish the given message to the server, giving up if it is not acknowledged within the given timeout.
+	///
+	/// If the timeout expires, the returned future fails with [`PublishError::Timeout`]. If the client had not yet sent the publication
+	/// to the server by then, it is discarded. Otherwise the client still waits for the server to acknowledge it, since the server may have received it.
+	///
+	/// The publication is also discarded if the returned future is dropped before the client sends it.
+	pub fn publish_with_timeout(&mut self, publication: crate::proto::Publication, timeout: std::time::Duration) -> PublishFuture {
+		self.publish_inner(publication, None, Some(timeout))
+	}
 
-		let sender = self.0.clone();
-		PublishRequest::new(publication, ack_sender)
-			.into_future()
-			.and_then(|publish_request| sender.send(publish_request).map_err(|_| PublishError::ClientDoesNotExist))
-			.and_then(|_| ack_receiver.map_err(|_| PublishError::ClientDoesNotExist))
+	/// Publishes the same payload to each of the given topics, like when mirroring data to several topics.
+	///
+	/// Every publication shares the payload's buffer instead of getting its own copy. The returned future resolves once all of them
+	/// have been acknowledged. If any of them fails, the future fails with the first error, after the others have completed.
+	#[allow(clippy::needless_pass_by_value)] // Taken by value like the payload of a `Publication`
+	pub fn publish_to_many<I>(&mut self, topic_names: I, qos: crate::proto::QoS, retain: bool, payload: bytes::Bytes) -> PublishToManyFuture
+	where
+		I: IntoIterator<Item = crate::topic::TopicName>,
+	{
+		let publishes =
+			topic_names.into_iter()
+			.map(|topic_name| self.publish(crate::proto::Publication {
+				topic_name,
+				qos,
+				retain,
+				payload: payload.clone(),
+			}))
+			.collect();
+
+		PublishToManyFuture {
+			publishes,
+			err: None,
+		}
+	}
+
+	/// Publishes a payload that is produced as a stream of chunks, like a firmware image that is read from a file a piece at a time.
+	///
+	/// The chunks are written to the connection one after the other instead of being copied into one contiguous payload. They are still
+	/// all held in memory until the server has acknowledged the publication, since they are needed to retransmit it, so this saves
+	/// the copy of a large payload but not its memory. The publication is queued once the stream has ended, and the returned future
+	/// resolves when the server has acknowledged it, like the one returned by [`PublishHandle::publish`].
+	///
+	/// The publication is not passed to interceptors, since those need the whole payload. Packet logging and capture leave out its payload.
+	pub fn publish_stream<S>(
+		&mut self,
+		topic_name: crate::topic::TopicName,
+		qos: crate::proto::QoS,
+		retain: bool,
+		payload: S,
+	) -> PublishStreamFuture<S>
+	where
+		S: Stream<Item = bytes::Bytes>,
+	{
+		let publish_handle = PublishHandle {
+			publish_request_send: self.publish_request_send.clone(),
+			max_outgoing_packet_size: self.max_outgoing_packet_size.clone(),
+			interceptors: self.interceptors.clone(),
+			completions: self.completions.clone(),
+		};
+
+		PublishStreamFuture {
+			payload,
+			state: PublishStreamFutureState::ReceivingPayload {
+				publish_handle,
+				publication: Some(crate::proto::Publication {
+					topic_name,
+					qos,
+					retain,
+					payload: Default::default(),
+				}),
+				payload_chunks: vec![],
+			},
+		}
+	}
+
+	fn publish_inner(
+		&mut self,
+		publication: crate::proto::Publication,
+		payload_chunks: Option<std::sync::Arc<[bytes::Bytes]>>,
+		timeout: Option<std::time::Duration>,
+	) -> PublishFuture {
+		let (ack_sender, ack_receiver) = self.completions.pair();
+
+		let mut publish_request = match PublishRequest::new(publication, payload_chunks, Some(ack_sender), &self.max_outgoing_packet_size, &self.interceptors) {
+			Ok(publish_request) => publish_request,
+			Err(err) => return PublishFuture::err(err),
+		};
+		publish_request.cancelable = timeout.is_some();
+
+		let timer = timeout.map(|timeout| tokio_timer::Delay::new(tokio_timer::clock::now() + timeout));
+
+		// The future holds the publication until it's acked anyway, so it's queued even if the queue is full
+		match self.publish_request_send.send(publish_request) {
+			Ok(()) => PublishFuture(PublishFutureState::WaitingForAck(ack_receiver), timer),
+			Err(super::publish_queue::QueueClosed) => PublishFuture::err(PublishError::ClientDoesNotExist),
+		}
+	}
+}
+
+/// The [`Future`] returned by [`PublishHandle::publish`] and [`Client::publish`](crate::Client::publish).
+///
+/// It resolves when the server has acknowledged the publication, or as soon as the client has queued it if it does not need to be acknowledged.
+#[must_use = "futures do nothing unless polled"]
+pub struct PublishFuture(PublishFutureState, Option<tokio_timer::Delay>);
+
+enum PublishFutureState {
+	Failed(Option<PublishError>),
+	WaitingForAck(super::completions::CompletionReceiver),
+}
+
+impl PublishFuture {
+	pub(super) fn err(err: PublishError) -> Self {
+		PublishFuture(PublishFutureState::Failed(Some(err)), None)
+	}
+
+	/// Attaches an opaque token to this publication. The returned future resolves to the token when the publication is acked,
+	/// or fails with the token and the error.
+	///
+	/// This makes it easy to tie acks back to the upstream records that produced the publications, say to checkpoint them.
+	pub fn with_token<T>(self, token: T) -> PublishWithTokenFuture<T> {
+		PublishWithTokenFuture {
+			inner: self,
+			token: Some(token),
+		}
+	}
+}
+
+impl Future for PublishFuture {
+	type Item = ();
+	type Error = PublishError;
+
+	fn poll(&mut self) -> futures::Poll<Self::Item, Self::Error> {
+		if let Some(timer) = &mut self.1 {
+			match timer.poll().map_err(PublishError::Timer)? {
+				futures::Async::Ready(()) => {
+					// Dropping the receiver cancels the request, so the client discards it if it hasn't sent it yet
+					self.0 = PublishFutureState::Failed(None);
+					self.1 = None;
+					return Err(PublishError::Timeout);
+				},
+				futures::Async::NotReady => (),
+			}
+		}
+
+		match &mut self.0 {
+			PublishFutureState::Failed(err) => Err(err.take().expect("PublishFuture polled after completion")),
+
+			PublishFutureState::WaitingForAck(ack_receiver) =>
+				ack_receiver.poll().map_err(|()| PublishError::ClientDoesNotExist),
+		}
+	}
+}
+
+impl std::fmt::Debug for PublishFuture {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match &self.0 {
+			PublishFutureState::Failed(_) => f.write_str("Failed"),
+			PublishFutureState::WaitingForAck(_) => f.write_str("WaitingForAck"),
+		}
+	}
+}
+
+/// The [`Future`] returned by [`PublishFuture::with_token`]
+#[derive(Debug)]
+#[must_use = "futures do nothing unless polled"]
+pub struct PublishWithTokenFuture<T> {
+	inner: PublishFuture,
+	token: Option<T>,
+}
+
+impl<T> Future for PublishWithTokenFuture<T> {
+	type Item = T;
+	type Error = (T, PublishError);
+
+	fn poll(&mut self) -> futures::Poll<Self::Item, Self::Error> {
+		let result = match self.inner.poll() {
+			Ok(futures::Async::Ready(())) => Ok(()),
+			Ok(futures::Async::NotReady) => return Ok(futures::Async::NotReady),
+			Err(err) => Err(err),
+		};
+
+		let token = self.token.take().expect("PublishWithTokenFuture polled after completion");
+		match result {
+			Ok(()) => Ok(futures::Async::Ready(token)),
+			Err(err) => Err((token, err)),
+		}
+	}
+}
+
+/// The [`Future`] returned by [`PublishHandle::publish_to_many`]
+#[derive(Debug)]
+#[must_use = "futures do nothing unless polled"]
+pub struct PublishToManyFuture {
+	publishes: futures::stream::FuturesUnordered<PublishFuture>,
+	err: Option<PublishError>,
+}
+
+impl Future for PublishToManyFuture {
+	type Item = ();
+	type Error = PublishError;
+
+	fn poll(&mut self) -> futures::Poll<Self::Item, Self::Error> {
+		loop {
+			match self.publishes.poll() {
+				Ok(futures::Async::Ready(Some(()))) => (),
+
+				Ok(futures::Async::Ready(None)) => return match self.err.take() {
+					Some(err) => Err(err),
+					None => Ok(futures::Async::Ready(())),
+				},
+
+				Ok(futures::Async::NotReady) => return Ok(futures::Async::NotReady),
+
+				Err(err) => if self.err.is_none() {
+					self.err = Some(err);
+				},
+			}
+		}
+	}
+}
+
+/// The [`Future`] returned by [`PublishHandle::publish_stream`]
+#[must_use = "futures do nothing unless polled"]
+pub struct PublishStreamFuture<S> {
+	payload: S,
+	state: PublishStreamFutureState,
+}
+
+enum PublishStreamFutureState {
+	ReceivingPayload {
+		publish_handle: PublishHandle,
+		publication: Option<crate::proto::Publication>,
+		payload_chunks: Vec<bytes::Bytes>,
+	},
+
+	WaitingForAck(PublishFuture),
+}
+
+impl<S> Future for PublishStreamFuture<S> where S: Stream<Item = bytes::Bytes> {
+	type Item = ();
+	type Error = PublishStreamError<S::Error>;
+
+	fn poll(&mut self) -> futures::Poll<Self::Item, Self::Error> {
+		loop {
+			let publish = match &mut self.state {
+				PublishStreamFutureState::ReceivingPayload { publish_handle, publication, payload_chunks } =>
+					match self.payload.poll().map_err(PublishStreamError::Payload)? {
+						futures::Async::Ready(Some(payload_chunk)) => {
+							if !payload_chunk.is_empty() {
+								payload_chunks.push(payload_chunk);
+							}
+							continue;
+						},
+
+						futures::Async::Ready(None) => {
+							let publication = publication.take().expect("PublishStreamFuture polled after completion");
+							let payload_chunks = std::mem::take(payload_chunks).into();
+							publish_handle.publish_inner(publication, Some(payload_chunks), None)
+						},
+
+						futures::Async::NotReady => return Ok(futures::Async::NotReady),
+					},
+
+				PublishStreamFutureState::WaitingForAck(publish) => return publish.poll().map_err(PublishStreamError::Publish),
+			};
+
+			self.state = PublishStreamFutureState::WaitingForAck(publish);
+		}
+	}
+}
+
+impl<S> std::fmt::Debug for PublishStreamFuture<S> {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match &self.state {
+			PublishStreamFutureState::ReceivingPayload { payload_chunks, .. } =>
+				f.debug_struct("ReceivingPayload").field("payload_chunks", &payload_chunks.len()).finish_non_exhaustive(),
+			PublishStreamFutureState::WaitingForAck(publish) => publish.fmt(f),
+		}
+	}
+}
+
+/// The error of a [`PublishStreamFuture`]
+#[derive(Debug)]
+pub enum PublishStreamError<E> {
+	/// The stream of the payload failed
+	Payload(E),
+
+	Publish(PublishError),
+}
+
+impl<E> std::fmt::Display for PublishStreamError<E> where E: std::fmt::Display {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			PublishStreamError::Payload(err) => write!(f, "could not get payload: {}", err),
+			PublishStreamError::Publish(err) => err.fmt(f),
+		}
+	}
+}
+
+impl<E> std::error::Error for PublishStreamError<E> where E: std::error::Error + 'static {
+	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+		match self {
+			PublishStreamError::Payload(err) => Some(err),
+			PublishStreamError::Publish(err) => Some(err),
+		}
+	}
+}
+
+/// Acks the at-least-once publications received by a [`Client`](crate::Client) that has manual acks enabled.
+/// See [`Client::set_manual_acks`](crate::Client::set_manual_acks).
+#[derive(Clone, Debug)]
+pub struct AckHandle(futures::sync::mpsc::UnboundedSender<()>);
+
+impl AckHandle {
+	/// Acks the oldest at-least-once publication received by the client that has not been acked yet.
+	///
+	/// The client sends the PUBACK the next time it is polled.
+	pub fn ack(&self) -> Result<(), AckError> {
+		self.0.unbounded_send(()).map_err(|_| AckError::ClientDoesNotExist)
 	}
 }
 
+#[derive(Debug)]
+pub enum AckError {
+	ClientDoesNotExist,
+}
+
+impl std::fmt::Display for AckError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			AckError::ClientDoesNotExist =>
+				write!(f, "client does not exist"),
+		}
+	}
+}
+
+impl std::error::Error for AckError {
+}
+
 #[derive(Debug)]
 pub enum PublishError {
 	ClientDoesNotExist,
 	EncodePacket(crate::proto::Publication, crate::proto::EncodeError),
+	NotReady(crate::proto::Publication),
+	PacketTooLarge { publication: crate::proto::Publication, remaining_length: usize, max: usize },
+	Rejected(crate::interceptor::Rejection),
+	Timeout,
+	Timer(tokio_timer::Error),
 }
 
 impl std::fmt::Display for PublishError {
@@ -299,49 +939,91 @@ impl std::fmt::Display for PublishError {
 		match self {
 			PublishError::ClientDoesNotExist => write!(f, "client does not exist"),
 			PublishError::EncodePacket(publication, err) => write!(f, "cannot encode PUBLISH packet with topic {:?}: {}", publication.topic_name, err),
+			PublishError::NotReady(publication) => write!(f, "client is not ready to accept PUBLISH packet with topic {:?}", publication.topic_name),
+			PublishError::PacketTooLarge { publication, remaining_length, max } =>
+				write!(
+					f,
+					"PUBLISH packet with topic {:?} has remaining length {} which is larger than the maximum of {}",
+					publication.topic_name,
+					remaining_length,
+					max,
+				),
+			PublishError::Rejected(err) => write!(f, "publication was rejected by an interceptor: {}", err),
+			PublishError::Timeout => write!(f, "publication was not acknowledged in time"),
+			PublishError::Timer(err) => write!(f, "could not poll publish timer: {}", err),
 		}
 	}
 }
 
 impl std::error::Error for PublishError {
 	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+		#[allow(clippy::match_same_arms)]
 		match self {
 			PublishError::ClientDoesNotExist => None,
 			PublishError::EncodePacket(_, err) => Some(err),
+			PublishError::NotReady(_) => None,
+			PublishError::PacketTooLarge { .. } => None,
+			PublishError::Rejected(err) => Some(&**err),
+			PublishError::Timeout => None,
+			PublishError::Timer(err) => Some(err),
 		}
 	}
 }
 
 #[derive(Debug)]
-struct PublishRequest {
-	publication: crate::proto::Publication,
-	ack_sender: futures::sync::oneshot::Sender<()>,
+pub(super) struct PublishRequest {
+	pub(super) publication: crate::proto::Publication,
+
+	/// Set for publications from `PublishHandle::publish_stream`, whose payload is sent from these chunks instead of from the publication
+	payload_chunks: Option<std::sync::Arc<[bytes::Bytes]>>,
+
+	/// `None` if the publisher doesn't want to be notified when the publication is acked
+	ack_sender: Option<super::completions::CompletionSender>,
+
+	/// Whether the request should be discarded if its `PublishFuture` is dropped before it is sent
+	cancelable: bool,
+
+	span: crate::trace::Span,
 }
 
 impl PublishRequest {
-	fn new(publication: crate::proto::Publication, ack_sender: futures::sync::oneshot::Sender<()>) -> Result<PublishRequest, PublishError> {
-		use crate::proto::PacketMeta;
-
-		let packet = crate::proto::Publish {
-			packet_identifier_dup_qos: crate::proto::PacketIdentifierDupQoS::AtMostOnce,
-			retain: publication.retain,
-			topic_name: publication.topic_name,
-			payload: publication.payload,
+	fn new(
+		mut publication: crate::proto::Publication,
+		payload_chunks: Option<std::sync::Arc<[bytes::Bytes]>>,
+		ack_sender: Option<super::completions::CompletionSender>,
+		max_outgoing_packet_size: &std::sync::atomic::AtomicUsize,
+		interceptors: &crate::interceptor::Interceptors,
+	) -> Result<PublishRequest, PublishError> {
+		// Interceptors work on the whole payload, so streamed publications skip them
+		let payload_len = match &payload_chunks {
+			Some(payload_chunks) => payload_chunks.iter().map(bytes::Bytes::len).sum(),
+			None => {
+				interceptors.outgoing(&mut publication).map_err(PublishError::Rejected)?;
+				publication.payload.len()
+			},
 		};
 
-		let mut counter = crate::proto::ByteCounter::new();
-		let encode_result = packet.encode(&mut counter).and_then(|()| crate::proto::encode_remaining_length(counter.0, &mut counter));
-
-		let publication = crate::proto::Publication {
-			topic_name: packet.topic_name,
-			qos: publication.qos,
-			retain: publication.retain,
-			payload: packet.payload,
+		// The packet identifier is not known yet, but it takes up the same space regardless of its value
+		let packet_identifier_len = match publication.qos {
+			crate::proto::QoS::AtMostOnce => 0,
+			crate::proto::QoS::AtLeastOnce |
+			crate::proto::QoS::ExactlyOnce => std::mem::size_of::<u16>(),
 		};
 
-		match encode_result {
-			Ok(_) => Ok(PublishRequest { publication, ack_sender }),
-			Err(err) => Err(PublishError::EncodePacket(publication, err)),
+		// The length-prefixed topic name, the packet identifier and the payload. The topic name has been validated already,
+		// so this is computed directly instead of encoding the packet, which would need a copy of the topic name.
+		let remaining_length = std::mem::size_of::<u16>() + publication.topic_name.len() + packet_identifier_len + payload_len;
+		if let Err(err) = crate::proto::encode_remaining_length(remaining_length, &mut crate::proto::ByteCounter::new()) {
+			return Err(PublishError::EncodePacket(publication, err));
+		}
+
+		let max = max_outgoing_packet_size.load(std::sync::atomic::Ordering::Relaxed);
+		if remaining_length > max {
+			return Err(PublishError::PacketTooLarge { publication, remaining_length, max });
 		}
+
+		let span = crate::trace::Span::publish(&publication);
+		span.event("queued");
+		Ok(PublishRequest { publication, payload_chunks, ack_sender, cancelable: false, span })
 	}
 }
@@ -0,0 +1,220 @@
+/// The queue that carries publish requests from [`PublishHandle`](super::PublishHandle)s to the client.
+///
+/// This takes the place of a futures mpsc channel. All handles share one queue behind a mutex, and the client takes all the requests
+/// that were queued since it was last polled at once. Producers only wake the client when they add a request to an empty queue,
+/// and the client only wakes producers that are waiting for room once per batch. A channel allocates a node for every request,
+/// and wakes up both sides for every request.
+///
+/// A request is accepted if the queue holds fewer than `capacity` requests plus one for every handle.
+pub(super) struct PublishQueue {
+	inner: std::sync::Arc<std::sync::Mutex<Inner>>,
+
+	/// Reused to notify the producers that were waiting for room, without allocating
+	waiting_senders: Vec<futures::task::Task>,
+}
+
+struct Inner {
+	requests: std::collections::VecDeque<super::publish::PublishRequest>,
+	capacity: usize,
+
+	/// The number of `PublishQueueSender`s
+	senders: usize,
+
+	/// Set when the `PublishQueue` has been dropped, ie the client does not exist anymore
+	closed: bool,
+
+	/// The task of the client, to be notified when a request is added to the empty queue
+	receiver: Option<futures::task::Task>,
+
+	/// The tasks of the producers that are waiting for room in the queue
+	waiting_senders: Vec<futures::task::Task>,
+}
+
+impl Inner {
+	fn has_room(&self) -> bool {
+		self.requests.len() < self.capacity.saturating_add(self.senders)
+	}
+}
+
+impl PublishQueue {
+	pub(super) fn new(capacity: usize) -> Self {
+		PublishQueue {
+			inner: std::sync::Arc::new(std::sync::Mutex::new(Inner {
+				requests: Default::default(),
+				capacity,
+				senders: 0,
+				closed: false,
+				receiver: None,
+				waiting_senders: vec![],
+			})),
+			waiting_senders: vec![],
+		}
+	}
+
+	pub(super) fn sender(&self) -> PublishQueueSender {
+		lock(&self.inner).senders += 1;
+		PublishQueueSender(self.inner.clone())
+	}
+
+	/// Moves all the queued requests to the end of `requests`.
+	///
+	/// The current task is notified when a request is queued after this.
+	pub(super) fn poll(&mut self, requests: &mut std::collections::VecDeque<super::publish::PublishRequest>) {
+		{
+			let mut inner = lock(&self.inner);
+
+			if !inner.receiver.as_ref().is_some_and(futures::task::Task::will_notify_current) {
+				inner.receiver = Some(futures::task::current());
+			}
+
+			if inner.requests.is_empty() {
+				return;
+			}
+
+			requests.append(&mut inner.requests);
+			std::mem::swap(&mut inner.waiting_senders, &mut self.waiting_senders);
+		}
+
+		for task in self.waiting_senders.drain(..) {
+			task.notify();
+		}
+	}
+
+	pub(super) fn set_capacity(&mut self, capacity: usize) {
+		{
+			let mut inner = lock(&self.inner);
+			inner.capacity = capacity;
+			std::mem::swap(&mut inner.waiting_senders, &mut self.waiting_senders);
+		}
+
+		for task in self.waiting_senders.drain(..) {
+			task.notify();
+		}
+	}
+}
+
+impl Drop for PublishQueue {
+	fn drop(&mut self) {
+		// Dropping the requests fails their futures, so do it outside the lock
+		let requests = {
+			let mut inner = lock(&self.inner);
+			inner.closed = true;
+			std::mem::swap(&mut inner.waiting_senders, &mut self.waiting_senders);
+			std::mem::take(&mut inner.requests)
+		};
+		drop(requests);
+
+		for task in self.waiting_senders.drain(..) {
+			task.notify();
+		}
+	}
+}
+
+impl std::fmt::Debug for PublishQueue {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		let inner = lock(&self.inner);
+		f.debug_struct("PublishQueue")
+			.field("requests", &inner.requests.len())
+			.field("capacity", &inner.capacity)
+			.field("senders", &inner.senders)
+			.finish_non_exhaustive()
+	}
+}
+
+/// Adds publish requests to a [`PublishQueue`]. Each sender adds room for one request to the queue.
+pub(super) struct PublishQueueSender(std::sync::Arc<std::sync::Mutex<Inner>>);
+
+impl PublishQueueSender {
+	/// Whether the queue has room for a request. If it doesn't, the current task is notified when it might.
+	pub(super) fn poll_ready(&self) -> futures::Poll<(), QueueClosed> {
+		let mut inner = lock(&self.0);
+
+		if inner.closed {
+			return Err(QueueClosed);
+		}
+
+		if inner.has_room() {
+			return Ok(futures::Async::Ready(()));
+		}
+
+		if !inner.waiting_senders.iter().any(futures::task::Task::will_notify_current) {
+			inner.waiting_senders.push(futures::task::current());
+		}
+		Ok(futures::Async::NotReady)
+	}
+
+	/// Queues the given request if the queue has room for it, otherwise returns its publication
+	pub(super) fn try_send(&self, request: super::publish::PublishRequest) -> Result<(), TrySendError> {
+		let inner = lock(&self.0);
+
+		if inner.closed {
+			return Err(TrySendError::Closed);
+		}
+
+		if !inner.has_room() {
+			return Err(TrySendError::Full(request.publication));
+		}
+
+		push(inner, request);
+		Ok(())
+	}
+
+	/// Queues the given request even if the queue is full, for publish requests whose futures hold them until they can be sent
+	pub(super) fn send(&self, request: super::publish::PublishRequest) -> Result<(), QueueClosed> {
+		let inner = lock(&self.0);
+
+		if inner.closed {
+			return Err(QueueClosed);
+		}
+
+		push(inner, request);
+		Ok(())
+	}
+}
+
+fn push(mut inner: std::sync::MutexGuard<'_, Inner>, request: super::publish::PublishRequest) {
+	let was_empty = inner.requests.is_empty();
+	inner.requests.push_back(request);
+
+	// The client takes all the requests when it's polled, so it only needs to be woken up for the first one
+	let receiver = if was_empty { inner.receiver.take() } else { None };
+	drop(inner);
+
+	if let Some(receiver) = receiver {
+		receiver.notify();
+	}
+}
+
+impl Clone for PublishQueueSender {
+	fn clone(&self) -> Self {
+		lock(&self.0).senders += 1;
+		PublishQueueSender(self.0.clone())
+	}
+}
+
+impl Drop for PublishQueueSender {
+	fn drop(&mut self) {
+		lock(&self.0).senders -= 1;
+	}
+}
+
+impl std::fmt::Debug for PublishQueueSender {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("PublishQueueSender").finish_non_exhaustive()
+	}
+}
+
+fn lock(inner: &std::sync::Mutex<Inner>) -> std::sync::MutexGuard<'_, Inner> {
+	// Every change to the queue is completed before the lock is released, so a poisoned lock still has a consistent queue.
+	inner.lock().unwrap_or_else(std::sync::PoisonError::into_inner)
+}
+
+/// The client of a [`PublishQueue`] does not exist anymore
+#[derive(Debug)]
+pub(super) struct QueueClosed;
+
+#[derive(Debug)]
+pub(super) enum TrySendError {
+	Closed,
+	Full(crate::proto::Publication),
+}
@@ -0,0 +1,148 @@
+use futures::Future;
+
+/// Detects that the application is not polling the client fast enough to keep up with the events it receives.
+///
+/// The client's receive backlog is the events that it has read from the connection but not returned yet, and the publications that it has
+/// buffered while paused. The application is a slow consumer if the backlog stays at or above a threshold for too long without the
+/// application taking an event from it, whether because it polls the client too rarely or because the client is paused.
+pub(super) enum State {
+	BelowThreshold,
+	AboveThreshold(tokio_timer::Delay),
+	Warned,
+}
+
+impl State {
+	/// Returns `true` once the backlog has been at or above `threshold` for `duration` since the application last took an event from it.
+	/// It is not returned again until the backlog drops below `threshold` and the timer starts over.
+	pub(super) fn poll(
+		&mut self,
+		backlog: usize,
+		threshold: usize,
+		duration: std::time::Duration,
+	) -> bool {
+		if backlog < threshold {
+			*self = State::BelowThreshold;
+			return false;
+		}
+
+		loop {
+			log::trace!("    {:?}", self);
+
+			match self {
+				State::BelowThreshold => *self = State::AboveThreshold(tokio_timer::Delay::new(tokio_timer::clock::now() + duration)),
+
+				State::AboveThreshold(timer) => match timer.poll() {
+					Ok(futures::Async::Ready(())) => {
+						*self = State::Warned;
+						return true;
+					},
+					Ok(futures::Async::NotReady) => return false,
+					Err(err) => {
+						log::warn!("slow consumer timer failed: {}", err);
+						*self = State::Warned;
+						return false;
+					},
+				},
+
+				State::Warned => return false,
+			}
+		}
+	}
+
+	/// Restarts the timer when the client has returned an event, ie the application has taken an event from the backlog.
+	///
+	/// This is called at the end of the poll that returned the event, since the application might not poll the client again for a while.
+	pub(super) fn event_returned(
+		&mut self,
+		backlog: usize,
+		threshold: usize,
+		duration: std::time::Duration,
+	) {
+		if backlog < threshold {
+			*self = State::BelowThreshold;
+			return;
+		}
+
+		match self {
+			State::BelowThreshold => *self = State::AboveThreshold(tokio_timer::Delay::new(tokio_timer::clock::now() + duration)),
+			State::AboveThreshold(timer) => timer.reset(tokio_timer::clock::now() + duration),
+			State::Warned => (),
+		}
+	}
+}
+
+impl std::fmt::Debug for State {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			State::BelowThreshold => f.write_str("BelowThreshold"),
+			State::AboveThreshold(_) => f.write_str("AboveThreshold"),
+			State::Warned => f.write_str("Warned"),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	#[test]
+	fn warns_once_after_duration_above_threshold() {
+		let mut timer = crate::client::mock_timer::MockTimer::new();
+		let mut state = super::State::BelowThreshold;
+		let duration = std::time::Duration::from_secs(10);
+
+		assert!(!timer.run(|| state.poll(1, 2, duration)));
+		assert!(!timer.run(|| state.poll(2, 2, duration)));
+
+		timer.advance(std::time::Duration::from_secs(9));
+		assert!(!timer.run(|| state.poll(2, 2, duration)));
+
+		timer.advance(std::time::Duration::from_secs(1));
+		assert!(timer.run(|| state.poll(2, 2, duration)));
+
+		timer.advance(std::time::Duration::from_secs(10));
+		assert!(!timer.run(|| state.poll(3, 2, duration)));
+	}
+
+	#[test]
+	fn dropping_below_threshold_restarts_timer() {
+		let mut timer = crate::client::mock_timer::MockTimer::new();
+		let mut state = super::State::BelowThreshold;
+		let duration = std::time::Duration::from_secs(10);
+
+		assert!(!timer.run(|| state.poll(2, 2, duration)));
+
+		timer.advance(std::time::Duration::from_secs(5));
+		assert!(!timer.run(|| state.poll(1, 2, duration)));
+		assert!(!timer.run(|| state.poll(2, 2, duration)));
+
+		timer.advance(std::time::Duration::from_secs(9));
+		assert!(!timer.run(|| state.poll(2, 2, duration)));
+
+		timer.advance(std::time::Duration::from_secs(1));
+		assert!(timer.run(|| state.poll(2, 2, duration)));
+	}
+
+	#[test]
+	fn taking_events_restarts_timer() {
+		let mut timer = crate::client::mock_timer::MockTimer::new();
+		let mut state = super::State::BelowThreshold;
+		let duration = std::time::Duration::from_secs(10);
+
+		// The backlog reaches the threshold in the poll that returns an event
+		timer.run(|| state.event_returned(3, 2, duration));
+
+		timer.advance(std::time::Duration::from_secs(9));
+		assert!(!timer.run(|| state.poll(3, 2, duration)));
+		timer.run(|| state.event_returned(2, 2, duration));
+
+		timer.advance(std::time::Duration::from_secs(9));
+		assert!(!timer.run(|| state.poll(2, 2, duration)));
+
+		timer.advance(std::time::Duration::from_secs(1));
+		assert!(timer.run(|| state.poll(2, 2, duration)));
+
+		// Taking the event that was warned about doesn't warn again until the backlog drops below the threshold
+		timer.run(|| state.event_returned(2, 2, duration));
+		timer.advance(std::time::Duration::from_secs(10));
+		assert!(!timer.run(|| state.poll(2, 2, duration)));
+	}
+}
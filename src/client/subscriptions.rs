@@ -1,14 +1,21 @@
-use futures::{ Future, IntoFuture, Sink, Stream };
+use futures::{ Future, Sink, Stream };
 
 #[derive(Debug)]
 pub(super) struct State {
-	subscriptions: std::collections::BTreeMap<String, crate::proto::QoS>,
+	subscriptions: std::collections::BTreeMap<crate::topic::TopicFilter, crate::proto::QoS>,
 
-	subscriptions_updated_send: futures::sync::mpsc::Sender<SubscriptionUpdate>,
-	subscriptions_updated_recv: futures::sync::mpsc::Receiver<SubscriptionUpdate>,
+	/// Whether `subscriptions` is resubscribed to when the session is reset
+	resubscribe_on_session_reset: bool,
+
+	subscriptions_updated_send: futures::sync::mpsc::Sender<Vec<SubscriptionUpdate>>,
+	subscriptions_updated_recv: futures::sync::mpsc::Receiver<Vec<SubscriptionUpdate>>,
 
 	subscription_updates_waiting_to_be_sent: std::collections::VecDeque<SubscriptionUpdate>,
 	subscription_updates_waiting_to_be_acked: std::collections::VecDeque<(crate::proto::PacketIdentifier, BatchedSubscriptionUpdate)>,
+
+	/// The spans of the SUBSCRIBE and UNSUBSCRIBE packets in `subscription_updates_waiting_to_be_acked`
+	#[cfg_attr(not(feature = "tracing"), allow(clippy::zero_sized_map_values))]
+	spans: std::collections::BTreeMap<crate::proto::PacketIdentifier, crate::trace::Span>,
 }
 
 impl State {
@@ -44,6 +51,10 @@ impl State {
 
 					packet_identifiers.discard(packet_identifier);
 
+					if let Some(span) = self.spans.remove(&packet_identifier) {
+						span.event("received SUBACK");
+					}
+
 					// We can't put subscribe_to back into self.subscription_updates_waiting_to_be_acked within the below loop
 					// since we would've partially consumed it.
 					// Instead, if there's an error, we'll update self.subscriptions anyway with the expected QoS, and set the error to be returned here.
@@ -60,7 +71,7 @@ impl State {
 								}
 								else {
 									if err.is_none() {
-										err = Some(super::Error::SubscriptionDowngraded(topic_filter.clone(), expected_qos, actual_qos));
+										err = Some(super::Error::SubscriptionDowngraded(topic_filter.to_string(), expected_qos, actual_qos));
 									}
 
 									self.subscriptions.insert(topic_filter, expected_qos);
@@ -108,10 +119,14 @@ impl State {
 
 					packet_identifiers.discard(packet_identifier);
 
+					if let Some(span) = self.spans.remove(&packet_identifier) {
+						span.event("received UNSUBACK");
+					}
+
 					for topic_filter in unsubscribe_from {
 						log::debug!("Unsubscribed from {}", topic_filter);
-						self.subscriptions.remove(&topic_filter);
-						subscription_updates.push(super::SubscriptionUpdateEvent::Unsubscribe(topic_filter));
+						self.subscriptions.remove(&*topic_filter);
+						subscription_updates.push(super::SubscriptionUpdateEvent::Unsubscribe(crate::topic::TopicFilter::new_unchecked(topic_filter)));
 					}
 				},
 
@@ -131,8 +146,8 @@ impl State {
 		}
 
 
-		while let futures::Async::Ready(Some(subscription_to_update)) = self.subscriptions_updated_recv.poll().expect("Receiver::poll cannot fail") {
-			self.subscription_updates_waiting_to_be_sent.push_back(subscription_to_update);
+		while let futures::Async::Ready(Some(subscriptions_to_update)) = self.subscriptions_updated_recv.poll().expect("Receiver::poll cannot fail") {
+			self.subscription_updates_waiting_to_be_sent.extend(subscriptions_to_update);
 		}
 
 		let mut packets_waiting_to_be_sent = vec![];
@@ -173,11 +188,15 @@ impl State {
 
 			while let Some(subscription_update) = self.subscription_updates_waiting_to_be_sent.pop_front() {
 				match subscription_update {
-					SubscriptionUpdate::Subscribe(subscribe_to) =>
-						target_subscriptions.insert(std::borrow::Cow::Owned(subscribe_to.topic_filter), subscribe_to.qos),
-					SubscriptionUpdate::Unsubscribe(unsubscribe_from) =>
-						target_subscriptions.remove(&*unsubscribe_from),
-				};
+					SubscriptionUpdate::Subscribe(subscribe_to) => {
+						target_subscriptions.insert(std::borrow::Cow::Owned(subscribe_to.topic_filter.into_string()), subscribe_to.qos);
+					},
+					SubscriptionUpdate::Unsubscribe(unsubscribe_from) => {
+						target_subscriptions.remove(&*unsubscribe_from);
+					},
+					SubscriptionUpdate::UnsubscribeAll =>
+						target_subscriptions.clear(),
+				}
 			}
 
 			let mut pending_subscriptions: std::collections::VecDeque<_> = Default::default();
@@ -185,7 +204,7 @@ impl State {
 				if current_subscriptions.get(topic_filter) != Some(&qos) {
 					// Current subscription doesn't exist, or exists but has different QoS
 					pending_subscriptions.push_back(crate::proto::SubscribeTo {
-						topic_filter: topic_filter.clone().into_owned(),
+						topic_filter: crate::topic::TopicFilter::new_unchecked(topic_filter.clone().into_owned()),
 						qos,
 					});
 				}
@@ -237,6 +256,10 @@ impl State {
 							BatchedSubscriptionUpdate::Subscribe(packet.subscribe_to.clone()),
 						));
 
+						let span = crate::trace::Span::subscribe(&packet);
+						span.event("sent SUBSCRIBE");
+						self.spans.insert(packet_identifier, span);
+
 						packets_waiting_to_be_sent.push(crate::proto::Packet::Subscribe(packet));
 					},
 
@@ -283,6 +306,10 @@ impl State {
 							BatchedSubscriptionUpdate::Unsubscribe(packet.unsubscribe_from.clone()),
 						));
 
+						let span = crate::trace::Span::unsubscribe(&packet);
+						span.event("sent UNSUBSCRIBE");
+						self.spans.insert(packet_identifier, span);
+
 						packets_waiting_to_be_sent.push(crate::proto::Packet::Unsubscribe(packet));
 					},
 
@@ -290,7 +317,9 @@ impl State {
 						err = Some(err_);
 
 						for pending_unsubscription in pending_unsubscriptions.drain(..) {
-							self.subscription_updates_waiting_to_be_sent.push_front(SubscriptionUpdate::Unsubscribe(pending_unsubscription));
+							self.subscription_updates_waiting_to_be_sent.push_front(SubscriptionUpdate::Unsubscribe(
+								crate::topic::TopicFilter::new_unchecked(pending_unsubscription),
+							));
 						}
 					},
 				};
@@ -313,8 +342,16 @@ impl State {
 	) -> impl Iterator<Item = crate::proto::Packet> {
 		if reset_session {
 			let mut subscriptions = std::mem::replace(&mut self.subscriptions, Default::default());
+			if !self.resubscribe_on_session_reset {
+				subscriptions.clear();
+			}
 			let subscription_updates_waiting_to_be_acked = std::mem::replace(&mut self.subscription_updates_waiting_to_be_acked, Default::default());
 
+			// The unacked updates are merged into a single new SUBSCRIBE packet below, which gets its own span
+			for span in std::mem::take(&mut self.spans).values() {
+				span.event("merged into resubscription because the session was reset");
+			}
+
 			// Apply all pending (ie unacked) changes to the set of subscriptions, in order that they were original requested
 			for (packet_identifier, subscription_update_waiting_to_be_acked) in subscription_updates_waiting_to_be_acked {
 				packet_identifiers.discard(packet_identifier);
@@ -328,7 +365,7 @@ impl State {
 
 					BatchedSubscriptionUpdate::Unsubscribe(unsubscribe_from) => {
 						for topic_filter in unsubscribe_from {
-							subscriptions.remove(&topic_filter);
+							subscriptions.remove(&*topic_filter);
 						}
 					},
 				}
@@ -354,13 +391,23 @@ impl State {
 					BatchedSubscriptionUpdate::Subscribe(subscriptions_waiting_to_be_acked.clone()),
 				));
 
-				NewConnectionIter::Single(std::iter::once(crate::proto::Packet::Subscribe(crate::proto::Subscribe {
+				let packet = crate::proto::Subscribe {
 					packet_identifier,
 					subscribe_to: subscriptions_waiting_to_be_acked,
-				})))
+				};
+
+				let span = crate::trace::Span::subscribe(&packet);
+				span.event("sent SUBSCRIBE");
+				self.spans.insert(packet_identifier, span);
+
+				NewConnectionIter::Single(std::iter::once(crate::proto::Packet::Subscribe(packet)))
 			}
 		}
 		else {
+			for span in self.spans.values() {
+				span.event("resending on new connection");
+			}
+
 			// Re-create all pending (ie unacked) changes to the set of subscriptions
 			let unacked_packets: Vec<_> =
 				self.subscription_updates_waiting_to_be_acked.iter()
@@ -389,12 +436,71 @@ impl State {
 		Ok(())
 	}
 
-	pub(super) fn unsubscribe(&mut self, unsubscribe_from: String) -> Result<(), UpdateSubscriptionError> {
+	pub(super) fn unsubscribe(&mut self, unsubscribe_from: crate::topic::TopicFilter) -> Result<(), UpdateSubscriptionError> {
 		let subscription_update = SubscriptionUpdate::unsubscribe(unsubscribe_from)?;
 		self.subscription_updates_waiting_to_be_sent.push_back(subscription_update);
 		Ok(())
 	}
 
+	pub(super) fn set_subscriptions(&mut self, subscribe_to: Vec<crate::proto::SubscribeTo>) -> Result<(), UpdateSubscriptionError> {
+		let subscription_updates = SubscriptionUpdate::set_subscriptions(subscribe_to)?;
+		self.subscription_updates_waiting_to_be_sent.extend(subscription_updates);
+		Ok(())
+	}
+
+	pub(super) fn set_resubscribe_on_session_reset(&mut self, resubscribe_on_session_reset: bool) {
+		self.resubscribe_on_session_reset = resubscribe_on_session_reset;
+	}
+
+	pub(super) fn subscriptions(&self) -> super::Subscriptions {
+		let mut pending = vec![];
+
+		for (_, subscription_update) in &self.subscription_updates_waiting_to_be_acked {
+			match subscription_update {
+				BatchedSubscriptionUpdate::Subscribe(subscribe_to) =>
+					pending.extend(subscribe_to.iter().cloned().map(super::SubscriptionUpdateEvent::Subscribe)),
+
+				BatchedSubscriptionUpdate::Unsubscribe(unsubscribe_from) =>
+					pending.extend(
+						unsubscribe_from.iter()
+							.map(|topic_filter| super::SubscriptionUpdateEvent::Unsubscribe(crate::topic::TopicFilter::new_unchecked(topic_filter.clone()))),
+					),
+			}
+		}
+
+		for subscription_update in &self.subscription_updates_waiting_to_be_sent {
+			match subscription_update {
+				SubscriptionUpdate::Subscribe(subscribe_to) =>
+					pending.push(super::SubscriptionUpdateEvent::Subscribe(subscribe_to.clone())),
+
+				SubscriptionUpdate::Unsubscribe(unsubscribe_from) =>
+					pending.push(super::SubscriptionUpdateEvent::Unsubscribe(unsubscribe_from.clone())),
+
+				SubscriptionUpdate::UnsubscribeAll => {
+					// Expand this into an unsubscription from every topic filter that is subscribed to at this point
+					let mut subscribed: std::collections::BTreeSet<_> = self.subscriptions.keys().cloned().collect();
+					for subscription_update in &pending {
+						match subscription_update {
+							super::SubscriptionUpdateEvent::Subscribe(subscribe_to) => {
+								subscribed.insert(subscribe_to.topic_filter.clone());
+							},
+							super::SubscriptionUpdateEvent::Unsubscribe(unsubscribe_from) => {
+								subscribed.remove(unsubscribe_from);
+							},
+						}
+					}
+
+					pending.extend(subscribed.into_iter().map(super::SubscriptionUpdateEvent::Unsubscribe));
+				},
+			}
+		}
+
+		super::Subscriptions {
+			acked: self.subscriptions.clone(),
+			pending,
+		}
+	}
+
 	pub(super) fn update_subscription_handle(&self) -> UpdateSubscriptionHandle {
 		UpdateSubscriptionHandle(self.subscriptions_updated_send.clone())
 	}
@@ -407,11 +513,14 @@ impl Default for State {
 		State {
 			subscriptions: Default::default(),
 
+			resubscribe_on_session_reset: true,
+
 			subscriptions_updated_send,
 			subscriptions_updated_recv,
 
 			subscription_updates_waiting_to_be_sent: Default::default(),
 			subscription_updates_waiting_to_be_acked: Default::default(),
+			spans: Default::default(),
 		}
 	}
 }
@@ -419,7 +528,11 @@ impl Default for State {
 #[derive(Clone, Debug)]
 pub(super) enum SubscriptionUpdate {
 	Subscribe(crate::proto::SubscribeTo),
-	Unsubscribe(String),
+	Unsubscribe(crate::topic::TopicFilter),
+
+	/// Removes all subscriptions, including the ones requested by earlier updates.
+	/// Only used as the first update of a [`SubscriptionUpdate::set_subscriptions`] batch.
+	UnsubscribeAll,
 }
 
 impl SubscriptionUpdate {
@@ -431,24 +544,33 @@ impl SubscriptionUpdate {
 
 		let subscribe_to = match try_append_subscription(&mut packet, subscribe_to) {
 			Ok(()) => packet.subscribe_to.into_iter().next().expect("just inserted element above, so it must exist"),
-			Err((subscribe_to, err)) => return Err(UpdateSubscriptionError::EncodePacket(subscribe_to.topic_filter, err)),
+			Err((subscribe_to, err)) => return Err(UpdateSubscriptionError::EncodePacket(subscribe_to.topic_filter.into_string(), err)),
 		};
 
 		Ok(SubscriptionUpdate::Subscribe(subscribe_to))
 	}
 
-	pub(super) fn unsubscribe(unsubscribe_from: String) -> Result<Self, UpdateSubscriptionError> {
+	pub(super) fn unsubscribe(unsubscribe_from: crate::topic::TopicFilter) -> Result<Self, UpdateSubscriptionError> {
 		let mut packet = crate::proto::Unsubscribe {
 			packet_identifier: crate::proto::PacketIdentifier::max_value(),
 			unsubscribe_from: vec![],
 		};
 
-		let unsubscribe_from = match try_append_unsubscription(&mut packet, unsubscribe_from) {
+		let unsubscribe_from = match try_append_unsubscription(&mut packet, unsubscribe_from.into_string()) {
 			Ok(()) => packet.unsubscribe_from.into_iter().next().expect("just inserted element above, so it must exist"),
 			Err((unsubscribe_from, err)) => return Err(UpdateSubscriptionError::EncodePacket(unsubscribe_from, err)),
 		};
 
-		Ok(SubscriptionUpdate::Unsubscribe(unsubscribe_from))
+		Ok(SubscriptionUpdate::Unsubscribe(crate::topic::TopicFilter::new_unchecked(unsubscribe_from)))
+	}
+
+	/// The updates that replace the current subscriptions with the given ones.
+	///
+	/// Since all the updates are applied in the same client poll, the client only sends the difference between the current and the new subscriptions.
+	pub(super) fn set_subscriptions(subscribe_to: Vec<crate::proto::SubscribeTo>) -> Result<Vec<Self>, UpdateSubscriptionError> {
+		std::iter::once(Ok(SubscriptionUpdate::UnsubscribeAll))
+			.chain(subscribe_to.into_iter().map(SubscriptionUpdate::subscribe))
+			.collect()
 	}
 }
 
@@ -478,7 +600,7 @@ impl Iterator for NewConnectionIter {
 }
 
 /// Used to update subscriptions
-pub struct UpdateSubscriptionHandle(futures::sync::mpsc::Sender<SubscriptionUpdate>);
+pub struct UpdateSubscriptionHandle(futures::sync::mpsc::Sender<Vec<SubscriptionUpdate>>);
 
 impl UpdateSubscriptionHandle {
 	/// Subscribe to a topic with the given parameters.
@@ -497,12 +619,8 @@ impl UpdateSubscriptionHandle {
 	/// To know when the server has acked the subscription update, wait for the client to send an [`mqtt::Event::SubscriptionUpdate::Subscribe`] value
 	/// that contains a `mqtt::proto::SubscribeTo` value with the same topic filter.
 	/// Be careful about using `==` to determine this, since the QoS in the event may be higher than the one requested here.
-	pub fn subscribe(&mut self, subscribe_to: crate::proto::SubscribeTo) -> impl Future<Item = (), Error = UpdateSubscriptionError> {
-		let sender = self.0.clone();
-		SubscriptionUpdate::subscribe(subscribe_to)
-			.into_future()
-			.and_then(|subscription_update| sender.send(subscription_update).map_err(|_| UpdateSubscriptionError::ClientDoesNotExist))
-			.map(|_| ())
+	pub fn subscribe(&mut self, subscribe_to: crate::proto::SubscribeTo) -> UpdateSubscriptionFuture {
+		self.send(SubscriptionUpdate::subscribe(subscribe_to).map(|subscription_update| vec![subscription_update]))
 	}
 
 	/// Unsubscribe from the given topic.
@@ -520,12 +638,87 @@ impl UpdateSubscriptionHandle {
 	///
 	/// To know when the server has acked the subscription update, wait for the client to send an [`mqtt::Event::SubscriptionUpdate::Unsubscribe`] value
 	/// for this topic filter.
-	pub fn unsubscribe(&mut self, unsubscribe_from: String) -> impl Future<Item = (), Error = UpdateSubscriptionError> {
-		let sender = self.0.clone();
-		SubscriptionUpdate::unsubscribe(unsubscribe_from)
-			.into_future()
-			.and_then(|subscription_update| sender.send(subscription_update).map_err(|_| UpdateSubscriptionError::ClientDoesNotExist))
-			.map(|_| ())
+	pub fn unsubscribe(&mut self, unsubscribe_from: crate::topic::TopicFilter) -> UpdateSubscriptionFuture {
+		self.send(SubscriptionUpdate::unsubscribe(unsubscribe_from).map(|subscription_update| vec![subscription_update]))
+	}
+
+	/// Subscribe to multiple topics with the given parameters.
+	///
+	/// The subscriptions are handed to the client together, so they will be sent to the server in a single SUBSCRIBE packet
+	/// unless they do not fit in one, in which case they are split across as few packets as possible.
+	///
+	/// If any of the subscriptions is invalid, the returned [`Future`] fails and none of them are sent.
+	///
+	/// See [`UpdateSubscriptionHandle::subscribe`] for details on when the returned [`Future`] resolves, and how to know when the server has acked the subscriptions.
+	pub fn subscribe_many(&mut self, subscribe_to: Vec<crate::proto::SubscribeTo>) -> UpdateSubscriptionFuture {
+		self.send(subscribe_to.into_iter().map(SubscriptionUpdate::subscribe).collect())
+	}
+
+	/// Unsubscribe from multiple topics.
+	///
+	/// The unsubscriptions are handed to the client together, so they will be sent to the server in a single UNSUBSCRIBE packet
+	/// unless they do not fit in one, in which case they are split across as few packets as possible.
+	///
+	/// If any of the topic filters is invalid, the returned [`Future`] fails and none of them are sent.
+	///
+	/// See [`UpdateSubscriptionHandle::unsubscribe`] for details on when the returned [`Future`] resolves, and how to know when the server has acked the unsubscriptions.
+	pub fn unsubscribe_many(&mut self, unsubscribe_from: Vec<crate::topic::TopicFilter>) -> UpdateSubscriptionFuture {
+		self.send(unsubscribe_from.into_iter().map(SubscriptionUpdate::unsubscribe).collect())
+	}
+
+	/// Replace the current subscriptions with the given ones.
+	///
+	/// The client computes the difference between its current subscriptions (including ones that the server has not acked yet)
+	/// and `subscribe_to`, and only sends SUBSCRIBE and UNSUBSCRIBE packets for topic filters that were added, removed, or whose requested quality of service changed.
+	///
+	/// If any of the subscriptions is invalid, the returned [`Future`] fails and the current subscriptions are left unchanged.
+	///
+	/// See [`UpdateSubscriptionHandle::subscribe`] for details on when the returned [`Future`] resolves, and how to know when the server has acked the changes.
+	pub fn set_subscriptions(&mut self, subscribe_to: Vec<crate::proto::SubscribeTo>) -> UpdateSubscriptionFuture {
+		self.send(SubscriptionUpdate::set_subscriptions(subscribe_to))
+	}
+
+	fn send(&self, subscription_updates: Result<Vec<SubscriptionUpdate>, UpdateSubscriptionError>) -> UpdateSubscriptionFuture {
+		match subscription_updates {
+			Ok(subscription_updates) => UpdateSubscriptionFuture(UpdateSubscriptionFutureState::Sending(self.0.clone().send(subscription_updates))),
+			Err(err) => UpdateSubscriptionFuture(UpdateSubscriptionFutureState::Failed(Some(err))),
+		}
+	}
+}
+
+/// The [`Future`] returned by the methods of [`UpdateSubscriptionHandle`].
+///
+/// It resolves when the subscription update has been received by the client.
+#[must_use = "futures do nothing unless polled"]
+pub struct UpdateSubscriptionFuture(UpdateSubscriptionFutureState);
+
+enum UpdateSubscriptionFutureState {
+	Failed(Option<UpdateSubscriptionError>),
+	Sending(futures::sink::Send<futures::sync::mpsc::Sender<Vec<SubscriptionUpdate>>>),
+}
+
+impl Future for UpdateSubscriptionFuture {
+	type Item = ();
+	type Error = UpdateSubscriptionError;
+
+	fn poll(&mut self) -> futures::Poll<Self::Item, Self::Error> {
+		match &mut self.0 {
+			UpdateSubscriptionFutureState::Failed(err) => Err(err.take().expect("UpdateSubscriptionFuture polled after completion")),
+
+			UpdateSubscriptionFutureState::Sending(send) => {
+				let _ = futures::try_ready!(send.poll().map_err(|_| UpdateSubscriptionError::ClientDoesNotExist));
+				Ok(futures::Async::Ready(()))
+			},
+		}
+	}
+}
+
+impl std::fmt::Debug for UpdateSubscriptionFuture {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match &self.0 {
+			UpdateSubscriptionFutureState::Failed(_) => f.write_str("Failed"),
+			UpdateSubscriptionFutureState::Sending(_) => f.write_str("Sending"),
+		}
 	}
 }
 
@@ -0,0 +1,96 @@
+use futures::Future;
+
+/// Tears down the connection if the server has not sent anything for too long.
+///
+/// Writes to a half-open TCP connection can keep succeeding for a long time since they only fill up the OS send buffer,
+/// so the client would not otherwise notice that the connection is dead until the ping response timer expires.
+pub(super) enum State {
+	BeginWaiting,
+	Waiting(tokio_timer::Delay),
+}
+
+impl State {
+	pub(super) fn poll(
+		&mut self,
+		packet_received: bool,
+		timeout: std::time::Duration,
+	) -> Result<(), super::Error> {
+		if packet_received {
+			match self {
+				State::BeginWaiting => (),
+				State::Waiting(timer) => timer.reset(tokio_timer::clock::now() + timeout),
+			}
+		}
+
+		loop {
+			log::trace!("    {:?}", self);
+
+			match self {
+				State::BeginWaiting => *self = State::Waiting(tokio_timer::Delay::new(tokio_timer::clock::now() + timeout)),
+
+				State::Waiting(timer) => match timer.poll().map_err(super::Error::InactivityTimer)? {
+					futures::Async::Ready(()) => return Err(super::Error::InactivityTimeout),
+					futures::Async::NotReady => return Ok(()),
+				},
+			}
+		}
+	}
+
+	pub(super) fn new_connection(&mut self) {
+		*self = State::BeginWaiting;
+	}
+}
+
+impl std::fmt::Debug for State {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			State::BeginWaiting => f.write_str("BeginWaiting"),
+			State::Waiting(_) => f.write_str("Waiting"),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	#[test]
+	fn inactive_connection_times_out() {
+		let mut timer = crate::client::mock_timer::MockTimer::new();
+		let mut state = super::State::BeginWaiting;
+		let timeout = std::time::Duration::from_secs(10);
+
+		timer.run(|| state.poll(false, timeout)).unwrap();
+
+		timer.advance(std::time::Duration::from_secs(9));
+		timer.run(|| state.poll(false, timeout)).unwrap();
+
+		timer.advance(std::time::Duration::from_secs(1));
+		match timer.run(|| state.poll(false, timeout)) {
+			Err(crate::Error::InactivityTimeout) => (),
+			result => panic!("expected InactivityTimeout but got {:?}", result),
+		}
+	}
+
+	#[test]
+	fn received_packets_reset_timer() {
+		let mut timer = crate::client::mock_timer::MockTimer::new();
+		let mut state = super::State::BeginWaiting;
+		let timeout = std::time::Duration::from_secs(10);
+
+		timer.run(|| state.poll(false, timeout)).unwrap();
+
+		// Keep receiving packets for longer than the timeout
+		for _ in 0..4 {
+			timer.advance(std::time::Duration::from_secs(5));
+			timer.run(|| state.poll(true, timeout)).unwrap();
+		}
+
+		timer.advance(std::time::Duration::from_secs(9));
+		timer.run(|| state.poll(false, timeout)).unwrap();
+
+		timer.advance(std::time::Duration::from_secs(1));
+		match timer.run(|| state.poll(false, timeout)) {
+			Err(crate::Error::InactivityTimeout) => (),
+			result => panic!("expected InactivityTimeout but got {:?}", result),
+		}
+	}
+}
@@ -0,0 +1,117 @@
+/*!
+ * A [`ConfigWatcher`] that keeps the latest retained value of every topic under a configuration subtree, like `config/my-device/#`.
+ *
+ * Configuration is commonly published as retained publications, so that a client gets the current configuration as soon as it subscribes,
+ * and then gets every change while it stays subscribed. A retained publication with an empty payload clears the value of its topic.
+ *
+ * The watcher gets the publications of its subtree from a [`Router`](crate::router::Router), and subscribes to the subtree itself.
+ * It is a [`Stream`] of the changes to the values, and [`ConfigWatcher::handle`] returns a handle to read the current values from elsewhere.
+ */
+
+use futures::{ Future, Stream };
+
+/// Keeps the latest value of every topic that matches a topic filter, and yields a [`ConfigChange`] every time one of them changes.
+///
+/// The watcher must be polled for the values to be updated. The stream fails if the subscription fails, and ends when the router is dropped
+/// or the client's events end.
+///
+/// The server sends the retained publications again when the client resubscribes, like after it reconnects without a session.
+/// Publications that don't change the value of their topic are not yielded as changes.
+#[derive(Debug)]
+pub struct ConfigWatcher {
+	subscription: Option<crate::UpdateSubscriptionFuture>,
+	route: crate::router::RouteStream,
+	values: Values,
+}
+
+type Values = std::sync::Arc<std::sync::RwLock<std::collections::BTreeMap<String, bytes::Bytes>>>;
+
+impl ConfigWatcher {
+	/// Creates a watcher for the topics that match the given topic filter, and subscribes to them with at-least-once quality of service.
+	pub fn new<S>(
+		router: &mut crate::router::Router<S>,
+		update_subscription_handle: &mut crate::UpdateSubscriptionHandle,
+		topic_filter: crate::topic::TopicFilter,
+	) -> Self {
+		let route = router.route(topic_filter.to_string());
+		let subscription = update_subscription_handle.subscribe(crate::proto::SubscribeTo { topic_filter, qos: crate::proto::QoS::AtLeastOnce });
+
+		ConfigWatcher {
+			subscription: Some(subscription),
+			route,
+			values: Default::default(),
+		}
+	}
+
+	/// Returns a handle to read the current values, like from another task than the one that polls the watcher.
+	pub fn handle(&self) -> ConfigHandle {
+		ConfigHandle(self.values.clone())
+	}
+
+	fn update(&self, publication: crate::ReceivedPublication) -> Option<ConfigChange> {
+		let mut values = self.values.write().unwrap_or_else(std::sync::PoisonError::into_inner);
+
+		if publication.payload.is_empty() {
+			let previous = values.remove(&publication.topic_name)?;
+			Some(ConfigChange::Removed { topic_name: publication.topic_name, previous })
+		}
+		else {
+			let previous = values.insert(publication.topic_name.clone(), publication.payload.clone());
+			if previous.as_ref() == Some(&publication.payload) {
+				return None;
+			}
+
+			Some(ConfigChange::Set { topic_name: publication.topic_name, value: publication.payload, previous })
+		}
+	}
+}
+
+impl Stream for ConfigWatcher {
+	type Item = ConfigChange;
+	type Error = crate::UpdateSubscriptionError;
+
+	fn poll(&mut self) -> futures::Poll<Option<Self::Item>, Self::Error> {
+		if let Some(subscription) = &mut self.subscription {
+			let () = futures::try_ready!(subscription.poll());
+			self.subscription = None;
+		}
+
+		loop {
+			let publication = match self.route.poll().expect("RouteStream::poll cannot fail") {
+				futures::Async::Ready(Some(publication)) => publication,
+				futures::Async::Ready(None) => return Ok(futures::Async::Ready(None)),
+				futures::Async::NotReady => return Ok(futures::Async::NotReady),
+			};
+
+			if let Some(change) = self.update(publication) {
+				return Ok(futures::Async::Ready(Some(change)));
+			}
+		}
+	}
+}
+
+/// A handle to read the current values of a [`ConfigWatcher`]. It can be cloned and sent to other threads.
+#[derive(Clone, Debug)]
+pub struct ConfigHandle(Values);
+
+impl ConfigHandle {
+	/// The current value of the given topic, or `None` if the topic has no value
+	pub fn get(&self, topic_name: &str) -> Option<bytes::Bytes> {
+		self.0.read().unwrap_or_else(std::sync::PoisonError::into_inner).get(topic_name).cloned()
+	}
+
+	/// The current values of all the topics that have one, by topic name
+	pub fn snapshot(&self) -> std::collections::BTreeMap<String, bytes::Bytes> {
+		self.0.read().unwrap_or_else(std::sync::PoisonError::into_inner).clone()
+	}
+}
+
+/// A change to the value of a topic, yielded by a [`ConfigWatcher`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConfigChange {
+	/// The topic got a new value. `previous` is `None` if it did not have a value before.
+	Set { topic_name: String, value: bytes::Bytes, previous: Option<bytes::Bytes> },
+
+	/// The value of the topic was cleared by a publication with an empty payload
+	Removed { topic_name: String, previous: bytes::Bytes },
+}
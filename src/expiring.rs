@@ -0,0 +1,170 @@
+/*!
+ * An [`IoSource`](crate::IoSource) whose connections are closed once they reach a maximum age.
+ *
+ * This is useful when the server's credentials expire, like SAS tokens or JWTs, and the server disconnects the client when they do.
+ * Closing the connection a little before that makes the client reconnect, and so get fresh credentials from its
+ * [`CredentialsProvider`](crate::CredentialsProvider), on its own schedule rather than the server's.
+ *
+ * The maximum age is either fixed, or a shared [`Deadline`] that a credentials provider updates whenever it gets credentials that expire
+ * at a time it only learns then, like OAuth2 access tokens.
+ */
+
+use futures::Future;
+
+/// An [`IoSource`](crate::IoSource) whose connections are closed once they reach a maximum age.
+///
+/// The connections are made by the wrapped `IoSource`. Once a connection reaches the maximum age, reading from and writing to it fail
+/// with [`std::io::ErrorKind::ConnectionAborted`], so the client reconnects like it does for any other broken connection.
+///
+/// The connections use a timer, so they must be used from within a tokio runtime.
+#[derive(Debug)]
+pub struct ExpiringIoSource<S> {
+	io_source: S,
+	max_age: MaxAge,
+}
+
+#[derive(Debug)]
+enum MaxAge {
+	Duration(std::time::Duration),
+	Deadline(Deadline),
+}
+
+impl<S> ExpiringIoSource<S> {
+	pub fn new(io_source: S, max_age: std::time::Duration) -> Self {
+		ExpiringIoSource {
+			io_source,
+			max_age: MaxAge::Duration(max_age),
+		}
+	}
+
+	/// Creates an `IoSource` whose connections are closed at the time of the given deadline, as of when they are made.
+	///
+	/// Connections that are made while the deadline is not set are not closed.
+	pub fn with_deadline(io_source: S, deadline: Deadline) -> Self {
+		ExpiringIoSource {
+			io_source,
+			max_age: MaxAge::Deadline(deadline),
+		}
+	}
+}
+
+impl<S> crate::IoSource for ExpiringIoSource<S> where S: crate::IoSource {
+	type Io = ExpiringIo<S::Io>;
+	type Future = ExpiringConnectFuture<S::Future>;
+
+	fn connect(&mut self) -> Self::Future {
+		ExpiringConnectFuture {
+			inner: self.io_source.connect(),
+			max_age: match &self.max_age {
+				MaxAge::Duration(max_age) => MaxAge::Duration(*max_age),
+				MaxAge::Deadline(deadline) => MaxAge::Deadline(deadline.clone()),
+			},
+		}
+	}
+}
+
+/// The time at which the connections of an [`ExpiringIoSource`] are closed, shared with whatever sets it.
+///
+/// Clones of a `Deadline` share the same time.
+#[derive(Clone, Debug, Default)]
+pub struct Deadline(std::sync::Arc<std::sync::Mutex<Option<std::time::Instant>>>);
+
+impl Deadline {
+	pub fn new() -> Self {
+		Deadline::default()
+	}
+
+	pub fn get(&self) -> Option<std::time::Instant> {
+		*self.0.lock().unwrap_or_else(std::sync::PoisonError::into_inner)
+	}
+
+	/// Sets the deadline of connections that are made from now on. Connections that have already been made keep their deadline.
+	pub fn set(&self, deadline: Option<std::time::Instant>) {
+		*self.0.lock().unwrap_or_else(std::sync::PoisonError::into_inner) = deadline;
+	}
+}
+
+/// The connection future of an [`ExpiringIoSource`]
+#[derive(Debug)]
+pub struct ExpiringConnectFuture<F> {
+	inner: F,
+	max_age: MaxAge,
+}
+
+impl<F, I> Future for ExpiringConnectFuture<F> where F: Future<Item = (I, Option<String>)> {
+	type Item = (ExpiringIo<I>, Option<String>);
+	type Error = F::Error;
+
+	fn poll(&mut self) -> futures::Poll<Self::Item, Self::Error> {
+		let (inner, password) = futures::try_ready!(self.inner.poll());
+		let expiry = match &self.max_age {
+			MaxAge::Duration(max_age) => Some(tokio_timer::clock::now() + *max_age),
+			MaxAge::Deadline(deadline) => deadline.get(),
+		};
+		let io = ExpiringIo {
+			inner,
+			expiry: expiry.map(tokio_timer::Delay::new),
+			expired: false,
+		};
+		Ok(futures::Async::Ready((io, password)))
+	}
+}
+
+/// A connection of an [`ExpiringIoSource`]
+#[derive(Debug)]
+pub struct ExpiringIo<T> {
+	inner: T,
+	expiry: Option<tokio_timer::Delay>,
+	expired: bool,
+}
+
+impl<T> ExpiringIo<T> {
+	/// Returns an error if the connection has reached its maximum age. Otherwise the current task is notified when it does.
+	fn poll_expiry(&mut self) -> std::io::Result<()> {
+		if !self.expired {
+			let expiry = match &mut self.expiry {
+				Some(expiry) => expiry,
+				None => return Ok(()),
+			};
+
+			match expiry.poll() {
+				Ok(futures::Async::Ready(())) => {
+					log::info!("closing connection since it has reached its maximum age");
+					self.expired = true;
+				},
+				Ok(futures::Async::NotReady) => return Ok(()),
+				Err(err) => return Err(std::io::Error::other(err)),
+			}
+		}
+
+		Err(std::io::Error::new(std::io::ErrorKind::ConnectionAborted, "connection has reached its maximum age"))
+	}
+}
+
+impl<T> std::io::Read for ExpiringIo<T> where T: std::io::Read {
+	fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+		self.poll_expiry()?;
+		self.inner.read(buf)
+	}
+}
+
+impl<T> tokio_io::AsyncRead for ExpiringIo<T> where T: tokio_io::AsyncRead {
+}
+
+impl<T> std::io::Write for ExpiringIo<T> where T: std::io::Write {
+	fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+		self.poll_expiry()?;
+		self.inner.write(buf)
+	}
+
+	fn flush(&mut self) -> std::io::Result<()> {
+		self.poll_expiry()?;
+		self.inner.flush()
+	}
+}
+
+impl<T> tokio_io::AsyncWrite for ExpiringIo<T> where T: tokio_io::AsyncWrite {
+	fn shutdown(&mut self) -> futures::Poll<(), std::io::Error> {
+		self.inner.shutdown()
+	}
+}
@@ -0,0 +1,79 @@
+/*!
+ * Interceptors that see every publication that a client sends and receives, and can change or reject it.
+ *
+ * This is for cross-cutting concerns like encrypting or signing payloads, or validating them against a schema, without changing
+ * every place that publishes or handles publications. Add an [`Interceptor`] to a client with [`Client::add_interceptor`](crate::Client::add_interceptor).
+ *
+ * A client runs its interceptors in order for the publications that it sends, and in reverse order for the publications that it receives,
+ * so that an interceptor that was added after another one sees the publications it receives the way it sent them. For example,
+ * with a signing interceptor followed by an encrypting one, outgoing payloads are signed and then encrypted,
+ * and incoming payloads are decrypted and then verified.
+ */
+
+/// An error returned by an [`Interceptor`] to reject a publication
+pub type Rejection = Box<dyn std::error::Error + Send + Sync>;
+
+/// Sees every publication that a client sends and receives, and can change or reject it.
+///
+/// Interceptors are shared by the client and its publish handles, which can be used from other threads, so they take `&self`.
+/// Use interior mutability for any state that they need to keep.
+pub trait Interceptor: Send + Sync {
+	/// Called for every publication that is published with the client or one of its publish handles, before it is queued to be sent.
+	///
+	/// A rejected publication is not sent, and publishing it fails with [`PublishError::Rejected`](crate::PublishError::Rejected).
+	/// The will of the client is not intercepted.
+	///
+	/// Defaults to passing the publication through unchanged.
+	fn outgoing(&self, publication: &mut crate::proto::Publication) -> Result<(), Rejection> {
+		let _ = publication;
+		Ok(())
+	}
+
+	/// Called for every publication that the client receives, before it is buffered by a paused client, passed to handlers
+	/// or returned as an [`Event::Publication`](crate::Event::Publication).
+	///
+	/// A rejected publication is dropped. It is still acked to the server, since the server would otherwise send it again.
+	///
+	/// Defaults to passing the publication through unchanged.
+	fn incoming(&self, publication: &mut crate::ReceivedPublication) -> Result<(), Rejection> {
+		let _ = publication;
+		Ok(())
+	}
+}
+
+/// The interceptors of a client, shared with its publish handles
+#[derive(Clone, Default)]
+pub(crate) struct Interceptors(std::sync::Arc<std::sync::RwLock<Vec<Box<dyn Interceptor>>>>);
+
+impl Interceptors {
+	pub(crate) fn add(&self, interceptor: Box<dyn Interceptor>) {
+		self.0.write().unwrap_or_else(std::sync::PoisonError::into_inner).push(interceptor);
+	}
+
+	pub(crate) fn is_empty(&self) -> bool {
+		self.0.read().unwrap_or_else(std::sync::PoisonError::into_inner).is_empty()
+	}
+
+	pub(crate) fn outgoing(&self, publication: &mut crate::proto::Publication) -> Result<(), Rejection> {
+		let interceptors = self.0.read().unwrap_or_else(std::sync::PoisonError::into_inner);
+		for interceptor in &*interceptors {
+			interceptor.outgoing(publication)?;
+		}
+		Ok(())
+	}
+
+	pub(crate) fn incoming(&self, publication: &mut crate::ReceivedPublication) -> Result<(), Rejection> {
+		let interceptors = self.0.read().unwrap_or_else(std::sync::PoisonError::into_inner);
+		for interceptor in interceptors.iter().rev() {
+			interceptor.incoming(publication)?;
+		}
+		Ok(())
+	}
+}
+
+impl std::fmt::Debug for Interceptors {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		let len = self.0.read().unwrap_or_else(std::sync::PoisonError::into_inner).len();
+		f.debug_struct("Interceptors").field("len", &len).finish()
+	}
+}
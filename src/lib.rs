@@ -18,20 +18,112 @@
 
 mod client;
 pub use self::client::{
+	AckError,
+	AckHandle,
 	Client,
+	Credentials,
+	CredentialsProvider,
 	Error,
 	Event,
 	IoSource,
+	KeepAliveError,
+	KeepAliveFuture,
+	KeepAliveHandle,
+	OverflowPolicy,
+	PauseError,
+	PauseFuture,
+	PauseHandle,
 	PublishError,
+	PublishFuture,
 	PublishHandle,
+	PublishStreamError,
+	PublishStreamFuture,
+	PublishToManyFuture,
+	PublishWithTokenFuture,
 	ReceivedPublication,
 	ShutdownError,
 	ShutdownHandle,
+	Stats,
+	StatsHandle,
+	StreamedPublication,
 	SubscriptionUpdateEvent,
+	Subscriptions,
 	UpdateSubscriptionError,
+	UpdateSubscriptionFuture,
 	UpdateSubscriptionHandle,
 };
 
+#[cfg(feature = "aws-iot")]
+pub mod aws_iot;
+
+#[cfg(feature = "azure-iot-hub")]
+pub mod azure_iot_hub;
+
+pub mod bridge;
+
+pub mod capture;
+
+pub mod config;
+
+pub mod expiring;
+
+pub mod interceptor;
+
 mod logging_framed;
+pub use self::logging_framed::{ FlushPolicy, PacketLogFormat, PayloadLogging };
+
+pub mod metrics;
+
+#[cfg(feature = "native-tls")]
+pub mod native_tls;
+
+#[cfg(feature = "oauth2")]
+pub mod oauth2;
+
+#[cfg(any(feature = "aws-iot", feature = "azure-iot-hub", feature = "oauth2"))]
+mod percent_encoding;
+
+pub mod pool;
 
 pub mod proto;
+
+#[cfg(feature = "proxy")]
+pub mod proxy;
+
+pub mod replay;
+
+pub mod rpc;
+
+pub mod router;
+
+pub mod tap;
+
+pub mod topic;
+
+#[cfg(feature = "tower")]
+pub mod tower;
+
+#[cfg(feature = "serde")]
+pub mod typed;
+
+mod trace;
+
+#[cfg(feature = "tcp")]
+pub mod tcp;
+
+#[cfg(feature = "testing")]
+pub mod testing;
+
+#[cfg(feature = "tls")]
+pub mod tls;
+
+#[cfg(all(unix, feature = "uds"))]
+pub mod uds;
+
+#[cfg(feature = "tcp")]
+pub mod url;
+
+#[cfg(feature = "websocket")]
+pub mod websocket;
+
+pub mod work_queue;
@@ -1,35 +1,999 @@
+/// The size of the read buffer, and the amount of buffered writes after which `start_send` first tries to flush them.
+/// These are the same as the ones used by `tokio_codec::Framed`.
+const INITIAL_CAPACITY: usize = 8 * 1024;
+const BACKPRESSURE_BOUNDARY: usize = INITIAL_CAPACITY;
+
+/// PUBLISH payloads at least this large are written straight from their own buffer with a vectored write,
+/// instead of being copied into the write buffer after the packet's header
+const VECTORED_WRITE_MIN_PAYLOAD_LEN: usize = 4 * 1024;
+
+/// The amount of a streamed payload that is read at a time, ie the largest chunk of it
+const STREAMED_PAYLOAD_CHUNK_LEN: usize = 64 * 1024;
+
+/// A framed transport of MQTT packets that logs every packet it sends and receives.
+///
+/// Unlike `tokio_codec::Framed`, the packets that are waiting to be written are held as a list of chunks,
+/// so that large PUBLISH payloads do not need to be copied. `poll_complete` writes as many of the chunks as it can with a single vectored write,
+/// so multiple small packets sent in the same poll are also coalesced into a single write.
 #[derive(Debug)]
-pub(crate) struct LoggingFramed<T>(tokio_codec::Framed<T, crate::proto::PacketCodec>) where T: tokio_io::AsyncRead + tokio_io::AsyncWrite;
+pub(crate) struct LoggingFramed<T> where T: tokio_io::AsyncRead + tokio_io::AsyncWrite {
+	io: T,
+	codec: crate::proto::PacketCodec,
+	metrics: crate::metrics::Metrics,
+	packet_log_format: PacketLogFormat,
+	payload_logging: PayloadLogging,
+	capture: Option<std::sync::Arc<crate::capture::PacketCapture>>,
+	tap: Option<crate::tap::PacketTap>,
+	topic_prefix: Option<crate::topic::TopicName>,
+	flush_policy: FlushPolicy,
+
+	/// PUBLISH packets with longer payloads are received as a header followed by chunks of the payload
+	payload_streaming_threshold: Option<usize>,
+
+	read_buffer: bytes::BytesMut,
+	is_readable: bool,
+	eof: bool,
+
+	/// The length of the rest of the streamed payload that is being received
+	streamed_payload_remaining: usize,
+
+	/// Chunks that are waiting to be written, in order, before `write_buffer`
+	write_chunks: std::collections::VecDeque<bytes::Bytes>,
+
+	/// Encoded packets that are waiting to be written after `write_chunks`
+	write_buffer: bytes::BytesMut,
+
+	/// The number of packets that have been encoded since the connection was last flushed
+	unflushed_packets: usize,
+
+	/// Set when a packet is encoded under a flush policy with a delay, and fires when the delay has passed
+	flush_timer: Option<tokio_timer::Delay>,
+}
 
 impl<T> LoggingFramed<T> where T: tokio_io::AsyncRead + tokio_io::AsyncWrite {
-	pub(crate) fn new(io: T) -> Self {
-		LoggingFramed(tokio_codec::Framed::new(io, Default::default()))
+	pub(crate) fn new(io: T, codec: crate::proto::PacketCodec, metrics: crate::metrics::Metrics) -> Self {
+		LoggingFramed {
+			io,
+			codec,
+			metrics,
+			packet_log_format: Default::default(),
+			payload_logging: Default::default(),
+			capture: None,
+			tap: None,
+			topic_prefix: None,
+			flush_policy: Default::default(),
+			payload_streaming_threshold: None,
+
+			read_buffer: bytes::BytesMut::with_capacity(INITIAL_CAPACITY),
+			is_readable: false,
+			eof: false,
+			streamed_payload_remaining: 0,
+
+			write_chunks: Default::default(),
+			write_buffer: bytes::BytesMut::with_capacity(INITIAL_CAPACITY),
+
+			unflushed_packets: 0,
+			flush_timer: None,
+		}
+	}
+
+	pub(crate) fn set_packet_log_format(&mut self, packet_log_format: PacketLogFormat) {
+		self.packet_log_format = packet_log_format;
+	}
+
+	pub(crate) fn set_payload_logging(&mut self, payload_logging: PayloadLogging) {
+		self.payload_logging = payload_logging;
+	}
+
+	pub(crate) fn set_capture(&mut self, capture: Option<std::sync::Arc<crate::capture::PacketCapture>>) {
+		self.capture = capture;
+	}
+
+	pub(crate) fn set_tap(&mut self, tap: Option<crate::tap::PacketTap>) {
+		self.tap = tap;
+	}
+
+	pub(crate) fn set_topic_prefix(&mut self, topic_prefix: Option<crate::topic::TopicName>) {
+		self.topic_prefix = topic_prefix;
+	}
+
+	pub(crate) fn set_flush_policy(&mut self, flush_policy: FlushPolicy) {
+		self.flush_policy = flush_policy;
+	}
+
+	pub(crate) fn set_payload_streaming_threshold(&mut self, payload_streaming_threshold: Option<usize>) {
+		self.payload_streaming_threshold = payload_streaming_threshold;
+	}
+
+	/// Reads the next packet from the connection, or `None` once the server has closed it. PUBLISH packets with payloads longer than
+	/// the payload streaming threshold are returned as a header followed by chunks of their payload, so that the payload doesn't need
+	/// to be buffered whole.
+	pub(crate) fn poll_received(&mut self) -> futures::Poll<Option<Received>, crate::proto::DecodeError> {
+		use tokio_codec::Decoder;
+
+		if self.streamed_payload_remaining == 0 {
+			if let Some(item) = self.tap.as_ref().and_then(crate::tap::PacketTap::poll_injected) {
+				return Ok(futures::Async::Ready(Some(Received::Packet(self.packet_received(item)))));
+			}
+		}
+
+		loop {
+			if self.is_readable {
+				if self.streamed_payload_remaining > 0 {
+					if !self.read_buffer.is_empty() {
+						let chunk_len = std::cmp::min(self.read_buffer.len(), self.streamed_payload_remaining);
+						self.streamed_payload_remaining -= chunk_len;
+						let chunk = self.read_buffer.split_to(chunk_len).freeze();
+						return Ok(futures::Async::Ready(Some(Received::PayloadChunk(chunk))));
+					}
+
+					if self.eof {
+						return Err(crate::proto::DecodeError::IncompletePacket);
+					}
+				}
+				else if self.eof {
+					let item = self.codec.decode_eof(&mut self.read_buffer)?;
+					return Ok(futures::Async::Ready(item.map(|item| Received::Packet(self.packet_received(item)))));
+				}
+				else {
+					let decoded = match self.payload_streaming_threshold {
+						Some(payload_streaming_threshold) => self.codec.decode_streaming(&mut self.read_buffer, payload_streaming_threshold)?,
+						None => self.codec.decode(&mut self.read_buffer)?.map(crate::proto::Decoded::Packet),
+					};
+
+					match decoded {
+						Some(crate::proto::Decoded::Packet(item)) => return Ok(futures::Async::Ready(Some(Received::Packet(self.packet_received(item))))),
+
+						Some(crate::proto::Decoded::PublishHeader { publish, payload_len }) => {
+							self.streamed_payload_remaining = payload_len;
+
+							let publish = match self.packet_received(crate::proto::Packet::Publish(publish)) {
+								crate::proto::Packet::Publish(publish) => publish,
+								_ => unreachable!("packet_received only changes the topic of a PUBLISH packet"),
+							};
+							return Ok(futures::Async::Ready(Some(Received::StreamedPublish { publish, payload_len })));
+						},
+
+						None => (),
+					}
+				}
+
+				self.is_readable = false;
+			}
+
+			// A streamed payload is read in chunks of its own, instead of growing the read buffer to fit all of it
+			self.read_buffer.reserve(if self.streamed_payload_remaining > 0 { STREAMED_PAYLOAD_CHUNK_LEN } else { 1 });
+			match futures::try_ready!(tokio_io::AsyncRead::read_buf(&mut self.io, &mut self.read_buffer)) {
+				0 => self.eof = true,
+				n => self.metrics.bytes_received(n),
+			}
+
+			self.is_readable = true;
+		}
+	}
+
+	/// Like `poll_complete`, but only flushes the packets that have been sent so far when the flush policy says so.
+	/// Otherwise returns `NotReady`, and the current task is notified when the flush policy's delay, if any, has passed.
+	pub(crate) fn poll_flush_policy(&mut self) -> futures::Poll<(), crate::proto::EncodeError> {
+		use futures::{ Future, Sink };
+
+		if self.unflushed_packets == 0 {
+			return self.poll_complete();
+		}
+
+		let max_delay = match self.flush_policy {
+			FlushPolicy::Immediate => return self.poll_complete(),
+			FlushPolicy::AfterPackets { packets, .. } if self.unflushed_packets >= packets => return self.poll_complete(),
+			FlushPolicy::AfterPackets { max_delay, .. } |
+			FlushPolicy::AfterDelay(max_delay) => max_delay,
+		};
+
+		let flush_timer = self.flush_timer.get_or_insert_with(|| tokio_timer::Delay::new(tokio_timer::clock::now() + max_delay));
+		match flush_timer.poll() {
+			Ok(futures::Async::Ready(())) => self.poll_complete(),
+			Ok(futures::Async::NotReady) => Ok(futures::Async::NotReady),
+			Err(err) => {
+				log::warn!("flush timer failed, flushing now: {}", err);
+				self.poll_complete()
+			},
+		}
+	}
+
+	/// Adds a payload after the packet header that was just encoded into the write buffer
+	fn buffer_payload(&mut self, payload: bytes::Bytes) {
+		if payload.len() < VECTORED_WRITE_MIN_PAYLOAD_LEN {
+			self.write_buffer.extend_from_slice(&payload);
+		}
+		else {
+			self.write_chunks.push_back(self.write_buffer.take().freeze());
+			self.write_chunks.push_back(payload);
+		}
+	}
+
+	fn write_len(&self) -> usize {
+		self.write_chunks.iter().map(bytes::Bytes::len).sum::<usize>() + self.write_buffer.len()
+	}
+
+	/// Logs, counts and captures a received packet as it was received, and then strips the topic prefix from it
+	fn packet_received(&self, packet: crate::proto::Packet) -> crate::proto::Packet {
+		self.log_packet(crate::capture::Direction::Received, &packet);
+		self.metrics.packet_received(&packet);
+		if let Some(capture) = &self.capture {
+			capture.record(crate::capture::Direction::Received, &packet);
+		}
+
+		match (&self.topic_prefix, packet) {
+			(Some(topic_prefix), crate::proto::Packet::Publish(mut publish)) => {
+				if !publish.topic_name.starts_with('$') {
+					if let Some(topic_name) = publish.topic_name.strip_prefix(topic_prefix.as_str()) {
+						publish.topic_name = topic_name.to_owned();
+					}
+				}
+				crate::proto::Packet::Publish(publish)
+			},
+
+			(_, packet) => packet,
+		}
+	}
+
+	/// Adds the topic prefix to the topic names and topic filters of a packet that is about to be sent
+	fn add_topic_prefix(&self, packet: crate::proto::Packet) -> crate::proto::Packet {
+		let topic_prefix = match &self.topic_prefix {
+			Some(topic_prefix) => topic_prefix.as_str(),
+			None => return packet,
+		};
+
+		// Topics that start with `$` are reserved by the server, like `$SYS/#`, so they are not in any namespace
+		let prefixed = |topic: String| if topic.starts_with('$') { topic } else { format!("{}{}", topic_prefix, topic) };
+
+		// A shared subscription's topic filter is in the namespace too, so the prefix goes after its share name
+		let prefixed_filter = |topic_filter: String| match crate::topic::parse_shared_subscription(&topic_filter) {
+			Some((share_name, levels_topic_filter)) =>
+				format!("{}{}/{}", crate::topic::SHARED_SUBSCRIPTION_PREFIX, share_name, prefixed(levels_topic_filter.to_owned())),
+			None => prefixed(topic_filter),
+		};
+
+		match packet {
+			crate::proto::Packet::Connect(mut connect) => {
+				connect.will = connect.will.map(|mut will| {
+					will.topic_name = crate::topic::TopicName::new_unchecked(prefixed(will.topic_name.into_string()));
+					will
+				});
+				crate::proto::Packet::Connect(connect)
+			},
+
+			crate::proto::Packet::Publish(mut publish) => {
+				publish.topic_name = prefixed(std::mem::take(&mut publish.topic_name));
+				crate::proto::Packet::Publish(publish)
+			},
+
+			crate::proto::Packet::Subscribe(mut subscribe) => {
+				subscribe.subscribe_to =
+					subscribe.subscribe_to.into_iter()
+					.map(|crate::proto::SubscribeTo { topic_filter, qos }| crate::proto::SubscribeTo {
+						topic_filter: crate::topic::TopicFilter::new_unchecked(prefixed_filter(topic_filter.into_string())),
+						qos,
+					})
+					.collect();
+				crate::proto::Packet::Subscribe(subscribe)
+			},
+
+			crate::proto::Packet::Unsubscribe(mut unsubscribe) => {
+				for unsubscribe_from in &mut unsubscribe.unsubscribe_from {
+					*unsubscribe_from = prefixed_filter(std::mem::take(unsubscribe_from));
+				}
+				crate::proto::Packet::Unsubscribe(unsubscribe)
+			},
+
+			packet => packet,
+		}
+	}
+
+	/// Whether anything looks at the packets that are sent, beyond encoding them
+	fn needs_whole_packets(&self) -> bool {
+		self.topic_prefix.is_some() ||
+		self.tap.is_some() ||
+		self.capture.is_some() ||
+		self.metrics.is_enabled() ||
+		log::log_enabled!(log::Level::Trace)
+	}
+
+	fn log_packet(&self, direction: crate::capture::Direction, packet: &crate::proto::Packet) {
+		match (self.packet_log_format, direction) {
+			(PacketLogFormat::Debug, crate::capture::Direction::Sent) => log::trace!(">>> {:?}", PacketLog(packet, self.payload_logging)),
+			(PacketLogFormat::Debug, crate::capture::Direction::Received) => log::trace!("<<< {:?}", PacketLog(packet, self.payload_logging)),
+			(PacketLogFormat::Json, direction) => log::trace!("{}", PacketJson(packet, direction)),
+		}
 	}
 }
 
 impl<T> futures::Sink for LoggingFramed<T> where T: tokio_io::AsyncRead + tokio_io::AsyncWrite {
-	type SinkItem = <tokio_codec::Framed<T, crate::proto::PacketCodec> as futures::Sink>::SinkItem;
-	type SinkError = <tokio_codec::Framed<T, crate::proto::PacketCodec> as futures::Sink>::SinkError;
+	type SinkItem = OutgoingPacket;
+	type SinkError = crate::proto::EncodeError;
 
 	fn start_send(&mut self, item: Self::SinkItem) -> futures::StartSend<Self::SinkItem, Self::SinkError> {
-		log::trace!(">>> {:?}", item);
-		self.0.start_send(item)
+		if self.write_len() >= BACKPRESSURE_BOUNDARY {
+			let _ = self.poll_complete()?;
+
+			if self.write_len() >= BACKPRESSURE_BOUNDARY {
+				return Ok(futures::AsyncSink::NotReady(item));
+			}
+		}
+
+		let OutgoingPacket { packet: item, topic_name, payload_chunks } = item;
+
+		// The topic prefix, the tap, packet logging, metrics and capture all need the whole packet, so only then is the topic name copied into it
+		let (item, topic_name) = match (item, topic_name) {
+			(crate::proto::Packet::Publish(mut publish), Some(topic_name)) if self.needs_whole_packets() => {
+				publish.topic_name = topic_name.into_string();
+				(crate::proto::Packet::Publish(publish), None)
+			},
+
+			(item, topic_name) => (item, topic_name),
+		};
+
+		let item = self.add_topic_prefix(item);
+
+		let item = match &self.tap {
+			Some(tap) => match tap.intercept(item) {
+				Some(item) => item,
+				None => return Ok(futures::AsyncSink::Ready),
+			},
+			None => item,
+		};
+
+		self.log_packet(crate::capture::Direction::Sent, &item);
+		self.metrics.packet_sent(&item);
+		if let Some(capture) = &self.capture {
+			capture.record(crate::capture::Direction::Sent, &item);
+		}
+
+		match (item, payload_chunks) {
+			(crate::proto::Packet::Publish(publish), payload_chunks) if topic_name.is_some() || payload_chunks.is_some() => {
+				let topic_name = topic_name.as_ref().map_or(&*publish.topic_name, crate::topic::TopicName::as_str);
+
+				match payload_chunks {
+					Some(payload_chunks) => {
+						let payload_len = payload_chunks.iter().map(bytes::Bytes::len).sum();
+						self.codec.encode_publish_header(&publish, topic_name, payload_len, &mut self.write_buffer)?;
+						for payload_chunk in payload_chunks.iter() {
+							self.buffer_payload(payload_chunk.clone());
+						}
+					},
+
+					None => {
+						self.codec.encode_publish_header(&publish, topic_name, publish.payload.len(), &mut self.write_buffer)?;
+						self.buffer_payload(publish.payload);
+					},
+				}
+			},
+
+			(item, _) =>
+				if let Some(payload) = self.codec.encode_without_payload(item, &mut self.write_buffer)? {
+					self.buffer_payload(payload);
+				},
+		}
+
+		self.unflushed_packets += 1;
+
+		Ok(futures::AsyncSink::Ready)
 	}
 
 	fn poll_complete(&mut self) -> futures::Poll<(), Self::SinkError> {
-		self.0.poll_complete()
+		while self.write_len() > 0 {
+			let n = futures::try_ready!(tokio_io::AsyncWrite::write_buf(&mut self.io, &mut WriteBuf {
+				chunks: &mut self.write_chunks,
+				buffer: &mut self.write_buffer,
+			}));
+
+			if n == 0 {
+				return Err(std::io::Error::new(std::io::ErrorKind::WriteZero, "failed to write packet to transport").into());
+			}
+
+			self.metrics.bytes_sent(n);
+		}
+
+		futures::try_ready!(tokio_io::AsyncWrite::poll_flush(&mut self.io));
+
+		if self.unflushed_packets > 0 {
+			self.metrics.flushed(self.unflushed_packets);
+			self.unflushed_packets = 0;
+		}
+		self.flush_timer = None;
+
+		Ok(futures::Async::Ready(()))
+	}
+}
+
+/// A packet that is waiting to be sent by a [`LoggingFramed`]
+#[derive(Debug)]
+pub(crate) struct OutgoingPacket {
+	pub(crate) packet: crate::proto::Packet,
+
+	/// If set, the topic name of the PUBLISH packet is written from this instead of from the packet, whose topic name is empty.
+	/// This lets the client send and retransmit a publication without copying its topic name into a new `String` every time.
+	pub(crate) topic_name: Option<crate::topic::TopicName>,
+
+	/// If set, the payload of the PUBLISH packet is written from these chunks instead of from the packet, whose payload is empty
+	pub(crate) payload_chunks: Option<std::sync::Arc<[bytes::Bytes]>>,
+}
+
+impl From<crate::proto::Packet> for OutgoingPacket {
+	fn from(packet: crate::proto::Packet) -> Self {
+		OutgoingPacket { packet, topic_name: None, payload_chunks: None }
+	}
+}
+
+/// Something that was received by a [`LoggingFramed`], see [`LoggingFramed::poll_received`]
+#[derive(Debug)]
+pub(crate) enum Received {
+	Packet(crate::proto::Packet),
+
+	/// A PUBLISH packet, with an empty payload, whose actual payload of `payload_len` bytes is received next as `PayloadChunk`s
+	StreamedPublish { publish: crate::proto::Publish, payload_len: usize },
+
+	PayloadChunk(bytes::Bytes),
+}
+
+/// When the client writes the packets that it sends to the connection.
+///
+/// Writing packets as soon as they're sent keeps their latency low, but costs a write to the connection, ie a syscall for a TCP connection,
+/// every time the client is polled. Holding them back lets more packets share a write.
+///
+/// Regardless of the policy, packets are written once about 8 KiB of them are waiting, and the CONNECT packet is always written immediately.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FlushPolicy {
+	/// The packets are written at the end of every poll of the client that sent any.
+	Immediate,
+
+	/// The packets are written once this many of them are waiting, or once the first of them has been waiting for `max_delay`.
+	AfterPackets { packets: usize, max_delay: std::time::Duration },
+
+	/// The packets are written once the first of them has been waiting for this long.
+	AfterDelay(std::time::Duration),
+}
+
+impl Default for FlushPolicy {
+	fn default() -> Self {
+		FlushPolicy::Immediate
+	}
+}
+
+/// The format that packets are written in when they are logged at the trace level
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PacketLogFormat {
+	/// Packets are written with their `Debug` impl, prefixed with `>>>` for packets that are sent and `<<<` for packets that are received.
+	/// Payloads are written according to the [`PayloadLogging`].
+	Debug,
+
+	/// Packets are written as JSON objects, so that log pipelines can index them, like
+	///
+	/// ```json
+	/// {"direction":"sent","type":"PUBLISH","packet_identifier":1,"topic":"topic1","qos":1,"dup":false,"retain":false,"payload_len":3}
+	/// ```
+	///
+	/// Every object has `direction` and `type` fields. The other fields are only present for the packets that have them.
+	/// Payloads are never written, only their length.
+	Json,
+}
+
+impl Default for PacketLogFormat {
+	fn default() -> Self {
+		PacketLogFormat::Debug
 	}
 }
 
-impl<T> futures::Stream for LoggingFramed<T> where T: tokio_io::AsyncRead + tokio_io::AsyncWrite {
-	type Item = <tokio_codec::Framed<T, crate::proto::PacketCodec> as futures::Stream>::Item;
-	type Error = <tokio_codec::Framed<T, crate::proto::PacketCodec> as futures::Stream>::Error;
+/// Formats a packet as a JSON object for the log. See [`PacketLogFormat::Json`].
+struct PacketJson<'a>(&'a crate::proto::Packet, crate::capture::Direction);
 
-	fn poll(&mut self) -> futures::Poll<Option<Self::Item>, Self::Error> {
-		let result = self.0.poll()?;
-		if let futures::Async::Ready(Some(item)) = &result {
-			log::trace!("<<< {:?}", item);
+impl std::fmt::Display for PacketJson<'_> {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		let direction = match self.1 {
+			crate::capture::Direction::Sent => "sent",
+			crate::capture::Direction::Received => "received",
+		};
+		write!(f, r#"{{"direction":"{}","type":"{}""#, direction, self.0.type_name())?;
+
+		match self.0 {
+			crate::proto::Packet::ConnAck(crate::proto::ConnAck { session_present, return_code }) =>
+				write!(f, r#","session_present":{},"return_code":{}"#, session_present, u8::from(*return_code))?,
+
+			crate::proto::Packet::Publish(crate::proto::Publish { packet_identifier_dup_qos, retain, topic_name, payload }) => {
+				let (packet_identifier, qos, dup) = match packet_identifier_dup_qos {
+					crate::proto::PacketIdentifierDupQoS::AtMostOnce => (None, 0, false),
+					crate::proto::PacketIdentifierDupQoS::AtLeastOnce(packet_identifier, dup) => (Some(packet_identifier), 1, *dup),
+					crate::proto::PacketIdentifierDupQoS::ExactlyOnce(packet_identifier, dup) => (Some(packet_identifier), 2, *dup),
+				};
+				if let Some(packet_identifier) = packet_identifier {
+					write!(f, r#","packet_identifier":{}"#, packet_identifier.get())?;
+				}
+				f.write_str(r#","topic":"#)?;
+				write_json_string(f, topic_name)?;
+				write!(f, r#","qos":{},"dup":{},"retain":{},"payload_len":{}"#, qos, dup, retain, payload.len())?;
+			},
+
+			crate::proto::Packet::PubAck(crate::proto::PubAck { packet_identifier }) |
+			crate::proto::Packet::PubComp(crate::proto::PubComp { packet_identifier }) |
+			crate::proto::Packet::PubRec(crate::proto::PubRec { packet_identifier }) |
+			crate::proto::Packet::PubRel(crate::proto::PubRel { packet_identifier }) |
+			crate::proto::Packet::SubAck(crate::proto::SubAck { packet_identifier, .. }) |
+			crate::proto::Packet::Subscribe(crate::proto::Subscribe { packet_identifier, .. }) |
+			crate::proto::Packet::UnsubAck(crate::proto::UnsubAck { packet_identifier }) |
+			crate::proto::Packet::Unsubscribe(crate::proto::Unsubscribe { packet_identifier, .. }) =>
+				write!(f, r#","packet_identifier":{}"#, packet_identifier.get())?,
+
+			crate::proto::Packet::Connect(_) |
+			crate::proto::Packet::Disconnect(_) |
+			crate::proto::Packet::PingReq(_) |
+			crate::proto::Packet::PingResp(_) => (),
+		}
+
+		f.write_str("}")
+	}
+}
+
+fn write_json_string(f: &mut std::fmt::Formatter<'_>, s: &str) -> std::fmt::Result {
+	use std::fmt::Write;
+
+	f.write_char('"')?;
+	for c in s.chars() {
+		match c {
+			'"' => f.write_str("\\\"")?,
+			'\\' => f.write_str("\\\\")?,
+			c if c < ' ' => write!(f, "\\u{:04x}", u32::from(c))?,
+			c => f.write_char(c)?,
+		}
+	}
+	f.write_char('"')
+}
+
+/// How the payloads of PUBLISH packets and of wills are written when packets are logged at the trace level
+///
+/// Payloads can contain credentials or personal data that must not end up in logs.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PayloadLogging {
+	/// The payload is written as a byte string. If `max_len` is set, only that many bytes are written, followed by the payload's length.
+	Debug { max_len: Option<usize> },
+
+	/// The payload is written as hex. If `max_len` is set, only that many bytes are written, followed by the payload's length.
+	Hex { max_len: Option<usize> },
+
+	/// Only the payload's length is written.
+	Redacted,
+}
+
+impl Default for PayloadLogging {
+	fn default() -> Self {
+		PayloadLogging::Debug { max_len: None }
+	}
+}
+
+/// Formats a packet for the log, with its payload written according to a [`PayloadLogging`]
+struct PacketLog<'a>(&'a crate::proto::Packet, PayloadLogging);
+
+impl std::fmt::Debug for PacketLog<'_> {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self.0 {
+			crate::proto::Packet::Connect(connect) =>
+				f.debug_tuple("Connect").field(&ConnectLog(connect, self.1)).finish(),
+
+			crate::proto::Packet::Publish(publish) =>
+				f.debug_tuple("Publish").field(&PublishLog(publish, self.1)).finish(),
+
+			packet => packet.fmt(f),
+		}
+	}
+}
+
+/// Like the `Debug` impl of [`crate::proto::Connect`], with the payload of the will written according to a [`PayloadLogging`]
+struct ConnectLog<'a>(&'a crate::proto::Connect, PayloadLogging);
+
+impl std::fmt::Debug for ConnectLog<'_> {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("Connect")
+			.field("username", &self.0.username)
+			.field("will", &self.0.will.as_ref().map(|will| PublicationLog(will, self.1)))
+			.field("client_id", &self.0.client_id)
+			.field("keep_alive", &self.0.keep_alive)
+			.finish()
+	}
+}
+
+struct PublicationLog<'a>(&'a crate::proto::Publication, PayloadLogging);
+
+impl std::fmt::Debug for PublicationLog<'_> {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("Publication")
+			.field("topic_name", &self.0.topic_name)
+			.field("qos", &self.0.qos)
+			.field("retain", &self.0.retain)
+			.field("payload", &PayloadLog(&self.0.payload, self.1))
+			.finish()
+	}
+}
+
+struct PublishLog<'a>(&'a crate::proto::Publish, PayloadLogging);
+
+impl std::fmt::Debug for PublishLog<'_> {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("Publish")
+			.field("packet_identifier_dup_qos", &self.0.packet_identifier_dup_qos)
+			.field("retain", &self.0.retain)
+			.field("topic_name", &self.0.topic_name)
+			.field("payload", &PayloadLog(&self.0.payload, self.1))
+			.finish()
+	}
+}
+
+struct PayloadLog<'a>(&'a [u8], PayloadLogging);
+
+impl std::fmt::Debug for PayloadLog<'_> {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		let max_len = match self.1 {
+			PayloadLogging::Debug { max_len } |
+			PayloadLogging::Hex { max_len } => max_len,
+			PayloadLogging::Redacted => Some(0),
+		};
+
+		let (written, truncated) = match max_len {
+			Some(max_len) if self.0.len() > max_len => (&self.0[..max_len], true),
+			_ => (self.0, false),
+		};
+
+		match self.1 {
+			PayloadLogging::Debug { .. } => write!(f, "b\"{}\"", written.escape_ascii())?,
+
+			PayloadLogging::Hex { .. } =>
+				for b in written {
+					write!(f, "{:02x}", b)?;
+				},
+
+			PayloadLogging::Redacted => (),
+		}
+
+		match (self.1, truncated) {
+			(PayloadLogging::Redacted, _) => write!(f, "<{} bytes>", self.0.len()),
+			(_, true) => write!(f, "... <{} bytes>", self.0.len()),
+			(_, false) => Ok(()),
+		}
+	}
+}
+
+/// A [`bytes::Buf`] over the chunks and buffer of a [`LoggingFramed`] that are waiting to be written
+struct WriteBuf<'a> {
+	chunks: &'a mut std::collections::VecDeque<bytes::Bytes>,
+	buffer: &'a mut bytes::BytesMut,
+}
+
+impl bytes::Buf for WriteBuf<'_> {
+	fn remaining(&self) -> usize {
+		self.chunks.iter().map(bytes::Bytes::len).sum::<usize>() + self.buffer.len()
+	}
+
+	fn bytes(&self) -> &[u8] {
+		self.chunks.iter().find(|chunk| !chunk.is_empty()).map_or(&self.buffer[..], |chunk| &chunk[..])
+	}
+
+	fn bytes_vec<'a>(&'a self, dst: &mut [&'a iovec::IoVec]) -> usize {
+		let mut n = 0;
+
+		let chunks = self.chunks.iter().map(|chunk| &chunk[..]).chain(std::iter::once(&self.buffer[..]));
+		for (dst, chunk) in dst.iter_mut().zip(chunks.filter(|chunk| !chunk.is_empty())) {
+			*dst = chunk.into();
+			n += 1;
+		}
+
+		n
+	}
+
+	fn advance(&mut self, mut cnt: usize) {
+		while let Some(chunk) = self.chunks.front_mut() {
+			if cnt < chunk.len() {
+				chunk.advance(cnt);
+				return;
+			}
+
+			cnt -= chunk.len();
+			let _ = self.chunks.pop_front();
 		}
-		Ok(result)
+
+		self.buffer.advance(cnt);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	/// An I/O object that accepts at most `max_write_len` bytes per write, and never has anything to read
+	struct PartialWrites {
+		written: Vec<u8>,
+		max_write_len: usize,
+	}
+
+	impl std::io::Read for PartialWrites {
+		fn read(&mut self, _: &mut [u8]) -> std::io::Result<usize> {
+			Err(std::io::ErrorKind::WouldBlock.into())
+		}
+	}
+
+	impl tokio_io::AsyncRead for PartialWrites {
+	}
+
+	impl std::io::Write for PartialWrites {
+		fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+			let len = std::cmp::min(buf.len(), self.max_write_len);
+			self.written.extend_from_slice(&buf[..len]);
+			Ok(len)
+		}
+
+		fn flush(&mut self) -> std::io::Result<()> {
+			Ok(())
+		}
+	}
+
+	impl tokio_io::AsyncWrite for PartialWrites {
+		fn shutdown(&mut self) -> futures::Poll<(), std::io::Error> {
+			Ok(futures::Async::Ready(()))
+		}
+	}
+
+	#[test]
+	fn payload_logging() {
+		let publish = crate::proto::Packet::Publish(crate::proto::Publish {
+			packet_identifier_dup_qos: crate::proto::PacketIdentifierDupQoS::AtMostOnce,
+			retain: false,
+			topic_name: "topic1".to_owned(),
+			payload: b"secret\x01"[..].into(),
+		});
+
+		for &(payload_logging, expected_payload) in &[
+			(super::PayloadLogging::Debug { max_len: None }, r#"b"secret\x01""#),
+			(super::PayloadLogging::Debug { max_len: Some(3) }, r#"b"sec"... <7 bytes>"#),
+			(super::PayloadLogging::Hex { max_len: None }, "73656372657401"),
+			(super::PayloadLogging::Hex { max_len: Some(3) }, "736563... <7 bytes>"),
+			(super::PayloadLogging::Redacted, "<7 bytes>"),
+		] {
+			assert_eq!(
+				format!("{:?}", super::PacketLog(&publish, payload_logging)),
+				format!("Publish(Publish {{ packet_identifier_dup_qos: AtMostOnce, retain: false, topic_name: \"topic1\", payload: {} }})", expected_payload),
+			);
+		}
+
+		let connect = crate::proto::Packet::Connect(crate::proto::Connect {
+			username: None,
+			password: Some("password".to_owned()),
+			will: Some(crate::proto::Publication {
+				topic_name: "will".parse().unwrap(),
+				qos: crate::proto::QoS::AtMostOnce,
+				retain: false,
+				payload: b"secret"[..].into(),
+			}),
+			client_id: crate::proto::ClientId::ServerGenerated,
+			keep_alive: std::time::Duration::from_secs(4),
+		});
+		let connect_log = format!("{:?}", super::PacketLog(&connect, super::PayloadLogging::Redacted));
+		assert!(connect_log.contains("payload: <6 bytes>"), "{}", connect_log);
+		assert!(!connect_log.contains("secret"), "{}", connect_log);
+		assert!(!connect_log.contains("password"), "{}", connect_log);
+	}
+
+	#[test]
+	fn packet_json() {
+		for (packet, direction, expected) in vec![
+			(
+				crate::proto::Packet::Publish(crate::proto::Publish {
+					packet_identifier_dup_qos: crate::proto::PacketIdentifierDupQoS::ExactlyOnce(crate::proto::PacketIdentifier::new(3).unwrap(), true),
+					retain: true,
+					topic_name: "topic\"1\"\n".to_owned(),
+					payload: b"secret"[..].into(),
+				}),
+				crate::capture::Direction::Sent,
+				r#"{"direction":"sent","type":"PUBLISH","packet_identifier":3,"topic":"topic\"1\"\u000a","qos":2,"dup":true,"retain":true,"payload_len":6}"#,
+			),
+			(
+				crate::proto::Packet::Publish(crate::proto::Publish {
+					packet_identifier_dup_qos: crate::proto::PacketIdentifierDupQoS::AtMostOnce,
+					retain: false,
+					topic_name: "topic1".to_owned(),
+					payload: Default::default(),
+				}),
+				crate::capture::Direction::Received,
+				r#"{"direction":"received","type":"PUBLISH","topic":"topic1","qos":0,"dup":false,"retain":false,"payload_len":0}"#,
+			),
+			(
+				crate::proto::Packet::PubAck(crate::proto::PubAck { packet_identifier: crate::proto::PacketIdentifier::new(3).unwrap() }),
+				crate::capture::Direction::Received,
+				r#"{"direction":"received","type":"PUBACK","packet_identifier":3}"#,
+			),
+			(
+				crate::proto::Packet::ConnAck(crate::proto::ConnAck { session_present: true, return_code: crate::proto::ConnectReturnCode::Accepted }),
+				crate::capture::Direction::Received,
+				r#"{"direction":"received","type":"CONNACK","session_present":true,"return_code":0}"#,
+			),
+			(
+				crate::proto::Packet::PingReq(crate::proto::PingReq),
+				crate::capture::Direction::Sent,
+				r#"{"direction":"sent","type":"PINGREQ"}"#,
+			),
+		] {
+			assert_eq!(super::PacketJson(&packet, direction).to_string(), expected);
+		}
+	}
+
+	#[test]
+	fn writes_packets_in_order() {
+		use futures::Sink;
+		use tokio_codec::Decoder;
+
+		let packets = vec![
+			crate::proto::Packet::PingReq(crate::proto::PingReq),
+			crate::proto::Packet::Publish(crate::proto::Publish {
+				packet_identifier_dup_qos: crate::proto::PacketIdentifierDupQoS::AtMostOnce,
+				retain: false,
+				topic_name: "topic1".to_owned(),
+				payload: vec![0x01; 3][..].into(),
+			}),
+			crate::proto::Packet::Publish(crate::proto::Publish {
+				packet_identifier_dup_qos: crate::proto::PacketIdentifierDupQoS::AtLeastOnce(crate::proto::PacketIdentifier::new(1).unwrap(), false),
+				retain: true,
+				topic_name: "topic2".to_owned(),
+				payload: vec![0x02; super::VECTORED_WRITE_MIN_PAYLOAD_LEN][..].into(),
+			}),
+			crate::proto::Packet::PingReq(crate::proto::PingReq),
+		];
+
+		let mut framed = super::LoggingFramed::new(PartialWrites { written: vec![], max_write_len: 1000 }, Default::default(), Default::default());
+		for packet in packets.clone() {
+			match framed.start_send(packet.into()).unwrap() {
+				futures::AsyncSink::Ready => (),
+				futures::AsyncSink::NotReady(packet) => panic!("could not send packet {:?}", packet),
+			}
+		}
+
+		// The first PINGREQ, the first PUBLISH and the header of the second PUBLISH, then the payload of the second PUBLISH, then the second PINGREQ
+		{
+			use bytes::Buf;
+
+			let write_buf = super::WriteBuf { chunks: &mut framed.write_chunks, buffer: &mut framed.write_buffer };
+			let dummy: &iovec::IoVec = (&[0_u8][..]).into();
+			let mut iovecs = [dummy; 4];
+			assert_eq!(write_buf.bytes_vec(&mut iovecs), 3);
+			assert_eq!(&iovecs[1][..], &[0x02; super::VECTORED_WRITE_MIN_PAYLOAD_LEN][..]);
+		}
+
+		while framed.poll_complete().unwrap().is_not_ready() {
+		}
+
+		let mut written = bytes::BytesMut::from(std::mem::take(&mut framed.io.written));
+		let mut codec: crate::proto::PacketCodec = Default::default();
+		for packet in packets {
+			assert_eq!(codec.decode(&mut written).unwrap(), Some(packet));
+		}
+		assert!(written.is_empty());
+	}
+
+	#[test]
+	fn writes_and_reads_streamed_payloads() {
+		use futures::Sink;
+
+		let payload_chunks: Vec<bytes::Bytes> = vec![vec![0x01; 3].into(), vec![0x02; super::VECTORED_WRITE_MIN_PAYLOAD_LEN].into(), vec![0x03; 5].into()];
+		let publish = |payload: bytes::Bytes| crate::proto::Publish {
+			packet_identifier_dup_qos: crate::proto::PacketIdentifierDupQoS::AtLeastOnce(crate::proto::PacketIdentifier::new(1).unwrap(), false),
+			retain: false,
+			topic_name: "topic1".to_owned(),
+			payload,
+		};
+
+		let mut framed = super::LoggingFramed::new(PartialWrites { written: vec![], max_write_len: 1000 }, Default::default(), Default::default());
+		let packet = super::OutgoingPacket {
+			packet: crate::proto::Packet::Publish(publish(Default::default())),
+			topic_name: None,
+			payload_chunks: Some(payload_chunks.clone().into()),
+		};
+		match framed.start_send(packet).unwrap() {
+			futures::AsyncSink::Ready => (),
+			futures::AsyncSink::NotReady(packet) => panic!("could not send packet {:?}", packet),
+		}
+
+		while framed.poll_complete().unwrap().is_not_ready() {
+		}
+
+		// The header is decoded as soon as it has been received, without waiting for the payload
+		let mut written = bytes::BytesMut::from(std::mem::take(&mut framed.io.written));
+		let payload = written.split_off(written.len() - super::VECTORED_WRITE_MIN_PAYLOAD_LEN - 8);
+		let mut codec: crate::proto::PacketCodec = Default::default();
+		match codec.decode_streaming(&mut written, 1024).unwrap() {
+			Some(crate::proto::Decoded::PublishHeader { publish: header, payload_len }) => {
+				assert_eq!(header, publish(Default::default()));
+				assert_eq!(payload_len, payload_chunks.iter().map(bytes::Bytes::len).sum::<usize>());
+			},
+			decoded => panic!("expected PUBLISH header but got {:?}", decoded),
+		}
+
+		// The rest of the payload is what's left
+		written.unsplit(payload);
+		assert_eq!(&written[..], &payload_chunks.concat()[..]);
+	}
+
+	#[test]
+	fn writes_shared_topic_names() {
+		use futures::Sink;
+		use tokio_codec::Decoder;
+
+		let publish = |topic_name: &str| crate::proto::Publish {
+			packet_identifier_dup_qos: crate::proto::PacketIdentifierDupQoS::AtLeastOnce(crate::proto::PacketIdentifier::new(1).unwrap(), false),
+			retain: false,
+			topic_name: topic_name.to_owned(),
+			payload: [0x01, 0x02, 0x03][..].into(),
+		};
+
+		for (topic_prefix, expected_topic_name) in vec![(None, "topic1"), (Some("ns/"), "ns/topic1")] {
+			let mut framed = super::LoggingFramed::new(PartialWrites { written: vec![], max_write_len: 1000 }, Default::default(), Default::default());
+			framed.set_topic_prefix(topic_prefix.map(|topic_prefix: &str| topic_prefix.parse().unwrap()));
+
+			let packet = super::OutgoingPacket {
+				packet: crate::proto::Packet::Publish(publish("")),
+				topic_name: Some("topic1".parse().unwrap()),
+				payload_chunks: None,
+			};
+			match framed.start_send(packet).unwrap() {
+				futures::AsyncSink::Ready => (),
+				futures::AsyncSink::NotReady(packet) => panic!("could not send packet {:?}", packet),
+			}
+
+			while framed.poll_complete().unwrap().is_not_ready() {
+			}
+
+			let mut written = bytes::BytesMut::from(std::mem::take(&mut framed.io.written));
+			let mut codec: crate::proto::PacketCodec = Default::default();
+			assert_eq!(codec.decode(&mut written).unwrap(), Some(crate::proto::Packet::Publish(publish(expected_topic_name))));
+			assert!(written.is_empty());
+		}
+	}
+
+	#[test]
+	fn topic_prefix_goes_after_share_name() {
+		use futures::Sink;
+		use tokio_codec::Decoder;
+
+		let mut framed = super::LoggingFramed::new(PartialWrites { written: vec![], max_write_len: 1000 }, Default::default(), Default::default());
+		framed.set_topic_prefix(Some("ns/".parse().unwrap()));
+
+		let packets = vec![
+			crate::proto::Packet::Subscribe(crate::proto::Subscribe {
+				packet_identifier: crate::proto::PacketIdentifier::new(1).unwrap(),
+				subscribe_to: vec![
+					crate::proto::SubscribeTo::shared("group1", "sensors/+", crate::proto::QoS::AtLeastOnce).unwrap(),
+					crate::proto::SubscribeTo { topic_filter: "$SYS/#".parse().unwrap(), qos: crate::proto::QoS::AtMostOnce },
+				],
+			}),
+			crate::proto::Packet::Unsubscribe(crate::proto::Unsubscribe {
+				packet_identifier: crate::proto::PacketIdentifier::new(2).unwrap(),
+				unsubscribe_from: vec!["$share/group1/sensors/+".to_owned(), "$SYS/#".to_owned()],
+			}),
+		];
+		for packet in packets {
+			match framed.start_send(packet.into()).unwrap() {
+				futures::AsyncSink::Ready => (),
+				futures::AsyncSink::NotReady(packet) => panic!("could not send packet {:?}", packet),
+			}
+		}
+
+		while framed.poll_complete().unwrap().is_not_ready() {
+		}
+
+		let mut written = bytes::BytesMut::from(std::mem::take(&mut framed.io.written));
+		let mut codec: crate::proto::PacketCodec = Default::default();
+		assert_eq!(codec.decode(&mut written).unwrap(), Some(crate::proto::Packet::Subscribe(crate::proto::Subscribe {
+			packet_identifier: crate::proto::PacketIdentifier::new(1).unwrap(),
+			subscribe_to: vec![
+				crate::proto::SubscribeTo { topic_filter: "$share/group1/ns/sensors/+".parse().unwrap(), qos: crate::proto::QoS::AtLeastOnce },
+				crate::proto::SubscribeTo { topic_filter: "$SYS/#".parse().unwrap(), qos: crate::proto::QoS::AtMostOnce },
+			],
+		})));
+		assert_eq!(codec.decode(&mut written).unwrap(), Some(crate::proto::Packet::Unsubscribe(crate::proto::Unsubscribe {
+			packet_identifier: crate::proto::PacketIdentifier::new(2).unwrap(),
+			unsubscribe_from: vec!["$share/group1/ns/sensors/+".to_owned(), "$SYS/#".to_owned()],
+		})));
+		assert!(written.is_empty());
 	}
 }
@@ -0,0 +1,199 @@
+/*!
+ * A [`MetricsSink`] that a [`Client`](crate::Client) reports its health to, so that it can be wired into existing monitoring.
+ */
+
+#[cfg(feature = "prometheus")]
+pub mod prometheus;
+
+/// Receives metrics from a [`Client`](crate::Client) as they happen.
+///
+/// Set a sink with [`Client::set_metrics_sink`](crate::Client::set_metrics_sink). Every method has an empty default implementation,
+/// so a sink only needs to implement the ones it is interested in.
+///
+/// The methods are called by the task that polls the client, while it is being polled, so they should be cheap, like updating an atomic counter.
+pub trait MetricsSink {
+	/// The client is about to send the given packet to the server.
+	fn packet_sent(&self, _packet: &crate::proto::Packet) {
+	}
+
+	/// The client received the given packet from the server.
+	fn packet_received(&self, _packet: &crate::proto::Packet) {
+	}
+
+	/// The client wrote this many bytes to the connection.
+	fn bytes_sent(&self, _len: usize) {
+	}
+
+	/// The client read this many bytes from the connection.
+	fn bytes_received(&self, _len: usize) {
+	}
+
+	/// The client flushed the connection after writing this many packets to it, according to its [`FlushPolicy`](crate::FlushPolicy).
+	fn flushed(&self, _packets: usize) {
+	}
+
+	/// The client established a new connection to the server, like [`Event::NewConnection`](crate::Event::NewConnection).
+	fn connected(&self, _reset_session: bool) {
+	}
+
+	/// The client lost its connection to the server because of the given error, and will reconnect.
+	fn connection_lost(&self, _err: &crate::Error) {
+	}
+
+	/// The client ignored the given packet from the server because it does not correspond to any packet that the client sent,
+	/// like a PUBACK for a PUBLISH that the client never sent, or a PUBREL for a PUBREC that it never sent.
+	///
+	/// Such packets indicate a protocol error in the server, or a session that the server and the client disagree about.
+	fn packet_ignored(&self, _packet: &crate::proto::Packet) {
+	}
+
+	/// A publication received from the server was dropped because the buffer for publications received while the client is paused was full.
+	///
+	/// See [`Client::set_paused_publications_limit`](crate::Client::set_paused_publications_limit).
+	fn publication_dropped(&self, _publication: &crate::ReceivedPublication) {
+	}
+
+	/// The number of publications that have been sent to the server and are waiting for it to ack them.
+	///
+	/// This gauge is reported every time the client is polled while it is connected.
+	fn publications_in_flight(&self, _count: usize) {
+	}
+
+	/// The number of publications that have been given to the client and are waiting to be sent to the server.
+	///
+	/// This gauge is reported every time the client is polled while it is connected.
+	fn publications_queued(&self, _count: usize) {
+	}
+}
+
+impl<M> MetricsSink for std::sync::Arc<M> where M: MetricsSink + ?Sized {
+	fn packet_sent(&self, packet: &crate::proto::Packet) {
+		(**self).packet_sent(packet);
+	}
+
+	fn packet_received(&self, packet: &crate::proto::Packet) {
+		(**self).packet_received(packet);
+	}
+
+	fn bytes_sent(&self, len: usize) {
+		(**self).bytes_sent(len);
+	}
+
+	fn bytes_received(&self, len: usize) {
+		(**self).bytes_received(len);
+	}
+
+	fn flushed(&self, packets: usize) {
+		(**self).flushed(packets);
+	}
+
+	fn connected(&self, reset_session: bool) {
+		(**self).connected(reset_session);
+	}
+
+	fn connection_lost(&self, err: &crate::Error) {
+		(**self).connection_lost(err);
+	}
+
+	fn packet_ignored(&self, packet: &crate::proto::Packet) {
+		(**self).packet_ignored(packet);
+	}
+
+	fn publication_dropped(&self, publication: &crate::ReceivedPublication) {
+		(**self).publication_dropped(publication);
+	}
+
+	fn publications_in_flight(&self, count: usize) {
+		(**self).publications_in_flight(count);
+	}
+
+	fn publications_queued(&self, count: usize) {
+		(**self).publications_queued(count);
+	}
+}
+
+/// The [`MetricsSink`] of a client, if it has one, shared between the client and its connections
+#[derive(Clone, Default)]
+pub(crate) struct Metrics(Option<std::sync::Arc<dyn MetricsSink + Send + Sync>>);
+
+impl Metrics {
+	pub(crate) fn new(metrics_sink: std::sync::Arc<dyn MetricsSink + Send + Sync>) -> Self {
+		Metrics(Some(metrics_sink))
+	}
+
+	pub(crate) fn is_enabled(&self) -> bool {
+		self.0.is_some()
+	}
+
+	pub(crate) fn packet_sent(&self, packet: &crate::proto::Packet) {
+		if let Some(metrics_sink) = &self.0 {
+			metrics_sink.packet_sent(packet);
+		}
+	}
+
+	pub(crate) fn packet_received(&self, packet: &crate::proto::Packet) {
+		if let Some(metrics_sink) = &self.0 {
+			metrics_sink.packet_received(packet);
+		}
+	}
+
+	pub(crate) fn bytes_sent(&self, len: usize) {
+		if let Some(metrics_sink) = &self.0 {
+			metrics_sink.bytes_sent(len);
+		}
+	}
+
+	pub(crate) fn bytes_received(&self, len: usize) {
+		if let Some(metrics_sink) = &self.0 {
+			metrics_sink.bytes_received(len);
+		}
+	}
+
+	pub(crate) fn flushed(&self, packets: usize) {
+		if let Some(metrics_sink) = &self.0 {
+			metrics_sink.flushed(packets);
+		}
+	}
+
+	pub(crate) fn connected(&self, reset_session: bool) {
+		if let Some(metrics_sink) = &self.0 {
+			metrics_sink.connected(reset_session);
+		}
+	}
+
+	pub(crate) fn connection_lost(&self, err: &crate::Error) {
+		if let Some(metrics_sink) = &self.0 {
+			metrics_sink.connection_lost(err);
+		}
+	}
+
+	pub(crate) fn packet_ignored(&self, packet: &crate::proto::Packet) {
+		if let Some(metrics_sink) = &self.0 {
+			metrics_sink.packet_ignored(packet);
+		}
+	}
+
+	pub(crate) fn publication_dropped(&self, publication: &crate::ReceivedPublication) {
+		if let Some(metrics_sink) = &self.0 {
+			metrics_sink.publication_dropped(publication);
+		}
+	}
+
+	pub(crate) fn publications_in_flight(&self, count: usize) {
+		if let Some(metrics_sink) = &self.0 {
+			metrics_sink.publications_in_flight(count);
+		}
+	}
+
+	pub(crate) fn publications_queued(&self, count: usize) {
+		if let Some(metrics_sink) = &self.0 {
+			metrics_sink.publications_queued(count);
+		}
+	}
+}
+
+impl std::fmt::Debug for Metrics {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_tuple("Metrics").field(&self.0.is_some()).finish()
+	}
+}
@@ -0,0 +1,168 @@
+/*!
+ * A [`MetricsSink`](super::MetricsSink) that records the metrics of a [`Client`](crate::Client) as Prometheus metrics.
+ *
+ * This module is only available with the `prometheus` feature.
+ */
+
+/// A [`MetricsSink`](super::MetricsSink) that records the metrics of a [`Client`](crate::Client) in a Prometheus registry.
+///
+/// The sink registers these metrics, each with a `client_id` label:
+///
+/// - `mqtt_client_publish_latency_seconds`: A histogram of the time between an `AtLeastOnce` or `ExactlyOnce` publication first being sent to the server
+///   and the server acking it with a PUBACK or PUBCOMP.
+/// - `mqtt_client_reconnects_total`: The number of times the client lost its connection to the server and reconnected.
+/// - `mqtt_client_dropped_publications_total`: The number of received publications that were dropped while the client was paused.
+/// - `mqtt_client_ignored_packets_total`: The number of packets from the server that the client ignored because they did not correspond to any packet that it sent.
+///
+/// Since the metrics are labeled with the client ID, the sinks of multiple clients can register their metrics with the same registry.
+#[derive(Debug)]
+pub struct PrometheusMetricsSink {
+	publish_latency: ::prometheus::Histogram,
+	reconnects: ::prometheus::IntCounter,
+	dropped_publications: ::prometheus::IntCounter,
+	ignored_packets: ::prometheus::IntCounter,
+
+	/// The time that each publication waiting to be acked was first sent, keyed by its packet identifier
+	publish_times: std::sync::Mutex<std::collections::BTreeMap<crate::proto::PacketIdentifier, std::time::Instant>>,
+}
+
+impl PrometheusMetricsSink {
+	/// Creates a sink for the client with the given ID, and registers its metrics with the given registry.
+	pub fn new(registry: &::prometheus::Registry, client_id: &str) -> Result<Self, ::prometheus::Error> {
+		let const_labels: std::collections::HashMap<_, _> = std::iter::once(("client_id".to_owned(), client_id.to_owned())).collect();
+
+		let publish_latency = ::prometheus::Histogram::with_opts(
+			::prometheus::HistogramOpts::new(
+				"mqtt_client_publish_latency_seconds",
+				"The time between a QoS 1 or 2 publication being sent to the server and the server acking it",
+			)
+			.const_labels(const_labels.clone()),
+		)?;
+		registry.register(Box::new(publish_latency.clone()))?;
+
+		let reconnects = ::prometheus::IntCounter::with_opts(
+			::prometheus::Opts::new("mqtt_client_reconnects_total", "The number of times the client lost its connection to the server and reconnected")
+			.const_labels(const_labels.clone()),
+		)?;
+		registry.register(Box::new(reconnects.clone()))?;
+
+		let dropped_publications = ::prometheus::IntCounter::with_opts(
+			::prometheus::Opts::new("mqtt_client_dropped_publications_total", "The number of received publications that were dropped while the client was paused")
+			.const_labels(const_labels.clone()),
+		)?;
+		registry.register(Box::new(dropped_publications.clone()))?;
+
+		let ignored_packets = ::prometheus::IntCounter::with_opts(
+			::prometheus::Opts::new(
+				"mqtt_client_ignored_packets_total",
+				"The number of packets from the server that the client ignored because they did not correspond to any packet that it sent",
+			)
+			.const_labels(const_labels),
+		)?;
+		registry.register(Box::new(ignored_packets.clone()))?;
+
+		Ok(PrometheusMetricsSink {
+			publish_latency,
+			reconnects,
+			dropped_publications,
+			ignored_packets,
+
+			publish_times: Default::default(),
+		})
+	}
+
+	fn lock_publish_times(&self) -> std::sync::MutexGuard<'_, std::collections::BTreeMap<crate::proto::PacketIdentifier, std::time::Instant>> {
+		// The map only holds plain values that can't be left in an inconsistent state, so a poisoned lock is still usable.
+		self.publish_times.lock().unwrap_or_else(std::sync::PoisonError::into_inner)
+	}
+}
+
+impl super::MetricsSink for PrometheusMetricsSink {
+	fn packet_sent(&self, packet: &crate::proto::Packet) {
+		if let crate::proto::Packet::Publish(crate::proto::Publish { packet_identifier_dup_qos, .. }) = packet {
+			match packet_identifier_dup_qos {
+				crate::proto::PacketIdentifierDupQoS::AtMostOnce => (),
+
+				crate::proto::PacketIdentifierDupQoS::AtLeastOnce(packet_identifier, _) |
+				crate::proto::PacketIdentifierDupQoS::ExactlyOnce(packet_identifier, _) => {
+					// Retransmissions keep the time that the publication was first sent
+					let _ = self.lock_publish_times().entry(*packet_identifier).or_insert_with(tokio_timer::clock::now);
+				},
+			}
+		}
+	}
+
+	fn packet_received(&self, packet: &crate::proto::Packet) {
+		let packet_identifier = match packet {
+			crate::proto::Packet::PubAck(crate::proto::PubAck { packet_identifier }) |
+			crate::proto::Packet::PubComp(crate::proto::PubComp { packet_identifier }) => packet_identifier,
+			_ => return,
+		};
+
+		if let Some(publish_time) = self.lock_publish_times().remove(packet_identifier) {
+			let latency = tokio_timer::clock::now() - publish_time;
+			self.publish_latency.observe(latency.as_secs_f64());
+		}
+	}
+
+	fn connection_lost(&self, _err: &crate::Error) {
+		self.reconnects.inc();
+	}
+
+	fn packet_ignored(&self, _packet: &crate::proto::Packet) {
+		self.ignored_packets.inc();
+	}
+
+	fn publication_dropped(&self, _publication: &crate::ReceivedPublication) {
+		self.dropped_publications.inc();
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	#[test]
+	fn records_metrics() {
+		use crate::metrics::MetricsSink;
+
+		let registry = ::prometheus::Registry::new();
+		let sink = super::PrometheusMetricsSink::new(&registry, "client1").unwrap();
+
+		let packet_identifier = crate::proto::PacketIdentifier::new(1).unwrap();
+		for &dup in &[false, true] {
+			sink.packet_sent(&crate::proto::Packet::Publish(crate::proto::Publish {
+				packet_identifier_dup_qos: crate::proto::PacketIdentifierDupQoS::AtLeastOnce(packet_identifier, dup),
+				retain: false,
+				topic_name: "topic1".to_owned(),
+				payload: Default::default(),
+			}));
+		}
+		sink.packet_received(&crate::proto::Packet::PubAck(crate::proto::PubAck { packet_identifier }));
+		sink.packet_received(&crate::proto::Packet::PubAck(crate::proto::PubAck { packet_identifier }));
+		assert_eq!(sink.publish_latency.get_sample_count(), 1);
+
+		sink.connection_lost(&crate::Error::ServerClosedConnection);
+		assert_eq!(sink.reconnects.get(), 1);
+
+		sink.publication_dropped(&crate::ReceivedPublication {
+			topic_name: "topic1".to_owned(),
+			dup: false,
+			qos: crate::proto::QoS::AtMostOnce,
+			retain: false,
+			payload: Default::default(),
+		});
+		assert_eq!(sink.dropped_publications.get(), 1);
+
+		sink.packet_ignored(&crate::proto::Packet::PubAck(crate::proto::PubAck { packet_identifier }));
+		assert_eq!(sink.ignored_packets.get(), 1);
+
+		// A sink for another client can share the registry
+		let _ = super::PrometheusMetricsSink::new(&registry, "client2").unwrap();
+		assert!(super::PrometheusMetricsSink::new(&registry, "client1").is_err());
+
+		let metric_families = registry.gather();
+		assert_eq!(metric_families.len(), 4);
+		for metric_family in metric_families {
+			assert_eq!(metric_family.get_metric().len(), 2);
+		}
+	}
+}
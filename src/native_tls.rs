@@ -0,0 +1,165 @@
+/*!
+ * An [`IoSource`](crate::IoSource) that connects to the server over TLS, using the platform's TLS implementation through native-tls.
+ *
+ * This is an alternative to the rustls-based [`tls`](crate::tls) module, for environments that require the operating system's
+ * certificate store or a FIPS-validated TLS stack: Schannel on Windows, Secure Transport on macOS and iOS, and OpenSSL elsewhere.
+ *
+ * The TLS connection runs on top of the connections of another `IoSource`, like a TCP connection. Root certificates, client certificates,
+ * protocol versions, ALPN protocols and whether SNI is sent are configured on the [`native_tls::TlsConnector`] that is given to
+ * [`NativeTlsIoSource::with_connector`].
+ *
+ * native-tls does not expose TLS session resumption, so whether reconnections resume a previous session depends on the platform's
+ * implementation; with OpenSSL they always make a full handshake. Use the `tls` module for reconnections to resume sessions everywhere.
+ *
+ * This module is only available with the `native-tls` feature.
+ */
+
+use futures::Future;
+
+/// Connects to an MQTT server over TLS with native-tls, on top of the connections of another [`IoSource`](crate::IoSource).
+pub struct NativeTlsIoSource<S> {
+	io_source: S,
+	domain: String,
+	connector: native_tls::TlsConnector,
+}
+
+impl<S> NativeTlsIoSource<S> {
+	/// Creates an `IoSource` that connects to the server with the given domain, like `broker.example.com`, over the connections of the given `io_source`.
+	///
+	/// The server's certificate is verified against the platform's root certificates.
+	pub fn new(io_source: S, domain: String) -> std::io::Result<Self> {
+		let connector = native_tls::TlsConnector::new().map_err(std::io::Error::other)?;
+		Ok(NativeTlsIoSource::with_connector(io_source, domain, connector))
+	}
+
+	/// Creates an `IoSource` that connects to the server with the given domain over the connections of the given `io_source`,
+	/// with the given connector.
+	pub fn with_connector(io_source: S, domain: String, connector: native_tls::TlsConnector) -> Self {
+		NativeTlsIoSource {
+			io_source,
+			domain,
+			connector,
+		}
+	}
+}
+
+impl<S> crate::IoSource for NativeTlsIoSource<S>
+where
+	S: crate::IoSource,
+	S::Future: Future<Error = std::io::Error>,
+{
+	type Io = NativeTlsIo<S::Io>;
+	type Future = NativeTlsConnectFuture<S::Future, S::Io>;
+
+	fn connect(&mut self) -> Self::Future {
+		NativeTlsConnectFuture(ConnectState::Connecting(self.io_source.connect(), Some((self.connector.clone(), self.domain.clone()))))
+	}
+}
+
+impl<S> std::fmt::Debug for NativeTlsIoSource<S> where S: std::fmt::Debug {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("NativeTlsIoSource")
+			.field("io_source", &self.io_source)
+			.field("domain", &self.domain)
+			.finish_non_exhaustive()
+	}
+}
+
+/// The connection future of a [`NativeTlsIoSource`]
+pub struct NativeTlsConnectFuture<F, I>(ConnectState<F, I>);
+
+enum ConnectState<F, I> {
+	Connecting(F, Option<(native_tls::TlsConnector, String)>),
+	Handshaking(Option<native_tls::MidHandshakeTlsStream<I>>, Option<String>),
+}
+
+impl<F, I> Future for NativeTlsConnectFuture<F, I>
+where
+	F: Future<Item = (I, Option<String>), Error = std::io::Error>,
+	I: std::io::Read + std::io::Write,
+{
+	type Item = (NativeTlsIo<I>, Option<String>);
+	type Error = std::io::Error;
+
+	fn poll(&mut self) -> futures::Poll<Self::Item, Self::Error> {
+		let handshake_result = match &mut self.0 {
+			ConnectState::Connecting(inner, connector) => {
+				let (io, password) = futures::try_ready!(inner.poll());
+				let (connector, domain) = connector.take().expect("polled after completion");
+				(connector.connect(&domain, io), password)
+			},
+
+			ConnectState::Handshaking(handshake, password) => {
+				let handshake = handshake.take().expect("polled after completion");
+				(handshake.handshake(), password.take())
+			},
+		};
+
+		match handshake_result {
+			(Ok(stream), password) => {
+				log::debug!("TLS handshake succeeded");
+				Ok(futures::Async::Ready((NativeTlsIo(stream), password)))
+			},
+
+			// The handshake is waiting for the inner connection, which has registered the current task to be notified.
+			(Err(native_tls::HandshakeError::WouldBlock(handshake)), password) => {
+				self.0 = ConnectState::Handshaking(Some(handshake), password);
+				Ok(futures::Async::NotReady)
+			},
+
+			(Err(native_tls::HandshakeError::Failure(err)), _) => Err(std::io::Error::other(err)),
+		}
+	}
+}
+
+impl<F, I> std::fmt::Debug for NativeTlsConnectFuture<F, I> {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		let state = match &self.0 {
+			ConnectState::Connecting(..) => "Connecting",
+			ConnectState::Handshaking(..) => "Handshaking",
+		};
+		f.debug_struct("NativeTlsConnectFuture").field("state", &state).finish()
+	}
+}
+
+/// A connection of a [`NativeTlsIoSource`]
+#[derive(Debug)]
+pub struct NativeTlsIo<T>(native_tls::TlsStream<T>);
+
+impl<T> NativeTlsIo<T> {
+	/// The underlying native-tls stream, for example to get the server's certificate or the negotiated ALPN protocol
+	pub fn get_ref(&self) -> &native_tls::TlsStream<T> {
+		&self.0
+	}
+}
+
+impl<T> std::io::Read for NativeTlsIo<T> where T: std::io::Read + std::io::Write {
+	fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+		self.0.read(buf)
+	}
+}
+
+impl<T> tokio_io::AsyncRead for NativeTlsIo<T> where T: tokio_io::AsyncRead + std::io::Write {
+}
+
+impl<T> std::io::Write for NativeTlsIo<T> where T: std::io::Read + std::io::Write {
+	fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+		self.0.write(buf)
+	}
+
+	fn flush(&mut self) -> std::io::Result<()> {
+		self.0.flush()
+	}
+}
+
+impl<T> tokio_io::AsyncWrite for NativeTlsIo<T> where T: std::io::Read + tokio_io::AsyncWrite {
+	fn shutdown(&mut self) -> futures::Poll<(), std::io::Error> {
+		match self.0.shutdown() {
+			Ok(()) => (),
+			Err(ref err) if err.kind() == std::io::ErrorKind::WouldBlock => return Ok(futures::Async::NotReady),
+			Err(err) => return Err(err),
+		}
+
+		self.0.get_mut().shutdown()
+	}
+}
@@ -0,0 +1,13 @@
+/// Percent-encodes everything in the given string except the unreserved characters of RFC 3986
+pub(crate) fn encode(s: &str) -> String {
+	use std::fmt::Write;
+
+	let mut encoded = String::with_capacity(s.len());
+	for b in s.bytes() {
+		match b {
+			b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => encoded.push(char::from(b)),
+			b => write!(encoded, "%{:02X}", b).expect("writing to a String cannot fail"),
+		}
+	}
+	encoded
+}
@@ -0,0 +1,262 @@
+/*!
+ * A [`ClientPool`] of several clients with distinct client IDs, for workloads whose throughput exceeds what a single connection can deliver.
+ *
+ * Each connection has its own window of in-flight publications and its own socket, so spreading publications over several connections
+ * increases the throughput when the round-trip time to the server or a per-connection limit of the server is the bottleneck.
+ *
+ * Publications that are published through the pool with a [`PoolPublishHandle`] are spread over the clients round-robin. So two publications
+ * can reach the server over different connections, and the server can deliver them to subscribers in the opposite order. Publications
+ * that must stay in order, like those of the same topic, should be published through the handle of one client in the pool instead.
+ */
+
+use futures::{ Future, Stream };
+
+/// Several clients whose events are merged into a single stream.
+///
+/// The pool is a [`Stream`] of the events of all its clients, each with the index of the client it came from, and must be polled
+/// for the clients to do anything. Errors of the clients are yielded too, and the pool can continue to be polled after them.
+/// The stream ends once the events of every client have ended.
+pub struct ClientPool<IoS> where IoS: crate::IoSource {
+	clients: Vec<Option<crate::Client<IoS>>>,
+
+	/// The index of the client to poll first, so that one busy client cannot starve the others
+	next_poll: usize,
+}
+
+impl<IoS> ClientPool<IoS> where IoS: crate::IoSource {
+	/// Creates a pool of `size` clients that connect with clones of the given `IoSource`.
+	///
+	/// The clients have the client IDs `{client_id_prefix}-0`, `{client_id_prefix}-1` and so on. The other parameters are the same as
+	/// for [`Client::new`](crate::Client::new), and are used for every client.
+	#[allow(clippy::needless_pass_by_value)] // Taken by value like they are by `Client::new`
+	pub fn new(
+		client_id_prefix: &str,
+		size: usize,
+		username: Option<String>,
+		will: Option<crate::proto::Publication>,
+		io_source: IoS,
+		max_reconnect_back_off: std::time::Duration,
+		keep_alive: std::time::Duration,
+	) -> Self where IoS: Clone {
+		let clients =
+			(0..size)
+			.map(|i| crate::Client::new(
+				Some(format!("{}-{}", client_id_prefix, i)),
+				username.clone(),
+				will.clone(),
+				io_source.clone(),
+				max_reconnect_back_off,
+				keep_alive,
+			))
+			.collect();
+		ClientPool::from_clients(clients)
+	}
+
+	/// Creates a pool of the given clients, for example to configure each of them differently. The clients must have distinct client IDs,
+	/// otherwise the server disconnects one of them every time the other one connects.
+	pub fn from_clients(clients: Vec<crate::Client<IoS>>) -> Self {
+		ClientPool {
+			clients: clients.into_iter().map(Some).collect(),
+			next_poll: 0,
+		}
+	}
+
+	/// The number of clients in the pool, including those whose events have ended
+	pub fn len(&self) -> usize {
+		self.clients.len()
+	}
+
+	/// Returns `true` if the pool has no clients
+	pub fn is_empty(&self) -> bool {
+		self.clients.is_empty()
+	}
+
+	/// The client with the given index, for example to get a handle to it. Returns `None` if the index is out of range,
+	/// or the client's events have ended.
+	pub fn client(&self, index: usize) -> Option<&crate::Client<IoS>> {
+		self.clients.get(index).and_then(Option::as_ref)
+	}
+
+	/// The client with the given index, for example to subscribe with it. Returns `None` if the index is out of range,
+	/// or the client's events have ended.
+	pub fn client_mut(&mut self, index: usize) -> Option<&mut crate::Client<IoS>> {
+		self.clients.get_mut(index).and_then(Option::as_mut)
+	}
+
+	/// Returns a handle that spreads publications over all the clients of the pool.
+	///
+	/// Clients that have been shut down are left out. Fails if that leaves no clients.
+	pub fn publish_handle(&self) -> Result<PoolPublishHandle, crate::PublishError> {
+		let publish_handles: Vec<_> =
+			self.clients.iter()
+			.filter_map(|client| client.as_ref()?.publish_handle().ok())
+			.collect();
+		if publish_handles.is_empty() {
+			return Err(crate::PublishError::ClientDoesNotExist);
+		}
+
+		Ok(PoolPublishHandle {
+			publish_handles,
+			next: 0,
+		})
+	}
+
+	/// Subscribes to a topic with the first client of the pool, so that every matching publication is only received once.
+	///
+	/// To receive publications over several connections, subscribe with each client through [`ClientPool::client_mut`],
+	/// to a shared subscription if the server supports them.
+	pub fn subscribe(&mut self, subscribe_to: crate::proto::SubscribeTo) -> Result<(), crate::UpdateSubscriptionError> {
+		match self.clients.iter_mut().find_map(Option::as_mut) {
+			Some(client) => client.subscribe(subscribe_to),
+			None => Err(crate::UpdateSubscriptionError::ClientDoesNotExist),
+		}
+	}
+}
+
+impl<IoS> Stream for ClientPool<IoS>
+where
+	IoS: crate::IoSource,
+	<<IoS as crate::IoSource>::Future as Future>::Error: std::fmt::Display,
+{
+	type Item = PoolEvent;
+	type Error = PoolError;
+
+	fn poll(&mut self) -> futures::Poll<Option<Self::Item>, Self::Error> {
+		let len = self.clients.len();
+
+		for i in 0..len {
+			let client_index = (self.next_poll + i) % len;
+			let client = match &mut self.clients[client_index] {
+				Some(client) => client,
+				None => continue,
+			};
+
+			match client.poll() {
+				Ok(futures::Async::Ready(Some(event))) => {
+					self.next_poll = (client_index + 1) % len;
+					return Ok(futures::Async::Ready(Some(PoolEvent { client_index, event })));
+				},
+
+				Ok(futures::Async::Ready(None)) => self.clients[client_index] = None,
+
+				Ok(futures::Async::NotReady) => (),
+
+				Err(err) => {
+					self.next_poll = (client_index + 1) % len;
+					return Err(PoolError { client_index, err });
+				},
+			}
+		}
+
+		if self.clients.iter().all(Option::is_none) {
+			Ok(futures::Async::Ready(None))
+		}
+		else {
+			Ok(futures::Async::NotReady)
+		}
+	}
+}
+
+impl<IoS> std::fmt::Debug for ClientPool<IoS> where IoS: crate::IoSource {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("ClientPool")
+			.field("len", &self.clients.len())
+			.field("running", &self.clients.iter().filter(|client| client.is_some()).count())
+			.finish_non_exhaustive()
+	}
+}
+
+/// Publishes publications with the clients of a [`ClientPool`], round-robin.
+///
+/// Use [`ClientPool::publish_handle`] to get one.
+pub struct PoolPublishHandle {
+	publish_handles: Vec<crate::PublishHandle>,
+	next: usize,
+}
+
+impl PoolPublishHandle {
+	/// Checks whether any client of the pool can accept a new publish request from this handle.
+	///
+	/// If this returns `Ready`, the next publication is handed to a client that is ready, even if it is not that client's turn.
+	/// Otherwise the current task is notified when a client becomes ready.
+	pub fn poll_ready(&mut self) -> futures::Poll<(), crate::PublishError> {
+		let len = self.publish_handles.len();
+		let mut failed = 0;
+
+		for i in 0..len {
+			let index = (self.next + i) % len;
+			match self.publish_handles[index].poll_ready() {
+				Ok(futures::Async::Ready(())) => {
+					self.next = index;
+					return Ok(futures::Async::Ready(()));
+				},
+				Ok(futures::Async::NotReady) => (),
+				Err(_) => failed += 1,
+			}
+		}
+
+		if failed == len {
+			// Every client has been shut down
+			Err(crate::PublishError::ClientDoesNotExist)
+		}
+		else {
+			Ok(futures::Async::NotReady)
+		}
+	}
+
+	/// Publishes the given publication with the next client of the pool.
+	pub fn publish(&mut self, publication: crate::proto::Publication) -> crate::PublishFuture {
+		self.next_publish_handle().publish(publication)
+	}
+
+	/// Queues the given publication to be published with the next client of the pool, without waiting for it to be acknowledged.
+	/// See [`PublishHandle::publish_without_ack`](crate::PublishHandle::publish_without_ack).
+	pub fn publish_without_ack(&mut self, publication: crate::proto::Publication) -> Result<(), crate::PublishError> {
+		self.next_publish_handle().publish_without_ack(publication)
+	}
+
+	fn next_publish_handle(&mut self) -> &mut crate::PublishHandle {
+		let index = self.next;
+		self.next = (index + 1) % self.publish_handles.len();
+		&mut self.publish_handles[index]
+	}
+}
+
+impl std::fmt::Debug for PoolPublishHandle {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("PoolPublishHandle")
+			.field("len", &self.publish_handles.len())
+			.field("next", &self.next)
+			.finish()
+	}
+}
+
+/// An event of one of the clients of a [`ClientPool`]
+#[derive(Debug, PartialEq, Eq)]
+pub struct PoolEvent {
+	/// The index of the client in the pool
+	pub client_index: usize,
+
+	pub event: crate::Event,
+}
+
+/// An error of one of the clients of a [`ClientPool`]
+#[derive(Debug)]
+pub struct PoolError {
+	/// The index of the client in the pool
+	pub client_index: usize,
+
+	pub err: crate::Error,
+}
+
+impl std::fmt::Display for PoolError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "client {} of the pool failed: {}", self.client_index, self.err)
+	}
+}
+
+impl std::error::Error for PoolError {
+	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+		Some(&self.err)
+	}
+}
@@ -0,0 +1,294 @@
+/*!
+ * `proptest::arbitrary::Arbitrary` impls for the protocol types.
+ *
+ * Every generated value can be encoded, and decodes back to the same value. So the impls can be used to property-test the codec,
+ * as well as anything that handles packets, like the client's state machine or a server.
+ */
+
+use proptest::prelude::*;
+
+/// Strings that can be encoded, ie that don't contain U+0000. They are kept short, since their length doesn't exercise anything interesting.
+fn string() -> impl Strategy<Value = String> {
+	"[^\0]{0,16}"
+}
+
+fn payload(max_len: usize) -> impl Strategy<Value = bytes::Bytes> {
+	proptest::collection::vec(any::<u8>(), 0..=max_len).prop_map(Into::into)
+}
+
+impl Arbitrary for super::Packet {
+	type Parameters = ();
+	type Strategy = BoxedStrategy<Self>;
+
+	fn arbitrary_with((): Self::Parameters) -> Self::Strategy {
+		prop_oneof![
+			any::<super::ConnAck>().prop_map(super::Packet::ConnAck),
+			any::<super::Connect>().prop_map(super::Packet::Connect),
+			Just(super::Packet::Disconnect(super::Disconnect)),
+			Just(super::Packet::PingReq(super::PingReq)),
+			Just(super::Packet::PingResp(super::PingResp)),
+			any::<super::PubAck>().prop_map(super::Packet::PubAck),
+			any::<super::PubComp>().prop_map(super::Packet::PubComp),
+			any::<super::Publish>().prop_map(super::Packet::Publish),
+			any::<super::PubRec>().prop_map(super::Packet::PubRec),
+			any::<super::PubRel>().prop_map(super::Packet::PubRel),
+			any::<super::SubAck>().prop_map(super::Packet::SubAck),
+			any::<super::Subscribe>().prop_map(super::Packet::Subscribe),
+			any::<super::UnsubAck>().prop_map(super::Packet::UnsubAck),
+			any::<super::Unsubscribe>().prop_map(super::Packet::Unsubscribe),
+		].boxed()
+	}
+}
+
+impl Arbitrary for super::ConnAck {
+	type Parameters = ();
+	type Strategy = BoxedStrategy<Self>;
+
+	fn arbitrary_with((): Self::Parameters) -> Self::Strategy {
+		(any::<bool>(), any::<super::ConnectReturnCode>())
+			.prop_map(|(session_present, return_code)| super::ConnAck { session_present, return_code })
+			.boxed()
+	}
+}
+
+impl Arbitrary for super::Connect {
+	type Parameters = ();
+	type Strategy = BoxedStrategy<Self>;
+
+	fn arbitrary_with((): Self::Parameters) -> Self::Strategy {
+		(
+			proptest::option::of(string()),
+			proptest::option::of(string()),
+			proptest::option::of(any::<super::Publication>()),
+			any::<super::ClientId>(),
+			any::<u16>(),
+		)
+			.prop_map(|(username, password, will, client_id, keep_alive)| super::Connect {
+				username,
+				password,
+				will,
+				client_id,
+				keep_alive: std::time::Duration::from_secs(u64::from(keep_alive)),
+			})
+			.boxed()
+	}
+}
+
+macro_rules! packet_identifier_packets {
+	($($ty:ident ,)*) => {
+		$(
+			impl Arbitrary for super::$ty {
+				type Parameters = ();
+				type Strategy = BoxedStrategy<Self>;
+
+				fn arbitrary_with((): Self::Parameters) -> Self::Strategy {
+					any::<super::PacketIdentifier>().prop_map(|packet_identifier| super::$ty { packet_identifier }).boxed()
+				}
+			}
+		)*
+	};
+}
+
+packet_identifier_packets! {
+	PubAck,
+	PubComp,
+	PubRec,
+	PubRel,
+	UnsubAck,
+}
+
+impl Arbitrary for super::Publish {
+	type Parameters = ();
+	type Strategy = BoxedStrategy<Self>;
+
+	fn arbitrary_with((): Self::Parameters) -> Self::Strategy {
+		(any::<super::PacketIdentifierDupQoS>(), any::<bool>(), any::<crate::topic::TopicName>(), payload(256))
+			.prop_map(|(packet_identifier_dup_qos, retain, topic_name, payload)| super::Publish {
+				packet_identifier_dup_qos,
+				retain,
+				topic_name: topic_name.into_string(),
+				payload,
+			})
+			.boxed()
+	}
+}
+
+impl Arbitrary for super::SubAck {
+	type Parameters = ();
+	type Strategy = BoxedStrategy<Self>;
+
+	fn arbitrary_with((): Self::Parameters) -> Self::Strategy {
+		(any::<super::PacketIdentifier>(), proptest::collection::vec(any::<super::SubAckQos>(), 1..=4))
+			.prop_map(|(packet_identifier, qos)| super::SubAck { packet_identifier, qos })
+			.boxed()
+	}
+}
+
+impl Arbitrary for super::Subscribe {
+	type Parameters = ();
+	type Strategy = BoxedStrategy<Self>;
+
+	fn arbitrary_with((): Self::Parameters) -> Self::Strategy {
+		(any::<super::PacketIdentifier>(), proptest::collection::vec(any::<super::SubscribeTo>(), 1..=4))
+			.prop_map(|(packet_identifier, subscribe_to)| super::Subscribe { packet_identifier, subscribe_to })
+			.boxed()
+	}
+}
+
+impl Arbitrary for super::Unsubscribe {
+	type Parameters = ();
+	type Strategy = BoxedStrategy<Self>;
+
+	fn arbitrary_with((): Self::Parameters) -> Self::Strategy {
+		(any::<super::PacketIdentifier>(), proptest::collection::vec(any::<crate::topic::TopicFilter>(), 1..=4))
+			.prop_map(|(packet_identifier, unsubscribe_from)| super::Unsubscribe {
+				packet_identifier,
+				unsubscribe_from: unsubscribe_from.into_iter().map(crate::topic::TopicFilter::into_string).collect(),
+			})
+			.boxed()
+	}
+}
+
+impl Arbitrary for super::ClientId {
+	type Parameters = ();
+	type Strategy = BoxedStrategy<Self>;
+
+	fn arbitrary_with((): Self::Parameters) -> Self::Strategy {
+		// An empty client ID is decoded as `ServerGenerated`, so the IDs are never empty
+		let id = "[^\0]{1,16}";
+
+		prop_oneof![
+			Just(super::ClientId::ServerGenerated),
+			id.prop_map(super::ClientId::IdWithCleanSession),
+			id.prop_map(super::ClientId::IdWithExistingSession),
+		].boxed()
+	}
+}
+
+impl Arbitrary for super::ConnectReturnCode {
+	type Parameters = ();
+	type Strategy = BoxedStrategy<Self>;
+
+	fn arbitrary_with((): Self::Parameters) -> Self::Strategy {
+		// Every code maps to exactly one value, so this covers all of them including `ConnectionRefusedReason::Other`
+		any::<u8>().prop_map(Into::into).boxed()
+	}
+}
+
+impl Arbitrary for super::PacketIdentifier {
+	type Parameters = ();
+	type Strategy = BoxedStrategy<Self>;
+
+	fn arbitrary_with((): Self::Parameters) -> Self::Strategy {
+		(1..=u16::max_value()).prop_map(super::PacketIdentifier).boxed()
+	}
+}
+
+impl Arbitrary for super::PacketIdentifierDupQoS {
+	type Parameters = ();
+	type Strategy = BoxedStrategy<Self>;
+
+	fn arbitrary_with((): Self::Parameters) -> Self::Strategy {
+		prop_oneof![
+			Just(super::PacketIdentifierDupQoS::AtMostOnce),
+			(any::<super::PacketIdentifier>(), any::<bool>())
+				.prop_map(|(packet_identifier, dup)| super::PacketIdentifierDupQoS::AtLeastOnce(packet_identifier, dup)),
+			(any::<super::PacketIdentifier>(), any::<bool>())
+				.prop_map(|(packet_identifier, dup)| super::PacketIdentifierDupQoS::ExactlyOnce(packet_identifier, dup)),
+		].boxed()
+	}
+}
+
+impl Arbitrary for super::Publication {
+	type Parameters = ();
+	type Strategy = BoxedStrategy<Self>;
+
+	fn arbitrary_with((): Self::Parameters) -> Self::Strategy {
+		(any::<crate::topic::TopicName>(), any::<super::QoS>(), any::<bool>(), payload(256))
+			.prop_map(|(topic_name, qos, retain, payload)| super::Publication { topic_name, qos, retain, payload })
+			.boxed()
+	}
+}
+
+impl Arbitrary for super::QoS {
+	type Parameters = ();
+	type Strategy = BoxedStrategy<Self>;
+
+	fn arbitrary_with((): Self::Parameters) -> Self::Strategy {
+		prop_oneof![
+			Just(super::QoS::AtMostOnce),
+			Just(super::QoS::AtLeastOnce),
+			Just(super::QoS::ExactlyOnce),
+		].boxed()
+	}
+}
+
+impl Arbitrary for super::SubAckQos {
+	type Parameters = ();
+	type Strategy = BoxedStrategy<Self>;
+
+	fn arbitrary_with((): Self::Parameters) -> Self::Strategy {
+		prop_oneof![
+			any::<super::QoS>().prop_map(super::SubAckQos::Success),
+			Just(super::SubAckQos::Failure),
+		].boxed()
+	}
+}
+
+impl Arbitrary for super::SubscribeTo {
+	type Parameters = ();
+	type Strategy = BoxedStrategy<Self>;
+
+	fn arbitrary_with((): Self::Parameters) -> Self::Strategy {
+		(any::<crate::topic::TopicFilter>(), any::<super::QoS>())
+			.prop_map(|(topic_filter, qos)| super::SubscribeTo { topic_filter, qos })
+			.boxed()
+	}
+}
+
+impl Arbitrary for crate::topic::TopicName {
+	type Parameters = ();
+	type Strategy = BoxedStrategy<Self>;
+
+	fn arbitrary_with((): Self::Parameters) -> Self::Strategy {
+		"[^\0+#]{1,32}".prop_map(crate::topic::TopicName::new_unchecked).boxed()
+	}
+}
+
+impl Arbitrary for crate::topic::TopicFilter {
+	type Parameters = ();
+	type Strategy = BoxedStrategy<Self>;
+
+	fn arbitrary_with((): Self::Parameters) -> Self::Strategy {
+		let level = prop_oneof![
+			"[^\0+#/]{0,8}",
+			Just("+".to_owned()),
+		];
+
+		(proptest::collection::vec(level, 1..=4), any::<bool>())
+			.prop_filter_map("topic filter is not valid", |(mut levels, multi_level_wildcard)| {
+				if multi_level_wildcard {
+					levels.push("#".to_owned());
+				}
+				crate::topic::TopicFilter::new(levels.join("/")).ok()
+			})
+			.boxed()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use proptest::prelude::*;
+
+	proptest! {
+		#[test]
+		fn packet_roundtrip(packet: crate::proto::Packet) {
+			let mut bytes = vec![];
+			crate::proto::encode(&packet, &mut bytes).unwrap();
+
+			let (decoded, len) = crate::proto::decode(&bytes).unwrap().unwrap();
+			prop_assert_eq!(decoded, packet);
+			prop_assert_eq!(len, bytes.len());
+		}
+	}
+}
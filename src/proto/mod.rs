@@ -1,8 +1,21 @@
 /*!
  * MQTT protocol types.
+ *
+ * This module is a supported API in its own right. Besides being used by the [`Client`](crate::Client), it can be used to build servers, proxies
+ * and protocol analyzers on top of the same codec. Every [`Packet`] can be constructed from its public fields, and is encoded and decoded with
+ * [`PacketCodec`].
+ *
+ * The codec only checks what the protocol requires of a well-formed packet. Protocol-level rules, like the order in which packets may be sent
+ * or which packets are valid from a client versus a server, are left to the user of the codec.
+ *
+ * With the `proptest` feature, the packet types implement `proptest::arbitrary::Arbitrary`, for property-testing code that handles packets.
+ * Every generated packet can be encoded and decodes back to the same packet.
  */
 
-use bytes::{ Buf, BufMut, IntoBuf };
+use bytes::BufMut;
+
+#[cfg(feature = "proptest")]
+mod arbitrary;
 
 mod packet;
 
@@ -32,7 +45,51 @@ pub use self::packet::{
 	SubscribeTo,
 };
 
-pub(crate) use self::packet::PacketMeta;
+pub(crate) use self::packet::{ Decoded, PacketMeta };
+
+/// Encodes the given packet and appends it to `dst`.
+///
+/// This is the same encoding as [`PacketCodec`] uses, for callers that don't use tokio, like CLI tools and tests.
+pub fn encode(packet: &Packet, dst: &mut Vec<u8>) -> Result<(), EncodeError> {
+	self::packet::encode(packet, dst)
+}
+
+/// Decodes the first packet in `src`.
+///
+/// Returns the packet and the number of bytes of `src` that it used, or `None` if `src` does not contain a complete packet yet.
+/// Strings are validated strictly and packets of any size are accepted. Use a [`PacketCodec`] to change these.
+///
+/// This is the same decoding as [`PacketCodec`] uses, for callers that don't use tokio, like CLI tools and replay utilities.
+pub fn decode(src: &[u8]) -> Result<Option<(Packet, usize)>, DecodeError> {
+	use tokio_codec::Decoder;
+
+	// Decode the fixed header first so that only the bytes of this packet are copied
+	let (_, remaining_length, fixed_header_len) = match decode_fixed_header(src)? {
+		Some(fixed_header) => fixed_header,
+		None => return Ok(None),
+	};
+
+	let len = fixed_header_len + remaining_length;
+	if src.len() < len {
+		return Ok(None);
+	}
+
+	let mut packet_bytes: bytes::BytesMut = src[..len].into();
+	let packet = PacketCodec::default().decode(&mut packet_bytes)?;
+	Ok(packet.map(|packet| (packet, len)))
+}
+
+/// Decodes the packet at the start of `src`, ignoring any bytes after it.
+///
+/// Unlike [`decode`], a `src` that does not contain a complete packet is an error ([`DecodeError::IncompletePacket`]), so there is only one
+/// way for malformed input to fail. This is meant for fuzzing the decoder: it never panics, whatever the contents of `src`.
+/// Strings are validated strictly and packets of any size are accepted.
+pub fn decode_packet(src: &[u8]) -> Result<Packet, DecodeError> {
+	match decode(src)? {
+		Some((packet, _)) => Ok(packet),
+		None => Err(DecodeError::IncompletePacket),
+	}
+}
 
 /// The client ID
 ///
@@ -133,10 +190,8 @@ impl tokio_codec::Decoder for Utf8StringDecoder {
 						return Ok(None);
 					}
 
-					let s = match std::str::from_utf8(&src.split_to(*len)) {
-						Ok(s) => s.to_string(),
-						Err(err) => return Err(DecodeError::StringNotUtf8(err)),
-					};
+					let s = validate_utf8_str(&src[..*len], StringValidation::Strict)?;
+					src.advance(*len);
 					*self = Utf8StringDecoder::Empty;
 					return Ok(Some(s));
 				},
@@ -145,7 +200,61 @@ impl tokio_codec::Decoder for Utf8StringDecoder {
 	}
 }
 
+/// How strictly the UTF-8 strings in decoded packets are validated
+///
+/// Ref: 1.5.3 UTF-8 encoded strings
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum StringValidation {
+	/// Strings must be valid UTF-8 and must not contain U+0000. This is what the specification requires.
+	///
+	/// Since Rust strings cannot contain surrogates, UTF-8 encodings of surrogates are rejected as invalid UTF-8.
+	Strict,
+
+	/// Invalid UTF-8 sequences are replaced with U+FFFD and U+0000 is allowed.
+	///
+	/// This is for interoperating with servers that do not validate the strings that they forward, like the topic names of publications.
+	/// Note that topic filters are still validated as a [`TopicFilter`](crate::topic::TopicFilter).
+	Lenient,
+}
+
+impl Default for StringValidation {
+	fn default() -> Self {
+		StringValidation::Strict
+	}
+}
+
+/// Decodes an MQTT-format string from the body of a packet, which has been received completely.
+fn decode_utf8_str(src: &mut bytes::BytesMut, string_validation: StringValidation) -> Result<String, DecodeError> {
+	let len = usize::from(src.try_get_u16_be()?);
+	if src.len() < len {
+		return Err(DecodeError::IncompletePacket);
+	}
+
+	let s = validate_utf8_str(&src[..len], string_validation)?;
+	src.advance(len);
+	Ok(s)
+}
+
+fn validate_utf8_str(src: &[u8], string_validation: StringValidation) -> Result<String, DecodeError> {
+	match string_validation {
+		StringValidation::Strict => {
+			let s = std::str::from_utf8(src).map_err(DecodeError::StringNotUtf8)?;
+			if s.contains('\0') {
+				return Err(DecodeError::StringContainsNul);
+			}
+
+			Ok(s.to_owned())
+		},
+
+		StringValidation::Lenient => Ok(String::from_utf8_lossy(src).into_owned()),
+	}
+}
+
 fn encode_utf8_str<B>(item: &str, dst: &mut B) -> Result<(), EncodeError> where B: ByteBuf {
+	if item.contains('\0') {
+		return Err(EncodeError::StringContainsNul(item.to_owned()));
+	}
+
 	#[allow(clippy::cast_possible_truncation)]
 	dst.put_u16_be_bytes(match item.len() {
 		len if len <= usize::from(u16::max_value()) => len as u16,
@@ -204,6 +313,34 @@ impl tokio_codec::Decoder for RemainingLengthDecoder {
 	}
 }
 
+/// Decodes the fixed header at the start of `src` in place, without consuming it.
+///
+/// Returns the first byte, the remaining length and the length of the fixed header, or `None` if `src` does not contain the whole fixed header yet.
+///
+/// Ref: 2.2 Fixed header
+fn decode_fixed_header(src: &[u8]) -> Result<Option<(u8, usize, usize)>, DecodeError> {
+	let first_byte = match src.first() {
+		Some(&first_byte) => first_byte,
+		None => return Ok(None),
+	};
+
+	let mut remaining_length = 0;
+	for (i, &encoded_byte) in src[1..].iter().take(4).enumerate() {
+		remaining_length |= usize::from(encoded_byte & 0x7F) << (i * 7);
+
+		if encoded_byte & 0x80 == 0 {
+			return Ok(Some((first_byte, remaining_length, 1 + i + 1)));
+		}
+	}
+
+	if src.len() < 1 + 4 {
+		Ok(None)
+	}
+	else {
+		Err(DecodeError::RemainingLengthTooHigh)
+	}
+}
+
 pub(crate) fn encode_remaining_length<B>(mut item: usize, dst: &mut B) -> Result<(), EncodeError> where B: ByteBuf {
 	dst.reserve_bytes(4 * std::mem::size_of::<u8>());
 
@@ -287,10 +424,13 @@ impl std::ops::AddAssign<u16> for PacketIdentifier {
 pub enum DecodeError {
 	ConnectReservedSet,
 	IncompletePacket,
+	InvalidTopic(crate::topic::TopicError),
 	Io(std::io::Error),
+	PacketTooLarge { remaining_length: usize, max: usize },
 	PublishDupAtMostOnce,
 	NoTopics,
 	RemainingLengthTooHigh,
+	StringContainsNul,
 	StringNotUtf8(std::str::Utf8Error),
 	UnrecognizedConnAckFlags(u8),
 	UnrecognizedPacket { packet_type: u8, flags: u8, remaining_length: usize },
@@ -305,10 +445,14 @@ impl std::fmt::Display for DecodeError {
 		match self {
 			DecodeError::ConnectReservedSet => write!(f, "the reserved byte of the CONNECT flags is set"),
 			DecodeError::IncompletePacket => write!(f, "packet is truncated"),
+			DecodeError::InvalidTopic(err) => err.fmt(f),
 			DecodeError::Io(err) => write!(f, "I/O error: {}", err),
 			DecodeError::NoTopics => write!(f, "expected at least one topic but there were none"),
+			DecodeError::PacketTooLarge { remaining_length, max } =>
+				write!(f, "packet has remaining length {} which is larger than the maximum of {}", remaining_length, max),
 			DecodeError::PublishDupAtMostOnce => write!(f, "PUBLISH packet has DUP flag set and QoS 0"),
 			DecodeError::RemainingLengthTooHigh => write!(f, "remaining length is too high to be decoded"),
+			DecodeError::StringContainsNul => write!(f, "string contains U+0000"),
 			DecodeError::StringNotUtf8(err) => err.fmt(f),
 			DecodeError::UnrecognizedConnAckFlags(flags) => write!(f, "could not parse CONNACK flags 0x{:02X}", flags),
 			DecodeError::UnrecognizedPacket { packet_type, flags, remaining_length } =>
@@ -333,10 +477,13 @@ impl std::error::Error for DecodeError {
 		match self {
 			DecodeError::ConnectReservedSet => None,
 			DecodeError::IncompletePacket => None,
+			DecodeError::InvalidTopic(err) => Some(err),
 			DecodeError::Io(err) => Some(err),
 			DecodeError::NoTopics => None,
+			DecodeError::PacketTooLarge { .. } => None,
 			DecodeError::PublishDupAtMostOnce => None,
 			DecodeError::RemainingLengthTooHigh => None,
+			DecodeError::StringContainsNul => None,
 			DecodeError::StringNotUtf8(err) => Some(err),
 			DecodeError::UnrecognizedConnAckFlags(_) => None,
 			DecodeError::UnrecognizedPacket { .. } => None,
@@ -359,6 +506,7 @@ pub enum EncodeError {
 	Io(std::io::Error),
 	KeepAliveTooHigh(std::time::Duration),
 	RemainingLengthTooHigh(usize),
+	StringContainsNul(String),
 	StringTooLarge(usize),
 	WillTooLarge(usize),
 }
@@ -370,6 +518,7 @@ impl EncodeError {
 			EncodeError::Io(_) => false,
 			EncodeError::KeepAliveTooHigh(_) => true,
 			EncodeError::RemainingLengthTooHigh(_) => true,
+			EncodeError::StringContainsNul(_) => true,
 			EncodeError::StringTooLarge(_) => true,
 			EncodeError::WillTooLarge(_) => true,
 		}
@@ -382,6 +531,7 @@ impl std::fmt::Display for EncodeError {
 			EncodeError::Io(err) => write!(f, "I/O error: {}", err),
 			EncodeError::KeepAliveTooHigh(keep_alive) => write!(f, "keep-alive {:?} is too high", keep_alive),
 			EncodeError::RemainingLengthTooHigh(len) => write!(f, "remaining length {} is too high to be encoded", len),
+			EncodeError::StringContainsNul(s) => write!(f, "string {:?} contains U+0000", s),
 			EncodeError::StringTooLarge(len) => write!(f, "string of length {} is too large to be encoded", len),
 			EncodeError::WillTooLarge(len) => write!(f, "will payload of length {} is too large to be encoded", len),
 		}
@@ -395,6 +545,7 @@ impl std::error::Error for EncodeError {
 			EncodeError::Io(err) => Some(err),
 			EncodeError::KeepAliveTooHigh(_) => None,
 			EncodeError::RemainingLengthTooHigh(_) => None,
+			EncodeError::StringContainsNul(_) => None,
 			EncodeError::StringTooLarge(_) => None,
 			EncodeError::WillTooLarge(_) => None,
 		}
@@ -407,6 +558,32 @@ impl From<std::io::Error> for EncodeError {
 	}
 }
 
+/// Errors from creating a shared subscription with [`SubscribeTo::shared`]
+#[derive(Debug)]
+pub enum SharedSubscriptionError {
+	InvalidShareName(String),
+	InvalidTopicFilter(crate::topic::TopicError),
+}
+
+impl std::fmt::Display for SharedSubscriptionError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			SharedSubscriptionError::InvalidShareName(share_name) =>
+				write!(f, "share name {:?} is invalid; it must not be empty or contain '/', '+' or '#'", share_name),
+			SharedSubscriptionError::InvalidTopicFilter(err) => write!(f, "topic filter of shared subscription is invalid: {}", err),
+		}
+	}
+}
+
+impl std::error::Error for SharedSubscriptionError {
+	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+		match self {
+			SharedSubscriptionError::InvalidShareName(_) => None,
+			SharedSubscriptionError::InvalidTopicFilter(err) => Some(err),
+		}
+	}
+}
+
 pub(crate) trait ByteBuf {
 	fn reserve_bytes(&mut self, additional: usize);
 
@@ -439,6 +616,24 @@ impl ByteBuf for bytes::BytesMut {
 	}
 }
 
+impl ByteBuf for Vec<u8> {
+	fn reserve_bytes(&mut self, additional: usize) {
+		self.reserve(additional);
+	}
+
+	fn put_u8_bytes(&mut self, n: u8) {
+		self.push(n);
+	}
+
+	fn put_u16_be_bytes(&mut self, n: u16) {
+		self.extend_from_slice(&n.to_be_bytes());
+	}
+
+	fn put_slice_bytes(&mut self, src: &[u8]) {
+		self.extend_from_slice(src);
+	}
+}
+
 pub(crate) struct ByteCounter(pub(crate) usize);
 
 impl ByteCounter {
@@ -481,7 +676,8 @@ impl BufMutExt for bytes::BytesMut {
 	}
 
 	fn get_packet_identifier(&mut self) -> Result<PacketIdentifier, DecodeError> {
-		let packet_identifier = self.split_to(std::mem::size_of::<u16>()).into_buf().get_u16_be();
+		let packet_identifier = u16::from_be_bytes([self[0], self[1]]);
+		self.advance(std::mem::size_of::<u16>());
 		PacketIdentifier::new(packet_identifier).ok_or(DecodeError::ZeroPacketIdentifier)
 	}
 
@@ -500,7 +696,9 @@ impl BufMutExt for bytes::BytesMut {
 			return Err(DecodeError::IncompletePacket);
 		}
 
-		Ok(self.split_to(std::mem::size_of::<u16>()).into_buf().get_u16_be())
+		let result = u16::from_be_bytes([self[0], self[1]]);
+		self.advance(std::mem::size_of::<u16>());
+		Ok(result)
 	}
 
 	fn try_get_packet_identifier(&mut self) -> Result<PacketIdentifier, DecodeError> {
@@ -584,6 +782,9 @@ mod tests {
 	fn remaining_length_decode_inner_ok(bytes: &[u8], expected: usize) {
 		use tokio_codec::Decoder;
 
+		let fixed_header = [&[0x30][..], bytes].concat();
+		assert_eq!(super::decode_fixed_header(&fixed_header).unwrap(), Some((0x30, expected, fixed_header.len())));
+
 		let mut bytes = bytes::BytesMut::from(bytes);
 		let actual = super::RemainingLengthDecoder::default().decode(&mut bytes).unwrap().unwrap();
 		assert_eq!(actual, expected);
@@ -593,6 +794,11 @@ mod tests {
 	fn remaining_length_decode_inner_too_high(bytes: &[u8]) {
 		use tokio_codec::Decoder;
 
+		match super::decode_fixed_header(&[&[0x30][..], bytes].concat()) {
+			Err(super::DecodeError::RemainingLengthTooHigh) => (),
+			result => panic!("expected RemainingLengthTooHigh but got {:?}", result),
+		}
+
 		let mut bytes = bytes::BytesMut::from(bytes);
 		let err = super::RemainingLengthDecoder::default().decode(&mut bytes).unwrap_err();
 		if let super::DecodeError::RemainingLengthTooHigh = err {
@@ -605,7 +811,225 @@ mod tests {
 	fn remaining_length_decode_inner_incomplete_packet(bytes: &[u8]) {
 		use tokio_codec::Decoder;
 
+		assert_eq!(super::decode_fixed_header(&[&[0x30][..], bytes].concat()).unwrap(), None);
+
 		let mut bytes = bytes::BytesMut::from(bytes);
 		assert_eq!(super::RemainingLengthDecoder::default().decode(&mut bytes).unwrap(), None);
 	}
+
+	#[test]
+	fn packet_codec_consumes_nothing_until_packet_is_complete() {
+		use tokio_codec::{ Decoder, Encoder };
+
+		let packet = super::Packet::Publish(super::Publish {
+			packet_identifier_dup_qos: super::PacketIdentifierDupQoS::AtLeastOnce(super::PacketIdentifier::new(1).unwrap(), false),
+			retain: false,
+			topic_name: "topic1".to_owned(),
+			payload: vec![0x01; 0x80].into(),
+		});
+		let mut encoded = bytes::BytesMut::new();
+		super::PacketCodec::default().encode(packet.clone(), &mut encoded).unwrap();
+
+		let mut codec: super::PacketCodec = Default::default();
+		let mut bytes = bytes::BytesMut::new();
+		for (i, &b) in encoded.iter().enumerate() {
+			bytes.extend_from_slice(&[b]);
+			let result = codec.decode(&mut bytes).unwrap();
+			if i + 1 < encoded.len() {
+				assert_eq!(result, None);
+				assert_eq!(bytes.len(), i + 1);
+			}
+			else {
+				assert_eq!(result, Some(packet.clone()));
+				assert!(bytes.is_empty());
+			}
+		}
+	}
+
+	#[test]
+	fn max_incoming_packet_size() {
+		use tokio_codec::Decoder;
+
+		// PUBLISH packet with remaining length 0x80, of which only the fixed header has been received
+		let mut bytes = bytes::BytesMut::from(&[0x30, 0x80, 0x01][..]);
+		match super::PacketCodec::new(Some(0x7F)).decode(&mut bytes) {
+			Err(super::DecodeError::PacketTooLarge { remaining_length: 0x80, max: 0x7F }) => (),
+			result => panic!("expected PacketTooLarge but got {:?}", result),
+		}
+
+		let mut bytes = bytes::BytesMut::from(&[0x30, 0x80, 0x01][..]);
+		assert!(super::PacketCodec::new(Some(0x80)).decode(&mut bytes).unwrap().is_none());
+
+		let mut bytes = bytes::BytesMut::from(&[0x30, 0x80, 0x01][..]);
+		assert!(super::PacketCodec::default().decode(&mut bytes).unwrap().is_none());
+	}
+
+	#[test]
+	fn string_validation() {
+		use tokio_codec::{ Decoder, Encoder };
+
+		for (topic_name, lenient_topic_name) in &[(&b"a\0b"[..], "a\0b"), (&b"a\xFFb"[..], "a\u{FFFD}b")] {
+			let mut packet = vec![0x30, 0x05, 0x00, 0x03];
+			packet.extend_from_slice(topic_name);
+
+			match super::PacketCodec::default().decode(&mut bytes::BytesMut::from(&packet[..])) {
+				Err(super::DecodeError::StringContainsNul) |
+				Err(super::DecodeError::StringNotUtf8(_)) => (),
+				result => panic!("expected StringContainsNul or StringNotUtf8 but got {:?}", result),
+			}
+
+			let mut codec = super::PacketCodec::default();
+			codec.set_string_validation(super::StringValidation::Lenient);
+			match codec.decode(&mut bytes::BytesMut::from(&packet[..])) {
+				Ok(Some(super::Packet::Publish(super::Publish { topic_name, .. }))) => assert_eq!(topic_name, *lenient_topic_name),
+				result => panic!("expected PUBLISH packet but got {:?}", result),
+			}
+		}
+
+		let packet = super::Packet::Publish(super::Publish {
+			packet_identifier_dup_qos: super::PacketIdentifierDupQoS::AtMostOnce,
+			retain: false,
+			topic_name: "a\0b".to_owned(),
+			payload: Default::default(),
+		});
+		match super::PacketCodec::default().encode(packet, &mut Default::default()) {
+			Err(super::EncodeError::StringContainsNul(_)) => (),
+			result => panic!("expected StringContainsNul but got {:?}", result),
+		}
+	}
+
+	#[test]
+	fn publish_payload_is_not_copied() {
+		use tokio_codec::Decoder;
+
+		let mut packet = vec![0x30, 0x88, 0x08, 0x00, 0x06];
+		packet.extend_from_slice(b"topic1");
+		packet.extend(std::iter::repeat(0x01).take(1024));
+		let mut bytes = bytes::BytesMut::from(packet);
+		let buffer = bytes.as_ptr() as usize .. bytes.as_ptr() as usize + bytes.len();
+
+		match super::PacketCodec::default().decode(&mut bytes) {
+			Ok(Some(super::Packet::Publish(super::Publish { payload, .. }))) => {
+				assert_eq!(payload.len(), 1024);
+				assert!(buffer.contains(&(payload.as_ptr() as usize)));
+			},
+			result => panic!("expected PUBLISH packet but got {:?}", result),
+		}
+	}
+
+	#[test]
+	fn encode_does_not_reallocate_sufficient_buffer() {
+		use tokio_codec::Encoder;
+
+		let mut bytes = bytes::BytesMut::with_capacity(1024);
+		let ptr = bytes.as_ptr();
+
+		let mut codec: super::PacketCodec = Default::default();
+		for _ in 0..10 {
+			codec.encode(super::Packet::Publish(super::Publish {
+				packet_identifier_dup_qos: super::PacketIdentifierDupQoS::AtLeastOnce(super::PacketIdentifier::new(1).unwrap(), false),
+				retain: false,
+				topic_name: "topic1".to_owned(),
+				payload: [0x01; 64][..].into(),
+			}), &mut bytes).unwrap();
+			codec.encode(super::Packet::PubAck(super::PubAck { packet_identifier: super::PacketIdentifier::new(1).unwrap() }), &mut bytes).unwrap();
+		}
+
+		assert_eq!(bytes.len(), 10 * ((2 + 2 + 6 + 2 + 64) + (2 + 2)));
+		assert_eq!(bytes.as_ptr(), ptr);
+	}
+
+	#[test]
+	fn sync_encode_decode() {
+		use tokio_codec::Encoder;
+
+		let packets = vec![
+			super::Packet::PingReq(super::PingReq),
+			super::Packet::Publish(super::Publish {
+				packet_identifier_dup_qos: super::PacketIdentifierDupQoS::AtLeastOnce(super::PacketIdentifier::new(1).unwrap(), false),
+				retain: false,
+				topic_name: "topic1".to_owned(),
+				payload: [0x01; 200][..].into(),
+			}),
+			super::Packet::PubAck(super::PubAck { packet_identifier: super::PacketIdentifier::new(1).unwrap() }),
+		];
+
+		let mut bytes = vec![];
+		let mut codec_bytes = bytes::BytesMut::new();
+		for packet in &packets {
+			super::encode(packet, &mut bytes).unwrap();
+			super::PacketCodec::default().encode(packet.clone(), &mut codec_bytes).unwrap();
+		}
+		assert_eq!(bytes, &codec_bytes[..]);
+
+		// Incomplete packets, including ones that end inside the remaining length
+		assert_eq!(super::decode(&[]).unwrap(), None);
+		assert_eq!(super::decode(&bytes[2..3]).unwrap(), None);
+		assert_eq!(super::decode(&bytes[2..4]).unwrap(), None);
+
+		let mut src = &bytes[..];
+		for packet in packets {
+			let (decoded, len) = super::decode(src).unwrap().unwrap();
+			assert_eq!(decoded, packet);
+			src = &src[len..];
+		}
+		assert!(src.is_empty());
+	}
+
+	#[test]
+	fn decode_packet() {
+		let mut bytes = vec![];
+		super::encode(&super::Packet::Subscribe(super::Subscribe {
+			packet_identifier: super::PacketIdentifier::new(1).unwrap(),
+			subscribe_to: vec![super::SubscribeTo { topic_filter: "topic/+".parse().unwrap(), qos: super::QoS::AtLeastOnce }],
+		}), &mut bytes).unwrap();
+
+		match super::decode_packet(&bytes[..(bytes.len() - 1)]) {
+			Err(super::DecodeError::IncompletePacket) => (),
+			result => panic!("expected IncompletePacket but got {:?}", result),
+		}
+
+		// Malformed input must only ever fail to decode, never panic
+		for first_byte in 0..=u8::max_value() {
+			for second_byte in 0..=u8::max_value() {
+				let _ = super::decode_packet(&[first_byte, second_byte]);
+			}
+		}
+		for i in 0..bytes.len() {
+			for byte in 0..=u8::max_value() {
+				let mut bytes = bytes.clone();
+				bytes[i] = byte;
+				let _ = super::decode_packet(&bytes);
+				let _ = super::decode_packet(&bytes[i..]);
+			}
+		}
+	}
+
+	#[test]
+	fn shared_subscription() {
+		let subscribe_to = super::SubscribeTo::shared("group1", "sport/#", super::QoS::AtLeastOnce).unwrap();
+		assert_eq!(subscribe_to.topic_filter, "$share/group1/sport/#");
+		assert_eq!(crate::topic::parse_shared_subscription(&subscribe_to.topic_filter), Some(("group1", "sport/#")));
+
+		assert_eq!(crate::topic::parse_shared_subscription("sport/#"), None);
+		assert_eq!(crate::topic::parse_shared_subscription("$share/group1"), None);
+		assert_eq!(crate::topic::parse_shared_subscription("$share//sport/#"), None);
+
+		for share_name in &["", "group/1", "group+", "group#"] {
+			match super::SubscribeTo::shared(share_name, "sport/#", super::QoS::AtLeastOnce) {
+				Err(super::SharedSubscriptionError::InvalidShareName(_)) => (),
+				result => panic!("expected InvalidShareName but got {:?}", result),
+			}
+		}
+
+		match super::SubscribeTo::shared("group1", "", super::QoS::AtLeastOnce) {
+			Err(super::SharedSubscriptionError::InvalidTopicFilter(crate::topic::TopicError::Empty)) => (),
+			result => panic!("expected InvalidTopicFilter(Empty) but got {:?}", result),
+		}
+
+		match super::SubscribeTo::shared("group1", "sport/#/tennis", super::QoS::AtLeastOnce) {
+			Err(super::SharedSubscriptionError::InvalidTopicFilter(crate::topic::TopicError::InvalidWildcard(_))) => (),
+			result => panic!("expected InvalidTopicFilter(InvalidWildcard) but got {:?}", result),
+		}
+	}
 }
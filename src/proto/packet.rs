@@ -1,5 +1,4 @@
 use bytes::{ Buf, BufMut, IntoBuf };
-use tokio_codec::Decoder;
 
 use super::{ BufMutExt, ByteBuf };
 
@@ -49,13 +48,64 @@ pub enum Packet {
 	Unsubscribe(Unsubscribe),
 }
 
+macro_rules! impl_from_for_packet {
+	($($packet:ident ,)*) => {
+		$(
+			impl From<$packet> for Packet {
+				fn from(packet: $packet) -> Self {
+					Packet::$packet(packet)
+				}
+			}
+		)*
+	};
+}
+
+impl_from_for_packet! {
+	ConnAck,
+	Connect,
+	Disconnect,
+	PingReq,
+	PingResp,
+	PubAck,
+	PubComp,
+	Publish,
+	PubRec,
+	PubRel,
+	SubAck,
+	Subscribe,
+	UnsubAck,
+	Unsubscribe,
+}
+
+impl Packet {
+	/// The name of this packet's type, as written in the spec, like `PUBLISH`
+	pub(crate) fn type_name(&self) -> &'static str {
+		match self {
+			Packet::ConnAck(_) => "CONNACK",
+			Packet::Connect(_) => "CONNECT",
+			Packet::Disconnect(_) => "DISCONNECT",
+			Packet::PingReq(_) => "PINGREQ",
+			Packet::PingResp(_) => "PINGRESP",
+			Packet::PubAck(_) => "PUBACK",
+			Packet::PubComp(_) => "PUBCOMP",
+			Packet::Publish(_) => "PUBLISH",
+			Packet::PubRec(_) => "PUBREC",
+			Packet::PubRel(_) => "PUBREL",
+			Packet::SubAck(_) => "SUBACK",
+			Packet::Subscribe(_) => "SUBSCRIBE",
+			Packet::UnsubAck(_) => "UNSUBACK",
+			Packet::Unsubscribe(_) => "UNSUBSCRIBE",
+		}
+	}
+}
+
 /// Metadata about a [`Packet`]
 pub(crate) trait PacketMeta: Sized {
 	/// The packet type for this kind of packet
 	const PACKET_TYPE: u8;
 
 	/// Decodes this packet from the given buffer
-	fn decode(flags: u8, src: bytes::BytesMut) -> Result<Self, super::DecodeError>;
+	fn decode(flags: u8, src: bytes::BytesMut, string_validation: super::StringValidation) -> Result<Self, super::DecodeError>;
 
 	/// Encodes the variable header and payload corresponding to this packet into the given buffer.
 	/// The buffer is expected to already have the packet type and body length encoded into it,
@@ -73,7 +123,7 @@ pub struct ConnAck {
 impl PacketMeta for ConnAck {
 	const PACKET_TYPE: u8 = 0x20;
 
-	fn decode(flags: u8, mut src: bytes::BytesMut) -> Result<Self, super::DecodeError> {
+	fn decode(flags: u8, mut src: bytes::BytesMut, _: super::StringValidation) -> Result<Self, super::DecodeError> {
 		if flags != 0 || src.len() != (std::mem::size_of::<u8>() + std::mem::size_of::<u8>()) {
 			return Err(super::DecodeError::UnrecognizedPacket { packet_type: Self::PACKET_TYPE, flags, remaining_length: src.len() });
 		}
@@ -132,12 +182,12 @@ impl std::fmt::Debug for Connect {
 impl PacketMeta for Connect {
 	const PACKET_TYPE: u8 = 0x10;
 
-	fn decode(flags: u8, mut src: bytes::BytesMut) -> Result<Self, super::DecodeError> {
+	fn decode(flags: u8, mut src: bytes::BytesMut, string_validation: super::StringValidation) -> Result<Self, super::DecodeError> {
 		if flags != 0 {
 			return Err(super::DecodeError::UnrecognizedPacket { packet_type: Self::PACKET_TYPE, flags, remaining_length: src.len() });
 		}
 
-		let protocol_name = super::Utf8StringDecoder::default().decode(&mut src)?.ok_or(super::DecodeError::IncompletePacket)?;
+		let protocol_name = super::decode_utf8_str(&mut src, string_validation)?;
 		if protocol_name != "MQTT" {
 			return Err(super::DecodeError::UnrecognizedProtocolName(protocol_name));
 		}
@@ -154,7 +204,7 @@ impl PacketMeta for Connect {
 
 		let keep_alive = std::time::Duration::from_secs(u64::from(src.try_get_u16_be()?));
 
-		let client_id = super::Utf8StringDecoder::default().decode(&mut src)?.ok_or(super::DecodeError::IncompletePacket)?;
+		let client_id = super::decode_utf8_str(&mut src, string_validation)?;
 		let client_id =
 			if client_id == "" {
 				super::ClientId::ServerGenerated
@@ -171,7 +221,8 @@ impl PacketMeta for Connect {
 				None
 			}
 			else {
-				let topic_name = super::Utf8StringDecoder::default().decode(&mut src)?.ok_or(super::DecodeError::IncompletePacket)?;
+				let topic_name = super::decode_utf8_str(&mut src, string_validation)?;
+				let topic_name = crate::topic::TopicName::new(topic_name).map_err(super::DecodeError::InvalidTopic)?;
 
 				let qos = match connect_flags & 0x18 {
 					0x00 => QoS::AtMostOnce,
@@ -201,7 +252,7 @@ impl PacketMeta for Connect {
 				None
 			}
 			else {
-				Some(super::Utf8StringDecoder::default().decode(&mut src)?.ok_or(super::DecodeError::IncompletePacket)?)
+				Some(super::decode_utf8_str(&mut src, string_validation)?)
 			};
 
 		let password =
@@ -209,7 +260,7 @@ impl PacketMeta for Connect {
 				None
 			}
 			else {
-				Some(super::Utf8StringDecoder::default().decode(&mut src)?.ok_or(super::DecodeError::IncompletePacket)?)
+				Some(super::decode_utf8_str(&mut src, string_validation)?)
 			};
 
 		Ok(Connect {
@@ -300,7 +351,7 @@ pub struct Disconnect;
 impl PacketMeta for Disconnect {
 	const PACKET_TYPE: u8 = 0xE0;
 
-	fn decode(flags: u8, src: bytes::BytesMut) -> Result<Self, super::DecodeError> {
+	fn decode(flags: u8, src: bytes::BytesMut, _: super::StringValidation) -> Result<Self, super::DecodeError> {
 		if flags != 0 || !src.is_empty() {
 			return Err(super::DecodeError::UnrecognizedPacket { packet_type: Self::PACKET_TYPE, flags, remaining_length: src.len() });
 		}
@@ -320,7 +371,7 @@ pub struct PingReq;
 impl PacketMeta for PingReq {
 	const PACKET_TYPE: u8 = 0xC0;
 
-	fn decode(flags: u8, src: bytes::BytesMut) -> Result<Self, super::DecodeError> {
+	fn decode(flags: u8, src: bytes::BytesMut, _: super::StringValidation) -> Result<Self, super::DecodeError> {
 		if flags != 0 || !src.is_empty() {
 			return Err(super::DecodeError::UnrecognizedPacket { packet_type: Self::PACKET_TYPE, flags, remaining_length: src.len() });
 		}
@@ -340,7 +391,7 @@ pub struct PingResp;
 impl PacketMeta for PingResp {
 	const PACKET_TYPE: u8 = 0xD0;
 
-	fn decode(flags: u8, src: bytes::BytesMut) -> Result<Self, super::DecodeError> {
+	fn decode(flags: u8, src: bytes::BytesMut, _: super::StringValidation) -> Result<Self, super::DecodeError> {
 		if flags != 0 || !src.is_empty() {
 			return Err(super::DecodeError::UnrecognizedPacket { packet_type: Self::PACKET_TYPE, flags, remaining_length: src.len() });
 		}
@@ -362,7 +413,7 @@ pub struct PubAck {
 impl PacketMeta for PubAck {
 	const PACKET_TYPE: u8 = 0x40;
 
-	fn decode(flags: u8, mut src: bytes::BytesMut) -> Result<Self, super::DecodeError> {
+	fn decode(flags: u8, mut src: bytes::BytesMut, _: super::StringValidation) -> Result<Self, super::DecodeError> {
 		if flags != 0 || src.len() != std::mem::size_of::<u16>() {
 			return Err(super::DecodeError::UnrecognizedPacket { packet_type: Self::PACKET_TYPE, flags, remaining_length: src.len() });
 		}
@@ -391,7 +442,7 @@ pub struct PubComp {
 impl PacketMeta for PubComp {
 	const PACKET_TYPE: u8 = 0x70;
 
-	fn decode(flags: u8, mut src: bytes::BytesMut) -> Result<Self, super::DecodeError> {
+	fn decode(flags: u8, mut src: bytes::BytesMut, _: super::StringValidation) -> Result<Self, super::DecodeError> {
 		if flags != 0 || src.len() != std::mem::size_of::<u16>() {
 			return Err(super::DecodeError::UnrecognizedPacket { packet_type: Self::PACKET_TYPE, flags, remaining_length: src.len() });
 		}
@@ -416,17 +467,19 @@ pub struct Publish {
 	pub packet_identifier_dup_qos: PacketIdentifierDupQoS,
 	pub retain: bool,
 	pub topic_name: String,
+
+	/// When decoded by [`PacketCodec`], this is a slice of the codec's read buffer rather than a copy of it.
 	pub payload: bytes::Bytes,
 }
 
 impl PacketMeta for Publish {
 	const PACKET_TYPE: u8 = 0x30;
 
-	fn decode(flags: u8, mut src: bytes::BytesMut) -> Result<Self, super::DecodeError> {
+	fn decode(flags: u8, mut src: bytes::BytesMut, string_validation: super::StringValidation) -> Result<Self, super::DecodeError> {
 		let dup = (flags & 0x08) != 0;
 		let retain = (flags & 0x01) != 0;
 
-		let topic_name = super::Utf8StringDecoder::default().decode(&mut src)?.ok_or(super::DecodeError::IncompletePacket)?;
+		let topic_name = super::decode_utf8_str(&mut src, string_validation)?;
 
 		let packet_identifier_dup_qos = match (flags & 0x06) >> 1 {
 			0x00 if dup => return Err(super::DecodeError::PublishDupAtMostOnce),
@@ -446,6 +499,7 @@ impl PacketMeta for Publish {
 			qos => return Err(super::DecodeError::UnrecognizedQoS(qos)),
 		};
 
+		// No copy; the payload shares the read buffer that `src` was split from
 		let payload = src.freeze();
 
 		Ok(Publish {
@@ -457,8 +511,35 @@ impl PacketMeta for Publish {
 	}
 
 	fn encode<B>(&self, dst: &mut B) -> Result<(), super::EncodeError> where B: ByteBuf {
+		self.encode_variable_header(dst)?;
+		dst.put_slice_bytes(&self.payload);
+		Ok(())
+	}
+}
+
+impl Publish {
+	fn flags(&self) -> u8 {
+		let mut flags = match self.packet_identifier_dup_qos {
+			PacketIdentifierDupQoS::AtMostOnce => 0x00,
+			PacketIdentifierDupQoS::AtLeastOnce(_, true) => 0x0A,
+			PacketIdentifierDupQoS::AtLeastOnce(_, false) => 0x02,
+			PacketIdentifierDupQoS::ExactlyOnce(_, true) => 0x0C,
+			PacketIdentifierDupQoS::ExactlyOnce(_, false) => 0x04,
+		};
+		if self.retain {
+			flags |= 0x01;
+		}
+		flags
+	}
+
+	fn encode_variable_header<B>(&self, dst: &mut B) -> Result<(), super::EncodeError> where B: ByteBuf {
+		self.encode_variable_header_with_topic_name(&self.topic_name, dst)
+	}
+
+	/// Like `encode_variable_header`, but with the given topic name instead of `self.topic_name`
+	fn encode_variable_header_with_topic_name<B>(&self, topic_name: &str, dst: &mut B) -> Result<(), super::EncodeError> where B: ByteBuf {
 		#[allow(clippy::unneeded_field_pattern)]
-		let Publish { packet_identifier_dup_qos, retain: _, topic_name, payload } = self;
+		let Publish { packet_identifier_dup_qos, retain: _, topic_name: _, payload: _ } = self;
 
 		super::encode_utf8_str(topic_name, dst)?;
 
@@ -469,8 +550,6 @@ impl PacketMeta for Publish {
 				dst.put_packet_identifier_bytes(*packet_identifier),
 		}
 
-		dst.put_slice_bytes(&payload);
-
 		Ok(())
 	}
 }
@@ -485,7 +564,7 @@ pub struct PubRec {
 impl PacketMeta for PubRec {
 	const PACKET_TYPE: u8 = 0x50;
 
-	fn decode(flags: u8, mut src: bytes::BytesMut) -> Result<Self, super::DecodeError> {
+	fn decode(flags: u8, mut src: bytes::BytesMut, _: super::StringValidation) -> Result<Self, super::DecodeError> {
 		if flags != 0 || src.len() != std::mem::size_of::<u16>() {
 			return Err(super::DecodeError::UnrecognizedPacket { packet_type: Self::PACKET_TYPE, flags, remaining_length: src.len() });
 		}
@@ -514,7 +593,7 @@ pub struct PubRel {
 impl PacketMeta for PubRel {
 	const PACKET_TYPE: u8 = 0x60;
 
-	fn decode(flags: u8, mut src: bytes::BytesMut) -> Result<Self, super::DecodeError> {
+	fn decode(flags: u8, mut src: bytes::BytesMut, _: super::StringValidation) -> Result<Self, super::DecodeError> {
 		if flags != 2 || src.len() != std::mem::size_of::<u16>() {
 			return Err(super::DecodeError::UnrecognizedPacket { packet_type: Self::PACKET_TYPE, flags, remaining_length: src.len() });
 		}
@@ -543,7 +622,7 @@ pub struct SubAck {
 impl PacketMeta for SubAck {
 	const PACKET_TYPE: u8 = 0x90;
 
-	fn decode(flags: u8, mut src: bytes::BytesMut) -> Result<Self, super::DecodeError> {
+	fn decode(flags: u8, mut src: bytes::BytesMut, _: super::StringValidation) -> Result<Self, super::DecodeError> {
 		if flags != 0 || src.len() < std::mem::size_of::<u16>() {
 			return Err(super::DecodeError::UnrecognizedPacket { packet_type: Self::PACKET_TYPE, flags, remaining_length: src.len() });
 		}
@@ -592,7 +671,7 @@ pub struct Subscribe {
 impl PacketMeta for Subscribe {
 	const PACKET_TYPE: u8 = 0x80;
 
-	fn decode(flags: u8, mut src: bytes::BytesMut) -> Result<Self, super::DecodeError> {
+	fn decode(flags: u8, mut src: bytes::BytesMut, string_validation: super::StringValidation) -> Result<Self, super::DecodeError> {
 		if flags != 2 || src.len() < std::mem::size_of::<u16>() {
 			return Err(super::DecodeError::UnrecognizedPacket { packet_type: Self::PACKET_TYPE, flags, remaining_length: src.len() });
 		}
@@ -602,7 +681,8 @@ impl PacketMeta for Subscribe {
 		let mut subscribe_to = vec![];
 
 		while !src.is_empty() {
-			let topic_filter = super::Utf8StringDecoder::default().decode(&mut src)?.ok_or(super::DecodeError::IncompletePacket)?;
+			let topic_filter = super::decode_utf8_str(&mut src, string_validation)?;
+			let topic_filter = crate::topic::TopicFilter::new(topic_filter).map_err(super::DecodeError::InvalidTopic)?;
 			let qos = match src.try_get_u8()? {
 				0x00 => QoS::AtMostOnce,
 				0x01 => QoS::AtLeastOnce,
@@ -645,7 +725,7 @@ pub struct UnsubAck {
 impl PacketMeta for UnsubAck {
 	const PACKET_TYPE: u8 = 0xB0;
 
-	fn decode(flags: u8, mut src: bytes::BytesMut) -> Result<Self, super::DecodeError> {
+	fn decode(flags: u8, mut src: bytes::BytesMut, _: super::StringValidation) -> Result<Self, super::DecodeError> {
 		if flags != 0 || src.len() != std::mem::size_of::<u16>() {
 			return Err(super::DecodeError::UnrecognizedPacket { packet_type: Self::PACKET_TYPE, flags, remaining_length: src.len() });
 		}
@@ -674,7 +754,7 @@ pub struct Unsubscribe {
 impl PacketMeta for Unsubscribe {
 	const PACKET_TYPE: u8 = 0xA0;
 
-	fn decode(flags: u8, mut src: bytes::BytesMut) -> Result<Self, super::DecodeError> {
+	fn decode(flags: u8, mut src: bytes::BytesMut, string_validation: super::StringValidation) -> Result<Self, super::DecodeError> {
 		if flags != 2 || src.len() < std::mem::size_of::<u16>() {
 			return Err(super::DecodeError::UnrecognizedPacket { packet_type: Self::PACKET_TYPE, flags, remaining_length: src.len() });
 		}
@@ -684,7 +764,7 @@ impl PacketMeta for Unsubscribe {
 		let mut unsubscribe_from = vec![];
 
 		while !src.is_empty() {
-			unsubscribe_from.push(super::Utf8StringDecoder::default().decode(&mut src)?.ok_or(super::DecodeError::IncompletePacket)?);
+			unsubscribe_from.push(super::decode_utf8_str(&mut src, string_validation)?);
 		}
 
 		if unsubscribe_from.is_empty() {
@@ -723,10 +803,34 @@ pub enum PacketIdentifierDupQoS {
 /// A subscription request.
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct SubscribeTo {
-	pub topic_filter: String,
+	pub topic_filter: crate::topic::TopicFilter,
 	pub qos: QoS,
 }
 
+impl SubscribeTo {
+	/// Creates a shared subscription, ie one with a `$share/<share_name>/<topic_filter>` topic filter.
+	///
+	/// The server delivers each publication that matches `topic_filter` to only one of the clients that subscribed with the same `share_name`,
+	/// so the clients can share the load of processing them. Note that shared subscriptions are not part of MQTT 3.1.1,
+	/// so the server must support them as an extension.
+	pub fn shared(share_name: &str, topic_filter: &str, qos: QoS) -> Result<Self, super::SharedSubscriptionError> {
+		if share_name.is_empty() || share_name.contains(&['/', '+', '#'][..]) {
+			return Err(super::SharedSubscriptionError::InvalidShareName(share_name.to_owned()));
+		}
+
+		let topic_filter = crate::topic::TopicFilter::new(topic_filter.to_owned()).map_err(super::SharedSubscriptionError::InvalidTopicFilter)?;
+
+		let topic_filter =
+			crate::topic::TopicFilter::new(format!("{}{}/{}", crate::topic::SHARED_SUBSCRIPTION_PREFIX, share_name, topic_filter))
+			.map_err(super::SharedSubscriptionError::InvalidTopicFilter)?;
+
+		Ok(SubscribeTo {
+			topic_filter,
+			qos,
+		})
+	}
+}
+
 /// The level of reliability for a publication
 ///
 /// Ref: 4.3 Quality of Service levels and protocol flows
@@ -765,9 +869,12 @@ impl From<SubAckQos> for u8 {
 }
 
 /// A message that can be published to the server
+///
+/// Cloning it is cheap, since the clones share the topic name and the payload instead of copying them.
+/// So retrying a publication or publishing it with several clients doesn't need to keep it around by reference.
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct Publication {
-	pub topic_name: String,
+	pub topic_name: crate::topic::TopicName,
 	pub qos: crate::proto::QoS,
 	pub retain: bool,
 	pub payload: bytes::Bytes,
@@ -775,23 +882,144 @@ pub struct Publication {
 
 /// A tokio codec that encodes and decodes MQTT packets.
 ///
+/// The codec handles the packets sent by both clients and servers, so it can be used with `tokio_codec::Framed`
+/// to implement servers and proxies as well as clients.
+///
 /// Ref: 2 MQTT Control Packet format
 #[derive(Debug, Default)]
 pub struct PacketCodec {
-	decoder_state: PacketDecoderState,
+	max_incoming_packet_size: Option<usize>,
+	string_validation: super::StringValidation,
 }
 
-#[derive(Debug)]
-pub enum PacketDecoderState {
-	Empty,
-	HaveFirstByte { first_byte: u8, remaining_length: super::RemainingLengthDecoder },
-	HaveFixedHeader { first_byte: u8, remaining_length: usize },
-}
+impl PacketCodec {
+	/// Creates a codec that fails to decode packets whose remaining length is larger than `max_incoming_packet_size`, if set.
+	///
+	/// The remaining length is checked as soon as the fixed header has been decoded, so the rest of the packet is never buffered.
+	/// A codec created with [`Default::default`] decodes packets of any size.
+	pub fn new(max_incoming_packet_size: Option<usize>) -> Self {
+		PacketCodec {
+			max_incoming_packet_size,
+			string_validation: Default::default(),
+		}
+	}
+
+	/// Encodes the given packet into `dst` like [`tokio_codec::Encoder::encode`], except that the payload of a PUBLISH packet is not copied into `dst`.
+	/// Instead it is returned, and must be written right after the bytes in `dst`.
+	///
+	/// This lets the caller write large payloads with vectored writes straight from the buffer that holds them.
+	pub(crate) fn encode_without_payload(&mut self, item: Packet, dst: &mut bytes::BytesMut) -> Result<Option<bytes::Bytes>, super::EncodeError> {
+		match item {
+			Packet::Publish(packet) => {
+				let mut counter = super::ByteCounter::new();
+				packet.encode(&mut counter)?;
+				let body_len = counter.0;
+
+				dst.reserve(
+					std::mem::size_of::<u8>() + // packet type
+					4 * std::mem::size_of::<u8>() + // remaining length
+					body_len - packet.payload.len());
+
+				dst.put_u8(<Publish as PacketMeta>::PACKET_TYPE | packet.flags());
+				super::encode_remaining_length(body_len, dst)?;
+				packet.encode_variable_header(dst)?;
+
+				Ok(Some(packet.payload))
+			},
 
-impl Default for PacketDecoderState {
-	fn default() -> Self {
-		PacketDecoderState::Empty
+			item => {
+				tokio_codec::Encoder::encode(self, item, dst)?;
+				Ok(None)
+			},
+		}
+	}
+
+	/// Like [`PacketCodec::encode_without_payload`] for a PUBLISH packet whose payload of `payload_len` bytes is written separately,
+	/// such as in chunks. `topic_name` is written instead of `packet.topic_name`, so that the client doesn't need to copy the topic name
+	/// of a publication into a new PUBLISH packet every time it sends it. `packet.payload` is ignored.
+	#[allow(clippy::unused_self)] // A method like the other encoding functions of the codec
+	pub(crate) fn encode_publish_header(
+		&mut self,
+		packet: &Publish,
+		topic_name: &str,
+		payload_len: usize,
+		dst: &mut bytes::BytesMut,
+	) -> Result<(), super::EncodeError> {
+		let mut counter = super::ByteCounter::new();
+		packet.encode_variable_header_with_topic_name(topic_name, &mut counter)?;
+		let variable_header_len = counter.0;
+
+		dst.reserve(
+			std::mem::size_of::<u8>() + // packet type
+			4 * std::mem::size_of::<u8>() + // remaining length
+			variable_header_len);
+
+		dst.put_u8(<Publish as PacketMeta>::PACKET_TYPE | packet.flags());
+		super::encode_remaining_length(variable_header_len + payload_len, dst)?;
+		packet.encode_variable_header_with_topic_name(topic_name, dst)?;
+
+		Ok(())
+	}
+
+	/// Like [`tokio_codec::Decoder::decode`], except that an at-most-once or at-least-once PUBLISH packet whose payload is longer than `min_streamed_payload_len`
+	/// is returned as soon as its variable header has been received, as a [`Decoded::PublishHeader`]. Its payload is left in `src`, or is yet to be received.
+	///
+	/// Exactly-once PUBLISH packets are always decoded whole, since their publications are only delivered once the server releases them.
+	pub(crate) fn decode_streaming(&mut self, src: &mut bytes::BytesMut, min_streamed_payload_len: usize) -> Result<Option<Decoded>, super::DecodeError> {
+		use tokio_codec::Decoder;
+
+		let (first_byte, remaining_length, fixed_header_len) = match super::decode_fixed_header(src)? {
+			Some(fixed_header) => fixed_header,
+			None => return Ok(None),
+		};
+
+		// The length-prefixed topic name, and the packet identifier of an at-least-once publication
+		let variable_header_len = match (first_byte & 0xF0, (first_byte & 0x06) >> 1, src.get(fixed_header_len..fixed_header_len + 2)) {
+			(Publish::PACKET_TYPE, 0x00, Some(topic_name_len)) => 2 + usize::from(u16::from_be_bytes([topic_name_len[0], topic_name_len[1]])),
+			(Publish::PACKET_TYPE, 0x01, Some(topic_name_len)) => 2 + usize::from(u16::from_be_bytes([topic_name_len[0], topic_name_len[1]])) + 2,
+			(Publish::PACKET_TYPE, 0x00 | 0x01, None) if remaining_length > min_streamed_payload_len => return Ok(None),
+			_ => return Ok(self.decode(src)?.map(Decoded::Packet)),
+		};
+
+		let payload_len = match remaining_length.checked_sub(variable_header_len) {
+			Some(payload_len) if payload_len > min_streamed_payload_len => payload_len,
+			_ => return Ok(self.decode(src)?.map(Decoded::Packet)),
+		};
+
+		if let Some(max_incoming_packet_size) = self.max_incoming_packet_size {
+			if remaining_length > max_incoming_packet_size {
+				return Err(super::DecodeError::PacketTooLarge { remaining_length, max: max_incoming_packet_size });
+			}
+		}
+
+		if src.len() < fixed_header_len + variable_header_len {
+			return Ok(None);
+		}
+
+		src.advance(fixed_header_len);
+		let variable_header = src.split_to(variable_header_len);
+		let publish = Publish::decode(first_byte & 0x0F, variable_header, self.string_validation)?;
+
+		Ok(Some(Decoded::PublishHeader { publish, payload_len }))
 	}
+
+	/// Sets how strictly the UTF-8 strings in decoded packets are validated.
+	///
+	/// Strings in encoded packets are always validated strictly.
+	///
+	/// Defaults to [`StringValidation::Strict`](super::StringValidation::Strict).
+	pub fn set_string_validation(&mut self, string_validation: super::StringValidation) {
+		self.string_validation = string_validation;
+	}
+}
+
+/// A packet decoded by [`PacketCodec::decode_streaming`]
+#[derive(Debug)]
+pub(crate) enum Decoded {
+	Packet(Packet),
+
+	/// A PUBLISH packet with an empty payload, whose actual payload of `payload_len` bytes follows it
+	PublishHeader { publish: Publish, payload_len: usize },
 }
 
 impl tokio_codec::Decoder for PacketCodec {
@@ -799,51 +1027,43 @@ impl tokio_codec::Decoder for PacketCodec {
 	type Error = super::DecodeError;
 
 	fn decode(&mut self, src: &mut bytes::BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-		let (first_byte, src) = loop {
-			match &mut self.decoder_state {
-				PacketDecoderState::Empty => {
-					let first_byte = match src.try_get_u8() {
-						Ok(first_byte) => first_byte,
-						Err(_) => return Ok(None),
-					};
-					self.decoder_state = PacketDecoderState::HaveFirstByte { first_byte, remaining_length: Default::default() };
-				},
-
-				PacketDecoderState::HaveFirstByte { first_byte, remaining_length } => match remaining_length.decode(src)? {
-					Some(remaining_length) => self.decoder_state = PacketDecoderState::HaveFixedHeader { first_byte: *first_byte, remaining_length },
-					None => return Ok(None),
-				},
-
-				PacketDecoderState::HaveFixedHeader { first_byte, remaining_length } => {
-					if src.len() < *remaining_length {
-						return Ok(None);
-					}
-
-					let first_byte = *first_byte;
-					let src = src.split_to(*remaining_length);
-					self.decoder_state = PacketDecoderState::Empty;
-					break (first_byte, src);
-				},
-			}
+		// The fixed header is parsed in place, and nothing is consumed until the whole packet has been received.
+		// Only the bytes of the packet body are split off, and the payload of a PUBLISH packet shares them.
+		let (first_byte, remaining_length, fixed_header_len) = match super::decode_fixed_header(src)? {
+			Some(fixed_header) => fixed_header,
+			None => return Ok(None),
 		};
 
+		if let Some(max_incoming_packet_size) = self.max_incoming_packet_size {
+			if remaining_length > max_incoming_packet_size {
+				return Err(super::DecodeError::PacketTooLarge { remaining_length, max: max_incoming_packet_size });
+			}
+		}
+
+		if src.len() < fixed_header_len + remaining_length {
+			return Ok(None);
+		}
+
+		src.advance(fixed_header_len);
+		let src = src.split_to(remaining_length);
+
 		let packet_type = first_byte & 0xF0;
 		let flags = first_byte & 0x0F;
 		match packet_type {
-			ConnAck::PACKET_TYPE => Ok(Some(Packet::ConnAck(ConnAck::decode(flags, src)?))),
-			Connect::PACKET_TYPE => Ok(Some(Packet::Connect(Connect::decode(flags, src)?))),
-			Disconnect::PACKET_TYPE => Ok(Some(Packet::Disconnect(Disconnect::decode(flags, src)?))),
-			PingReq::PACKET_TYPE => Ok(Some(Packet::PingReq(PingReq::decode(flags, src)?))),
-			PingResp::PACKET_TYPE => Ok(Some(Packet::PingResp(PingResp::decode(flags, src)?))),
-			PubAck::PACKET_TYPE => Ok(Some(Packet::PubAck(PubAck::decode(flags, src)?))),
-			PubComp::PACKET_TYPE => Ok(Some(Packet::PubComp(PubComp::decode(flags, src)?))),
-			Publish::PACKET_TYPE => Ok(Some(Packet::Publish(Publish::decode(flags, src)?))),
-			PubRec::PACKET_TYPE => Ok(Some(Packet::PubRec(PubRec::decode(flags, src)?))),
-			PubRel::PACKET_TYPE => Ok(Some(Packet::PubRel(PubRel::decode(flags, src)?))),
-			SubAck::PACKET_TYPE => Ok(Some(Packet::SubAck(SubAck::decode(flags, src)?))),
-			Subscribe::PACKET_TYPE => Ok(Some(Packet::Subscribe(Subscribe::decode(flags, src)?))),
-			UnsubAck::PACKET_TYPE => Ok(Some(Packet::UnsubAck(UnsubAck::decode(flags, src)?))),
-			Unsubscribe::PACKET_TYPE => Ok(Some(Packet::Unsubscribe(Unsubscribe::decode(flags, src)?))),
+			ConnAck::PACKET_TYPE => Ok(Some(Packet::ConnAck(ConnAck::decode(flags, src, self.string_validation)?))),
+			Connect::PACKET_TYPE => Ok(Some(Packet::Connect(Connect::decode(flags, src, self.string_validation)?))),
+			Disconnect::PACKET_TYPE => Ok(Some(Packet::Disconnect(Disconnect::decode(flags, src, self.string_validation)?))),
+			PingReq::PACKET_TYPE => Ok(Some(Packet::PingReq(PingReq::decode(flags, src, self.string_validation)?))),
+			PingResp::PACKET_TYPE => Ok(Some(Packet::PingResp(PingResp::decode(flags, src, self.string_validation)?))),
+			PubAck::PACKET_TYPE => Ok(Some(Packet::PubAck(PubAck::decode(flags, src, self.string_validation)?))),
+			PubComp::PACKET_TYPE => Ok(Some(Packet::PubComp(PubComp::decode(flags, src, self.string_validation)?))),
+			Publish::PACKET_TYPE => Ok(Some(Packet::Publish(Publish::decode(flags, src, self.string_validation)?))),
+			PubRec::PACKET_TYPE => Ok(Some(Packet::PubRec(PubRec::decode(flags, src, self.string_validation)?))),
+			PubRel::PACKET_TYPE => Ok(Some(Packet::PubRel(PubRel::decode(flags, src, self.string_validation)?))),
+			SubAck::PACKET_TYPE => Ok(Some(Packet::SubAck(SubAck::decode(flags, src, self.string_validation)?))),
+			Subscribe::PACKET_TYPE => Ok(Some(Packet::Subscribe(Subscribe::decode(flags, src, self.string_validation)?))),
+			UnsubAck::PACKET_TYPE => Ok(Some(Packet::UnsubAck(UnsubAck::decode(flags, src, self.string_validation)?))),
+			Unsubscribe::PACKET_TYPE => Ok(Some(Packet::Unsubscribe(Unsubscribe::decode(flags, src, self.string_validation)?))),
 			packet_type => Err(super::DecodeError::UnrecognizedPacket { packet_type, flags, remaining_length: src.len() }),
 		}
 	}
@@ -854,50 +1074,45 @@ impl tokio_codec::Encoder for PacketCodec {
 	type Error = super::EncodeError;
 
 	fn encode(&mut self, item: Self::Item, dst: &mut bytes::BytesMut) -> Result<(), Self::Error> {
-		dst.reserve(std::mem::size_of::<u8>() + 4 * std::mem::size_of::<u8>());
-
-		match &item {
-			Packet::ConnAck(packet) => encode_packet(packet, 0, dst),
-			Packet::Connect(packet) => encode_packet(packet, 0, dst),
-			Packet::Disconnect(packet) => encode_packet(packet, 0, dst),
-			Packet::PingReq(packet) => encode_packet(packet, 0, dst),
-			Packet::PingResp(packet) => encode_packet(packet, 0, dst),
-			Packet::PubAck(packet) => encode_packet(packet, 0, dst),
-			Packet::PubComp(packet) => encode_packet(packet, 0, dst),
-			Packet::Publish(packet) => {
-				let mut flags = match packet.packet_identifier_dup_qos {
-					PacketIdentifierDupQoS::AtMostOnce => 0x00,
-					PacketIdentifierDupQoS::AtLeastOnce(_, true) => 0x0A,
-					PacketIdentifierDupQoS::AtLeastOnce(_, false) => 0x02,
-					PacketIdentifierDupQoS::ExactlyOnce(_, true) => 0x0C,
-					PacketIdentifierDupQoS::ExactlyOnce(_, false) => 0x04,
-				};
-				if packet.retain {
-					flags |= 0x01;
-				};
-				encode_packet(packet, flags, dst)
-			},
-			Packet::PubRec(packet) => encode_packet(packet, 0, dst),
-			Packet::PubRel(packet) => encode_packet(packet, 0x02, dst),
-			Packet::SubAck(packet) => encode_packet(packet, 0, dst),
-			Packet::Subscribe(packet) => encode_packet(packet, 0x02, dst),
-			Packet::UnsubAck(packet) => encode_packet(packet, 0, dst),
-			Packet::Unsubscribe(packet) => encode_packet(packet, 0x02, dst),
-		}
+		encode(&item, dst)
 	}
 }
 
-fn encode_packet<P>(packet: &P, flags: u8, dst: &mut bytes::BytesMut) -> Result<(), super::EncodeError> where P: PacketMeta {
+/// Encodes any packet straight into `dst`.
+pub(super) fn encode<B>(item: &Packet, dst: &mut B) -> Result<(), super::EncodeError> where B: ByteBuf {
+	match item {
+		Packet::ConnAck(packet) => encode_packet(packet, 0, dst),
+		Packet::Connect(packet) => encode_packet(packet, 0, dst),
+		Packet::Disconnect(packet) => encode_packet(packet, 0, dst),
+		Packet::PingReq(packet) => encode_packet(packet, 0, dst),
+		Packet::PingResp(packet) => encode_packet(packet, 0, dst),
+		Packet::PubAck(packet) => encode_packet(packet, 0, dst),
+		Packet::PubComp(packet) => encode_packet(packet, 0, dst),
+		Packet::Publish(packet) => encode_packet(packet, packet.flags(), dst),
+		Packet::PubRec(packet) => encode_packet(packet, 0, dst),
+		Packet::PubRel(packet) => encode_packet(packet, 0x02, dst),
+		Packet::SubAck(packet) => encode_packet(packet, 0, dst),
+		Packet::Subscribe(packet) => encode_packet(packet, 0x02, dst),
+		Packet::UnsubAck(packet) => encode_packet(packet, 0, dst),
+		Packet::Unsubscribe(packet) => encode_packet(packet, 0x02, dst),
+	}
+}
+
+/// Encodes the packet straight into `dst`.
+///
+/// The packet is first encoded into a [`ByteCounter`](super::ByteCounter) to find its length, so that `dst` only needs to be reserved once
+/// and no intermediate buffer is needed.
+fn encode_packet<P, B>(packet: &P, flags: u8, dst: &mut B) -> Result<(), super::EncodeError> where P: PacketMeta, B: ByteBuf {
 	let mut counter = super::ByteCounter::new();
 	packet.encode(&mut counter)?;
 	let body_len = counter.0;
 
-	dst.reserve(
+	dst.reserve_bytes(
 		std::mem::size_of::<u8>() + // packet type
 		4 * std::mem::size_of::<u8>() + // remaining length
 		body_len);
 
-	dst.put_u8(<P as PacketMeta>::PACKET_TYPE | flags);
+	dst.put_u8_bytes(<P as PacketMeta>::PACKET_TYPE | flags);
 	super::encode_remaining_length(body_len, dst)?;
 	packet.encode(dst)?;
 
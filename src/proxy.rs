@@ -0,0 +1,505 @@
+/*!
+ * [`IoSource`](crate::IoSource)s that connect to the server through a SOCKS5 or HTTP proxy, for networks that only allow
+ * outgoing connections through one.
+ *
+ * The connection to the proxy itself is made by another `IoSource`, like a TCP connection to the proxy's address. Once the proxy has
+ * connected to the server, the connection is handed to the client as is, so TLS and WebSocket `IoSource`s can be layered on top of it.
+ *
+ * ```no_run
+ * # use futures::Future;
+ * let proxy_addr = "10.0.0.1:1080".parse().unwrap();
+ * let mut io_source = mqtt::proxy::Socks5IoSource::new(
+ *     move || tokio::net::TcpStream::connect(&proxy_addr).map(|io| (io, None)),
+ *     "broker.example.com".to_owned(),
+ *     1883,
+ * );
+ * io_source.set_credentials(Some(("user".to_owned(), "password".to_owned())));
+ *
+ * let client = mqtt::Client::new(
+ *     Some("my-client".to_owned()),
+ *     None,
+ *     None,
+ *     io_source,
+ *     std::time::Duration::from_secs(30),
+ *     std::time::Duration::from_secs(240),
+ * );
+ * ```
+ *
+ * This module is only available with the `proxy` feature.
+ */
+
+use futures::Future;
+
+/// Connects to an MQTT server through a SOCKS5 proxy, on top of the connections to the proxy of another [`IoSource`](crate::IoSource).
+#[derive(Debug)]
+pub struct Socks5IoSource<S> {
+	io_source: S,
+	host: String,
+	port: u16,
+	credentials: Option<(String, String)>,
+}
+
+impl<S> Socks5IoSource<S> {
+	/// Creates an `IoSource` that asks the proxy to connect to the server at the given host and port.
+	///
+	/// The host can be a hostname, which the proxy resolves, or an IP address.
+	pub fn new(io_source: S, host: String, port: u16) -> Self {
+		Socks5IoSource {
+			io_source,
+			host,
+			port,
+			credentials: None,
+		}
+	}
+
+	/// Sets the username and password to authenticate with to the proxy.
+	///
+	/// Defaults to `None`, ie the client does not authenticate.
+	pub fn set_credentials(&mut self, credentials: Option<(String, String)>) {
+		self.credentials = credentials;
+	}
+}
+
+impl<S> crate::IoSource for Socks5IoSource<S>
+where
+	S: crate::IoSource,
+	S::Future: Future<Error = std::io::Error>,
+{
+	type Io = S::Io;
+	type Future = ProxyConnectFuture<S::Future, S::Io, Socks5Handshake>;
+
+	fn connect(&mut self) -> Self::Future {
+		let handshake = Socks5Handshake {
+			host: self.host.clone(),
+			port: self.port,
+			credentials: self.credentials.clone(),
+			phase: Socks5Phase::Start,
+		};
+		ProxyConnectFuture::new(self.io_source.connect(), handshake)
+	}
+}
+
+/// Connects to an MQTT server through an HTTP proxy with the `CONNECT` method, on top of the connections to the proxy
+/// of another [`IoSource`](crate::IoSource).
+#[derive(Debug)]
+pub struct HttpConnectIoSource<S> {
+	io_source: S,
+	host: String,
+	port: u16,
+	credentials: Option<(String, String)>,
+}
+
+impl<S> HttpConnectIoSource<S> {
+	/// Creates an `IoSource` that asks the proxy to connect to the server at the given host and port.
+	///
+	/// The host can be a hostname, which the proxy resolves, or an IP address.
+	pub fn new(io_source: S, host: String, port: u16) -> Self {
+		HttpConnectIoSource {
+			io_source,
+			host,
+			port,
+			credentials: None,
+		}
+	}
+
+	/// Sets the username and password to authenticate with to the proxy, with HTTP basic authentication.
+	///
+	/// Defaults to `None`, ie the client does not authenticate.
+	pub fn set_credentials(&mut self, credentials: Option<(String, String)>) {
+		self.credentials = credentials;
+	}
+}
+
+impl<S> crate::IoSource for HttpConnectIoSource<S>
+where
+	S: crate::IoSource,
+	S::Future: Future<Error = std::io::Error>,
+{
+	type Io = S::Io;
+	type Future = ProxyConnectFuture<S::Future, S::Io, HttpConnectHandshake>;
+
+	fn connect(&mut self) -> Self::Future {
+		let handshake = HttpConnectHandshake {
+			host: self.host.clone(),
+			port: self.port,
+			credentials: self.credentials.clone(),
+			started: false,
+		};
+		ProxyConnectFuture::new(self.io_source.connect(), handshake)
+	}
+}
+
+/// The connection future of a [`Socks5IoSource`] or [`HttpConnectIoSource`]
+pub struct ProxyConnectFuture<F, I, H> {
+	state: ConnectState<F, I>,
+	handshake: H,
+}
+
+enum ConnectState<F, I> {
+	Connecting(F),
+	Handshaking {
+		io: Option<I>,
+		password: Option<String>,
+		send: Vec<u8>,
+		sent: usize,
+		flushed: bool,
+		received: Vec<u8>,
+		remaining: usize,
+	},
+}
+
+impl<F, I, H> ProxyConnectFuture<F, I, H> {
+	fn new(inner: F, handshake: H) -> Self {
+		ProxyConnectFuture {
+			state: ConnectState::Connecting(inner),
+			handshake,
+		}
+	}
+}
+
+impl<F, I, H> Future for ProxyConnectFuture<F, I, H>
+where
+	F: Future<Item = (I, Option<String>), Error = std::io::Error>,
+	I: tokio_io::AsyncRead + tokio_io::AsyncWrite,
+	H: Handshake,
+{
+	type Item = (I, Option<String>);
+	type Error = std::io::Error;
+
+	fn poll(&mut self) -> futures::Poll<Self::Item, Self::Error> {
+		loop {
+			match &mut self.state {
+				ConnectState::Connecting(inner) => {
+					let (io, password) = futures::try_ready!(inner.poll());
+					self.state = ConnectState::Handshaking {
+						io: Some(io),
+						password,
+						send: vec![],
+						sent: 0,
+						flushed: true,
+						received: vec![],
+						remaining: 0,
+					};
+				},
+
+				ConnectState::Handshaking { io: io_slot, password, send, sent, flushed, received, remaining } => {
+					let io = io_slot.as_mut().expect("polled after completion");
+
+					if *sent < send.len() {
+						match tokio_io::AsyncWrite::poll_write(io, &send[*sent..])? {
+							futures::Async::Ready(0) => return Err(std::io::ErrorKind::WriteZero.into()),
+							futures::Async::Ready(written) => *sent += written,
+							futures::Async::NotReady => return Ok(futures::Async::NotReady),
+						}
+						continue;
+					}
+
+					if !*flushed {
+						futures::try_ready!(tokio_io::AsyncWrite::poll_flush(io));
+						*flushed = true;
+					}
+
+					if *remaining > 0 {
+						let start = received.len();
+						received.resize(start + *remaining, 0);
+						let read = match tokio_io::AsyncRead::poll_read(io, &mut received[start..]) {
+							Ok(futures::Async::Ready(read)) => read,
+							Ok(futures::Async::NotReady) => {
+								received.truncate(start);
+								return Ok(futures::Async::NotReady);
+							},
+							Err(err) => return Err(err),
+						};
+						received.truncate(start + read);
+						if read == 0 {
+							return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "proxy closed the connection during the handshake"));
+						}
+						*remaining -= read;
+						if *remaining > 0 {
+							continue;
+						}
+					}
+
+					match self.handshake.step(received)? {
+						Step::Exchange { send: new_send, receive } => {
+							*send = new_send;
+							*sent = 0;
+							*flushed = false;
+							received.clear();
+							*remaining = receive;
+						},
+
+						Step::Receive(receive) => *remaining = receive,
+
+						Step::Done => {
+							log::debug!("proxy connected to the server");
+							let io = io_slot.take().expect("polled after completion");
+							return Ok(futures::Async::Ready((io, password.take())));
+						},
+					}
+				},
+			}
+		}
+	}
+}
+
+impl<F, I, H> std::fmt::Debug for ProxyConnectFuture<F, I, H> {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		let state = match &self.state {
+			ConnectState::Connecting(_) => "Connecting",
+			ConnectState::Handshaking { .. } => "Handshaking",
+		};
+		f.debug_struct("ProxyConnectFuture").field("state", &state).finish_non_exhaustive()
+	}
+}
+
+/// A proxy protocol's handshake, as a sequence of requests and responses
+pub(crate) trait Handshake {
+	/// Returns the next step of the handshake, given the response received so far to the last request.
+	///
+	/// The first call has no response.
+	fn step(&mut self, received: &[u8]) -> std::io::Result<Step>;
+}
+
+/// A step of a proxy [`Handshake`]
+#[derive(Debug)]
+pub(crate) enum Step {
+	/// Send the given request, then receive the given number of bytes of its response
+	Exchange { send: Vec<u8>, receive: usize },
+
+	/// Receive the given number of additional bytes of the response to the last request
+	Receive(usize),
+
+	/// The proxy has connected to the server
+	Done,
+}
+
+/// The handshake of a [`Socks5IoSource`]
+#[derive(Debug)]
+pub struct Socks5Handshake {
+	host: String,
+	port: u16,
+	credentials: Option<(String, String)>,
+	phase: Socks5Phase,
+}
+
+#[derive(Debug)]
+enum Socks5Phase {
+	Start,
+	MethodSelection,
+	Authentication,
+	ReplyHeader,
+	ReplyAddress,
+}
+
+const SOCKS5_VERSION: u8 = 0x05;
+const SOCKS5_METHOD_NO_AUTHENTICATION: u8 = 0x00;
+const SOCKS5_METHOD_USERNAME_PASSWORD: u8 = 0x02;
+const SOCKS5_COMMAND_CONNECT: u8 = 0x01;
+const SOCKS5_ADDRESS_IPV4: u8 = 0x01;
+const SOCKS5_ADDRESS_DOMAIN_NAME: u8 = 0x03;
+const SOCKS5_ADDRESS_IPV6: u8 = 0x04;
+
+impl Socks5Handshake {
+	fn connect_request(&self) -> std::io::Result<Vec<u8>> {
+		let mut request = vec![SOCKS5_VERSION, SOCKS5_COMMAND_CONNECT, 0x00];
+		match self.host.parse() {
+			Ok(std::net::IpAddr::V4(addr)) => {
+				request.push(SOCKS5_ADDRESS_IPV4);
+				request.extend_from_slice(&addr.octets());
+			},
+
+			Ok(std::net::IpAddr::V6(addr)) => {
+				request.push(SOCKS5_ADDRESS_IPV6);
+				request.extend_from_slice(&addr.octets());
+			},
+
+			Err(_) => {
+				request.push(SOCKS5_ADDRESS_DOMAIN_NAME);
+				request.push(length_u8(self.host.as_bytes(), "hostname")?);
+				request.extend_from_slice(self.host.as_bytes());
+			},
+		}
+		request.extend_from_slice(&self.port.to_be_bytes());
+		Ok(request)
+	}
+}
+
+impl Handshake for Socks5Handshake {
+	fn step(&mut self, received: &[u8]) -> std::io::Result<Step> {
+		match self.phase {
+			Socks5Phase::Start => {
+				let method = if self.credentials.is_some() { SOCKS5_METHOD_USERNAME_PASSWORD } else { SOCKS5_METHOD_NO_AUTHENTICATION };
+				self.phase = Socks5Phase::MethodSelection;
+				Ok(Step::Exchange { send: vec![SOCKS5_VERSION, 0x01, method], receive: 2 })
+			},
+
+			Socks5Phase::MethodSelection => match (received[1], &self.credentials) {
+				(SOCKS5_METHOD_NO_AUTHENTICATION, None) => {
+					self.phase = Socks5Phase::ReplyHeader;
+					Ok(Step::Exchange { send: self.connect_request()?, receive: 4 })
+				},
+
+				(SOCKS5_METHOD_USERNAME_PASSWORD, Some((username, password))) => {
+					// RFC 1929
+					let mut request = vec![0x01, length_u8(username.as_bytes(), "username")?];
+					request.extend_from_slice(username.as_bytes());
+					request.push(length_u8(password.as_bytes(), "password")?);
+					request.extend_from_slice(password.as_bytes());
+					self.phase = Socks5Phase::Authentication;
+					Ok(Step::Exchange { send: request, receive: 2 })
+				},
+
+				_ => Err(proxy_error("SOCKS5 proxy does not accept any of the offered authentication methods")),
+			},
+
+			Socks5Phase::Authentication => {
+				if received[1] != 0x00 {
+					return Err(proxy_error("SOCKS5 proxy rejected the credentials"));
+				}
+
+				self.phase = Socks5Phase::ReplyHeader;
+				Ok(Step::Exchange { send: self.connect_request()?, receive: 4 })
+			},
+
+			Socks5Phase::ReplyHeader => {
+				if received[1] != 0x00 {
+					return Err(proxy_error(&format!("SOCKS5 proxy could not connect to the server: {}", socks5_reply_description(received[1]))));
+				}
+
+				// The rest of the reply is the address that the proxy bound to, followed by the port
+				self.phase = Socks5Phase::ReplyAddress;
+				match received[3] {
+					SOCKS5_ADDRESS_IPV4 => Ok(Step::Receive(4 + 2)),
+					SOCKS5_ADDRESS_DOMAIN_NAME => Ok(Step::Receive(1)),
+					SOCKS5_ADDRESS_IPV6 => Ok(Step::Receive(16 + 2)),
+					address_type => Err(proxy_error(&format!("SOCKS5 proxy replied with unknown address type 0x{:02x}", address_type))),
+				}
+			},
+
+			Socks5Phase::ReplyAddress => match received.get(3..5) {
+				Some(&[SOCKS5_ADDRESS_DOMAIN_NAME, len]) if received.len() == 5 => Ok(Step::Receive(usize::from(len) + 2)),
+				_ => Ok(Step::Done),
+			},
+		}
+	}
+}
+
+fn socks5_reply_description(reply: u8) -> &'static str {
+	match reply {
+		0x01 => "general SOCKS server failure",
+		0x02 => "connection not allowed by ruleset",
+		0x03 => "network unreachable",
+		0x04 => "host unreachable",
+		0x05 => "connection refused",
+		0x06 => "TTL expired",
+		0x07 => "command not supported",
+		0x08 => "address type not supported",
+		_ => "unknown error",
+	}
+}
+
+/// The handshake of an [`HttpConnectIoSource`]
+#[derive(Debug)]
+pub struct HttpConnectHandshake {
+	host: String,
+	port: u16,
+	credentials: Option<(String, String)>,
+	started: bool,
+}
+
+/// The maximum length of the proxy's response headers
+const HTTP_MAX_RESPONSE_LEN: usize = 8192;
+
+impl Handshake for HttpConnectHandshake {
+	fn step(&mut self, received: &[u8]) -> std::io::Result<Step> {
+		if !self.started {
+			let authority = match self.host.parse() {
+				Ok(std::net::IpAddr::V6(addr)) => format!("[{}]:{}", addr, self.port),
+				_ => format!("{}:{}", self.host, self.port),
+			};
+
+			let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", authority);
+			if let Some((username, password)) = &self.credentials {
+				let credentials = base64::Engine::encode(&base64::engine::general_purpose::STANDARD, format!("{}:{}", username, password));
+				request.push_str("Proxy-Authorization: Basic ");
+				request.push_str(&credentials);
+				request.push_str("\r\n");
+			}
+			request.push_str("\r\n");
+
+			self.started = true;
+
+			// The response is read a byte at a time, so that nothing after its headers is consumed
+			return Ok(Step::Exchange { send: request.into_bytes(), receive: 1 });
+		}
+
+		if !received.ends_with(b"\r\n\r\n") {
+			if received.len() >= HTTP_MAX_RESPONSE_LEN {
+				return Err(proxy_error("HTTP proxy response headers are too long"));
+			}
+
+			return Ok(Step::Receive(1));
+		}
+
+		let status_line = received.split(|&b| b == b'\r').next().unwrap_or_default();
+		let status_line = String::from_utf8_lossy(status_line);
+		match parse_http_status(&status_line) {
+			Some(200..=299) => Ok(Step::Done),
+			Some(_) => Err(proxy_error(&format!("HTTP proxy could not connect to the server: {}", status_line))),
+			None => Err(proxy_error(&format!("HTTP proxy sent a malformed response: {:?}", status_line))),
+		}
+	}
+}
+
+/// Parses the status code of an HTTP status line, like `HTTP/1.1 200 Connection established`
+fn parse_http_status(status_line: &str) -> Option<u16> {
+	let mut parts = status_line.splitn(3, ' ');
+	let version = parts.next()?;
+	if !version.starts_with("HTTP/1.") {
+		return None;
+	}
+
+	parts.next()?.parse().ok()
+}
+
+fn length_u8(value: &[u8], name: &str) -> std::io::Result<u8> {
+	std::convert::TryInto::try_into(value.len())
+		.map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("{} is too long for SOCKS5", name)))
+}
+
+fn proxy_error(message: &str) -> std::io::Error {
+	std::io::Error::new(std::io::ErrorKind::ConnectionRefused, message)
+}
+
+#[cfg(test)]
+mod tests {
+	#[test]
+	fn parse_http_status() {
+		assert_eq!(super::parse_http_status("HTTP/1.1 200 Connection established"), Some(200));
+		assert_eq!(super::parse_http_status("HTTP/1.0 407 Proxy Authentication Required"), Some(407));
+		assert_eq!(super::parse_http_status("HTTP/1.1 200"), Some(200));
+		assert_eq!(super::parse_http_status("SSH-2.0-OpenSSH"), None);
+		assert_eq!(super::parse_http_status("HTTP/1.1 OK"), None);
+	}
+
+	#[test]
+	fn socks5_connect_request() {
+		let mut handshake = super::Socks5Handshake {
+			host: "broker.example.com".to_owned(),
+			port: 8883,
+			credentials: None,
+			phase: super::Socks5Phase::Start,
+		};
+		assert_eq!(
+			handshake.connect_request().unwrap(),
+			b"\x05\x01\x00\x03\x12broker.example.com\x22\xb3",
+		);
+
+		handshake.host = "10.0.0.1".to_owned();
+		assert_eq!(handshake.connect_request().unwrap(), b"\x05\x01\x00\x01\x0a\x00\x00\x01\x22\xb3");
+
+		handshake.host = "::1".to_owned();
+		assert_eq!(handshake.connect_request().unwrap(), b"\x05\x01\x00\x04\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\x01\x22\xb3");
+	}
+}
@@ -0,0 +1,396 @@
+/*!
+ * Records the byte streams of a client's connections, and replays them later as the [`IoSource`](crate::IoSource) of another client.
+ *
+ * Wrap the `IoSource` of a client that talks to a real server in a [`RecordingIoSource`] to record its connections, for example in production
+ * to capture a server's quirks. A [`ReplayIoSource`] created from the recording then serves the same bytes to a client in a regression test,
+ * without the server.
+ *
+ * A recording is a sequence of records. Each record is one byte for its kind, followed by the kind's data:
+ *
+ * - `0x00`: the client connected to the server. No data.
+ * - `0x01`: the client read bytes from the server. Four bytes for the number of bytes, big-endian, followed by the bytes.
+ * - `0x02`: the client wrote bytes to the server. Same data as `0x01`.
+ * - `0x03`: the server closed the connection, ie the client read EOF. No data.
+ * - `0x04`: reading from the connection failed, like when it is reset. No data.
+ */
+
+const RECORD_CONNECTED: u8 = 0x00;
+const RECORD_READ: u8 = 0x01;
+const RECORD_WRITTEN: u8 = 0x02;
+const RECORD_CLOSED: u8 = 0x03;
+const RECORD_FAILED: u8 = 0x04;
+
+/// An [`IoSource`](crate::IoSource) that records the byte streams of the connections of another `IoSource`.
+///
+/// Each record is flushed as soon as it is written, so that the recording is complete even if the process crashes.
+/// Errors from writing records are logged and otherwise ignored, so that they do not affect the client's connections.
+#[derive(Debug)]
+pub struct RecordingIoSource<S> {
+	io_source: S,
+	recorder: std::sync::Arc<Recorder>,
+}
+
+impl<S> RecordingIoSource<S> {
+	/// Creates an `IoSource` that records the connections of `io_source` to the given writer.
+	pub fn new<W>(io_source: S, writer: W) -> Self where W: std::io::Write + Send + 'static {
+		RecordingIoSource {
+			io_source,
+			recorder: std::sync::Arc::new(Recorder(std::sync::Mutex::new(Box::new(writer)))),
+		}
+	}
+
+	/// Creates an `IoSource` that records the connections of `io_source` to the file at the given path. The file is truncated if it already exists.
+	pub fn create<P>(io_source: S, path: P) -> std::io::Result<Self> where P: AsRef<std::path::Path> {
+		let file = std::fs::File::create(path)?;
+		Ok(RecordingIoSource::new(io_source, std::io::BufWriter::new(file)))
+	}
+}
+
+impl<S> crate::IoSource for RecordingIoSource<S> where S: crate::IoSource {
+	type Io = RecordingIo<S::Io>;
+	type Future = RecordingConnectFuture<S::Future>;
+
+	fn connect(&mut self) -> Self::Future {
+		RecordingConnectFuture {
+			inner: self.io_source.connect(),
+			recorder: self.recorder.clone(),
+		}
+	}
+}
+
+/// The connection future of a [`RecordingIoSource`]
+#[derive(Debug)]
+pub struct RecordingConnectFuture<F> {
+	inner: F,
+	recorder: std::sync::Arc<Recorder>,
+}
+
+impl<F, I> futures::Future for RecordingConnectFuture<F> where F: futures::Future<Item = (I, Option<String>)> {
+	type Item = (RecordingIo<I>, Option<String>);
+	type Error = F::Error;
+
+	fn poll(&mut self) -> futures::Poll<Self::Item, Self::Error> {
+		let (io, password) = futures::try_ready!(self.inner.poll());
+		self.recorder.record(RECORD_CONNECTED, None);
+		Ok(futures::Async::Ready((RecordingIo { inner: io, recorder: self.recorder.clone() }, password)))
+	}
+}
+
+/// A connection of a [`RecordingIoSource`]
+#[derive(Debug)]
+pub struct RecordingIo<T> {
+	inner: T,
+	recorder: std::sync::Arc<Recorder>,
+}
+
+impl<T> std::io::Read for RecordingIo<T> where T: std::io::Read {
+	fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+		match self.inner.read(buf) {
+			Ok(0) => {
+				self.recorder.record(RECORD_CLOSED, None);
+				Ok(0)
+			},
+
+			Ok(read) => {
+				self.recorder.record(RECORD_READ, Some(&buf[..read]));
+				Ok(read)
+			},
+
+			Err(err) => {
+				match err.kind() {
+					std::io::ErrorKind::WouldBlock |
+					std::io::ErrorKind::Interrupted => (),
+					_ => self.recorder.record(RECORD_FAILED, None),
+				}
+				Err(err)
+			},
+		}
+	}
+}
+
+impl<T> tokio_io::AsyncRead for RecordingIo<T> where T: tokio_io::AsyncRead {
+}
+
+impl<T> std::io::Write for RecordingIo<T> where T: std::io::Write {
+	fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+		let written = self.inner.write(buf)?;
+		if written > 0 {
+			self.recorder.record(RECORD_WRITTEN, Some(&buf[..written]));
+		}
+		Ok(written)
+	}
+
+	fn flush(&mut self) -> std::io::Result<()> {
+		self.inner.flush()
+	}
+}
+
+impl<T> tokio_io::AsyncWrite for RecordingIo<T> where T: tokio_io::AsyncWrite {
+	fn shutdown(&mut self) -> futures::Poll<(), std::io::Error> {
+		self.inner.shutdown()
+	}
+}
+
+struct Recorder(std::sync::Mutex<Box<dyn std::io::Write + Send>>);
+
+impl Recorder {
+	fn record(&self, kind: u8, bytes: Option<&[u8]>) {
+		if let Err(err) = self.try_record(kind, bytes) {
+			log::warn!("could not write record to connection recording: {}", err);
+		}
+	}
+
+	fn try_record(&self, kind: u8, bytes: Option<&[u8]>) -> std::io::Result<()> {
+		let mut record = vec![kind];
+		if let Some(bytes) = bytes {
+			// Reads and writes are never anywhere near 4 GiB, since they are done with buffers of a few KiB
+			let len: u32 = std::convert::TryInto::try_into(bytes.len()).map_err(|_| std::io::Error::from(std::io::ErrorKind::InvalidInput))?;
+			record.extend_from_slice(&len.to_be_bytes());
+			record.extend_from_slice(bytes);
+		}
+
+		// A poisoned writer is still usable. At worst the record that was being written when the lock was poisoned is incomplete.
+		let mut writer = self.0.lock().unwrap_or_else(std::sync::PoisonError::into_inner);
+		writer.write_all(&record)?;
+		writer.flush()?;
+		Ok(())
+	}
+}
+
+impl std::fmt::Debug for Recorder {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("Recorder").finish_non_exhaustive()
+	}
+}
+
+/// An [`IoSource`](crate::IoSource) that replays the connections in a recording that was written by a [`RecordingIoSource`].
+///
+/// The first connection that the client makes replays the first recorded connection, its first reconnection replays the second one, and so on.
+/// Once all the recorded connections have been used up, new connections never complete.
+///
+/// Each connection serves the bytes that the client read in the recording, in the same order. Bytes that the client read after it had written
+/// some bytes are only served once the client has written at least as many bytes on the replayed connection, so that the server's responses
+/// are not read before the client's requests. The bytes that the client writes are otherwise ignored.
+///
+/// After the recorded bytes have been served, the connection is closed or fails if it was closed or failed in the recording.
+/// Otherwise the server goes silent, ie there is nothing more to read but the connection stays open.
+#[derive(Debug)]
+pub struct ReplayIoSource {
+	connections: std::collections::VecDeque<ReplayIo>,
+}
+
+impl ReplayIoSource {
+	/// Reads the recording from the given reader.
+	pub fn new<R>(mut reader: R) -> Result<Self, ReplayError> where R: std::io::Read {
+		let mut connections = std::collections::VecDeque::new();
+
+		loop {
+			let mut kind = [0_u8; 1];
+			match reader.read(&mut kind) {
+				Ok(0) => break,
+				Ok(_) => (),
+				Err(ref err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+				Err(err) => return Err(ReplayError::Io(err)),
+			}
+
+			if kind[0] == RECORD_CONNECTED {
+				connections.push_back(ReplayIo {
+					chunks: Default::default(),
+					end: ReplayEnd::Silent,
+					written: 0,
+					recorded_written: 0,
+				});
+				continue;
+			}
+
+			let connection = connections.back_mut().ok_or(ReplayError::RecordBeforeConnected(kind[0]))?;
+
+			match kind[0] {
+				RECORD_READ => {
+					let bytes = read_bytes(&mut reader)?;
+					connection.chunks.push_back(ReplayChunk { written_before: connection.recorded_written, bytes: std::io::Cursor::new(bytes) });
+				},
+				RECORD_WRITTEN => connection.recorded_written += read_bytes(&mut reader)?.len(),
+				RECORD_CLOSED => connection.end = ReplayEnd::Closed,
+				RECORD_FAILED => connection.end = ReplayEnd::Failed,
+				kind => return Err(ReplayError::UnrecognizedRecord(kind)),
+			}
+		}
+
+		Ok(ReplayIoSource { connections })
+	}
+
+	/// Reads the recording from the file at the given path.
+	pub fn open<P>(path: P) -> Result<Self, ReplayError> where P: AsRef<std::path::Path> {
+		let file = std::fs::File::open(path).map_err(ReplayError::Io)?;
+		ReplayIoSource::new(std::io::BufReader::new(file))
+	}
+}
+
+fn read_bytes<R>(reader: &mut R) -> Result<Vec<u8>, ReplayError> where R: std::io::Read {
+	let mut len = [0_u8; 4];
+	reader.read_exact(&mut len).map_err(ReplayError::Io)?;
+	let len = u32::from_be_bytes(len) as usize;
+
+	let mut bytes = vec![0_u8; len];
+	reader.read_exact(&mut bytes).map_err(ReplayError::Io)?;
+	Ok(bytes)
+}
+
+impl crate::IoSource for ReplayIoSource {
+	type Io = ReplayIo;
+	type Future = Box<dyn futures::Future<Item = (Self::Io, Option<String>), Error = std::io::Error> + Send>;
+
+	fn connect(&mut self) -> Self::Future {
+		match self.connections.pop_front() {
+			Some(io) => Box::new(futures::future::ok((io, None))),
+
+			None => {
+				log::debug!("replay has no more recorded connections, so the new connection never completes");
+				Box::new(futures::future::empty())
+			},
+		}
+	}
+}
+
+/// A connection of a [`ReplayIoSource`]
+#[derive(Debug)]
+pub struct ReplayIo {
+	chunks: std::collections::VecDeque<ReplayChunk>,
+	end: ReplayEnd,
+
+	/// The number of bytes that the client has written to this connection
+	written: usize,
+
+	/// The number of bytes that the client wrote to the recorded connection. Only used while reading the recording.
+	recorded_written: usize,
+}
+
+#[derive(Debug)]
+struct ReplayChunk {
+	/// The number of bytes that the client had written to the recorded connection before it read this chunk
+	written_before: usize,
+	bytes: std::io::Cursor<Vec<u8>>,
+}
+
+#[derive(Debug)]
+enum ReplayEnd {
+	Closed,
+	Failed,
+	Silent,
+}
+
+impl std::io::Read for ReplayIo {
+	fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+		match self.chunks.front_mut() {
+			Some(chunk) if chunk.written_before <= self.written => {
+				let read = std::io::Read::read(&mut chunk.bytes, buf)?;
+				if chunk.bytes.position() == chunk.bytes.get_ref().len() as u64 {
+					let _ = self.chunks.pop_front();
+				}
+				Ok(read)
+			},
+
+			// The client writes what it's going to write without needing to read anything first, and it writes and reads in the same task,
+			// so there is no need to register for a wakeup. Likewise for a silent server, since the client only reads again
+			// after it has written something or its timers fire.
+			Some(_) => Err(std::io::ErrorKind::WouldBlock.into()),
+
+			None => match self.end {
+				ReplayEnd::Closed => Ok(0),
+				ReplayEnd::Failed => Err(std::io::ErrorKind::ConnectionReset.into()),
+				ReplayEnd::Silent => Err(std::io::ErrorKind::WouldBlock.into()),
+			},
+		}
+	}
+}
+
+impl tokio_io::AsyncRead for ReplayIo {
+}
+
+impl std::io::Write for ReplayIo {
+	fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+		self.written += buf.len();
+		Ok(buf.len())
+	}
+
+	fn flush(&mut self) -> std::io::Result<()> {
+		Ok(())
+	}
+}
+
+impl tokio_io::AsyncWrite for ReplayIo {
+	fn shutdown(&mut self) -> futures::Poll<(), std::io::Error> {
+		Ok(futures::Async::Ready(()))
+	}
+}
+
+/// An error from reading a recording with [`ReplayIoSource::new`]
+#[derive(Debug)]
+pub enum ReplayError {
+	Io(std::io::Error),
+	RecordBeforeConnected(u8),
+	UnrecognizedRecord(u8),
+}
+
+impl std::fmt::Display for ReplayError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			ReplayError::Io(err) => write!(f, "I/O error: {}", err),
+			ReplayError::RecordBeforeConnected(kind) => write!(f, "record of kind 0x{:02X} is not preceded by a connection", kind),
+			ReplayError::UnrecognizedRecord(kind) => write!(f, "could not parse record kind 0x{:02X}", kind),
+		}
+	}
+}
+
+impl std::error::Error for ReplayError {
+	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+		match self {
+			ReplayError::Io(err) => Some(err),
+			ReplayError::RecordBeforeConnected(_) |
+			ReplayError::UnrecognizedRecord(_) => None,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	#[test]
+	fn replay_waits_for_writes() {
+		let recording = [
+			&[super::RECORD_CONNECTED][..],
+			&[super::RECORD_READ, 0x00, 0x00, 0x00, 0x01, 0xAA],
+			&[super::RECORD_WRITTEN, 0x00, 0x00, 0x00, 0x02, 0x01, 0x02],
+			&[super::RECORD_READ, 0x00, 0x00, 0x00, 0x01, 0xBB],
+			&[super::RECORD_CLOSED],
+			&[super::RECORD_CONNECTED],
+			&[super::RECORD_FAILED],
+		].concat();
+
+		let mut io_source = super::ReplayIoSource::new(&recording[..]).unwrap();
+		assert_eq!(io_source.connections.len(), 2);
+
+		let mut io = io_source.connections.pop_front().unwrap();
+		let mut buf = [0_u8; 8];
+		assert_eq!(std::io::Read::read(&mut io, &mut buf).unwrap(), 1);
+		assert_eq!(buf[0], 0xAA);
+
+		// The second read was recorded after two bytes were written
+		assert_eq!(std::io::Read::read(&mut io, &mut buf).unwrap_err().kind(), std::io::ErrorKind::WouldBlock);
+		assert_eq!(std::io::Write::write(&mut io, &[0x01]).unwrap(), 1);
+		assert_eq!(std::io::Read::read(&mut io, &mut buf).unwrap_err().kind(), std::io::ErrorKind::WouldBlock);
+		assert_eq!(std::io::Write::write(&mut io, &[0x02]).unwrap(), 1);
+		assert_eq!(std::io::Read::read(&mut io, &mut buf).unwrap(), 1);
+		assert_eq!(buf[0], 0xBB);
+
+		assert_eq!(std::io::Read::read(&mut io, &mut buf).unwrap(), 0);
+
+		let mut io = io_source.connections.pop_front().unwrap();
+		assert_eq!(std::io::Read::read(&mut io, &mut buf).unwrap_err().kind(), std::io::ErrorKind::ConnectionReset);
+
+		match super::ReplayIoSource::new(&[super::RECORD_READ, 0x00, 0x00, 0x00, 0x00][..]) {
+			Err(super::ReplayError::RecordBeforeConnected(super::RECORD_READ)) => (),
+			result => panic!("expected RecordBeforeConnected but got {:?}", result),
+		}
+	}
+}
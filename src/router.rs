@@ -0,0 +1,205 @@
+/*!
+ * A [`Router`] that splits the publications received by a [`Client`](crate::Client) into separate streams by topic.
+ */
+
+use futures::{ Future, Stream };
+
+/// Dispatches the publications received by a [`Client`](crate::Client) to a separate [`RouteStream`] for each topic filter.
+///
+/// Wrap the client with [`Router::new`], then call [`Router::route`] for each topic filter that a component of the application wants
+/// to receive publications for. The router itself is a [`Stream`] of the client's events, and must be polled for the routes to receive anything.
+///
+/// A publication is sent to every route whose topic filter matches its topic name. Publications that don't match any route,
+/// as well as all other events, are yielded by the router as is.
+///
+/// The router does not subscribe to anything by itself. The topic filters must still be subscribed to with the client.
+#[derive(Debug)]
+pub struct Router<S> {
+	events: S,
+	routes: Vec<Route>,
+}
+
+impl<S> Router<S> {
+	/// Creates a router for the given stream of client events. This is usually the [`Client`](crate::Client) itself.
+	pub fn new(events: S) -> Self {
+		Router {
+			events,
+			routes: vec![],
+		}
+	}
+
+	/// Returns a stream of the publications whose topic name matches the given topic filter.
+	///
+	/// The route is removed once the returned stream is dropped. The stream ends when the router is dropped or the client's events end.
+	pub fn route(&mut self, topic_filter: String) -> RouteStream {
+		self.add_route(topic_filter, false)
+	}
+
+	/// Subscribes to the given topic filter and collects the retained publications that the server sends for it.
+	///
+	/// The returned [`Future`] resolves once no retained publication has been received for `settling_window`,
+	/// counting from when the subscription request has been received by the client. This is useful for bootstrapping
+	/// state like configuration from retained topics.
+	///
+	/// Only retained publications are taken by this method. Publications that are not retained are routed as usual.
+	/// The subscription remains in place after the future has resolved.
+	pub fn subscribe_and_collect_retained(
+		&mut self,
+		update_subscription_handle: &mut crate::UpdateSubscriptionHandle,
+		subscribe_to: crate::proto::SubscribeTo,
+		settling_window: std::time::Duration,
+	) -> CollectRetainedFuture {
+		let route = self.add_route(subscribe_to.topic_filter.to_string(), true);
+		let subscribe = update_subscription_handle.subscribe(subscribe_to);
+
+		CollectRetainedFuture {
+			state: CollectRetainedFutureState::Subscribing(subscribe),
+			route,
+			settling_window,
+			publications: vec![],
+		}
+	}
+
+	fn add_route(&mut self, topic_filter: String, retained_only: bool) -> RouteStream {
+		let (sender, receiver) = futures::sync::mpsc::unbounded();
+		self.routes.push(Route { topic_filter, retained_only, sender });
+		RouteStream(receiver)
+	}
+}
+
+impl<S> Stream for Router<S> where S: Stream<Item = crate::Event> {
+	type Item = crate::Event;
+	type Error = S::Error;
+
+	fn poll(&mut self) -> futures::Poll<Option<Self::Item>, Self::Error> {
+		loop {
+			match futures::try_ready!(self.events.poll()) {
+				Some(crate::Event::Publication(publication)) => {
+					let mut routed = false;
+
+					self.routes.retain(|route| {
+						if
+							(route.retained_only && !publication.retain) ||
+							!crate::topic::matches(&route.topic_filter, &publication.topic_name)
+						{
+							return true;
+						}
+
+						if route.sender.unbounded_send(publication.clone()).is_err() {
+							// The route's stream was dropped
+							return false;
+						}
+
+						routed = true;
+						true
+					});
+
+					if !routed {
+						return Ok(futures::Async::Ready(Some(crate::Event::Publication(publication))));
+					}
+				},
+
+				event => return Ok(futures::Async::Ready(event)),
+			}
+		}
+	}
+}
+
+#[derive(Debug)]
+struct Route {
+	topic_filter: String,
+
+	/// Set for the routes created by `Router::subscribe_and_collect_retained`
+	retained_only: bool,
+
+	sender: futures::sync::mpsc::UnboundedSender<crate::ReceivedPublication>,
+}
+
+/// A stream of the publications that matched a topic filter, returned by [`Router::route`]
+#[derive(Debug)]
+pub struct RouteStream(futures::sync::mpsc::UnboundedReceiver<crate::ReceivedPublication>);
+
+impl Stream for RouteStream {
+	type Item = crate::ReceivedPublication;
+	type Error = ();
+
+	fn poll(&mut self) -> futures::Poll<Option<Self::Item>, Self::Error> {
+		self.0.poll()
+	}
+}
+
+/// The [`Future`] returned by [`Router::subscribe_and_collect_retained`]
+///
+/// It resolves to the retained publications received for the subscription, in the order that they were received.
+#[derive(Debug)]
+#[must_use = "futures do nothing unless polled"]
+pub struct CollectRetainedFuture {
+	state: CollectRetainedFutureState,
+	route: RouteStream,
+	settling_window: std::time::Duration,
+	publications: Vec<crate::ReceivedPublication>,
+}
+
+#[derive(Debug)]
+enum CollectRetainedFutureState {
+	Subscribing(crate::UpdateSubscriptionFuture),
+	Collecting(tokio_timer::Delay),
+}
+
+impl Future for CollectRetainedFuture {
+	type Item = Vec<crate::ReceivedPublication>;
+	type Error = CollectRetainedError;
+
+	fn poll(&mut self) -> futures::Poll<Self::Item, Self::Error> {
+		loop {
+			match &mut self.state {
+				CollectRetainedFutureState::Subscribing(subscribe) => {
+					let () = futures::try_ready!(subscribe.poll().map_err(CollectRetainedError::UpdateSubscription));
+					self.state = CollectRetainedFutureState::Collecting(tokio_timer::Delay::new(tokio_timer::clock::now() + self.settling_window));
+				},
+
+				CollectRetainedFutureState::Collecting(settling_timer) => {
+					while let futures::Async::Ready(publication) = self.route.poll().expect("RouteStream::poll cannot fail") {
+						match publication {
+							Some(publication) => {
+								self.publications.push(publication);
+								settling_timer.reset(tokio_timer::clock::now() + self.settling_window);
+							},
+
+							// The router has been dropped, so no more publications will be received
+							None => return Ok(futures::Async::Ready(std::mem::take(&mut self.publications))),
+						}
+					}
+
+					let () = futures::try_ready!(settling_timer.poll().map_err(CollectRetainedError::Timer));
+					return Ok(futures::Async::Ready(std::mem::take(&mut self.publications)));
+				},
+			}
+		}
+	}
+}
+
+/// Errors from collecting retained publications with [`Router::subscribe_and_collect_retained`]
+#[derive(Debug)]
+pub enum CollectRetainedError {
+	Timer(tokio_timer::Error),
+	UpdateSubscription(crate::UpdateSubscriptionError),
+}
+
+impl std::fmt::Display for CollectRetainedError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			CollectRetainedError::Timer(err) => write!(f, "settling timer failed: {}", err),
+			CollectRetainedError::UpdateSubscription(err) => write!(f, "could not subscribe: {}", err),
+		}
+	}
+}
+
+impl std::error::Error for CollectRetainedError {
+	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+		match self {
+			CollectRetainedError::Timer(err) => Some(err),
+			CollectRetainedError::UpdateSubscription(err) => Some(err),
+		}
+	}
+}
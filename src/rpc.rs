@@ -0,0 +1,620 @@
+/*!
+ * Remote procedure calls over MQTT, with an [`RpcServer`] that handles the calls of methods and an [`RpcClient`] that makes them.
+ *
+ * MQTT 3.1.1 publications have no properties for a reply topic or a correlation ID, so both are part of the topic names:
+ *
+ * - A call of `method` is published to `{request_prefix}/{method}/{caller_id}/{correlation_id}`, with the request as the payload.
+ * - Its response is published to `{response_prefix}/{caller_id}/{correlation_id}/ok`, or to `.../error` if the method failed,
+ *   with the response or the error as the payload.
+ *
+ * The caller ID identifies the `RpcClient`, so that it only receives the responses to its own calls. It must be unique,
+ * like the client ID of its [`Client`](crate::Client). Methods can have `/` in their names, to group them into levels.
+ *
+ * Both sides get the publications of their topics from a [`Router`](crate::router::Router), and subscribe to them themselves:
+ * the server to the request topics of the methods that it handles, and the client to the response topics of its caller ID.
+ * The `RpcServer` and the [`RpcDispatcher`] of an `RpcClient` are futures that must be spawned on the runtime, alongside the router.
+ *
+ * ```no_run
+ * # use futures::{ Future, Stream };
+ * # let addr = "127.0.0.1:1883".parse().unwrap();
+ * # let io_source = move || tokio::net::TcpStream::connect(&addr).map(|io| (io, None));
+ * let client =
+ *     mqtt::Client::new(Some("thermostat".to_owned()), None, None, io_source, std::time::Duration::from_secs(30), std::time::Duration::from_secs(240));
+ * let mut update_subscription_handle = client.update_subscription_handle().unwrap();
+ * let server_publish_handle = client.publish_handle().unwrap();
+ * let client_publish_handle = client.publish_handle().unwrap();
+ * let mut router = mqtt::router::Router::new(client);
+ *
+ * let mut server = mqtt::rpc::RpcServer::new(&mut router, server_publish_handle, "thermostat/methods", "thermostat/responses").unwrap();
+ * server.handle(&mut update_subscription_handle, "set_temperature", |request: mqtt::rpc::RpcRequest| {
+ *     // Apply request.payload, then respond with an empty payload
+ *     Ok::<_, bytes::Bytes>(bytes::Bytes::new())
+ * }).unwrap();
+ *
+ * let (mut caller, dispatcher) =
+ *     mqtt::rpc::RpcClient::new(&mut router, &mut update_subscription_handle, client_publish_handle, "thermostat/methods", "thermostat/responses", "app")
+ *     .unwrap();
+ * let call = caller.call("set_temperature", "21.5".into(), std::time::Duration::from_secs(10));
+ *
+ * tokio::run(futures::lazy(move || {
+ *     tokio::spawn(server);
+ *     tokio::spawn(dispatcher);
+ *     tokio::spawn(call.map(|_response| ()).map_err(|err| eprintln!("call failed: {}", err)));
+ *     router.for_each(|_event| Ok(())).map_err(|err| eprintln!("client failed: {}", err))
+ * }));
+ * ```
+ */
+
+use futures::{ Future, IntoFuture, Stream };
+
+/// A call received by an [`RpcServer`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RpcRequest {
+	pub method: String,
+	pub caller_id: String,
+	pub payload: bytes::Bytes,
+}
+
+/// Handles the calls of methods that are published to the request topics under a prefix, and publishes their responses.
+///
+/// The server is a [`Future`] that must be spawned on the runtime. It handles calls concurrently, and resolves once the router is dropped
+/// and the calls that were being handled have been responded to.
+#[must_use = "futures do nothing unless polled"]
+pub struct RpcServer {
+	route: crate::router::RouteStream,
+	route_ended: bool,
+	publish_handle: crate::PublishHandle,
+	request_prefix: String,
+	response_prefix: String,
+	qos: crate::proto::QoS,
+	handlers: std::collections::BTreeMap<String, Handler>,
+	subscriptions: Vec<crate::UpdateSubscriptionFuture>,
+	calls: futures::stream::FuturesUnordered<CallHandlerFuture>,
+	responses: futures::stream::FuturesUnordered<crate::PublishFuture>,
+}
+
+type Handler = Box<dyn FnMut(RpcRequest) -> Box<dyn Future<Item = bytes::Bytes, Error = bytes::Bytes> + Send> + Send>;
+
+impl RpcServer {
+	/// Creates a server for the methods whose request topics start with the given request prefix, like `thermostat/methods`.
+	/// Their responses are published under the given response prefix.
+	///
+	/// Methods are added with [`RpcServer::handle`].
+	pub fn new<S>(
+		router: &mut crate::router::Router<S>,
+		publish_handle: crate::PublishHandle,
+		request_prefix: &str,
+		response_prefix: &str,
+	) -> Result<Self, RpcError> {
+		validate_prefix(request_prefix)?;
+		validate_prefix(response_prefix)?;
+
+		Ok(RpcServer {
+			route: router.route(format!("{}/#", request_prefix)),
+			route_ended: false,
+			publish_handle,
+			request_prefix: request_prefix.to_owned(),
+			response_prefix: response_prefix.to_owned(),
+			qos: crate::proto::QoS::AtLeastOnce,
+			handlers: Default::default(),
+			subscriptions: vec![],
+			calls: futures::stream::FuturesUnordered::new(),
+			responses: futures::stream::FuturesUnordered::new(),
+		})
+	}
+
+	/// Sets the quality of service of the subscriptions to the request topics and of the responses.
+	///
+	/// Defaults to [`QoS::AtLeastOnce`](crate::proto::QoS::AtLeastOnce).
+	pub fn set_qos(&mut self, qos: crate::proto::QoS) {
+		self.qos = qos;
+	}
+
+	/// Handles the calls of the given method with the given handler, and subscribes to the method's request topics.
+	///
+	/// The handler's future resolves to the method's response, or fails with its error. Both are sent back to the caller.
+	/// A method that already has a handler gets the new one instead.
+	pub fn handle<F, R>(&mut self, update_subscription_handle: &mut crate::UpdateSubscriptionHandle, method: &str, mut handler: F) -> Result<(), RpcError>
+	where
+		F: FnMut(RpcRequest) -> R + Send + 'static,
+		R: IntoFuture<Item = bytes::Bytes, Error = bytes::Bytes>,
+		R::Future: Send + 'static,
+	{
+		if method.is_empty() || method.contains(['+', '#']) || method.starts_with('/') || method.ends_with('/') {
+			return Err(RpcError::InvalidMethod(method.to_owned()));
+		}
+
+		let topic_filter = format!("{}/{}/+/+", self.request_prefix, method);
+		let topic_filter = crate::topic::TopicFilter::new(topic_filter).map_err(|_| RpcError::InvalidMethod(method.to_owned()))?;
+		self.subscriptions.push(update_subscription_handle.subscribe(crate::proto::SubscribeTo { topic_filter, qos: self.qos }));
+
+		let _ = self.handlers.insert(method.to_owned(), Box::new(move |request| Box::new(handler(request).into_future())));
+		Ok(())
+	}
+
+	/// Parses the method, caller ID and correlation ID out of the topic name of a request
+	fn parse_request_topic<'a>(&self, topic_name: &'a str) -> Option<(&'a str, &'a str, &'a str)> {
+		let topic_name = topic_name.strip_prefix(&*self.request_prefix)?.strip_prefix('/')?;
+		let mut levels = topic_name.rsplitn(3, '/');
+		let correlation_id = levels.next()?;
+		let caller_id = levels.next()?;
+		let method = levels.next()?;
+		Some((method, caller_id, correlation_id))
+	}
+}
+
+impl Future for RpcServer {
+	type Item = ();
+	type Error = ();
+
+	fn poll(&mut self) -> futures::Poll<Self::Item, Self::Error> {
+		self.subscriptions.retain_mut(|subscription| match subscription.poll() {
+			Ok(futures::Async::Ready(())) => false,
+			Ok(futures::Async::NotReady) => true,
+			Err(err) => {
+				log::warn!("could not subscribe to RPC requests: {}", err);
+				false
+			},
+		});
+
+		while !self.route_ended {
+			let publication = match self.route.poll()? {
+				futures::Async::Ready(Some(publication)) => publication,
+				futures::Async::Ready(None) => {
+					self.route_ended = true;
+					break;
+				},
+				futures::Async::NotReady => break,
+			};
+
+			let (method, caller_id, correlation_id) = match self.parse_request_topic(&publication.topic_name) {
+				Some(request) => request,
+				None => {
+					log::debug!("ignoring RPC request with malformed topic name {:?}", publication.topic_name);
+					continue;
+				},
+			};
+
+			// Requests for methods without a handler are only received when another subscription of the client overlaps with the request topics
+			let handler = match self.handlers.get_mut(method) {
+				Some(handler) => handler,
+				None => continue,
+			};
+
+			let response_topic_name = format!("{}/{}/{}", self.response_prefix, caller_id, correlation_id);
+			let request = RpcRequest {
+				method: method.to_owned(),
+				caller_id: caller_id.to_owned(),
+				payload: publication.payload,
+			};
+			self.calls.push(CallHandlerFuture {
+				response_topic_name: Some(response_topic_name),
+				inner: handler(request),
+			});
+		}
+
+		while let futures::Async::Ready(Some((response_topic_name, response))) = self.calls.poll()? {
+			let (response_topic_name, payload) = match response {
+				Ok(payload) => (format!("{}/ok", response_topic_name), payload),
+				Err(payload) => (format!("{}/error", response_topic_name), payload),
+			};
+
+			let topic_name = match crate::topic::TopicName::new(response_topic_name) {
+				Ok(topic_name) => topic_name,
+				Err(err) => {
+					log::warn!("could not respond to RPC request: {}", err);
+					continue;
+				},
+			};
+
+			self.responses.push(self.publish_handle.publish(crate::proto::Publication {
+				topic_name,
+				qos: self.qos,
+				retain: false,
+				payload,
+			}));
+		}
+
+		loop {
+			match self.responses.poll() {
+				Ok(futures::Async::Ready(Some(()))) => (),
+				Ok(futures::Async::Ready(None) | futures::Async::NotReady) => break,
+				Err(err) => log::warn!("could not publish RPC response: {}", err),
+			}
+		}
+
+		if self.route_ended && self.calls.is_empty() && self.responses.is_empty() {
+			Ok(futures::Async::Ready(()))
+		}
+		else {
+			Ok(futures::Async::NotReady)
+		}
+	}
+}
+
+impl std::fmt::Debug for RpcServer {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("RpcServer")
+			.field("request_prefix", &self.request_prefix)
+			.field("response_prefix", &self.response_prefix)
+			.field("qos", &self.qos)
+			.field("methods", &self.handlers.keys().collect::<Vec<_>>())
+			.finish_non_exhaustive()
+	}
+}
+
+/// A call that an [`RpcServer`] is handling, along with the topic name that its response is published under
+struct CallHandlerFuture {
+	response_topic_name: Option<String>,
+	inner: Box<dyn Future<Item = bytes::Bytes, Error = bytes::Bytes> + Send>,
+}
+
+impl Future for CallHandlerFuture {
+	type Item = (String, Result<bytes::Bytes, bytes::Bytes>);
+	type Error = ();
+
+	fn poll(&mut self) -> futures::Poll<Self::Item, Self::Error> {
+		let response = match self.inner.poll() {
+			Ok(futures::Async::Ready(response)) => Ok(response),
+			Ok(futures::Async::NotReady) => return Ok(futures::Async::NotReady),
+			Err(err) => Err(err),
+		};
+
+		let response_topic_name = self.response_topic_name.take().expect("polled after completion");
+		Ok(futures::Async::Ready((response_topic_name, response)))
+	}
+}
+
+/// Calls the methods of an [`RpcServer`].
+///
+/// The responses are received by the [`RpcDispatcher`] that is created along with the client, which must be spawned on the runtime.
+/// Any number of calls can be in progress at the same time.
+pub struct RpcClient {
+	publish_handle: crate::PublishHandle,
+	request_prefix: String,
+	caller_id: String,
+	qos: crate::proto::QoS,
+	next_correlation_id: u64,
+	pending_calls: PendingCalls,
+}
+
+type PendingCalls = std::sync::Arc<std::sync::Mutex<std::collections::BTreeMap<u64, futures::sync::oneshot::Sender<Result<bytes::Bytes, bytes::Bytes>>>>>;
+
+impl RpcClient {
+	/// Creates a client that calls the methods of the server with the given request and response prefixes, with the given caller ID.
+	///
+	/// The dispatcher subscribes to the response topics of the caller ID. Calls that are made before the server has acked the subscription
+	/// may get their response before the subscription is in place, so wait for the [`Event::SubscriptionUpdates`](crate::Event::SubscriptionUpdates)
+	/// for it if the server responds quickly.
+	pub fn new<S>(
+		router: &mut crate::router::Router<S>,
+		update_subscription_handle: &mut crate::UpdateSubscriptionHandle,
+		publish_handle: crate::PublishHandle,
+		request_prefix: &str,
+		response_prefix: &str,
+		caller_id: &str,
+	) -> Result<(Self, RpcDispatcher), RpcError> {
+		validate_prefix(request_prefix)?;
+		validate_prefix(response_prefix)?;
+		if caller_id.is_empty() || caller_id.contains(['/', '+', '#']) {
+			return Err(RpcError::InvalidCallerId(caller_id.to_owned()));
+		}
+
+		let topic_filter = format!("{}/{}/#", response_prefix, caller_id);
+		let route = router.route(topic_filter.clone());
+		let topic_filter = crate::topic::TopicFilter::new(topic_filter).map_err(|_| RpcError::InvalidCallerId(caller_id.to_owned()))?;
+		let subscription = update_subscription_handle.subscribe(crate::proto::SubscribeTo { topic_filter, qos: crate::proto::QoS::AtLeastOnce });
+
+		let pending_calls: PendingCalls = Default::default();
+
+		let client = RpcClient {
+			publish_handle,
+			request_prefix: request_prefix.to_owned(),
+			caller_id: caller_id.to_owned(),
+			qos: crate::proto::QoS::AtLeastOnce,
+			next_correlation_id: 0,
+			pending_calls: pending_calls.clone(),
+		};
+
+		let dispatcher = RpcDispatcher {
+			subscription: Some(subscription),
+			route,
+			response_prefix: format!("{}/{}/", response_prefix, caller_id),
+			pending_calls,
+		};
+
+		Ok((client, dispatcher))
+	}
+
+	/// Sets the quality of service of the requests.
+	///
+	/// Defaults to [`QoS::AtLeastOnce`](crate::proto::QoS::AtLeastOnce).
+	pub fn set_qos(&mut self, qos: crate::proto::QoS) {
+		self.qos = qos;
+	}
+
+	/// Checks whether the client can accept a new request for a call, like [`PublishHandle::poll_ready`](crate::PublishHandle::poll_ready).
+	pub fn poll_ready(&mut self) -> futures::Poll<(), crate::PublishError> {
+		self.publish_handle.poll_ready()
+	}
+
+	/// Calls the given method with the given request.
+	///
+	/// The returned future resolves to the method's response, or fails with [`CallError::Remote`] if the method failed.
+	/// It fails with [`CallError::Timeout`] if there is no response within the given timeout, counting from now. A response that is received
+	/// after that, or after the future is dropped, is ignored.
+	pub fn call(&mut self, method: &str, payload: bytes::Bytes, timeout: std::time::Duration) -> CallFuture {
+		let correlation_id = self.next_correlation_id;
+		self.next_correlation_id = self.next_correlation_id.wrapping_add(1);
+
+		let timeout = tokio_timer::Delay::new(tokio_timer::clock::now() + timeout);
+
+		let topic_name = format!("{}/{}/{}/{}", self.request_prefix, method, self.caller_id, correlation_id);
+		let topic_name = match crate::topic::TopicName::new(topic_name) {
+			Ok(topic_name) if !method.is_empty() => topic_name,
+			_ => return CallFuture {
+				state: CallState::Failed(Some(CallError::InvalidMethod(method.to_owned()))),
+				timeout,
+				correlation_id: None,
+			},
+		};
+
+		let (response_sender, response_receiver) = futures::sync::oneshot::channel();
+		let _ = lock(&self.pending_calls).insert(correlation_id, response_sender);
+
+		let publish = self.publish_handle.publish(crate::proto::Publication {
+			topic_name,
+			qos: self.qos,
+			retain: false,
+			payload,
+		});
+
+		CallFuture {
+			state: CallState::Publishing(publish, Some(response_receiver)),
+			timeout,
+			correlation_id: Some((correlation_id, self.pending_calls.clone())),
+		}
+	}
+}
+
+impl std::fmt::Debug for RpcClient {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("RpcClient")
+			.field("request_prefix", &self.request_prefix)
+			.field("caller_id", &self.caller_id)
+			.field("qos", &self.qos)
+			.finish_non_exhaustive()
+	}
+}
+
+/// Receives the responses to the calls of an [`RpcClient`], and hands them to the calls' futures.
+///
+/// The dispatcher is a [`Future`] that must be spawned on the runtime. It resolves once the router is dropped,
+/// which fails the calls that are still waiting for a response with [`CallError::DispatcherDropped`].
+#[must_use = "futures do nothing unless polled"]
+pub struct RpcDispatcher {
+	subscription: Option<crate::UpdateSubscriptionFuture>,
+	route: crate::router::RouteStream,
+
+	/// The response prefix followed by the caller ID, ie everything in the response topic names before the correlation ID
+	response_prefix: String,
+
+	pending_calls: PendingCalls,
+}
+
+impl Future for RpcDispatcher {
+	type Item = ();
+	type Error = ();
+
+	fn poll(&mut self) -> futures::Poll<Self::Item, Self::Error> {
+		if let Some(subscription) = &mut self.subscription {
+			match subscription.poll() {
+				Ok(futures::Async::Ready(())) => self.subscription = None,
+				Ok(futures::Async::NotReady) => (),
+				Err(err) => {
+					log::warn!("could not subscribe to RPC responses: {}", err);
+					self.subscription = None;
+				},
+			}
+		}
+
+		loop {
+			let publication = match futures::try_ready!(self.route.poll()) {
+				Some(publication) => publication,
+				None => {
+					lock(&self.pending_calls).clear();
+					return Ok(futures::Async::Ready(()));
+				},
+			};
+
+			let response =
+				publication.topic_name.strip_prefix(&*self.response_prefix)
+				.and_then(|response| {
+					let (correlation_id, status) = response.split_once('/')?;
+					Some((correlation_id.parse::<u64>().ok()?, status))
+				});
+			let (correlation_id, response) = match response {
+				Some((correlation_id, "ok")) => (correlation_id, Ok(publication.payload)),
+				Some((correlation_id, "error")) => (correlation_id, Err(publication.payload)),
+				_ => {
+					log::debug!("ignoring RPC response with malformed topic name {:?}", publication.topic_name);
+					continue;
+				},
+			};
+
+			// The call may have timed out or been dropped already, or this is a duplicate of a QoS 1 response
+			if let Some(response_sender) = lock(&self.pending_calls).remove(&correlation_id) {
+				let _ = response_sender.send(response);
+			}
+		}
+	}
+}
+
+impl std::fmt::Debug for RpcDispatcher {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("RpcDispatcher")
+			.field("response_prefix", &self.response_prefix)
+			.finish_non_exhaustive()
+	}
+}
+
+/// The [`Future`] returned by [`RpcClient::call`]
+///
+/// It resolves to the response of the called method.
+#[must_use = "futures do nothing unless polled"]
+pub struct CallFuture {
+	state: CallState,
+	timeout: tokio_timer::Delay,
+
+	/// Used to remove the call from the pending calls when the future is dropped
+	correlation_id: Option<(u64, PendingCalls)>,
+}
+
+enum CallState {
+	Failed(Option<CallError>),
+	Publishing(crate::PublishFuture, Option<futures::sync::oneshot::Receiver<Result<bytes::Bytes, bytes::Bytes>>>),
+	WaitingForResponse(futures::sync::oneshot::Receiver<Result<bytes::Bytes, bytes::Bytes>>),
+}
+
+impl Future for CallFuture {
+	type Item = bytes::Bytes;
+	type Error = CallError;
+
+	fn poll(&mut self) -> futures::Poll<Self::Item, Self::Error> {
+		loop {
+			match &mut self.state {
+				CallState::Failed(err) => return Err(err.take().expect("polled after completion")),
+
+				CallState::Publishing(publish, response_receiver) => match publish.poll() {
+					Ok(futures::Async::Ready(())) => {
+						let response_receiver = response_receiver.take().expect("polled after completion");
+						self.state = CallState::WaitingForResponse(response_receiver);
+					},
+					Ok(futures::Async::NotReady) => break,
+					Err(err) => return Err(CallError::Publish(err)),
+				},
+
+				CallState::WaitingForResponse(response_receiver) => match response_receiver.poll() {
+					Ok(futures::Async::Ready(Ok(response))) => return Ok(futures::Async::Ready(response)),
+					Ok(futures::Async::Ready(Err(err))) => return Err(CallError::Remote(err)),
+					Ok(futures::Async::NotReady) => break,
+					Err(futures::sync::oneshot::Canceled) => return Err(CallError::DispatcherDropped),
+				},
+			}
+		}
+
+		match self.timeout.poll() {
+			Ok(futures::Async::Ready(())) => Err(CallError::Timeout),
+			Ok(futures::Async::NotReady) => Ok(futures::Async::NotReady),
+			Err(err) => Err(CallError::Timer(err)),
+		}
+	}
+}
+
+impl Drop for CallFuture {
+	fn drop(&mut self) {
+		if let Some((correlation_id, pending_calls)) = &self.correlation_id {
+			let _ = lock(pending_calls).remove(correlation_id);
+		}
+	}
+}
+
+impl std::fmt::Debug for CallFuture {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		let state = match &self.state {
+			CallState::Failed(_) => "Failed",
+			CallState::Publishing(..) => "Publishing",
+			CallState::WaitingForResponse(_) => "WaitingForResponse",
+		};
+		f.debug_struct("CallFuture").field("state", &state).finish_non_exhaustive()
+	}
+}
+
+fn lock(pending_calls: &PendingCalls) -> std::sync::MutexGuard<'_, std::collections::BTreeMap<u64, futures::sync::oneshot::Sender<Result<bytes::Bytes, bytes::Bytes>>>> {
+	pending_calls.lock().unwrap_or_else(std::sync::PoisonError::into_inner)
+}
+
+fn validate_prefix(prefix: &str) -> Result<(), RpcError> {
+	if prefix.is_empty() || prefix.ends_with('/') || crate::topic::TopicName::new(prefix.to_owned()).is_err() {
+		return Err(RpcError::InvalidPrefix(prefix.to_owned()));
+	}
+
+	Ok(())
+}
+
+/// An error from creating an [`RpcServer`] or [`RpcClient`], or adding a method to an `RpcServer`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RpcError {
+	InvalidCallerId(String),
+	InvalidMethod(String),
+	InvalidPrefix(String),
+}
+
+impl std::fmt::Display for RpcError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			RpcError::InvalidCallerId(caller_id) => write!(f, "caller ID {:?} is not a valid topic level", caller_id),
+			RpcError::InvalidMethod(method) => write!(f, "method {:?} is not valid in a topic name", method),
+			RpcError::InvalidPrefix(prefix) => write!(f, "prefix {:?} is not a valid topic name", prefix),
+		}
+	}
+}
+
+impl std::error::Error for RpcError {
+}
+
+/// An error from a call made with [`RpcClient::call`]
+#[derive(Debug)]
+pub enum CallError {
+	/// The dispatcher of the client was dropped, so the response would never be received.
+	DispatcherDropped,
+
+	InvalidMethod(String),
+
+	Publish(crate::PublishError),
+
+	/// The method failed. This contains the error that the server responded with.
+	Remote(bytes::Bytes),
+
+	/// There was no response within the timeout.
+	Timeout,
+
+	Timer(tokio_timer::Error),
+}
+
+impl std::fmt::Display for CallError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			CallError::DispatcherDropped => f.write_str("RPC dispatcher was dropped"),
+			CallError::InvalidMethod(method) => write!(f, "method {:?} is not valid in a topic name", method),
+			CallError::Publish(err) => write!(f, "could not publish request: {}", err),
+			CallError::Remote(err) => write!(f, "method failed: {:?}", err),
+			CallError::Timeout => f.write_str("timed out waiting for response"),
+			CallError::Timer(err) => write!(f, "timeout timer failed: {}", err),
+		}
+	}
+}
+
+impl std::error::Error for CallError {
+	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+		match self {
+			CallError::Publish(err) => Some(err),
+			CallError::Timer(err) => Some(err),
+			CallError::DispatcherDropped |
+			CallError::InvalidMethod(_) |
+			CallError::Remote(_) |
+			CallError::Timeout => None,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	#[test]
+	fn validate_prefix() {
+		assert_eq!(super::validate_prefix("thermostat/methods"), Ok(()));
+		assert_eq!(super::validate_prefix(""), Err(super::RpcError::InvalidPrefix("".to_owned())));
+		assert_eq!(super::validate_prefix("methods/"), Err(super::RpcError::InvalidPrefix("methods/".to_owned())));
+		assert_eq!(super::validate_prefix("methods/+"), Err(super::RpcError::InvalidPrefix("methods/+".to_owned())));
+	}
+}
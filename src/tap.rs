@@ -0,0 +1,106 @@
+/*!
+ * A handle to inject packets into a client's receive path and to intercept the packets that it sends.
+ *
+ * This is meant for black-box tests of the client's state machine, and for protocol gateways that need to translate
+ * or filter the packets that the client exchanges with the server.
+ */
+
+/// A handle to inject packets into a client's receive path and to intercept the packets that it sends.
+///
+/// Set it on a client with [`Client::set_packet_tap`](crate::Client::set_packet_tap), and keep a clone to use it.
+///
+/// Injected packets are handled by the client as if the server had sent them, and are logged, recorded in the metrics and captured like them.
+/// Intercepted packets are seen before they are logged, so a packet that the interceptor drops is not logged or counted as sent.
+#[derive(Clone, Default)]
+pub struct PacketTap(std::sync::Arc<std::sync::Mutex<State>>);
+
+#[derive(Default)]
+struct State {
+	injected: std::collections::VecDeque<crate::proto::Packet>,
+	interceptor: Option<Box<dyn FnMut(crate::proto::Packet) -> Option<crate::proto::Packet> + Send>>,
+
+	/// The task of the connection that polls for received packets, to be notified when a packet is injected
+	task: Option<futures::task::Task>,
+}
+
+impl PacketTap {
+	pub fn new() -> Self {
+		Default::default()
+	}
+
+	/// Injects a packet into the client's receive path, as if the server had sent it.
+	///
+	/// The packet is received on the client's current connection, or on the next one if it is not connected. A packet that is injected
+	/// while the client is connecting is received before the server's CONNACK, so it is possible to inject the CONNACK itself.
+	pub fn inject(&self, packet: crate::proto::Packet) {
+		let mut state = self.lock();
+		state.injected.push_back(packet);
+		if let Some(task) = &state.task {
+			task.notify();
+		}
+	}
+
+	/// Sets a function that is called with every packet that the client sends, before it is written to the connection.
+	///
+	/// The function returns the packet that is written instead, which may be the same packet, a modified one, or `None` to drop it.
+	/// Pass `None` to remove the interceptor.
+	///
+	/// Defaults to `None`, ie every packet is written as is.
+	pub fn set_interceptor<F>(&self, interceptor: Option<F>) where F: FnMut(crate::proto::Packet) -> Option<crate::proto::Packet> + Send + 'static {
+		self.lock().interceptor = interceptor.map(|interceptor| Box::new(interceptor) as _);
+	}
+
+	/// Returns the next injected packet, or registers the current task to be notified when one is injected.
+	pub(crate) fn poll_injected(&self) -> Option<crate::proto::Packet> {
+		let mut state = self.lock();
+		let packet = state.injected.pop_front();
+		if packet.is_none() {
+			state.task = Some(futures::task::current());
+		}
+		packet
+	}
+
+	/// Passes a packet that the client is about to send through the interceptor.
+	pub(crate) fn intercept(&self, packet: crate::proto::Packet) -> Option<crate::proto::Packet> {
+		match &mut self.lock().interceptor {
+			Some(interceptor) => interceptor(packet),
+			None => Some(packet),
+		}
+	}
+
+	fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+		// A panic in the interceptor doesn't leave the rest of the state inconsistent, so a poisoned lock is still usable.
+		self.0.lock().unwrap_or_else(std::sync::PoisonError::into_inner)
+	}
+}
+
+impl std::fmt::Debug for PacketTap {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		let state = self.lock();
+		f.debug_struct("PacketTap")
+			.field("injected", &state.injected)
+			.field("has_interceptor", &state.interceptor.is_some())
+			.finish_non_exhaustive()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	#[test]
+	fn intercept() {
+		let tap = super::PacketTap::new();
+		let packet = crate::proto::Packet::PingReq(crate::proto::PingReq);
+
+		assert_eq!(tap.intercept(packet.clone()), Some(packet.clone()));
+
+		tap.set_interceptor(Some(|packet| match packet {
+			crate::proto::Packet::PingReq(_) => None,
+			packet => Some(packet),
+		}));
+		assert_eq!(tap.intercept(packet.clone()), None);
+		assert_eq!(tap.intercept(crate::proto::Packet::Disconnect(crate::proto::Disconnect)), Some(crate::proto::Packet::Disconnect(crate::proto::Disconnect)));
+
+		tap.set_interceptor(None::<fn(_) -> _>);
+		assert_eq!(tap.intercept(packet.clone()), Some(packet));
+	}
+}
@@ -0,0 +1,166 @@
+/*!
+ * An [`IoSource`](crate::IoSource) that connects to the server over TCP with configurable socket options.
+ *
+ * This module is only available with the `tcp` feature.
+ */
+
+use futures::Future;
+
+/// Connects to an MQTT server over TCP.
+///
+/// Use [`TcpConnector::new`] to create it, and the `set_*` methods to configure the socket options of every connection it makes.
+/// Then pass it as the `io_source` of [`Client::new`](crate::Client::new).
+#[derive(Clone, Debug)]
+pub struct TcpConnector {
+	addr: std::net::SocketAddr,
+	password: Option<String>,
+	nodelay: bool,
+	keepalive_time: Option<std::time::Duration>,
+	keepalive_interval: Option<std::time::Duration>,
+	bind_device: Option<String>,
+}
+
+impl TcpConnector {
+	/// Creates a connector for the server at the given address.
+	///
+	/// `password` is the password credential for the server, if any. It is passed on to the client for every connection.
+	pub fn new(addr: std::net::SocketAddr, password: Option<String>) -> Self {
+		TcpConnector {
+			addr,
+			password,
+			nodelay: false,
+			keepalive_time: None,
+			keepalive_interval: None,
+			bind_device: None,
+		}
+	}
+
+	/// Sets `TCP_NODELAY` on the socket, so that small packets like PUBLISH, PUBACK and PINGREQ are sent immediately
+	/// instead of being coalesced with later writes.
+	///
+	/// Defaults to `false`.
+	pub fn set_nodelay(&mut self, nodelay: bool) {
+		self.nodelay = nodelay;
+	}
+
+	/// Enables `SO_KEEPALIVE` on the socket. The OS will start sending TCP keep-alive probes when the connection has been idle for `time`.
+	///
+	/// This is independent of the MQTT keep-alive.
+	///
+	/// Defaults to `None`, ie TCP keep-alive probes are not sent.
+	pub fn set_keepalive(&mut self, time: Option<std::time::Duration>) {
+		self.keepalive_time = time;
+	}
+
+	/// Sets the interval between TCP keep-alive probes, once the OS has started sending them. Only has an effect if [`TcpConnector::set_keepalive`]
+	/// has been used to enable TCP keep-alive.
+	///
+	/// Defaults to `None`, ie the OS default interval.
+	#[cfg(any(
+		target_os = "android",
+		target_os = "dragonfly",
+		target_os = "freebsd",
+		target_os = "fuchsia",
+		target_os = "illumos",
+		target_os = "linux",
+		target_os = "netbsd",
+		target_vendor = "apple",
+		windows,
+	))]
+	pub fn set_keepalive_interval(&mut self, interval: Option<std::time::Duration>) {
+		self.keepalive_interval = interval;
+	}
+
+	/// Binds the socket to the network interface with the given name (`SO_BINDTODEVICE`), so that the connection is only made over that interface.
+	/// This usually requires the `CAP_NET_RAW` capability.
+	///
+	/// Defaults to `None`, ie the OS chooses the interface based on its routing table.
+	#[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+	pub fn set_bind_device(&mut self, bind_device: Option<String>) {
+		self.bind_device = bind_device;
+	}
+
+	fn socket(&self) -> std::io::Result<std::net::TcpStream> {
+		let socket = socket2::Socket::new(socket2::Domain::for_address(self.addr), socket2::Type::STREAM, Some(socket2::Protocol::TCP))?;
+
+		if let Some(keepalive_time) = self.keepalive_time {
+			let keepalive = socket2::TcpKeepalive::new().with_time(keepalive_time);
+
+			#[cfg(any(
+				target_os = "android",
+				target_os = "dragonfly",
+				target_os = "freebsd",
+				target_os = "fuchsia",
+				target_os = "illumos",
+				target_os = "linux",
+				target_os = "netbsd",
+				target_vendor = "apple",
+				windows,
+			))]
+			let keepalive = match self.keepalive_interval {
+				Some(keepalive_interval) => keepalive.with_interval(keepalive_interval),
+				None => keepalive,
+			};
+
+			socket.set_tcp_keepalive(&keepalive)?;
+		}
+
+		#[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+		{
+			if let Some(bind_device) = &self.bind_device {
+				socket.bind_device(Some(bind_device.as_bytes()))?;
+			}
+		}
+
+		Ok(socket.into())
+	}
+}
+
+impl crate::IoSource for TcpConnector {
+	type Io = tokio_tcp::TcpStream;
+	type Future = TcpConnectFuture;
+
+	fn connect(&mut self) -> Self::Future {
+		let state = match self.socket() {
+			Ok(socket) => TcpConnectFutureState::Connecting(tokio_tcp::TcpStream::connect_std(socket, &self.addr, &Default::default())),
+			Err(err) => TcpConnectFutureState::Failed(Some(err)),
+		};
+
+		TcpConnectFuture {
+			state,
+			password: self.password.clone(),
+			nodelay: self.nodelay,
+		}
+	}
+}
+
+/// The connection future returned by [`TcpConnector`]
+#[derive(Debug)]
+pub struct TcpConnectFuture {
+	state: TcpConnectFutureState,
+	password: Option<String>,
+	nodelay: bool,
+}
+
+#[derive(Debug)]
+enum TcpConnectFutureState {
+	Connecting(tokio_tcp::ConnectFuture),
+	Failed(Option<std::io::Error>),
+}
+
+impl Future for TcpConnectFuture {
+	type Item = (tokio_tcp::TcpStream, Option<String>);
+	type Error = std::io::Error;
+
+	fn poll(&mut self) -> futures::Poll<Self::Item, Self::Error> {
+		match &mut self.state {
+			TcpConnectFutureState::Connecting(connect) => {
+				let io = futures::try_ready!(connect.poll());
+				io.set_nodelay(self.nodelay)?;
+				Ok(futures::Async::Ready((io, self.password.take())))
+			},
+
+			TcpConnectFutureState::Failed(err) => Err(err.take().expect("TcpConnectFuture polled after completion")),
+		}
+	}
+}
@@ -0,0 +1,143 @@
+/// Asserts that the next packet of an iterator of [`Packet`](crate::proto::Packet)s is of the given type and has the given field values,
+/// and evaluates to the packet's inner struct so that the test can make further assertions on it.
+///
+/// ```
+/// # use mqtt::proto::{ Packet, PacketIdentifier, PingReq, PubAck };
+/// let packet_identifier = PacketIdentifier::new(1).unwrap();
+/// let mut packets = vec![Packet::PingReq(PingReq), Packet::PubAck(PubAck { packet_identifier })].into_iter();
+///
+/// mqtt::assert_next_packet!(packets, PingReq);
+/// let puback = mqtt::assert_next_packet!(packets, PubAck { packet_identifier == packet_identifier });
+/// # let _ = puback;
+/// ```
+///
+/// The iterator can be the packets that a test server received, or the packets read back from a [`PacketCapture`](crate::capture::PacketCapture)
+/// after mapping them to their [`CapturedPacket::packet`](crate::capture::CapturedPacket::packet).
+///
+/// # Panics
+///
+/// Panics if there is no next packet, if the next packet is of a different type, or if any of the fields has a different value.
+#[macro_export]
+macro_rules! assert_next_packet {
+	($packets:expr, $variant:ident) => {
+		$crate::assert_next_packet!($packets, $variant {})
+	};
+
+	($packets:expr, $variant:ident { $($field:ident == $value:expr),* $(,)? }) => {
+		match ::std::iter::Iterator::next(&mut $packets) {
+			::std::option::Option::Some($crate::proto::Packet::$variant(packet)) => {
+				$(
+					assert_eq!(packet.$field, $value, concat!("unexpected ", stringify!($field), " of ", stringify!($variant), " packet"));
+				)*
+				packet
+			},
+
+			::std::option::Option::Some(packet) => panic!(concat!("expected ", stringify!($variant), " packet but got {:?}"), packet),
+
+			::std::option::Option::None => panic!(concat!("expected ", stringify!($variant), " packet but there are no more packets")),
+		}
+	};
+}
+
+/// Asserts that an iterator of [`Packet`](crate::proto::Packet)s yields exactly the given sequence of packets, and nothing after them.
+///
+/// Each packet is matched like with [`assert_next_packet!`](crate::assert_next_packet), by its type and optionally the values of some of its fields.
+///
+/// ```
+/// # use mqtt::proto::{ Disconnect, Packet, PingReq };
+/// let packets = vec![Packet::PingReq(PingReq), Packet::Disconnect(Disconnect)];
+///
+/// mqtt::assert_packets!(packets, [
+///     PingReq,
+///     Disconnect,
+/// ]);
+/// ```
+///
+/// # Panics
+///
+/// Panics if any packet doesn't match, or if the iterator yields fewer or more packets than the given sequence.
+#[macro_export]
+macro_rules! assert_packets {
+	($packets:expr, [ $($variant:ident $({ $($field:ident == $value:expr),* $(,)? })?),* $(,)? ]) => {{
+		let mut packets = ::std::iter::IntoIterator::into_iter($packets);
+		$(
+			let _ = $crate::assert_next_packet!(packets, $variant $({ $($field == $value),* })?);
+		)*
+		if let ::std::option::Option::Some(packet) = ::std::iter::Iterator::next(&mut packets) {
+			panic!("expected no more packets but got {:?}", packet);
+		}
+	}};
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::proto::{ Packet, PacketIdentifier, PacketIdentifierDupQoS, PingReq, PubAck, Publish };
+
+	fn packets() -> Vec<Packet> {
+		vec![
+			Packet::Publish(Publish {
+				packet_identifier_dup_qos: PacketIdentifierDupQoS::AtLeastOnce(PacketIdentifier::new(1).unwrap(), false),
+				retain: false,
+				topic_name: "foo".to_owned(),
+				payload: b"hello"[..].into(),
+			}),
+			Packet::PingReq(PingReq),
+			Packet::PubAck(PubAck { packet_identifier: PacketIdentifier::new(1).unwrap() }),
+		]
+	}
+
+	#[test]
+	fn assert_next_packet() {
+		let mut packets = packets().into_iter();
+
+		let publish = crate::assert_next_packet!(packets, Publish { topic_name == "foo", payload == b"hello"[..] });
+		assert!(!publish.retain);
+
+		crate::assert_next_packet!(packets, PingReq);
+		crate::assert_next_packet!(packets, PubAck {});
+		assert_eq!(packets.next(), None);
+	}
+
+	#[test]
+	#[should_panic(expected = "unexpected topic_name of Publish packet")]
+	fn assert_next_packet_mismatched_field() {
+		let mut packets = packets().into_iter();
+		crate::assert_next_packet!(packets, Publish { topic_name == "bar" });
+	}
+
+	#[test]
+	#[should_panic(expected = "expected PubAck packet but got Publish")]
+	fn assert_next_packet_mismatched_type() {
+		let mut packets = packets().into_iter();
+		crate::assert_next_packet!(packets, PubAck);
+	}
+
+	#[test]
+	fn assert_packets() {
+		crate::assert_packets!(packets(), [
+			Publish { topic_name == "foo" },
+			PingReq,
+			PubAck { packet_identifier == PacketIdentifier::new(1).unwrap() },
+		]);
+	}
+
+	#[test]
+	#[should_panic(expected = "expected no more packets but got PubAck")]
+	fn assert_packets_extra_packet() {
+		crate::assert_packets!(packets(), [
+			Publish {},
+			PingReq,
+		]);
+	}
+
+	#[test]
+	#[should_panic(expected = "expected PubAck packet but there are no more packets")]
+	fn assert_packets_missing_packet() {
+		crate::assert_packets!(packets(), [
+			Publish {},
+			PingReq,
+			PubAck,
+			PubAck,
+		]);
+	}
+}
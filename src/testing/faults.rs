@@ -0,0 +1,464 @@
+use futures::Future;
+
+/// The faults that a [`FaultyIoSource`] injects into the packets going in one direction of its connections.
+///
+/// Each fault is applied to each packet independently with its own probability, between `0.0` (never) and `1.0` (always).
+/// All probabilities default to `0.0`, ie the packets are passed through untouched.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Faults {
+	drop: f64,
+	duplicate: f64,
+	reorder: f64,
+	split: f64,
+	delay: f64,
+	delay_duration: std::time::Duration,
+}
+
+impl Faults {
+	pub fn new() -> Self {
+		Default::default()
+	}
+
+	/// The probability that a packet is dropped, ie it is never delivered.
+	#[must_use]
+	pub fn drop(mut self, probability: f64) -> Self {
+		self.drop = probability;
+		self
+	}
+
+	/// The probability that a packet is delivered twice in a row.
+	#[must_use]
+	pub fn duplicate(mut self, probability: f64) -> Self {
+		self.duplicate = probability;
+		self
+	}
+
+	/// The probability that a packet is swapped with the packet after it.
+	///
+	/// Only packets that are written or read in the same batch can be swapped, so that a packet is never held back waiting for another one
+	/// that might never come.
+	#[must_use]
+	pub fn reorder(mut self, probability: f64) -> Self {
+		self.reorder = probability;
+		self
+	}
+
+	/// The probability that a packet is split at a random point into two chunks, which are written or read separately.
+	#[must_use]
+	pub fn split(mut self, probability: f64) -> Self {
+		self.split = probability;
+		self
+	}
+
+	/// The probability that a packet is delivered after the given duration, rather than immediately. Packets after it are not held back,
+	/// so a delayed packet is also delivered out of order.
+	#[must_use]
+	pub fn delay(mut self, probability: f64, duration: std::time::Duration) -> Self {
+		self.delay = probability;
+		self.delay_duration = duration;
+		self
+	}
+}
+
+/// An [`IoSource`](crate::IoSource) whose connections drop, duplicate, reorder, split or delay the packets that go through them,
+/// to validate that the client and the code that uses it handle a bad network.
+///
+/// The connections are made by the wrapped `IoSource`, like a [`MockBroker`](super::MockBroker) or a TCP connection to a real server.
+/// The faults are decided by a pseudo-random number generator with the given seed, so a run with the same seed and the same traffic
+/// injects the same faults.
+///
+/// The connections use timers to delay packets, so they must be used from within a tokio runtime or a [`Simulation`](super::Simulation).
+#[derive(Debug)]
+pub struct FaultyIoSource<S> {
+	io_source: S,
+	sent: Faults,
+	received: Faults,
+	rng: Rng,
+}
+
+impl<S> FaultyIoSource<S> {
+	/// Wraps the given `IoSource`. `sent` are the faults injected into the packets that the client sends, and `received` are the faults
+	/// injected into the packets that the client receives.
+	///
+	/// The seed is taken from the current time, and logged so that a run can be reproduced with [`FaultyIoSource::with_seed`].
+	pub fn new(io_source: S, sent: Faults, received: Faults) -> Self {
+		let seed =
+			std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)
+			.map(|since_epoch| std::convert::TryInto::try_into(since_epoch.as_nanos()).unwrap_or_default())
+			.unwrap_or_default();
+		log::info!("faulty I/O source uses seed {}", seed);
+		FaultyIoSource::with_seed(io_source, sent, received, seed)
+	}
+
+	/// Wraps the given `IoSource`, like [`FaultyIoSource::new`], with the given seed.
+	pub fn with_seed(io_source: S, sent: Faults, received: Faults, seed: u64) -> Self {
+		FaultyIoSource {
+			io_source,
+			sent,
+			received,
+			rng: Rng(seed),
+		}
+	}
+}
+
+impl<S> crate::IoSource for FaultyIoSource<S> where S: crate::IoSource {
+	type Io = FaultyIo<S::Io>;
+	type Future = FaultyConnectFuture<S::Future>;
+
+	fn connect(&mut self) -> Self::Future {
+		FaultyConnectFuture {
+			inner: self.io_source.connect(),
+			sent: Some(FaultyPipe::new(self.sent.clone(), Rng(self.rng.next_u64()))),
+			received: Some(FaultyPipe::new(self.received.clone(), Rng(self.rng.next_u64()))),
+		}
+	}
+}
+
+/// The connection future of a [`FaultyIoSource`]
+#[derive(Debug)]
+pub struct FaultyConnectFuture<F> {
+	inner: F,
+	sent: Option<FaultyPipe>,
+	received: Option<FaultyPipe>,
+}
+
+impl<F, I> Future for FaultyConnectFuture<F> where F: Future<Item = (I, Option<String>)> {
+	type Item = (FaultyIo<I>, Option<String>);
+	type Error = F::Error;
+
+	fn poll(&mut self) -> futures::Poll<Self::Item, Self::Error> {
+		let (inner, password) = futures::try_ready!(self.inner.poll());
+		let io = FaultyIo {
+			inner,
+			sent: self.sent.take().expect("polled after completion"),
+			received: self.received.take().expect("polled after completion"),
+		};
+		Ok(futures::Async::Ready((io, password)))
+	}
+}
+
+/// A connection of a [`FaultyIoSource`]
+#[derive(Debug)]
+pub struct FaultyIo<T> {
+	inner: T,
+	sent: FaultyPipe,
+	received: FaultyPipe,
+}
+
+impl<T> FaultyIo<T> where T: std::io::Write {
+	/// Writes the chunks of the sent packets that are ready to the inner connection, each with a separate write
+	fn write_ready(&mut self) -> std::io::Result<()> {
+		while let Some(chunk) = self.sent.ready.front_mut() {
+			let written = self.inner.write(chunk)?;
+			if written == 0 {
+				return Err(std::io::ErrorKind::WriteZero.into());
+			}
+
+			let _ = chunk.split_to(written);
+			if chunk.is_empty() {
+				let _ = self.sent.ready.pop_front();
+			}
+		}
+
+		Ok(())
+	}
+}
+
+impl<T> std::io::Read for FaultyIo<T> where T: std::io::Read {
+	fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+		loop {
+			// Each chunk is read separately, so that a split packet is seen in two reads
+			if let Some(chunk) = self.received.ready.front_mut() {
+				let len = std::cmp::min(buf.len(), chunk.len());
+				buf[..len].copy_from_slice(&chunk.split_to(len));
+				if chunk.is_empty() {
+					let _ = self.received.ready.pop_front();
+				}
+				return Ok(len);
+			}
+
+			if self.received.poll_delayed()? {
+				continue;
+			}
+
+			let mut inner_buf = [0_u8; 4096];
+			match self.inner.read(&mut inner_buf) {
+				Ok(0) => {
+					// Deliver the packets that are still delayed before the EOF
+					if self.received.finish() {
+						continue;
+					}
+					return Ok(0);
+				},
+				Ok(read) => self.received.push(&inner_buf[..read]),
+				Err(err) => return Err(err),
+			}
+		}
+	}
+}
+
+impl<T> tokio_io::AsyncRead for FaultyIo<T> where T: tokio_io::AsyncRead {
+}
+
+impl<T> std::io::Write for FaultyIo<T> where T: std::io::Write {
+	fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+		// Apply backpressure if the inner connection can't keep up, but not while packets are only waiting to be delayed
+		self.write_ready()?;
+
+		self.sent.push(buf);
+		match self.write_ready() {
+			Ok(()) => Ok(buf.len()),
+			Err(ref err) if err.kind() == std::io::ErrorKind::WouldBlock => Ok(buf.len()),
+			Err(err) => Err(err),
+		}
+	}
+
+	fn flush(&mut self) -> std::io::Result<()> {
+		loop {
+			self.write_ready()?;
+			if !self.sent.poll_delayed()? {
+				break;
+			}
+		}
+
+		if !self.sent.delayed.is_empty() {
+			return Err(std::io::ErrorKind::WouldBlock.into());
+		}
+
+		self.inner.flush()
+	}
+}
+
+impl<T> tokio_io::AsyncWrite for FaultyIo<T> where T: tokio_io::AsyncWrite {
+	fn shutdown(&mut self) -> futures::Poll<(), std::io::Error> {
+		self.inner.shutdown()
+	}
+}
+
+/// Injects faults into the packets going in one direction of a `FaultyIo`
+#[derive(Debug)]
+struct FaultyPipe {
+	faults: Faults,
+	rng: Rng,
+
+	/// The bytes that don't make up a whole packet yet
+	incoming: bytes::BytesMut,
+
+	/// The chunks that are ready to be delivered, in order
+	ready: std::collections::VecDeque<bytes::BytesMut>,
+
+	/// The delayed packets, with the time that they are to be delivered at
+	delayed: std::collections::VecDeque<(std::time::Instant, bytes::BytesMut)>,
+
+	/// The timer for the first of the delayed packets
+	delay: Option<tokio_timer::Delay>,
+
+	/// Set if the incoming bytes could not be decoded. The pipe then passes everything through untouched, since it can't tell where packets begin.
+	passthrough: bool,
+}
+
+impl FaultyPipe {
+	fn new(faults: Faults, rng: Rng) -> Self {
+		FaultyPipe {
+			faults,
+			rng,
+			incoming: Default::default(),
+			ready: Default::default(),
+			delayed: Default::default(),
+			delay: None,
+			passthrough: false,
+		}
+	}
+
+	/// Splits the given bytes into packets and injects faults into them
+	fn push(&mut self, bytes: &[u8]) {
+		self.incoming.extend_from_slice(bytes);
+
+		let mut packets = vec![];
+		while !self.passthrough {
+			match crate::proto::decode(&self.incoming) {
+				Ok(Some((packet, len))) => packets.push((packet.type_name(), self.incoming.split_to(len))),
+				Ok(None) => break,
+				Err(err) => {
+					log::warn!("faulty connection could not decode packet, so it no longer injects faults: {}", err);
+					self.passthrough = true;
+				},
+			}
+		}
+
+		let mut packets = packets.into_iter().peekable();
+		while let Some((type_name, packet)) = packets.next() {
+			if self.rng.chance(self.faults.drop) {
+				log::debug!("faulty connection drops {} packet", type_name);
+				continue;
+			}
+
+			if packets.peek().is_some() && self.rng.chance(self.faults.reorder) {
+				log::debug!("faulty connection swaps {} packet with the next one", type_name);
+				let (next_type_name, next_packet) = packets.next().expect("peeked");
+				self.deliver(next_type_name, &next_packet);
+			}
+
+			self.deliver(type_name, &packet);
+		}
+
+		if self.passthrough && !self.incoming.is_empty() {
+			let incoming = self.incoming.take();
+			self.ready.push_back(incoming);
+		}
+	}
+
+	fn deliver(&mut self, type_name: &'static str, packet: &bytes::BytesMut) {
+		let copies = if self.rng.chance(self.faults.duplicate) {
+			log::debug!("faulty connection duplicates {} packet", type_name);
+			2
+		}
+		else {
+			1
+		};
+
+		for _ in 0..copies {
+			let mut packet = packet.clone();
+
+			if self.rng.chance(self.faults.delay) {
+				log::debug!("faulty connection delays {} packet by {:?}", type_name, self.faults.delay_duration);
+				self.delayed.push_back((tokio_timer::clock::now() + self.faults.delay_duration, packet));
+			}
+			else if packet.len() > 1 && self.rng.chance(self.faults.split) {
+				let at = 1 + self.rng.below(packet.len() - 1);
+				log::debug!("faulty connection splits {} packet at byte {} of {}", type_name, at, packet.len());
+				let first = packet.split_to(at);
+				self.ready.push_back(first);
+				self.ready.push_back(packet);
+			}
+			else {
+				self.ready.push_back(packet);
+			}
+		}
+	}
+
+	/// Moves the delayed packets whose time has come to the ready chunks, and returns whether there were any.
+	///
+	/// If there are delayed packets left, the current task is notified when the first of them is due.
+	fn poll_delayed(&mut self) -> std::io::Result<bool> {
+		let mut any_due = false;
+
+		while let Some((deadline, _)) = self.delayed.front() {
+			let deadline = *deadline;
+			let delay = self.delay.get_or_insert_with(|| tokio_timer::Delay::new(deadline));
+			match delay.poll() {
+				Ok(futures::Async::Ready(())) => {
+					self.delay = None;
+					let (_, packet) = self.delayed.pop_front().expect("peeked");
+					self.ready.push_back(packet);
+					any_due = true;
+				},
+				Ok(futures::Async::NotReady) => break,
+				Err(err) => return Err(std::io::Error::other(err)),
+			}
+		}
+
+		Ok(any_due)
+	}
+
+	/// Delivers the delayed packets immediately, and returns whether there were any
+	fn finish(&mut self) -> bool {
+		self.delay = None;
+		let any_delayed = !self.delayed.is_empty();
+		self.ready.extend(self.delayed.drain(..).map(|(_, packet)| packet));
+		any_delayed
+	}
+}
+
+/// A `SplitMix64` pseudo-random number generator. The faults don't need a high-quality generator, just a reproducible one.
+#[derive(Debug)]
+struct Rng(u64);
+
+impl Rng {
+	fn next_u64(&mut self) -> u64 {
+		self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+		let mut z = self.0;
+		z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+		z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+		z ^ (z >> 31)
+	}
+
+	/// Returns `true` with the given probability
+	fn chance(&mut self, probability: f64) -> bool {
+		// The top 52 bits as the mantissa of a float with exponent 0 make a uniformly distributed float in [1, 2)
+		probability > 0.0 && f64::from_bits(0x3FF0_0000_0000_0000 | (self.next_u64() >> 12)) - 1.0 < probability
+	}
+
+	/// Returns a number in `0..n`
+	fn below(&mut self, n: usize) -> usize {
+		let n: u64 = std::convert::TryInto::try_into(n).expect("usize fits in u64");
+		std::convert::TryInto::try_into(self.next_u64() % n).expect("number is below a usize")
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	fn encoded(packets: &[crate::proto::Packet]) -> Vec<u8> {
+		let mut bytes = vec![];
+		for packet in packets {
+			crate::proto::encode(packet, &mut bytes).unwrap();
+		}
+		bytes
+	}
+
+	fn packet(packet_identifier: u16) -> crate::proto::Packet {
+		crate::proto::Packet::PubAck(crate::proto::PubAck { packet_identifier: crate::proto::PacketIdentifier::new(packet_identifier).unwrap() })
+	}
+
+	fn delivered(faults: super::Faults, packets: &[crate::proto::Packet]) -> Vec<Vec<u8>> {
+		let mut pipe = super::FaultyPipe::new(faults, super::Rng(0));
+		pipe.push(&encoded(packets));
+		pipe.ready.into_iter().map(|chunk| chunk.to_vec()).collect()
+	}
+
+	#[test]
+	fn no_faults() {
+		assert_eq!(delivered(super::Faults::new(), &[packet(1), packet(2)]), [encoded(&[packet(1)]), encoded(&[packet(2)])]);
+	}
+
+	#[test]
+	fn drop() {
+		assert!(delivered(super::Faults::new().drop(1.0), &[packet(1), packet(2)]).is_empty());
+	}
+
+	#[test]
+	fn duplicate() {
+		assert_eq!(
+			delivered(super::Faults::new().duplicate(1.0), &[packet(1)]),
+			[encoded(&[packet(1)]), encoded(&[packet(1)])],
+		);
+	}
+
+	#[test]
+	fn reorder() {
+		assert_eq!(
+			delivered(super::Faults::new().reorder(1.0), &[packet(1), packet(2), packet(3)]),
+			[encoded(&[packet(2)]), encoded(&[packet(1)]), encoded(&[packet(3)])],
+		);
+	}
+
+	#[test]
+	fn split() {
+		let chunks = delivered(super::Faults::new().split(1.0), &[packet(1)]);
+		assert_eq!(chunks.len(), 2);
+		assert!(chunks.iter().all(|chunk| !chunk.is_empty()));
+		assert_eq!(chunks.concat(), encoded(&[packet(1)]));
+	}
+
+	#[test]
+	fn partial_packets() {
+		let bytes = encoded(&[packet(1)]);
+		let mut pipe = super::FaultyPipe::new(super::Faults::new(), super::Rng(0));
+
+		pipe.push(&bytes[..1]);
+		assert!(pipe.ready.is_empty());
+
+		pipe.push(&bytes[1..]);
+		assert_eq!(pipe.ready.into_iter().map(|chunk| chunk.to_vec()).collect::<Vec<_>>(), [bytes]);
+	}
+}
@@ -0,0 +1,458 @@
+/*!
+ * In-memory transports and a mock broker, for testing code that uses a [`Client`](crate::Client) without a real server or network.
+ *
+ * [`duplex`] creates a pair of connected in-memory streams. [`MockBroker`] is an [`IoSource`](crate::IoSource) whose connections
+ * are served by a minimal broker that answers CONNECT, SUBSCRIBE, UNSUBSCRIBE and PINGREQ packets, acks publications,
+ * and delivers publications to the clients that subscribed to them.
+ *
+ * The streams must be read from within a futures task, like any other [`tokio_io::AsyncRead`].
+ *
+ * [`ScriptedServer`] is an `IoSource` whose connections follow a [`Script`] of the packets that the client is expected to send
+ * and the server's responses, including malformed packets, delays and abrupt disconnects.
+ *
+ * [`FaultyIoSource`] wraps another `IoSource` and drops, duplicates, reorders, splits or delays the packets on its connections.
+ *
+ * [`Simulation`] runs clients against a `MockBroker` in virtual time, so that tests of timeouts and reconnects are deterministic and fast.
+ *
+ * The [`assert_next_packet!`](crate::assert_next_packet) and [`assert_packets!`](crate::assert_packets) macros assert on sequences of packets,
+ * like the ones read back from a [`PacketCapture`](crate::capture::PacketCapture).
+ *
+ * This module is only available with the `testing` feature.
+ */
+
+mod assertions;
+
+mod faults;
+pub use self::faults::{ FaultyConnectFuture, FaultyIo, FaultyIoSource, Faults };
+
+mod scripted;
+pub use self::scripted::{ Script, ScriptedConnection, ScriptedServer };
+
+mod simulation;
+pub use self::simulation::Simulation;
+
+/// One direction of an in-memory stream
+#[derive(Debug, Default)]
+struct Pipe {
+	buffer: bytes::BytesMut,
+	closed: bool,
+
+	/// The task that last tried to read from the empty pipe, to be notified when there is something to read
+	reader: Option<futures::task::Task>,
+}
+
+impl Pipe {
+	fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+		if self.buffer.is_empty() {
+			if self.closed {
+				return Ok(0);
+			}
+
+			self.reader = Some(futures::task::current());
+			return Err(std::io::ErrorKind::WouldBlock.into());
+		}
+
+		let len = std::cmp::min(buf.len(), self.buffer.len());
+		buf[..len].copy_from_slice(&self.buffer.split_to(len));
+		Ok(len)
+	}
+
+	fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+		if self.closed {
+			return Err(std::io::ErrorKind::BrokenPipe.into());
+		}
+
+		self.buffer.extend_from_slice(buf);
+		self.notify_reader();
+		Ok(buf.len())
+	}
+
+	fn close(&mut self) {
+		self.closed = true;
+		self.notify_reader();
+	}
+
+	fn notify_reader(&mut self) {
+		if let Some(reader) = self.reader.take() {
+			reader.notify();
+		}
+	}
+}
+
+#[derive(Clone, Debug, Default)]
+struct SharedPipe(std::sync::Arc<std::sync::Mutex<Pipe>>);
+
+impl SharedPipe {
+	fn lock(&self) -> std::sync::MutexGuard<'_, Pipe> {
+		// A pipe only holds plain values that can't be left in an inconsistent state, so a poisoned lock is still usable.
+		self.0.lock().unwrap_or_else(std::sync::PoisonError::into_inner)
+	}
+}
+
+/// Creates a pair of in-memory streams that are connected to each other. What is written to one can be read from the other.
+///
+/// When one of the streams is shut down or dropped, the other one reads EOF once it has read everything that was written to it.
+pub fn duplex() -> (DuplexStream, DuplexStream) {
+	let a_to_b: SharedPipe = Default::default();
+	let b_to_a: SharedPipe = Default::default();
+
+	let a = DuplexStream { read: b_to_a.clone(), write: a_to_b.clone() };
+	let b = DuplexStream { read: a_to_b, write: b_to_a };
+	(a, b)
+}
+
+/// One end of an in-memory stream created by [`duplex`]
+#[derive(Debug)]
+pub struct DuplexStream {
+	read: SharedPipe,
+	write: SharedPipe,
+}
+
+impl std::io::Read for DuplexStream {
+	fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+		self.read.lock().read(buf)
+	}
+}
+
+impl tokio_io::AsyncRead for DuplexStream {
+}
+
+impl std::io::Write for DuplexStream {
+	fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+		self.write.lock().write(buf)
+	}
+
+	fn flush(&mut self) -> std::io::Result<()> {
+		Ok(())
+	}
+}
+
+impl tokio_io::AsyncWrite for DuplexStream {
+	fn shutdown(&mut self) -> futures::Poll<(), std::io::Error> {
+		self.write.lock().close();
+		Ok(futures::Async::Ready(()))
+	}
+}
+
+impl Drop for DuplexStream {
+	fn drop(&mut self) {
+		self.write.lock().close();
+		self.read.lock().close();
+	}
+}
+
+/// A minimal MQTT broker for tests, that is also an [`IoSource`](crate::IoSource) for the clients that connect to it.
+///
+/// Pass a clone of the broker as the `io_source` of [`Client::new`](crate::Client::new). The broker then:
+///
+/// - accepts every CONNECT, without a session,
+/// - grants every subscription with the requested [`QoS`](crate::proto::QoS) and acks every unsubscription,
+/// - answers every PINGREQ,
+/// - acks the publications that clients send, records them for [`MockBroker::published`], and delivers them to the clients that subscribed to them,
+/// - delivers the publications given to [`MockBroker::publish`] to the clients that subscribed to them.
+///
+/// Answering pings and acking publications can be disabled to test how clients handle an unresponsive broker.
+///
+/// A publication is delivered with the lower of its [`QoS`](crate::proto::QoS) and the one of the subscription. The broker does not retain publications.
+#[derive(Clone, Debug, Default)]
+pub struct MockBroker(std::sync::Arc<std::sync::Mutex<BrokerState>>);
+
+#[derive(Debug, Default)]
+struct BrokerState {
+	connections: std::collections::BTreeMap<usize, BrokerConnection>,
+	next_connection_id: usize,
+	published: Vec<crate::proto::Publication>,
+
+	/// Set with `MockBroker::set_answer_pings`
+	ignore_pings: bool,
+
+	/// Set with `MockBroker::set_ack_publications`
+	ignore_publications: bool,
+}
+
+#[derive(Debug)]
+struct BrokerConnection {
+	to_client: SharedPipe,
+	subscriptions: std::collections::BTreeMap<String, crate::proto::QoS>,
+	next_packet_identifier: crate::proto::PacketIdentifier,
+}
+
+impl MockBroker {
+	pub fn new() -> Self {
+		Default::default()
+	}
+
+	/// Delivers the given publication to every connected client that has a subscription that matches it.
+	pub fn publish(&self, publication: &crate::proto::Publication) {
+		self.lock().deliver(publication);
+	}
+
+	/// Returns the publications that clients have sent to the broker, in the order they were received.
+	pub fn published(&self) -> Vec<crate::proto::Publication> {
+		self.lock().published.clone()
+	}
+
+	/// Returns the number of clients that are currently connected to the broker.
+	pub fn connections(&self) -> usize {
+		self.lock().connections.len()
+	}
+
+	/// Sets whether the broker answers PINGREQs. Disable this to make clients time out their connections.
+	///
+	/// Defaults to `true`.
+	pub fn set_answer_pings(&self, answer_pings: bool) {
+		self.lock().ignore_pings = !answer_pings;
+	}
+
+	/// Sets whether the broker acks and delivers the publications that clients send. Disable this to keep publications in flight.
+	/// Publications that are not acked are still recorded for [`MockBroker::published`].
+	///
+	/// Defaults to `true`.
+	pub fn set_ack_publications(&self, ack_publications: bool) {
+		self.lock().ignore_publications = !ack_publications;
+	}
+
+	/// Closes the connections of every client, like a broker that restarts. The clients will reconnect.
+	pub fn disconnect_all(&self) {
+		for (_, connection) in std::mem::take(&mut self.lock().connections) {
+			connection.to_client.lock().close();
+		}
+	}
+
+	fn lock(&self) -> std::sync::MutexGuard<'_, BrokerState> {
+		// If a test panicked while holding the lock, that test has already failed, so the state does not need to be consistent.
+		self.0.lock().unwrap_or_else(std::sync::PoisonError::into_inner)
+	}
+}
+
+impl crate::IoSource for MockBroker {
+	type Io = MockConnection;
+	type Future = futures::future::FutureResult<(Self::Io, Option<String>), std::io::Error>;
+
+	fn connect(&mut self) -> Self::Future {
+		let to_client: SharedPipe = Default::default();
+
+		let id = {
+			let mut state = self.lock();
+			let id = state.next_connection_id;
+			state.next_connection_id += 1;
+			state.connections.insert(id, BrokerConnection {
+				to_client: to_client.clone(),
+				subscriptions: Default::default(),
+				next_packet_identifier: crate::proto::PacketIdentifier::new(1).expect("1 is a valid packet identifier"),
+			});
+			id
+		};
+
+		futures::future::ok((
+			MockConnection {
+				id,
+				broker: self.clone(),
+				to_client,
+				from_client: Default::default(),
+			},
+			None,
+		))
+	}
+}
+
+impl BrokerState {
+	fn handle(&mut self, id: usize, packet: crate::proto::Packet) {
+		let connection = match self.connections.get_mut(&id) {
+			Some(connection) => connection,
+			None => return,
+		};
+
+		let response = match packet {
+			crate::proto::Packet::Connect(_) => Some(crate::proto::Packet::ConnAck(crate::proto::ConnAck {
+				session_present: false,
+				return_code: crate::proto::ConnectReturnCode::Accepted,
+			})),
+
+			crate::proto::Packet::Disconnect(_) => {
+				connection.to_client.lock().close();
+				None
+			},
+
+			crate::proto::Packet::PingReq(_) =>
+				if self.ignore_pings {
+					None
+				}
+				else {
+					Some(crate::proto::Packet::PingResp(crate::proto::PingResp))
+				},
+
+			crate::proto::Packet::Publish(crate::proto::Publish { packet_identifier_dup_qos, retain, topic_name, payload }) => {
+				let (qos, response) = match packet_identifier_dup_qos {
+					crate::proto::PacketIdentifierDupQoS::AtMostOnce => (crate::proto::QoS::AtMostOnce, None),
+					crate::proto::PacketIdentifierDupQoS::AtLeastOnce(packet_identifier, _) =>
+						(crate::proto::QoS::AtLeastOnce, Some(crate::proto::Packet::PubAck(crate::proto::PubAck { packet_identifier }))),
+					crate::proto::PacketIdentifierDupQoS::ExactlyOnce(packet_identifier, _) =>
+						(crate::proto::QoS::ExactlyOnce, Some(crate::proto::Packet::PubRec(crate::proto::PubRec { packet_identifier }))),
+				};
+
+				match topic_name.parse() {
+					Ok(topic_name) => {
+						let publication = crate::proto::Publication { topic_name, qos, retain, payload };
+						if !self.ignore_publications {
+							if let Some(response) = &response {
+								send(&connection.to_client, response);
+							}
+							self.deliver(&publication);
+						}
+						self.published.push(publication);
+					},
+
+					Err(err) => log::warn!("mock broker ignoring publication to invalid topic name {:?}: {}", topic_name, err),
+				}
+
+				return;
+			},
+
+			crate::proto::Packet::PubRec(crate::proto::PubRec { packet_identifier }) =>
+				Some(crate::proto::Packet::PubRel(crate::proto::PubRel { packet_identifier })),
+
+			crate::proto::Packet::PubRel(crate::proto::PubRel { packet_identifier }) =>
+				Some(crate::proto::Packet::PubComp(crate::proto::PubComp { packet_identifier })),
+
+			crate::proto::Packet::Subscribe(crate::proto::Subscribe { packet_identifier, subscribe_to }) => {
+				let qos = subscribe_to.into_iter().map(|crate::proto::SubscribeTo { topic_filter, qos }| {
+					connection.subscriptions.insert(topic_filter.into_string(), qos);
+					crate::proto::SubAckQos::Success(qos)
+				}).collect();
+				Some(crate::proto::Packet::SubAck(crate::proto::SubAck { packet_identifier, qos }))
+			},
+
+			crate::proto::Packet::Unsubscribe(crate::proto::Unsubscribe { packet_identifier, unsubscribe_from }) => {
+				for topic_filter in unsubscribe_from {
+					connection.subscriptions.remove(&topic_filter);
+				}
+				Some(crate::proto::Packet::UnsubAck(crate::proto::UnsubAck { packet_identifier }))
+			},
+
+			crate::proto::Packet::ConnAck(_) |
+			crate::proto::Packet::PingResp(_) |
+			crate::proto::Packet::PubAck(_) |
+			crate::proto::Packet::PubComp(_) |
+			crate::proto::Packet::SubAck(_) |
+			crate::proto::Packet::UnsubAck(_) => None,
+		};
+
+		if let Some(response) = response {
+			send(&connection.to_client, &response);
+		}
+	}
+
+	fn deliver(&mut self, publication: &crate::proto::Publication) {
+		for connection in self.connections.values_mut() {
+			let subscription_qos =
+				connection.subscriptions.iter()
+				.filter(|(topic_filter, _)| crate::topic::matches(topic_filter, &publication.topic_name))
+				.map(|(_, &qos)| qos)
+				.max();
+			let qos = match subscription_qos {
+				Some(subscription_qos) => std::cmp::min(subscription_qos, publication.qos),
+				None => continue,
+			};
+
+			let packet_identifier_dup_qos = match qos {
+				crate::proto::QoS::AtMostOnce => crate::proto::PacketIdentifierDupQoS::AtMostOnce,
+				crate::proto::QoS::AtLeastOnce => crate::proto::PacketIdentifierDupQoS::AtLeastOnce(connection.next_packet_identifier, false),
+				crate::proto::QoS::ExactlyOnce => crate::proto::PacketIdentifierDupQoS::ExactlyOnce(connection.next_packet_identifier, false),
+			};
+			if qos != crate::proto::QoS::AtMostOnce {
+				connection.next_packet_identifier += 1;
+			}
+
+			send(&connection.to_client, &crate::proto::Packet::Publish(crate::proto::Publish {
+				packet_identifier_dup_qos,
+				retain: false,
+				topic_name: publication.topic_name.as_str().to_owned(),
+				payload: publication.payload.clone(),
+			}));
+		}
+	}
+}
+
+fn send(to_client: &SharedPipe, packet: &crate::proto::Packet) {
+	let mut bytes = vec![];
+	if let Err(err) = crate::proto::encode(packet, &mut bytes) {
+		log::warn!("mock broker could not encode {:?}: {}", packet, err);
+		return;
+	}
+
+	// The pipe is closed if the connection was closed by `MockBroker::disconnect_all`, in which case the packet is dropped.
+	let _ = to_client.lock().write(&bytes);
+}
+
+/// A connection from a client to a [`MockBroker`]
+#[derive(Debug)]
+pub struct MockConnection {
+	id: usize,
+	broker: MockBroker,
+	to_client: SharedPipe,
+
+	/// Bytes written by the client that do not make up a complete packet yet
+	from_client: bytes::BytesMut,
+}
+
+impl std::io::Read for MockConnection {
+	fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+		self.to_client.lock().read(buf)
+	}
+}
+
+impl tokio_io::AsyncRead for MockConnection {
+}
+
+impl std::io::Write for MockConnection {
+	fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+		if self.to_client.lock().closed {
+			return Err(std::io::ErrorKind::BrokenPipe.into());
+		}
+
+		self.from_client.extend_from_slice(buf);
+
+		while let Some((packet, len)) = crate::proto::decode(&self.from_client).map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))? {
+			let _ = self.from_client.split_to(len);
+			self.broker.lock().handle(self.id, packet);
+		}
+
+		Ok(buf.len())
+	}
+
+	fn flush(&mut self) -> std::io::Result<()> {
+		Ok(())
+	}
+}
+
+impl tokio_io::AsyncWrite for MockConnection {
+	fn shutdown(&mut self) -> futures::Poll<(), std::io::Error> {
+		Ok(futures::Async::Ready(()))
+	}
+}
+
+impl Drop for MockConnection {
+	fn drop(&mut self) {
+		let _ = self.broker.lock().connections.remove(&self.id);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	#[test]
+	fn duplex() {
+		let mut runtime = tokio::runtime::current_thread::Runtime::new().expect("couldn't initialize tokio runtime");
+
+		let (a, b) = super::duplex();
+
+		let a = runtime.block_on(tokio_io::io::write_all(a, b"hello")).unwrap().0;
+
+		let (b, buf) = runtime.block_on(tokio_io::io::read_exact(b, [0_u8; 5])).unwrap();
+		assert_eq!(&buf, b"hello");
+
+		drop(a);
+		let (_, buf) = runtime.block_on(tokio_io::io::read_to_end(b, vec![])).unwrap();
+		assert!(buf.is_empty());
+	}
+}
@@ -0,0 +1,317 @@
+use futures::Future;
+
+/// The steps that a [`ScriptedServer`] runs on one connection, in order.
+///
+/// Once all the steps have run, the server closes the connection, ie the client reads EOF.
+#[derive(Debug, Default)]
+pub struct Script {
+	steps: std::collections::VecDeque<Step>,
+}
+
+#[derive(Debug)]
+enum Step {
+	Receives(crate::proto::Packet),
+	Sends {
+		/// The packet that the bytes are the encoding of, if they were scripted as a packet rather than as raw bytes
+		packet: Option<crate::proto::Packet>,
+		bytes: std::io::Cursor<bytes::Bytes>,
+	},
+	Delay(std::time::Duration),
+	Reset,
+}
+
+impl Script {
+	pub fn new() -> Self {
+		Default::default()
+	}
+
+	/// The server expects the client to send the given packet. The server panics if the client sends a different packet.
+	///
+	/// While the server is waiting for the packet, there is nothing for the client to read.
+	#[must_use]
+	pub fn receives(mut self, packet: crate::proto::Packet) -> Self {
+		self.steps.push_back(Step::Receives(packet));
+		self
+	}
+
+	/// The server sends the given packet.
+	///
+	/// # Panics
+	///
+	/// Panics if the packet cannot be encoded.
+	#[must_use]
+	pub fn sends(mut self, packet: crate::proto::Packet) -> Self {
+		let mut bytes = vec![];
+		crate::proto::encode(&packet, &mut bytes).expect("could not encode scripted packet");
+		self.steps.push_back(Step::Sends { packet: Some(packet), bytes: std::io::Cursor::new(bytes.into()) });
+		self
+	}
+
+	/// The server sends the given bytes, which need not be a valid packet. Use this to test how the client handles malformed packets.
+	#[must_use]
+	pub fn sends_raw(mut self, bytes: impl Into<bytes::Bytes>) -> Self {
+		self.steps.push_back(Step::Sends { packet: None, bytes: std::io::Cursor::new(bytes.into()) });
+		self
+	}
+
+	/// The server does nothing for the given duration. The client can't read from or write to the connection in the meantime.
+	#[must_use]
+	pub fn delay(mut self, duration: std::time::Duration) -> Self {
+		self.steps.push_back(Step::Delay(duration));
+		self
+	}
+
+	/// The server resets the connection, ie the client's reads and writes fail with [`std::io::ErrorKind::ConnectionReset`].
+	/// Any steps after this one are never run.
+	#[must_use]
+	pub fn reset(mut self) -> Self {
+		self.steps.push_back(Step::Reset);
+		self
+	}
+}
+
+/// An [`IoSource`](crate::IoSource) whose connections are served by a server that follows a [`Script`] per connection.
+///
+/// Unlike a [`MockBroker`](super::MockBroker), the server does not implement any of the protocol by itself. Every packet that the client sends
+/// and every response of the server is declared up-front, including malformed packets, delays and abrupt disconnects, so tests can exercise
+/// the client's reconnect and error paths precisely.
+///
+/// The first connection that the client makes runs the first script, its first reconnection runs the second script, and so on.
+/// Once all the scripts have been used up, new connections never complete.
+#[derive(Debug)]
+pub struct ScriptedServer {
+	scripts: std::collections::VecDeque<Script>,
+	progress: std::sync::Arc<std::sync::Mutex<Progress>>,
+}
+
+#[derive(Debug, Default)]
+struct Progress {
+	/// The number of scripts that have not run all their steps yet
+	remaining: usize,
+
+	/// Set if a connection was dropped before its script ran all its steps
+	failed: Option<String>,
+
+	/// The task waiting on `ScriptedServer::done`
+	task: Option<futures::task::Task>,
+}
+
+impl ScriptedServer {
+	pub fn new(scripts: Vec<Script>) -> Self {
+		let progress = Progress {
+			remaining: scripts.len(),
+			..Default::default()
+		};
+
+		ScriptedServer {
+			scripts: scripts.into(),
+			progress: std::sync::Arc::new(std::sync::Mutex::new(progress)),
+		}
+	}
+
+	/// Returns a future that resolves when every script has run all its steps.
+	///
+	/// The future fails if a connection was dropped, for example because the client reconnected, while its script still had steps to run.
+	pub fn done(&self) -> impl Future<Item = (), Error = String> {
+		let progress = self.progress.clone();
+
+		futures::future::poll_fn(move || {
+			let mut progress = progress.lock().unwrap_or_else(std::sync::PoisonError::into_inner);
+
+			if let Some(err) = progress.failed.take() {
+				return Err(err);
+			}
+
+			if progress.remaining == 0 {
+				return Ok(futures::Async::Ready(()));
+			}
+
+			progress.task = Some(futures::task::current());
+			Ok(futures::Async::NotReady)
+		})
+	}
+}
+
+impl crate::IoSource for ScriptedServer {
+	type Io = ScriptedConnection;
+	type Future = Box<dyn Future<Item = (Self::Io, Option<String>), Error = std::io::Error> + Send>;
+
+	fn connect(&mut self) -> Self::Future {
+		match self.scripts.pop_front() {
+			Some(Script { steps }) => {
+				log::debug!("scripted server accepted connection with {} steps", steps.len());
+
+				Box::new(futures::future::ok((ScriptedConnection {
+					steps,
+					delay: None,
+					receive_buffer: Default::default(),
+					progress: Some(self.progress.clone()),
+				}, None)))
+			},
+
+			None => {
+				log::debug!("scripted server has no more scripts, so the new connection never completes");
+				Box::new(futures::future::empty())
+			},
+		}
+	}
+}
+
+/// A connection to a [`ScriptedServer`]
+#[derive(Debug)]
+pub struct ScriptedConnection {
+	steps: std::collections::VecDeque<Step>,
+
+	/// The timer of the current `Step::Delay`
+	delay: Option<tokio_timer::Delay>,
+
+	/// The bytes of the packet that the current `Step::Receives` is waiting for, that have been written so far
+	receive_buffer: bytes::BytesMut,
+
+	/// Reset to `None` once the script is done, so that it's only reported once
+	progress: Option<std::sync::Arc<std::sync::Mutex<Progress>>>,
+}
+
+impl ScriptedConnection {
+	/// Runs the current step if it's a `Step::Delay`, and marks the script as done if it has run all its steps.
+	///
+	/// Returns an error if the client must wait for the delay to elapse, or if the connection has been reset.
+	fn poll_steps(&mut self) -> std::io::Result<()> {
+		loop {
+			match self.steps.front() {
+				Some(Step::Delay(duration)) => {
+					let duration = *duration;
+					let delay = self.delay.get_or_insert_with(|| tokio_timer::Delay::new(tokio_timer::clock::now() + duration));
+					match delay.poll() {
+						Ok(futures::Async::Ready(())) => {
+							self.delay = None;
+							let _ = self.steps.pop_front();
+						},
+						Ok(futures::Async::NotReady) => return Err(std::io::ErrorKind::WouldBlock.into()),
+						Err(err) => return Err(std::io::Error::other(err)),
+					}
+				},
+
+				Some(Step::Reset) => {
+					self.script_done();
+					return Err(std::io::ErrorKind::ConnectionReset.into());
+				},
+
+				Some(Step::Receives(_) | Step::Sends { .. }) => return Ok(()),
+
+				None => {
+					self.script_done();
+					return Ok(());
+				},
+			}
+		}
+	}
+
+	fn script_done(&mut self) {
+		if let Some(progress) = self.progress.take() {
+			let mut progress = progress.lock().unwrap_or_else(std::sync::PoisonError::into_inner);
+			progress.remaining -= 1;
+			if let Some(task) = &progress.task {
+				task.notify();
+			}
+		}
+	}
+}
+
+impl std::io::Read for ScriptedConnection {
+	fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+		self.poll_steps()?;
+
+		match self.steps.front_mut() {
+			Some(Step::Sends { packet, bytes }) => {
+				if bytes.position() == 0 {
+					log::debug!("scripted server sends {:?}", packet);
+				}
+
+				let read = std::io::Read::read(bytes, buf)?;
+				if bytes.position() == bytes.get_ref().len() as u64 {
+					let _ = self.steps.pop_front();
+				}
+				Ok(read)
+			},
+
+			// The client always writes the packet that the server is waiting for without needing to read anything first,
+			// so there is no need to register for a wakeup.
+			Some(Step::Receives(_)) => Err(std::io::ErrorKind::WouldBlock.into()),
+
+			Some(Step::Delay(_) | Step::Reset) => unreachable!("poll_steps returns an error for these steps"),
+
+			None => Ok(0),
+		}
+	}
+}
+
+impl tokio_io::AsyncRead for ScriptedConnection {
+}
+
+impl std::io::Write for ScriptedConnection {
+	fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+		use tokio_codec::Decoder;
+
+		self.poll_steps()?;
+
+		match self.steps.front() {
+			Some(Step::Receives(expected)) => {
+				let previous_len = self.receive_buffer.len();
+				self.receive_buffer.extend_from_slice(buf);
+
+				match crate::proto::PacketCodec::default().decode(&mut self.receive_buffer) {
+					Ok(Some(actual)) => {
+						log::debug!("scripted server received {:?}", actual);
+						assert_eq!(*expected, actual, "scripted server received an unexpected packet");
+
+						// Whatever the codec didn't consume belongs to the next packet, so it wasn't written yet
+						let written = previous_len + buf.len() - self.receive_buffer.len();
+						self.receive_buffer.clear();
+						let _ = self.steps.pop_front();
+						Ok(written)
+					},
+
+					Ok(None) => Ok(buf.len()),
+
+					Err(err) => panic!("scripted server received a malformed packet: {}", err),
+				}
+			},
+
+			// The client always reads what the server sends while it's waiting to write, so there is no need to register for a wakeup.
+			Some(Step::Sends { .. }) => Err(std::io::ErrorKind::WouldBlock.into()),
+
+			Some(Step::Delay(_) | Step::Reset) => unreachable!("poll_steps returns an error for these steps"),
+
+			None => Err(std::io::ErrorKind::BrokenPipe.into()),
+		}
+	}
+
+	fn flush(&mut self) -> std::io::Result<()> {
+		Ok(())
+	}
+}
+
+impl tokio_io::AsyncWrite for ScriptedConnection {
+	fn shutdown(&mut self) -> futures::Poll<(), std::io::Error> {
+		Ok(futures::Async::Ready(()))
+	}
+}
+
+impl Drop for ScriptedConnection {
+	fn drop(&mut self) {
+		if self.steps.is_empty() {
+			self.script_done();
+		}
+
+		if let Some(progress) = self.progress.take() {
+			let mut progress = progress.lock().unwrap_or_else(std::sync::PoisonError::into_inner);
+			if progress.failed.is_none() {
+				progress.failed = Some(format!("connection was dropped with {} scripted steps remaining: {:?}", self.steps.len(), self.steps));
+			}
+			if let Some(task) = &progress.task {
+				task.notify();
+			}
+		}
+	}
+}
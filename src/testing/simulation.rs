@@ -0,0 +1,198 @@
+use futures::Future;
+
+/// The ID that the future given to `Simulation::block_on` is polled with. Spawned futures are polled with their index in `Simulation::tasks`.
+const MAIN_TASK_ID: usize = usize::max_value();
+
+type Task = Box<dyn Future<Item = (), Error = ()>>;
+
+/// Runs futures, like a [`Client`](crate::Client) and the tasks that use it, in virtual time.
+///
+/// The simulation polls its futures until none of them can make progress, and then advances its virtual clock straight to the next timer
+/// that the futures are waiting on, like the client's keep-alive or reconnect back-off. So tests that involve timeouts run deterministically
+/// and without actually waiting for them. Combined with a [`MockBroker`](super::MockBroker), this makes it possible to assert things like
+/// "after the keep-alive expires with no PINGRESP, the client reconnects and retransmits its in-flight publications".
+///
+/// A simulation cannot be run from within another executor, like a tokio runtime.
+pub struct Simulation {
+	clock: VirtualClock,
+	timer: tokio_timer::Timer<VirtualPark, tokio_timer::clock::Clock>,
+	tasks: Vec<Option<futures::executor::Spawn<Task>>>,
+	ready: std::sync::Arc<ReadyTasks>,
+}
+
+impl Simulation {
+	/// Creates a simulation whose virtual clock starts at the current time.
+	pub fn new() -> Self {
+		let clock = VirtualClock(std::sync::Arc::new(std::sync::Mutex::new(std::time::Instant::now())));
+		let timer = tokio_timer::Timer::new_with_now(VirtualPark(clock.clone()), tokio_timer::clock::Clock::new_with_now(clock.clone()));
+
+		Simulation {
+			clock,
+			timer,
+			tasks: vec![],
+			ready: Default::default(),
+		}
+	}
+
+	/// The current virtual time
+	pub fn now(&self) -> std::time::Instant {
+		self.clock.get()
+	}
+
+	/// Spawns a future that is run alongside the future given to every later [`Simulation::block_on`] or [`Simulation::advance`].
+	pub fn spawn<F>(&mut self, future: F) where F: Future<Item = (), Error = ()> + 'static {
+		self.ready.insert(self.tasks.len());
+		self.tasks.push(Some(futures::executor::spawn(Box::new(future))));
+	}
+
+	/// Runs the given future and the spawned futures until the given future completes, advancing virtual time whenever none of them can make progress.
+	///
+	/// # Panics
+	///
+	/// Panics if none of the futures can make progress and none of them are waiting on a timer, since the given future can then never complete.
+	pub fn block_on<F>(&mut self, future: F) -> Result<F::Item, F::Error> where F: Future {
+		let mut future = futures::executor::spawn(future);
+		let notify = futures::executor::NotifyHandle::from(self.ready.clone());
+		self.ready.insert(MAIN_TASK_ID);
+
+		let clock = tokio_timer::clock::Clock::new_with_now(self.clock.clone());
+		let timer_handle = self.timer.handle();
+		let mut enter = tokio_executor::enter().expect("a simulation cannot be run from within another executor");
+
+		tokio_timer::clock::with_default(&clock, &mut enter, |enter| tokio_timer::with_default(&timer_handle, enter, |_| loop {
+			while let Some(id) = self.ready.pop() {
+				if id == MAIN_TASK_ID {
+					if let futures::Async::Ready(item) = future.poll_future_notify(&notify, MAIN_TASK_ID)? {
+						return Ok(item);
+					}
+				}
+				else if let Some(task) = self.tasks.get_mut(id) {
+					let done = match task {
+						Some(spawned) => match spawned.poll_future_notify(&notify, id) {
+							Ok(futures::Async::NotReady) => false,
+							Ok(futures::Async::Ready(())) | Err(()) => true,
+						},
+						None => false,
+					};
+					if done {
+						*task = None;
+					}
+				}
+			}
+
+			// Nothing can make progress, so advance the virtual clock to the next timer, which notifies the futures that are waiting on it
+			match self.timer.turn(None) {
+				Ok(_) => (),
+				Err(Deadlock) => panic!("simulation is deadlocked: no future can make progress and none of them are waiting on a timer"),
+			}
+		}))
+	}
+
+	/// Runs the spawned futures while advancing virtual time by the given duration.
+	///
+	/// # Panics
+	///
+	/// Panics if the simulation's timer fails.
+	pub fn advance(&mut self, duration: std::time::Duration) {
+		let delay = tokio_timer::Delay::new(self.now() + duration);
+		self.block_on(delay).expect("simulation timer failed");
+	}
+}
+
+impl Default for Simulation {
+	fn default() -> Self {
+		Simulation::new()
+	}
+}
+
+impl std::fmt::Debug for Simulation {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("Simulation")
+			.field("now", &self.now())
+			.field("tasks", &self.tasks.iter().filter(|task| task.is_some()).count())
+			.finish_non_exhaustive()
+	}
+}
+
+/// The IDs of the futures that have been notified and need to be polled
+#[derive(Debug, Default)]
+struct ReadyTasks(std::sync::Mutex<std::collections::BTreeSet<usize>>);
+
+impl ReadyTasks {
+	fn insert(&self, id: usize) {
+		let _ = self.lock().insert(id);
+	}
+
+	fn pop(&self) -> Option<usize> {
+		let mut ready = self.lock();
+		let id = *ready.iter().next()?;
+		let _ = ready.remove(&id);
+		Some(id)
+	}
+
+	fn lock(&self) -> std::sync::MutexGuard<'_, std::collections::BTreeSet<usize>> {
+		// The set only holds plain values that can't be left in an inconsistent state, so a poisoned lock is still usable.
+		self.0.lock().unwrap_or_else(std::sync::PoisonError::into_inner)
+	}
+}
+
+impl futures::executor::Notify for ReadyTasks {
+	fn notify(&self, id: usize) {
+		self.insert(id);
+	}
+}
+
+/// The virtual clock of a `Simulation`, used as the clock of its futures as well as of its timer
+#[derive(Clone, Debug)]
+struct VirtualClock(std::sync::Arc<std::sync::Mutex<std::time::Instant>>);
+
+impl VirtualClock {
+	fn get(&self) -> std::time::Instant {
+		*self.0.lock().unwrap_or_else(std::sync::PoisonError::into_inner)
+	}
+
+	fn advance(&self, duration: std::time::Duration) {
+		*self.0.lock().unwrap_or_else(std::sync::PoisonError::into_inner) += duration;
+	}
+}
+
+impl tokio_timer::clock::Now for VirtualClock {
+	fn now(&self) -> std::time::Instant {
+		self.get()
+	}
+}
+
+/// Parks the timer of a `Simulation` by advancing the virtual clock instead of blocking the thread
+#[derive(Debug)]
+struct VirtualPark(VirtualClock);
+
+/// The error from parking the timer of a `Simulation` when it is not waiting on any timer, so time would never advance
+#[derive(Debug)]
+struct Deadlock;
+
+impl tokio_executor::park::Park for VirtualPark {
+	type Unpark = VirtualUnpark;
+	type Error = Deadlock;
+
+	fn unpark(&self) -> Self::Unpark {
+		VirtualUnpark
+	}
+
+	fn park(&mut self) -> Result<(), Self::Error> {
+		Err(Deadlock)
+	}
+
+	fn park_timeout(&mut self, duration: std::time::Duration) -> Result<(), Self::Error> {
+		self.0.advance(duration);
+		Ok(())
+	}
+}
+
+/// The timer of a `Simulation` never blocks, so there is nothing to unpark
+#[derive(Debug)]
+struct VirtualUnpark;
+
+impl tokio_executor::park::Unpark for VirtualUnpark {
+	fn unpark(&self) {
+	}
+}
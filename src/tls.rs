@@ -0,0 +1,541 @@
+/*!
+ * An [`IoSource`](crate::IoSource) that connects to the server over TLS, using rustls.
+ *
+ * The TLS connection runs on top of the connections of another `IoSource`, like a TCP connection.
+ * The server's certificate is verified against the Mozilla root certificates by default, or against the root certificates given to
+ * [`TlsIoSource::add_root_certificates`].
+ *
+ * For servers that authenticate clients with certificates, [`TlsIoSource::set_client_certificate_provider`] sets a provider that is
+ * asked for the client certificate for every new connection. This allows short-lived certificates, like the ones issued by
+ * an EST or SCEP workflow, to be renewed without recreating the client; the client uses the renewed certificate the next time it reconnects.
+ * [`PemFiles`] is a provider that reads the certificate and key from files every time, so the files can be replaced while the client is running.
+ *
+ * Servers that need the client to offer specific ALPN protocols, or to send a different SNI hostname than the server name, are supported
+ * with [`TlsIoSource::set_alpn_protocols`] and [`TlsIoSource::set_sni_hostname`].
+ *
+ * Reconnections resume the TLS session of a previous connection when the server allows it, with TLS 1.3 session tickets or
+ * TLS 1.2 session IDs and tickets, so that they don't need a full handshake. See [`TlsIoSource::set_session_resumption`].
+ *
+ * This module is only available with the `tls` feature.
+ */
+
+use futures::Future;
+
+/// A client certificate chain and its private key
+pub struct ClientCertificate {
+	chain: Vec<rustls::pki_types::CertificateDer<'static>>,
+	key: rustls::pki_types::PrivateKeyDer<'static>,
+}
+
+impl ClientCertificate {
+	/// Parses a client certificate from PEM.
+	///
+	/// `chain` contains the client certificate followed by any intermediate certificates. `key` contains the certificate's private key,
+	/// in PKCS#1, PKCS#8 or SEC1 format.
+	pub fn from_pem(chain: &[u8], key: &[u8]) -> std::io::Result<Self> {
+		let chain = rustls_pemfile::certs(&mut &*chain).collect::<Result<Vec<_>, _>>()?;
+		if chain.is_empty() {
+			return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "client certificate chain does not contain any certificates"));
+		}
+
+		let key =
+			rustls_pemfile::private_key(&mut &*key)?
+			.ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "client certificate key does not contain a private key"))?;
+
+		Ok(ClientCertificate {
+			chain,
+			key,
+		})
+	}
+}
+
+impl std::fmt::Debug for ClientCertificate {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("ClientCertificate")
+			.field("chain_len", &self.chain.len())
+			.finish_non_exhaustive()
+	}
+}
+
+/// Provides the client certificate that a [`TlsIoSource`] uses for a new connection.
+///
+/// The trait is automatically implemented for all [`FnMut`] that return a client certificate.
+pub trait ClientCertificateProvider {
+	/// Returns the client certificate for the next connection
+	fn client_certificate(&mut self) -> std::io::Result<ClientCertificate>;
+}
+
+impl<F> ClientCertificateProvider for F where F: FnMut() -> std::io::Result<ClientCertificate> {
+	fn client_certificate(&mut self) -> std::io::Result<ClientCertificate> {
+		(self)()
+	}
+}
+
+/// A [`ClientCertificateProvider`] that reads the client certificate from PEM files for every connection.
+#[derive(Clone, Debug)]
+pub struct PemFiles {
+	chain: std::path::PathBuf,
+	key: std::path::PathBuf,
+}
+
+impl PemFiles {
+	/// Creates a provider for the given certificate chain file and private key file. See [`ClientCertificate::from_pem`] for their contents.
+	pub fn new(chain: impl Into<std::path::PathBuf>, key: impl Into<std::path::PathBuf>) -> Self {
+		PemFiles {
+			chain: chain.into(),
+			key: key.into(),
+		}
+	}
+}
+
+impl ClientCertificateProvider for PemFiles {
+	fn client_certificate(&mut self) -> std::io::Result<ClientCertificate> {
+		let chain = std::fs::read(&self.chain)?;
+		let key = std::fs::read(&self.key)?;
+		ClientCertificate::from_pem(&chain, &key)
+	}
+}
+
+/// Connects to an MQTT server over TLS, on top of the connections of another [`IoSource`](crate::IoSource).
+pub struct TlsIoSource<S> {
+	io_source: S,
+	server_name: rustls::pki_types::ServerName<'static>,
+	root_certificates: Option<std::sync::Arc<rustls::RootCertStore>>,
+	alpn_protocols: Vec<Vec<u8>>,
+	sni: Sni,
+	session_resumption: bool,
+	client_certificate_provider: Option<Box<dyn ClientCertificateProvider + Send>>,
+
+	/// The config of the previous connection, and the client certificate chain it was built with
+	config: Option<(std::sync::Arc<rustls::ClientConfig>, Option<Vec<rustls::pki_types::CertificateDer<'static>>>)>,
+}
+
+impl<S> TlsIoSource<S> {
+	/// Creates an `IoSource` that connects to the server with the given name, like `broker.example.com`, over the connections of the given `io_source`.
+	///
+	/// The server name is used to verify the server's certificate, and is sent to the server with SNI. It can also be an IP address.
+	pub fn new(io_source: S, server_name: &str) -> std::io::Result<Self> {
+		let server_name = parse_server_name(server_name)?;
+
+		Ok(TlsIoSource {
+			io_source,
+			server_name,
+			root_certificates: None,
+			alpn_protocols: vec![],
+			sni: Sni::ServerName,
+			session_resumption: true,
+			client_certificate_provider: None,
+			config: None,
+		})
+	}
+
+	/// Adds the root certificates in the given PEM to the ones that the server's certificate is verified against.
+	///
+	/// This is needed for servers with certificates from a private CA. The first call replaces the default Mozilla root certificates,
+	/// so that only the given CAs are trusted.
+	pub fn add_root_certificates(&mut self, pem: &[u8]) -> std::io::Result<()> {
+		let mut root_certificates = match &self.root_certificates {
+			Some(root_certificates) => (**root_certificates).clone(),
+			None => rustls::RootCertStore::empty(),
+		};
+
+		for certificate in rustls_pemfile::certs(&mut &*pem) {
+			root_certificates.add(certificate?).map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
+		}
+
+		self.root_certificates = Some(std::sync::Arc::new(root_certificates));
+		self.config = None;
+		Ok(())
+	}
+
+	/// Sets the protocols that the client offers to the server with ALPN, in order of preference, like `mqtt`.
+	///
+	/// Some servers require a specific protocol, like the `x-amzn-mqtt-ca` protocol that AWS IoT Core requires for MQTT connections on port 443.
+	/// The protocol that the server chose can be read from the connection with [`TlsIo::alpn_protocol`]. The connection fails if the server
+	/// does not support any of the protocols.
+	///
+	/// Defaults to no protocols, ie the client does not use ALPN.
+	pub fn set_alpn_protocols(&mut self, alpn_protocols: Vec<Vec<u8>>) {
+		self.alpn_protocols = alpn_protocols;
+		self.config = None;
+	}
+
+	/// Sets the hostname that is sent to the server with SNI, when it's different from the server name that the server's certificate
+	/// is verified against. This is needed for servers behind a load balancer or gateway that routes connections by SNI.
+	///
+	/// `None` disables SNI, so the server only sees the connection's IP address.
+	///
+	/// Defaults to sending the server name given to [`TlsIoSource::new`].
+	pub fn set_sni_hostname(&mut self, sni_hostname: Option<&str>) -> std::io::Result<()> {
+		self.sni = match sni_hostname {
+			Some(sni_hostname) => Sni::Hostname(parse_server_name(sni_hostname)?),
+			None => Sni::Disabled,
+		};
+		self.config = None;
+		Ok(())
+	}
+
+	/// Sets whether connections resume the TLS session of a previous connection, if the server allows it.
+	///
+	/// Resumed connections skip the server's certificate and the key exchange of a full handshake, which makes reconnecting over slow
+	/// or flaky links faster and cheaper. Whether a connection was resumed can be read from it with [`TlsIo::is_resumed`].
+	/// Sessions are stored in memory, so they are not resumed across restarts of the process. They are also not resumed once
+	/// the client certificate provider returns a different certificate, since the server would otherwise authenticate
+	/// the resumed connection with the previous one.
+	///
+	/// Defaults to `true`.
+	pub fn set_session_resumption(&mut self, session_resumption: bool) {
+		self.session_resumption = session_resumption;
+		self.config = None;
+	}
+
+	/// Sets a provider of the client certificate that will be invoked before each new connection to the server.
+	///
+	/// If the provider fails, the client backs off and tries again like it does for any other connection failure.
+	///
+	/// Defaults to no provider, ie the client does not authenticate with a certificate.
+	pub fn set_client_certificate_provider<P>(&mut self, client_certificate_provider: P) where P: ClientCertificateProvider + Send + 'static {
+		self.client_certificate_provider = Some(Box::new(client_certificate_provider));
+		self.config = None;
+	}
+
+	fn client_config(&mut self) -> std::io::Result<std::sync::Arc<rustls::ClientConfig>> {
+		let client_certificate = match &mut self.client_certificate_provider {
+			Some(client_certificate_provider) => Some(client_certificate_provider.client_certificate()?),
+			None => None,
+		};
+		let client_certificate_chain = client_certificate.as_ref().map(|client_certificate| client_certificate.chain.clone());
+
+		// rustls only resumes sessions with the config that they were made with, so the config is reused for as long as the
+		// client certificate stays the same.
+		if let Some((config, previous_client_certificate_chain)) = &self.config {
+			if *previous_client_certificate_chain == client_certificate_chain {
+				return Ok(config.clone());
+			}
+		}
+
+		let root_certificates = match &self.root_certificates {
+			Some(root_certificates) => root_certificates.clone(),
+			None => std::sync::Arc::new(rustls::RootCertStore { roots: webpki_roots::TLS_SERVER_ROOTS.to_vec() }),
+		};
+
+		let crypto_provider = std::sync::Arc::new(rustls::crypto::ring::default_provider());
+		let builder =
+			rustls::ClientConfig::builder_with_provider(crypto_provider.clone())
+			.with_safe_default_protocol_versions()
+			.map_err(into_io_error)?;
+
+		let builder = match &self.sni {
+			Sni::ServerName | Sni::Disabled => builder.with_root_certificates(root_certificates),
+
+			// The connection is made to the SNI hostname, so the server's certificate must be verified against the server name explicitly
+			Sni::Hostname(_) => {
+				let verifier =
+					rustls::client::WebPkiServerVerifier::builder_with_provider(root_certificates, crypto_provider)
+					.build()
+					.map_err(std::io::Error::other)?;
+				builder.dangerous().with_custom_certificate_verifier(std::sync::Arc::new(VerifyServerNameVerifier {
+					inner: verifier,
+					server_name: self.server_name.clone(),
+				}))
+			},
+		};
+
+		let mut config = match client_certificate {
+			Some(ClientCertificate { chain, key }) => builder.with_client_auth_cert(chain, key).map_err(into_io_error)?,
+			None => builder.with_no_client_auth(),
+		};
+		config.alpn_protocols.clone_from(&self.alpn_protocols);
+		config.enable_sni = !matches!(self.sni, Sni::Disabled);
+		if !self.session_resumption {
+			config.resumption = rustls::client::Resumption::disabled();
+		}
+
+		let config = std::sync::Arc::new(config);
+		self.config = Some((config.clone(), client_certificate_chain));
+		Ok(config)
+	}
+}
+
+/// The hostname that a [`TlsIoSource`] sends to the server with SNI
+#[derive(Debug)]
+enum Sni {
+	ServerName,
+	Hostname(rustls::pki_types::ServerName<'static>),
+	Disabled,
+}
+
+/// Verifies the server's certificate against a fixed server name instead of the SNI hostname that the connection was made to
+#[derive(Debug)]
+struct VerifyServerNameVerifier {
+	inner: std::sync::Arc<rustls::client::WebPkiServerVerifier>,
+	server_name: rustls::pki_types::ServerName<'static>,
+}
+
+impl rustls::client::danger::ServerCertVerifier for VerifyServerNameVerifier {
+	fn verify_server_cert(
+		&self,
+		end_entity: &rustls::pki_types::CertificateDer<'_>,
+		intermediates: &[rustls::pki_types::CertificateDer<'_>],
+		_server_name: &rustls::pki_types::ServerName<'_>,
+		ocsp_response: &[u8],
+		now: rustls::pki_types::UnixTime,
+	) -> Result<rustls::client::danger::ServerCertVerified, rustls::Error> {
+		self.inner.verify_server_cert(end_entity, intermediates, &self.server_name, ocsp_response, now)
+	}
+
+	fn verify_tls12_signature(
+		&self,
+		message: &[u8],
+		cert: &rustls::pki_types::CertificateDer<'_>,
+		dss: &rustls::DigitallySignedStruct,
+	) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+		self.inner.verify_tls12_signature(message, cert, dss)
+	}
+
+	fn verify_tls13_signature(
+		&self,
+		message: &[u8],
+		cert: &rustls::pki_types::CertificateDer<'_>,
+		dss: &rustls::DigitallySignedStruct,
+	) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+		self.inner.verify_tls13_signature(message, cert, dss)
+	}
+
+	fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+		self.inner.supported_verify_schemes()
+	}
+}
+
+impl<S> crate::IoSource for TlsIoSource<S>
+where
+	S: crate::IoSource,
+	S::Future: Future<Error = std::io::Error>,
+{
+	type Io = TlsIo<S::Io>;
+	type Future = TlsConnectFuture<S::Future, S::Io>;
+
+	fn connect(&mut self) -> Self::Future {
+		let connection =
+			self.client_config()
+			.and_then(|config| {
+				let server_name = match &self.sni {
+					Sni::ServerName | Sni::Disabled => self.server_name.clone(),
+					Sni::Hostname(sni_hostname) => sni_hostname.clone(),
+				};
+				rustls::ClientConnection::new(config, server_name).map_err(into_io_error)
+			});
+
+		let state = match connection {
+			Ok(connection) => ConnectState::Connecting(self.io_source.connect(), Some(connection)),
+			Err(err) => ConnectState::Failed(Some(err)),
+		};
+
+		TlsConnectFuture(state)
+	}
+}
+
+impl<S> std::fmt::Debug for TlsIoSource<S> where S: std::fmt::Debug {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("TlsIoSource")
+			.field("io_source", &self.io_source)
+			.field("server_name", &self.server_name)
+			.field("sni", &self.sni)
+			.field("session_resumption", &self.session_resumption)
+			.field("client_certificate_provider", &self.client_certificate_provider.is_some())
+			.finish_non_exhaustive()
+	}
+}
+
+/// The connection future of a [`TlsIoSource`]
+pub struct TlsConnectFuture<F, I>(ConnectState<F, I>);
+
+enum ConnectState<F, I> {
+	Connecting(F, Option<rustls::ClientConnection>),
+	Handshaking(Option<TlsIo<I>>, Option<String>),
+	Failed(Option<std::io::Error>),
+}
+
+impl<F, I> Future for TlsConnectFuture<F, I>
+where
+	F: Future<Item = (I, Option<String>), Error = std::io::Error>,
+	I: std::io::Read + std::io::Write,
+{
+	type Item = (TlsIo<I>, Option<String>);
+	type Error = std::io::Error;
+
+	fn poll(&mut self) -> futures::Poll<Self::Item, Self::Error> {
+		loop {
+			match &mut self.0 {
+				ConnectState::Connecting(inner, connection) => {
+					let (inner, password) = futures::try_ready!(inner.poll());
+					let connection = connection.take().expect("polled after completion");
+					self.0 = ConnectState::Handshaking(Some(TlsIo { inner, connection }), password);
+				},
+
+				ConnectState::Handshaking(io, password) => {
+					match io.as_mut().expect("polled after completion").handshake() {
+						Ok(()) => (),
+						Err(ref err) if err.kind() == std::io::ErrorKind::WouldBlock => return Ok(futures::Async::NotReady),
+						Err(err) => return Err(err),
+					}
+					let io = io.take().expect("polled after completion");
+					log::debug!("TLS handshake succeeded, session resumed: {}", io.is_resumed());
+					return Ok(futures::Async::Ready((io, password.take())));
+				},
+
+				ConnectState::Failed(err) => return Err(err.take().expect("polled after completion")),
+			}
+		}
+	}
+}
+
+impl<F, I> std::fmt::Debug for TlsConnectFuture<F, I> {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		let state = match &self.0 {
+			ConnectState::Connecting(..) => "Connecting",
+			ConnectState::Handshaking(..) => "Handshaking",
+			ConnectState::Failed(_) => "Failed",
+		};
+		f.debug_struct("TlsConnectFuture").field("state", &state).finish()
+	}
+}
+
+/// A connection of a [`TlsIoSource`]
+#[derive(Debug)]
+pub struct TlsIo<T> {
+	inner: T,
+	connection: rustls::ClientConnection,
+}
+
+impl<T> TlsIo<T> {
+	/// The protocol that the server chose with ALPN, if any. See [`TlsIoSource::set_alpn_protocols`].
+	pub fn alpn_protocol(&self) -> Option<&[u8]> {
+		self.connection.alpn_protocol()
+	}
+
+	/// Whether the connection resumed the TLS session of a previous connection. See [`TlsIoSource::set_session_resumption`].
+	pub fn is_resumed(&self) -> bool {
+		self.connection.handshake_kind() == Some(rustls::HandshakeKind::Resumed)
+	}
+}
+
+impl<T> TlsIo<T> where T: std::io::Read + std::io::Write {
+	/// Continues the TLS handshake. Fails with [`std::io::ErrorKind::WouldBlock`] if the inner connection is not ready yet.
+	fn handshake(&mut self) -> std::io::Result<()> {
+		loop {
+			self.write_tls()?;
+
+			if !self.connection.is_handshaking() {
+				return Ok(());
+			}
+
+			if !self.read_tls()? {
+				return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "connection closed during TLS handshake"));
+			}
+		}
+	}
+
+	/// Reads and processes TLS records from the inner connection. Returns `false` if the inner connection has been closed.
+	fn read_tls(&mut self) -> std::io::Result<bool> {
+		let read = self.connection.read_tls(&mut self.inner)?;
+		let _ = self.connection.process_new_packets().map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
+		Ok(read > 0)
+	}
+
+	/// Writes all pending TLS records to the inner connection.
+	fn write_tls(&mut self) -> std::io::Result<()> {
+		while self.connection.wants_write() {
+			let _ = self.connection.write_tls(&mut self.inner)?;
+		}
+		Ok(())
+	}
+}
+
+impl<T> std::io::Read for TlsIo<T> where T: std::io::Read + std::io::Write {
+	fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+		loop {
+			match std::io::Read::read(&mut self.connection.reader(), buf) {
+				Err(ref err) if err.kind() == std::io::ErrorKind::WouldBlock => (),
+				result => return result,
+			}
+
+			// A closed inner connection makes the reader return an error for the missing close_notify alert
+			let _ = self.read_tls()?;
+
+			// Send alerts and key updates caused by the new records, if possible
+			match self.write_tls() {
+				Err(ref err) if err.kind() == std::io::ErrorKind::WouldBlock => (),
+				result => result?,
+			}
+		}
+	}
+}
+
+impl<T> tokio_io::AsyncRead for TlsIo<T> where T: tokio_io::AsyncRead + std::io::Write {
+}
+
+impl<T> std::io::Write for TlsIo<T> where T: std::io::Read + std::io::Write {
+	fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+		loop {
+			let written = std::io::Write::write(&mut self.connection.writer(), buf)?;
+			if written > 0 || buf.is_empty() {
+				match self.write_tls() {
+					Err(ref err) if err.kind() == std::io::ErrorKind::WouldBlock => (),
+					result => result?,
+				}
+				return Ok(written);
+			}
+
+			// The connection's buffer is full, so write it to the inner connection to make room
+			let _ = self.connection.write_tls(&mut self.inner)?;
+		}
+	}
+
+	fn flush(&mut self) -> std::io::Result<()> {
+		std::io::Write::flush(&mut self.connection.writer())?;
+		self.write_tls()?;
+		self.inner.flush()
+	}
+}
+
+impl<T> tokio_io::AsyncWrite for TlsIo<T> where T: std::io::Read + tokio_io::AsyncWrite {
+	fn shutdown(&mut self) -> futures::Poll<(), std::io::Error> {
+		self.connection.send_close_notify();
+		match self.write_tls() {
+			Ok(()) => (),
+			Err(ref err) if err.kind() == std::io::ErrorKind::WouldBlock => return Ok(futures::Async::NotReady),
+			Err(err) => return Err(err),
+		}
+
+		self.inner.shutdown()
+	}
+}
+
+fn parse_server_name(server_name: &str) -> std::io::Result<rustls::pki_types::ServerName<'static>> {
+	let server_name =
+		<rustls::pki_types::ServerName<'_> as std::convert::TryFrom<_>>::try_from(server_name)
+		.map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+	Ok(server_name.to_owned())
+}
+
+fn into_io_error(err: rustls::Error) -> std::io::Error {
+	std::io::Error::other(err)
+}
+
+#[cfg(test)]
+mod tests {
+	#[test]
+	fn client_certificate_from_pem() {
+		let _ = super::ClientCertificate::from_pem(
+			include_bytes!("../tests/certs/client.pem"),
+			include_bytes!("../tests/certs/client.key"),
+		).unwrap();
+
+		let err = super::ClientCertificate::from_pem(b"", include_bytes!("../tests/certs/client.key")).unwrap_err();
+		assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+		let err = super::ClientCertificate::from_pem(include_bytes!("../tests/certs/client.pem"), b"").unwrap_err();
+		assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+	}
+}
@@ -0,0 +1,530 @@
+/*!
+ * Utilities for working with topic names and topic filters.
+ *
+ * Ref: 4.7 Topic Names and Topic Filters
+ */
+
+pub(crate) const SHARED_SUBSCRIPTION_PREFIX: &str = "$share/";
+
+/// Returns whether a publication to the given topic name would be delivered to a subscription with the given topic filter.
+///
+/// `+` in the topic filter matches exactly one topic level, and a trailing `#` matches any number of levels including the parent level.
+/// Topic names that start with `$` are not matched by topic filters that start with a wildcard.
+///
+/// For a shared subscription, the topic name is matched against the topic filter that follows the share name.
+pub fn matches(topic_filter: &str, topic_name: &str) -> bool {
+	let topic_filter = match parse_shared_subscription(topic_filter) {
+		Some((_, topic_filter)) => topic_filter,
+		None => topic_filter,
+	};
+
+	let mut topic_filter_levels = filter_levels(topic_filter).peekable();
+	let mut topic_name_levels = levels(topic_name);
+
+	if topic_name.starts_with('$') {
+		match topic_filter_levels.peek() {
+			Some(FilterLevel::Literal(_)) |
+			None => (),
+			Some(_) => return false,
+		}
+	}
+
+	loop {
+		match (topic_filter_levels.next(), topic_name_levels.next()) {
+			(Some(FilterLevel::MultiLevelWildcard), _) |
+			(None, None) => return true,
+
+			(Some(FilterLevel::SingleLevelWildcard), Some(_)) => (),
+
+			(Some(FilterLevel::Literal(topic_filter_level)), Some(topic_name_level)) =>
+				if topic_filter_level != topic_name_level {
+					return false;
+				},
+
+			(Some(_), None) |
+			(None, Some(_)) => return false,
+		}
+	}
+}
+
+/// Returns an iterator over the levels of the given topic name.
+///
+/// A topic name always has at least one level, though levels can be empty. For example, `"/finance"` has the levels `""` and `"finance"`.
+pub fn levels(topic_name: &str) -> Levels<'_> {
+	Levels(topic_name.split('/'))
+}
+
+/// The iterator returned by [`levels`]
+#[derive(Clone, Debug)]
+pub struct Levels<'a>(std::str::Split<'a, char>);
+
+impl<'a> Iterator for Levels<'a> {
+	type Item = &'a str;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		self.0.next()
+	}
+}
+
+/// Returns an iterator over the levels of the given topic filter, which distinguishes the wildcard levels from literal ones.
+///
+/// The iterator does not validate the topic filter, so a level like `"a+"` where a wildcard does not occupy the whole level
+/// is treated as a literal level.
+pub fn filter_levels(topic_filter: &str) -> FilterLevels<'_> {
+	FilterLevels(levels(topic_filter))
+}
+
+/// The iterator returned by [`filter_levels`]
+#[derive(Clone, Debug)]
+pub struct FilterLevels<'a>(Levels<'a>);
+
+impl<'a> Iterator for FilterLevels<'a> {
+	type Item = FilterLevel<'a>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		match self.0.next()? {
+			"+" => Some(FilterLevel::SingleLevelWildcard),
+			"#" => Some(FilterLevel::MultiLevelWildcard),
+			level => Some(FilterLevel::Literal(level)),
+		}
+	}
+}
+
+/// A level of a topic filter
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FilterLevel<'a> {
+	/// A level that only matches the identical topic level
+	Literal(&'a str),
+
+	/// `+`, which matches any single topic level
+	SingleLevelWildcard,
+
+	/// `#`, which matches any number of topic levels
+	MultiLevelWildcard,
+}
+
+/// Splits a shared subscription's topic filter of the form `$share/<share_name>/<topic_filter>` into its share name and topic filter.
+///
+/// Returns `None` if the given topic filter is not that of a shared subscription.
+pub fn parse_shared_subscription(topic_filter: &str) -> Option<(&str, &str)> {
+	if !topic_filter.starts_with(SHARED_SUBSCRIPTION_PREFIX) {
+		return None;
+	}
+
+	let mut parts = topic_filter[SHARED_SUBSCRIPTION_PREFIX.len()..].splitn(2, '/');
+	match (parts.next(), parts.next()) {
+		(Some(share_name), Some(topic_filter)) if !share_name.is_empty() && !topic_filter.is_empty() => Some((share_name, topic_filter)),
+		_ => None,
+	}
+}
+
+/// A topic name that a publication can be sent to.
+///
+/// It is guaranteed to be non-empty, to not contain U+0000 or the `+` and `#` wildcards, and to fit in an MQTT packet.
+///
+/// Clones share the same string, so cloning a topic name, and the [`Publication`](crate::proto::Publication) that holds it, doesn't allocate.
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct TopicName(std::sync::Arc<str>);
+
+impl TopicName {
+	/// Validates the given topic name.
+	pub fn new(topic_name: String) -> Result<Self, TopicError> {
+		validate(&topic_name)?;
+
+		if topic_name.contains(&['+', '#'][..]) {
+			return Err(TopicError::WildcardInTopicName(topic_name));
+		}
+
+		Ok(TopicName(topic_name.into()))
+	}
+
+	pub fn as_str(&self) -> &str {
+		&self.0
+	}
+
+	/// Copies the topic name into a `String`, since its string may be shared with clones of it
+	pub fn into_string(self) -> String {
+		self.0.to_string()
+	}
+
+	/// For topic names that are known to be valid, such as the ones that were validated before being converted to strings
+	pub(crate) fn new_unchecked(topic_name: String) -> Self {
+		TopicName(topic_name.into())
+	}
+}
+
+/// Hands out shared [`TopicName`]s for a set of topic names that are published to repeatedly.
+///
+/// Parsing a topic name validates it and allocates its string, for every publication. An interner does that once per distinct topic name,
+/// and returns clones of the same `TopicName` after that, so the publications and their retries share one string.
+/// This is worthwhile for applications that publish to a fixed set of topics, like the telemetry topics of a device.
+///
+/// The interner is limited to a maximum number of topic names, so that it doesn't grow without bound when the topic names aren't fixed after all.
+/// Topic names that don't fit are still validated and returned, but not remembered.
+///
+/// Clones of the interner share its topic names, and it can be used from multiple threads.
+#[derive(Clone)]
+pub struct TopicInterner {
+	inner: std::sync::Arc<std::sync::Mutex<std::collections::HashSet<TopicName>>>,
+	max_topic_names: usize,
+}
+
+impl TopicInterner {
+	/// Creates an interner that remembers up to the given number of topic names.
+	pub fn new(max_topic_names: usize) -> Self {
+		TopicInterner {
+			inner: Default::default(),
+			max_topic_names,
+		}
+	}
+
+	/// Returns the shared `TopicName` for the given topic name, validating it if it hasn't been interned yet.
+	pub fn intern(&self, topic_name: &str) -> Result<TopicName, TopicError> {
+		let mut topic_names = self.lock();
+
+		if let Some(interned) = topic_names.get(topic_name) {
+			return Ok(interned.clone());
+		}
+
+		let interned = TopicName::new(topic_name.to_owned())?;
+		if topic_names.len() < self.max_topic_names {
+			let _ = topic_names.insert(interned.clone());
+		}
+		Ok(interned)
+	}
+
+	/// The number of topic names that have been interned
+	pub fn len(&self) -> usize {
+		self.lock().len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.lock().is_empty()
+	}
+
+	/// Forgets all the interned topic names, for example to make room for a new set of topic names.
+	///
+	/// `TopicName`s that were already handed out keep their strings.
+	pub fn clear(&self) {
+		self.lock().clear();
+	}
+
+	fn lock(&self) -> std::sync::MutexGuard<'_, std::collections::HashSet<TopicName>> {
+		// The set is never left half-modified, so a poisoned lock still has a consistent set.
+		self.inner.lock().unwrap_or_else(std::sync::PoisonError::into_inner)
+	}
+}
+
+impl std::fmt::Debug for TopicInterner {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("TopicInterner")
+			.field("len", &self.len())
+			.field("max_topic_names", &self.max_topic_names)
+			.finish_non_exhaustive()
+	}
+}
+
+/// A topic filter that can be subscribed to.
+///
+/// It is guaranteed to be non-empty, to not contain U+0000, to fit in an MQTT packet, and for its wildcards to be placed correctly:
+/// `+` and `#` must occupy an entire level, and `#` must be the last level.
+/// A shared subscription's topic filter must be of the form `$share/<share_name>/<topic_filter>`, where the share name does not contain wildcards.
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct TopicFilter(String);
+
+impl TopicFilter {
+	/// Validates the given topic filter.
+	pub fn new(topic_filter: String) -> Result<Self, TopicError> {
+		validate(&topic_filter)?;
+
+		let levels_topic_filter =
+			if topic_filter.starts_with(SHARED_SUBSCRIPTION_PREFIX) {
+				match parse_shared_subscription(&topic_filter) {
+					Some((share_name, levels_topic_filter)) if !share_name.contains(&['+', '#'][..]) => levels_topic_filter,
+					_ => return Err(TopicError::InvalidSharedSubscription(topic_filter)),
+				}
+			}
+			else {
+				&topic_filter
+			};
+
+		let mut levels = filter_levels(levels_topic_filter).peekable();
+		while let Some(level) = levels.next() {
+			let is_valid = match level {
+				FilterLevel::Literal(level) => !level.contains(&['+', '#'][..]),
+				FilterLevel::SingleLevelWildcard => true,
+				FilterLevel::MultiLevelWildcard => levels.peek().is_none(),
+			};
+			if !is_valid {
+				return Err(TopicError::InvalidWildcard(topic_filter));
+			}
+		}
+
+		Ok(TopicFilter(topic_filter))
+	}
+
+	/// Returns whether a publication to the given topic name would be delivered to a subscription with this topic filter.
+	///
+	/// See [`matches`] for details.
+	pub fn matches(&self, topic_name: &str) -> bool {
+		matches(&self.0, topic_name)
+	}
+
+	pub fn as_str(&self) -> &str {
+		&self.0
+	}
+
+	pub fn into_string(self) -> String {
+		self.0
+	}
+
+	/// For topic filters that are known to be valid, such as the ones that were validated before being stored as strings
+	pub(crate) fn new_unchecked(topic_filter: String) -> Self {
+		TopicFilter(topic_filter)
+	}
+}
+
+macro_rules! impl_topic_traits {
+	($ty:ident) => {
+		impl std::ops::Deref for $ty {
+			type Target = str;
+
+			fn deref(&self) -> &Self::Target {
+				&self.0
+			}
+		}
+
+		impl AsRef<str> for $ty {
+			fn as_ref(&self) -> &str {
+				&self.0
+			}
+		}
+
+		impl std::borrow::Borrow<str> for $ty {
+			fn borrow(&self) -> &str {
+				&self.0
+			}
+		}
+
+		impl std::fmt::Display for $ty {
+			fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+				self.0.fmt(f)
+			}
+		}
+
+		impl std::str::FromStr for $ty {
+			type Err = TopicError;
+
+			fn from_str(s: &str) -> Result<Self, Self::Err> {
+				$ty::new(s.to_owned())
+			}
+		}
+
+		impl std::convert::TryFrom<String> for $ty {
+			type Error = TopicError;
+
+			fn try_from(s: String) -> Result<Self, Self::Error> {
+				$ty::new(s)
+			}
+		}
+
+		impl From<$ty> for String {
+			fn from(topic: $ty) -> Self {
+				topic.into_string()
+			}
+		}
+
+		impl PartialEq<str> for $ty {
+			fn eq(&self, other: &str) -> bool {
+				*self.0 == *other
+			}
+		}
+
+		impl<'a> PartialEq<&'a str> for $ty {
+			fn eq(&self, other: &&'a str) -> bool {
+				*self.0 == **other
+			}
+		}
+	};
+}
+
+impl_topic_traits!(TopicName);
+impl_topic_traits!(TopicFilter);
+
+/// Validation common to topic names and topic filters
+///
+/// Ref: 4.7.3 Topic semantic and usage
+fn validate(topic: &str) -> Result<(), TopicError> {
+	if topic.is_empty() {
+		return Err(TopicError::Empty);
+	}
+
+	if topic.len() > usize::from(u16::max_value()) {
+		return Err(TopicError::TooLong(topic.len()));
+	}
+
+	if topic.contains('\0') {
+		return Err(TopicError::ContainsNul(topic.to_owned()));
+	}
+
+	Ok(())
+}
+
+/// Errors from validating a [`TopicName`] or [`TopicFilter`]
+#[derive(Debug)]
+pub enum TopicError {
+	ContainsNul(String),
+	Empty,
+	InvalidSharedSubscription(String),
+	InvalidWildcard(String),
+	TooLong(usize),
+	WildcardInTopicName(String),
+}
+
+impl std::fmt::Display for TopicError {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			TopicError::ContainsNul(topic) => write!(f, "topic {:?} contains U+0000", topic),
+			TopicError::Empty => write!(f, "topic is empty"),
+			TopicError::InvalidSharedSubscription(topic_filter) =>
+				write!(f, "topic filter {:?} is not a valid shared subscription of the form $share/<share_name>/<topic_filter>", topic_filter),
+			TopicError::InvalidWildcard(topic_filter) =>
+				write!(f, "topic filter {:?} has a wildcard that does not occupy an entire level, or a multi-level wildcard that is not the last level", topic_filter),
+			TopicError::TooLong(len) => write!(f, "topic of length {} is too long to be encoded", len),
+			TopicError::WildcardInTopicName(topic_name) => write!(f, "topic name {:?} contains a wildcard", topic_name),
+		}
+	}
+}
+
+impl std::error::Error for TopicError {
+}
+
+#[cfg(test)]
+mod tests {
+	#[test]
+	fn matches() {
+		assert!(super::matches("sport/tennis/player1", "sport/tennis/player1"));
+		assert!(!super::matches("sport/tennis/player1", "sport/tennis/player2"));
+		assert!(!super::matches("sport/tennis", "sport/tennis/player1"));
+		assert!(!super::matches("sport/tennis/player1", "sport/tennis"));
+
+		assert!(super::matches("sport/tennis/player1/#", "sport/tennis/player1"));
+		assert!(super::matches("sport/tennis/player1/#", "sport/tennis/player1/ranking"));
+		assert!(super::matches("sport/tennis/player1/#", "sport/tennis/player1/score/wimbledon"));
+		assert!(super::matches("sport/#", "sport"));
+		assert!(super::matches("#", "sport/tennis"));
+
+		assert!(super::matches("sport/tennis/+", "sport/tennis/player1"));
+		assert!(!super::matches("sport/tennis/+", "sport/tennis/player1/ranking"));
+		assert!(!super::matches("sport/+", "sport"));
+		assert!(super::matches("sport/+", "sport/"));
+		assert!(super::matches("+/+", "/finance"));
+		assert!(super::matches("/+", "/finance"));
+		assert!(!super::matches("+", "/finance"));
+
+		assert!(!super::matches("#", "$SYS/monitor/Clients"));
+		assert!(!super::matches("+/monitor/Clients", "$SYS/monitor/Clients"));
+		assert!(super::matches("$SYS/#", "$SYS/monitor/Clients"));
+		assert!(super::matches("$SYS/monitor/+", "$SYS/monitor/Clients"));
+
+		assert!(super::matches("$share/group1/sport/+", "sport/tennis"));
+		assert!(!super::matches("$share/group1/sport/+", "sport/tennis/player1"));
+		assert!(!super::matches("$share/group1/#", "$SYS/monitor/Clients"));
+	}
+
+	#[test]
+	fn levels() {
+		assert_eq!(super::levels("sport/tennis/player1").collect::<Vec<_>>(), vec!["sport", "tennis", "player1"]);
+		assert_eq!(super::levels("/finance").collect::<Vec<_>>(), vec!["", "finance"]);
+		assert_eq!(super::levels("").collect::<Vec<_>>(), vec![""]);
+
+		assert_eq!(super::filter_levels("sport/+/a+/#").collect::<Vec<_>>(), vec![
+			super::FilterLevel::Literal("sport"),
+			super::FilterLevel::SingleLevelWildcard,
+			super::FilterLevel::Literal("a+"),
+			super::FilterLevel::MultiLevelWildcard,
+		]);
+	}
+
+	#[test]
+	fn topic_name() {
+		assert_eq!(super::TopicName::new("sport/tennis/player1".to_owned()).unwrap(), "sport/tennis/player1");
+		assert_eq!(super::TopicName::new("/".to_owned()).unwrap(), "/");
+
+		match super::TopicName::new("".to_owned()) {
+			Err(super::TopicError::Empty) => (),
+			result => panic!("expected Empty but got {:?}", result),
+		}
+		match super::TopicName::new("sport/\0".to_owned()) {
+			Err(super::TopicError::ContainsNul(_)) => (),
+			result => panic!("expected ContainsNul but got {:?}", result),
+		}
+		match super::TopicName::new("a".repeat(usize::from(u16::max_value()) + 1)) {
+			Err(super::TopicError::TooLong(_)) => (),
+			result => panic!("expected TooLong but got {:?}", result),
+		}
+		for topic_name in &["sport/+", "sport/#", "sport+"] {
+			match topic_name.parse::<super::TopicName>() {
+				Err(super::TopicError::WildcardInTopicName(_)) => (),
+				result => panic!("expected WildcardInTopicName for {:?} but got {:?}", topic_name, result),
+			}
+		}
+
+		// Clones share the string
+		let topic_name: super::TopicName = "sport/tennis/player1".parse().unwrap();
+		assert!(std::ptr::eq(topic_name.as_str(), topic_name.clone().as_str()));
+		assert_eq!(topic_name.into_string(), "sport/tennis/player1");
+	}
+
+	#[test]
+	fn topic_interner() {
+		let interner = super::TopicInterner::new(2);
+
+		let topic_name = interner.intern("sport/tennis/player1").unwrap();
+		assert!(std::ptr::eq(topic_name.as_str(), interner.intern("sport/tennis/player1").unwrap().as_str()));
+		assert_eq!(interner.len(), 1);
+
+		match interner.intern("sport/+") {
+			Err(super::TopicError::WildcardInTopicName(_)) => (),
+			result => panic!("expected WildcardInTopicName but got {:?}", result),
+		}
+		assert_eq!(interner.len(), 1);
+
+		let _ = interner.intern("sport/tennis/player2").unwrap();
+
+		// Topic names beyond the limit are returned but not remembered
+		let topic_name = interner.intern("sport/tennis/player3").unwrap();
+		assert_eq!(topic_name, "sport/tennis/player3");
+		assert!(!std::ptr::eq(topic_name.as_str(), interner.intern("sport/tennis/player3").unwrap().as_str()));
+		assert_eq!(interner.len(), 2);
+
+		interner.clear();
+		assert!(interner.is_empty());
+	}
+
+	#[test]
+	fn topic_filter() {
+		for topic_filter in &["sport/tennis/player1", "sport/#", "#", "+", "+/tennis/#", "sport/+/player1", "/+", "$share/group1/sport/#"] {
+			assert_eq!(topic_filter.parse::<super::TopicFilter>().unwrap(), *topic_filter);
+		}
+
+		for topic_filter in &["sport/tennis#", "sport/tennis/#/ranking", "sport+", "sport/+tennis", "##"] {
+			match topic_filter.parse::<super::TopicFilter>() {
+				Err(super::TopicError::InvalidWildcard(_)) => (),
+				result => panic!("expected InvalidWildcard for {:?} but got {:?}", topic_filter, result),
+			}
+		}
+
+		for topic_filter in &["$share/group1", "$share//sport/#", "$share/group+/sport/#"] {
+			match topic_filter.parse::<super::TopicFilter>() {
+				Err(super::TopicError::InvalidSharedSubscription(_)) => (),
+				result => panic!("expected InvalidSharedSubscription for {:?} but got {:?}", topic_filter, result),
+			}
+		}
+
+		match super::TopicFilter::new("".to_owned()) {
+			Err(super::TopicError::Empty) => (),
+			result => panic!("expected Empty but got {:?}", result),
+		}
+	}
+}
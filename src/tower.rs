@@ -0,0 +1,107 @@
+/*!
+ * Adapters that expose publishing and RPC calls as [`tower_service::Service`]s, so that they can be wrapped in the middleware of the tower ecosystem,
+ * like timeouts, retries, rate limits and load shedding.
+ *
+ * Both services report readiness from the client's publish queue, so middleware that waits for readiness, like a rate limit
+ * or a concurrency limit, also applies backpressure when the client is not keeping up.
+ *
+ * ```no_run
+ * # use futures::Future;
+ * # let addr = "127.0.0.1:1883".parse().unwrap();
+ * # let io_source = move || tokio::net::TcpStream::connect(&addr).map(|io| (io, None));
+ * use tower_service::Service;
+ *
+ * let client = mqtt::Client::new(None, None, None, io_source, std::time::Duration::from_secs(30), std::time::Duration::from_secs(240));
+ * let publish_service = mqtt::tower::PublishService::new(client.publish_handle().unwrap());
+ * let mut publish = tower_timeout::Timeout::new(publish_service, std::time::Duration::from_secs(5));
+ * let published = publish.call(mqtt::proto::Publication {
+ *     topic_name: "sensors/kitchen".parse().unwrap(),
+ *     qos: mqtt::proto::QoS::AtLeastOnce,
+ *     retain: false,
+ *     payload: "21.5".into(),
+ * });
+ * ```
+ *
+ * This module is only available with the `tower` feature.
+ */
+
+/// A [`Service`](tower_service::Service) that publishes the publications it is called with, and resolves once they have been acked.
+pub struct PublishService(crate::PublishHandle);
+
+impl PublishService {
+	/// Creates a service that publishes with the given publish handle.
+	pub fn new(publish_handle: crate::PublishHandle) -> Self {
+		PublishService(publish_handle)
+	}
+
+	/// Returns the publish handle of this service
+	pub fn into_inner(self) -> crate::PublishHandle {
+		self.0
+	}
+}
+
+impl tower_service::Service<crate::proto::Publication> for PublishService {
+	type Response = ();
+	type Error = crate::PublishError;
+	type Future = crate::PublishFuture;
+
+	fn poll_ready(&mut self) -> futures::Poll<(), Self::Error> {
+		self.0.poll_ready()
+	}
+
+	fn call(&mut self, publication: crate::proto::Publication) -> Self::Future {
+		self.0.publish(publication)
+	}
+}
+
+impl std::fmt::Debug for PublishService {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("PublishService").finish_non_exhaustive()
+	}
+}
+
+/// A call of a method with an [`RpcService`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RpcCall {
+	pub method: String,
+	pub payload: bytes::Bytes,
+}
+
+/// A [`Service`](tower_service::Service) that calls methods with an [`RpcClient`](crate::rpc::RpcClient), and resolves to their responses.
+#[derive(Debug)]
+pub struct RpcService {
+	client: crate::rpc::RpcClient,
+	timeout: std::time::Duration,
+}
+
+impl RpcService {
+	/// Creates a service for the given RPC client. Calls fail with [`CallError::Timeout`](crate::rpc::CallError::Timeout)
+	/// if there is no response within the given timeout.
+	///
+	/// Shorter timeouts can also be applied with a timeout middleware, which then fails calls with its own error.
+	pub fn new(client: crate::rpc::RpcClient, timeout: std::time::Duration) -> Self {
+		RpcService {
+			client,
+			timeout,
+		}
+	}
+
+	/// Returns the RPC client of this service
+	pub fn into_inner(self) -> crate::rpc::RpcClient {
+		self.client
+	}
+}
+
+impl tower_service::Service<RpcCall> for RpcService {
+	type Response = bytes::Bytes;
+	type Error = crate::rpc::CallError;
+	type Future = crate::rpc::CallFuture;
+
+	fn poll_ready(&mut self) -> futures::Poll<(), Self::Error> {
+		self.client.poll_ready().map_err(crate::rpc::CallError::Publish)
+	}
+
+	fn call(&mut self, call: RpcCall) -> Self::Future {
+		self.client.call(&call.method, call.payload, self.timeout)
+	}
+}
@@ -0,0 +1,100 @@
+/*!
+ * The spans that the client is instrumented with.
+ *
+ * With the `tracing` feature, these wrap `tracing` spans. Otherwise they are zero-sized and do nothing,
+ * so that the code that uses them does not need to be conditionally compiled.
+ */
+
+#[derive(Clone, Debug)]
+pub(crate) struct Span {
+	#[cfg(feature = "tracing")]
+	inner: tracing::Span,
+}
+
+#[cfg_attr(not(feature = "tracing"), allow(clippy::unused_self, unused_variables))]
+impl Span {
+	/// A span for a connection to the server, from when the client starts connecting to when the connection is lost
+	pub(crate) fn connection() -> Self {
+		Span {
+			#[cfg(feature = "tracing")]
+			inner: tracing::info_span!("mqtt_connection"),
+		}
+	}
+
+	/// A span for a publication, from when it is given to the client to when the server acks it
+	pub(crate) fn publish(publication: &crate::proto::Publication) -> Self {
+		Span {
+			#[cfg(feature = "tracing")]
+			inner: tracing::info_span!(
+				"mqtt_publish",
+				topic_name = %publication.topic_name,
+				qos = ?publication.qos,
+				packet_identifier = tracing::field::Empty,
+			),
+		}
+	}
+
+	/// A span for a SUBSCRIBE packet, from when it is sent to when the server acks it
+	pub(crate) fn subscribe(packet: &crate::proto::Subscribe) -> Self {
+		Span {
+			#[cfg(feature = "tracing")]
+			inner: tracing::info_span!(
+				"mqtt_subscribe",
+				packet_identifier = packet.packet_identifier.get(),
+				subscribe_to = ?packet.subscribe_to,
+			),
+		}
+	}
+
+	/// A span for an UNSUBSCRIBE packet, from when it is sent to when the server acks it
+	pub(crate) fn unsubscribe(packet: &crate::proto::Unsubscribe) -> Self {
+		Span {
+			#[cfg(feature = "tracing")]
+			inner: tracing::info_span!(
+				"mqtt_unsubscribe",
+				packet_identifier = packet.packet_identifier.get(),
+				unsubscribe_from = ?packet.unsubscribe_from,
+			),
+		}
+	}
+
+	/// Enters the span until the returned guard is dropped
+	pub(crate) fn enter(&self) -> Entered<'_> {
+		Entered {
+			#[cfg(feature = "tracing")]
+			_inner: self.inner.enter(),
+			_span: std::marker::PhantomData,
+		}
+	}
+
+	/// Records the packet identifier of a publication, once it has been assigned one
+	pub(crate) fn record_packet_identifier(&self, packet_identifier: crate::proto::PacketIdentifier) {
+		#[cfg(feature = "tracing")]
+		let _ = self.inner.record("packet_identifier", packet_identifier.get());
+	}
+
+	/// Records an event in the span
+	pub(crate) fn event(&self, message: &'static str) {
+		#[cfg(feature = "tracing")]
+		tracing::debug!(parent: &self.inner, "{}", message);
+	}
+
+	/// Records that the connection of this span has been established
+	pub(crate) fn connected(&self, reset_session: bool) {
+		#[cfg(feature = "tracing")]
+		tracing::info!(parent: &self.inner, reset_session, "connected");
+	}
+
+	/// Records that the connection of this span has been lost because of the given error
+	pub(crate) fn connection_lost(&self, err: &crate::Error) {
+		#[cfg(feature = "tracing")]
+		tracing::warn!(parent: &self.inner, error = %err, "connection lost");
+	}
+}
+
+/// The guard returned by [`Span::enter`]
+pub(crate) struct Entered<'a> {
+	#[cfg(feature = "tracing")]
+	_inner: tracing::span::Entered<'a>,
+	_span: std::marker::PhantomData<&'a Span>,
+}
@@ -0,0 +1,333 @@
+/*!
+ * Publishing and receiving publications whose payloads are serialized values, with serde.
+ *
+ * A [`TypedPublishHandle`] serializes the values it publishes with a [`Codec`], and a [`TypedStream`] deserializes the payloads of
+ * a stream of received publications, like a [`RouteStream`](crate::router::RouteStream) of a [`Router`](crate::router::Router),
+ * into values of the same type.
+ *
+ * The codecs are [`Json`] with the `json` feature, [`Cbor`] with the `cbor` feature and [`MessagePack`] with the `msgpack` feature.
+ * Other formats can be used by implementing [`Codec`].
+ *
+ * This module is only available with the `serde` feature, which the codec features enable.
+ */
+
+use futures::Stream;
+
+/// Serializes values into publication payloads, and deserializes them back.
+pub trait Codec {
+	type EncodeError;
+	type DecodeError;
+
+	fn encode<T>(&self, value: &T) -> Result<Vec<u8>, Self::EncodeError> where T: serde::Serialize + ?Sized;
+
+	fn decode<T>(&self, payload: &[u8]) -> Result<T, Self::DecodeError> where T: serde::de::DeserializeOwned;
+}
+
+/// A [`Codec`] for JSON.
+///
+/// ```no_run
+/// # use futures::Stream;
+/// #[derive(serde_derive::Deserialize, serde_derive::Serialize)]
+/// struct Reading {
+///     temperature: f64,
+/// }
+///
+/// # let addr = "127.0.0.1:1883".parse().unwrap();
+/// # let io_source = move || futures::Future::map(tokio::net::TcpStream::connect(&addr), |io| (io, None));
+/// let client = mqtt::Client::new(None, None, None, io_source, std::time::Duration::from_secs(30), std::time::Duration::from_secs(240));
+///
+/// let mut readings =
+///     mqtt::typed::TypedPublishHandle::<Reading, _>::new(
+///         client.publish_handle().unwrap(),
+///         "sensors/kitchen".parse().unwrap(),
+///         mqtt::proto::QoS::AtLeastOnce,
+///         mqtt::typed::Json,
+///     );
+/// let published = readings.publish(&Reading { temperature: 21.5 }).unwrap();
+///
+/// let mut router = mqtt::router::Router::new(client);
+/// let readings = mqtt::typed::TypedStream::<_, Reading, _>::new(router.route("sensors/+".to_owned()), mqtt::typed::Json);
+/// ```
+///
+/// This is only available with the `json` feature.
+#[cfg(feature = "json")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Json;
+
+#[cfg(feature = "json")]
+impl Codec for Json {
+	type EncodeError = serde_json::Error;
+	type DecodeError = serde_json::Error;
+
+	fn encode<T>(&self, value: &T) -> Result<Vec<u8>, Self::EncodeError> where T: serde::Serialize + ?Sized {
+		serde_json::to_vec(value)
+	}
+
+	fn decode<T>(&self, payload: &[u8]) -> Result<T, Self::DecodeError> where T: serde::de::DeserializeOwned {
+		serde_json::from_slice(payload)
+	}
+}
+
+/// A [`Codec`] for CBOR.
+///
+/// This is only available with the `cbor` feature.
+#[cfg(feature = "cbor")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Cbor;
+
+#[cfg(feature = "cbor")]
+impl Codec for Cbor {
+	type EncodeError = ciborium::ser::Error<std::io::Error>;
+	type DecodeError = ciborium::de::Error<std::io::Error>;
+
+	fn encode<T>(&self, value: &T) -> Result<Vec<u8>, Self::EncodeError> where T: serde::Serialize + ?Sized {
+		let mut payload = vec![];
+		ciborium::into_writer(value, &mut payload)?;
+		Ok(payload)
+	}
+
+	fn decode<T>(&self, payload: &[u8]) -> Result<T, Self::DecodeError> where T: serde::de::DeserializeOwned {
+		ciborium::from_reader(payload)
+	}
+}
+
+/// A [`Codec`] for MessagePack. Structs are serialized as maps with their field names, so that they can be decoded by other implementations.
+///
+/// This is only available with the `msgpack` feature.
+#[cfg(feature = "msgpack")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MessagePack;
+
+#[cfg(feature = "msgpack")]
+impl Codec for MessagePack {
+	type EncodeError = rmp_serde::encode::Error;
+	type DecodeError = rmp_serde::decode::Error;
+
+	fn encode<T>(&self, value: &T) -> Result<Vec<u8>, Self::EncodeError> where T: serde::Serialize + ?Sized {
+		rmp_serde::to_vec_named(value)
+	}
+
+	fn decode<T>(&self, payload: &[u8]) -> Result<T, Self::DecodeError> where T: serde::de::DeserializeOwned {
+		rmp_serde::from_slice(payload)
+	}
+}
+
+/// A [`PublishHandle`](crate::PublishHandle) that publishes values of type `T` to a topic, serialized with a [`Codec`].
+pub struct TypedPublishHandle<T, C> where T: ?Sized {
+	publish_handle: crate::PublishHandle,
+	topic_name: crate::topic::TopicName,
+	qos: crate::proto::QoS,
+	retain: bool,
+	codec: C,
+	_value: std::marker::PhantomData<fn(&T)>,
+}
+
+impl<T, C> TypedPublishHandle<T, C> where T: serde::Serialize + ?Sized, C: Codec {
+	/// Creates a handle that publishes to the given topic with the given quality of service, using the given publish handle.
+	pub fn new(publish_handle: crate::PublishHandle, topic_name: crate::topic::TopicName, qos: crate::proto::QoS, codec: C) -> Self {
+		TypedPublishHandle {
+			publish_handle,
+			topic_name,
+			qos,
+			retain: false,
+			codec,
+			_value: Default::default(),
+		}
+	}
+
+	/// Sets whether the publications are retained by the server.
+	///
+	/// Defaults to `false`.
+	pub fn set_retain(&mut self, retain: bool) {
+		self.retain = retain;
+	}
+
+	/// Serializes the given value and queues it to be published. See [`PublishHandle::publish`](crate::PublishHandle::publish).
+	///
+	/// Fails without publishing anything if the value could not be serialized.
+	pub fn publish(&mut self, value: &T) -> Result<crate::PublishFuture, C::EncodeError> {
+		let publication = self.publication(value)?;
+		Ok(self.publish_handle.publish(publication))
+	}
+
+	/// Serializes the given value and publishes it without waiting for it to be acknowledged.
+	/// See [`PublishHandle::publish_without_ack`](crate::PublishHandle::publish_without_ack).
+	pub fn publish_without_ack(&mut self, value: &T) -> Result<(), TypedPublishError<C::EncodeError>> {
+		let publication = self.publication(value).map_err(TypedPublishError::Encode)?;
+		self.publish_handle.publish_without_ack(publication).map_err(TypedPublishError::Publish)
+	}
+
+	fn publication(&self, value: &T) -> Result<crate::proto::Publication, C::EncodeError> {
+		let payload = self.codec.encode(value)?;
+		Ok(crate::proto::Publication {
+			topic_name: self.topic_name.clone(),
+			qos: self.qos,
+			retain: self.retain,
+			payload: payload.into(),
+		})
+	}
+}
+
+impl<T, C> std::fmt::Debug for TypedPublishHandle<T, C> where T: ?Sized, C: std::fmt::Debug {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("TypedPublishHandle")
+			.field("topic_name", &self.topic_name)
+			.field("qos", &self.qos)
+			.field("retain", &self.retain)
+			.field("codec", &self.codec)
+			.finish_non_exhaustive()
+	}
+}
+
+/// A publication whose payload has been deserialized into a value of type `T`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TypedPublication<T> {
+	pub topic_name: String,
+	pub dup: bool,
+	pub qos: crate::proto::QoS,
+	pub retain: bool,
+	pub value: T,
+}
+
+/// A stream of the values of type `T` that the payloads of a stream of [`ReceivedPublication`](crate::ReceivedPublication)s
+/// deserialize to, with a [`Codec`].
+///
+/// A payload that can't be deserialized does not end the stream. It is yielded as a [`DecodeError`] instead, so that it can be logged
+/// or answered with an error, and the stream continues with the next publication.
+pub struct TypedStream<S, T, C> {
+	publications: S,
+	codec: C,
+	_value: std::marker::PhantomData<fn() -> T>,
+}
+
+impl<S, T, C> TypedStream<S, T, C> {
+	pub fn new(publications: S, codec: C) -> Self {
+		TypedStream {
+			publications,
+			codec,
+			_value: Default::default(),
+		}
+	}
+}
+
+impl<S, T, C> Stream for TypedStream<S, T, C>
+where
+	S: Stream<Item = crate::ReceivedPublication>,
+	T: serde::de::DeserializeOwned,
+	C: Codec,
+{
+	type Item = Result<TypedPublication<T>, DecodeError<C::DecodeError>>;
+	type Error = S::Error;
+
+	fn poll(&mut self) -> futures::Poll<Option<Self::Item>, Self::Error> {
+		let publication = match futures::try_ready!(self.publications.poll()) {
+			Some(publication) => publication,
+			None => return Ok(futures::Async::Ready(None)),
+		};
+
+		let typed_publication = match self.codec.decode(&publication.payload) {
+			Ok(value) => Ok(TypedPublication {
+				topic_name: publication.topic_name,
+				dup: publication.dup,
+				qos: publication.qos,
+				retain: publication.retain,
+				value,
+			}),
+
+			Err(err) => Err(DecodeError {
+				publication,
+				err,
+			}),
+		};
+
+		Ok(futures::Async::Ready(Some(typed_publication)))
+	}
+}
+
+impl<S, T, C> std::fmt::Debug for TypedStream<S, T, C> where S: std::fmt::Debug, C: std::fmt::Debug {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("TypedStream")
+			.field("publications", &self.publications)
+			.field("codec", &self.codec)
+			.finish_non_exhaustive()
+	}
+}
+
+/// An error from [`TypedPublishHandle::publish_without_ack`]
+#[derive(Debug)]
+pub enum TypedPublishError<E> {
+	Encode(E),
+	Publish(crate::PublishError),
+}
+
+impl<E> std::fmt::Display for TypedPublishError<E> where E: std::fmt::Display {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			TypedPublishError::Encode(err) => write!(f, "could not serialize payload: {}", err),
+			TypedPublishError::Publish(err) => write!(f, "could not publish: {}", err),
+		}
+	}
+}
+
+impl<E> std::error::Error for TypedPublishError<E> where E: std::error::Error + 'static {
+	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+		match self {
+			TypedPublishError::Encode(err) => Some(err),
+			TypedPublishError::Publish(err) => Some(err),
+		}
+	}
+}
+
+/// A publication received by a [`TypedStream`] whose payload could not be deserialized
+#[derive(Debug)]
+pub struct DecodeError<E> {
+	pub publication: crate::ReceivedPublication,
+	pub err: E,
+}
+
+impl<E> std::fmt::Display for DecodeError<E> where E: std::fmt::Display {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "could not deserialize payload of publication to {:?}: {}", self.publication.topic_name, self.err)
+	}
+}
+
+impl<E> std::error::Error for DecodeError<E> where E: std::error::Error + 'static {
+	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+		Some(&self.err)
+	}
+}
+
+#[cfg(all(test, any(feature = "cbor", feature = "json", feature = "msgpack")))]
+mod tests {
+	#[derive(Debug, PartialEq, serde_derive::Deserialize, serde_derive::Serialize)]
+	struct Reading {
+		sensor: String,
+		temperature: f64,
+	}
+
+	fn round_trip<C>(codec: &C) where C: super::Codec, C::EncodeError: std::fmt::Debug, C::DecodeError: std::fmt::Debug {
+		let reading = Reading { sensor: "kitchen".to_owned(), temperature: 21.5 };
+		let payload = codec.encode(&reading).unwrap();
+		assert_eq!(codec.decode::<Reading>(&payload).unwrap(), reading);
+		assert!(codec.decode::<Reading>(&payload[..payload.len() - 1]).is_err());
+	}
+
+	#[cfg(feature = "json")]
+	#[test]
+	fn json() {
+		round_trip(&super::Json);
+		assert_eq!(super::Codec::encode(&super::Json, &Reading { sensor: "kitchen".to_owned(), temperature: 21.5 }).unwrap(), br#"{"sensor":"kitchen","temperature":21.5}"#);
+	}
+
+	#[cfg(feature = "cbor")]
+	#[test]
+	fn cbor() {
+		round_trip(&super::Cbor);
+	}
+
+	#[cfg(feature = "msgpack")]
+	#[test]
+	fn msgpack() {
+		round_trip(&super::MessagePack);
+	}
+}
@@ -0,0 +1,193 @@
+/*!
+ * An [`IoSource`](crate::IoSource) that connects to the server over a Unix domain socket, like a broker running as a local sidecar.
+ *
+ * Local brokers commonly create their socket file when they start and remove it when they stop, so the file may not exist yet when
+ * the client first connects, and may be replaced by a new one when the broker restarts. Connecting fails while the file does not exist
+ * or nothing is listening on it, and the client retries with its usual back-off until the broker is back.
+ *
+ * A restarted broker usually closes the connections of its previous instance, but a connection to a socket file that has since been
+ * replaced can also stay open without a broker on the other end, for example when the previous instance hangs. Use
+ * [`UdsConnector::set_socket_file_check_interval`] to close such connections, so that the client reconnects to the new socket file.
+ *
+ * This module is only available on Unix with the `uds` feature.
+ */
+
+use futures::{ Future, Stream };
+
+/// Connects to an MQTT server over a Unix domain socket.
+///
+/// Use [`UdsConnector::new`] to create it, then pass it as the `io_source` of [`Client::new`](crate::Client::new).
+#[derive(Clone, Debug)]
+pub struct UdsConnector {
+	path: std::path::PathBuf,
+	password: Option<String>,
+	socket_file_check_interval: Option<std::time::Duration>,
+}
+
+impl UdsConnector {
+	/// Creates a connector for the server listening on the socket file at the given path.
+	///
+	/// `password` is the password credential for the server, if any. It is passed on to the client for every connection.
+	pub fn new(path: impl Into<std::path::PathBuf>, password: Option<String>) -> Self {
+		UdsConnector {
+			path: path.into(),
+			password,
+			socket_file_check_interval: None,
+		}
+	}
+
+	/// Sets how often every connection checks that the socket file it connected to still exists at the connector's path.
+	/// Once the file has been removed or replaced, reading from and writing to the connection fail with
+	/// [`std::io::ErrorKind::ConnectionAborted`], so the client reconnects like it does for any other broken connection.
+	///
+	/// The checks use a timer, so the connections must be used from within a tokio runtime.
+	///
+	/// Defaults to `None`, ie connections are only closed when the server closes them.
+	pub fn set_socket_file_check_interval(&mut self, socket_file_check_interval: Option<std::time::Duration>) {
+		self.socket_file_check_interval = socket_file_check_interval;
+	}
+}
+
+impl crate::IoSource for UdsConnector {
+	type Io = UdsIo;
+	type Future = UdsConnectFuture;
+
+	fn connect(&mut self) -> Self::Future {
+		UdsConnectFuture {
+			inner: tokio_uds::UnixStream::connect(&self.path),
+			path: self.path.clone(),
+			password: self.password.clone(),
+			socket_file_check_interval: self.socket_file_check_interval,
+		}
+	}
+}
+
+/// The connection future returned by [`UdsConnector`]
+#[derive(Debug)]
+pub struct UdsConnectFuture {
+	inner: tokio_uds::ConnectFuture,
+	path: std::path::PathBuf,
+	password: Option<String>,
+	socket_file_check_interval: Option<std::time::Duration>,
+}
+
+impl Future for UdsConnectFuture {
+	type Item = (UdsIo, Option<String>);
+	type Error = std::io::Error;
+
+	fn poll(&mut self) -> futures::Poll<Self::Item, Self::Error> {
+		let inner = match self.inner.poll() {
+			Ok(futures::Async::Ready(inner)) => inner,
+			Ok(futures::Async::NotReady) => return Ok(futures::Async::NotReady),
+			Err(err) => return Err(std::io::Error::new(err.kind(), format!("could not connect to {}: {}", self.path.display(), err))),
+		};
+
+		let socket_file_check = match self.socket_file_check_interval {
+			Some(interval) => {
+				// If the socket file has already been replaced since the connection was made, the first check closes the connection.
+				let socket_file = socket_file_id(&self.path)?;
+				Some(SocketFileCheck {
+					path: std::mem::take(&mut self.path),
+					socket_file,
+					interval: tokio_timer::Interval::new(tokio_timer::clock::now() + interval, interval),
+				})
+			},
+
+			None => None,
+		};
+
+		let io = UdsIo {
+			inner,
+			socket_file_check,
+			socket_file_gone: false,
+		};
+		Ok(futures::Async::Ready((io, self.password.take())))
+	}
+}
+
+/// A connection of a [`UdsConnector`]
+#[derive(Debug)]
+pub struct UdsIo {
+	inner: tokio_uds::UnixStream,
+	socket_file_check: Option<SocketFileCheck>,
+	socket_file_gone: bool,
+}
+
+#[derive(Debug)]
+struct SocketFileCheck {
+	path: std::path::PathBuf,
+	socket_file: Option<(u64, u64)>,
+	interval: tokio_timer::Interval,
+}
+
+impl UdsIo {
+	/// The underlying Unix domain socket, for example to get the credentials of the server process
+	pub fn get_ref(&self) -> &tokio_uds::UnixStream {
+		&self.inner
+	}
+
+	/// Returns an error if the socket file has been removed or replaced since the connection was made.
+	/// Otherwise the current task is notified when it is next checked.
+	fn poll_socket_file(&mut self) -> std::io::Result<()> {
+		if !self.socket_file_gone {
+			let socket_file_check = match &mut self.socket_file_check {
+				Some(socket_file_check) => socket_file_check,
+				None => return Ok(()),
+			};
+
+			loop {
+				match socket_file_check.interval.poll() {
+					Ok(futures::Async::Ready(_)) => {
+						let socket_file = socket_file_id(&socket_file_check.path)?;
+						if socket_file != socket_file_check.socket_file || socket_file.is_none() {
+							log::info!("closing connection since socket file {} has been removed or replaced", socket_file_check.path.display());
+							self.socket_file_gone = true;
+							break;
+						}
+					},
+					Ok(futures::Async::NotReady) => return Ok(()),
+					Err(err) => return Err(std::io::Error::other(err)),
+				}
+			}
+		}
+
+		Err(std::io::Error::new(std::io::ErrorKind::ConnectionAborted, "socket file has been removed or replaced"))
+	}
+}
+
+impl std::io::Read for UdsIo {
+	fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+		self.poll_socket_file()?;
+		std::io::Read::read(&mut self.inner, buf)
+	}
+}
+
+impl tokio_io::AsyncRead for UdsIo {
+}
+
+impl std::io::Write for UdsIo {
+	fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+		self.poll_socket_file()?;
+		std::io::Write::write(&mut self.inner, buf)
+	}
+
+	fn flush(&mut self) -> std::io::Result<()> {
+		self.poll_socket_file()?;
+		std::io::Write::flush(&mut self.inner)
+	}
+}
+
+impl tokio_io::AsyncWrite for UdsIo {
+	fn shutdown(&mut self) -> futures::Poll<(), std::io::Error> {
+		tokio_io::AsyncWrite::shutdown(&mut self.inner)
+	}
+}
+
+/// The device and inode numbers of the file at the given path, or `None` if there is no such file
+fn socket_file_id(path: &std::path::Path) -> std::io::Result<Option<(u64, u64)>> {
+	match std::fs::metadata(path) {
+		Ok(metadata) => Ok(Some((std::os::unix::fs::MetadataExt::dev(&metadata), std::os::unix::fs::MetadataExt::ino(&metadata)))),
+		Err(ref err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+		Err(err) => Err(err),
+	}
+}
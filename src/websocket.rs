@@ -0,0 +1,219 @@
+/*!
+ * An [`IoSource`](crate::IoSource) that connects to the server over a WebSocket, for servers and networks that only allow MQTT over WebSockets.
+ *
+ * The WebSocket runs on top of the connections of another `IoSource`, like a TCP connection or a TLS stream on top of one for `wss://` servers.
+ * Every MQTT packet is sent in binary WebSocket messages, with the `mqtt` subprotocol that the MQTT specification requires.
+ *
+ * This module is only available with the `websocket` feature.
+ */
+
+use futures::Future;
+
+/// Provides the HTTP request that a [`WebSocketIoSource`] makes to open the WebSocket of a new connection.
+///
+/// The trait is automatically implemented for all [`FnMut`] that return a request.
+pub trait RequestProvider {
+	/// Returns the request for the next connection
+	fn request(&mut self) -> std::io::Result<tungstenite::handshake::client::Request>;
+}
+
+impl<F> RequestProvider for F where F: FnMut() -> std::io::Result<tungstenite::handshake::client::Request> {
+	fn request(&mut self) -> std::io::Result<tungstenite::handshake::client::Request> {
+		(self)()
+	}
+}
+
+/// Builds the request to open a WebSocket at the given URL, like `ws://broker.example.com/mqtt`, with the `mqtt` subprotocol.
+pub fn request(url: &str) -> std::io::Result<tungstenite::handshake::client::Request> {
+	let mut request = tungstenite::client::IntoClientRequest::into_client_request(url).map_err(into_io_error)?;
+	let _ = request.headers_mut().insert("Sec-WebSocket-Protocol", tungstenite::http::HeaderValue::from_static("mqtt"));
+	Ok(request)
+}
+
+/// Connects to an MQTT server over a WebSocket, on top of the connections of another [`IoSource`](crate::IoSource).
+///
+/// The request for every connection comes from the [`RequestProvider`], so it can be different every time, for example
+/// to sign it with fresh credentials. Use [`request`] to build a request for a fixed URL.
+#[derive(Debug)]
+pub struct WebSocketIoSource<S, R> {
+	io_source: S,
+	request_provider: R,
+}
+
+impl<S, R> WebSocketIoSource<S, R> {
+	pub fn new(io_source: S, request_provider: R) -> Self {
+		WebSocketIoSource {
+			io_source,
+			request_provider,
+		}
+	}
+}
+
+impl<S, R> crate::IoSource for WebSocketIoSource<S, R>
+where
+	S: crate::IoSource,
+	S::Future: Future<Error = std::io::Error>,
+	R: RequestProvider,
+{
+	type Io = WebSocketIo<S::Io>;
+	type Future = WebSocketConnectFuture<S::Future, S::Io>;
+
+	fn connect(&mut self) -> Self::Future {
+		let state = match self.request_provider.request() {
+			Ok(request) => ConnectState::Connecting(self.io_source.connect(), Some(request)),
+			Err(err) => ConnectState::Failed(Some(err)),
+		};
+
+		WebSocketConnectFuture(state)
+	}
+}
+
+/// The connection future of a [`WebSocketIoSource`]
+pub struct WebSocketConnectFuture<F, I>(ConnectState<F, I>) where I: std::io::Read + std::io::Write;
+
+enum ConnectState<F, I> where I: std::io::Read + std::io::Write {
+	Connecting(F, Option<tungstenite::handshake::client::Request>),
+	Handshaking(Option<tungstenite::handshake::MidHandshake<tungstenite::ClientHandshake<I>>>, Option<String>),
+	Failed(Option<std::io::Error>),
+}
+
+impl<F, I> Future for WebSocketConnectFuture<F, I>
+where
+	F: Future<Item = (I, Option<String>), Error = std::io::Error>,
+	I: std::io::Read + std::io::Write,
+{
+	type Item = (WebSocketIo<I>, Option<String>);
+	type Error = std::io::Error;
+
+	fn poll(&mut self) -> futures::Poll<Self::Item, Self::Error> {
+		let handshake_result = match &mut self.0 {
+			ConnectState::Connecting(inner, request) => {
+				let (io, password) = futures::try_ready!(inner.poll());
+				let request = request.take().expect("polled after completion");
+				(tungstenite::client(request, io), password)
+			},
+
+			ConnectState::Handshaking(handshake, password) => {
+				let handshake = handshake.take().expect("polled after completion");
+				(handshake.handshake(), password.take())
+			},
+
+			ConnectState::Failed(err) => return Err(err.take().expect("polled after completion")),
+		};
+
+		match handshake_result {
+			(Ok((socket, _)), password) => {
+				log::debug!("WebSocket handshake succeeded");
+				let io = WebSocketIo {
+					socket,
+					read_buffer: Default::default(),
+				};
+				Ok(futures::Async::Ready((io, password)))
+			},
+
+			// The handshake is waiting for the inner connection, which has registered the current task to be notified.
+			(Err(tungstenite::HandshakeError::Interrupted(handshake)), password) => {
+				self.0 = ConnectState::Handshaking(Some(handshake), password);
+				Ok(futures::Async::NotReady)
+			},
+
+			(Err(tungstenite::HandshakeError::Failure(err)), _) => Err(into_io_error(err)),
+		}
+	}
+}
+
+impl<F, I> std::fmt::Debug for WebSocketConnectFuture<F, I> where I: std::io::Read + std::io::Write {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		let state = match &self.0 {
+			ConnectState::Connecting(..) => "Connecting",
+			ConnectState::Handshaking(..) => "Handshaking",
+			ConnectState::Failed(_) => "Failed",
+		};
+		f.debug_struct("WebSocketConnectFuture").field("state", &state).finish()
+	}
+}
+
+/// A connection of a [`WebSocketIoSource`]
+#[derive(Debug)]
+pub struct WebSocketIo<T> {
+	socket: tungstenite::WebSocket<T>,
+
+	/// The part of the last received message that has not been read yet
+	read_buffer: std::io::Cursor<Vec<u8>>,
+}
+
+impl<T> std::io::Read for WebSocketIo<T> where T: std::io::Read + std::io::Write {
+	fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+		loop {
+			// MQTT packets are not aligned to WebSocket messages, so a message can hold part of a packet or several packets
+			if self.read_buffer.position() < self.read_buffer.get_ref().len() as u64 {
+				return std::io::Read::read(&mut self.read_buffer, buf);
+			}
+
+			match self.socket.read() {
+				Ok(tungstenite::Message::Binary(message)) => self.read_buffer = std::io::Cursor::new(message),
+
+				Ok(tungstenite::Message::Text(_)) =>
+					return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "received a text WebSocket message instead of a binary one")),
+
+				// The socket replies to pings by itself
+				Ok(tungstenite::Message::Ping(_) | tungstenite::Message::Pong(_) | tungstenite::Message::Frame(_)) => (),
+
+				Ok(tungstenite::Message::Close(_)) |
+				Err(tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed) => return Ok(0),
+
+				Err(err) => return Err(into_io_error(err)),
+			}
+		}
+	}
+}
+
+impl<T> tokio_io::AsyncRead for WebSocketIo<T> where T: tokio_io::AsyncRead + std::io::Write {
+}
+
+impl<T> std::io::Write for WebSocketIo<T> where T: std::io::Read + std::io::Write {
+	fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+		match self.socket.write(tungstenite::Message::Binary(buf.to_owned())) {
+			Ok(()) => Ok(buf.len()),
+
+			// The message was buffered and is written by a later write or flush
+			Err(tungstenite::Error::Io(ref err)) if err.kind() == std::io::ErrorKind::WouldBlock => Ok(buf.len()),
+
+			// Flush the buffer so that the current task is notified when there is room for the message
+			Err(tungstenite::Error::WriteBufferFull(_)) => match self.socket.flush() {
+				Ok(()) => std::io::Write::write(self, buf),
+				Err(err) => Err(into_io_error(err)),
+			},
+
+			Err(err) => Err(into_io_error(err)),
+		}
+	}
+
+	fn flush(&mut self) -> std::io::Result<()> {
+		self.socket.flush().map_err(into_io_error)
+	}
+}
+
+impl<T> tokio_io::AsyncWrite for WebSocketIo<T> where T: std::io::Read + tokio_io::AsyncWrite {
+	fn shutdown(&mut self) -> futures::Poll<(), std::io::Error> {
+		let result = match self.socket.close(None) {
+			Ok(()) => self.socket.flush(),
+			Err(err) => Err(err),
+		};
+		match result {
+			Ok(()) |
+			Err(tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed) => (),
+			Err(tungstenite::Error::Io(ref err)) if err.kind() == std::io::ErrorKind::WouldBlock => return Ok(futures::Async::NotReady),
+			Err(err) => return Err(into_io_error(err)),
+		}
+
+		self.socket.get_mut().shutdown()
+	}
+}
+
+fn into_io_error(err: tungstenite::Error) -> std::io::Error {
+	match err {
+		tungstenite::Error::Io(err) => err,
+		err => std::io::Error::other(err),
+	}
+}
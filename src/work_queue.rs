@@ -0,0 +1,315 @@
+/*!
+ * A [`WorkQueue`] that processes the publications of a subscription as jobs, with at-least-once delivery.
+ *
+ * The work queue enables manual acks on its client (see [`Client::set_manual_acks`](crate::Client::set_manual_acks)),
+ * and only acks an at-least-once publication once its handler has processed it. If the worker fails before then, the server
+ * delivers the publication again, to this worker when it reconnects with the same session, or to another one.
+ *
+ * Back-pressure comes from the server: it only sends a limited number of at-least-once publications that have not been acked yet,
+ * so a worker that is busy with as many jobs as it can run at once stops receiving more.
+ * The details of this limit depend on the server, like `max_inflight_messages` for Mosquitto.
+ */
+
+use futures::{ Future, IntoFuture, Stream };
+
+/// The number of processed publications that are remembered to recognize redeliveries of them
+const MAX_RECENTLY_PROCESSED: usize = 1024;
+
+/// Processes the publications received by a client with a handler, a bounded number at a time.
+///
+/// The work queue owns the client, and is a [`Stream`] of the client's other events and of the publications that could not be processed.
+/// It must be polled for the client to do anything and for the handler to be called.
+///
+/// A publication is acked once its handler succeeds. If the handler fails, it is called again after a delay, up to a maximum number of attempts.
+/// A publication that still fails after that is acked anyway and yielded as a [`WorkQueueEvent::Failed`], so that the application can log it
+/// or publish it to a dead-letter topic. Otherwise it would keep the publications received after it from being acked, since MQTT requires acks
+/// to be sent in order.
+///
+/// Publications that the server delivers again while the same publication is still being processed, or shortly after, are recognized
+/// by their DUP flag, topic and payload, and are acked without being processed again.
+pub struct WorkQueue<IoS, F, R> where IoS: crate::IoSource, R: IntoFuture<Item = ()> {
+	client: crate::Client<IoS>,
+	ack_handle: crate::AckHandle,
+	handler: F,
+
+	max_concurrency: usize,
+	max_attempts: usize,
+	retry_delay: std::time::Duration,
+
+	next_sequence_number: u64,
+
+	/// The jobs that are waiting for one of the `max_concurrency` slots
+	waiting: std::collections::VecDeque<Job>,
+
+	/// The jobs that are running or waiting to be retried. These take up a slot.
+	running: futures::stream::FuturesUnordered<Attempt<R::Future>>,
+
+	/// The sequence numbers of the at-least-once publications that have not been acked yet, in the order they were received,
+	/// and whether they have been processed
+	unacked: std::collections::VecDeque<(u64, bool)>,
+
+	/// The topics and payloads of the at-least-once publications that are waiting or running, by sequence number
+	outstanding: std::collections::BTreeMap<u64, (String, bytes::Bytes)>,
+
+	/// The topics and payloads of the at-least-once publications that were processed most recently
+	recently_processed: std::collections::VecDeque<(String, bytes::Bytes)>,
+}
+
+impl<IoS, F, R> WorkQueue<IoS, F, R>
+where
+	IoS: crate::IoSource,
+	F: FnMut(crate::ReceivedPublication) -> R,
+	R: IntoFuture<Item = ()>,
+{
+	/// Creates a work queue that subscribes to the given topic filter with the given client, and processes the publications with the given handler.
+	///
+	/// The client must not be used for any other subscriptions, since every at-least-once publication that it receives has to be acked
+	/// by the work queue. To share the jobs of a topic between several workers, subscribe to a shared subscription if the server supports them.
+	/// Workers should connect with a client ID and a persistent session, so that the server keeps the publications that they have not acked
+	/// while they are disconnected.
+	pub fn new(mut client: crate::Client<IoS>, subscribe_to: crate::proto::SubscribeTo, handler: F) -> Result<Self, crate::UpdateSubscriptionError> {
+		client.set_manual_acks(true);
+		let ack_handle = client.ack_handle().map_err(|_| crate::UpdateSubscriptionError::ClientDoesNotExist)?;
+		client.subscribe(subscribe_to)?;
+
+		Ok(WorkQueue {
+			client,
+			ack_handle,
+			handler,
+
+			max_concurrency: 1,
+			max_attempts: 3,
+			retry_delay: std::time::Duration::from_secs(1),
+
+			next_sequence_number: 0,
+			waiting: Default::default(),
+			running: futures::stream::FuturesUnordered::new(),
+			unacked: Default::default(),
+			outstanding: Default::default(),
+			recently_processed: Default::default(),
+		})
+	}
+
+	/// Sets the maximum number of publications that are processed at the same time. A value of 0 is treated as 1.
+	///
+	/// Publications that are processed at the same time can finish in any order.
+	///
+	/// Defaults to 1, ie publications are processed one at a time, in the order they were received.
+	pub fn set_max_concurrency(&mut self, max_concurrency: usize) {
+		self.max_concurrency = max_concurrency.max(1);
+	}
+
+	/// Sets the number of times that the handler is called for a publication before it is given up on. A value of 0 is treated as 1.
+	///
+	/// Defaults to 3.
+	pub fn set_max_attempts(&mut self, max_attempts: usize) {
+		self.max_attempts = max_attempts.max(1);
+	}
+
+	/// Sets how long to wait after the handler fails before calling it again for the same publication.
+	/// The publication keeps its slot in the meantime.
+	///
+	/// Defaults to 1 second.
+	pub fn set_retry_delay(&mut self, retry_delay: std::time::Duration) {
+		self.retry_delay = retry_delay;
+	}
+
+	/// The client of the work queue, for example to get a handle to it
+	pub fn client(&self) -> &crate::Client<IoS> {
+		&self.client
+	}
+
+	/// The client of the work queue, for example to set its options
+	pub fn client_mut(&mut self) -> &mut crate::Client<IoS> {
+		&mut self.client
+	}
+
+	fn receive(&mut self, publication: crate::ReceivedPublication) {
+		let sequence_number = self.next_sequence_number;
+		self.next_sequence_number += 1;
+
+		if publication.qos == crate::proto::QoS::AtLeastOnce {
+			self.unacked.push_back((sequence_number, false));
+
+			if publication.dup && self.is_redelivery(&publication) {
+				log::debug!("skipping publication with topic {:?} that was delivered again while it was being processed", publication.topic_name);
+				self.processed(sequence_number);
+				return;
+			}
+
+			self.outstanding.insert(sequence_number, (publication.topic_name.clone(), publication.payload.clone()));
+		}
+
+		self.waiting.push_back(Job { sequence_number, publication, attempt: 1 });
+	}
+
+	fn is_redelivery(&self, publication: &crate::ReceivedPublication) -> bool {
+		self.outstanding.values().chain(&self.recently_processed)
+			.any(|(topic_name, payload)| *topic_name == publication.topic_name && *payload == publication.payload)
+	}
+
+	fn start(&mut self, job: Job) {
+		let handle = (self.handler)(job.publication.clone()).into_future();
+		self.running.push(Attempt { job: Some(job), state: AttemptState::Running(handle) });
+	}
+
+	fn finish(&mut self, job: Job) -> crate::ReceivedPublication {
+		if let Some(processed) = self.outstanding.remove(&job.sequence_number) {
+			if self.recently_processed.len() == MAX_RECENTLY_PROCESSED {
+				self.recently_processed.pop_front();
+			}
+			self.recently_processed.push_back(processed);
+		}
+
+		self.processed(job.sequence_number);
+
+		job.publication
+	}
+
+	/// Marks the publication with the given sequence number as processed, and acks all the publications up to the first one that hasn't been processed yet
+	fn processed(&mut self, sequence_number: u64) {
+		if let Some((_, processed)) = self.unacked.iter_mut().find(|(unacked, _)| *unacked == sequence_number) {
+			*processed = true;
+		}
+
+		while let Some(&(_, true)) = self.unacked.front() {
+			self.unacked.pop_front();
+
+			if let Err(err) = self.ack_handle.ack() {
+				log::warn!("could not ack processed publication: {}", err);
+			}
+		}
+	}
+}
+
+impl<IoS, F, R> Stream for WorkQueue<IoS, F, R>
+where
+	IoS: crate::IoSource,
+	<<IoS as crate::IoSource>::Future as Future>::Error: std::fmt::Display,
+	F: FnMut(crate::ReceivedPublication) -> R,
+	R: IntoFuture<Item = ()>,
+	R::Error: std::fmt::Display,
+{
+	type Item = WorkQueueEvent<R::Error>;
+	type Error = crate::Error;
+
+	fn poll(&mut self) -> futures::Poll<Option<Self::Item>, Self::Error> {
+		loop {
+			while self.running.len() < self.max_concurrency {
+				match self.waiting.pop_front() {
+					Some(job) => self.start(job),
+					None => break,
+				}
+			}
+
+			if let futures::Async::Ready(Some((job, outcome))) = self.running.poll().expect("Attempt::poll cannot fail") {
+				match outcome {
+					Outcome::Processed => { let _ = self.finish(job); },
+
+					Outcome::Failed(err) if job.attempt < self.max_attempts => {
+						log::warn!(
+							"processing publication with topic {:?} failed on attempt {} of {}, will retry: {}",
+							job.publication.topic_name, job.attempt, self.max_attempts, err,
+						);
+						let delay = tokio_timer::Delay::new(tokio_timer::clock::now() + self.retry_delay);
+						self.running.push(Attempt { job: Some(job), state: AttemptState::WaitingToRetry(delay) });
+					},
+
+					Outcome::Failed(err) => {
+						let publication = self.finish(job);
+						return Ok(futures::Async::Ready(Some(WorkQueueEvent::Failed { publication, err })));
+					},
+
+					Outcome::RetryDue => {
+						let job = Job { attempt: job.attempt + 1, ..job };
+						self.start(job);
+					},
+				}
+
+				continue;
+			}
+
+			match futures::try_ready!(self.client.poll()) {
+				Some(crate::Event::Publication(publication)) => self.receive(publication),
+				Some(event) => return Ok(futures::Async::Ready(Some(WorkQueueEvent::Client(event)))),
+				None => return Ok(futures::Async::Ready(None)),
+			}
+		}
+	}
+}
+
+impl<IoS, F, R> std::fmt::Debug for WorkQueue<IoS, F, R> where IoS: crate::IoSource, R: IntoFuture<Item = ()> {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("WorkQueue")
+			.field("max_concurrency", &self.max_concurrency)
+			.field("max_attempts", &self.max_attempts)
+			.field("retry_delay", &self.retry_delay)
+			.field("waiting", &self.waiting.len())
+			.field("running", &self.running.len())
+			.field("unacked", &self.unacked.len())
+			.finish_non_exhaustive()
+	}
+}
+
+/// An event yielded by a [`WorkQueue`]
+#[derive(Debug, PartialEq, Eq)]
+pub enum WorkQueueEvent<E> {
+	/// An event of the client, other than a publication
+	Client(crate::Event),
+
+	/// The handler failed on every attempt to process the publication, with the given error on the last one.
+	/// The publication has been acked, so the server won't deliver it again.
+	Failed { publication: crate::ReceivedPublication, err: E },
+}
+
+#[derive(Debug)]
+struct Job {
+	/// Numbers the publications in the order they were received
+	sequence_number: u64,
+	publication: crate::ReceivedPublication,
+	attempt: usize,
+}
+
+/// One call of the handler for a job, or the delay before the next one
+struct Attempt<F> {
+	job: Option<Job>,
+	state: AttemptState<F>,
+}
+
+enum AttemptState<F> {
+	Running(F),
+	WaitingToRetry(tokio_timer::Delay),
+}
+
+enum Outcome<E> {
+	Processed,
+	Failed(E),
+	RetryDue,
+}
+
+impl<F> Future for Attempt<F> where F: Future<Item = ()> {
+	type Item = (Job, Outcome<F::Error>);
+	type Error = ();
+
+	fn poll(&mut self) -> futures::Poll<Self::Item, Self::Error> {
+		let outcome = match &mut self.state {
+			AttemptState::Running(handle) => match handle.poll() {
+				Ok(futures::Async::Ready(())) => Outcome::Processed,
+				Ok(futures::Async::NotReady) => return Ok(futures::Async::NotReady),
+				Err(err) => Outcome::Failed(err),
+			},
+
+			AttemptState::WaitingToRetry(delay) => match delay.poll() {
+				Ok(futures::Async::Ready(())) => Outcome::RetryDue,
+				Ok(futures::Async::NotReady) => return Ok(futures::Async::NotReady),
+				Err(err) => {
+					log::warn!("retry timer failed, retrying now: {}", err);
+					Outcome::RetryDue
+				},
+			},
+		};
+
+		let job = self.job.take().expect("Attempt polled after completion");
+		Ok(futures::Async::Ready((job, outcome)))
+	}
+}
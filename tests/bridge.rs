@@ -0,0 +1,96 @@
+#![cfg(feature = "testing")]
+
+use futures::{ Future, Stream };
+
+#[test]
+fn bridge_forwards_publications_according_to_rules() {
+	let mut simulation = mqtt::testing::Simulation::new();
+
+	let local_broker = mqtt::testing::MockBroker::new();
+	let remote_broker = mqtt::testing::MockBroker::new();
+
+	let mut local =
+		mqtt::Client::new(
+			Some("bridge".to_owned()),
+			None,
+			None,
+			local_broker.clone(),
+			std::time::Duration::from_secs(0),
+			std::time::Duration::from_secs(4),
+		);
+	local.subscribe(mqtt::proto::SubscribeTo { topic_filter: "status".parse().unwrap(), qos: mqtt::proto::QoS::AtLeastOnce }).unwrap();
+
+	let remote =
+		mqtt::Client::new(
+			Some("edge-1".to_owned()),
+			None,
+			None,
+			remote_broker.clone(),
+			std::time::Duration::from_secs(0),
+			std::time::Duration::from_secs(4),
+		);
+
+	let mut bridge = mqtt::bridge::Bridge::new(local, remote);
+
+	let mut telemetry = mqtt::bridge::BridgeRule::new("sensors/#", mqtt::bridge::Direction::Out);
+	telemetry.set_remote_prefix("edge-1/");
+	telemetry.set_max_qos(mqtt::proto::QoS::AtMostOnce);
+	bridge.add_rule(telemetry).unwrap();
+
+	let mut commands = mqtt::bridge::BridgeRule::new("commands/#", mqtt::bridge::Direction::In);
+	commands.set_local_prefix("cloud/");
+	commands.set_remote_prefix("edge-1/");
+	bridge.add_rule(commands).unwrap();
+
+	bridge.add_rule(mqtt::bridge::BridgeRule::new("sync/#", mqtt::bridge::Direction::Both)).unwrap();
+
+	let topic_interner = mqtt::topic::TopicInterner::new(16);
+	bridge.set_topic_interner(Some(topic_interner.clone()));
+
+	let events: std::rc::Rc<std::cell::RefCell<Vec<mqtt::bridge::BridgeEvent>>> = Default::default();
+	simulation.spawn({
+		let events = events.clone();
+		bridge.for_each(move |event| { events.borrow_mut().push(event); Ok(()) }).map_err(|err| panic!("{:?}", err))
+	});
+	simulation.advance(std::time::Duration::from_secs(1));
+
+	let publication = |topic_name: &str, qos, payload: &'static [u8]| mqtt::proto::Publication {
+		topic_name: topic_name.parse().unwrap(),
+		qos,
+		retain: false,
+		payload: payload.into(),
+	};
+
+	local_broker.publish(&publication("sensors/kitchen/temperature", mqtt::proto::QoS::AtLeastOnce, b"21.5"));
+	local_broker.publish(&publication("status", mqtt::proto::QoS::AtLeastOnce, b"online"));
+	remote_broker.publish(&publication("edge-1/commands/reboot", mqtt::proto::QoS::AtLeastOnce, b"now"));
+	local_broker.publish(&publication("sync/config", mqtt::proto::QoS::AtLeastOnce, b"v2"));
+	simulation.advance(std::time::Duration::from_secs(1));
+
+	// Telemetry is capped to QoS 0, and the synced publication is not forwarded back to the local broker when the remote broker delivers it back
+	assert_eq!(remote_broker.published(), vec![
+		publication("edge-1/sensors/kitchen/temperature", mqtt::proto::QoS::AtMostOnce, b"21.5"),
+		publication("sync/config", mqtt::proto::QoS::AtLeastOnce, b"v2"),
+	]);
+	assert_eq!(local_broker.published(), vec![
+		publication("cloud/commands/reboot", mqtt::proto::QoS::AtLeastOnce, b"now"),
+	]);
+
+	assert_eq!(topic_interner.len(), 3);
+
+	// Publications that don't match any rule are yielded
+	let events = events.borrow();
+	let publications: Vec<_> = events.iter().filter(|event| matches!(event,
+		mqtt::bridge::BridgeEvent::Local(mqtt::Event::Publication(_)) |
+		mqtt::bridge::BridgeEvent::Remote(mqtt::Event::Publication(_))
+	)).collect();
+	assert_eq!(publications, vec![
+		&mqtt::bridge::BridgeEvent::Local(mqtt::Event::Publication(mqtt::ReceivedPublication {
+			topic_name: "status".to_owned(),
+			dup: false,
+			qos: mqtt::proto::QoS::AtLeastOnce,
+			retain: false,
+			payload: b"online"[..].into(),
+		})),
+	]);
+}
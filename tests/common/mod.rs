@@ -1,16 +1,35 @@
 use futures::{ Future, Stream };
 
-pub(crate) fn verify_client_events(
+#[allow(dead_code)]
+pub(crate) mod simulated_time;
+
+pub(crate) fn verify_client_events<S>(
 	runtime: &mut tokio::runtime::current_thread::Runtime,
-	client: mqtt::Client<IoSource>,
+	client: S,
 	expected: Vec<mqtt::Event>,
-) {
+)
+where
+	S: Stream<Item = mqtt::Event> + 'static,
+	S::Error: std::fmt::Debug,
+{
+	runtime.spawn(client_events_verifier(client, expected));
+}
+
+/// Returns a future that drives the client (or any other stream of its events) and asserts that it emits the `expected` events, in order
+pub(crate) fn client_events_verifier<S>(
+	client: S,
+	expected: Vec<mqtt::Event>,
+) -> impl Future<Item = (), Error = ()>
+where
+	S: Stream<Item = mqtt::Event>,
+	S::Error: std::fmt::Debug,
+{
 	let mut expected = expected.into_iter();
 
-	runtime.spawn(client.map_err(|err| panic!("{:?}", err)).for_each(move |event| {
+	client.map_err(|err| panic!("{:?}", err)).for_each(move |event| {
 		assert_eq!(expected.next(), Some(event));
 		Ok(())
-	}));
+	})
 }
 
 /// An `mqtt::IoSource` impl suitable for use with an `mqtt::Client`. The IoSource pretends to provide connections
@@ -85,7 +104,7 @@ impl mqtt::IoSource for IoSource {
 			//
 			// If the connection broke while there were still steps remaining in the TestConnection, then the dropped sender will cause the test
 			// to receive a futures::sync::oneshot::Canceled error, so the test will panic before this deadline elapses anyway.
-			Box::new(tokio::timer::Delay::new(std::time::Instant::now() + std::time::Duration::from_secs(5))
+			Box::new(tokio::timer::Delay::new(tokio::clock::now() + std::time::Duration::from_secs(5))
 			.then(|result| -> std::io::Result<_> {
 				let _ = result.unwrap();
 				unreachable!();
@@ -157,15 +176,14 @@ impl std::io::Write for TestConnection {
 			Some(TestConnectionStep::Receives((expected_packet, bytes))) => {
 				println!("server expects to receive {:?}", expected_packet);
 
-				let previous_bytes_len = bytes.len();
-
 				bytes.extend_from_slice(buf);
 
 				let mut packet_codec: mqtt::proto::PacketCodec = Default::default();
 				match packet_codec.decode(bytes) {
 					Ok(Some(actual_packet)) => {
-						// Codec will remove the bytes it's parsed successfully, so whatever's left is what didn't get parsed
-						let written = previous_bytes_len + buf.len() - bytes.len();
+						// Codec will remove the bytes it's parsed successfully, so whatever's left is what didn't get parsed.
+						// The previous writes of this packet were already counted when they returned.
+						let written = buf.len() - bytes.len();
 
 						println!("server received {:?}", actual_packet);
 						assert_eq!(*expected_packet, actual_packet);
@@ -0,0 +1,91 @@
+//! A single-threaded executor whose timers run on simulated time.
+//!
+//! Whenever the executor has no work to do, time jumps forward to the next timer's deadline instead of
+//! the thread sleeping until then. This lets tests verify the client's ping and back-off behavior
+//! deterministically, without waiting for real time to pass.
+
+use futures::Future;
+
+pub(crate) struct Runtime {
+	executor: tokio_current_thread::CurrentThread<tokio_timer::Timer<Park, tokio_timer::clock::Clock>>,
+	timer_handle: tokio_timer::timer::Handle,
+	clock: tokio_timer::clock::Clock,
+	now: Now,
+}
+
+impl Runtime {
+	pub(crate) fn new() -> Self {
+		let now = Now(std::sync::Arc::new(std::sync::Mutex::new(std::time::Instant::now())));
+		let clock = tokio_timer::clock::Clock::new_with_now(now.clone());
+		let timer = tokio_timer::Timer::new_with_now(Park(now.clone()), clock.clone());
+		let timer_handle = timer.handle();
+		let executor = tokio_current_thread::CurrentThread::new_with_park(timer);
+
+		Runtime {
+			executor,
+			timer_handle,
+			clock,
+			now,
+		}
+	}
+
+	/// The current simulated time
+	pub(crate) fn now(&self) -> std::time::Instant {
+		*self.now.0.lock().unwrap()
+	}
+
+	pub(crate) fn spawn<F>(&mut self, future: F) where F: Future<Item = (), Error = ()> + 'static {
+		self.executor.spawn(future);
+	}
+
+	pub(crate) fn block_on<F>(&mut self, future: F) -> Result<F::Item, F::Error> where F: Future {
+		let Runtime { executor, timer_handle, clock, .. } = self;
+
+		let mut enter = tokio_executor::enter().expect("multiple executors at once");
+		tokio_timer::clock::with_default(clock, &mut enter, |enter|
+			tokio_timer::with_default(timer_handle, enter, |enter|
+				executor.enter(enter).block_on(future)))
+		.map_err(|err| match err.into_inner() {
+			Some(err) => err,
+			None => panic!("executor failed to block on future"),
+		})
+	}
+}
+
+#[derive(Clone)]
+struct Now(std::sync::Arc<std::sync::Mutex<std::time::Instant>>);
+
+impl tokio_timer::clock::Now for Now {
+	fn now(&self) -> std::time::Instant {
+		*self.0.lock().unwrap()
+	}
+}
+
+/// The executor only parks when all its tasks are blocked. Since the tests' I/O is in-memory, only a timer can unblock them,
+/// so parking advances the simulated time to the timer's deadline.
+struct Park(Now);
+
+impl tokio_executor::park::Park for Park {
+	type Unpark = Unpark;
+	type Error = ();
+
+	fn unpark(&self) -> Self::Unpark {
+		Unpark
+	}
+
+	fn park(&mut self) -> Result<(), Self::Error> {
+		panic!("all tasks are blocked and there are no timers to advance simulated time to");
+	}
+
+	fn park_timeout(&mut self, duration: std::time::Duration) -> Result<(), Self::Error> {
+		*(self.0).0.lock().unwrap() += duration;
+		Ok(())
+	}
+}
+
+struct Unpark;
+
+impl tokio_executor::park::Unpark for Unpark {
+	fn unpark(&self) {
+	}
+}
@@ -0,0 +1,65 @@
+#![cfg(feature = "testing")]
+
+use futures::{ Future, Stream };
+
+fn publication(topic_name: &str, payload: &'static [u8]) -> mqtt::proto::Publication {
+	mqtt::proto::Publication {
+		topic_name: topic_name.parse().unwrap(),
+		qos: mqtt::proto::QoS::AtLeastOnce,
+		retain: true,
+		payload: payload.into(),
+	}
+}
+
+#[test]
+fn config_watcher_keeps_latest_values_and_yields_changes() {
+	let mut simulation = mqtt::testing::Simulation::new();
+
+	let broker = mqtt::testing::MockBroker::new();
+
+	let client =
+		mqtt::Client::new(
+			None,
+			None,
+			None,
+			broker.clone(),
+			std::time::Duration::from_secs(0),
+			std::time::Duration::from_secs(60),
+		);
+	let mut update_subscription_handle = client.update_subscription_handle().unwrap();
+	let mut router = mqtt::router::Router::new(client);
+
+	let watcher = mqtt::config::ConfigWatcher::new(&mut router, &mut update_subscription_handle, "config/#".parse().unwrap());
+	let config = watcher.handle();
+
+	let changes: std::rc::Rc<std::cell::RefCell<Vec<mqtt::config::ConfigChange>>> = Default::default();
+	simulation.spawn({
+		let changes = changes.clone();
+		watcher.for_each(move |change| { changes.borrow_mut().push(change); Ok(()) }).map_err(|err| panic!("{:?}", err))
+	});
+	simulation.spawn(router.for_each(|_| Ok(())).map_err(|err| panic!("{:?}", err)));
+	simulation.advance(std::time::Duration::from_secs(1));
+
+	broker.publish(&publication("config/interval", b"10"));
+	broker.publish(&publication("config/level", b"debug"));
+	simulation.advance(std::time::Duration::from_secs(1));
+	assert_eq!(config.get("config/interval"), Some(b"10"[..].into()));
+
+	// The same value again, like when the server resends the retained publications, is not a change
+	broker.publish(&publication("config/interval", b"10"));
+	broker.publish(&publication("config/interval", b"20"));
+	broker.publish(&publication("config/level", b""));
+	broker.publish(&publication("config/unset", b""));
+	broker.publish(&publication("other/interval", b"30"));
+	simulation.advance(std::time::Duration::from_secs(1));
+
+	assert_eq!(*changes.borrow(), vec![
+		mqtt::config::ConfigChange::Set { topic_name: "config/interval".to_owned(), value: b"10"[..].into(), previous: None },
+		mqtt::config::ConfigChange::Set { topic_name: "config/level".to_owned(), value: b"debug"[..].into(), previous: None },
+		mqtt::config::ConfigChange::Set { topic_name: "config/interval".to_owned(), value: b"20"[..].into(), previous: Some(b"10"[..].into()) },
+		mqtt::config::ConfigChange::Removed { topic_name: "config/level".to_owned(), previous: b"debug"[..].into() },
+	]);
+
+	assert_eq!(config.get("config/level"), None);
+	assert_eq!(config.snapshot().into_iter().collect::<Vec<_>>(), vec![("config/interval".to_owned(), b"20"[..].into())]);
+}
@@ -0,0 +1,105 @@
+#![cfg(feature = "testing")]
+
+use futures::{ Future, Stream };
+
+/// Prefixes outgoing payloads with a signature, and strips it from incoming ones
+struct Sign;
+
+impl mqtt::interceptor::Interceptor for Sign {
+	fn outgoing(&self, publication: &mut mqtt::proto::Publication) -> Result<(), mqtt::interceptor::Rejection> {
+		let mut payload = b"signed:".to_vec();
+		payload.extend_from_slice(&publication.payload);
+		publication.payload = payload.into();
+		Ok(())
+	}
+
+	fn incoming(&self, publication: &mut mqtt::ReceivedPublication) -> Result<(), mqtt::interceptor::Rejection> {
+		if !publication.payload.starts_with(b"signed:") {
+			return Err("payload is not signed".into());
+		}
+
+		publication.payload = publication.payload.slice_from(b"signed:".len());
+		Ok(())
+	}
+}
+
+/// Rejects outgoing publications to the `forbidden` topic
+struct Forbid;
+
+impl mqtt::interceptor::Interceptor for Forbid {
+	fn outgoing(&self, publication: &mut mqtt::proto::Publication) -> Result<(), mqtt::interceptor::Rejection> {
+		if publication.topic_name == "forbidden" {
+			return Err("topic is forbidden".into());
+		}
+
+		Ok(())
+	}
+}
+
+fn publication(topic_name: &str, payload: &'static [u8]) -> mqtt::proto::Publication {
+	mqtt::proto::Publication {
+		topic_name: topic_name.parse().unwrap(),
+		qos: mqtt::proto::QoS::AtLeastOnce,
+		retain: false,
+		payload: payload.into(),
+	}
+}
+
+#[test]
+fn interceptors_transform_and_reject_publications() {
+	let mut simulation = mqtt::testing::Simulation::new();
+
+	let broker = mqtt::testing::MockBroker::new();
+
+	let mut client =
+		mqtt::Client::new(
+			None,
+			None,
+			None,
+			broker.clone(),
+			std::time::Duration::from_secs(0),
+			std::time::Duration::from_secs(60),
+		);
+	let mut publish_handle = client.publish_handle().unwrap();
+	client.add_interceptor(Sign);
+	client.add_interceptor(Forbid);
+	client.subscribe(mqtt::proto::SubscribeTo { topic_filter: "commands".parse().unwrap(), qos: mqtt::proto::QoS::AtLeastOnce }).unwrap();
+
+	let publications: std::rc::Rc<std::cell::RefCell<Vec<mqtt::ReceivedPublication>>> = Default::default();
+	simulation.spawn({
+		let publications = publications.clone();
+		client
+			.for_each(move |event| {
+				if let mqtt::Event::Publication(publication) = event {
+					publications.borrow_mut().push(publication);
+				}
+				Ok(())
+			})
+			.map_err(|err| panic!("{:?}", err))
+	});
+	simulation.advance(std::time::Duration::from_secs(1));
+
+	// The handle was created before the interceptors were added, and still uses them
+	simulation.block_on(publish_handle.publish(publication("commands", b"reboot"))).unwrap();
+	assert_eq!(broker.published(), vec![publication("commands", b"signed:reboot")]);
+
+	match simulation.block_on(publish_handle.publish(publication("forbidden", b"reboot"))) {
+		Err(mqtt::PublishError::Rejected(err)) => assert_eq!(err.to_string(), "topic is forbidden"),
+		result => panic!("expected publication to be rejected but got {:?}", result),
+	}
+	assert_eq!(broker.published().len(), 1);
+
+	// Publications that the incoming interceptor rejects are dropped
+	broker.publish(&publication("commands", b"shutdown"));
+	simulation.advance(std::time::Duration::from_secs(1));
+
+	assert_eq!(*publications.borrow(), vec![
+		mqtt::ReceivedPublication {
+			topic_name: "commands".to_owned(),
+			dup: false,
+			qos: mqtt::proto::QoS::AtLeastOnce,
+			retain: false,
+			payload: b"reboot"[..].into(),
+		},
+	]);
+}
@@ -0,0 +1,57 @@
+#![cfg(feature = "native-tls")]
+
+use futures::{ Future, Stream };
+
+#[test]
+fn client_connects_over_native_tls() {
+	let mut runtime = tokio::runtime::current_thread::Runtime::new().expect("couldn't initialize tokio runtime");
+
+	let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("couldn't bind listener");
+	let addr = listener.local_addr().expect("couldn't get listener address");
+
+	let server = std::thread::spawn(move || {
+		let identity = native_tls::Identity::from_pkcs8(include_bytes!("certs/server.pem"), include_bytes!("certs/server.key")).unwrap();
+		let acceptor = native_tls::TlsAcceptor::new(identity).unwrap();
+
+		let (stream, _) = listener.accept().expect("couldn't accept connection");
+		let mut stream = acceptor.accept(stream).expect("couldn't accept TLS connection");
+
+		let mut header = [0_u8; 2];
+		std::io::Read::read_exact(&mut stream, &mut header).expect("couldn't read CONNECT");
+		assert_eq!(header[0], 0x10, "expected CONNECT packet but got {:?}", header);
+		let mut connect = vec![0; usize::from(header[1])];
+		std::io::Read::read_exact(&mut stream, &mut connect).expect("couldn't read CONNECT");
+
+		// CONNACK, connection accepted
+		std::io::Write::write_all(&mut stream, &[0x20, 0x02, 0x00, 0x00]).expect("couldn't send CONNACK");
+		stream
+	});
+
+	let connector =
+		native_tls::TlsConnector::builder()
+		.add_root_certificate(native_tls::Certificate::from_pem(include_bytes!("certs/ca.pem")).unwrap())
+		.disable_built_in_roots(true)
+		.build()
+		.unwrap();
+	let io_source =
+		mqtt::native_tls::NativeTlsIoSource::with_connector(
+			move || tokio::net::TcpStream::connect(&addr).map(|io| (io, None)),
+			"localhost".to_owned(),
+			connector,
+		);
+
+	let client =
+		mqtt::Client::new(
+			Some("client".to_owned()),
+			None,
+			None,
+			io_source,
+			std::time::Duration::from_secs(0),
+			std::time::Duration::from_secs(60),
+		);
+
+	let (event, _client) = runtime.block_on(client.into_future()).map_err(|(err, _)| err).unwrap();
+	assert_eq!(event, Some(mqtt::Event::NewConnection { reset_session: true }));
+
+	let _ = server.join().expect("server thread panicked");
+}
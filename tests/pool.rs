@@ -0,0 +1,79 @@
+#![cfg(feature = "testing")]
+
+use futures::{ Future, Stream };
+
+#[test]
+fn client_pool_spreads_publications_over_clients() {
+	let mut simulation = mqtt::testing::Simulation::new();
+
+	let broker = mqtt::testing::MockBroker::new();
+
+	let mut pool =
+		mqtt::pool::ClientPool::new(
+			"pool",
+			3,
+			None,
+			None,
+			broker.clone(),
+			std::time::Duration::from_secs(0),
+			std::time::Duration::from_secs(60),
+		);
+	assert_eq!(pool.len(), 3);
+	pool.subscribe(mqtt::proto::SubscribeTo { topic_filter: "commands".parse().unwrap(), qos: mqtt::proto::QoS::AtLeastOnce }).unwrap();
+	let mut publish_handle = pool.publish_handle().unwrap();
+
+	let events: std::rc::Rc<std::cell::RefCell<Vec<mqtt::pool::PoolEvent>>> = Default::default();
+	simulation.spawn({
+		let events = events.clone();
+		pool.for_each(move |event| { events.borrow_mut().push(event); Ok(()) }).map_err(|err| panic!("{:?}", err))
+	});
+	simulation.advance(std::time::Duration::from_secs(1));
+	assert_eq!(broker.connections(), 3);
+
+	let publication = |payload: u8| mqtt::proto::Publication {
+		topic_name: "sensors/kitchen".parse().unwrap(),
+		qos: mqtt::proto::QoS::AtLeastOnce,
+		retain: false,
+		payload: vec![payload].into(),
+	};
+
+	let publishes: Vec<_> = (0..6).map(|payload| publish_handle.publish(publication(payload))).collect();
+	simulation.block_on(futures::future::join_all(publishes)).unwrap();
+
+	let mut published = broker.published();
+	published.sort_by(|publication1, publication2| publication1.payload.cmp(&publication2.payload));
+	assert_eq!(published, (0..6).map(publication).collect::<Vec<_>>());
+
+	// The subscription was only made with the first client, so the publication is only received once
+	broker.publish(&mqtt::proto::Publication {
+		topic_name: "commands".parse().unwrap(),
+		qos: mqtt::proto::QoS::AtLeastOnce,
+		retain: false,
+		payload: b"reboot"[..].into(),
+	});
+	simulation.advance(std::time::Duration::from_secs(1));
+
+	let events = events.borrow();
+
+	let mut new_connections: Vec<_> =
+		events.iter()
+		.filter(|event| event.event == mqtt::Event::NewConnection { reset_session: true })
+		.map(|event| event.client_index)
+		.collect();
+	new_connections.sort_unstable();
+	assert_eq!(new_connections, vec![0, 1, 2]);
+
+	let publications: Vec<_> = events.iter().filter(|event| matches!(event.event, mqtt::Event::Publication(_))).collect();
+	assert_eq!(publications, vec![
+		&mqtt::pool::PoolEvent {
+			client_index: 0,
+			event: mqtt::Event::Publication(mqtt::ReceivedPublication {
+				topic_name: "commands".to_owned(),
+				dup: false,
+				qos: mqtt::proto::QoS::AtLeastOnce,
+				retain: false,
+				payload: b"reboot"[..].into(),
+			}),
+		},
+	]);
+}
@@ -0,0 +1,63 @@
+#[test]
+fn codec_round_trips_every_packet() {
+	use tokio_codec::{ Decoder, Encoder };
+
+	let packet_identifier = mqtt::proto::PacketIdentifier::new(5).unwrap();
+
+	let packets: Vec<mqtt::proto::Packet> = vec![
+		mqtt::proto::Connect {
+			username: Some("username".to_string()),
+			password: Some("password".to_string()),
+			will: Some(mqtt::proto::Publication {
+				topic_name: "will/topic".parse().unwrap(),
+				qos: mqtt::proto::QoS::AtLeastOnce,
+				retain: true,
+				payload: b"will"[..].into(),
+			}),
+			client_id: mqtt::proto::ClientId::IdWithCleanSession("client1".to_string()),
+			keep_alive: std::time::Duration::from_secs(30),
+		}.into(),
+		mqtt::proto::ConnAck {
+			session_present: true,
+			return_code: mqtt::proto::ConnectReturnCode::Accepted,
+		}.into(),
+		mqtt::proto::Publish {
+			packet_identifier_dup_qos: mqtt::proto::PacketIdentifierDupQoS::ExactlyOnce(packet_identifier, true),
+			retain: false,
+			topic_name: "topic1".to_string(),
+			payload: b"payload"[..].into(),
+		}.into(),
+		mqtt::proto::PubAck { packet_identifier }.into(),
+		mqtt::proto::PubRec { packet_identifier }.into(),
+		mqtt::proto::PubRel { packet_identifier }.into(),
+		mqtt::proto::PubComp { packet_identifier }.into(),
+		mqtt::proto::Subscribe {
+			packet_identifier,
+			subscribe_to: vec![mqtt::proto::SubscribeTo { topic_filter: "topic/+".parse().unwrap(), qos: mqtt::proto::QoS::ExactlyOnce }],
+		}.into(),
+		mqtt::proto::SubAck {
+			packet_identifier,
+			qos: vec![mqtt::proto::SubAckQos::Success(mqtt::proto::QoS::ExactlyOnce), mqtt::proto::SubAckQos::Failure],
+		}.into(),
+		mqtt::proto::Unsubscribe {
+			packet_identifier,
+			unsubscribe_from: vec!["topic/+".to_string()],
+		}.into(),
+		mqtt::proto::UnsubAck { packet_identifier }.into(),
+		mqtt::proto::PingReq.into(),
+		mqtt::proto::PingResp.into(),
+		mqtt::proto::Disconnect.into(),
+	];
+
+	let mut codec: mqtt::proto::PacketCodec = Default::default();
+
+	let mut bytes = bytes::BytesMut::new();
+	for packet in packets.clone() {
+		codec.encode(packet, &mut bytes).unwrap();
+	}
+
+	for packet in packets {
+		assert_eq!(codec.decode(&mut bytes).unwrap(), Some(packet));
+	}
+	assert!(bytes.is_empty());
+}
@@ -22,7 +22,7 @@ fn server_publishes_at_most_once() {
 			common::TestConnectionStep::Receives(mqtt::proto::Packet::Subscribe(mqtt::proto::Subscribe {
 				packet_identifier: mqtt::proto::PacketIdentifier::new(1).unwrap(),
 				subscribe_to: vec![
-					mqtt::proto::SubscribeTo { topic_filter: "topic1".to_owned(), qos: mqtt::proto::QoS::AtMostOnce },
+					mqtt::proto::SubscribeTo { topic_filter: "topic1".parse().unwrap(), qos: mqtt::proto::QoS::AtMostOnce },
 				],
 			})),
 
@@ -59,12 +59,12 @@ fn server_publishes_at_most_once() {
 			std::time::Duration::from_secs(0),
 			std::time::Duration::from_secs(4),
 		);
-	client.subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic1".to_owned(), qos: mqtt::proto::QoS::AtMostOnce }).unwrap();
+	client.subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic1".parse().unwrap(), qos: mqtt::proto::QoS::AtMostOnce }).unwrap();
 
 	common::verify_client_events(&mut runtime, client, vec![
 		mqtt::Event::NewConnection { reset_session: true },
 		mqtt::Event::SubscriptionUpdates(vec![
-			mqtt::SubscriptionUpdateEvent::Subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic1".to_owned(), qos: mqtt::proto::QoS::AtMostOnce }),
+			mqtt::SubscriptionUpdateEvent::Subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic1".parse().unwrap(), qos: mqtt::proto::QoS::AtMostOnce }),
 		]),
 		mqtt::Event::Publication(mqtt::ReceivedPublication {
 			topic_name: "topic1".to_owned(),
@@ -100,7 +100,7 @@ fn server_publishes_at_least_once() {
 			common::TestConnectionStep::Receives(mqtt::proto::Packet::Subscribe(mqtt::proto::Subscribe {
 				packet_identifier: mqtt::proto::PacketIdentifier::new(1).unwrap(),
 				subscribe_to: vec![
-					mqtt::proto::SubscribeTo { topic_filter: "topic1".to_owned(), qos: mqtt::proto::QoS::AtLeastOnce },
+					mqtt::proto::SubscribeTo { topic_filter: "topic1".parse().unwrap(), qos: mqtt::proto::QoS::AtLeastOnce },
 				],
 			})),
 
@@ -141,12 +141,12 @@ fn server_publishes_at_least_once() {
 			std::time::Duration::from_secs(0),
 			std::time::Duration::from_secs(4),
 		);
-	client.subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic1".to_owned(), qos: mqtt::proto::QoS::AtLeastOnce }).unwrap();
+	client.subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic1".parse().unwrap(), qos: mqtt::proto::QoS::AtLeastOnce }).unwrap();
 
 	common::verify_client_events(&mut runtime, client, vec![
 		mqtt::Event::NewConnection { reset_session: true },
 		mqtt::Event::SubscriptionUpdates(vec![
-			mqtt::SubscriptionUpdateEvent::Subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic1".to_owned(), qos: mqtt::proto::QoS::AtLeastOnce }),
+			mqtt::SubscriptionUpdateEvent::Subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic1".parse().unwrap(), qos: mqtt::proto::QoS::AtLeastOnce }),
 		]),
 		mqtt::Event::Publication(mqtt::ReceivedPublication {
 			topic_name: "topic1".to_owned(),
@@ -197,7 +197,7 @@ fn server_publishes_at_least_once_with_reconnect_before_publish() {
 			common::TestConnectionStep::Receives(mqtt::proto::Packet::Subscribe(mqtt::proto::Subscribe {
 				packet_identifier: mqtt::proto::PacketIdentifier::new(1).unwrap(),
 				subscribe_to: vec![
-					mqtt::proto::SubscribeTo { topic_filter: "topic1".to_owned(), qos: mqtt::proto::QoS::AtLeastOnce },
+					mqtt::proto::SubscribeTo { topic_filter: "topic1".parse().unwrap(), qos: mqtt::proto::QoS::AtLeastOnce },
 				],
 			})),
 
@@ -253,13 +253,13 @@ fn server_publishes_at_least_once_with_reconnect_before_publish() {
 			std::time::Duration::from_secs(0),
 			std::time::Duration::from_secs(4),
 		);
-	client.subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic1".to_owned(), qos: mqtt::proto::QoS::AtLeastOnce }).unwrap();
+	client.subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic1".parse().unwrap(), qos: mqtt::proto::QoS::AtLeastOnce }).unwrap();
 
 	common::verify_client_events(&mut runtime, client, vec![
 		mqtt::Event::NewConnection { reset_session: true },
 		mqtt::Event::NewConnection { reset_session: false },
 		mqtt::Event::SubscriptionUpdates(vec![
-			mqtt::SubscriptionUpdateEvent::Subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic1".to_owned(), qos: mqtt::proto::QoS::AtLeastOnce }),
+			mqtt::SubscriptionUpdateEvent::Subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic1".parse().unwrap(), qos: mqtt::proto::QoS::AtLeastOnce }),
 		]),
 		mqtt::Event::NewConnection { reset_session: false },
 		mqtt::Event::Publication(mqtt::ReceivedPublication {
@@ -311,7 +311,7 @@ fn server_publishes_at_least_once_with_reconnect_before_ack() {
 			common::TestConnectionStep::Receives(mqtt::proto::Packet::Subscribe(mqtt::proto::Subscribe {
 				packet_identifier: mqtt::proto::PacketIdentifier::new(1).unwrap(),
 				subscribe_to: vec![
-					mqtt::proto::SubscribeTo { topic_filter: "topic1".to_owned(), qos: mqtt::proto::QoS::AtLeastOnce },
+					mqtt::proto::SubscribeTo { topic_filter: "topic1".parse().unwrap(), qos: mqtt::proto::QoS::AtLeastOnce },
 				],
 			})),
 
@@ -374,13 +374,13 @@ fn server_publishes_at_least_once_with_reconnect_before_ack() {
 			std::time::Duration::from_secs(0),
 			std::time::Duration::from_secs(4),
 		);
-	client.subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic1".to_owned(), qos: mqtt::proto::QoS::AtLeastOnce }).unwrap();
+	client.subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic1".parse().unwrap(), qos: mqtt::proto::QoS::AtLeastOnce }).unwrap();
 
 	common::verify_client_events(&mut runtime, client, vec![
 		mqtt::Event::NewConnection { reset_session: true },
 		mqtt::Event::NewConnection { reset_session: false },
 		mqtt::Event::SubscriptionUpdates(vec![
-			mqtt::SubscriptionUpdateEvent::Subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic1".to_owned(), qos: mqtt::proto::QoS::AtLeastOnce }),
+			mqtt::SubscriptionUpdateEvent::Subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic1".parse().unwrap(), qos: mqtt::proto::QoS::AtLeastOnce }),
 		]),
 		mqtt::Event::Publication(mqtt::ReceivedPublication {
 			topic_name: "topic1".to_owned(),
@@ -404,6 +404,71 @@ fn server_publishes_at_least_once_with_reconnect_before_ack() {
 
 #[test]
 fn should_reject_invalid_publications() {
+	let too_large_topic_name = "a".repeat(usize::from(u16::max_value()) + 1);
+
+	match mqtt::topic::TopicName::new(too_large_topic_name) {
+		Err(mqtt::topic::TopicError::TooLong(_)) => (),
+		result => panic!("expected TopicName::new() to fail with TooLong but it returned {:?}", result),
+	}
+	match "topic/+".parse::<mqtt::topic::TopicName>() {
+		Err(mqtt::topic::TopicError::WildcardInTopicName(_)) => (),
+		result => panic!("expected TopicName::new() to fail with WildcardInTopicName but it returned {:?}", result),
+	}
+}
+
+#[test]
+fn should_reject_publications_larger_than_max_outgoing_packet_size() {
+	let mut runtime = tokio::runtime::current_thread::Runtime::new().expect("couldn't initialize tokio runtime");
+
+	let (io_source, _) = common::IoSource::new(vec![]);
+
+	let mut client =
+		mqtt::Client::new(
+			None,
+			None,
+			None,
+			io_source,
+			std::time::Duration::from_secs(0),
+			std::time::Duration::from_secs(4),
+		);
+
+	let mut publish_handle = client.publish_handle().unwrap();
+
+	client.set_max_outgoing_packet_size(Some(12));
+
+	// Remaining length = topic name (2 + 6) + packet identifier (2) + payload (3) = 13
+	match runtime.block_on(client.publish(mqtt::proto::Publication {
+		topic_name: "topic1".parse().unwrap(),
+		qos: mqtt::proto::QoS::AtLeastOnce,
+		retain: false,
+		payload: [0x01, 0x02, 0x03][..].into(),
+	})) {
+		Err(mqtt::PublishError::PacketTooLarge { remaining_length: 13, max: 12, .. }) => (),
+		result => panic!("expected client.publish() to fail with PacketTooLarge but it returned {:?}", result),
+	}
+
+	// The handle was created before the limit was set, but still enforces it
+	match publish_handle.publish_without_ack(mqtt::proto::Publication {
+		topic_name: "topic1".parse().unwrap(),
+		qos: mqtt::proto::QoS::AtLeastOnce,
+		retain: false,
+		payload: [0x01, 0x02, 0x03][..].into(),
+	}) {
+		Err(mqtt::PublishError::PacketTooLarge { remaining_length: 13, max: 12, .. }) => (),
+		result => panic!("expected publish_without_ack() to fail with PacketTooLarge but it returned {:?}", result),
+	}
+
+	// Remaining length = topic name (2 + 6) + payload (3) = 11
+	publish_handle.publish_without_ack(mqtt::proto::Publication {
+		topic_name: "topic1".parse().unwrap(),
+		qos: mqtt::proto::QoS::AtMostOnce,
+		retain: false,
+		payload: [0x01, 0x02, 0x03][..].into(),
+	}).unwrap();
+}
+
+#[test]
+fn client_publishes_after_handle_is_ready() {
 	let mut runtime = tokio::runtime::current_thread::Runtime::new().expect("couldn't initialize tokio runtime");
 
 	let (io_source, done) = common::IoSource::new(vec![
@@ -421,13 +486,79 @@ fn should_reject_invalid_publications() {
 				return_code: mqtt::proto::ConnectReturnCode::Accepted,
 			})),
 
-			common::TestConnectionStep::Receives(mqtt::proto::Packet::PingReq(mqtt::proto::PingReq)),
+			common::TestConnectionStep::Receives(mqtt::proto::Packet::Publish(mqtt::proto::Publish {
+				packet_identifier_dup_qos: mqtt::proto::PacketIdentifierDupQoS::AtLeastOnce(mqtt::proto::PacketIdentifier::new(1).unwrap(), false),
+				retain: false,
+				topic_name: "topic1".to_owned(),
+				payload: [0x01, 0x02, 0x03][..].into(),
+			})),
 
-			common::TestConnectionStep::Sends(mqtt::proto::Packet::PingResp(mqtt::proto::PingResp)),
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::PubAck(mqtt::proto::PubAck {
+				packet_identifier: mqtt::proto::PacketIdentifier::new(1).unwrap(),
+			})),
+		],
+	]);
 
-			common::TestConnectionStep::Receives(mqtt::proto::Packet::PingReq(mqtt::proto::PingReq)),
+	let client =
+		mqtt::Client::new(
+			None,
+			None,
+			None,
+			io_source,
+			std::time::Duration::from_secs(0),
+			std::time::Duration::from_secs(4),
+		);
 
-			common::TestConnectionStep::Sends(mqtt::proto::Packet::PingResp(mqtt::proto::PingResp)),
+	let mut publish_handle = client.publish_handle().expect("couldn't get publish handle");
+
+	common::verify_client_events(&mut runtime, client, vec![
+		mqtt::Event::NewConnection { reset_session: true },
+	]);
+
+	runtime.block_on(futures::future::poll_fn(|| publish_handle.poll_ready())).expect("publish handle did not become ready");
+	runtime.spawn(futures::Future::map_err(
+		publish_handle.publish(mqtt::proto::Publication {
+			topic_name: "topic1".parse().unwrap(),
+			qos: mqtt::proto::QoS::AtLeastOnce,
+			retain: false,
+			payload: [0x01, 0x02, 0x03][..].into(),
+		}),
+		|err| panic!("couldn't publish: {}", err)));
+
+	runtime.block_on(done).expect("connection broken while there were still steps remaining on the server");
+}
+
+#[test]
+fn publish_channel_capacity_buffers_requests() {
+	let mut runtime = tokio::runtime::current_thread::Runtime::new().expect("couldn't initialize tokio runtime");
+
+	let publish_packet = mqtt::proto::Packet::Publish(mqtt::proto::Publish {
+		packet_identifier_dup_qos: mqtt::proto::PacketIdentifierDupQoS::AtMostOnce,
+		retain: false,
+		topic_name: "topic1".to_owned(),
+		payload: [0x01, 0x02, 0x03][..].into(),
+	});
+
+	let (io_source, done) = common::IoSource::new(vec![
+		vec![
+			common::TestConnectionStep::Receives(mqtt::proto::Packet::Connect(mqtt::proto::Connect {
+				username: None,
+				password: None,
+				will: None,
+				client_id: mqtt::proto::ClientId::ServerGenerated,
+				keep_alive: std::time::Duration::from_secs(4),
+			})),
+
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck(mqtt::proto::ConnAck {
+				session_present: false,
+				return_code: mqtt::proto::ConnectReturnCode::Accepted,
+			})),
+
+			common::TestConnectionStep::Receives(publish_packet.clone()),
+
+			common::TestConnectionStep::Receives(publish_packet.clone()),
+
+			common::TestConnectionStep::Receives(publish_packet),
 		],
 	]);
 
@@ -440,23 +571,1399 @@ fn should_reject_invalid_publications() {
 			std::time::Duration::from_secs(0),
 			std::time::Duration::from_secs(4),
 		);
+	client.set_publish_channel_capacity(2);
+
+	let mut publish_handle = client.publish_handle().expect("couldn't get publish handle");
+
+	// The client isn't running yet, so these can only be accepted if they're buffered in the channel.
+	// The channel has room for the configured capacity plus one request from each handle.
+	let publish_futures = runtime.block_on(futures::future::poll_fn(|| -> futures::Poll<_, mqtt::PublishError> {
+		let mut publish_futures = vec![];
+		for _ in 0..3 {
+			futures::try_ready!(publish_handle.poll_ready());
+			publish_futures.push(publish_handle.publish(mqtt::proto::Publication {
+				topic_name: "topic1".parse().unwrap(),
+				qos: mqtt::proto::QoS::AtMostOnce,
+				retain: false,
+				payload: [0x01, 0x02, 0x03][..].into(),
+			}));
+		}
+		Ok(futures::Async::Ready(publish_futures))
+	})).expect("publish handle did not become ready");
 
-	let too_large_topic_name = "a".repeat(usize::from(u16::max_value()) + 1);
+	common::verify_client_events(&mut runtime, client, vec![
+		mqtt::Event::NewConnection { reset_session: true },
+	]);
 
-	let publish_future = client.publish(mqtt::proto::Publication {
-		topic_name: too_large_topic_name,
+	runtime.spawn(futures::Future::map_err(
+		futures::Future::map(futures::future::join_all(publish_futures), |_| ()),
+		|err| panic!("couldn't publish: {}", err)));
+
+	runtime.block_on(done).expect("connection broken while there were still steps remaining on the server");
+}
+
+#[test]
+fn publish_channel_capacity_applies_to_existing_handles() {
+	let (io_source, _done) = common::IoSource::new(vec![]);
+
+	let mut client =
+		mqtt::Client::new(
+			None,
+			None,
+			None,
+			io_source,
+			std::time::Duration::from_secs(0),
+			std::time::Duration::from_secs(4),
+		);
+
+	let mut publish_handle = client.publish_handle().expect("couldn't get publish handle");
+
+	let publication = mqtt::proto::Publication {
+		topic_name: "topic1".parse().unwrap(),
 		qos: mqtt::proto::QoS::AtMostOnce,
 		retain: false,
-		payload: Default::default(),
+		payload: [0x01, 0x02, 0x03][..].into(),
+	};
+
+	publish_handle.publish_without_ack(publication.clone()).expect("couldn't publish");
+	let publication = match publish_handle.publish_without_ack(publication) {
+		Err(mqtt::PublishError::NotReady(publication)) => publication,
+		result => panic!("expected publish to fail with NotReady but got {:?}", result),
+	};
+
+	client.set_publish_channel_capacity(1);
+
+	publish_handle.publish_without_ack(publication.clone()).expect("couldn't publish");
+	match publish_handle.publish_without_ack(publication) {
+		Err(mqtt::PublishError::NotReady(_)) => (),
+		result => panic!("expected publish to fail with NotReady but got {:?}", result),
+	}
+
+	drop(client);
+
+	match publish_handle.poll_ready() {
+		Err(mqtt::PublishError::ClientDoesNotExist) => (),
+		result => panic!("expected handle to fail with ClientDoesNotExist but got {:?}", result),
+	}
+}
+
+#[test]
+fn flush_policy_holds_packets_back_until_the_delay_has_passed() {
+	#[derive(Debug, Default)]
+	struct RecordingMetricsSink {
+		flushes: std::sync::Mutex<Vec<usize>>,
+	}
+
+	impl mqtt::metrics::MetricsSink for RecordingMetricsSink {
+		fn flushed(&self, packets: usize) {
+			self.flushes.lock().unwrap().push(packets);
+		}
+	}
+
+	let mut runtime = common::simulated_time::Runtime::new();
+
+	let publish_packet = mqtt::proto::Packet::Publish(mqtt::proto::Publish {
+		packet_identifier_dup_qos: mqtt::proto::PacketIdentifierDupQoS::AtMostOnce,
+		retain: false,
+		topic_name: "topic1".to_owned(),
+		payload: [0x01, 0x02, 0x03][..].into(),
 	});
 
-	common::verify_client_events(&mut runtime, client, vec![
+	let (io_source, _done) = common::IoSource::new(vec![
+		vec![
+			common::TestConnectionStep::Receives(mqtt::proto::Packet::Connect(mqtt::proto::Connect {
+				username: None,
+				password: None,
+				will: None,
+				client_id: mqtt::proto::ClientId::ServerGenerated,
+				keep_alive: std::time::Duration::from_secs(0),
+			})),
+
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck(mqtt::proto::ConnAck {
+				session_present: false,
+				return_code: mqtt::proto::ConnectReturnCode::Accepted,
+			})),
+
+			common::TestConnectionStep::Receives(publish_packet.clone()),
+
+			common::TestConnectionStep::Receives(publish_packet.clone()),
+
+			common::TestConnectionStep::Receives(publish_packet),
+		],
+	]);
+
+	let mut client =
+		mqtt::Client::new(
+			None,
+			None,
+			None,
+			io_source,
+			std::time::Duration::from_secs(0),
+			std::time::Duration::from_secs(0),
+		);
+	client.set_flush_policy(mqtt::FlushPolicy::AfterDelay(std::time::Duration::from_secs(1)));
+
+	let metrics_sink: std::sync::Arc<RecordingMetricsSink> = Default::default();
+	client.set_metrics_sink(metrics_sink.clone());
+
+	let mut publish_handle = client.publish_handle().expect("couldn't get publish handle");
+
+	let publish_futures: Vec<_> =
+		(0..3)
+		.map(|_| publish_handle.publish(mqtt::proto::Publication {
+			topic_name: "topic1".parse().unwrap(),
+			qos: mqtt::proto::QoS::AtMostOnce,
+			retain: false,
+			payload: [0x01, 0x02, 0x03][..].into(),
+		}))
+		.collect();
+
+	runtime.spawn(common::client_events_verifier(client, vec![
 		mqtt::Event::NewConnection { reset_session: true },
+	]));
+
+	let start = runtime.now();
+
+	runtime.block_on(futures::future::join_all(publish_futures)).expect("couldn't publish");
+
+	// The CONNECT packet is flushed immediately, but the PUBLISH packets are held back
+	runtime.block_on(tokio::timer::Delay::new(start + std::time::Duration::from_millis(500))).unwrap();
+	assert_eq!(*metrics_sink.flushes.lock().unwrap(), [1]);
+
+	// ... until they share one flush after the delay
+	runtime.block_on(tokio::timer::Delay::new(start + std::time::Duration::from_secs(2))).unwrap();
+	assert_eq!(*metrics_sink.flushes.lock().unwrap(), [1, 3]);
+}
+
+#[test]
+fn publish_with_timeout_fails_when_not_acked() {
+	let mut runtime = common::simulated_time::Runtime::new();
+
+	let (io_source, _done) = common::IoSource::new(vec![
+		vec![
+			common::TestConnectionStep::Receives(mqtt::proto::Packet::Connect(mqtt::proto::Connect {
+				username: None,
+				password: None,
+				will: None,
+				client_id: mqtt::proto::ClientId::ServerGenerated,
+				keep_alive: std::time::Duration::from_secs(0),
+			})),
+
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck(mqtt::proto::ConnAck {
+				session_present: false,
+				return_code: mqtt::proto::ConnectReturnCode::Accepted,
+			})),
+
+			common::TestConnectionStep::Receives(mqtt::proto::Packet::Publish(mqtt::proto::Publish {
+				packet_identifier_dup_qos: mqtt::proto::PacketIdentifierDupQoS::AtLeastOnce(mqtt::proto::PacketIdentifier::new(1).unwrap(), false),
+				retain: false,
+				topic_name: "topic1".to_owned(),
+				payload: [0x01, 0x02, 0x03][..].into(),
+			})),
+
+			// The server never acks the publication, so the client doesn't get past this.
+			common::TestConnectionStep::Receives(mqtt::proto::Packet::Disconnect(mqtt::proto::Disconnect)),
+		],
 	]);
 
-	runtime.block_on(done).expect("connection broken while there were still steps remaining on the server");
+	let client =
+		mqtt::Client::new(
+			None,
+			None,
+			None,
+			io_source,
+			std::time::Duration::from_secs(0),
+			std::time::Duration::from_secs(0),
+		);
+
+	let mut publish_handle = client.publish_handle().expect("couldn't get publish handle");
+
+	runtime.spawn(common::client_events_verifier(client, vec![
+		mqtt::Event::NewConnection { reset_session: true },
+	]));
+
+	let start = runtime.now();
+
+	let publish_future = publish_handle.publish_with_timeout(mqtt::proto::Publication {
+		topic_name: "topic1".parse().unwrap(),
+		qos: mqtt::proto::QoS::AtLeastOnce,
+		retain: false,
+		payload: [0x01, 0x02, 0x03][..].into(),
+	}, std::time::Duration::from_secs(10));
+
 	match runtime.block_on(publish_future) {
-		Err(mqtt::PublishError::EncodePacket(_, mqtt::proto::EncodeError::StringTooLarge(_))) => (),
-		result => panic!("expected client.publish() to fail with EncodePacket(StringTooLarge) but it returned {:?}", result),
+		Err(mqtt::PublishError::Timeout) => (),
+		result => panic!("expected publish to time out but got {:?}", result),
+	}
+	assert!(runtime.now() - start >= std::time::Duration::from_secs(10));
+}
+
+#[test]
+fn publish_with_timeout_is_discarded_if_not_sent_in_time() {
+	let mut runtime = common::simulated_time::Runtime::new();
+
+	let (io_source, done) = common::IoSource::new(vec![
+		vec![
+			common::TestConnectionStep::Receives(mqtt::proto::Packet::Connect(mqtt::proto::Connect {
+				username: None,
+				password: None,
+				will: None,
+				client_id: mqtt::proto::ClientId::ServerGenerated,
+				keep_alive: std::time::Duration::from_secs(4),
+			})),
+
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck(mqtt::proto::ConnAck {
+				session_present: false,
+				return_code: mqtt::proto::ConnectReturnCode::Accepted,
+			})),
+
+			common::TestConnectionStep::Receives(mqtt::proto::Packet::Publish(mqtt::proto::Publish {
+				packet_identifier_dup_qos: mqtt::proto::PacketIdentifierDupQoS::AtMostOnce,
+				retain: false,
+				topic_name: "topic2".to_owned(),
+				payload: [0x01, 0x02, 0x03][..].into(),
+			})),
+		],
+	]);
+
+	let client =
+		mqtt::Client::new(
+			None,
+			None,
+			None,
+			io_source,
+			std::time::Duration::from_secs(0),
+			std::time::Duration::from_secs(4),
+		);
+
+	let mut publish_handle = client.publish_handle().expect("couldn't get publish handle");
+
+	// The client isn't running yet, so this times out before the client can send it.
+	let mut publish_future = publish_handle.publish_with_timeout(mqtt::proto::Publication {
+		topic_name: "topic1".parse().unwrap(),
+		qos: mqtt::proto::QoS::AtMostOnce,
+		retain: false,
+		payload: [0x01, 0x02, 0x03][..].into(),
+	}, std::time::Duration::from_secs(10));
+
+	// The future is kept, so it's the timeout itself that discards the publication
+	match runtime.block_on(futures::future::poll_fn(|| futures::Future::poll(&mut publish_future))) {
+		Err(mqtt::PublishError::Timeout) => (),
+		result => panic!("expected publish to time out but got {:?}", result),
 	}
+
+	runtime.spawn(common::client_events_verifier(client, vec![
+		mqtt::Event::NewConnection { reset_session: true },
+	]));
+
+	runtime.spawn(futures::Future::map_err(
+		publish_handle.publish(mqtt::proto::Publication {
+			topic_name: "topic2".parse().unwrap(),
+			qos: mqtt::proto::QoS::AtMostOnce,
+			retain: false,
+			payload: [0x01, 0x02, 0x03][..].into(),
+		}),
+		|err| panic!("couldn't publish: {}", err)));
+
+	runtime.block_on(done).expect("connection broken while there were still steps remaining on the server");
+
+	drop(publish_future);
+}
+
+#[test]
+fn publish_without_ack() {
+	let mut runtime = tokio::runtime::current_thread::Runtime::new().expect("couldn't initialize tokio runtime");
+
+	let (io_source, done) = common::IoSource::new(vec![
+		vec![
+			common::TestConnectionStep::Receives(mqtt::proto::Packet::Connect(mqtt::proto::Connect {
+				username: None,
+				password: None,
+				will: None,
+				client_id: mqtt::proto::ClientId::ServerGenerated,
+				keep_alive: std::time::Duration::from_secs(4),
+			})),
+
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck(mqtt::proto::ConnAck {
+				session_present: false,
+				return_code: mqtt::proto::ConnectReturnCode::Accepted,
+			})),
+
+			common::TestConnectionStep::Receives(mqtt::proto::Packet::Publish(mqtt::proto::Publish {
+				packet_identifier_dup_qos: mqtt::proto::PacketIdentifierDupQoS::AtMostOnce,
+				retain: false,
+				topic_name: "topic1".to_owned(),
+				payload: [0x01, 0x02, 0x03][..].into(),
+			})),
+		],
+	]);
+
+	let client =
+		mqtt::Client::new(
+			None,
+			None,
+			None,
+			io_source,
+			std::time::Duration::from_secs(0),
+			std::time::Duration::from_secs(4),
+		);
+
+	let mut publish_handle = client.publish_handle().expect("couldn't get publish handle");
+
+	let publication = mqtt::proto::Publication {
+		topic_name: "topic1".parse().unwrap(),
+		qos: mqtt::proto::QoS::AtMostOnce,
+		retain: false,
+		payload: [0x01, 0x02, 0x03][..].into(),
+	};
+
+	publish_handle.publish_without_ack(publication.clone()).expect("couldn't publish");
+
+	// The client isn't running yet, so it can't have picked up the first publication.
+	match publish_handle.publish_without_ack(publication.clone()) {
+		Err(mqtt::PublishError::NotReady(returned_publication)) => assert_eq!(returned_publication, publication),
+		result => panic!("expected publish to fail with NotReady but got {:?}", result),
+	}
+
+	common::verify_client_events(&mut runtime, client, vec![
+		mqtt::Event::NewConnection { reset_session: true },
+	]);
+
+	runtime.block_on(done).expect("connection broken while there were still steps remaining on the server");
+}
+
+#[test]
+fn publish_ack_returns_token() {
+	let mut runtime = tokio::runtime::current_thread::Runtime::new().expect("couldn't initialize tokio runtime");
+
+	let (io_source, done) = common::IoSource::new(vec![
+		vec![
+			common::TestConnectionStep::Receives(mqtt::proto::Packet::Connect(mqtt::proto::Connect {
+				username: None,
+				password: None,
+				will: None,
+				client_id: mqtt::proto::ClientId::ServerGenerated,
+				keep_alive: std::time::Duration::from_secs(4),
+			})),
+
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck(mqtt::proto::ConnAck {
+				session_present: false,
+				return_code: mqtt::proto::ConnectReturnCode::Accepted,
+			})),
+
+			common::TestConnectionStep::Receives(mqtt::proto::Packet::Publish(mqtt::proto::Publish {
+				packet_identifier_dup_qos: mqtt::proto::PacketIdentifierDupQoS::AtLeastOnce(mqtt::proto::PacketIdentifier::new(1).unwrap(), false),
+				retain: false,
+				topic_name: "topic1".to_owned(),
+				payload: [0x01, 0x02, 0x03][..].into(),
+			})),
+
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::PubAck(mqtt::proto::PubAck {
+				packet_identifier: mqtt::proto::PacketIdentifier::new(1).unwrap(),
+			})),
+		],
+	]);
+
+	let client =
+		mqtt::Client::new(
+			None,
+			None,
+			None,
+			io_source,
+			std::time::Duration::from_secs(0),
+			std::time::Duration::from_secs(4),
+		);
+
+	let mut publish_handle = client.publish_handle().expect("couldn't get publish handle");
+
+	common::verify_client_events(&mut runtime, client, vec![
+		mqtt::Event::NewConnection { reset_session: true },
+	]);
+
+	let publish_future =
+		publish_handle.publish(mqtt::proto::Publication {
+			topic_name: "topic1".parse().unwrap(),
+			qos: mqtt::proto::QoS::AtLeastOnce,
+			retain: false,
+			payload: [0x01, 0x02, 0x03][..].into(),
+		})
+		.with_token(("record", 5));
+	let token = runtime.block_on(publish_future).unwrap_or_else(|(_, err)| panic!("couldn't publish: {}", err));
+	assert_eq!(token, ("record", 5));
+
+	runtime.block_on(done).expect("connection broken while there were still steps remaining on the server");
+}
+
+#[test]
+fn publish_to_many_publishes_payload_to_every_topic() {
+	let mut runtime = tokio::runtime::current_thread::Runtime::new().expect("couldn't initialize tokio runtime");
+
+	let publish = |packet_identifier, topic_name: &str| mqtt::proto::Packet::Publish(mqtt::proto::Publish {
+		packet_identifier_dup_qos: mqtt::proto::PacketIdentifierDupQoS::AtLeastOnce(mqtt::proto::PacketIdentifier::new(packet_identifier).unwrap(), false),
+		retain: true,
+		topic_name: topic_name.to_owned(),
+		payload: [0x01, 0x02, 0x03][..].into(),
+	});
+	let puback = |packet_identifier| mqtt::proto::Packet::PubAck(mqtt::proto::PubAck {
+		packet_identifier: mqtt::proto::PacketIdentifier::new(packet_identifier).unwrap(),
+	});
+
+	let (io_source, done) = common::IoSource::new(vec![
+		vec![
+			common::TestConnectionStep::Receives(mqtt::proto::Packet::Connect(mqtt::proto::Connect {
+				username: None,
+				password: None,
+				will: None,
+				client_id: mqtt::proto::ClientId::ServerGenerated,
+				keep_alive: std::time::Duration::from_secs(4),
+			})),
+
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck(mqtt::proto::ConnAck {
+				session_present: false,
+				return_code: mqtt::proto::ConnectReturnCode::Accepted,
+			})),
+
+			common::TestConnectionStep::Receives(publish(1, "topic1")),
+			common::TestConnectionStep::Receives(publish(2, "topic2")),
+			common::TestConnectionStep::Receives(publish(3, "topic3")),
+
+			common::TestConnectionStep::Sends(puback(2)),
+			common::TestConnectionStep::Sends(puback(1)),
+			common::TestConnectionStep::Sends(puback(3)),
+		],
+	]);
+
+	let client =
+		mqtt::Client::new(
+			None,
+			None,
+			None,
+			io_source,
+			std::time::Duration::from_secs(0),
+			std::time::Duration::from_secs(4),
+		);
+
+	let mut publish_handle = client.publish_handle().expect("couldn't get publish handle");
+
+	common::verify_client_events(&mut runtime, client, vec![
+		mqtt::Event::NewConnection { reset_session: true },
+	]);
+
+	let publish_future = publish_handle.publish_to_many(
+		vec!["topic1".parse().unwrap(), "topic2".parse().unwrap(), "topic3".parse().unwrap()],
+		mqtt::proto::QoS::AtLeastOnce,
+		true,
+		[0x01, 0x02, 0x03][..].into(),
+	);
+	runtime.block_on(publish_future).unwrap();
+
+	runtime.block_on(done).expect("connection broken while there were still steps remaining on the server");
+}
+
+#[test]
+fn publish_stream_publishes_payload_chunks() {
+	let mut runtime = tokio::runtime::current_thread::Runtime::new().expect("couldn't initialize tokio runtime");
+
+	let payload_chunks: Vec<bytes::Bytes> = vec![vec![0x01; 5000].into(), vec![0x02; 3].into(), vec![0x03; 6000].into()];
+	let payload: bytes::Bytes = payload_chunks.concat().into();
+
+	let (io_source, done) = common::IoSource::new(vec![
+		vec![
+			common::TestConnectionStep::Receives(mqtt::proto::Packet::Connect(mqtt::proto::Connect {
+				username: None,
+				password: None,
+				will: None,
+				client_id: mqtt::proto::ClientId::ServerGenerated,
+				keep_alive: std::time::Duration::from_secs(4),
+			})),
+
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck(mqtt::proto::ConnAck {
+				session_present: false,
+				return_code: mqtt::proto::ConnectReturnCode::Accepted,
+			})),
+
+			common::TestConnectionStep::Receives(mqtt::proto::Packet::Publish(mqtt::proto::Publish {
+				packet_identifier_dup_qos: mqtt::proto::PacketIdentifierDupQoS::AtLeastOnce(mqtt::proto::PacketIdentifier::new(1).unwrap(), false),
+				retain: false,
+				topic_name: "firmware".to_owned(),
+				payload,
+			})),
+
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::PubAck(mqtt::proto::PubAck {
+				packet_identifier: mqtt::proto::PacketIdentifier::new(1).unwrap(),
+			})),
+		],
+	]);
+
+	let client =
+		mqtt::Client::new(
+			None,
+			None,
+			None,
+			io_source,
+			std::time::Duration::from_secs(0),
+			std::time::Duration::from_secs(4),
+		);
+
+	let mut publish_handle = client.publish_handle().expect("couldn't get publish handle");
+
+	common::verify_client_events(&mut runtime, client, vec![
+		mqtt::Event::NewConnection { reset_session: true },
+	]);
+
+	let publish_future = publish_handle.publish_stream(
+		"firmware".parse().unwrap(),
+		mqtt::proto::QoS::AtLeastOnce,
+		false,
+		futures::stream::iter_ok::<_, ()>(payload_chunks),
+	);
+	runtime.block_on(publish_future).unwrap();
+
+	runtime.block_on(done).expect("connection broken while there were still steps remaining on the server");
+}
+
+#[test]
+fn payload_streaming_threshold_receives_payload_in_chunks() {
+	let mut runtime = tokio::runtime::current_thread::Runtime::new().expect("couldn't initialize tokio runtime");
+
+	let payload: bytes::Bytes = (0..200_000_u32).map(|i| i as u8).collect::<Vec<_>>().into();
+
+	let (io_source, done) = common::IoSource::new(vec![
+		vec![
+			common::TestConnectionStep::Receives(mqtt::proto::Packet::Connect(mqtt::proto::Connect {
+				username: None,
+				password: None,
+				will: None,
+				client_id: mqtt::proto::ClientId::ServerGenerated,
+				keep_alive: std::time::Duration::from_secs(4),
+			})),
+
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck(mqtt::proto::ConnAck {
+				session_present: false,
+				return_code: mqtt::proto::ConnectReturnCode::Accepted,
+			})),
+
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::Publish(mqtt::proto::Publish {
+				packet_identifier_dup_qos: mqtt::proto::PacketIdentifierDupQoS::AtLeastOnce(mqtt::proto::PacketIdentifier::new(1).unwrap(), false),
+				retain: false,
+				topic_name: "firmware".to_owned(),
+				payload: payload.clone(),
+			})),
+
+			// Publications with short payloads are still received whole
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::Publish(mqtt::proto::Publish {
+				packet_identifier_dup_qos: mqtt::proto::PacketIdentifierDupQoS::AtMostOnce,
+				retain: false,
+				topic_name: "topic1".to_owned(),
+				payload: [0x01, 0x02, 0x03][..].into(),
+			})),
+
+			// The PUBACK is only sent once the whole payload has been received
+			common::TestConnectionStep::Receives(mqtt::proto::Packet::PubAck(mqtt::proto::PubAck {
+				packet_identifier: mqtt::proto::PacketIdentifier::new(1).unwrap(),
+			})),
+		],
+	]);
+
+	let mut client =
+		mqtt::Client::new(
+			None,
+			None,
+			None,
+			io_source,
+			std::time::Duration::from_secs(0),
+			std::time::Duration::from_secs(4),
+		);
+	client.set_payload_streaming_threshold(Some(1024));
+
+	let events: std::sync::Arc<std::sync::Mutex<Vec<mqtt::Event>>> = Default::default();
+	runtime.spawn(futures::Stream::for_each(
+		futures::Stream::map_err(client, |err| panic!("{:?}", err)),
+		{
+			let events = events.clone();
+			move |event| {
+				events.lock().unwrap().push(event);
+				Ok(())
+			}
+		},
+	));
+
+	runtime.block_on(done).expect("connection broken while there were still steps remaining on the server");
+
+	let mut events = std::mem::take(&mut *events.lock().unwrap()).into_iter();
+	assert_eq!(events.next(), Some(mqtt::Event::NewConnection { reset_session: true }));
+	assert_eq!(events.next(), Some(mqtt::Event::StreamedPublication(mqtt::StreamedPublication {
+		topic_name: "firmware".to_owned(),
+		dup: false,
+		qos: mqtt::proto::QoS::AtLeastOnce,
+		retain: false,
+		payload_len: payload.len(),
+	})));
+
+	let mut received_payload = vec![];
+	let mut chunks = 0;
+	loop {
+		match events.next() {
+			Some(mqtt::Event::StreamedPayloadChunk { chunk, remaining }) => {
+				received_payload.extend_from_slice(&chunk);
+				chunks += 1;
+				assert_eq!(remaining, payload.len() - received_payload.len());
+				if remaining == 0 {
+					break;
+				}
+			},
+			event => panic!("expected a payload chunk but got {:?}", event),
+		}
+	}
+	assert_eq!(received_payload, payload);
+	assert!(chunks > 1, "payload was received in {} chunk(s)", chunks);
+
+	assert_eq!(events.next(), Some(mqtt::Event::Publication(mqtt::ReceivedPublication {
+		topic_name: "topic1".to_owned(),
+		dup: false,
+		qos: mqtt::proto::QoS::AtMostOnce,
+		retain: false,
+		payload: [0x01, 0x02, 0x03][..].into(),
+	})));
+	assert_eq!(events.next(), None);
+}
+
+#[test]
+fn publication_handlers_receive_matching_publications() {
+	let mut runtime = tokio::runtime::current_thread::Runtime::new().expect("couldn't initialize tokio runtime");
+
+	let (io_source, done) = common::IoSource::new(vec![
+		vec![
+			common::TestConnectionStep::Receives(mqtt::proto::Packet::Connect(mqtt::proto::Connect {
+				username: None,
+				password: None,
+				will: None,
+				client_id: mqtt::proto::ClientId::ServerGenerated,
+				keep_alive: std::time::Duration::from_secs(4),
+			})),
+
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck(mqtt::proto::ConnAck {
+				session_present: false,
+				return_code: mqtt::proto::ConnectReturnCode::Accepted,
+			})),
+
+			common::TestConnectionStep::Receives(mqtt::proto::Packet::Subscribe(mqtt::proto::Subscribe {
+				packet_identifier: mqtt::proto::PacketIdentifier::new(1).unwrap(),
+				subscribe_to: vec![
+					mqtt::proto::SubscribeTo { topic_filter: "sensors/#".parse().unwrap(), qos: mqtt::proto::QoS::AtMostOnce },
+				],
+			})),
+
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::SubAck(mqtt::proto::SubAck {
+				packet_identifier: mqtt::proto::PacketIdentifier::new(1).unwrap(),
+				qos: vec![
+					mqtt::proto::SubAckQos::Success(mqtt::proto::QoS::AtMostOnce),
+				],
+			})),
+
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::Publish(mqtt::proto::Publish {
+				packet_identifier_dup_qos: mqtt::proto::PacketIdentifierDupQoS::AtMostOnce,
+				retain: false,
+				topic_name: "sensors/kitchen/temp".to_owned(),
+				payload: [0x01][..].into(),
+			})),
+
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::Publish(mqtt::proto::Publish {
+				packet_identifier_dup_qos: mqtt::proto::PacketIdentifierDupQoS::AtMostOnce,
+				retain: false,
+				topic_name: "sensors/kitchen/humidity".to_owned(),
+				payload: [0x02][..].into(),
+			})),
+
+			common::TestConnectionStep::Receives(mqtt::proto::Packet::PingReq(mqtt::proto::PingReq)),
+
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::PingResp(mqtt::proto::PingResp)),
+		],
+	]);
+
+	let mut client =
+		mqtt::Client::new(
+			None,
+			None,
+			None,
+			io_source,
+			std::time::Duration::from_secs(0),
+			std::time::Duration::from_secs(4),
+		);
+	client.subscribe(mqtt::proto::SubscribeTo { topic_filter: "sensors/#".parse().unwrap(), qos: mqtt::proto::QoS::AtMostOnce }).unwrap();
+
+	let handled: std::sync::Arc<std::sync::Mutex<Vec<String>>> = Default::default();
+	client.on("sensors/+/temp".parse().unwrap(), {
+		let handled = handled.clone();
+		move |publication| handled.lock().unwrap().push(publication.topic_name.clone())
+	});
+
+	common::verify_client_events(&mut runtime, client, vec![
+		mqtt::Event::NewConnection { reset_session: true },
+		mqtt::Event::SubscriptionUpdates(vec![
+			mqtt::SubscriptionUpdateEvent::Subscribe(mqtt::proto::SubscribeTo { topic_filter: "sensors/#".parse().unwrap(), qos: mqtt::proto::QoS::AtMostOnce }),
+		]),
+		mqtt::Event::Publication(mqtt::ReceivedPublication {
+			topic_name: "sensors/kitchen/humidity".to_owned(),
+			dup: false,
+			qos: mqtt::proto::QoS::AtMostOnce,
+			retain: false,
+			payload: [0x02][..].into(),
+		}),
+	]);
+
+	runtime.block_on(done).expect("connection broken while there were still steps remaining on the server");
+
+	assert_eq!(*handled.lock().unwrap(), vec!["sensors/kitchen/temp".to_owned()]);
+}
+
+#[test]
+fn paused_client_buffers_publications() {
+	let mut runtime = tokio::runtime::current_thread::Runtime::new().expect("couldn't initialize tokio runtime");
+
+	let (io_source, done) = common::IoSource::new(vec![
+		vec![
+			common::TestConnectionStep::Receives(mqtt::proto::Packet::Connect(mqtt::proto::Connect {
+				username: None,
+				password: None,
+				will: None,
+				client_id: mqtt::proto::ClientId::ServerGenerated,
+				keep_alive: std::time::Duration::from_secs(4),
+			})),
+
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck(mqtt::proto::ConnAck {
+				session_present: false,
+				return_code: mqtt::proto::ConnectReturnCode::Accepted,
+			})),
+
+			common::TestConnectionStep::Receives(mqtt::proto::Packet::Subscribe(mqtt::proto::Subscribe {
+				packet_identifier: mqtt::proto::PacketIdentifier::new(1).unwrap(),
+				subscribe_to: vec![
+					mqtt::proto::SubscribeTo { topic_filter: "topic1".parse().unwrap(), qos: mqtt::proto::QoS::AtMostOnce },
+				],
+			})),
+
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::SubAck(mqtt::proto::SubAck {
+				packet_identifier: mqtt::proto::PacketIdentifier::new(1).unwrap(),
+				qos: vec![
+					mqtt::proto::SubAckQos::Success(mqtt::proto::QoS::AtMostOnce),
+				],
+			})),
+
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::Publish(mqtt::proto::Publish {
+				packet_identifier_dup_qos: mqtt::proto::PacketIdentifierDupQoS::AtMostOnce,
+				retain: false,
+				topic_name: "topic1".to_owned(),
+				payload: [0x01, 0x02, 0x03][..].into(),
+			})),
+
+			common::TestConnectionStep::Receives(mqtt::proto::Packet::PingReq(mqtt::proto::PingReq)),
+
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::PingResp(mqtt::proto::PingResp)),
+		],
+	]);
+
+	let mut client =
+		mqtt::Client::new(
+			None,
+			None,
+			None,
+			io_source,
+			std::time::Duration::from_secs(0),
+			std::time::Duration::from_secs(4),
+		);
+	client.subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic1".parse().unwrap(), qos: mqtt::proto::QoS::AtMostOnce }).unwrap();
+
+	let start = std::time::Instant::now();
+
+	let pause_handle = client.pause_handle().unwrap();
+	runtime.spawn(futures::Future::map_err(pause_handle.pause(), |err| panic!("couldn't pause client: {}", err)));
+	runtime.spawn(futures::Future::and_then(
+		futures::Future::map_err(
+			tokio::timer::Delay::new(start + std::time::Duration::from_secs(1)),
+			|err| panic!("timer failed: {}", err),
+		),
+		move |()| futures::Future::map_err(pause_handle.resume(), |err| panic!("couldn't resume client: {}", err)),
+	));
+
+	let mut expected = vec![
+		mqtt::Event::NewConnection { reset_session: true },
+		mqtt::Event::SubscriptionUpdates(vec![
+			mqtt::SubscriptionUpdateEvent::Subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic1".parse().unwrap(), qos: mqtt::proto::QoS::AtMostOnce }),
+		]),
+		mqtt::Event::Publication(mqtt::ReceivedPublication {
+			topic_name: "topic1".to_owned(),
+			dup: false,
+			qos: mqtt::proto::QoS::AtMostOnce,
+			retain: false,
+			payload: [0x01, 0x02, 0x03][..].into(),
+		}),
+	].into_iter();
+	runtime.spawn(futures::Stream::for_each(
+		futures::Stream::map_err(client, |err| panic!("{:?}", err)),
+		move |event| {
+			if let mqtt::Event::Publication(_) = event {
+				assert!(start.elapsed() >= std::time::Duration::from_secs(1), "publication was delivered while the client was paused");
+			}
+
+			assert_eq!(expected.next(), Some(event));
+			Ok(())
+		},
+	));
+
+	runtime.block_on(done).expect("connection broken while there were still steps remaining on the server");
+}
+
+#[test]
+fn paused_client_drops_oldest_publication_at_limit() {
+	let mut runtime = tokio::runtime::current_thread::Runtime::new().expect("couldn't initialize tokio runtime");
+
+	let (io_source, done) = common::IoSource::new(vec![
+		vec![
+			common::TestConnectionStep::Receives(mqtt::proto::Packet::Connect(mqtt::proto::Connect {
+				username: None,
+				password: None,
+				will: None,
+				client_id: mqtt::proto::ClientId::ServerGenerated,
+				keep_alive: std::time::Duration::from_secs(4),
+			})),
+
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck(mqtt::proto::ConnAck {
+				session_present: false,
+				return_code: mqtt::proto::ConnectReturnCode::Accepted,
+			})),
+
+			common::TestConnectionStep::Receives(mqtt::proto::Packet::Subscribe(mqtt::proto::Subscribe {
+				packet_identifier: mqtt::proto::PacketIdentifier::new(1).unwrap(),
+				subscribe_to: vec![
+					mqtt::proto::SubscribeTo { topic_filter: "topic1".parse().unwrap(), qos: mqtt::proto::QoS::AtMostOnce },
+				],
+			})),
+
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::SubAck(mqtt::proto::SubAck {
+				packet_identifier: mqtt::proto::PacketIdentifier::new(1).unwrap(),
+				qos: vec![
+					mqtt::proto::SubAckQos::Success(mqtt::proto::QoS::AtMostOnce),
+				],
+			})),
+
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::Publish(mqtt::proto::Publish {
+				packet_identifier_dup_qos: mqtt::proto::PacketIdentifierDupQoS::AtMostOnce,
+				retain: false,
+				topic_name: "topic1".to_owned(),
+				payload: [0x01][..].into(),
+			})),
+
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::Publish(mqtt::proto::Publish {
+				packet_identifier_dup_qos: mqtt::proto::PacketIdentifierDupQoS::AtMostOnce,
+				retain: false,
+				topic_name: "topic1".to_owned(),
+				payload: [0x02][..].into(),
+			})),
+
+			common::TestConnectionStep::Receives(mqtt::proto::Packet::PingReq(mqtt::proto::PingReq)),
+
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::PingResp(mqtt::proto::PingResp)),
+		],
+	]);
+
+	let mut client =
+		mqtt::Client::new(
+			None,
+			None,
+			None,
+			io_source,
+			std::time::Duration::from_secs(0),
+			std::time::Duration::from_secs(4),
+		);
+	client.subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic1".parse().unwrap(), qos: mqtt::proto::QoS::AtMostOnce }).unwrap();
+	client.set_paused_publications_limit(Some((1, mqtt::OverflowPolicy::DropOldest)));
+
+	let start = std::time::Instant::now();
+
+	let pause_handle = client.pause_handle().unwrap();
+	runtime.spawn(futures::Future::map_err(pause_handle.pause(), |err| panic!("couldn't pause client: {}", err)));
+	runtime.spawn(futures::Future::and_then(
+		futures::Future::map_err(
+			tokio::timer::Delay::new(start + std::time::Duration::from_secs(1)),
+			|err| panic!("timer failed: {}", err),
+		),
+		move |()| futures::Future::map_err(pause_handle.resume(), |err| panic!("couldn't resume client: {}", err)),
+	));
+
+	common::verify_client_events(&mut runtime, client, vec![
+		mqtt::Event::NewConnection { reset_session: true },
+		mqtt::Event::SubscriptionUpdates(vec![
+			mqtt::SubscriptionUpdateEvent::Subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic1".parse().unwrap(), qos: mqtt::proto::QoS::AtMostOnce }),
+		]),
+		mqtt::Event::Publication(mqtt::ReceivedPublication {
+			topic_name: "topic1".to_owned(),
+			dup: false,
+			qos: mqtt::proto::QoS::AtMostOnce,
+			retain: false,
+			payload: [0x02][..].into(),
+		}),
+	]);
+
+	runtime.block_on(done).expect("connection broken while there were still steps remaining on the server");
+}
+
+#[test]
+fn stats_count_publications_retransmissions_and_reconnects() {
+	let mut runtime = tokio::runtime::current_thread::Runtime::new().expect("couldn't initialize tokio runtime");
+
+	let (io_source, done) = common::IoSource::new(vec![
+		vec![
+			common::TestConnectionStep::Receives(mqtt::proto::Packet::Connect(mqtt::proto::Connect {
+				username: None,
+				password: None,
+				will: None,
+				client_id: mqtt::proto::ClientId::IdWithCleanSession("client_id".to_owned()),
+				keep_alive: std::time::Duration::from_secs(4),
+			})),
+
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck(mqtt::proto::ConnAck {
+				session_present: false,
+				return_code: mqtt::proto::ConnectReturnCode::Accepted,
+			})),
+
+			common::TestConnectionStep::Receives(mqtt::proto::Packet::Publish(mqtt::proto::Publish {
+				packet_identifier_dup_qos: mqtt::proto::PacketIdentifierDupQoS::AtLeastOnce(mqtt::proto::PacketIdentifier::new(1).unwrap(), false),
+				retain: false,
+				topic_name: "topic1".to_owned(),
+				payload: [0x01, 0x02, 0x03][..].into(),
+			})),
+		],
+
+		vec![
+			common::TestConnectionStep::Receives(mqtt::proto::Packet::Connect(mqtt::proto::Connect {
+				username: None,
+				password: None,
+				will: None,
+				client_id: mqtt::proto::ClientId::IdWithExistingSession("client_id".to_owned()),
+				keep_alive: std::time::Duration::from_secs(4),
+			})),
+
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck(mqtt::proto::ConnAck {
+				session_present: true,
+				return_code: mqtt::proto::ConnectReturnCode::Accepted,
+			})),
+
+			common::TestConnectionStep::Receives(mqtt::proto::Packet::Publish(mqtt::proto::Publish {
+				packet_identifier_dup_qos: mqtt::proto::PacketIdentifierDupQoS::AtLeastOnce(mqtt::proto::PacketIdentifier::new(1).unwrap(), true),
+				retain: false,
+				topic_name: "topic1".to_owned(),
+				payload: [0x01, 0x02, 0x03][..].into(),
+			})),
+
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::PubAck(mqtt::proto::PubAck {
+				packet_identifier: mqtt::proto::PacketIdentifier::new(1).unwrap(),
+			})),
+
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::Publish(mqtt::proto::Publish {
+				packet_identifier_dup_qos: mqtt::proto::PacketIdentifierDupQoS::AtMostOnce,
+				retain: false,
+				topic_name: "topic2".to_owned(),
+				payload: [0x04, 0x05, 0x06][..].into(),
+			})),
+		],
+	]);
+
+	let mut client =
+		mqtt::Client::new(
+			Some("client_id".to_owned()),
+			None,
+			None,
+			io_source,
+			std::time::Duration::from_secs(0),
+			std::time::Duration::from_secs(4),
+		);
+	assert_eq!(client.stats(), Default::default());
+
+	let stats_handle = client.stats_handle();
+
+	let publish = client.publish(mqtt::proto::Publication {
+		topic_name: "topic1".parse().unwrap(),
+		qos: mqtt::proto::QoS::AtLeastOnce,
+		retain: false,
+		payload: [0x01, 0x02, 0x03][..].into(),
+	});
+	runtime.spawn(futures::Future::map_err(publish, |err| panic!("{:?}", err)));
+
+	common::verify_client_events(&mut runtime, client, vec![
+		mqtt::Event::NewConnection { reset_session: true },
+		mqtt::Event::NewConnection { reset_session: false },
+		mqtt::Event::Publication(mqtt::ReceivedPublication {
+			topic_name: "topic2".to_owned(),
+			dup: false,
+			qos: mqtt::proto::QoS::AtMostOnce,
+			retain: false,
+			payload: [0x04, 0x05, 0x06][..].into(),
+		}),
+	]);
+
+	runtime.block_on(done).expect("connection broken while there were still steps remaining on the server");
+
+	let stats = stats_handle.stats();
+	assert_eq!(stats.publications_sent, 1);
+	assert_eq!(stats.publications_acked, 1);
+	assert_eq!(stats.publications_received_at_most_once, 1);
+	assert_eq!(stats.publications_received_at_least_once, 0);
+	assert_eq!(stats.publications_received_exactly_once, 0);
+	assert_eq!(stats.retransmissions, 1);
+	// The server closes both connections
+	assert_eq!(stats.reconnects, 2);
+	assert_eq!(stats.publications_in_flight, 0);
+	assert_eq!(stats.publications_queued, 0);
+}
+
+#[test]
+fn metrics_sink_is_told_about_ignored_packets() {
+	#[derive(Debug, Default)]
+	struct RecordingMetricsSink {
+		packets_ignored: std::sync::Mutex<Vec<mqtt::proto::Packet>>,
+	}
+
+	impl mqtt::metrics::MetricsSink for RecordingMetricsSink {
+		fn packet_ignored(&self, packet: &mqtt::proto::Packet) {
+			self.packets_ignored.lock().unwrap().push(packet.clone());
+		}
+	}
+
+	let mut runtime = tokio::runtime::current_thread::Runtime::new().expect("couldn't initialize tokio runtime");
+
+	let (io_source, done) = common::IoSource::new(vec![
+		vec![
+			common::TestConnectionStep::Receives(mqtt::proto::Packet::Connect(mqtt::proto::Connect {
+				username: None,
+				password: None,
+				will: None,
+				client_id: mqtt::proto::ClientId::ServerGenerated,
+				keep_alive: std::time::Duration::from_secs(4),
+			})),
+
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck(mqtt::proto::ConnAck {
+				session_present: false,
+				return_code: mqtt::proto::ConnectReturnCode::Accepted,
+			})),
+
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::PubAck(mqtt::proto::PubAck {
+				packet_identifier: mqtt::proto::PacketIdentifier::new(1).unwrap(),
+			})),
+
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::PubRel(mqtt::proto::PubRel {
+				packet_identifier: mqtt::proto::PacketIdentifier::new(2).unwrap(),
+			})),
+
+			// The client still completes the server's ExactlyOnce flow
+			common::TestConnectionStep::Receives(mqtt::proto::Packet::PubComp(mqtt::proto::PubComp {
+				packet_identifier: mqtt::proto::PacketIdentifier::new(2).unwrap(),
+			})),
+		],
+	]);
+
+	let mut client =
+		mqtt::Client::new(
+			None,
+			None,
+			None,
+			io_source,
+			std::time::Duration::from_secs(0),
+			std::time::Duration::from_secs(4),
+		);
+
+	let metrics_sink: std::sync::Arc<RecordingMetricsSink> = Default::default();
+	client.set_metrics_sink(metrics_sink.clone());
+
+	common::verify_client_events(&mut runtime, client, vec![
+		mqtt::Event::NewConnection { reset_session: true },
+	]);
+
+	runtime.block_on(done).expect("connection broken while there were still steps remaining on the server");
+
+	assert_eq!(*metrics_sink.packets_ignored.lock().unwrap(), [
+		mqtt::proto::Packet::PubAck(mqtt::proto::PubAck {
+			packet_identifier: mqtt::proto::PacketIdentifier::new(1).unwrap(),
+		}),
+		mqtt::proto::Packet::PubRel(mqtt::proto::PubRel {
+			packet_identifier: mqtt::proto::PacketIdentifier::new(2).unwrap(),
+		}),
+	]);
+}
+
+#[test]
+fn paused_client_emits_slow_consumer_warning() {
+	let mut runtime = tokio::runtime::current_thread::Runtime::new().expect("couldn't initialize tokio runtime");
+
+	let (io_source, done) = common::IoSource::new(vec![
+		vec![
+			common::TestConnectionStep::Receives(mqtt::proto::Packet::Connect(mqtt::proto::Connect {
+				username: None,
+				password: None,
+				will: None,
+				client_id: mqtt::proto::ClientId::ServerGenerated,
+				keep_alive: std::time::Duration::from_secs(4),
+			})),
+
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck(mqtt::proto::ConnAck {
+				session_present: false,
+				return_code: mqtt::proto::ConnectReturnCode::Accepted,
+			})),
+
+			common::TestConnectionStep::Receives(mqtt::proto::Packet::Subscribe(mqtt::proto::Subscribe {
+				packet_identifier: mqtt::proto::PacketIdentifier::new(1).unwrap(),
+				subscribe_to: vec![
+					mqtt::proto::SubscribeTo { topic_filter: "topic1".parse().unwrap(), qos: mqtt::proto::QoS::AtMostOnce },
+				],
+			})),
+
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::SubAck(mqtt::proto::SubAck {
+				packet_identifier: mqtt::proto::PacketIdentifier::new(1).unwrap(),
+				qos: vec![
+					mqtt::proto::SubAckQos::Success(mqtt::proto::QoS::AtMostOnce),
+				],
+			})),
+
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::Publish(mqtt::proto::Publish {
+				packet_identifier_dup_qos: mqtt::proto::PacketIdentifierDupQoS::AtMostOnce,
+				retain: false,
+				topic_name: "topic1".to_owned(),
+				payload: [0x01, 0x02, 0x03][..].into(),
+			})),
+
+			common::TestConnectionStep::Receives(mqtt::proto::Packet::PingReq(mqtt::proto::PingReq)),
+
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::PingResp(mqtt::proto::PingResp)),
+		],
+	]);
+
+	let mut client =
+		mqtt::Client::new(
+			None,
+			None,
+			None,
+			io_source,
+			std::time::Duration::from_secs(0),
+			std::time::Duration::from_secs(4),
+		);
+	client.subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic1".parse().unwrap(), qos: mqtt::proto::QoS::AtMostOnce }).unwrap();
+	client.set_slow_consumer_warning(Some((1, std::time::Duration::from_millis(200))));
+
+	let start = std::time::Instant::now();
+
+	let pause_handle = client.pause_handle().unwrap();
+	runtime.spawn(futures::Future::map_err(pause_handle.pause(), |err| panic!("couldn't pause client: {}", err)));
+	runtime.spawn(futures::Future::and_then(
+		futures::Future::map_err(
+			tokio::timer::Delay::new(start + std::time::Duration::from_secs(1)),
+			|err| panic!("timer failed: {}", err),
+		),
+		move |()| futures::Future::map_err(pause_handle.resume(), |err| panic!("couldn't resume client: {}", err)),
+	));
+
+	let mut expected = vec![
+		mqtt::Event::NewConnection { reset_session: true },
+		mqtt::Event::SubscriptionUpdates(vec![
+			mqtt::SubscriptionUpdateEvent::Subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic1".parse().unwrap(), qos: mqtt::proto::QoS::AtMostOnce }),
+		]),
+		mqtt::Event::SlowConsumer { queued_events: 1 },
+		mqtt::Event::Publication(mqtt::ReceivedPublication {
+			topic_name: "topic1".to_owned(),
+			dup: false,
+			qos: mqtt::proto::QoS::AtMostOnce,
+			retain: false,
+			payload: [0x01, 0x02, 0x03][..].into(),
+		}),
+	].into_iter();
+	runtime.spawn(futures::Stream::for_each(
+		futures::Stream::map_err(client, |err| panic!("{:?}", err)),
+		move |event| {
+			if let mqtt::Event::SlowConsumer { .. } = event {
+				assert!(start.elapsed() >= std::time::Duration::from_millis(200), "slow consumer warning was emitted too early");
+				assert!(start.elapsed() < std::time::Duration::from_secs(1), "slow consumer warning was emitted after the client was resumed");
+			}
+
+			assert_eq!(expected.next(), Some(event));
+			Ok(())
+		},
+	));
+
+	runtime.block_on(done).expect("connection broken while there were still steps remaining on the server");
+}
+
+#[test]
+fn slow_polling_emits_slow_consumer_warning() {
+	let mut runtime = common::simulated_time::Runtime::new();
+
+	let (io_source, done) = common::IoSource::new(vec![
+		vec![
+			common::TestConnectionStep::Receives(mqtt::proto::Packet::Connect(mqtt::proto::Connect {
+				username: None,
+				password: None,
+				will: None,
+				client_id: mqtt::proto::ClientId::ServerGenerated,
+				keep_alive: std::time::Duration::from_secs(0),
+			})),
+
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck(mqtt::proto::ConnAck {
+				session_present: false,
+				return_code: mqtt::proto::ConnectReturnCode::Accepted,
+			})),
+
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::Publish(mqtt::proto::Publish {
+				packet_identifier_dup_qos: mqtt::proto::PacketIdentifierDupQoS::AtMostOnce,
+				retain: false,
+				topic_name: "topic1".to_owned(),
+				payload: [0x01][..].into(),
+			})),
+
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::Publish(mqtt::proto::Publish {
+				packet_identifier_dup_qos: mqtt::proto::PacketIdentifierDupQoS::AtMostOnce,
+				retain: false,
+				topic_name: "topic1".to_owned(),
+				payload: [0x02][..].into(),
+			})),
+
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::Publish(mqtt::proto::Publish {
+				packet_identifier_dup_qos: mqtt::proto::PacketIdentifierDupQoS::AtMostOnce,
+				retain: false,
+				topic_name: "topic1".to_owned(),
+				payload: [0x03][..].into(),
+			})),
+		],
+	]);
+
+	let mut client =
+		mqtt::Client::new(
+			None,
+			None,
+			None,
+			io_source,
+			std::time::Duration::from_secs(0),
+			std::time::Duration::from_secs(0),
+		);
+	client.set_slow_consumer_warning(Some((2, std::time::Duration::from_secs(1))));
+
+	// The application takes two seconds to handle each event, so the publications that were read together wait longer than a second for it
+	let events = runtime.block_on(futures::Stream::collect(futures::Stream::take(
+		futures::Stream::and_then(
+			futures::Stream::map_err(client, |err| panic!("{:?}", err)),
+			|event| futures::Future::map(
+				futures::Future::map_err(
+					tokio::timer::Delay::new(tokio::clock::now() + std::time::Duration::from_secs(2)),
+					|err| panic!("timer failed: {}", err),
+				),
+				move |()| event,
+			),
+		),
+		5,
+	))).unwrap();
+
+	assert_eq!(events, vec![
+		mqtt::Event::NewConnection { reset_session: true },
+		mqtt::Event::Publication(mqtt::ReceivedPublication {
+			topic_name: "topic1".to_owned(),
+			dup: false,
+			qos: mqtt::proto::QoS::AtMostOnce,
+			retain: false,
+			payload: [0x01][..].into(),
+		}),
+		// The other two publications, and the error of the connection that the server closed after sending them
+		mqtt::Event::SlowConsumer { queued_events: 3 },
+		mqtt::Event::Publication(mqtt::ReceivedPublication {
+			topic_name: "topic1".to_owned(),
+			dup: false,
+			qos: mqtt::proto::QoS::AtMostOnce,
+			retain: false,
+			payload: [0x02][..].into(),
+		}),
+		mqtt::Event::Publication(mqtt::ReceivedPublication {
+			topic_name: "topic1".to_owned(),
+			dup: false,
+			qos: mqtt::proto::QoS::AtMostOnce,
+			retain: false,
+			payload: [0x03][..].into(),
+		}),
+	]);
+
+	runtime.block_on(done).expect("connection broken while there were still steps remaining on the server");
+}
+
+#[test]
+fn manual_acks_are_sent_in_order_once_acked() {
+	let mut runtime = tokio::runtime::current_thread::Runtime::new().expect("couldn't initialize tokio runtime");
+
+	let (io_source, done) = common::IoSource::new(vec![
+		vec![
+			common::TestConnectionStep::Receives(mqtt::proto::Packet::Connect(mqtt::proto::Connect {
+				username: None,
+				password: None,
+				will: None,
+				client_id: mqtt::proto::ClientId::ServerGenerated,
+				keep_alive: std::time::Duration::from_secs(4),
+			})),
+
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck(mqtt::proto::ConnAck {
+				session_present: false,
+				return_code: mqtt::proto::ConnectReturnCode::Accepted,
+			})),
+
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::Publish(mqtt::proto::Publish {
+				packet_identifier_dup_qos: mqtt::proto::PacketIdentifierDupQoS::AtLeastOnce(mqtt::proto::PacketIdentifier::new(1).unwrap(), false),
+				retain: false,
+				topic_name: "topic1".to_owned(),
+				payload: [0x01][..].into(),
+			})),
+
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::Publish(mqtt::proto::Publish {
+				packet_identifier_dup_qos: mqtt::proto::PacketIdentifierDupQoS::AtLeastOnce(mqtt::proto::PacketIdentifier::new(2).unwrap(), false),
+				retain: false,
+				topic_name: "topic1".to_owned(),
+				payload: [0x02][..].into(),
+			})),
+
+			// The publications are not acked until the application acks them, after the first ping
+			common::TestConnectionStep::Receives(mqtt::proto::Packet::PingReq(mqtt::proto::PingReq)),
+
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::PingResp(mqtt::proto::PingResp)),
+
+			common::TestConnectionStep::Receives(mqtt::proto::Packet::PubAck(mqtt::proto::PubAck {
+				packet_identifier: mqtt::proto::PacketIdentifier::new(1).unwrap(),
+			})),
+
+			common::TestConnectionStep::Receives(mqtt::proto::Packet::PubAck(mqtt::proto::PubAck {
+				packet_identifier: mqtt::proto::PacketIdentifier::new(2).unwrap(),
+			})),
+		],
+	]);
+
+	let mut client =
+		mqtt::Client::new(
+			None,
+			None,
+			None,
+			io_source,
+			std::time::Duration::from_secs(0),
+			std::time::Duration::from_secs(4),
+		);
+	client.set_manual_acks(true);
+
+	let ack_handle = client.ack_handle().unwrap();
+	runtime.spawn(futures::Future::map(
+		futures::Future::map_err(
+			tokio::timer::Delay::new(std::time::Instant::now() + std::time::Duration::from_secs(3)),
+			|err| panic!("timer failed: {}", err),
+		),
+		move |()| {
+			ack_handle.ack().unwrap();
+			ack_handle.ack().unwrap();
+		},
+	));
+
+	runtime.spawn(futures::Stream::for_each(
+		futures::Stream::map_err(client, |err| panic!("{:?}", err)),
+		|_| Ok(()),
+	));
+
+	runtime.block_on(done).expect("connection broken while there were still steps remaining on the server");
 }
@@ -0,0 +1,160 @@
+mod common;
+
+fn publication(topic_name: &str) -> mqtt::ReceivedPublication {
+	mqtt::ReceivedPublication {
+		topic_name: topic_name.to_string(),
+		dup: false,
+		qos: mqtt::proto::QoS::AtMostOnce,
+		retain: false,
+		payload: topic_name.to_string().into(),
+	}
+}
+
+#[test]
+fn router_dispatches_publications_by_topic_filter() {
+	let events = futures::stream::iter_ok::<_, ()>(vec![
+		mqtt::Event::NewConnection { reset_session: true },
+		mqtt::Event::Publication(publication("sensors/kitchen/temp")),
+		mqtt::Event::Publication(publication("sensors/kitchen/humidity")),
+		mqtt::Event::Publication(publication("alerts")),
+		mqtt::Event::Publication(publication("sensors/garage/temp")),
+	]);
+
+	let mut router = mqtt::router::Router::new(events);
+	let temperatures = router.route("sensors/+/temp".to_string());
+	let sensors = router.route("sensors/#".to_string());
+	let dropped = router.route("alerts".to_string());
+	drop(dropped);
+
+	let unrouted = futures::Future::wait(futures::Stream::collect(router)).unwrap();
+	assert_eq!(unrouted, vec![
+		mqtt::Event::NewConnection { reset_session: true },
+		mqtt::Event::Publication(publication("alerts")),
+	]);
+
+	let temperatures = futures::Future::wait(futures::Stream::collect(temperatures)).unwrap();
+	assert_eq!(temperatures, vec![
+		publication("sensors/kitchen/temp"),
+		publication("sensors/garage/temp"),
+	]);
+
+	let sensors = futures::Future::wait(futures::Stream::collect(sensors)).unwrap();
+	assert_eq!(sensors, vec![
+		publication("sensors/kitchen/temp"),
+		publication("sensors/kitchen/humidity"),
+		publication("sensors/garage/temp"),
+	]);
+}
+
+#[test]
+fn subscribe_and_collect_retained() {
+	let mut runtime = tokio::runtime::current_thread::Runtime::new().expect("couldn't initialize tokio runtime");
+
+	let (io_source, done) = common::IoSource::new(vec![
+		vec![
+			common::TestConnectionStep::Receives(mqtt::proto::Packet::Connect(mqtt::proto::Connect {
+				username: None,
+				password: None,
+				will: None,
+				client_id: mqtt::proto::ClientId::ServerGenerated,
+				keep_alive: std::time::Duration::from_secs(4),
+			})),
+
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck(mqtt::proto::ConnAck {
+				session_present: false,
+				return_code: mqtt::proto::ConnectReturnCode::Accepted,
+			})),
+
+			common::TestConnectionStep::Receives(mqtt::proto::Packet::Subscribe(mqtt::proto::Subscribe {
+				packet_identifier: mqtt::proto::PacketIdentifier::new(1).unwrap(),
+				subscribe_to: vec![
+					mqtt::proto::SubscribeTo { topic_filter: "config/#".parse().unwrap(), qos: mqtt::proto::QoS::AtMostOnce },
+				],
+			})),
+
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::SubAck(mqtt::proto::SubAck {
+				packet_identifier: mqtt::proto::PacketIdentifier::new(1).unwrap(),
+				qos: vec![
+					mqtt::proto::SubAckQos::Success(mqtt::proto::QoS::AtMostOnce),
+				],
+			})),
+
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::Publish(mqtt::proto::Publish {
+				packet_identifier_dup_qos: mqtt::proto::PacketIdentifierDupQoS::AtMostOnce,
+				retain: true,
+				topic_name: "config/a".to_owned(),
+				payload: [0x01][..].into(),
+			})),
+
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::Publish(mqtt::proto::Publish {
+				packet_identifier_dup_qos: mqtt::proto::PacketIdentifierDupQoS::AtMostOnce,
+				retain: false,
+				topic_name: "config/b".to_owned(),
+				payload: [0x02][..].into(),
+			})),
+
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::Publish(mqtt::proto::Publish {
+				packet_identifier_dup_qos: mqtt::proto::PacketIdentifierDupQoS::AtMostOnce,
+				retain: true,
+				topic_name: "config/c".to_owned(),
+				payload: [0x03][..].into(),
+			})),
+
+			common::TestConnectionStep::Receives(mqtt::proto::Packet::PingReq(mqtt::proto::PingReq)),
+
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::PingResp(mqtt::proto::PingResp)),
+		],
+	]);
+
+	let client =
+		mqtt::Client::new(
+			None,
+			None,
+			None,
+			io_source,
+			std::time::Duration::from_secs(0),
+			std::time::Duration::from_secs(4),
+		);
+	let mut update_subscription_handle = client.update_subscription_handle().unwrap();
+
+	let mut router = mqtt::router::Router::new(client);
+	let retained = router.subscribe_and_collect_retained(
+		&mut update_subscription_handle,
+		mqtt::proto::SubscribeTo { topic_filter: "config/#".parse().unwrap(), qos: mqtt::proto::QoS::AtMostOnce },
+		std::time::Duration::from_millis(500),
+	);
+
+	common::verify_client_events(&mut runtime, router, vec![
+		mqtt::Event::NewConnection { reset_session: true },
+		mqtt::Event::SubscriptionUpdates(vec![
+			mqtt::SubscriptionUpdateEvent::Subscribe(mqtt::proto::SubscribeTo { topic_filter: "config/#".parse().unwrap(), qos: mqtt::proto::QoS::AtMostOnce }),
+		]),
+		mqtt::Event::Publication(mqtt::ReceivedPublication {
+			topic_name: "config/b".to_owned(),
+			dup: false,
+			qos: mqtt::proto::QoS::AtMostOnce,
+			retain: false,
+			payload: [0x02][..].into(),
+		}),
+	]);
+
+	let retained = runtime.block_on(retained).unwrap();
+	assert_eq!(retained, vec![
+		mqtt::ReceivedPublication {
+			topic_name: "config/a".to_owned(),
+			dup: false,
+			qos: mqtt::proto::QoS::AtMostOnce,
+			retain: true,
+			payload: [0x01][..].into(),
+		},
+		mqtt::ReceivedPublication {
+			topic_name: "config/c".to_owned(),
+			dup: false,
+			qos: mqtt::proto::QoS::AtMostOnce,
+			retain: true,
+			payload: [0x03][..].into(),
+		},
+	]);
+
+	runtime.block_on(done).expect("connection broken while there were still steps remaining on the server");
+}
@@ -0,0 +1,92 @@
+#![cfg(feature = "testing")]
+
+use futures::{ Future, Stream };
+
+#[test]
+fn rpc_client_calls_methods_of_rpc_server() {
+	let mut runtime = tokio::runtime::current_thread::Runtime::new().expect("couldn't initialize tokio runtime");
+
+	let broker = mqtt::testing::MockBroker::new();
+
+	let new_client = |client_id: &str| mqtt::Client::new(
+		Some(client_id.to_owned()),
+		None,
+		None,
+		broker.clone(),
+		std::time::Duration::from_secs(0),
+		std::time::Duration::from_secs(4),
+	);
+
+	let server_client = new_client("server");
+	let mut server_update_subscription_handle = server_client.update_subscription_handle().unwrap();
+	let server_publish_handle = server_client.publish_handle().unwrap();
+	let mut server_router = mqtt::router::Router::new(server_client);
+
+	let mut server = mqtt::rpc::RpcServer::new(&mut server_router, server_publish_handle, "calculator/methods", "calculator/responses").unwrap();
+	server.handle(&mut server_update_subscription_handle, "math/add", |request: mqtt::rpc::RpcRequest| {
+		assert_eq!(request.caller_id, "app");
+		let sum: u8 = request.payload.iter().sum();
+		Ok::<_, bytes::Bytes>(vec![sum].into())
+	}).unwrap();
+	server.handle(&mut server_update_subscription_handle, "divide", |request: mqtt::rpc::RpcRequest| {
+		match request.payload[..] {
+			[_, 0] => Err(bytes::Bytes::from("division by zero")),
+			[dividend, divisor] => Ok(vec![dividend / divisor].into()),
+			_ => Err(bytes::Bytes::from("expected two operands")),
+		}
+	}).unwrap();
+
+	let caller_client = new_client("caller");
+	let mut caller_update_subscription_handle = caller_client.update_subscription_handle().unwrap();
+	let caller_publish_handle = caller_client.publish_handle().unwrap();
+	let mut caller_router = mqtt::router::Router::new(caller_client);
+
+	let (mut caller, dispatcher) =
+		mqtt::rpc::RpcClient::new(
+			&mut caller_router,
+			&mut caller_update_subscription_handle,
+			caller_publish_handle,
+			"calculator/methods",
+			"calculator/responses",
+			"app",
+		).unwrap();
+
+	runtime.spawn(server.map(|()| panic!("RPC server ended")));
+	runtime.spawn(dispatcher.map(|()| panic!("RPC dispatcher ended")));
+
+	// Wait for the subscriptions to the request and response topics before making any calls
+	let mut server_subscriptions = 0;
+	let server_router = server_router.skip_while(move |event| {
+		if let mqtt::Event::SubscriptionUpdates(updates) = event {
+			server_subscriptions += updates.len();
+		}
+		Ok(server_subscriptions < 2)
+	});
+	let (_, server_router) = runtime.block_on(server_router.into_future()).map_err(|(err, _)| err).unwrap();
+	runtime.spawn(server_router.for_each(|_| Ok(())).map_err(|err| panic!("{:?}", err)));
+
+	let caller_router = caller_router.skip_while(|event| Ok(!matches!(event, mqtt::Event::SubscriptionUpdates(_))));
+	let (_, caller_router) = runtime.block_on(caller_router.into_future()).map_err(|(err, _)| err).unwrap();
+	runtime.spawn(caller_router.for_each(|_| Ok(())).map_err(|err| panic!("{:?}", err)));
+
+	// Concurrent calls get their own responses
+	let timeout = std::time::Duration::from_secs(5);
+	let calls = futures::future::join_all(vec![
+		caller.call("math/add", vec![1, 2, 3][..].into(), timeout),
+		caller.call("divide", vec![12, 4][..].into(), timeout),
+		caller.call("math/add", vec![10, 20][..].into(), timeout),
+	]);
+	let responses = runtime.block_on(calls).unwrap();
+	assert_eq!(responses, vec![bytes::Bytes::from(vec![6]), vec![3].into(), vec![30].into()]);
+
+	match runtime.block_on(caller.call("divide", vec![1, 0][..].into(), timeout)) {
+		Err(mqtt::rpc::CallError::Remote(err)) => assert_eq!(err, "division by zero"),
+		result => panic!("expected remote error but got {:?}", result),
+	}
+
+	// Nothing handles this method, so the call times out
+	match runtime.block_on(caller.call("multiply", vec![2, 3][..].into(), std::time::Duration::from_millis(100))) {
+		Err(mqtt::rpc::CallError::Timeout) => (),
+		result => panic!("expected timeout but got {:?}", result),
+	}
+}
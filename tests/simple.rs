@@ -166,3 +166,815 @@ fn client_id_can_connect_and_idle() {
 
 	runtime.block_on(done).expect("connection broken while there were still steps remaining on the server");
 }
+
+#[test]
+fn credentials_provider_is_invoked_for_each_connection() {
+	let mut runtime = tokio::runtime::current_thread::Runtime::new().expect("couldn't initialize tokio runtime");
+
+	let (io_source, done) = common::IoSource::new(vec![
+		vec![
+			common::TestConnectionStep::Receives(mqtt::proto::Packet::Connect(mqtt::proto::Connect {
+				username: Some("username1".to_string()),
+				password: Some("password1".to_string()),
+				will: None,
+				client_id: mqtt::proto::ClientId::ServerGenerated,
+				keep_alive: std::time::Duration::from_secs(4),
+			})),
+
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck(mqtt::proto::ConnAck {
+				session_present: false,
+				return_code: mqtt::proto::ConnectReturnCode::Accepted,
+			})),
+		],
+
+		vec![
+			common::TestConnectionStep::Receives(mqtt::proto::Packet::Connect(mqtt::proto::Connect {
+				username: Some("username2".to_string()),
+				password: Some("password2".to_string()),
+				will: None,
+				client_id: mqtt::proto::ClientId::ServerGenerated,
+				keep_alive: std::time::Duration::from_secs(4),
+			})),
+
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck(mqtt::proto::ConnAck {
+				session_present: false,
+				return_code: mqtt::proto::ConnectReturnCode::Accepted,
+			})),
+		],
+	]);
+
+	let mut client =
+		mqtt::Client::new(
+			None,
+			Some("ignored".to_string()),
+			None,
+			io_source,
+			std::time::Duration::from_secs(0),
+			std::time::Duration::from_secs(4),
+		);
+
+	let mut num_connections = 0;
+	client.set_credentials_provider(move || {
+		num_connections += 1;
+		futures::future::ok::<_, std::io::Error>(mqtt::Credentials {
+			username: Some(format!("username{}", num_connections)),
+			password: Some(format!("password{}", num_connections)),
+		})
+	});
+
+	common::verify_client_events(&mut runtime, client, vec![
+		mqtt::Event::NewConnection { reset_session: true },
+		mqtt::Event::NewConnection { reset_session: true },
+	]);
+
+	runtime.block_on(done).expect("connection broken while there were still steps remaining on the server");
+}
+
+#[test]
+fn connect_timeout_abandons_hung_connection() {
+	let mut runtime = tokio::runtime::current_thread::Runtime::new().expect("couldn't initialize tokio runtime");
+
+	let (mut io_source, done) = common::IoSource::new(vec![
+		vec![
+			common::TestConnectionStep::Receives(mqtt::proto::Packet::Connect(mqtt::proto::Connect {
+				username: None,
+				password: None,
+				will: None,
+				client_id: mqtt::proto::ClientId::ServerGenerated,
+				keep_alive: std::time::Duration::from_secs(4),
+			})),
+
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck(mqtt::proto::ConnAck {
+				session_present: false,
+				return_code: mqtt::proto::ConnectReturnCode::Accepted,
+			})),
+		],
+	]);
+
+	// The first connection attempt never completes
+	let mut first_attempt = true;
+	let io_source = move || -> <common::IoSource as mqtt::IoSource>::Future {
+		if first_attempt {
+			first_attempt = false;
+			Box::new(futures::future::empty())
+		}
+		else {
+			mqtt::IoSource::connect(&mut io_source)
+		}
+	};
+
+	let mut client =
+		mqtt::Client::new(
+			None,
+			None,
+			None,
+			io_source,
+			std::time::Duration::from_secs(0),
+			std::time::Duration::from_secs(4),
+		);
+	client.set_connect_timeout(Some(std::time::Duration::from_secs(1)));
+
+	common::verify_client_events(&mut runtime, client, vec![
+		mqtt::Event::NewConnection { reset_session: true },
+	]);
+
+	runtime.block_on(done).expect("connection broken while there were still steps remaining on the server");
+}
+
+#[test]
+fn pings_are_suppressed_by_other_packets() {
+	use futures::{ Future, Stream };
+
+	let mut runtime = tokio::runtime::current_thread::Runtime::new().expect("couldn't initialize tokio runtime");
+
+	let publish = mqtt::proto::Packet::Publish(mqtt::proto::Publish {
+		packet_identifier_dup_qos: mqtt::proto::PacketIdentifierDupQoS::AtMostOnce,
+		retain: false,
+		topic_name: "topic1".to_string(),
+		payload: [0x01, 0x02, 0x03][..].into(),
+	});
+
+	let (io_source, done) = common::IoSource::new(vec![
+		vec![
+			common::TestConnectionStep::Receives(mqtt::proto::Packet::Connect(mqtt::proto::Connect {
+				username: None,
+				password: None,
+				will: None,
+				client_id: mqtt::proto::ClientId::ServerGenerated,
+				keep_alive: std::time::Duration::from_secs(4),
+			})),
+
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck(mqtt::proto::ConnAck {
+				session_present: false,
+				return_code: mqtt::proto::ConnectReturnCode::Accepted,
+			})),
+
+			// The client publishes at 0.5s, 1.5s and 2.5s, so it must not ping at 2s.
+			common::TestConnectionStep::Receives(publish.clone()),
+
+			common::TestConnectionStep::Receives(publish.clone()),
+
+			common::TestConnectionStep::Receives(publish),
+
+			common::TestConnectionStep::Receives(mqtt::proto::Packet::PingReq(mqtt::proto::PingReq)),
+
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::PingResp(mqtt::proto::PingResp)),
+		],
+	]);
+
+	let client =
+		mqtt::Client::new(
+			None,
+			None,
+			None,
+			io_source,
+			std::time::Duration::from_secs(0),
+			std::time::Duration::from_secs(4),
+		);
+
+	let mut publish_handle = client.publish_handle().unwrap();
+	runtime.spawn(
+		tokio::timer::Interval::new(std::time::Instant::now() + std::time::Duration::from_millis(500), std::time::Duration::from_secs(1))
+		.take(3)
+		.map_err(|err| panic!("{:?}", err))
+		.for_each(move |_| publish_handle.publish(mqtt::proto::Publication {
+			topic_name: "topic1".parse().unwrap(),
+			qos: mqtt::proto::QoS::AtMostOnce,
+			retain: false,
+			payload: [0x01, 0x02, 0x03][..].into(),
+		}).map_err(|err| panic!("{:?}", err))));
+
+	common::verify_client_events(&mut runtime, client, vec![
+		mqtt::Event::NewConnection { reset_session: true },
+	]);
+
+	runtime.block_on(done).expect("connection broken while there were still steps remaining on the server");
+}
+
+#[test]
+fn keep_alive_change_takes_effect_on_next_connection() {
+	let mut runtime = tokio::runtime::current_thread::Runtime::new().expect("couldn't initialize tokio runtime");
+
+	let (io_source, done) = common::IoSource::new(vec![
+		vec![
+			common::TestConnectionStep::Receives(mqtt::proto::Packet::Connect(mqtt::proto::Connect {
+				username: None,
+				password: None,
+				will: None,
+				client_id: mqtt::proto::ClientId::ServerGenerated,
+				keep_alive: std::time::Duration::from_secs(10),
+			})),
+
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck(mqtt::proto::ConnAck {
+				session_present: false,
+				return_code: mqtt::proto::ConnectReturnCode::Accepted,
+			})),
+		],
+	]);
+
+	let client =
+		mqtt::Client::new(
+			None,
+			None,
+			None,
+			io_source,
+			std::time::Duration::from_secs(0),
+			std::time::Duration::from_secs(4),
+		);
+
+	// Changed before the first connection, so the first CONNECT already uses it
+	let keep_alive_handle = client.keep_alive_handle().expect("couldn't get keep-alive handle");
+	runtime.spawn(futures::Future::map_err(
+		keep_alive_handle.set_keep_alive(std::time::Duration::from_secs(10)),
+		|err| panic!("couldn't set keep-alive: {}", err)));
+
+	common::verify_client_events(&mut runtime, client, vec![
+		mqtt::Event::NewConnection { reset_session: true },
+	]);
+
+	runtime.block_on(done).expect("connection broken while there were still steps remaining on the server");
+}
+
+#[test]
+fn keep_alive_change_during_back_off_takes_effect_on_next_attempt() {
+	let mut runtime = common::simulated_time::Runtime::new();
+	let start = runtime.now();
+
+	let (mut io_source, done) = common::IoSource::new(vec![
+		vec![
+			common::TestConnectionStep::Receives(mqtt::proto::Packet::Connect(mqtt::proto::Connect {
+				username: None,
+				password: None,
+				will: None,
+				client_id: mqtt::proto::ClientId::ServerGenerated,
+				keep_alive: std::time::Duration::from_secs(10),
+			})),
+
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck(mqtt::proto::ConnAck {
+				session_present: false,
+				return_code: mqtt::proto::ConnectReturnCode::Accepted,
+			})),
+		],
+	]);
+
+	// The first two connection attempts fail, so the client backs off for a second before the third one
+	let mut failed_attempts = 0;
+	let io_source = move || -> <common::IoSource as mqtt::IoSource>::Future {
+		if failed_attempts < 2 {
+			failed_attempts += 1;
+			Box::new(futures::future::err(std::io::ErrorKind::ConnectionRefused.into()))
+		}
+		else {
+			mqtt::IoSource::connect(&mut io_source)
+		}
+	};
+
+	let client =
+		mqtt::Client::new(
+			None,
+			None,
+			None,
+			io_source,
+			std::time::Duration::from_secs(0),
+			std::time::Duration::from_secs(4),
+		);
+
+	let keep_alive_handle = client.keep_alive_handle().expect("couldn't get keep-alive handle");
+	runtime.spawn(futures::Future::map_err(
+		futures::Future::and_then(
+			futures::Future::map_err(
+				tokio::timer::Delay::new(start + std::time::Duration::from_millis(500)),
+				|err| panic!("timer failed: {}", err),
+			),
+			move |()| keep_alive_handle.set_keep_alive(std::time::Duration::from_secs(10)),
+		),
+		|err| panic!("couldn't set keep-alive: {}", err)));
+
+	runtime.spawn(common::client_events_verifier(client, vec![
+		mqtt::Event::NewConnection { reset_session: true },
+	]));
+
+	runtime.block_on(done).expect("connection broken while there were still steps remaining on the server");
+
+	assert!(runtime.now() >= start + std::time::Duration::from_secs(1));
+}
+
+#[test]
+fn keep_alive_that_does_not_fit_in_connect_packet_is_rejected() {
+	let (io_source, _) = common::IoSource::new(vec![]);
+
+	let client =
+		mqtt::Client::new(
+			None,
+			None,
+			None,
+			io_source,
+			std::time::Duration::from_secs(0),
+			std::time::Duration::from_secs(4),
+		);
+
+	let keep_alive_handle = client.keep_alive_handle().expect("couldn't get keep-alive handle");
+	match futures::Future::wait(keep_alive_handle.set_keep_alive(std::time::Duration::from_secs(u64::from(u16::MAX) + 1))) {
+		Err(mqtt::KeepAliveError::KeepAliveTooHigh(keep_alive)) => assert_eq!(keep_alive, std::time::Duration::from_secs(u64::from(u16::MAX) + 1)),
+		result => panic!("expected KeepAliveTooHigh but got {:?}", result),
+	}
+}
+
+#[test]
+fn pings_are_sent_in_simulated_time() {
+	let mut runtime = common::simulated_time::Runtime::new();
+	let start = runtime.now();
+
+	let (io_source, done) = common::IoSource::new(vec![
+		vec![
+			common::TestConnectionStep::Receives(mqtt::proto::Packet::Connect(mqtt::proto::Connect {
+				username: None,
+				password: None,
+				will: None,
+				client_id: mqtt::proto::ClientId::ServerGenerated,
+				keep_alive: std::time::Duration::from_secs(60 * 60),
+			})),
+
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck(mqtt::proto::ConnAck {
+				session_present: false,
+				return_code: mqtt::proto::ConnectReturnCode::Accepted,
+			})),
+
+			common::TestConnectionStep::Receives(mqtt::proto::Packet::PingReq(mqtt::proto::PingReq)),
+
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::PingResp(mqtt::proto::PingResp)),
+
+			common::TestConnectionStep::Receives(mqtt::proto::Packet::PingReq(mqtt::proto::PingReq)),
+
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::PingResp(mqtt::proto::PingResp)),
+		],
+	]);
+
+	let client =
+		mqtt::Client::new(
+			None,
+			None,
+			None,
+			io_source,
+			std::time::Duration::from_secs(0),
+			std::time::Duration::from_secs(60 * 60),
+		);
+
+	runtime.spawn(common::client_events_verifier(client, vec![
+		mqtt::Event::NewConnection { reset_session: true },
+	]));
+
+	runtime.block_on(done).expect("connection broken while there were still steps remaining on the server");
+
+	// Two pings at half the keep-alive each
+	assert!(runtime.now() - start >= std::time::Duration::from_secs(60 * 60));
+}
+
+#[test]
+fn ping_timeout_emits_event() {
+	let mut runtime = common::simulated_time::Runtime::new();
+
+	let (io_source, _done) = common::IoSource::new(vec![
+		vec![
+			common::TestConnectionStep::Receives(mqtt::proto::Packet::Connect(mqtt::proto::Connect {
+				username: None,
+				password: None,
+				will: None,
+				client_id: mqtt::proto::ClientId::ServerGenerated,
+				keep_alive: std::time::Duration::from_secs(4),
+			})),
+
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck(mqtt::proto::ConnAck {
+				session_present: false,
+				return_code: mqtt::proto::ConnectReturnCode::Accepted,
+			})),
+
+			common::TestConnectionStep::Receives(mqtt::proto::Packet::PingReq(mqtt::proto::PingReq)),
+
+			// The server never responds to the ping, so the client gives up on this connection before sending this.
+			common::TestConnectionStep::Receives(mqtt::proto::Packet::Disconnect(mqtt::proto::Disconnect)),
+		],
+
+		vec![
+			common::TestConnectionStep::Receives(mqtt::proto::Packet::Connect(mqtt::proto::Connect {
+				username: None,
+				password: None,
+				will: None,
+				client_id: mqtt::proto::ClientId::ServerGenerated,
+				keep_alive: std::time::Duration::from_secs(4),
+			})),
+
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck(mqtt::proto::ConnAck {
+				session_present: false,
+				return_code: mqtt::proto::ConnectReturnCode::Accepted,
+			})),
+		],
+	]);
+
+	let mut client =
+		mqtt::Client::new(
+			None,
+			None,
+			None,
+			io_source,
+			std::time::Duration::from_secs(0),
+			std::time::Duration::from_secs(4),
+		);
+	client.set_ping_response_timeout(std::time::Duration::from_secs(1));
+
+	let events = runtime.block_on(futures::Stream::collect(futures::Stream::take(client, 3))).expect("client failed");
+	assert_eq!(events, vec![
+		mqtt::Event::NewConnection { reset_session: true },
+		mqtt::Event::PingTimeout,
+		mqtt::Event::NewConnection { reset_session: true },
+	]);
+}
+
+#[test]
+fn metrics_sink_is_told_about_connections_and_packets() {
+	#[derive(Debug, Default)]
+	struct RecordingMetricsSink {
+		events: std::sync::Mutex<Vec<String>>,
+		bytes_sent: std::sync::atomic::AtomicUsize,
+		bytes_received: std::sync::atomic::AtomicUsize,
+	}
+
+	fn packet_type(packet: &mqtt::proto::Packet) -> &'static str {
+		match packet {
+			mqtt::proto::Packet::ConnAck(_) => "CONNACK",
+			mqtt::proto::Packet::Connect(_) => "CONNECT",
+			_ => "other",
+		}
+	}
+
+	impl mqtt::metrics::MetricsSink for RecordingMetricsSink {
+		fn packet_sent(&self, packet: &mqtt::proto::Packet) {
+			self.events.lock().unwrap().push(format!("sent {}", packet_type(packet)));
+		}
+
+		fn packet_received(&self, packet: &mqtt::proto::Packet) {
+			self.events.lock().unwrap().push(format!("received {}", packet_type(packet)));
+		}
+
+		fn bytes_sent(&self, len: usize) {
+			self.bytes_sent.fetch_add(len, std::sync::atomic::Ordering::SeqCst);
+		}
+
+		fn bytes_received(&self, len: usize) {
+			self.bytes_received.fetch_add(len, std::sync::atomic::Ordering::SeqCst);
+		}
+
+		fn connected(&self, reset_session: bool) {
+			self.events.lock().unwrap().push(format!("connected {}", reset_session));
+		}
+	}
+
+	let mut runtime = tokio::runtime::current_thread::Runtime::new().expect("couldn't initialize tokio runtime");
+
+	let (io_source, done) = common::IoSource::new(vec![
+		vec![
+			common::TestConnectionStep::Receives(mqtt::proto::Packet::Connect(mqtt::proto::Connect {
+				username: None,
+				password: None,
+				will: None,
+				client_id: mqtt::proto::ClientId::ServerGenerated,
+				keep_alive: std::time::Duration::from_secs(4),
+			})),
+
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck(mqtt::proto::ConnAck {
+				session_present: false,
+				return_code: mqtt::proto::ConnectReturnCode::Accepted,
+			})),
+		],
+	]);
+
+	let mut client =
+		mqtt::Client::new(
+			None,
+			None,
+			None,
+			io_source,
+			std::time::Duration::from_secs(0),
+			std::time::Duration::from_secs(4),
+		);
+
+	let metrics_sink: std::sync::Arc<RecordingMetricsSink> = Default::default();
+	client.set_metrics_sink(metrics_sink.clone());
+
+	common::verify_client_events(&mut runtime, client, vec![
+		mqtt::Event::NewConnection { reset_session: true },
+	]);
+
+	runtime.block_on(done).expect("connection broken while there were still steps remaining on the server");
+
+	assert_eq!(metrics_sink.events.lock().unwrap()[..3], ["sent CONNECT", "received CONNACK", "connected true"]);
+
+	// CONNECT with an empty client ID is 14 bytes, and CONNACK is 4 bytes
+	assert_eq!(metrics_sink.bytes_sent.load(std::sync::atomic::Ordering::SeqCst), 14);
+	assert_eq!(metrics_sink.bytes_received.load(std::sync::atomic::Ordering::SeqCst), 4);
+}
+
+#[test]
+fn packet_capture_records_packets() {
+	let mut runtime = tokio::runtime::current_thread::Runtime::new().expect("couldn't initialize tokio runtime");
+
+	let connect = mqtt::proto::Packet::Connect(mqtt::proto::Connect {
+		username: None,
+		password: None,
+		will: None,
+		client_id: mqtt::proto::ClientId::ServerGenerated,
+		keep_alive: std::time::Duration::from_secs(4),
+	});
+	let connack = mqtt::proto::Packet::ConnAck(mqtt::proto::ConnAck {
+		session_present: false,
+		return_code: mqtt::proto::ConnectReturnCode::Accepted,
+	});
+
+	let (io_source, done) = common::IoSource::new(vec![
+		vec![
+			common::TestConnectionStep::Receives(connect.clone()),
+			common::TestConnectionStep::Sends(connack.clone()),
+		],
+	]);
+
+	let mut client =
+		mqtt::Client::new(
+			None,
+			None,
+			None,
+			io_source,
+			std::time::Duration::from_secs(0),
+			std::time::Duration::from_secs(4),
+		);
+
+	let capture_path = std::env::temp_dir().join(format!("mqtt-packet-capture-{}.bin", std::process::id()));
+	client.set_packet_capture(Some(mqtt::capture::PacketCapture::create(&capture_path, mqtt::capture::CaptureFormat::Binary).unwrap()));
+
+	common::verify_client_events(&mut runtime, client, vec![
+		mqtt::Event::NewConnection { reset_session: true },
+	]);
+
+	runtime.block_on(done).expect("connection broken while there were still steps remaining on the server");
+
+	let capture = std::fs::read(&capture_path).unwrap();
+	std::fs::remove_file(&capture_path).unwrap();
+
+	let captured_packets: Vec<_> =
+		mqtt::capture::CaptureReader::new(&capture[..])
+		.map(|captured_packet| {
+			let captured_packet = captured_packet.unwrap();
+			(captured_packet.direction, captured_packet.packet)
+		})
+		.collect();
+	assert_eq!(captured_packets[..2], [
+		(mqtt::capture::Direction::Sent, connect),
+		(mqtt::capture::Direction::Received, connack),
+	]);
+}
+
+#[test]
+fn packet_tap_injects_and_intercepts_packets() {
+	let mut runtime = tokio::runtime::current_thread::Runtime::new().expect("couldn't initialize tokio runtime");
+
+	let (io_source, done) = common::IoSource::new(vec![
+		vec![
+			// The interceptor changed the keep-alive
+			common::TestConnectionStep::Receives(mqtt::proto::Packet::Connect(mqtt::proto::Connect {
+				username: None,
+				password: None,
+				will: None,
+				client_id: mqtt::proto::ClientId::ServerGenerated,
+				keep_alive: std::time::Duration::from_secs(8),
+			})),
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck(mqtt::proto::ConnAck {
+				session_present: false,
+				return_code: mqtt::proto::ConnectReturnCode::Accepted,
+			})),
+		],
+	]);
+
+	let mut client =
+		mqtt::Client::new(
+			None,
+			None,
+			None,
+			io_source,
+			std::time::Duration::from_secs(0),
+			std::time::Duration::from_secs(4),
+		);
+
+	let tap = mqtt::tap::PacketTap::new();
+	tap.set_interceptor(Some(|packet| match packet {
+		mqtt::proto::Packet::Connect(connect) => Some(mqtt::proto::Packet::Connect(mqtt::proto::Connect {
+			keep_alive: std::time::Duration::from_secs(8),
+			..connect
+		})),
+		packet => Some(packet),
+	}));
+	client.set_packet_tap(Some(tap.clone()));
+
+	let (event, client) = runtime.block_on(futures::Stream::into_future(client)).map_err(|(err, _)| err).unwrap();
+	assert_eq!(event, Some(mqtt::Event::NewConnection { reset_session: true }));
+
+	tap.inject(mqtt::proto::Packet::Publish(mqtt::proto::Publish {
+		packet_identifier_dup_qos: mqtt::proto::PacketIdentifierDupQoS::AtMostOnce,
+		retain: false,
+		topic_name: "topic1".to_owned(),
+		payload: [0x01][..].into(),
+	}));
+
+	common::verify_client_events(&mut runtime, client, vec![
+		mqtt::Event::Publication(mqtt::ReceivedPublication {
+			topic_name: "topic1".to_owned(),
+			dup: false,
+			qos: mqtt::proto::QoS::AtMostOnce,
+			retain: false,
+			payload: [0x01][..].into(),
+		}),
+	]);
+
+	runtime.block_on(done).expect("connection broken while there were still steps remaining on the server");
+}
+
+#[test]
+fn topic_prefix_is_added_to_sent_topics_and_stripped_from_received_ones() {
+	let mut runtime = tokio::runtime::current_thread::Runtime::new().expect("couldn't initialize tokio runtime");
+
+	let (io_source, done) = common::IoSource::new(vec![
+		vec![
+			common::TestConnectionStep::Receives(mqtt::proto::Packet::Connect(mqtt::proto::Connect {
+				username: None,
+				password: None,
+				will: Some(mqtt::proto::Publication {
+					topic_name: "tenant-a/status".parse().unwrap(),
+					qos: mqtt::proto::QoS::AtMostOnce,
+					retain: true,
+					payload: b"offline"[..].into(),
+				}),
+				client_id: mqtt::proto::ClientId::ServerGenerated,
+				keep_alive: std::time::Duration::from_secs(4),
+			})),
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck(mqtt::proto::ConnAck {
+				session_present: false,
+				return_code: mqtt::proto::ConnectReturnCode::Accepted,
+			})),
+
+			common::TestConnectionStep::Receives(mqtt::proto::Packet::Publish(mqtt::proto::Publish {
+				packet_identifier_dup_qos: mqtt::proto::PacketIdentifierDupQoS::AtMostOnce,
+				retain: false,
+				topic_name: "tenant-a/telemetry".to_owned(),
+				payload: [0x01][..].into(),
+			})),
+
+			// Topics that start with `$` don't get the prefix
+			common::TestConnectionStep::Receives(mqtt::proto::Packet::Subscribe(mqtt::proto::Subscribe {
+				packet_identifier: mqtt::proto::PacketIdentifier::new(1).unwrap(),
+				subscribe_to: vec![
+					mqtt::proto::SubscribeTo { topic_filter: "$SYS/broker/uptime".parse().unwrap(), qos: mqtt::proto::QoS::AtMostOnce },
+					mqtt::proto::SubscribeTo { topic_filter: "tenant-a/commands/+".parse().unwrap(), qos: mqtt::proto::QoS::AtMostOnce },
+				],
+			})),
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::SubAck(mqtt::proto::SubAck {
+				packet_identifier: mqtt::proto::PacketIdentifier::new(1).unwrap(),
+				qos: vec![
+					mqtt::proto::SubAckQos::Success(mqtt::proto::QoS::AtMostOnce),
+					mqtt::proto::SubAckQos::Success(mqtt::proto::QoS::AtMostOnce),
+				],
+			})),
+
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::Publish(mqtt::proto::Publish {
+				packet_identifier_dup_qos: mqtt::proto::PacketIdentifierDupQoS::AtMostOnce,
+				retain: false,
+				topic_name: "tenant-a/commands/reboot".to_owned(),
+				payload: [0x02][..].into(),
+			})),
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::Publish(mqtt::proto::Publish {
+				packet_identifier_dup_qos: mqtt::proto::PacketIdentifierDupQoS::AtMostOnce,
+				retain: false,
+				topic_name: "$SYS/broker/uptime".to_owned(),
+				payload: [0x03][..].into(),
+			})),
+		],
+	]);
+
+	let mut client =
+		mqtt::Client::new(
+			None,
+			None,
+			Some(mqtt::proto::Publication {
+				topic_name: "status".parse().unwrap(),
+				qos: mqtt::proto::QoS::AtMostOnce,
+				retain: true,
+				payload: b"offline"[..].into(),
+			}),
+			io_source,
+			std::time::Duration::from_secs(0),
+			std::time::Duration::from_secs(4),
+		);
+	client.set_topic_prefix(Some("tenant-a/".parse().unwrap()));
+
+	client.subscribe(mqtt::proto::SubscribeTo { topic_filter: "commands/+".parse().unwrap(), qos: mqtt::proto::QoS::AtMostOnce }).unwrap();
+	client.subscribe(mqtt::proto::SubscribeTo { topic_filter: "$SYS/broker/uptime".parse().unwrap(), qos: mqtt::proto::QoS::AtMostOnce }).unwrap();
+	client.publish_handle().unwrap().publish_without_ack(mqtt::proto::Publication {
+		topic_name: "telemetry".parse().unwrap(),
+		qos: mqtt::proto::QoS::AtMostOnce,
+		retain: false,
+		payload: [0x01][..].into(),
+	}).unwrap();
+
+	common::verify_client_events(&mut runtime, client, vec![
+		mqtt::Event::NewConnection { reset_session: true },
+		mqtt::Event::SubscriptionUpdates(vec![
+			mqtt::SubscriptionUpdateEvent::Subscribe(mqtt::proto::SubscribeTo { topic_filter: "$SYS/broker/uptime".parse().unwrap(), qos: mqtt::proto::QoS::AtMostOnce }),
+			mqtt::SubscriptionUpdateEvent::Subscribe(mqtt::proto::SubscribeTo { topic_filter: "commands/+".parse().unwrap(), qos: mqtt::proto::QoS::AtMostOnce }),
+		]),
+		mqtt::Event::Publication(mqtt::ReceivedPublication {
+			topic_name: "commands/reboot".to_owned(),
+			dup: false,
+			qos: mqtt::proto::QoS::AtMostOnce,
+			retain: false,
+			payload: [0x02][..].into(),
+		}),
+		mqtt::Event::Publication(mqtt::ReceivedPublication {
+			topic_name: "$SYS/broker/uptime".to_owned(),
+			dup: false,
+			qos: mqtt::proto::QoS::AtMostOnce,
+			retain: false,
+			payload: [0x03][..].into(),
+		}),
+	]);
+
+	runtime.block_on(done).expect("connection broken while there were still steps remaining on the server");
+}
+
+#[test]
+fn recorded_connections_can_be_replayed() {
+	let mut runtime = tokio::runtime::current_thread::Runtime::new().expect("couldn't initialize tokio runtime");
+
+	let (io_source, done) = common::IoSource::new(vec![
+		vec![
+			common::TestConnectionStep::Receives(mqtt::proto::Packet::Connect(mqtt::proto::Connect {
+				username: None,
+				password: None,
+				will: None,
+				client_id: mqtt::proto::ClientId::ServerGenerated,
+				keep_alive: std::time::Duration::from_secs(4),
+			})),
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck(mqtt::proto::ConnAck {
+				session_present: false,
+				return_code: mqtt::proto::ConnectReturnCode::Accepted,
+			})),
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::Publish(mqtt::proto::Publish {
+				packet_identifier_dup_qos: mqtt::proto::PacketIdentifierDupQoS::AtMostOnce,
+				retain: false,
+				topic_name: "topic1".to_owned(),
+				payload: [0x01][..].into(),
+			})),
+		],
+	]);
+
+	let recording_path = std::env::temp_dir().join(format!("mqtt-recording-{}.bin", std::process::id()));
+	let io_source = mqtt::replay::RecordingIoSource::create(io_source, &recording_path).unwrap();
+
+	let expected_events = || vec![
+		mqtt::Event::NewConnection { reset_session: true },
+		mqtt::Event::Publication(mqtt::ReceivedPublication {
+			topic_name: "topic1".to_owned(),
+			dup: false,
+			qos: mqtt::proto::QoS::AtMostOnce,
+			retain: false,
+			payload: [0x01][..].into(),
+		}),
+	];
+
+	let client =
+		mqtt::Client::new(
+			None,
+			None,
+			None,
+			io_source,
+			std::time::Duration::from_secs(0),
+			std::time::Duration::from_secs(4),
+		);
+	common::verify_client_events(&mut runtime, client, expected_events());
+	runtime.block_on(done).expect("connection broken while there were still steps remaining on the server");
+
+	// The replayed client sees the same events without the server
+	let io_source = mqtt::replay::ReplayIoSource::open(&recording_path).unwrap();
+	std::fs::remove_file(&recording_path).unwrap();
+
+	let client =
+		mqtt::Client::new(
+			None,
+			None,
+			None,
+			io_source,
+			std::time::Duration::from_secs(0),
+			std::time::Duration::from_secs(4),
+		);
+	let mut expected_events = expected_events().into_iter();
+	let (event, client) = runtime.block_on(futures::Stream::into_future(client)).map_err(|(err, _)| err).unwrap();
+	assert_eq!(event, expected_events.next());
+	let (event, _) = runtime.block_on(futures::Stream::into_future(client)).map_err(|(err, _)| err).unwrap();
+	assert_eq!(event, expected_events.next());
+}
@@ -22,9 +22,9 @@ fn server_generated_id_must_always_resubscribe() {
 			common::TestConnectionStep::Receives(mqtt::proto::Packet::Subscribe(mqtt::proto::Subscribe {
 				packet_identifier: mqtt::proto::PacketIdentifier::new(1).unwrap(),
 				subscribe_to: vec![
-					mqtt::proto::SubscribeTo { topic_filter: "topic1".to_string(), qos: mqtt::proto::QoS::AtMostOnce },
-					mqtt::proto::SubscribeTo { topic_filter: "topic2".to_string(), qos: mqtt::proto::QoS::AtLeastOnce },
-					mqtt::proto::SubscribeTo { topic_filter: "topic3".to_string(), qos: mqtt::proto::QoS::ExactlyOnce },
+					mqtt::proto::SubscribeTo { topic_filter: "topic1".parse().unwrap(), qos: mqtt::proto::QoS::AtMostOnce },
+					mqtt::proto::SubscribeTo { topic_filter: "topic2".parse().unwrap(), qos: mqtt::proto::QoS::AtLeastOnce },
+					mqtt::proto::SubscribeTo { topic_filter: "topic3".parse().unwrap(), qos: mqtt::proto::QoS::ExactlyOnce },
 				],
 			})),
 
@@ -59,9 +59,9 @@ fn server_generated_id_must_always_resubscribe() {
 			common::TestConnectionStep::Receives(mqtt::proto::Packet::Subscribe(mqtt::proto::Subscribe {
 				packet_identifier: mqtt::proto::PacketIdentifier::new(2).unwrap(),
 				subscribe_to: vec![
-					mqtt::proto::SubscribeTo { topic_filter: "topic1".to_string(), qos: mqtt::proto::QoS::AtMostOnce },
-					mqtt::proto::SubscribeTo { topic_filter: "topic2".to_string(), qos: mqtt::proto::QoS::AtLeastOnce },
-					mqtt::proto::SubscribeTo { topic_filter: "topic3".to_string(), qos: mqtt::proto::QoS::ExactlyOnce },
+					mqtt::proto::SubscribeTo { topic_filter: "topic1".parse().unwrap(), qos: mqtt::proto::QoS::AtMostOnce },
+					mqtt::proto::SubscribeTo { topic_filter: "topic2".parse().unwrap(), qos: mqtt::proto::QoS::AtLeastOnce },
+					mqtt::proto::SubscribeTo { topic_filter: "topic3".parse().unwrap(), qos: mqtt::proto::QoS::ExactlyOnce },
 				],
 			})),
 
@@ -89,28 +89,28 @@ fn server_generated_id_must_always_resubscribe() {
 			std::time::Duration::from_secs(0),
 			std::time::Duration::from_secs(4),
 		);
-	client.subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic1".to_string(), qos: mqtt::proto::QoS::AtMostOnce }).unwrap();
-	client.subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic2".to_string(), qos: mqtt::proto::QoS::AtLeastOnce }).unwrap();
-	client.subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic3".to_string(), qos: mqtt::proto::QoS::ExactlyOnce }).unwrap();
+	client.subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic1".parse().unwrap(), qos: mqtt::proto::QoS::AtMostOnce }).unwrap();
+	client.subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic2".parse().unwrap(), qos: mqtt::proto::QoS::AtLeastOnce }).unwrap();
+	client.subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic3".parse().unwrap(), qos: mqtt::proto::QoS::ExactlyOnce }).unwrap();
 
 	common::verify_client_events(&mut runtime, client, vec![
 		mqtt::Event::NewConnection { reset_session: true },
 		mqtt::Event::SubscriptionUpdates(vec![
-			mqtt::SubscriptionUpdateEvent::Subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic1".to_string(), qos: mqtt::proto::QoS::AtMostOnce }),
-			mqtt::SubscriptionUpdateEvent::Subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic2".to_string(), qos: mqtt::proto::QoS::AtLeastOnce }),
-			mqtt::SubscriptionUpdateEvent::Subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic3".to_string(), qos: mqtt::proto::QoS::ExactlyOnce }),
+			mqtt::SubscriptionUpdateEvent::Subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic1".parse().unwrap(), qos: mqtt::proto::QoS::AtMostOnce }),
+			mqtt::SubscriptionUpdateEvent::Subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic2".parse().unwrap(), qos: mqtt::proto::QoS::AtLeastOnce }),
+			mqtt::SubscriptionUpdateEvent::Subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic3".parse().unwrap(), qos: mqtt::proto::QoS::ExactlyOnce }),
 		]),
 		mqtt::Event::NewConnection { reset_session: true },
 		mqtt::Event::SubscriptionUpdates(vec![
-			mqtt::SubscriptionUpdateEvent::Subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic1".to_string(), qos: mqtt::proto::QoS::AtMostOnce }),
-			mqtt::SubscriptionUpdateEvent::Subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic2".to_string(), qos: mqtt::proto::QoS::AtLeastOnce }),
-			mqtt::SubscriptionUpdateEvent::Subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic3".to_string(), qos: mqtt::proto::QoS::ExactlyOnce }),
+			mqtt::SubscriptionUpdateEvent::Subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic1".parse().unwrap(), qos: mqtt::proto::QoS::AtMostOnce }),
+			mqtt::SubscriptionUpdateEvent::Subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic2".parse().unwrap(), qos: mqtt::proto::QoS::AtLeastOnce }),
+			mqtt::SubscriptionUpdateEvent::Subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic3".parse().unwrap(), qos: mqtt::proto::QoS::ExactlyOnce }),
 		]),
 		mqtt::Event::NewConnection { reset_session: false },
 		mqtt::Event::SubscriptionUpdates(vec![
-			mqtt::SubscriptionUpdateEvent::Subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic1".to_string(), qos: mqtt::proto::QoS::AtMostOnce }),
-			mqtt::SubscriptionUpdateEvent::Subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic2".to_string(), qos: mqtt::proto::QoS::AtLeastOnce }),
-			mqtt::SubscriptionUpdateEvent::Subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic3".to_string(), qos: mqtt::proto::QoS::ExactlyOnce }),
+			mqtt::SubscriptionUpdateEvent::Subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic1".parse().unwrap(), qos: mqtt::proto::QoS::AtMostOnce }),
+			mqtt::SubscriptionUpdateEvent::Subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic2".parse().unwrap(), qos: mqtt::proto::QoS::AtLeastOnce }),
+			mqtt::SubscriptionUpdateEvent::Subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic3".parse().unwrap(), qos: mqtt::proto::QoS::ExactlyOnce }),
 		]),
 	]);
 
@@ -139,9 +139,9 @@ fn client_id_should_not_resubscribe_when_session_is_present() {
 			common::TestConnectionStep::Receives(mqtt::proto::Packet::Subscribe(mqtt::proto::Subscribe {
 				packet_identifier: mqtt::proto::PacketIdentifier::new(1).unwrap(),
 				subscribe_to: vec![
-					mqtt::proto::SubscribeTo { topic_filter: "topic1".to_string(), qos: mqtt::proto::QoS::AtMostOnce },
-					mqtt::proto::SubscribeTo { topic_filter: "topic2".to_string(), qos: mqtt::proto::QoS::AtLeastOnce },
-					mqtt::proto::SubscribeTo { topic_filter: "topic3".to_string(), qos: mqtt::proto::QoS::ExactlyOnce },
+					mqtt::proto::SubscribeTo { topic_filter: "topic1".parse().unwrap(), qos: mqtt::proto::QoS::AtMostOnce },
+					mqtt::proto::SubscribeTo { topic_filter: "topic2".parse().unwrap(), qos: mqtt::proto::QoS::AtLeastOnce },
+					mqtt::proto::SubscribeTo { topic_filter: "topic3".parse().unwrap(), qos: mqtt::proto::QoS::ExactlyOnce },
 				],
 			})),
 
@@ -179,9 +179,9 @@ fn client_id_should_not_resubscribe_when_session_is_present() {
 			common::TestConnectionStep::Receives(mqtt::proto::Packet::Subscribe(mqtt::proto::Subscribe {
 				packet_identifier: mqtt::proto::PacketIdentifier::new(2).unwrap(),
 				subscribe_to: vec![
-					mqtt::proto::SubscribeTo { topic_filter: "topic1".to_string(), qos: mqtt::proto::QoS::AtMostOnce },
-					mqtt::proto::SubscribeTo { topic_filter: "topic2".to_string(), qos: mqtt::proto::QoS::AtLeastOnce },
-					mqtt::proto::SubscribeTo { topic_filter: "topic3".to_string(), qos: mqtt::proto::QoS::ExactlyOnce },
+					mqtt::proto::SubscribeTo { topic_filter: "topic1".parse().unwrap(), qos: mqtt::proto::QoS::AtMostOnce },
+					mqtt::proto::SubscribeTo { topic_filter: "topic2".parse().unwrap(), qos: mqtt::proto::QoS::AtLeastOnce },
+					mqtt::proto::SubscribeTo { topic_filter: "topic3".parse().unwrap(), qos: mqtt::proto::QoS::ExactlyOnce },
 				],
 			})),
 
@@ -228,22 +228,22 @@ fn client_id_should_not_resubscribe_when_session_is_present() {
 			std::time::Duration::from_secs(0),
 			std::time::Duration::from_secs(4),
 		);
-	client.subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic1".to_string(), qos: mqtt::proto::QoS::AtMostOnce }).unwrap();
-	client.subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic2".to_string(), qos: mqtt::proto::QoS::AtLeastOnce }).unwrap();
-	client.subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic3".to_string(), qos: mqtt::proto::QoS::ExactlyOnce }).unwrap();
+	client.subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic1".parse().unwrap(), qos: mqtt::proto::QoS::AtMostOnce }).unwrap();
+	client.subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic2".parse().unwrap(), qos: mqtt::proto::QoS::AtLeastOnce }).unwrap();
+	client.subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic3".parse().unwrap(), qos: mqtt::proto::QoS::ExactlyOnce }).unwrap();
 
 	common::verify_client_events(&mut runtime, client, vec![
 		mqtt::Event::NewConnection { reset_session: true },
 		mqtt::Event::SubscriptionUpdates(vec![
-			mqtt::SubscriptionUpdateEvent::Subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic1".to_string(), qos: mqtt::proto::QoS::AtMostOnce }),
-			mqtt::SubscriptionUpdateEvent::Subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic2".to_string(), qos: mqtt::proto::QoS::AtLeastOnce }),
-			mqtt::SubscriptionUpdateEvent::Subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic3".to_string(), qos: mqtt::proto::QoS::ExactlyOnce }),
+			mqtt::SubscriptionUpdateEvent::Subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic1".parse().unwrap(), qos: mqtt::proto::QoS::AtMostOnce }),
+			mqtt::SubscriptionUpdateEvent::Subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic2".parse().unwrap(), qos: mqtt::proto::QoS::AtLeastOnce }),
+			mqtt::SubscriptionUpdateEvent::Subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic3".parse().unwrap(), qos: mqtt::proto::QoS::ExactlyOnce }),
 		]),
 		mqtt::Event::NewConnection { reset_session: true },
 		mqtt::Event::SubscriptionUpdates(vec![
-			mqtt::SubscriptionUpdateEvent::Subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic1".to_string(), qos: mqtt::proto::QoS::AtMostOnce }),
-			mqtt::SubscriptionUpdateEvent::Subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic2".to_string(), qos: mqtt::proto::QoS::AtLeastOnce }),
-			mqtt::SubscriptionUpdateEvent::Subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic3".to_string(), qos: mqtt::proto::QoS::ExactlyOnce }),
+			mqtt::SubscriptionUpdateEvent::Subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic1".parse().unwrap(), qos: mqtt::proto::QoS::AtMostOnce }),
+			mqtt::SubscriptionUpdateEvent::Subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic2".parse().unwrap(), qos: mqtt::proto::QoS::AtLeastOnce }),
+			mqtt::SubscriptionUpdateEvent::Subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic3".parse().unwrap(), qos: mqtt::proto::QoS::ExactlyOnce }),
 		]),
 		mqtt::Event::NewConnection { reset_session: false },
 	]);
@@ -273,8 +273,8 @@ fn should_combine_pending_subscription_updates() {
 			common::TestConnectionStep::Receives(mqtt::proto::Packet::Subscribe(mqtt::proto::Subscribe {
 				packet_identifier: mqtt::proto::PacketIdentifier::new(1).unwrap(),
 				subscribe_to: vec![
-					mqtt::proto::SubscribeTo { topic_filter: "topic1".to_string(), qos: mqtt::proto::QoS::AtLeastOnce },
-					mqtt::proto::SubscribeTo { topic_filter: "topic3".to_string(), qos: mqtt::proto::QoS::ExactlyOnce },
+					mqtt::proto::SubscribeTo { topic_filter: "topic1".parse().unwrap(), qos: mqtt::proto::QoS::AtLeastOnce },
+					mqtt::proto::SubscribeTo { topic_filter: "topic3".parse().unwrap(), qos: mqtt::proto::QoS::ExactlyOnce },
 				],
 			})),
 
@@ -301,17 +301,17 @@ fn should_combine_pending_subscription_updates() {
 			std::time::Duration::from_secs(0),
 			std::time::Duration::from_secs(4),
 		);
-	client.subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic1".to_string(), qos: mqtt::proto::QoS::AtMostOnce }).unwrap();
-	client.subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic2".to_string(), qos: mqtt::proto::QoS::AtLeastOnce }).unwrap();
-	client.subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic3".to_string(), qos: mqtt::proto::QoS::ExactlyOnce }).unwrap();
-	client.subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic1".to_string(), qos: mqtt::proto::QoS::AtLeastOnce }).unwrap();
-	client.unsubscribe("topic2".to_string()).unwrap();
+	client.subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic1".parse().unwrap(), qos: mqtt::proto::QoS::AtMostOnce }).unwrap();
+	client.subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic2".parse().unwrap(), qos: mqtt::proto::QoS::AtLeastOnce }).unwrap();
+	client.subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic3".parse().unwrap(), qos: mqtt::proto::QoS::ExactlyOnce }).unwrap();
+	client.subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic1".parse().unwrap(), qos: mqtt::proto::QoS::AtLeastOnce }).unwrap();
+	client.unsubscribe("topic2".parse().unwrap()).unwrap();
 
 	common::verify_client_events(&mut runtime, client, vec![
 		mqtt::Event::NewConnection { reset_session: true },
 		mqtt::Event::SubscriptionUpdates(vec![
-			mqtt::SubscriptionUpdateEvent::Subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic1".to_string(), qos: mqtt::proto::QoS::AtLeastOnce }),
-			mqtt::SubscriptionUpdateEvent::Subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic3".to_string(), qos: mqtt::proto::QoS::ExactlyOnce }),
+			mqtt::SubscriptionUpdateEvent::Subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic1".parse().unwrap(), qos: mqtt::proto::QoS::AtLeastOnce }),
+			mqtt::SubscriptionUpdateEvent::Subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic3".parse().unwrap(), qos: mqtt::proto::QoS::ExactlyOnce }),
 		]),
 	]);
 
@@ -319,8 +319,142 @@ fn should_combine_pending_subscription_updates() {
 }
 
 #[test]
-fn should_reject_invalid_subscriptions() {
-	let (io_source, _) = common::IoSource::new(vec![]);
+fn subscribe_many_sends_single_packet() {
+	let mut runtime = tokio::runtime::current_thread::Runtime::new().expect("couldn't initialize tokio runtime");
+
+	let (io_source, done) = common::IoSource::new(vec![
+		vec![
+			common::TestConnectionStep::Receives(mqtt::proto::Packet::Connect(mqtt::proto::Connect {
+				username: None,
+				password: None,
+				will: None,
+				client_id: mqtt::proto::ClientId::ServerGenerated,
+				keep_alive: std::time::Duration::from_secs(4),
+			})),
+
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck(mqtt::proto::ConnAck {
+				session_present: false,
+				return_code: mqtt::proto::ConnectReturnCode::Accepted,
+			})),
+
+			common::TestConnectionStep::Receives(mqtt::proto::Packet::Subscribe(mqtt::proto::Subscribe {
+				packet_identifier: mqtt::proto::PacketIdentifier::new(1).unwrap(),
+				subscribe_to: vec![
+					mqtt::proto::SubscribeTo { topic_filter: "topic1".parse().unwrap(), qos: mqtt::proto::QoS::AtMostOnce },
+					mqtt::proto::SubscribeTo { topic_filter: "topic2".parse().unwrap(), qos: mqtt::proto::QoS::AtLeastOnce },
+				],
+			})),
+
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::SubAck(mqtt::proto::SubAck {
+				packet_identifier: mqtt::proto::PacketIdentifier::new(1).unwrap(),
+				qos: vec![
+					mqtt::proto::SubAckQos::Success(mqtt::proto::QoS::AtMostOnce),
+					mqtt::proto::SubAckQos::Success(mqtt::proto::QoS::AtLeastOnce),
+				],
+			})),
+
+			common::TestConnectionStep::Receives(mqtt::proto::Packet::PingReq(mqtt::proto::PingReq)),
+
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::PingResp(mqtt::proto::PingResp)),
+		],
+	]);
+
+	let client =
+		mqtt::Client::new(
+			None,
+			None,
+			None,
+			io_source,
+			std::time::Duration::from_secs(0),
+			std::time::Duration::from_secs(4),
+		);
+
+	let mut update_subscription_handle = client.update_subscription_handle().unwrap();
+	runtime.spawn(futures::Future::map_err(
+		update_subscription_handle.subscribe_many(vec![
+			mqtt::proto::SubscribeTo { topic_filter: "topic1".parse().unwrap(), qos: mqtt::proto::QoS::AtMostOnce },
+			mqtt::proto::SubscribeTo { topic_filter: "topic2".parse().unwrap(), qos: mqtt::proto::QoS::AtLeastOnce },
+		]),
+		|err| panic!("couldn't subscribe: {}", err),
+	));
+
+	common::verify_client_events(&mut runtime, client, vec![
+		mqtt::Event::NewConnection { reset_session: true },
+		mqtt::Event::SubscriptionUpdates(vec![
+			mqtt::SubscriptionUpdateEvent::Subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic1".parse().unwrap(), qos: mqtt::proto::QoS::AtMostOnce }),
+			mqtt::SubscriptionUpdateEvent::Subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic2".parse().unwrap(), qos: mqtt::proto::QoS::AtLeastOnce }),
+		]),
+	]);
+
+	runtime.block_on(done).expect("connection broken while there were still steps remaining on the server");
+}
+
+#[test]
+fn set_subscriptions_sends_difference() {
+	let mut runtime = tokio::runtime::current_thread::Runtime::new().expect("couldn't initialize tokio runtime");
+
+	let (io_source, done) = common::IoSource::new(vec![
+		vec![
+			common::TestConnectionStep::Receives(mqtt::proto::Packet::Connect(mqtt::proto::Connect {
+				username: None,
+				password: None,
+				will: None,
+				client_id: mqtt::proto::ClientId::ServerGenerated,
+				keep_alive: std::time::Duration::from_secs(4),
+			})),
+
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck(mqtt::proto::ConnAck {
+				session_present: false,
+				return_code: mqtt::proto::ConnectReturnCode::Accepted,
+			})),
+
+			common::TestConnectionStep::Receives(mqtt::proto::Packet::Subscribe(mqtt::proto::Subscribe {
+				packet_identifier: mqtt::proto::PacketIdentifier::new(1).unwrap(),
+				subscribe_to: vec![
+					mqtt::proto::SubscribeTo { topic_filter: "topic1".parse().unwrap(), qos: mqtt::proto::QoS::AtMostOnce },
+					mqtt::proto::SubscribeTo { topic_filter: "topic2".parse().unwrap(), qos: mqtt::proto::QoS::AtLeastOnce },
+				],
+			})),
+
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::SubAck(mqtt::proto::SubAck {
+				packet_identifier: mqtt::proto::PacketIdentifier::new(1).unwrap(),
+				qos: vec![
+					mqtt::proto::SubAckQos::Success(mqtt::proto::QoS::AtMostOnce),
+					mqtt::proto::SubAckQos::Success(mqtt::proto::QoS::AtLeastOnce),
+				],
+			})),
+
+			// topic2 is unchanged, so it isn't resubscribed
+			common::TestConnectionStep::Receives(mqtt::proto::Packet::Subscribe(mqtt::proto::Subscribe {
+				packet_identifier: mqtt::proto::PacketIdentifier::new(2).unwrap(),
+				subscribe_to: vec![
+					mqtt::proto::SubscribeTo { topic_filter: "topic3".parse().unwrap(), qos: mqtt::proto::QoS::ExactlyOnce },
+				],
+			})),
+
+			common::TestConnectionStep::Receives(mqtt::proto::Packet::Unsubscribe(mqtt::proto::Unsubscribe {
+				packet_identifier: mqtt::proto::PacketIdentifier::new(3).unwrap(),
+				unsubscribe_from: vec![
+					"topic1".to_string(),
+				],
+			})),
+
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::SubAck(mqtt::proto::SubAck {
+				packet_identifier: mqtt::proto::PacketIdentifier::new(2).unwrap(),
+				qos: vec![
+					mqtt::proto::SubAckQos::Success(mqtt::proto::QoS::ExactlyOnce),
+				],
+			})),
+
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::UnsubAck(mqtt::proto::UnsubAck {
+				packet_identifier: mqtt::proto::PacketIdentifier::new(3).unwrap(),
+			})),
+
+			common::TestConnectionStep::Receives(mqtt::proto::Packet::PingReq(mqtt::proto::PingReq)),
+
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::PingResp(mqtt::proto::PingResp)),
+		],
+	]);
 
 	let mut client =
 		mqtt::Client::new(
@@ -331,15 +465,169 @@ fn should_reject_invalid_subscriptions() {
 			std::time::Duration::from_secs(0),
 			std::time::Duration::from_secs(4),
 		);
+	client.subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic1".parse().unwrap(), qos: mqtt::proto::QoS::AtMostOnce }).unwrap();
+	client.subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic2".parse().unwrap(), qos: mqtt::proto::QoS::AtLeastOnce }).unwrap();
+
+	// Replace the subscriptions after the first SUBACK has been received
+	let mut update_subscription_handle = client.update_subscription_handle().unwrap();
+	runtime.spawn(
+		futures::Future::and_then(
+			futures::Future::map_err(
+				tokio::timer::Delay::new(std::time::Instant::now() + std::time::Duration::from_secs(1)),
+				|err| panic!("timer failed: {}", err),
+			),
+			move |()| futures::Future::map_err(
+				update_subscription_handle.set_subscriptions(vec![
+					mqtt::proto::SubscribeTo { topic_filter: "topic2".parse().unwrap(), qos: mqtt::proto::QoS::AtLeastOnce },
+					mqtt::proto::SubscribeTo { topic_filter: "topic3".parse().unwrap(), qos: mqtt::proto::QoS::ExactlyOnce },
+				]),
+				|err| panic!("couldn't set subscriptions: {}", err),
+			),
+		),
+	);
+
+	common::verify_client_events(&mut runtime, client, vec![
+		mqtt::Event::NewConnection { reset_session: true },
+		mqtt::Event::SubscriptionUpdates(vec![
+			mqtt::SubscriptionUpdateEvent::Subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic1".parse().unwrap(), qos: mqtt::proto::QoS::AtMostOnce }),
+			mqtt::SubscriptionUpdateEvent::Subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic2".parse().unwrap(), qos: mqtt::proto::QoS::AtLeastOnce }),
+		]),
+		mqtt::Event::SubscriptionUpdates(vec![
+			mqtt::SubscriptionUpdateEvent::Subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic3".parse().unwrap(), qos: mqtt::proto::QoS::ExactlyOnce }),
+		]),
+		mqtt::Event::SubscriptionUpdates(vec![
+			mqtt::SubscriptionUpdateEvent::Unsubscribe("topic1".parse().unwrap()),
+		]),
+	]);
+
+	runtime.block_on(done).expect("connection broken while there were still steps remaining on the server");
+}
 
+#[test]
+fn should_reject_invalid_subscriptions() {
 	let too_large_topic_filter = "a".repeat(usize::from(u16::max_value()) + 1);
 
-	match client.subscribe(mqtt::proto::SubscribeTo { topic_filter: too_large_topic_filter.clone(), qos: mqtt::proto::QoS::AtMostOnce }) {
-		Err(mqtt::UpdateSubscriptionError::EncodePacket(_, mqtt::proto::EncodeError::StringTooLarge(_))) => (),
-		result => panic!("expected client.subscribe() to fail with EncodePacket(StringTooLarge) but it returned {:?}", result),
+	match mqtt::topic::TopicFilter::new(too_large_topic_filter) {
+		Err(mqtt::topic::TopicError::TooLong(_)) => (),
+		result => panic!("expected TopicFilter::new() to fail with TooLong but it returned {:?}", result),
 	}
-	match client.unsubscribe(too_large_topic_filter.clone()) {
-		Err(mqtt::UpdateSubscriptionError::EncodePacket(_, mqtt::proto::EncodeError::StringTooLarge(_))) => (),
-		result => panic!("expected client.unsubscribe() to fail with EncodePacket(StringTooLarge) but it returned {:?}", result),
+	match "topic/#/a".parse::<mqtt::topic::TopicFilter>() {
+		Err(mqtt::topic::TopicError::InvalidWildcard(_)) => (),
+		result => panic!("expected TopicFilter::new() to fail with InvalidWildcard but it returned {:?}", result),
 	}
 }
+
+#[test]
+fn subscriptions_include_pending_updates() {
+	let (io_source, _) = common::IoSource::new(vec![]);
+
+	let mut client =
+		mqtt::Client::new(
+			None,
+			None,
+			None,
+			io_source,
+			std::time::Duration::from_secs(0),
+			std::time::Duration::from_secs(4),
+		);
+
+	assert_eq!(client.subscriptions().unwrap(), Default::default());
+
+	client.subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic1".parse().unwrap(), qos: mqtt::proto::QoS::AtMostOnce }).unwrap();
+	client.subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic2".parse().unwrap(), qos: mqtt::proto::QoS::AtLeastOnce }).unwrap();
+	client.set_subscriptions(vec![
+		mqtt::proto::SubscribeTo { topic_filter: "topic3".parse().unwrap(), qos: mqtt::proto::QoS::ExactlyOnce },
+	]).unwrap();
+
+	assert_eq!(client.subscriptions().unwrap(), mqtt::Subscriptions {
+		acked: Default::default(),
+		pending: vec![
+			mqtt::SubscriptionUpdateEvent::Subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic1".parse().unwrap(), qos: mqtt::proto::QoS::AtMostOnce }),
+			mqtt::SubscriptionUpdateEvent::Subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic2".parse().unwrap(), qos: mqtt::proto::QoS::AtLeastOnce }),
+			mqtt::SubscriptionUpdateEvent::Unsubscribe("topic1".parse().unwrap()),
+			mqtt::SubscriptionUpdateEvent::Unsubscribe("topic2".parse().unwrap()),
+			mqtt::SubscriptionUpdateEvent::Subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic3".parse().unwrap(), qos: mqtt::proto::QoS::ExactlyOnce }),
+		],
+	});
+}
+
+#[test]
+fn should_not_resubscribe_when_disabled() {
+	let mut runtime = tokio::runtime::current_thread::Runtime::new().expect("couldn't initialize tokio runtime");
+
+	let (io_source, done) = common::IoSource::new(vec![
+		vec![
+			common::TestConnectionStep::Receives(mqtt::proto::Packet::Connect(mqtt::proto::Connect {
+				username: None,
+				password: None,
+				will: None,
+				client_id: mqtt::proto::ClientId::ServerGenerated,
+				keep_alive: std::time::Duration::from_secs(4),
+			})),
+
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck(mqtt::proto::ConnAck {
+				session_present: false,
+				return_code: mqtt::proto::ConnectReturnCode::Accepted,
+			})),
+
+			common::TestConnectionStep::Receives(mqtt::proto::Packet::Subscribe(mqtt::proto::Subscribe {
+				packet_identifier: mqtt::proto::PacketIdentifier::new(1).unwrap(),
+				subscribe_to: vec![
+					mqtt::proto::SubscribeTo { topic_filter: "topic1".parse().unwrap(), qos: mqtt::proto::QoS::AtMostOnce },
+				],
+			})),
+
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::SubAck(mqtt::proto::SubAck {
+				packet_identifier: mqtt::proto::PacketIdentifier::new(1).unwrap(),
+				qos: vec![
+					mqtt::proto::SubAckQos::Success(mqtt::proto::QoS::AtMostOnce),
+				],
+			})),
+
+			common::TestConnectionStep::Receives(mqtt::proto::Packet::PingReq(mqtt::proto::PingReq)),
+
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::PingResp(mqtt::proto::PingResp)),
+		],
+
+		vec![
+			common::TestConnectionStep::Receives(mqtt::proto::Packet::Connect(mqtt::proto::Connect {
+				username: None,
+				password: None,
+				will: None,
+				client_id: mqtt::proto::ClientId::ServerGenerated,
+				keep_alive: std::time::Duration::from_secs(4),
+			})),
+
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck(mqtt::proto::ConnAck {
+				session_present: false,
+				return_code: mqtt::proto::ConnectReturnCode::Accepted,
+			})),
+
+			common::TestConnectionStep::Receives(mqtt::proto::Packet::PingReq(mqtt::proto::PingReq)),
+
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::PingResp(mqtt::proto::PingResp)),
+		],
+	]);
+
+	let mut client =
+		mqtt::Client::new(
+			None,
+			None,
+			None,
+			io_source,
+			std::time::Duration::from_secs(0),
+			std::time::Duration::from_secs(4),
+		);
+	client.set_resubscribe_on_session_reset(false);
+	client.subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic1".parse().unwrap(), qos: mqtt::proto::QoS::AtMostOnce }).unwrap();
+
+	common::verify_client_events(&mut runtime, client, vec![
+		mqtt::Event::NewConnection { reset_session: true },
+		mqtt::Event::SubscriptionUpdates(vec![
+			mqtt::SubscriptionUpdateEvent::Subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic1".parse().unwrap(), qos: mqtt::proto::QoS::AtMostOnce }),
+		]),
+		mqtt::Event::NewConnection { reset_session: true },
+	]);
+
+	runtime.block_on(done).expect("connection broken while there were still steps remaining on the server");
+}
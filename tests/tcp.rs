@@ -0,0 +1,20 @@
+#![cfg(feature = "tcp")]
+
+#[test]
+fn tcp_connector_applies_socket_options() {
+	let mut runtime = tokio::runtime::current_thread::Runtime::new().expect("couldn't initialize tokio runtime");
+
+	let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("couldn't bind listener");
+	let addr = listener.local_addr().expect("couldn't get listener address");
+
+	let mut io_source = mqtt::tcp::TcpConnector::new(addr, Some("password".to_string()));
+	io_source.set_nodelay(true);
+	io_source.set_keepalive(Some(std::time::Duration::from_secs(30)));
+
+	let (io, password) = runtime.block_on(mqtt::IoSource::connect(&mut io_source)).expect("couldn't connect");
+	let _ = listener.accept().expect("couldn't accept connection");
+
+	assert!(io.nodelay().unwrap());
+	assert_eq!(io.keepalive().unwrap(), Some(std::time::Duration::from_secs(30)));
+	assert_eq!(password, Some("password".to_string()));
+}
@@ -0,0 +1,403 @@
+#![cfg(feature = "testing")]
+
+use futures::{ Future, Stream };
+
+#[test]
+fn client_subscribes_and_publishes_through_mock_broker() {
+	let mut runtime = tokio::runtime::current_thread::Runtime::new().expect("couldn't initialize tokio runtime");
+
+	let broker = mqtt::testing::MockBroker::new();
+
+	let mut client =
+		mqtt::Client::new(
+			None,
+			None,
+			None,
+			broker.clone(),
+			std::time::Duration::from_secs(0),
+			std::time::Duration::from_secs(4),
+		);
+	client.subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic/+".parse().unwrap(), qos: mqtt::proto::QoS::AtLeastOnce }).unwrap();
+
+	let (event, client) = runtime.block_on(client.into_future()).map_err(|(err, _)| err).unwrap();
+	assert_eq!(event, Some(mqtt::Event::NewConnection { reset_session: true }));
+	assert_eq!(broker.connections(), 1);
+
+	let (event, mut client) = runtime.block_on(client.into_future()).map_err(|(err, _)| err).unwrap();
+	assert_eq!(event, Some(mqtt::Event::SubscriptionUpdates(vec![
+		mqtt::SubscriptionUpdateEvent::Subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic/+".parse().unwrap(), qos: mqtt::proto::QoS::AtLeastOnce }),
+	])));
+
+	// Not subscribed
+	broker.publish(&mqtt::proto::Publication {
+		topic_name: "other".parse().unwrap(),
+		qos: mqtt::proto::QoS::AtMostOnce,
+		retain: false,
+		payload: [0x01][..].into(),
+	});
+
+	// Downgraded to the QoS of the subscription
+	broker.publish(&mqtt::proto::Publication {
+		topic_name: "topic/1".parse().unwrap(),
+		qos: mqtt::proto::QoS::ExactlyOnce,
+		retain: false,
+		payload: [0x02][..].into(),
+	});
+
+	let publish = client.publish(mqtt::proto::Publication {
+		topic_name: "topic/2".parse().unwrap(),
+		qos: mqtt::proto::QoS::AtLeastOnce,
+		retain: false,
+		payload: [0x03][..].into(),
+	});
+
+	let (event, client) = runtime.block_on(client.into_future()).map_err(|(err, _)| err).unwrap();
+	assert_eq!(event, Some(mqtt::Event::Publication(mqtt::ReceivedPublication {
+		topic_name: "topic/1".to_owned(),
+		dup: false,
+		qos: mqtt::proto::QoS::AtLeastOnce,
+		retain: false,
+		payload: [0x02][..].into(),
+	})));
+
+	// The client's own publication is delivered back to it since it matches its subscription
+	let (event, client) = runtime.block_on(client.into_future()).map_err(|(err, _)| err).unwrap();
+	assert_eq!(event, Some(mqtt::Event::Publication(mqtt::ReceivedPublication {
+		topic_name: "topic/2".to_owned(),
+		dup: false,
+		qos: mqtt::proto::QoS::AtLeastOnce,
+		retain: false,
+		payload: [0x03][..].into(),
+	})));
+
+	runtime.spawn(client.for_each(|_| Ok(())).map_err(|err| panic!("{:?}", err)));
+	runtime.block_on(publish).unwrap();
+
+	assert_eq!(broker.published(), vec![
+		mqtt::proto::Publication {
+			topic_name: "topic/2".parse().unwrap(),
+			qos: mqtt::proto::QoS::AtLeastOnce,
+			retain: false,
+			payload: [0x03][..].into(),
+		},
+	]);
+}
+
+#[test]
+fn client_reconnects_to_mock_broker() {
+	let mut runtime = tokio::runtime::current_thread::Runtime::new().expect("couldn't initialize tokio runtime");
+
+	let broker = mqtt::testing::MockBroker::new();
+
+	let client =
+		mqtt::Client::new(
+			None,
+			None,
+			None,
+			broker.clone(),
+			std::time::Duration::from_secs(0),
+			std::time::Duration::from_secs(4),
+		);
+
+	let (event, client) = runtime.block_on(client.into_future()).map_err(|(err, _)| err).unwrap();
+	assert_eq!(event, Some(mqtt::Event::NewConnection { reset_session: true }));
+
+	broker.disconnect_all();
+	assert_eq!(broker.connections(), 0);
+
+	let (event, _client) = runtime.block_on(client.into_future()).map_err(|(err, _)| err).unwrap();
+	assert_eq!(event, Some(mqtt::Event::NewConnection { reset_session: true }));
+	assert_eq!(broker.connections(), 1);
+}
+
+#[test]
+fn simulated_client_reconnects_after_ping_timeout_and_retransmits_publication() {
+	let mut simulation = mqtt::testing::Simulation::new();
+	let start = simulation.now();
+
+	let broker = mqtt::testing::MockBroker::new();
+	broker.set_answer_pings(false);
+	broker.set_ack_publications(false);
+
+	let mut client =
+		mqtt::Client::new(
+			None,
+			None,
+			None,
+			broker.clone(),
+			std::time::Duration::from_secs(0),
+			std::time::Duration::from_secs(4),
+		);
+
+	let publish = client.publish(mqtt::proto::Publication {
+		topic_name: "topic".parse().unwrap(),
+		qos: mqtt::proto::QoS::ExactlyOnce,
+		retain: false,
+		payload: [0x01][..].into(),
+	});
+	simulation.spawn(publish.then(|_| Ok(())));
+
+	let (event, client) = simulation.block_on(client.into_future()).map_err(|(err, _)| err).unwrap();
+	assert_eq!(event, Some(mqtt::Event::NewConnection { reset_session: true }));
+
+	let (event, client) = simulation.block_on(client.into_future()).map_err(|(err, _)| err).unwrap();
+	assert_eq!(event, Some(mqtt::Event::PingTimeout));
+	assert!(simulation.now() - start >= std::time::Duration::from_secs(4));
+
+	let (event, client) = simulation.block_on(client.into_future()).map_err(|(err, _)| err).unwrap();
+	assert_eq!(event, Some(mqtt::Event::NewConnection { reset_session: true }));
+
+	simulation.spawn(client.for_each(|_| Ok(())).map_err(|err| panic!("{:?}", err)));
+	simulation.advance(std::time::Duration::from_secs(1));
+
+	// The unacked publication was sent once on each connection
+	let published = broker.published();
+	assert_eq!(published.len(), 2);
+	assert!(published.iter().all(|publication| publication.topic_name.as_str() == "topic" && publication.qos == mqtt::proto::QoS::ExactlyOnce));
+}
+
+#[test]
+fn scripted_server_exercises_reconnects() {
+	let mut simulation = mqtt::testing::Simulation::new();
+
+	let connect = mqtt::proto::Packet::Connect(mqtt::proto::Connect {
+		username: None,
+		password: None,
+		will: None,
+		client_id: mqtt::proto::ClientId::ServerGenerated,
+		keep_alive: std::time::Duration::from_secs(4),
+	});
+	let connack = mqtt::proto::Packet::ConnAck(mqtt::proto::ConnAck {
+		session_present: false,
+		return_code: mqtt::proto::ConnectReturnCode::Accepted,
+	});
+
+	let server = mqtt::testing::ScriptedServer::new(vec![
+		// A malformed packet breaks the connection
+		mqtt::testing::Script::new()
+			.receives(connect.clone())
+			.sends(connack.clone())
+			.sends_raw(&[0xF0, 0x00][..]),
+
+		// A slow CONNACK, then an abrupt disconnect
+		mqtt::testing::Script::new()
+			.receives(connect.clone())
+			.delay(std::time::Duration::from_secs(1))
+			.sends(connack.clone())
+			.reset(),
+
+		mqtt::testing::Script::new()
+			.receives(connect)
+			.sends(connack),
+	]);
+	let done = server.done();
+
+	let client =
+		mqtt::Client::new(
+			None,
+			None,
+			None,
+			server,
+			std::time::Duration::from_secs(0),
+			std::time::Duration::from_secs(4),
+		);
+
+	let start = simulation.now();
+
+	let events: std::rc::Rc<std::cell::RefCell<Vec<_>>> = Default::default();
+	simulation.spawn(client.for_each({
+		let events = events.clone();
+		move |event| {
+			events.borrow_mut().push(event);
+			Ok(())
+		}
+	}).map_err(|err| panic!("{:?}", err)));
+
+	simulation.block_on(done).unwrap();
+	assert!(simulation.now() - start >= std::time::Duration::from_secs(1));
+	assert_eq!(*events.borrow(), vec![
+		mqtt::Event::NewConnection { reset_session: true },
+		mqtt::Event::NewConnection { reset_session: true },
+		mqtt::Event::NewConnection { reset_session: true },
+	]);
+}
+
+#[test]
+fn client_publishes_through_faulty_connection() {
+	let mut simulation = mqtt::testing::Simulation::new();
+
+	let broker = mqtt::testing::MockBroker::new();
+
+	// Every packet is split, and half of them are delayed, in both directions
+	let faults = mqtt::testing::Faults::new().split(1.0).delay(0.5, std::time::Duration::from_millis(100));
+	let io_source = mqtt::testing::FaultyIoSource::with_seed(broker.clone(), faults.clone(), faults, 1);
+
+	let mut client =
+		mqtt::Client::new(
+			None,
+			None,
+			None,
+			io_source,
+			std::time::Duration::from_secs(0),
+			std::time::Duration::from_secs(4),
+		);
+	client.subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic".parse().unwrap(), qos: mqtt::proto::QoS::AtLeastOnce }).unwrap();
+
+	let (event, client) = simulation.block_on(client.into_future()).map_err(|(err, _)| err).unwrap();
+	assert_eq!(event, Some(mqtt::Event::NewConnection { reset_session: true }));
+
+	let (event, mut client) = simulation.block_on(client.into_future()).map_err(|(err, _)| err).unwrap();
+	assert_eq!(event, Some(mqtt::Event::SubscriptionUpdates(vec![
+		mqtt::SubscriptionUpdateEvent::Subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic".parse().unwrap(), qos: mqtt::proto::QoS::AtLeastOnce }),
+	])));
+
+	let publish = client.publish(mqtt::proto::Publication {
+		topic_name: "topic".parse().unwrap(),
+		qos: mqtt::proto::QoS::AtLeastOnce,
+		retain: false,
+		payload: [0x01][..].into(),
+	});
+	simulation.spawn(publish.map_err(|err| panic!("{:?}", err)));
+
+	let (event, _client) = simulation.block_on(client.into_future()).map_err(|(err, _)| err).unwrap();
+	assert_eq!(event, Some(mqtt::Event::Publication(mqtt::ReceivedPublication {
+		topic_name: "topic".to_owned(),
+		dup: false,
+		qos: mqtt::proto::QoS::AtLeastOnce,
+		retain: false,
+		payload: [0x01][..].into(),
+	})));
+	assert_eq!(broker.connections(), 1);
+}
+
+#[test]
+fn client_reconnects_when_connection_expires() {
+	let mut simulation = mqtt::testing::Simulation::new();
+	let start = simulation.now();
+
+	let broker = mqtt::testing::MockBroker::new();
+
+	let client =
+		mqtt::Client::new(
+			None,
+			None,
+			None,
+			mqtt::expiring::ExpiringIoSource::new(broker.clone(), std::time::Duration::from_secs(10)),
+			std::time::Duration::from_secs(0),
+			std::time::Duration::from_secs(4),
+		);
+
+	let (event, client) = simulation.block_on(client.into_future()).map_err(|(err, _)| err).unwrap();
+	assert_eq!(event, Some(mqtt::Event::NewConnection { reset_session: true }));
+
+	let (event, _client) = simulation.block_on(client.into_future()).map_err(|(err, _)| err).unwrap();
+	assert_eq!(event, Some(mqtt::Event::NewConnection { reset_session: true }));
+	assert!(simulation.now() - start >= std::time::Duration::from_secs(10));
+	assert_eq!(broker.connections(), 1);
+}
+
+#[test]
+fn client_writes_acks_of_packets_that_arrive_together_at_once() {
+	use tokio::codec::{ Decoder, Encoder };
+
+	/// Counts the writes to the client's end of the stream
+	struct CountingStream(mqtt::testing::DuplexStream, std::sync::Arc<std::sync::atomic::AtomicUsize>);
+
+	impl std::io::Read for CountingStream {
+		fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+			self.0.read(buf)
+		}
+	}
+
+	impl tokio::io::AsyncRead for CountingStream {
+	}
+
+	impl std::io::Write for CountingStream {
+		fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+			let _ = self.1.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+			self.0.write(buf)
+		}
+
+		fn flush(&mut self) -> std::io::Result<()> {
+			self.0.flush()
+		}
+	}
+
+	impl tokio::io::AsyncWrite for CountingStream {
+		fn shutdown(&mut self) -> futures::Poll<(), std::io::Error> {
+			self.0.shutdown()
+		}
+	}
+
+	let mut simulation = mqtt::testing::Simulation::new();
+
+	let (client_io, mut server_io) = mqtt::testing::duplex();
+	let writes: std::sync::Arc<std::sync::atomic::AtomicUsize> = Default::default();
+	let mut client_io = Some(CountingStream(client_io, writes.clone()));
+
+	let client =
+		mqtt::Client::new(
+			None,
+			None,
+			None,
+			move || -> Box<dyn Future<Item = (CountingStream, Option<String>), Error = std::io::Error>> {
+				match client_io.take() {
+					Some(client_io) => Box::new(futures::future::ok((client_io, None))),
+					None => Box::new(futures::future::empty()),
+				}
+			},
+			std::time::Duration::from_secs(0),
+			std::time::Duration::from_secs(60),
+		);
+	simulation.spawn(client.for_each(|_| Ok(())).map_err(|err| panic!("{:?}", err)));
+
+	let mut codec: mqtt::proto::PacketCodec = Default::default();
+	let mut bytes = bytes::BytesMut::new();
+	codec.encode(mqtt::proto::Packet::ConnAck(mqtt::proto::ConnAck {
+		session_present: false,
+		return_code: mqtt::proto::ConnectReturnCode::Accepted,
+	}), &mut bytes).unwrap();
+	std::io::Write::write_all(&mut server_io, &bytes).unwrap();
+	simulation.advance(std::time::Duration::from_secs(1));
+
+	let writes_before = writes.load(std::sync::atomic::Ordering::SeqCst);
+
+	let mut bytes = bytes::BytesMut::new();
+	for packet_identifier in 1..=3 {
+		codec.encode(mqtt::proto::Packet::Publish(mqtt::proto::Publish {
+			packet_identifier_dup_qos: mqtt::proto::PacketIdentifierDupQoS::AtLeastOnce(mqtt::proto::PacketIdentifier::new(packet_identifier).unwrap(), false),
+			retain: false,
+			topic_name: "topic1".to_owned(),
+			payload: [0x01][..].into(),
+		}), &mut bytes).unwrap();
+	}
+	std::io::Write::write_all(&mut server_io, &bytes).unwrap();
+	simulation.advance(std::time::Duration::from_secs(1));
+
+	assert_eq!(writes.load(std::sync::atomic::Ordering::SeqCst) - writes_before, 1);
+
+	let packets = simulation.block_on(futures::future::lazy(move || -> Result<_, std::io::Error> {
+		let mut bytes = bytes::BytesMut::new();
+		let mut buf = [0_u8; 1024];
+		loop {
+			match std::io::Read::read(&mut server_io, &mut buf) {
+				Ok(read) => bytes.extend_from_slice(&buf[..read]),
+				Err(ref err) if err.kind() == std::io::ErrorKind::WouldBlock => break,
+				Err(err) => return Err(err),
+			}
+		}
+
+		let mut packets = vec![];
+		while let Some(packet) = codec.decode(&mut bytes).unwrap() {
+			packets.push(packet);
+		}
+		Ok(packets)
+	})).unwrap();
+
+	assert!(matches!(packets[0], mqtt::proto::Packet::Connect(_)));
+	assert_eq!(packets[1..], [
+		mqtt::proto::Packet::PubAck(mqtt::proto::PubAck { packet_identifier: mqtt::proto::PacketIdentifier::new(1).unwrap() }),
+		mqtt::proto::Packet::PubAck(mqtt::proto::PubAck { packet_identifier: mqtt::proto::PacketIdentifier::new(2).unwrap() }),
+		mqtt::proto::Packet::PubAck(mqtt::proto::PubAck { packet_identifier: mqtt::proto::PacketIdentifier::new(3).unwrap() }),
+	]);
+}
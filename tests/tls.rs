@@ -0,0 +1,214 @@
+#![cfg(feature = "tls")]
+
+use futures::{ Future, Stream };
+
+#[test]
+fn client_authenticates_with_new_client_certificate_on_every_connection() {
+	let mut runtime = tokio::runtime::current_thread::Runtime::new().expect("couldn't initialize tokio runtime");
+
+	let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("couldn't bind listener");
+	let addr = listener.local_addr().expect("couldn't get listener address");
+
+	let server = std::thread::spawn(move || {
+		let config = server_config(true, vec![]);
+
+		for _ in 0..2 {
+			let (stream, _) = listener.accept().expect("couldn't accept connection");
+			let connection = rustls::ServerConnection::new(config.clone()).expect("couldn't create TLS connection");
+			let mut stream = rustls::StreamOwned::new(connection, stream);
+
+			// CONNECT
+			let mut received = vec![];
+			while received.len() < 2 || received.len() < 2 + usize::from(received[1]) {
+				let mut buf = [0_u8; 256];
+				let read = std::io::Read::read(&mut stream, &mut buf).expect("couldn't read CONNECT");
+				assert_ne!(read, 0, "connection closed before CONNECT");
+				received.extend_from_slice(&buf[..read]);
+			}
+			assert_eq!(received[0], 0x10, "expected CONNECT packet but got {:?}", received);
+			assert!(stream.conn.peer_certificates().is_some(), "client did not send a certificate");
+
+			// CONNACK, connection accepted, then close the connection so that the client reconnects
+			std::io::Write::write_all(&mut stream, &[0x20, 0x02, 0x00, 0x00]).expect("couldn't send CONNACK");
+			stream.conn.send_close_notify();
+			std::io::Write::flush(&mut stream).expect("couldn't close connection");
+		}
+	});
+
+	let client_certificates_provided = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+
+	let mut io_source =
+		mqtt::tls::TlsIoSource::new(
+			move || tokio::net::TcpStream::connect(&addr).map(|io| (io, None)),
+			"localhost",
+		).unwrap();
+	io_source.add_root_certificates(include_bytes!("certs/ca.pem")).unwrap();
+	io_source.set_client_certificate_provider({
+		let client_certificates_provided = client_certificates_provided.clone();
+		move || {
+			let _ = client_certificates_provided.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+			mqtt::tls::ClientCertificate::from_pem(include_bytes!("certs/client.pem"), include_bytes!("certs/client.key"))
+		}
+	});
+
+	let client =
+		mqtt::Client::new(
+			Some("client".to_owned()),
+			None,
+			None,
+			io_source,
+			std::time::Duration::from_secs(0),
+			std::time::Duration::from_secs(60),
+		);
+
+	let (event, client) = runtime.block_on(client.into_future()).map_err(|(err, _)| err).unwrap();
+	assert_eq!(event, Some(mqtt::Event::NewConnection { reset_session: true }));
+	assert_eq!(client_certificates_provided.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+	let (event, _client) = runtime.block_on(client.into_future()).map_err(|(err, _)| err).unwrap();
+	assert_eq!(event, Some(mqtt::Event::NewConnection { reset_session: true }));
+	assert_eq!(client_certificates_provided.load(std::sync::atomic::Ordering::SeqCst), 2);
+
+	server.join().expect("server thread panicked");
+}
+
+#[test]
+fn client_offers_alpn_protocols_and_sni_hostname() {
+	let mut runtime = tokio::runtime::current_thread::Runtime::new().expect("couldn't initialize tokio runtime");
+
+	let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("couldn't bind listener");
+	let addr = listener.local_addr().expect("couldn't get listener address");
+
+	let server = std::thread::spawn(move || {
+		let config = server_config(false, vec![b"x-amzn-mqtt-ca".to_vec(), b"mqtt".to_vec()]);
+
+		let (stream, _) = listener.accept().expect("couldn't accept connection");
+		let connection = rustls::ServerConnection::new(config).expect("couldn't create TLS connection");
+		let mut stream = rustls::StreamOwned::new(connection, stream);
+
+		let mut header = [0_u8; 2];
+		std::io::Read::read_exact(&mut stream, &mut header).expect("couldn't read CONNECT");
+		assert_eq!(header[0], 0x10, "expected CONNECT packet but got {:?}", header);
+		assert_eq!(stream.conn.alpn_protocol(), Some(&b"mqtt"[..]));
+		assert_eq!(stream.conn.server_name(), Some("gateway.example.com"));
+
+		let mut connect = vec![0; usize::from(header[1])];
+		std::io::Read::read_exact(&mut stream, &mut connect).expect("couldn't read CONNECT");
+
+		// CONNACK, connection accepted
+		std::io::Write::write_all(&mut stream, &[0x20, 0x02, 0x00, 0x00]).expect("couldn't send CONNACK");
+		stream
+	});
+
+	// The server's certificate is for localhost, not for the SNI hostname
+	let mut io_source =
+		mqtt::tls::TlsIoSource::new(
+			move || tokio::net::TcpStream::connect(&addr).map(|io| (io, None)),
+			"localhost",
+		).unwrap();
+	io_source.add_root_certificates(include_bytes!("certs/ca.pem")).unwrap();
+	io_source.set_alpn_protocols(vec![b"mqtt".to_vec()]);
+	io_source.set_sni_hostname(Some("gateway.example.com")).unwrap();
+
+	let client =
+		mqtt::Client::new(
+			Some("client".to_owned()),
+			None,
+			None,
+			io_source,
+			std::time::Duration::from_secs(0),
+			std::time::Duration::from_secs(60),
+		);
+
+	let (event, _client) = runtime.block_on(client.into_future()).map_err(|(err, _)| err).unwrap();
+	assert_eq!(event, Some(mqtt::Event::NewConnection { reset_session: true }));
+
+	let _ = server.join().expect("server thread panicked");
+}
+
+#[test]
+fn client_resumes_tls_session_when_reconnecting() {
+	let mut runtime = tokio::runtime::current_thread::Runtime::new().expect("couldn't initialize tokio runtime");
+
+	let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("couldn't bind listener");
+	let addr = listener.local_addr().expect("couldn't get listener address");
+
+	let server = std::thread::spawn(move || {
+		let config = server_config(false, vec![]);
+
+		for expected_handshake_kind in &[rustls::HandshakeKind::Full, rustls::HandshakeKind::Resumed] {
+			let (stream, _) = listener.accept().expect("couldn't accept connection");
+			let connection = rustls::ServerConnection::new(config.clone()).expect("couldn't create TLS connection");
+			let mut stream = rustls::StreamOwned::new(connection, stream);
+
+			let mut header = [0_u8; 2];
+			std::io::Read::read_exact(&mut stream, &mut header).expect("couldn't read CONNECT");
+			assert_eq!(header[0], 0x10, "expected CONNECT packet but got {:?}", header);
+			assert_eq!(stream.conn.handshake_kind(), Some(*expected_handshake_kind));
+
+			let mut connect = vec![0; usize::from(header[1])];
+			std::io::Read::read_exact(&mut stream, &mut connect).expect("couldn't read CONNECT");
+
+			// CONNACK, connection accepted, then close the connection so that the client reconnects
+			std::io::Write::write_all(&mut stream, &[0x20, 0x02, 0x00, 0x00]).expect("couldn't send CONNACK");
+			stream.conn.send_close_notify();
+			std::io::Write::flush(&mut stream).expect("couldn't close connection");
+		}
+	});
+
+	let mut io_source =
+		mqtt::tls::TlsIoSource::new(
+			move || tokio::net::TcpStream::connect(&addr).map(|io| (io, None)),
+			"localhost",
+		).unwrap();
+	io_source.add_root_certificates(include_bytes!("certs/ca.pem")).unwrap();
+
+	let client =
+		mqtt::Client::new(
+			Some("client".to_owned()),
+			None,
+			None,
+			io_source,
+			std::time::Duration::from_secs(0),
+			std::time::Duration::from_secs(60),
+		);
+
+	let (event, client) = runtime.block_on(client.into_future()).map_err(|(err, _)| err).unwrap();
+	assert_eq!(event, Some(mqtt::Event::NewConnection { reset_session: true }));
+
+	let (event, _client) = runtime.block_on(client.into_future()).map_err(|(err, _)| err).unwrap();
+	assert_eq!(event, Some(mqtt::Event::NewConnection { reset_session: true }));
+
+	server.join().expect("server thread panicked");
+}
+
+fn server_config(client_auth: bool, alpn_protocols: Vec<Vec<u8>>) -> std::sync::Arc<rustls::ServerConfig> {
+	let crypto_provider = std::sync::Arc::new(rustls::crypto::ring::default_provider());
+
+	let mut client_roots = rustls::RootCertStore::empty();
+	for certificate in rustls_pemfile::certs(&mut &include_bytes!("certs/ca.pem")[..]) {
+		client_roots.add(certificate.unwrap()).unwrap();
+	}
+	let client_certificate_verifier =
+		if client_auth {
+			rustls::server::WebPkiClientVerifier::builder_with_provider(std::sync::Arc::new(client_roots), crypto_provider.clone())
+			.build()
+			.unwrap()
+		}
+		else {
+			rustls::server::WebPkiClientVerifier::no_client_auth()
+		};
+
+	let chain = rustls_pemfile::certs(&mut &include_bytes!("certs/server.pem")[..]).collect::<Result<Vec<_>, _>>().unwrap();
+	let key = rustls_pemfile::private_key(&mut &include_bytes!("certs/server.key")[..]).unwrap().unwrap();
+
+	let mut config =
+		rustls::ServerConfig::builder_with_provider(crypto_provider)
+		.with_safe_default_protocol_versions()
+		.unwrap()
+		.with_client_cert_verifier(client_certificate_verifier)
+		.with_single_cert(chain, key)
+		.unwrap();
+	config.alpn_protocols = alpn_protocols;
+	std::sync::Arc::new(config)
+}
@@ -0,0 +1,89 @@
+#![cfg(all(feature = "testing", feature = "tower"))]
+
+use futures::{ Future, Stream };
+use tower_service::Service;
+
+fn publication(payload: &'static [u8]) -> mqtt::proto::Publication {
+	mqtt::proto::Publication {
+		topic_name: "sensors/kitchen".parse().unwrap(),
+		qos: mqtt::proto::QoS::AtLeastOnce,
+		retain: false,
+		payload: payload.into(),
+	}
+}
+
+#[test]
+fn publish_service_works_with_timeout_middleware() {
+	let mut simulation = mqtt::testing::Simulation::new();
+
+	let broker = mqtt::testing::MockBroker::new();
+
+	let client =
+		mqtt::Client::new(
+			None,
+			None,
+			None,
+			broker.clone(),
+			std::time::Duration::from_secs(0),
+			std::time::Duration::from_secs(60),
+		);
+	let publish_service = mqtt::tower::PublishService::new(client.publish_handle().unwrap());
+	let mut publish = tower_timeout::Timeout::new(publish_service, std::time::Duration::from_secs(5));
+
+	simulation.spawn(client.for_each(|_| Ok(())).map_err(|err| panic!("{:?}", err)));
+
+	simulation.block_on(futures::future::poll_fn(|| publish.poll_ready())).unwrap();
+	simulation.block_on(publish.call(publication(b"21.5"))).unwrap();
+	assert_eq!(broker.published(), vec![publication(b"21.5")]);
+
+	// The broker stops acking publications, so the middleware times out the next one
+	broker.set_ack_publications(false);
+	let start = simulation.now();
+	simulation.block_on(futures::future::poll_fn(|| publish.poll_ready())).unwrap();
+	let err = simulation.block_on(publish.call(publication(b"22.0"))).unwrap_err();
+	assert!(err.is::<tower_timeout::error::Elapsed>(), "expected timeout but got {:?}", err);
+	assert!(simulation.now() - start >= std::time::Duration::from_secs(5));
+}
+
+#[test]
+fn rpc_service_calls_methods() {
+	let mut simulation = mqtt::testing::Simulation::new();
+
+	let broker = mqtt::testing::MockBroker::new();
+
+	let client =
+		mqtt::Client::new(
+			None,
+			None,
+			None,
+			broker.clone(),
+			std::time::Duration::from_secs(0),
+			std::time::Duration::from_secs(60),
+		);
+	let mut update_subscription_handle = client.update_subscription_handle().unwrap();
+	let server_publish_handle = client.publish_handle().unwrap();
+	let caller_publish_handle = client.publish_handle().unwrap();
+	let mut router = mqtt::router::Router::new(client);
+
+	let mut server = mqtt::rpc::RpcServer::new(&mut router, server_publish_handle, "echo/methods", "echo/responses").unwrap();
+	server.handle(&mut update_subscription_handle, "echo", |request: mqtt::rpc::RpcRequest| Ok::<_, bytes::Bytes>(request.payload)).unwrap();
+
+	let (caller, dispatcher) =
+		mqtt::rpc::RpcClient::new(&mut router, &mut update_subscription_handle, caller_publish_handle, "echo/methods", "echo/responses", "app").unwrap();
+	let mut rpc = mqtt::tower::RpcService::new(caller, std::time::Duration::from_secs(5));
+
+	simulation.spawn(server.map(|()| panic!("RPC server ended")));
+	simulation.spawn(dispatcher.map(|()| panic!("RPC dispatcher ended")));
+	simulation.spawn(router.for_each(|_| Ok(())).map_err(|err| panic!("{:?}", err)));
+	simulation.advance(std::time::Duration::from_secs(1));
+
+	simulation.block_on(futures::future::poll_fn(|| rpc.poll_ready())).unwrap();
+	let response = simulation.block_on(rpc.call(mqtt::tower::RpcCall { method: "echo".to_owned(), payload: b"hello"[..].into() })).unwrap();
+	assert_eq!(response, "hello");
+
+	simulation.block_on(futures::future::poll_fn(|| rpc.poll_ready())).unwrap();
+	match simulation.block_on(rpc.call(mqtt::tower::RpcCall { method: "unknown".to_owned(), payload: b"hello"[..].into() })) {
+		Err(mqtt::rpc::CallError::Timeout) => (),
+		result => panic!("expected timeout but got {:?}", result),
+	}
+}
@@ -0,0 +1,164 @@
+#![cfg(feature = "tracing")]
+
+use futures::Future;
+
+mod common;
+
+#[test]
+fn publications_and_subscription_updates_have_spans() {
+	let mut runtime = tokio::runtime::current_thread::Runtime::new().expect("couldn't initialize tokio runtime");
+
+	let (io_source, done) = common::IoSource::new(vec![
+		vec![
+			common::TestConnectionStep::Receives(mqtt::proto::Packet::Connect(mqtt::proto::Connect {
+				username: None,
+				password: None,
+				will: None,
+				client_id: mqtt::proto::ClientId::ServerGenerated,
+				keep_alive: std::time::Duration::from_secs(4),
+			})),
+
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck(mqtt::proto::ConnAck {
+				session_present: false,
+				return_code: mqtt::proto::ConnectReturnCode::Accepted,
+			})),
+
+			common::TestConnectionStep::Receives(mqtt::proto::Packet::Publish(mqtt::proto::Publish {
+				packet_identifier_dup_qos: mqtt::proto::PacketIdentifierDupQoS::AtLeastOnce(mqtt::proto::PacketIdentifier::new(1).unwrap(), false),
+				retain: false,
+				topic_name: "topic1".to_owned(),
+				payload: [0x01, 0x02, 0x03][..].into(),
+			})),
+
+			common::TestConnectionStep::Receives(mqtt::proto::Packet::Subscribe(mqtt::proto::Subscribe {
+				packet_identifier: mqtt::proto::PacketIdentifier::new(2).unwrap(),
+				subscribe_to: vec![
+					mqtt::proto::SubscribeTo { topic_filter: "topic2".parse().unwrap(), qos: mqtt::proto::QoS::AtLeastOnce },
+				],
+			})),
+
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::PubAck(mqtt::proto::PubAck {
+				packet_identifier: mqtt::proto::PacketIdentifier::new(1).unwrap(),
+			})),
+
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::SubAck(mqtt::proto::SubAck {
+				packet_identifier: mqtt::proto::PacketIdentifier::new(2).unwrap(),
+				qos: vec![
+					mqtt::proto::SubAckQos::Success(mqtt::proto::QoS::AtLeastOnce),
+				],
+			})),
+		],
+	]);
+
+	let recorder: SpanRecorder = Default::default();
+
+	tracing::subscriber::with_default(recorder.clone(), || {
+		let mut client =
+			mqtt::Client::new(
+				None,
+				None,
+				None,
+				io_source,
+				std::time::Duration::from_secs(0),
+				std::time::Duration::from_secs(4),
+			);
+
+		let publish = client.publish(mqtt::proto::Publication {
+			topic_name: "topic1".parse().unwrap(),
+			qos: mqtt::proto::QoS::AtLeastOnce,
+			retain: false,
+			payload: [0x01, 0x02, 0x03][..].into(),
+		});
+		runtime.spawn(publish.map_err(|err| panic!("{:?}", err)));
+
+		client.subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic2".parse().unwrap(), qos: mqtt::proto::QoS::AtLeastOnce }).unwrap();
+
+		common::verify_client_events(&mut runtime, client, vec![
+			mqtt::Event::NewConnection { reset_session: true },
+			mqtt::Event::SubscriptionUpdates(vec![
+				mqtt::SubscriptionUpdateEvent::Subscribe(mqtt::proto::SubscribeTo { topic_filter: "topic2".parse().unwrap(), qos: mqtt::proto::QoS::AtLeastOnce }),
+			]),
+		]);
+
+		runtime.block_on(done).expect("connection broken while there were still steps remaining on the server");
+	});
+
+	let log = recorder.log();
+	for expected in &["new mqtt_connection", "new mqtt_publish", "close mqtt_publish", "new mqtt_subscribe", "close mqtt_subscribe"] {
+		assert!(log.iter().any(|entry| entry == expected), "{:?} not found in {:?}", expected, log);
+	}
+}
+
+/// A `tracing::Subscriber` that records when spans are created and closed
+#[derive(Clone, Debug, Default)]
+struct SpanRecorder(std::sync::Arc<std::sync::Mutex<SpanRecorderInner>>);
+
+#[derive(Debug, Default)]
+struct SpanRecorderInner {
+	next_id: u64,
+	spans: std::collections::BTreeMap<u64, (&'static str, usize)>,
+	log: Vec<String>,
+}
+
+impl SpanRecorder {
+	fn log(&self) -> Vec<String> {
+		self.0.lock().unwrap().log.clone()
+	}
+}
+
+impl tracing::Subscriber for SpanRecorder {
+	fn enabled(&self, _: &tracing::Metadata<'_>) -> bool {
+		true
+	}
+
+	fn new_span(&self, span: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+		let mut inner = self.0.lock().unwrap();
+		inner.next_id += 1;
+		let id = inner.next_id;
+		let name = span.metadata().name();
+		inner.spans.insert(id, (name, 1));
+		inner.log.push(format!("new {}", name));
+		tracing::span::Id::from_u64(id)
+	}
+
+	fn record(&self, _: &tracing::span::Id, _: &tracing::span::Record<'_>) {
+	}
+
+	fn record_follows_from(&self, _: &tracing::span::Id, _: &tracing::span::Id) {
+	}
+
+	fn event(&self, _: &tracing::Event<'_>) {
+	}
+
+	fn enter(&self, _: &tracing::span::Id) {
+	}
+
+	fn exit(&self, _: &tracing::span::Id) {
+	}
+
+	fn clone_span(&self, id: &tracing::span::Id) -> tracing::span::Id {
+		let mut inner = self.0.lock().unwrap();
+		if let Some((_, ref_count)) = inner.spans.get_mut(&id.into_u64()) {
+			*ref_count += 1;
+		}
+		id.clone()
+	}
+
+	fn try_close(&self, id: tracing::span::Id) -> bool {
+		let mut inner = self.0.lock().unwrap();
+		let name = match inner.spans.get_mut(&id.into_u64()) {
+			Some((name, ref_count)) => {
+				*ref_count -= 1;
+				if *ref_count > 0 {
+					return false;
+				}
+				*name
+			},
+			None => return false,
+		};
+
+		inner.spans.remove(&id.into_u64());
+		inner.log.push(format!("close {}", name));
+		true
+	}
+}
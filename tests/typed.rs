@@ -0,0 +1,98 @@
+#![cfg(feature = "json")]
+
+mod common;
+
+#[derive(Debug, PartialEq, serde_derive::Deserialize, serde_derive::Serialize)]
+struct Reading {
+	temperature: f64,
+}
+
+#[test]
+fn typed_publish_handle_serializes_values() {
+	let mut runtime = tokio::runtime::current_thread::Runtime::new().expect("couldn't initialize tokio runtime");
+
+	let (io_source, done) = common::IoSource::new(vec![
+		vec![
+			common::TestConnectionStep::Receives(mqtt::proto::Packet::Connect(mqtt::proto::Connect {
+				username: None,
+				password: None,
+				will: None,
+				client_id: mqtt::proto::ClientId::ServerGenerated,
+				keep_alive: std::time::Duration::from_secs(4),
+			})),
+
+			common::TestConnectionStep::Sends(mqtt::proto::Packet::ConnAck(mqtt::proto::ConnAck {
+				session_present: false,
+				return_code: mqtt::proto::ConnectReturnCode::Accepted,
+			})),
+
+			common::TestConnectionStep::Receives(mqtt::proto::Packet::Publish(mqtt::proto::Publish {
+				packet_identifier_dup_qos: mqtt::proto::PacketIdentifierDupQoS::AtMostOnce,
+				retain: true,
+				topic_name: "sensors/kitchen".to_owned(),
+				payload: br#"{"temperature":21.5}"#[..].into(),
+			})),
+		],
+	]);
+
+	let client =
+		mqtt::Client::new(
+			None,
+			None,
+			None,
+			io_source,
+			std::time::Duration::from_secs(0),
+			std::time::Duration::from_secs(4),
+		);
+
+	let mut readings =
+		mqtt::typed::TypedPublishHandle::<Reading, _>::new(
+			client.publish_handle().unwrap(),
+			"sensors/kitchen".parse().unwrap(),
+			mqtt::proto::QoS::AtMostOnce,
+			mqtt::typed::Json,
+		);
+	readings.set_retain(true);
+	readings.publish_without_ack(&Reading { temperature: 21.5 }).unwrap();
+
+	common::verify_client_events(&mut runtime, client, vec![
+		mqtt::Event::NewConnection { reset_session: true },
+	]);
+
+	runtime.block_on(done).expect("connection broken while there were still steps remaining on the server");
+}
+
+#[test]
+fn typed_stream_deserializes_payloads() {
+	let publication = |payload: &'static [u8]| mqtt::ReceivedPublication {
+		topic_name: "sensors/kitchen".to_owned(),
+		dup: false,
+		qos: mqtt::proto::QoS::AtLeastOnce,
+		retain: false,
+		payload: payload.into(),
+	};
+
+	let publications = futures::stream::iter_ok::<_, ()>(vec![
+		publication(br#"{"temperature":21.5}"#),
+		publication(b"not json"),
+		publication(br#"{"temperature":-3}"#),
+	]);
+
+	let readings = mqtt::typed::TypedStream::<_, Reading, _>::new(publications, mqtt::typed::Json);
+	let mut readings = futures::Future::wait(futures::Stream::collect(readings)).unwrap().into_iter();
+
+	assert_eq!(readings.next().unwrap().unwrap(), mqtt::typed::TypedPublication {
+		topic_name: "sensors/kitchen".to_owned(),
+		dup: false,
+		qos: mqtt::proto::QoS::AtLeastOnce,
+		retain: false,
+		value: Reading { temperature: 21.5 },
+	});
+
+	// A payload that can't be deserialized doesn't end the stream
+	let err = readings.next().unwrap().unwrap_err();
+	assert_eq!(err.publication, publication(b"not json"));
+
+	assert_eq!(readings.next().unwrap().unwrap().value, Reading { temperature: -3.0 });
+	assert!(readings.next().is_none());
+}
@@ -0,0 +1,62 @@
+#![cfg(all(unix, feature = "uds"))]
+
+use futures::Stream;
+
+#[test]
+fn client_reconnects_when_socket_file_is_replaced() {
+	let mut runtime = tokio::runtime::current_thread::Runtime::new().expect("couldn't initialize tokio runtime");
+
+	let dir = std::env::temp_dir().join(format!("mqtt-uds-test-{}", std::process::id()));
+	std::fs::create_dir_all(&dir).expect("couldn't create socket directory");
+	let path = dir.join("broker.sock");
+	let _ = std::fs::remove_file(&path);
+
+	let server = std::thread::spawn({
+		let path = path.clone();
+		move || {
+			let mut streams = vec![];
+
+			for _ in 0..2 {
+				// The socket file doesn't exist when the client first connects, and is replaced by a new one for the second connection
+				std::thread::sleep(std::time::Duration::from_millis(100));
+				let _ = std::fs::remove_file(&path);
+				let listener = std::os::unix::net::UnixListener::bind(&path).expect("couldn't bind listener");
+				let (mut stream, _) = listener.accept().expect("couldn't accept connection");
+
+				let mut header = [0_u8; 2];
+				std::io::Read::read_exact(&mut stream, &mut header).expect("couldn't read CONNECT");
+				assert_eq!(header[0], 0x10, "expected CONNECT packet but got {:?}", header);
+				let mut connect = vec![0; usize::from(header[1])];
+				std::io::Read::read_exact(&mut stream, &mut connect).expect("couldn't read CONNECT");
+
+				// CONNACK, connection accepted. The connection is left open, so the client only reconnects because the socket file is replaced.
+				std::io::Write::write_all(&mut stream, &[0x20, 0x02, 0x00, 0x00]).expect("couldn't send CONNACK");
+				streams.push(stream);
+			}
+
+			streams
+		}
+	});
+
+	let mut io_source = mqtt::uds::UdsConnector::new(&path, None);
+	io_source.set_socket_file_check_interval(Some(std::time::Duration::from_millis(50)));
+
+	let client =
+		mqtt::Client::new(
+			Some("client".to_owned()),
+			None,
+			None,
+			io_source,
+			std::time::Duration::from_millis(10),
+			std::time::Duration::from_secs(60),
+		);
+
+	let (event, client) = runtime.block_on(client.into_future()).map_err(|(err, _)| err).unwrap();
+	assert_eq!(event, Some(mqtt::Event::NewConnection { reset_session: true }));
+
+	let (event, _client) = runtime.block_on(client.into_future()).map_err(|(err, _)| err).unwrap();
+	assert_eq!(event, Some(mqtt::Event::NewConnection { reset_session: true }));
+
+	let _ = server.join().expect("server thread panicked");
+	let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,59 @@
+#![cfg(feature = "websocket")]
+
+use futures::{ Future, Stream };
+
+#[test]
+fn client_connects_over_websocket() {
+	let mut runtime = tokio::runtime::current_thread::Runtime::new().expect("couldn't initialize tokio runtime");
+
+	let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("couldn't bind listener");
+	let addr = listener.local_addr().expect("couldn't get listener address");
+
+	let server = std::thread::spawn(move || {
+		let (stream, _) = listener.accept().expect("couldn't accept connection");
+		let mut socket =
+			tungstenite::accept_hdr(stream, |request: &tungstenite::handshake::server::Request, mut response: tungstenite::handshake::server::Response| {
+				assert_eq!(request.uri().path(), "/mqtt");
+				assert_eq!(request.headers()["Sec-WebSocket-Protocol"], "mqtt");
+				let _ = response.headers_mut().insert("Sec-WebSocket-Protocol", tungstenite::http::HeaderValue::from_static("mqtt"));
+				Ok(response)
+			})
+			.expect("couldn't accept WebSocket");
+
+		// The CONNECT packet may be spread over several messages
+		let mut received = vec![];
+		while received.len() < 2 || received.len() < 2 + usize::from(received[1]) {
+			match socket.read().expect("couldn't read message") {
+				tungstenite::Message::Binary(message) => received.extend(message),
+				message => panic!("unexpected message {:?}", message),
+			}
+		}
+		assert_eq!(received[0], 0x10, "expected CONNECT packet but got {:?}", received);
+
+		// CONNACK, connection accepted
+		socket.send(tungstenite::Message::Binary(vec![0x20, 0x02, 0x00, 0x00])).expect("couldn't send CONNACK");
+		socket
+	});
+
+	let url = format!("ws://{}/mqtt", addr);
+	let io_source =
+		mqtt::websocket::WebSocketIoSource::new(
+			move || tokio::net::TcpStream::connect(&addr).map(|io| (io, None)),
+			move || mqtt::websocket::request(&url),
+		);
+
+	let client =
+		mqtt::Client::new(
+			Some("client".to_owned()),
+			None,
+			None,
+			io_source,
+			std::time::Duration::from_secs(0),
+			std::time::Duration::from_secs(60),
+		);
+
+	let (event, _client) = runtime.block_on(client.into_future()).map_err(|(err, _)| err).unwrap();
+	assert_eq!(event, Some(mqtt::Event::NewConnection { reset_session: true }));
+
+	let _ = server.join().expect("server thread panicked");
+}
@@ -0,0 +1,115 @@
+#![cfg(feature = "testing")]
+
+use futures::{ Future, Stream };
+
+fn publish(packet_identifier: u16, dup: bool, topic_name: &str, payload: &'static [u8]) -> mqtt::proto::Packet {
+	mqtt::proto::Packet::Publish(mqtt::proto::Publish {
+		packet_identifier_dup_qos: mqtt::proto::PacketIdentifierDupQoS::AtLeastOnce(mqtt::proto::PacketIdentifier::new(packet_identifier).unwrap(), dup),
+		retain: false,
+		topic_name: topic_name.to_owned(),
+		payload: payload.into(),
+	})
+}
+
+fn puback(packet_identifier: u16) -> mqtt::proto::Packet {
+	mqtt::proto::Packet::PubAck(mqtt::proto::PubAck { packet_identifier: mqtt::proto::PacketIdentifier::new(packet_identifier).unwrap() })
+}
+
+#[test]
+fn work_queue_acks_processed_publications_in_order() {
+	let mut simulation = mqtt::testing::Simulation::new();
+
+	let subscribe_to = mqtt::proto::SubscribeTo { topic_filter: "jobs/#".parse().unwrap(), qos: mqtt::proto::QoS::AtLeastOnce };
+
+	let server = mqtt::testing::ScriptedServer::new(vec![
+		mqtt::testing::Script::new()
+			.receives(mqtt::proto::Packet::Connect(mqtt::proto::Connect {
+				username: None,
+				password: None,
+				will: None,
+				client_id: mqtt::proto::ClientId::ServerGenerated,
+				keep_alive: std::time::Duration::from_secs(60),
+			}))
+			.sends(mqtt::proto::Packet::ConnAck(mqtt::proto::ConnAck {
+				session_present: false,
+				return_code: mqtt::proto::ConnectReturnCode::Accepted,
+			}))
+			.receives(mqtt::proto::Packet::Subscribe(mqtt::proto::Subscribe {
+				packet_identifier: mqtt::proto::PacketIdentifier::new(1).unwrap(),
+				subscribe_to: vec![subscribe_to.clone()],
+			}))
+			.sends(mqtt::proto::Packet::SubAck(mqtt::proto::SubAck {
+				packet_identifier: mqtt::proto::PacketIdentifier::new(1).unwrap(),
+				qos: vec![mqtt::proto::SubAckQos::Success(mqtt::proto::QoS::AtLeastOnce)],
+			}))
+			.sends(publish(1, false, "jobs/a", b"slow"))
+			.sends(publish(2, false, "jobs/b", b"fast"))
+			.sends(publish(3, false, "jobs/c", b"fail"))
+			// Delivered again while the first one is still being processed
+			.sends(publish(4, true, "jobs/a", b"slow"))
+			// Nothing is acked until the first publication has been processed, then everything is acked in order
+			.receives(puback(1))
+			.receives(puback(2))
+			.receives(puback(3))
+			.receives(puback(4)),
+	]);
+	let done = server.done();
+
+	let client =
+		mqtt::Client::new(
+			None,
+			None,
+			None,
+			server,
+			std::time::Duration::from_secs(0),
+			std::time::Duration::from_secs(60),
+		);
+
+	let handled: std::rc::Rc<std::cell::RefCell<Vec<String>>> = Default::default();
+	let mut work_queue = mqtt::work_queue::WorkQueue::new(client, subscribe_to, {
+		let handled = handled.clone();
+		move |publication: mqtt::ReceivedPublication| -> Box<dyn Future<Item = (), Error = String>> {
+			handled.borrow_mut().push(publication.topic_name);
+			match &*publication.payload {
+				b"slow" => Box::new(
+					tokio::timer::Delay::new(tokio::clock::now() + std::time::Duration::from_secs(5))
+					.map_err(|err| err.to_string())
+				),
+				b"fail" => Box::new(futures::future::err("invalid job".to_owned())),
+				_ => Box::new(futures::future::ok(())),
+			}
+		}
+	}).unwrap();
+	work_queue.set_max_concurrency(2);
+	work_queue.set_max_attempts(2);
+
+	let events: std::rc::Rc<std::cell::RefCell<Vec<mqtt::work_queue::WorkQueueEvent<String>>>> = Default::default();
+	simulation.spawn({
+		let events = events.clone();
+		work_queue.for_each(move |event| { events.borrow_mut().push(event); Ok(()) }).map_err(|err| panic!("{:?}", err))
+	});
+
+	let start = simulation.now();
+	simulation.block_on(done).unwrap();
+	assert!(simulation.now() - start >= std::time::Duration::from_secs(5));
+
+	// The failing publication is retried after the retry delay, and the redelivered one is not processed again
+	assert_eq!(*handled.borrow(), vec!["jobs/a", "jobs/b", "jobs/c", "jobs/c"]);
+
+	assert_eq!(*events.borrow(), vec![
+		mqtt::work_queue::WorkQueueEvent::Client(mqtt::Event::NewConnection { reset_session: true }),
+		mqtt::work_queue::WorkQueueEvent::Client(mqtt::Event::SubscriptionUpdates(vec![
+			mqtt::SubscriptionUpdateEvent::Subscribe(mqtt::proto::SubscribeTo { topic_filter: "jobs/#".parse().unwrap(), qos: mqtt::proto::QoS::AtLeastOnce }),
+		])),
+		mqtt::work_queue::WorkQueueEvent::Failed {
+			publication: mqtt::ReceivedPublication {
+				topic_name: "jobs/c".to_owned(),
+				dup: false,
+				qos: mqtt::proto::QoS::AtLeastOnce,
+				retain: false,
+				payload: b"fail"[..].into(),
+			},
+			err: "invalid job".to_owned(),
+		},
+	]);
+}